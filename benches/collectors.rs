@@ -0,0 +1,67 @@
+//! Collector latency and batch serialization throughput
+//!
+//! `cargo bench --features bench --bench collectors -- --save-baseline <release>` records a
+//! release; `--baseline <release>` on the next one reports the change.
+//! `sentinel-agent --bench-collectors` times the collectors of a real host.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use std::time::Duration;
+
+use sentinel_agent::bench::synthetic_metrics;
+use sentinel_agent::config::{Config, DiskConfig};
+use sentinel_agent::metadata::SessionInfo;
+use sentinel_agent::metrics::{CollectContext, DiskCollector, MetricService};
+
+fn config() -> Config {
+    Config::load_from_str(
+        r#"
+agent:
+  id: "bench"
+api:
+  endpoint: "https://api.example.com"
+collection:
+  interval_seconds: 60
+  disk:
+    enabled: true
+"#,
+    )
+    .expect("bench config is valid")
+}
+
+fn collectors(c: &mut Criterion) {
+    let mut group = c.benchmark_group("collectors");
+
+    let disk = DiskCollector::new(DiskConfig {
+        enabled: true,
+        include_mount_points: None,
+        exclude_mount_points: None,
+        exclude_devices: None,
+    });
+    let ctx = CollectContext::new(Duration::from_secs(10));
+    group.bench_function("disk", |b| b.iter(|| disk.collect(&ctx)));
+
+    // A whole cycle: every collector on its own task, as the agent runs them
+    let runtime = tokio::runtime::Runtime::new().expect("tokio runtime");
+    let service = MetricService::new(&config());
+    group.bench_function("cycle", |b| {
+        b.iter(|| runtime.block_on(service.collect_all_metrics()))
+    });
+
+    group.finish();
+}
+
+fn serialization(c: &mut Criterion) {
+    let service = MetricService::new(&config());
+    let mut group = c.benchmark_group("serialization");
+    for size in [10, 100, 1000] {
+        let batch = service.create_batch(synthetic_metrics(size), "bench-resource", "bench-host", SessionInfo::generate());
+        group.throughput(Throughput::Elements(size as u64));
+        group.bench_with_input(BenchmarkId::new("batch_json", size), &batch, |b, batch| {
+            b.iter(|| serde_json::to_vec(batch).expect("batch serializes"))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, collectors, serialization);
+criterion_main!(benches);
@@ -169,6 +169,9 @@ EOF
     cat > "${CONFIG_DIR}/agent.yaml" << EOF
 # Operion Sentinel Agent Configuration
 
+# Config file layout; older layouts are migrated with a warning
+version: 2
+
 agent:
   # Optional: Override hostname detection
   # hostname: "custom-hostname"
@@ -233,6 +236,9 @@ else
     cat > "${USER_CONFIG}/agent.yaml" << EOF
 # Operion Sentinel Agent Configuration
 
+# Config file layout; older layouts are migrated with a warning
+version: 2
+
 agent:
   # Optional: Override hostname detection
   # hostname: "custom-hostname"
@@ -1,244 +1,1829 @@
-use std::collections::VecDeque;
-use tokio::time::{Duration, interval};
+use chrono::{DateTime, Utc};
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio::sync::{mpsc, OnceCell};
+use tokio::task::JoinHandle;
+use tokio::time::{Duration, Instant, interval};
+use tracing::{debug, error, info, info_span, warn, Instrument};
 
-use crate::client::{ApiClient, ApiError, ResourceRegistration};
-use crate::config::Config;
-use crate::metadata::{InstanceMetadata, SessionInfo};
-use crate::metrics::{DiskMetric, MetricService};
-use crate::state::ResourceState;
+use crate::alerts::{self, AlertEvaluator};
+use crate::buffer::{LatestSamples, MetricBuffer, MetricQueue};
+use crate::build_info::BuildInfo;
+use crate::clock::{self, ClockSkew};
+use crate::client::{ApiClient, ApiError, Heartbeat, MetricsAck, ResourceRegistration};
+use crate::commands::{self, AgentCommand, CommandEnvelope, CommandResult};
+use crate::config::{AlertMetric, Config, MAX_INTERVAL_SECONDS, MIN_INTERVAL_SECONDS};
+use crate::container::ContainerInfo;
+use crate::diagnose;
+use crate::limits;
+use crate::maintenance::{self, MaintenanceWindow};
+use crate::metadata::{self, InstanceMetadata, SessionInfo};
+use crate::metrics::{self, DeltaFilter, DiskMetric, MetricBatch, MetricService};
+use crate::network::NetworkIdentity;
+use crate::pressure::{self, PressureMonitor};
+use crate::relabel::{RelabelError, Relabeler};
+use crate::remote_config::{RemoteConfig, SignedRemoteConfig};
+use crate::schedule::{self, JitteredTimer};
+use crate::scripting::{Script, ScriptError};
+use crate::sinks::Sink;
+use crate::spool::{Spool, SpoolError};
+use crate::fingerprint::MachineFingerprint;
+use crate::identity::AgentIdentity;
+use crate::kubernetes::PodMetadata;
+use crate::state::{AgentUpgrade, DeliveryGap, FlushCheckpoint, ResourceState, StateError, PRIMARY_REGISTRATION};
+use crate::status::{self, StatusHandle};
+use crate::supervisor::{self, InlineTask};
+use crate::thresholds::ThresholdTracker;
+use crate::tls;
+use crate::wasm::{self, WasmModule};
+
+/// Upper bound on spooled batches replayed per flush, to avoid flooding a recovering API
+const MAX_REPLAY_SEGMENTS_PER_FLUSH: usize = 10;
+
+/// Pause applied when the API sheds load without a Retry-After header
+const DEFAULT_RATE_LIMIT_PAUSE: Duration = Duration::from_secs(30);
+
+/// How often to check whether the registration needs renewing
+const REGISTRATION_CHECK_INTERVAL: Duration = Duration::from_secs(60);
 
 pub struct SentinelAgent {
+    /// Effective configuration (local YAML with remote overrides applied)
     config: Config,
+    /// Configuration as loaded from disk, the base for remote overrides
+    local_config: Config,
+    remote_config: Option<RemoteConfig>,
     hostname: String,
     api_client: ApiClient,
+    /// `api.additional_endpoints`, sent a copy of every accepted batch
+    sinks: Vec<Sink>,
     metric_service: MetricService,
-    buffer: VecDeque<DiskMetric>,
+    /// Labels from the Kubernetes pod and cloud tags, added to every batch
+    metadata_labels: BTreeMap<String, String>,
+    relabeler: Option<Relabeler>,
+    /// WASM modules run, in file name order, after the relabel pipeline
+    wasm_transforms: Vec<WasmModule>,
+    /// `collection.script`, run after the WASM transforms
+    script: Option<Script>,
+    delta_filter: Option<DeltaFilter>,
+    buffer: MetricBuffer,
+    /// Samples from textfiles and similar sources, sent with the next batch
+    samples: LatestSamples,
+    spool: Option<Spool>,
+    /// Keep every batch in the spool instead of sending it (offline mode)
+    offline: bool,
+    /// Flushes failed in a row; batches are spooled once this reaches `spool.after_failures`
+    failed_flushes: u32,
     resource_id: Option<String>,
+    /// Persisted registration; the flush checkpoint is saved into it
+    state: Option<ResourceState>,
+    /// This machine's identity, compared with the one saved in the state
+    fingerprint: Option<MachineFingerprint>,
+    /// Cloud metadata detected at registration, reused by the additional endpoints
+    instance_metadata: Option<InstanceMetadata>,
+    /// The Kubernetes pod, looked up once since it cannot change under a running agent
+    pod: OnceCell<Option<PodMetadata>>,
+    checkpoint: Option<FlushCheckpoint>,
+    /// Time without acknowledged data before this start, reported with the next batch
+    pending_gap: Option<DeliveryGap>,
     session: SessionInfo,
+    flush_paused_until: Option<Instant>,
+    backend_unreachable_since: Option<DateTime<Utc>>,
+    status: StatusHandle,
+    started_at: Instant,
+    alerts: Option<AlertEvaluator>,
+    thresholds: Option<ThresholdTracker>,
+    /// When the current maintenance window started, if paused
+    maintenance_since: Option<DateTime<Utc>>,
+    /// Finished maintenance window, reported with the next batch
+    pending_maintenance: Option<MaintenanceWindow>,
+    pressure: Option<PressureMonitor>,
+    /// The agent is over `limits.max_memory_mb` even without its buffer, already warned about
+    memory_limit_unreachable: bool,
+    /// Metrics pushed out of the buffer that no spool took
+    overflow_dropped: u64,
 }
 
 impl SentinelAgent {
-    pub fn new(config: Config) -> Result<Self, AgentError> {
+    pub fn new(mut config: Config) -> Result<Self, AgentError> {
+        for warning in &config.migration_warnings {
+            warn!(version = config.get_version(), "Config migrated: {}", warning);
+        }
+        for key in &config.unknown_key_warnings {
+            warn!("Unknown config key ignored: {}", key);
+        }
+        for warning in config.clamp_limits() {
+            warn!("{}", warning);
+        }
         let hostname = config.get_hostname();
-        let api_client =
+        // Read before privileges are dropped; the DMI UUID is root-only
+        let fingerprint = MachineFingerprint::detect();
+        let saved_state = load_saved_state()?;
+        let mut api_client =
             ApiClient::new(&config).map_err(|e| AgentError::Initialization(e.to_string()))?;
+        // Only agents talking to the platform need an identity
+        if config.has_api_credentials() {
+            let mut identity = AgentIdentity::load_or_generate_in_state_dir()
+                .map_err(|e| AgentError::Initialization(e.to_string()))?;
+            // The instance ID is not known until registration, which checks it again
+            if saved_state.as_ref().is_some_and(|state| {
+                state.shares_key_with_other_machine(
+                    fingerprint.as_ref(),
+                    &InstanceMetadata::default(),
+                    &identity.public_key(),
+                )
+            }) {
+                identity = AgentIdentity::regenerate_in_state_dir()
+                    .map_err(|e| AgentError::Initialization(e.to_string()))?;
+            }
+            api_client = api_client.with_identity(identity);
+        }
+        let sinks =
+            Sink::from_config(&config, &api_client).map_err(|e| AgentError::Initialization(e.to_string()))?;
         let metric_service = MetricService::new(&config);
+        let relabeler =
+            build_relabeler(&config).map_err(|e| AgentError::Configuration(e.to_string()))?;
+        let wasm_transforms = build_wasm_transforms(&config);
+        let script = build_script(&config).map_err(|e| AgentError::Configuration(e.to_string()))?;
+        let delta_filter = config
+            .collection
+            .delta
+            .as_ref()
+            .filter(|delta| delta.enabled)
+            .map(DeltaFilter::new);
 
         let session = SessionInfo::generate();
+        let status = StatusHandle::new(hostname.clone());
+        let alerts = match config.alerts.as_ref().filter(|alerts| alerts.enabled) {
+            Some(alerts) => {
+                let http = tls::client_builder(config.api.tls.as_ref())
+                    .map_err(|e| AgentError::Initialization(e.to_string()))?
+                    .build()
+                    .map_err(|e| AgentError::Initialization(e.to_string()))?;
+                Some(AlertEvaluator::new(
+                    alerts,
+                    config.thresholds.as_ref(),
+                    hostname.clone(),
+                    &config.get_script_limits(),
+                    http,
+                ))
+            }
+            None => None,
+        };
+        let thresholds = config.thresholds.clone().map(ThresholdTracker::new);
+        let pressure = config
+            .collection
+            .adaptive
+            .clone()
+            .filter(|adaptive| adaptive.enabled)
+            .map(PressureMonitor::new);
+
+        // The instance ID is not known until registration, which resets again if it changed
+        let public_key = api_client.public_key();
+        let checkpoint = saved_state
+            .filter(|state| {
+                state
+                    .mismatch(fingerprint.as_ref(), &InstanceMetadata::default(), public_key.as_deref())
+                    .is_none()
+            })
+            .and_then(|state| state.checkpoint);
+        let pending_gap = checkpoint.as_ref().map(|checkpoint| {
+            let gap = checkpoint.gap_until(Utc::now().timestamp().max(0) as u64);
+            info!(
+                sequence = checkpoint.sequence,
+                acknowledged_at = %checkpoint.acknowledged_at,
+                gap_seconds = gap.seconds,
+                "Resuming after last acknowledged batch"
+            );
+            gap
+        });
+
+        let spool = match config.get_spool() {
+            Some(spool_config) => {
+                let directory = spool_config.get_directory();
+                let mut spool = Spool::open(&directory, spool_config.get_max_size_bytes())
+                    .map_err(|e| AgentError::Initialization(e.to_string()))?
+                    .with_compression(spool_config.is_compressed());
+                // New segments must not be mistaken for ones delivered before the restart
+                if let Some(segment) = checkpoint.as_ref().and_then(|c| c.spool_segment) {
+                    spool.continue_after(segment);
+                }
+                if !spool.is_empty() {
+                    info!(
+                        segments = spool.len(),
+                        directory = %directory.display(),
+                        "Found spooled batches, will replay once the API is reachable"
+                    );
+                }
+                Some(spool)
+            }
+            None => None,
+        };
+
+        let offline = config.is_offline();
+        let buffer = MetricBuffer::new(config.get_batch_size(), config.get_max_buffer_bytes() as usize);
 
         Ok(Self {
+            local_config: config.clone(),
+            remote_config: None,
             config,
             hostname,
             api_client,
+            sinks,
             metric_service,
-            buffer: VecDeque::new(),
+            metadata_labels: BTreeMap::new(),
+            relabeler,
+            wasm_transforms,
+            script,
+            delta_filter,
+            buffer,
+            samples: LatestSamples::default(),
+            spool,
+            offline,
+            failed_flushes: 0,
             resource_id: None,
+            state: None,
+            fingerprint,
+            instance_metadata: None,
+            pod: OnceCell::new(),
+            checkpoint,
+            pending_gap,
             session,
+            flush_paused_until: None,
+            backend_unreachable_since: None,
+            status,
+            started_at: Instant::now(),
+            alerts,
+            thresholds,
+            maintenance_since: None,
+            pending_maintenance: None,
+            pressure,
+            memory_limit_unreachable: false,
+            overflow_dropped: 0,
         })
     }
 
     fn add_to_buffer(&mut self, metrics: Vec<DiskMetric>) {
-        self.buffer.extend(metrics);
+        let overflow = self.buffer.extend(metrics);
+        self.spool_metrics(overflow);
+    }
+
+    /// Shed the older half of the buffer to the spool when the agent exceeds its memory ceiling
+    fn enforce_memory_limit(&mut self) {
+        let Some(limit) = self.config.get_max_memory_bytes() else {
+            return;
+        };
+        let Some(used) = limits::process_memory_bytes() else {
+            return;
+        };
+        self.status.update(|status| status.memory_bytes = Some(used));
+        self.shed_buffer_for_memory(used, limit);
+    }
 
-        let max_size = self.config.get_batch_size();
-        while self.buffer.len() > max_size {
-            self.buffer.pop_front();
+    fn shed_buffer_for_memory(&mut self, used: u64, limit: u64) {
+        if used <= limit || self.buffer.is_empty() {
+            return;
+        }
+        // Emptying the buffer would not get the agent under the limit, so keep
+        // the metrics rather than shedding half of them every cycle
+        if used.saturating_sub(self.buffer.size_bytes()) > limit {
+            if !self.memory_limit_unreachable {
+                warn!(
+                    memory_bytes = used,
+                    limit_bytes = limit,
+                    "Agent memory above limit without its buffer; not evicting buffered metrics"
+                );
+                self.memory_limit_unreachable = true;
+            }
+            return;
         }
+        self.memory_limit_unreachable = false;
+
+        let evicted = self.buffer.evict_oldest(self.buffer.len().div_ceil(2));
+        warn!(
+            memory_bytes = used,
+            limit_bytes = limit,
+            evicted = evicted.len(),
+            "Agent memory above limit, evicting buffered metrics"
+        );
+        self.status.record_error(format!(
+            "Memory usage {} bytes above limit {} bytes, evicted {} buffered metrics",
+            used,
+            limit,
+            evicted.len()
+        ));
+        self.spool_metrics(evicted);
     }
 
-    async fn flush_buffer(&mut self) -> Result<(), AgentError> {
-        if self.buffer.is_empty() {
-            return Ok(());
+    /// Persist metrics to the disk spool, if enabled, instead of dropping them
+    fn spool_metrics(&mut self, metrics: Vec<DiskMetric>) {
+        if metrics.is_empty() {
+            return;
         }
 
-        // Use resource_id if available, or fall back to test ID when no API key
-        let resource_id = match &self.resource_id {
-            Some(id) => id.clone(),
-            None => {
-                if self.config.api.api_key.is_none() {
-                    // In test/development mode without API key, use test resource ID
-                    "test-resource-id".to_string()
-                } else {
-                    return Err(AgentError::Configuration("Resource not registered".to_string()));
+        let count = metrics.len();
+        match self.spool.as_mut() {
+            Some(spool) => {
+                if let Err(e) = spool.push(metrics) {
+                    error!(count, error = %e, "Failed to spool metrics");
+                    self.overflow_dropped += count as u64;
                 }
             }
+            None => self.overflow_dropped += count as u64,
+        }
+    }
+
+    /// Resend up to `limit` spooled batches, oldest first; returns how many metrics were delivered
+    ///
+    /// Metrics keep the timestamps they were collected with and the batches
+    /// are marked as replayed.
+    async fn replay_spool(&mut self, resource_id: &str, limit: usize) -> Result<usize, AgentError> {
+        let Some(spool) = self.spool.take() else {
+            return Ok(0);
         };
 
-        let metrics: Vec<DiskMetric> = self.buffer.drain(..).collect();
+        let mut delivered = 0;
+        let mut result = Ok(());
+        for _ in 0..limit {
+            let Some((path, metrics)) = spool.oldest() else {
+                break;
+            };
+
+            let segment = Spool::sequence_of(&path);
+            let delivered_before = self.checkpoint.as_ref().and_then(|c| c.spool_segment);
+            if let (Some(segment), Some(delivered_before)) = (segment, delivered_before) {
+                if segment <= delivered_before {
+                    // Acknowledged before a crash, the segment just was not removed yet
+                    debug!(segment, "Dropping already delivered spool segment");
+                    if let Err(e) = spool.remove(&path) {
+                        result = Err(AgentError::Spool(e));
+                        break;
+                    }
+                    continue;
+                }
+            }
+
+            let mut batch = self.metric_service.create_batch(
+                metrics,
+                resource_id,
+                &self.hostname,
+                SessionInfo::generate(),
+            );
+            batch.replayed = true;
+
+            match self.api_client.send_metrics(&batch).await {
+                Ok(ack) => {
+                    self.copy_to_sinks(&batch);
+                    self.record_checkpoint(&batch, segment);
+                    if let Err(e) = spool.remove(&path) {
+                        result = Err(AgentError::Spool(e));
+                        break;
+                    }
+                    info!(count = batch.metrics.len(), "Replayed spooled metrics");
+                    delivered += batch.metrics.len();
+                    self.handle_ack(ack, batch.metrics);
+                }
+                Err(e) => {
+                    result = Err(AgentError::Api(e));
+                    break;
+                }
+            }
+        }
+
+        self.spool = Some(spool);
+        result.map(|()| delivered)
+    }
+
+    /// Queue a copy of a batch the primary endpoint accepted for each additional endpoint
+    fn copy_to_sinks(&mut self, batch: &MetricBatch) {
+        for sink in &mut self.sinks {
+            sink.enqueue(batch);
+        }
+    }
+
+    /// Remember the acknowledged batch, persisting it when the resource is registered
+    fn record_checkpoint(&mut self, batch: &MetricBatch, spool_segment: Option<u64>) {
+        let newest = batch
+            .metrics
+            .iter()
+            .map(|metric| metric.timestamp)
+            .chain(batch.samples.iter().map(|sample| sample.timestamp))
+            .max()
+            .unwrap_or(0);
+        let checkpoint = FlushCheckpoint::advance(self.checkpoint.as_ref(), newest, spool_segment);
+
+        if let Some(state) = self.state.as_mut() {
+            state.checkpoint = Some(checkpoint.clone());
+            if let Err(e) = state.save() {
+                warn!(error = %e, "Failed to save flush checkpoint");
+            }
+        }
+        self.checkpoint = Some(checkpoint);
+    }
+
+    /// Put unsent metrics back at the front of the buffer, spooling what no longer fits
+    fn requeue_metrics(&mut self, metrics: Vec<DiskMetric>) {
+        let overflow = self.buffer.requeue(metrics);
+        self.spool_metrics(overflow);
+    }
+
+    /// Re-queue retryable rejections and report the ones the API will never accept
+    fn handle_ack(&mut self, ack: MetricsAck, metrics: Vec<DiskMetric>) {
+        if ack.rejected.is_empty() {
+            return;
+        }
+
+        warn!(
+            accepted = ack.accepted.len(),
+            rejected = ack.rejected.len(),
+            total = metrics.len(),
+            "API partially accepted metric batch"
+        );
+
+        let mut retry = Vec::new();
+        for rejected in ack.rejected {
+            let reason = rejected.reason.as_deref().unwrap_or("no reason given");
+            let Some(metric) = metrics.get(rejected.index) else {
+                warn!(index = rejected.index, reason, "API rejected unknown metric index");
+                continue;
+            };
+
+            if rejected.retryable {
+                retry.push(metric.clone());
+            } else {
+                warn!(
+                    mount_point = %metric.mount_point,
+                    device = %metric.device,
+                    reason,
+                    "API rejected metric"
+                );
+            }
+        }
+
+        if !retry.is_empty() {
+            info!(count = retry.len(), "Re-queueing metrics rejected as retryable");
+            self.requeue_metrics(retry);
+        }
+    }
+
+    /// Drain the buffer, rolled up per series when `collection.aggregate` is on
+    fn take_buffer(&mut self) -> Vec<DiskMetric> {
+        let mut metrics = self.buffer.take();
+        if self.config.get_aggregate() {
+            let samples = metrics.len();
+            metrics = metrics::aggregate(metrics);
+            debug!(samples, series = metrics.len(), "Aggregated buffered metrics");
+        }
+        metrics
+    }
+
+    /// Resource ID batches are sent under, or the test ID when no API key is configured
+    fn batch_resource_id(&self) -> Result<String, AgentError> {
+        match &self.resource_id {
+            Some(id) => Ok(id.clone()),
+            None if !self.config.has_api_credentials() => Ok("test-resource-id".to_string()),
+            None => Err(AgentError::Configuration("Resource not registered".to_string())),
+        }
+    }
+
+    async fn flush_buffer(&mut self) -> Result<(), AgentError> {
+        if self.buffer.is_empty() && self.samples.is_empty() {
+            return Ok(());
+        }
+
+        // Samples are not spooled; the latest values go out once back online
+        if self.offline {
+            let metrics = self.take_buffer();
+            debug!(count = metrics.len(), "Offline, spooling metrics");
+            self.spool_metrics(metrics);
+            return Ok(());
+        }
+
+        // Respect a pause requested by the API via 429/503
+        if let Some(until) = self.flush_paused_until {
+            if Instant::now() < until {
+                return Ok(());
+            }
+            self.flush_paused_until = None;
+        }
+
+        let resource_id = self.batch_resource_id()?;
+        let metrics = self.take_buffer();
         let current_session = SessionInfo::generate();
-        let batch = self.metric_service.create_batch(
+        let mut batch = self.metric_service.create_batch(
             metrics,
             &resource_id,
             &self.hostname,
             current_session,
         );
+        batch.samples = self.samples.take();
+        batch.maintenance = self.pending_maintenance.take();
+        batch.gap = self.pending_gap.take();
+        if self.config.get_align_flushes() {
+            let period = Duration::from_secs(self.config.get_flush_interval_seconds());
+            batch.window = Some(schedule::aligned_window(period, SystemTime::now()));
+        }
+        batch.clock_skew = self.clock_skew().map(|offset_seconds| ClockSkew {
+            offset_seconds,
+            corrected: self.config.get_correct_timestamps(),
+        });
+
+        match self.api_client.send_metrics(&batch).await {
+            Ok(ack) => {
+                self.failed_flushes = 0;
+                self.copy_to_sinks(&batch);
+                self.record_checkpoint(&batch, None);
+                self.status.update(|status| status.last_flush_at = Some(Utc::now().to_rfc3339()));
+                self.handle_ack(ack, batch.metrics);
+                if let Err(e) = self.replay_spool(&resource_id, MAX_REPLAY_SEGMENTS_PER_FLUSH).await {
+                    warn!(error = %e, "Spool replay stopped");
+                }
+                Ok(())
+            }
+            Err(ApiError::RateLimited { status, retry_after }) => {
+                self.pending_maintenance = batch.maintenance;
+                self.pending_gap = batch.gap;
+                // Load shedding is expected during deploys; keep the metrics and back off
+                let pause = retry_after.unwrap_or(DEFAULT_RATE_LIMIT_PAUSE);
+                warn!(
+                    status,
+                    pause_seconds = pause.as_secs(),
+                    "API is shedding load, pausing metric flushes"
+                );
+                self.flush_paused_until = Some(Instant::now() + pause);
+                self.samples.restore(batch.samples);
+                self.requeue_metrics(batch.metrics);
+                Ok(())
+            }
+            Err(e) => {
+                self.pending_maintenance = batch.maintenance;
+                self.pending_gap = batch.gap;
+                self.failed_flushes += 1;
+                self.samples.restore(batch.samples);
+                let spool_after = self.config.get_spool().map(|spool| spool.get_after_failures());
+                match spool_after {
+                    // Short outages are ridden out in memory; the buffer caps still apply
+                    Some(after) if self.failed_flushes < after => self.requeue_metrics(batch.metrics),
+                    _ => self.spool_metrics(batch.metrics),
+                }
+                Err(AgentError::Api(e))
+            }
+        }
+    }
+
+    /// Run every collector; failures are recorded per collector and do not discard other metrics
+    async fn collect_metrics(&mut self) -> Vec<DiskMetric> {
+        let pressure = self.pressure.as_ref();
+        let report = self
+            .metric_service
+            .collect_metrics_except(|name| pressure.is_some_and(|p| p.skips(name)))
+            .await;
+        for failure in &report.failures {
+            self.status.update(|status| {
+                *status
+                    .collector_failures
+                    .entry(failure.collector.to_string())
+                    .or_default() += 1;
+            });
+            self.status.record_error(format!("Failed to collect metrics: {}", failure.error));
+        }
+        let mut samples = report.samples;
+        let mut metrics = report.metrics;
+        if let Some(thresholds) = self.thresholds.as_mut() {
+            thresholds.annotate(&mut metrics);
+        }
 
-        self.api_client
-            .send_metrics(&batch)
-            .await
-            .map_err(AgentError::Api)?;
+        // Alerts see every metric as collected, including ones the delta filter suppresses
+        self.evaluate_alerts(&metrics).await;
 
-        Ok(())
+        let mut metrics = match &self.relabeler {
+            Some(relabeler) => relabeler.apply(metrics),
+            None => metrics,
+        };
+        for module in &self.wasm_transforms {
+            // Modules run up to their fuel budget, so keep them off the runtime threads
+            let input = Arc::new(std::mem::take(&mut metrics));
+            let transform = {
+                let (module, input) = (module.clone(), input.clone());
+                tokio::task::spawn_blocking(move || module.transform(&input)).await
+            };
+            // A failing transform passes the metrics through unchanged
+            match transform {
+                Ok(Ok(transformed)) => metrics = transformed,
+                Ok(Err(e)) => {
+                    metrics = Arc::unwrap_or_clone(input);
+                    warn!(module = module.name(), error = %e, "WASM transform failed");
+                    self.status.record_error(format!("WASM transform failed: {}", e));
+                }
+                Err(e) => {
+                    metrics = Arc::unwrap_or_clone(input);
+                    warn!(module = module.name(), error = %e, "WASM transform panicked");
+                    self.status.record_error(format!("WASM transform panicked: {}", e));
+                }
+            }
+        }
+        if let Some(script) = &self.script {
+            match script.transform(&metrics) {
+                Ok(transformed) => metrics = transformed,
+                Err(e) => {
+                    warn!(error = %e, "Transform script failed");
+                    self.status.record_error(e.to_string());
+                }
+            }
+        }
+
+        // Corrected at collection time so re-queued metrics are never shifted twice
+        if self.config.get_correct_timestamps() {
+            if let Some(offset) = self.clock_skew() {
+                for metric in &mut metrics {
+                    metric.timestamp = metric.timestamp.saturating_add_signed(offset);
+                }
+                for sample in &mut samples {
+                    sample.timestamp = sample.timestamp.saturating_add_signed(offset);
+                }
+            }
+        }
+        self.samples.insert(samples);
+
+        match self.delta_filter.as_mut() {
+            Some(filter) => filter.filter(metrics),
+            None => metrics,
+        }
+    }
+
+    /// Track maintenance mode from config and the touch-file; returns true while paused
+    fn update_maintenance(&mut self) -> bool {
+        let now = Utc::now();
+        let active = self.config.get_maintenance()
+            || maintenance::is_active(&self.config.get_maintenance_file(), now);
+
+        match (active, self.maintenance_since) {
+            (true, None) => {
+                info!("Entering maintenance mode, collection and sending paused");
+                self.maintenance_since = Some(now);
+            }
+            (false, Some(since)) => {
+                info!(
+                    started_at = %since.to_rfc3339(),
+                    "Maintenance mode ended, resuming collection"
+                );
+                self.maintenance_since = None;
+                self.pending_maintenance = Some(MaintenanceWindow {
+                    started_at: since.to_rfc3339(),
+                    ended_at: now.to_rfc3339(),
+                });
+            }
+            _ => {}
+        }
+
+        active
     }
 
-    async fn collect_metrics(&self) -> Result<Vec<DiskMetric>, AgentError> {
-        self.metric_service
-            .collect_all_metrics()
-            .map_err(|e| AgentError::MetricCollection(e.to_string()))
+    /// Clock offset to the API server, if large enough to matter
+    fn clock_skew(&self) -> Option<i64> {
+        self.api_client.clock_offset_seconds().and_then(clock::significant)
     }
 
-    async fn register_resource(&mut self) -> Result<(), AgentError> {
-        // Only register if API key is configured (indicating Operion platform integration)
-        if self.config.api.api_key.is_none() {
-            println!("API key not configured, skipping resource registration");
-            return Ok(());
+    /// Sample host pressure; returns true when the agent entered or left backoff
+    fn update_pressure(&mut self) -> bool {
+        let Some(monitor) = self.pressure.as_mut() else {
+            return false;
+        };
+
+        let reading = pressure::read();
+        if !monitor.update(reading) {
+            return false;
         }
 
-        // Check if we already have a resource state
-        match ResourceState::load() {
-            Ok(Some(state)) => {
-                println!("✅ Found existing resource registration");
-                println!("   Resource ID: {}", state.resource_id);
-                println!("   Registered at: {}", state.registered_at);
-                self.resource_id = Some(state.resource_id);
-                return Ok(());
+        let under_pressure = monitor.under_pressure();
+        if under_pressure {
+            warn!(
+                reading = ?reading,
+                backoff_factor = monitor.backoff_factor(),
+                "Host under pressure, backing off collection"
+            );
+        } else {
+            info!(reading = ?reading, "Host pressure eased, resuming normal collection");
+        }
+        self.status.update(|status| status.under_pressure = under_pressure);
+        true
+    }
+
+    /// Check local alert thresholds and run hooks for any crossings
+    async fn evaluate_alerts(&mut self, metrics: &[DiskMetric]) {
+        let Some(evaluator) = self.alerts.as_mut() else {
+            return;
+        };
+
+        let mut crossings = evaluator.evaluate_disks(metrics);
+
+        if evaluator.has_rules_for(AlertMetric::FailedSystemdUnits) {
+            match alerts::failed_systemd_units().await {
+                Some(count) => {
+                    let now = Utc::now().timestamp().max(0) as u64;
+                    let severity = self.thresholds.as_mut().and_then(|thresholds| {
+                        thresholds.observe(AlertMetric::FailedSystemdUnits, "systemd", count, now)
+                    });
+                    crossings.extend(evaluator.evaluate(
+                        AlertMetric::FailedSystemdUnits,
+                        &[("systemd".to_string(), count, severity)],
+                    ))
+                }
+                None => debug!("systemd unavailable, skipping failed unit alerts"),
             }
-            Ok(None) => {
-                println!("📝 No existing registration found, registering new resource...");
+        }
+
+        for (event, actions) in crossings {
+            evaluator.dispatch(event, actions);
+        }
+    }
+
+    /// Probe the API health endpoint and track how long the backend has been unreachable
+    ///
+    /// Returns whether the backend answered.
+    async fn check_backend_health(&mut self) -> bool {
+        match self.api_client.check_health().await {
+            Ok(()) => {
+                if let Some(since) = self.backend_unreachable_since.take() {
+                    info!(
+                        unreachable_since = %since.to_rfc3339(),
+                        "Backend reachable again"
+                    );
+                }
+                if let (None, Some(offset)) =
+                    (self.status.snapshot().clock_skew_seconds, self.clock_skew())
+                {
+                    warn!(
+                        offset_seconds = offset,
+                        correcting = self.config.get_correct_timestamps(),
+                        "Local clock differs from the API server, check NTP"
+                    );
+                }
+                true
             }
             Err(e) => {
-                eprintln!("⚠️  Error loading resource state: {}", e);
-                eprintln!("   Will attempt to register new resource");
+                let since = *self.backend_unreachable_since.get_or_insert_with(Utc::now);
+                warn!(
+                    unreachable_since = %since.to_rfc3339(),
+                    error = %e,
+                    "Backend unreachable"
+                );
+                false
             }
         }
+    }
+
+    /// With `offline.auto_sync`, leave offline mode once the API answers
+    ///
+    /// The backlog then drains through the regular spool replay after each flush.
+    /// Returns true when the agent went online.
+    async fn try_go_online(&mut self) -> bool {
+        if !self.offline || !self.config.get_auto_sync() || !self.check_backend_health().await {
+            return false;
+        }
 
-        // Detect cloud metadata
-        println!("🔍 Detecting cloud environment...");
-        let instance_metadata = InstanceMetadata::detect().await;
+        // Registration failures are logged; stay offline and try again next time
+        let _ = self.register_resource().await;
+        if self.batch_resource_id().is_err() {
+            return false;
+        }
 
-        if let Some(ref provider) = instance_metadata.cloud_provider {
-            println!("☁️  Detected cloud provider: {:?}", provider);
-            if let Some(ref instance_id) = instance_metadata.instance_id {
-                println!("🆔 Instance ID: {}", instance_id);
+        self.offline = false;
+        info!(
+            spooled_batches = self.spool.as_ref().map(|s| s.len()).unwrap_or(0),
+            "API reachable, leaving offline mode and uploading the backlog"
+        );
+        self.refresh_remote_config().await;
+        true
+    }
+
+    /// Upload the whole spool, as `sentinel-agent sync` does after running offline
+    ///
+    /// Returns the number of metrics delivered. Stops at the first failed
+    /// upload; whatever is left stays spooled for the next attempt.
+    pub async fn sync(&mut self) -> Result<usize, AgentError> {
+        if self.spool.is_none() {
+            return Err(AgentError::Configuration(
+                "No spool configured, enable collection.spool or offline mode".to_string(),
+            ));
+        }
+
+        self.api_client.check_health().await?;
+        self.register_resource().await?;
+        let resource_id = self.batch_resource_id()?;
+
+        let result = self.replay_spool(&resource_id, usize::MAX).await;
+        // Retryable rejections were re-queued; keep them for the next sync
+        let remaining = self.buffer.take();
+        self.spool_metrics(remaining);
+        result
+    }
+
+    /// Decommission the resources and forget their registrations
+    async fn deregister_resource(&mut self) {
+        for sink in &mut self.sinks {
+            sink.deregister().await;
+        }
+
+        let Some(resource_id) = self.resource_id.take() else {
+            return;
+        };
+
+        match self.api_client.deregister_resource(&resource_id).await {
+            Ok(()) => {
+                info!(resource_id = %resource_id, "Resource deregistered");
+                self.state = None;
+                if let Err(e) = ResourceState::forget(PRIMARY_REGISTRATION) {
+                    warn!(error = %e, "Failed to remove resource state");
+                }
+                // A decommissioned host should not keep a login
+                if let Err(e) = self.api_client.revoke_credentials(true).await {
+                    warn!(error = %e, "Failed to revoke API tokens");
+                }
+            }
+            Err(e) => {
+                // Keep the state so a restart reuses the registration
+                warn!(resource_id = %resource_id, error = %e, "Failed to deregister resource");
             }
+        }
+    }
+
+    /// Send heartbeats from a supervised task, so a stalled loop iteration does not delay them
+    fn start_heartbeat(&self) -> Option<JoinHandle<()>> {
+        let resource_id = self.resource_id.clone()?;
+        let api_client = self.api_client.clone();
+        let status = self.status.clone();
+        let period = Duration::from_secs(self.config.get_heartbeat_interval_seconds());
+        let started_at = self.started_at;
+        let last_upgrade = self.state.as_ref().and_then(|state| state.upgrades.last().cloned());
+
+        Some(supervisor::supervise("heartbeat", self.status.clone(), move || {
+            send_heartbeats(
+                api_client.clone(),
+                resource_id.clone(),
+                status.clone(),
+                period,
+                started_at,
+                last_upgrade.clone(),
+            )
+        }))
+    }
+
+    async fn register_resource(&mut self) -> Result<(), AgentError> {
+        if self.session.binary_sha256.is_none() {
+            self.session.binary_sha256 = metadata::agent_binary_sha256().await;
+        }
+
+        // Only register if credentials are configured (indicating Operion platform integration)
+        if !self.config.has_api_credentials() {
+            info!("API credentials not configured, skipping resource registration");
+            return Ok(());
+        }
+
+        // Detect cloud metadata, also to tell whether a saved registration is for this instance
+        debug!("Detecting cloud environment");
+        let instance_metadata = self.detect_instance_metadata().await;
+
+        if let Some(ref provider) = instance_metadata.cloud_provider {
+            info!(
+                provider = ?provider,
+                instance_id = instance_metadata.instance_id.as_deref().unwrap_or("unknown"),
+                "Detected cloud provider"
+            );
         } else {
-            println!("💻 Running on-premises or in unrecognized environment");
+            info!("Running on-premises or in unrecognized environment");
         }
+        if !instance_metadata.tags.is_empty() {
+            self.metadata_labels.extend(instance_metadata.tag_labels());
+            self.metric_service.set_metadata_labels(self.metadata_labels.clone());
+        }
+        self.instance_metadata = Some(instance_metadata.clone());
 
-        // Perform new registration
-        let registration = ResourceRegistration {
-            hostname: self.hostname.clone(),
-            agent_version: env!("CARGO_PKG_VERSION").to_string(),
-            platform: std::env::consts::OS.to_string(),
-            arch: std::env::consts::ARCH.to_string(),
-            instance_metadata: instance_metadata.clone(),
+        // Check if we already have a resource state
+        let saved_state = match load_saved_state()? {
+            Some(state) => {
+                let public_key = self.api_client.public_key();
+                match state.mismatch(self.fingerprint.as_ref(), &instance_metadata, public_key.as_deref()) {
+                    Some(reason) => {
+                        warn!(
+                            resource_id = %state.resource_id,
+                            reason = %reason,
+                            "Saved registration is for another machine (cloned image or restored snapshot?), registering a new resource"
+                        );
+                        self.checkpoint = None;
+                        self.pending_gap = None;
+                        if public_key.is_some_and(|key| {
+                            state.shares_key_with_other_machine(self.fingerprint.as_ref(), &instance_metadata, &key)
+                        }) {
+                            self.regenerate_identity()?;
+                        }
+                        None
+                    }
+                    None => Some(state),
+                }
+            }
+            None => {
+                info!("No existing registration found, registering new resource");
+                None
+            }
         };
 
+        let registration = self.registration(instance_metadata.clone());
+        for sink in self.sinks.iter_mut().filter(|sink| sink.resource_id().is_none()) {
+            sink.register(&registration, self.fingerprint.as_ref(), &self.session).await;
+        }
+
+        if let Some(mut state) = saved_state {
+            info!(
+                resource_id = %state.resource_id,
+                registered_at = %state.registered_at,
+                "Found existing resource registration"
+            );
+            let upgraded = match state.record_version(env!("CARGO_PKG_VERSION")) {
+                Some(upgrade) => {
+                    info!(
+                        from_version = %upgrade.from_version,
+                        to_version = %upgrade.to_version,
+                        "Agent version changed since the last start"
+                    );
+                    true
+                }
+                None => false,
+            };
+            let session_changes = state.session.changes(&self.session);
+            if !session_changes.is_empty() {
+                // A new binary is expected with an upgrade, not otherwise
+                if session_changes.contains(&"binary_sha256") && !upgraded {
+                    warn!(
+                        changed = %session_changes.join(","),
+                        "Agent executable changed without a version change"
+                    );
+                } else {
+                    info!(changed = %session_changes.join(","), "Host or agent executable changed since the last start");
+                }
+            }
+            // States from before fingerprints and agent keys adopt the current ones
+            let public_key = self.api_client.public_key();
+            if upgraded
+                || !session_changes.is_empty()
+                || (state.fingerprint.is_none() && self.fingerprint.is_some())
+                || (state.public_key.is_none() && public_key.is_some())
+            {
+                state.fingerprint = state.fingerprint.take().or_else(|| self.fingerprint.clone());
+                state.public_key = state.public_key.take().or(public_key);
+                state.session = self.session.clone();
+                if let Err(e) = state.save() {
+                    warn!(error = %e, "Failed to save resource state");
+                }
+            }
+            self.resource_id = Some(state.resource_id.clone());
+            self.state = Some(state);
+            return Ok(());
+        }
+
+        // Perform new registration
         match self.api_client.register_resource(&registration).await {
             Ok(response) => {
-                println!("✅ Resource registered successfully");
-                println!("   Resource ID: {}", response.resource_id);
-                println!("   Status: {}", response.status);
-                if let Some(message) = response.message {
-                    println!("   Message: {}", message);
-                }
+                info!(
+                    resource_id = %response.resource_id,
+                    status = %response.status,
+                    message = response.message.as_deref().unwrap_or(""),
+                    "Resource registered successfully"
+                );
 
                 // Save the resource state
-                let state = ResourceState::new(
+                let mut state = ResourceState::new(
                     response.resource_id.clone(),
                     env!("CARGO_PKG_VERSION").to_string(),
                     instance_metadata,
                     self.session.clone(),
                 );
+                state.checkpoint = self.checkpoint.clone();
+                state.fingerprint = self.fingerprint.clone();
+                state.expires_at = response.expires_at;
+                state.public_key = registration.public_key;
 
                 if let Err(e) = state.save() {
-                    eprintln!("⚠️  Failed to save resource state: {}", e);
-                    eprintln!("   Resource will be re-registered on next restart");
+                    warn!(error = %e, "Failed to save resource state, resource will be re-registered on next restart");
                 } else {
-                    println!("💾 Resource state saved to: {}", ResourceState::get_state_file_path().display());
+                    debug!(path = %ResourceState::get_state_file_path().display(), "Resource state saved");
                 }
 
                 self.resource_id = Some(response.resource_id);
+                self.state = Some(state);
                 Ok(())
             }
             Err(e) => {
-                eprintln!("⚠️  Resource registration failed: {}", e);
-                eprintln!("   Agent will continue without registration");
+                warn!(error = %e, "Resource registration failed, agent will continue without registration");
+                self.status.record_error(format!("Resource registration failed: {}", e));
                 // Don't fail startup if registration fails - just log and continue
                 Ok(())
             }
         }
     }
 
-    pub async fn run(&mut self) -> Result<(), AgentError> {
-        println!("Starting Operion Sentinel Agent...");
-        println!("Hostname: {}", self.hostname);
-        println!("API Endpoint: {}", self.api_client.endpoint());
-        println!(
-            "Collection interval: {} seconds",
-            self.config.collection.interval_seconds
-        );
-        println!(
-            "Flush interval: {} seconds",
-            self.config.get_flush_interval_seconds()
-        );
+    fn registration(&self, instance_metadata: InstanceMetadata) -> ResourceRegistration {
+        ResourceRegistration {
+            hostname: self.hostname.clone(),
+            agent_version: env!("CARGO_PKG_VERSION").to_string(),
+            platform: std::env::consts::OS.to_string(),
+            arch: std::env::consts::ARCH.to_string(),
+            instance_metadata,
+            build: BuildInfo::current(),
+            labels: self.config.get_labels(),
+            org_id: self.config.get_org_id(),
+            project: self.config.get_project(),
+            fingerprint: self.fingerprint.as_ref().map(MachineFingerprint::id),
+            public_key: self.api_client.public_key(),
+            network: self.config.get_metadata().reports_network().then(NetworkIdentity::detect),
+            session: self.session.clone(),
+        }
+    }
 
-        // Register resource with Operion platform
-        self.register_resource().await?;
+    async fn pod(&self) -> Option<PodMetadata> {
+        self.pod.get_or_init(PodMetadata::detect).await.clone()
+    }
 
-        let mut collection_timer =
-            interval(Duration::from_secs(self.config.collection.interval_seconds));
-        let mut flush_timer = interval(Duration::from_secs(
-            self.config.get_flush_interval_seconds(),
-        ));
+    /// Cloud and environment metadata as of now, with the pod looked up at startup
+    async fn detect_instance_metadata(&self) -> InstanceMetadata {
+        InstanceMetadata::detect_with_pod(&self.config.get_metadata(), self.pod().await).await
+    }
 
-        loop {
-            tokio::select! {
-                _ = collection_timer.tick() => {
-                    match self.collect_metrics().await {
-                        Ok(metrics) => {
-                            if !metrics.is_empty() {
-                                println!("Collected {} disk metrics", metrics.len());
-                                self.add_to_buffer(metrics);
-                            }
-                        }
-                        Err(e) => {
-                            eprintln!("Failed to collect metrics: {}", e);
-                        }
-                    }
-                }
-                _ = flush_timer.tick() => {
-                    match self.flush_buffer().await {
-                        Ok(_) => {
-                            if !self.buffer.is_empty() {
-                                println!("Successfully flushed metrics buffer");
-                            }
-                        }
-                        Err(e) => {
-                            eprintln!("Failed to flush metrics: {}", e);
-                        }
-                    }
-                }
-            }
+    /// Sign with a new agent key from now on, in sinks too
+    fn regenerate_identity(&mut self) -> Result<(), AgentError> {
+        let identity = AgentIdentity::regenerate_in_state_dir()
+            .map_err(|e| AgentError::Initialization(e.to_string()))?;
+        self.api_client = self.api_client.clone().with_identity(identity);
+        for sink in &mut self.sinks {
+            sink.use_identity_of(&self.api_client);
         }
+        Ok(())
     }
-}
 
-#[derive(Debug, thiserror::Error)]
-pub enum AgentError {
-    #[error("Agent initialization failed: {0}")]
-    Initialization(String),
-    #[error("Configuration error: {0}")]
+    /// Renew the registration when it nears its expiry, registering again if the platform dropped it
+    ///
+    /// Also retries a registration lost earlier. Returns true when the
+    /// resource ID changed, so tasks holding the old one must be restarted.
+    async fn maintain_registration(&mut self) -> bool {
+        if self.offline || !self.config.has_api_credentials() {
+            return false;
+        }
+        self.maintain_sinks().await;
+        let Some(state) = self.state.as_ref() else {
+            let _ = self.register_resource().await;
+            return self.resource_id.is_some();
+        };
+        if !state.renewal_due(Utc::now()) {
+            return false;
+        }
+
+        let resource_id = state.resource_id.clone();
+        let registration = self.registration(state.instance_metadata.clone());
+        match self.api_client.renew_registration(&resource_id, &registration).await {
+            Ok(Some(response)) => {
+                let Some(state) = self.state.as_mut() else {
+                    return false;
+                };
+                state.renewed(response.expires_at);
+                info!(
+                    resource_id = %resource_id,
+                    expires_at = state.expires_at.as_deref().unwrap_or("never"),
+                    "Registration renewed"
+                );
+                if let Err(e) = state.save() {
+                    warn!(error = %e, "Failed to save renewed registration");
+                }
+                false
+            }
+            Ok(None) => {
+                warn!(resource_id = %resource_id, "Registration expired on the platform, registering again");
+                self.resource_id = None;
+                self.state = None;
+                if let Err(e) = ResourceState::forget(PRIMARY_REGISTRATION) {
+                    warn!(error = %e, "Failed to remove resource state");
+                }
+                let _ = self.register_resource().await;
+                self.resource_id.is_some()
+            }
+            Err(e) => {
+                warn!(resource_id = %resource_id, error = %e, "Failed to renew registration, will retry");
+                self.status.record_error(format!("Failed to renew registration: {}", e));
+                false
+            }
+        }
+    }
+
+    /// Re-detect instance metadata and update the registration when it drifted from the saved one
+    ///
+    /// Resizes, retagging and live migration change metadata while the agent runs.
+    async fn refresh_metadata(&mut self) {
+        if self.offline || !self.config.has_api_credentials() {
+            return;
+        }
+        let Some(state) = self.state.as_ref() else {
+            return;
+        };
+        let detected = self.detect_instance_metadata().await;
+        let drifted = state.instance_metadata.drift(&detected);
+        if drifted.is_empty() {
+            return;
+        }
+
+        let current = state.instance_metadata.refreshed(detected);
+        let resource_id = state.resource_id.clone();
+        warn!(
+            resource_id = %resource_id,
+            fields = %drifted.join(","),
+            "Instance metadata drifted since registration"
+        );
+        let registration = self.registration(current.clone());
+        match self.api_client.renew_registration(&resource_id, &registration).await {
+            Ok(Some(response)) => {
+                self.metadata_labels.retain(|key, _| !key.starts_with("tag."));
+                self.metadata_labels.extend(current.tag_labels());
+                self.metric_service.set_metadata_labels(self.metadata_labels.clone());
+                self.instance_metadata = Some(current.clone());
+                let Some(state) = self.state.as_mut() else {
+                    return;
+                };
+                state.instance_metadata = current;
+                state.renewed(response.expires_at);
+                info!(resource_id = %resource_id, "Registration updated with current instance metadata");
+                if let Err(e) = state.save() {
+                    warn!(error = %e, "Failed to save updated instance metadata");
+                }
+            }
+            // The registration check registers again
+            Ok(None) => warn!(resource_id = %resource_id, "Registration expired on the platform"),
+            Err(e) => {
+                warn!(resource_id = %resource_id, error = %e, "Failed to update instance metadata, will retry");
+                self.status.record_error(format!("Failed to update instance metadata: {}", e));
+            }
+        }
+    }
+
+    /// Keep the registrations with the additional endpoints alive, as `maintain_registration` does for the primary
+    async fn maintain_sinks(&mut self) {
+        if self.sinks.is_empty() {
+            return;
+        }
+        let instance_metadata = match self.instance_metadata.as_ref() {
+            Some(instance_metadata) => instance_metadata.clone(),
+            None => {
+                let detected = self.detect_instance_metadata().await;
+                self.instance_metadata.insert(detected).clone()
+            }
+        };
+        let registration = self.registration(instance_metadata);
+        for sink in &mut self.sinks {
+            sink.maintain(&registration, self.fingerprint.as_ref(), &self.session).await;
+        }
+    }
+
+    /// Fetch remote configuration and apply it on top of the local config
+    ///
+    /// A verified config is cached on disk; when the fetch fails before any
+    /// remote config was applied, the cached one is used instead. Returns true
+    /// when the effective configuration changed.
+    async fn refresh_remote_config(&mut self) -> bool {
+        let Some(settings) = self.local_config.remote_config.clone().filter(|r| r.enabled) else {
+            return false;
+        };
+        let cache_file = settings.get_cache_file();
+        let signing_key = settings.signing_key.as_deref();
+
+        let fetched = match self.resource_id.clone() {
+            Some(resource_id) => self
+                .api_client
+                .fetch_remote_config(&resource_id)
+                .await
+                .map_err(|e| e.to_string()),
+            None => Err("resource is not registered".to_string()),
+        };
+
+        let remote = match fetched {
+            Ok(Some(signed)) => match signed.verify(signing_key) {
+                Ok(remote) => {
+                    if let Err(e) = signed.save_cache(&cache_file) {
+                        warn!(error = %e, "Failed to cache remote config");
+                    }
+                    remote
+                }
+                Err(e) => {
+                    warn!(error = %e, "Rejected remote config");
+                    self.status.record_error(format!("Rejected remote config: {}", e));
+                    return false;
+                }
+            },
+            Ok(None) => {
+                if let Err(e) = SignedRemoteConfig::remove_cache(&cache_file) {
+                    warn!(error = %e, "Failed to remove cached remote config");
+                }
+                RemoteConfig::default()
+            }
+            Err(e) => {
+                warn!(error = %e, "Failed to fetch remote config");
+                // Keep whatever is applied; the cache only covers startup
+                if self.remote_config.is_some() {
+                    return false;
+                }
+                match SignedRemoteConfig::load_cache(&cache_file)
+                    .map(|cached| cached.map(|cached| cached.verify(signing_key)))
+                {
+                    Ok(Some(Ok(remote))) => {
+                        info!(path = %cache_file.display(), "Using cached remote config");
+                        remote
+                    }
+                    Ok(None) => return false,
+                    Ok(Some(Err(e))) | Err(e) => {
+                        warn!(error = %e, "Ignoring cached remote config");
+                        return false;
+                    }
+                }
+            }
+        };
+
+        if self.remote_config.as_ref() == Some(&remote) {
+            return false;
+        }
+
+        match remote.apply(&self.local_config) {
+            Ok(config) => {
+                info!("Applied remote configuration");
+                self.apply_config(config);
+                self.remote_config = Some(remote);
+                true
+            }
+            Err(e) => {
+                warn!(error = %e, "Ignoring invalid remote config");
+                false
+            }
+        }
+    }
+
+    fn apply_config(&mut self, mut config: Config) {
+        for warning in config.clamp_limits() {
+            warn!("Remote config: {}", warning);
+        }
+        self.metric_service = MetricService::new(&config);
+        self.metric_service.set_metadata_labels(self.metadata_labels.clone());
+        self.relabeler = build_relabeler(&config).unwrap_or_else(|e| {
+            warn!(error = %e, "Ignoring invalid relabel rules");
+            None
+        });
+        self.wasm_transforms = build_wasm_transforms(&config);
+        self.script = build_script(&config).unwrap_or_else(|e| {
+            warn!(error = %e, "Ignoring invalid transform script");
+            None
+        });
+        self.delta_filter = config
+            .collection
+            .delta
+            .as_ref()
+            .filter(|delta| delta.enabled)
+            .map(DeltaFilter::new);
+        let overflow = self
+            .buffer
+            .set_limits(config.get_batch_size(), config.get_max_buffer_bytes() as usize);
+        self.config = config;
+        self.spool_metrics(overflow);
+    }
+
+    /// Start the remote command channel if configured and the resource is registered
+    fn start_command_channel(&self) -> Option<(mpsc::Receiver<CommandEnvelope>, JoinHandle<()>)> {
+        let config = self.config.commands.as_ref().filter(|c| c.enabled)?;
+
+        let Some(resource_id) = self.resource_id.clone() else {
+            warn!("Command channel enabled but resource is not registered, skipping");
+            return None;
+        };
+
+        info!("Listening for remote commands");
+        Some(commands::spawn_command_channel(
+            self.api_client.clone(),
+            resource_id,
+            config.clone(),
+            self.status.clone(),
+        ))
+    }
+
+    /// Run a verified command, audit the outcome and report it to the platform
+    async fn execute_command(&mut self, envelope: CommandEnvelope, collection_timer: &mut JitteredTimer) {
+        let name = envelope.command.name();
+        let result = self.handle_command(envelope.command, collection_timer).await;
+
+        info!(
+            target: commands::AUDIT_TARGET,
+            command_id = %envelope.id,
+            command = name,
+            status = ?result.status,
+            error = result.error.as_deref().unwrap_or(""),
+            "Command executed"
+        );
+
+        if let Some(resource_id) = &self.resource_id {
+            if let Err(e) = self
+                .api_client
+                .post_command_result(resource_id, &envelope.id, &result)
+                .await
+            {
+                warn!(command_id = %envelope.id, error = %e, "Failed to report command result");
+            }
+        }
+    }
+
+    async fn handle_command(
+        &mut self,
+        command: AgentCommand,
+        collection_timer: &mut JitteredTimer,
+    ) -> CommandResult {
+        match command {
+            AgentCommand::FlushNow => {
+                let buffered = self.buffer.len();
+                match self.flush_buffer().await {
+                    Ok(()) => CommandResult::succeeded(Some(serde_json::json!({
+                        "buffered_metrics": buffered,
+                        "remaining_metrics": self.buffer.len(),
+                    }))),
+                    Err(e) => {
+                        error!(error = %e, "Failed to flush metrics");
+                        CommandResult::failed(e.to_string())
+                    }
+                }
+            }
+            AgentCommand::SetInterval { interval_seconds } => {
+                if interval_seconds == 0 {
+                    warn!("Ignoring set_interval command with interval of 0 seconds");
+                    return CommandResult::failed("interval_seconds must be greater than 0");
+                }
+                if interval_seconds < MIN_INTERVAL_SECONDS && !self.config.get_override_limits() {
+                    warn!(interval_seconds, "Ignoring set_interval command below the minimum interval");
+                    return CommandResult::failed(format!(
+                        "interval_seconds must be at least {}",
+                        MIN_INTERVAL_SECONDS
+                    ));
+                }
+                if interval_seconds > MAX_INTERVAL_SECONDS {
+                    warn!(interval_seconds, "Ignoring set_interval command above the maximum interval");
+                    return CommandResult::failed(format!(
+                        "interval_seconds must be at most {}",
+                        MAX_INTERVAL_SECONDS
+                    ));
+                }
+                info!(interval_seconds, "Collection interval changed");
+                self.config.collection.interval_seconds = interval_seconds;
+                *collection_timer = self.collection_timer().deferred();
+                CommandResult::succeeded(None)
+            }
+            AgentCommand::RunDiagnostic => {
+                self.log_diagnostic();
+                self.publish_status();
+                match serde_json::to_value(self.status.snapshot()) {
+                    Ok(output) => CommandResult::succeeded(Some(output)),
+                    Err(e) => CommandResult::failed(e.to_string()),
+                }
+            }
+            AgentCommand::Diagnose => {
+                let checks = diagnose::run_config_checks(&self.config).await;
+                match serde_json::to_value(checks) {
+                    Ok(output) => CommandResult::succeeded(Some(output)),
+                    Err(e) => CommandResult::failed(e.to_string()),
+                }
+            }
+            AgentCommand::CollectInventory => {
+                let instance_metadata = self.detect_instance_metadata().await;
+                let report = self.metric_service.collect_all_metrics().await;
+                if let Some(failure) = report.failures.first() {
+                    return CommandResult::failed(failure.error.to_string());
+                }
+                let disks = report.metrics;
+                CommandResult::succeeded(Some(serde_json::json!({
+                    "hostname": self.hostname,
+                    "platform": std::env::consts::OS,
+                    "arch": std::env::consts::ARCH,
+                    "build": BuildInfo::current(),
+                    "instance_metadata": instance_metadata,
+                    "disks": disks,
+                })))
+            }
+        }
+    }
+
+    fn log_diagnostic(&self) {
+        info!(
+            hostname = %self.hostname,
+            resource_id = self.resource_id.as_deref().unwrap_or("unregistered"),
+            collection_interval_seconds = self.config.collection.interval_seconds,
+            buffered_metrics = self.buffer.len(),
+            flush_paused = self.flush_paused_until.is_some(),
+            backend_unreachable_since = ?self.backend_unreachable_since.map(|since| since.to_rfc3339()),
+            "Agent diagnostic"
+        );
+    }
+
+    /// Copy current agent state into the snapshot served by the status endpoint
+    fn publish_status(&self) {
+        let spooled_batches = self.spool.as_ref().map(|spool| spool.len()).unwrap_or(0);
+        self.status.update(|status| {
+            status.registered = self.resource_id.is_some();
+            status.resource_id = self.resource_id.clone();
+            status.buffer_depth = self.buffer.len();
+            status.buffer_bytes = self.buffer.size_bytes() as usize;
+            status.buffered_series = self.samples.len();
+            status.buffer_evicted = self.buffer.evicted();
+            status.buffer_dropped = self.overflow_dropped;
+            status.spool_dropped = self.spool.as_ref().map(MetricQueue::dropped).unwrap_or(0);
+            status.spooled_batches = spooled_batches;
+            status.flush_paused = self.flush_paused_until.is_some();
+            status.maintenance = self.maintenance_since.is_some();
+            status.backend_unreachable_since =
+                self.backend_unreachable_since.map(|since| since.to_rfc3339());
+            status.clock_skew_seconds = self.clock_skew();
+            status.sinks = self
+                .sinks
+                .iter()
+                .map(|sink| (sink.name().to_string(), sink.status()))
+                .collect();
+        });
+    }
+
+    /// Start the local status endpoint unless disabled; bind failures are retried with backoff
+    fn start_status_server(&self) -> Option<JoinHandle<()>> {
+        let config = self.config.get_status();
+        if !config.is_enabled() {
+            return None;
+        }
+
+        let address = config.get_listen_address();
+        let profiling = config.profiling_enabled();
+        if profiling {
+            if let Err(e) = crate::profiling::enable_heap_sampling() {
+                warn!(error = %e, "Could not enable heap sampling");
+            }
+        }
+        let status = self.status.clone();
+        Some(supervisor::supervise("status_server", self.status.clone(), move || {
+            status::serve(address.clone(), status.clone(), profiling)
+        }))
+    }
+
+    pub async fn run(&mut self) -> Result<(), AgentError> {
+        self.run_until(shutdown_signal()).await
+    }
+
+    /// Run the agent until `shutdown` resolves, then spool anything still buffered
+    pub async fn run_until<F>(&mut self, shutdown: F) -> Result<(), AgentError>
+    where
+        F: Future<Output = ()>,
+    {
+        info!(
+            hostname = %self.hostname,
+            endpoint = %self.api_client.endpoint(),
+            collection_interval_seconds = self.config.collection.interval_seconds,
+            flush_interval_seconds = self.config.get_flush_interval_seconds(),
+            "Starting Operion Sentinel Agent"
+        );
+
+        if let Some(container) = ContainerInfo::detect() {
+            warn!(
+                runtime = ?container.runtime,
+                container_id = container.container_id.as_deref().unwrap_or("unknown"),
+                "Running in a container, disk metrics cover the container's mounts unless host filesystems are mounted in"
+            );
+        }
+
+        if let Some(pod) = self.pod().await {
+            info!(
+                namespace = %pod.namespace,
+                pod = %pod.pod_name,
+                node = pod.node_name.as_deref().unwrap_or("unknown"),
+                "Running in Kubernetes"
+            );
+            self.metadata_labels = pod.batch_labels();
+            self.metric_service.set_metadata_labels(self.metadata_labels.clone());
+        }
+
+        if self.offline {
+            info!(
+                auto_sync = self.config.get_auto_sync(),
+                "Offline mode, batches are kept in the spool until synced"
+            );
+            self.try_go_online().await;
+        } else {
+            // Connectivity preflight so problems surface before the first flush
+            self.check_backend_health().await;
+
+            // Register resource with Operion platform
+            self.register_resource().await?;
+
+            self.refresh_remote_config().await;
+        }
+
+        // Attach the resource ID to everything logged from the main loop
+        let span = info_span!(
+            "agent",
+            resource_id = self.resource_id.as_deref().unwrap_or("unregistered")
+        );
+        self.run_loop(shutdown).instrument(span).await
+    }
+
+    /// Collection ticks, optionally shifted to this host's phase so a fleet does not collect in lockstep
+    fn collection_timer(&self) -> JitteredTimer {
+        let backoff = self.pressure.as_ref().map(|p| p.backoff_factor()).unwrap_or(1);
+        let period = Duration::from_secs(self.config.collection.interval_seconds) * backoff;
+        let phase = if self.config.get_phase_offset() {
+            let key = self.resource_id.as_deref().unwrap_or(&self.hostname);
+            schedule::phase_offset(key, period, SystemTime::now())
+        } else {
+            Duration::ZERO
+        };
+        JitteredTimer::new(period, Duration::from_secs(self.config.get_jitter_seconds()), phase)
+    }
+
+    /// Flush ticks, optionally aligned to wall-clock multiples of the flush interval
+    fn flush_timer(&self) -> JitteredTimer {
+        let period = Duration::from_secs(self.config.get_flush_interval_seconds());
+        let phase = if self.config.get_align_flushes() {
+            schedule::align_offset(period, SystemTime::now())
+        } else {
+            Duration::ZERO
+        };
+        JitteredTimer::new(period, Duration::from_secs(self.config.get_jitter_seconds()), phase)
+    }
+
+    async fn run_loop<F>(&mut self, shutdown: F) -> Result<(), AgentError>
+    where
+        F: Future<Output = ()>,
+    {
+        tokio::pin!(shutdown);
+
+        let mut collection_timer = self.collection_timer();
+        let mut flush_timer = self.flush_timer();
+        debug!(
+            collection_seconds = collection_timer.period().as_secs(),
+            flush_seconds = flush_timer.period().as_secs(),
+            jitter_seconds = self.config.get_jitter_seconds(),
+            "Timers scheduled"
+        );
+        let mut health_timer = interval(Duration::from_secs(
+            self.config.get_health_check_interval_seconds(),
+        ));
+        // The preflight above already covered the first probe
+        health_timer.tick().await;
+
+        let remote_poll_seconds = self
+            .config
+            .remote_config
+            .as_ref()
+            .map(|r| r.get_poll_interval_seconds())
+            .unwrap_or(300);
+        let mut remote_config_timer = interval(Duration::from_secs(remote_poll_seconds));
+        // Remote config was already fetched during startup
+        remote_config_timer.tick().await;
+
+        // Background tasks read the published status, so make sure it is current
+        self.publish_status();
+        let (mut command_rx, command_task) = self.start_command_channel().unzip();
+        let status_task = self.start_status_server();
+        // Tasks bound to the resource ID, restarted when it changes
+        let mut resource_tasks: Vec<JoinHandle<()>> =
+            [self.start_heartbeat(), command_task].into_iter().flatten().collect();
+        // The first tick catches a registration that expired while the agent was stopped
+        let mut registration_timer = interval(REGISTRATION_CHECK_INTERVAL);
+        let metadata_refresh_seconds = self.config.get_metadata().get_refresh_interval_seconds();
+        let mut metadata_timer = interval(Duration::from_secs(metadata_refresh_seconds.max(1)));
+        // Metadata was just detected at registration
+        metadata_timer.tick().await;
+        // Collection and flushing run here rather than spawned, as they need the agent's state
+        let mut collector = InlineTask::new("collector", self.status.clone());
+        let mut flusher = InlineTask::new("flusher", self.status.clone());
+
+        loop {
+            self.publish_status();
+
+            tokio::select! {
+                _ = collection_timer.tick(), if collector.is_ready() => {
+                    if self.update_maintenance() {
+                        continue;
+                    }
+                    if self.update_pressure() {
+                        collection_timer = self.collection_timer().deferred();
+                    }
+                    let Some(metrics) = collector.run(self.collect_metrics()).await else {
+                        continue;
+                    };
+                    if !metrics.is_empty() {
+                        self.add_to_buffer(metrics);
+                    }
+                    self.enforce_memory_limit();
+                }
+                _ = flush_timer.tick(), if flusher.is_ready() => {
+                    if self.maintenance_since.is_some() {
+                        continue;
+                    }
+                    let Some(flushed) = flusher.run(self.flush_buffer()).await else {
+                        continue;
+                    };
+                    match flushed {
+                        Ok(_) => {
+                            if !self.buffer.is_empty() {
+                                debug!("Successfully flushed metrics buffer");
+                            }
+                        }
+                        Err(e) => {
+                            error!(error = %e, "Failed to flush metrics");
+                            self.status.record_error(format!("Failed to flush metrics: {}", e));
+                        }
+                    }
+                }
+                _ = health_timer.tick() => {
+                    if !self.offline {
+                        self.check_backend_health().await;
+                    } else if self.try_go_online().await {
+                        // Registration was skipped while offline, so these could not start yet
+                        let (receiver, command_task) = self.start_command_channel().unzip();
+                        command_rx = receiver;
+                        resource_tasks.extend([self.start_heartbeat(), command_task].into_iter().flatten());
+                        collection_timer = self.collection_timer().deferred();
+                        flush_timer = self.flush_timer().deferred();
+                    }
+                }
+                _ = registration_timer.tick() => {
+                    if self.maintain_registration().await {
+                        for task in resource_tasks.drain(..) {
+                            task.abort();
+                        }
+                        let (receiver, command_task) = self.start_command_channel().unzip();
+                        command_rx = receiver;
+                        resource_tasks.extend([self.start_heartbeat(), command_task].into_iter().flatten());
+                    }
+                }
+                _ = metadata_timer.tick(), if metadata_refresh_seconds > 0 => {
+                    self.refresh_metadata().await;
+                }
+                _ = remote_config_timer.tick() => {
+                    if self.refresh_remote_config().await {
+                        collection_timer = self.collection_timer().deferred();
+                        flush_timer = self.flush_timer().deferred();
+                    }
+                }
+                Some(envelope) = recv_command(&mut command_rx) => {
+                    self.execute_command(envelope, &mut collection_timer).await;
+                }
+                _ = &mut shutdown => {
+                    info!("Shutting down Operion Sentinel Agent");
+                    let remaining = self.buffer.take();
+                    self.spool_metrics(remaining);
+                    if self.config.get_deregister_on_shutdown() {
+                        self.deregister_resource().await;
+                    }
+                    for task in status_task.iter().chain(&resource_tasks) {
+                        task.abort();
+                    }
+                    if let Err(e) = self.api_client.revoke_credentials(false).await {
+                        warn!(error = %e, "Failed to revoke API access token");
+                    }
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+/// Resolve on Ctrl+C, on SIGTERM under Unix (how systemd stops the service),
+/// or on a `--force` stop request under Windows
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = sigterm.recv() => {}
+                }
+            }
+            Err(_) => {
+                let _ = tokio::signal::ctrl_c().await;
+            }
+        }
+    }
+
+    #[cfg(not(unix))]
+    {
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = crate::lock::stop_requested() => {}
+        }
+    }
+}
+
+/// Heartbeat loop run under supervision; only returns by being aborted
+async fn send_heartbeats(
+    api_client: ApiClient,
+    resource_id: String,
+    status: StatusHandle,
+    period: Duration,
+    started_at: Instant,
+    last_upgrade: Option<AgentUpgrade>,
+) -> Result<(), String> {
+    let mut timer = interval(period);
+    loop {
+        timer.tick().await;
+
+        let snapshot = status.snapshot();
+        let heartbeat = Heartbeat {
+            agent_version: env!("CARGO_PKG_VERSION").to_string(),
+            uptime_seconds: started_at.elapsed().as_secs(),
+            buffer_depth: snapshot.buffer_depth,
+            spooled_batches: snapshot.spooled_batches,
+            maintenance: snapshot.maintenance,
+            collector_failures: snapshot.collector_failures,
+            last_upgrade: last_upgrade.clone(),
+            timestamp: Utc::now().timestamp().max(0) as u64,
+        };
+
+        match api_client.send_heartbeat(&resource_id, &heartbeat).await {
+            Ok(()) => debug!(uptime_seconds = heartbeat.uptime_seconds, "Heartbeat sent"),
+            Err(e) => warn!(error = %e, "Failed to send heartbeat"),
+        }
+    }
+}
+
+/// Receive from the command channel, or wait forever when it is disabled
+async fn recv_command(
+    receiver: &mut Option<mpsc::Receiver<CommandEnvelope>>,
+) -> Option<CommandEnvelope> {
+    match receiver {
+        Some(receiver) => receiver.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+fn build_relabeler(config: &Config) -> Result<Option<Relabeler>, RelabelError> {
+    match &config.collection.relabel {
+        Some(rules) if !rules.is_empty() => Relabeler::new(rules).map(Some),
+        _ => Ok(None),
+    }
+}
+
+fn build_script(config: &Config) -> Result<Option<Script>, ScriptError> {
+    config
+        .collection
+        .script
+        .as_ref()
+        .map(|source| Script::compile("collection.script", source, &config.get_script_limits()))
+        .transpose()
+}
+
+/// Transform modules from the WASM directory; load errors are already logged by `MetricService`
+fn build_wasm_transforms(config: &Config) -> Vec<WasmModule> {
+    let Some(wasm_config) = config.get_wasm() else {
+        return Vec::new();
+    };
+    wasm::load_directory(wasm_config)
+        .0
+        .into_iter()
+        .filter(|module| module.is_transform())
+        .collect()
+}
+
+/// The saved primary registration
+///
+/// A state file that cannot be read or decrypted stops the agent: the fix is
+/// the right key or permissions, and registering anew would orphan the
+/// resource. Only a corrupt file, already moved aside, counts as no state.
+fn load_saved_state() -> Result<Option<ResourceState>, AgentError> {
+    match ResourceState::load() {
+        Err(e @ StateError::ParseError { .. }) => {
+            warn!(error = %e, "Resource state is unusable, will register a new resource");
+            Ok(None)
+        }
+        result => Ok(result?),
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum AgentError {
+    #[error("Agent initialization failed: {0}")]
+    Initialization(String),
+    #[error("Configuration error: {0}")]
     Configuration(String),
     #[error("API error: {0}")]
     Api(#[from] ApiError),
-    #[error("Metric collection error: {0}")]
-    MetricCollection(String),
+    #[error(transparent)]
+    Spool(#[from] SpoolError),
+    #[error(transparent)]
+    State(#[from] StateError),
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::collections::BTreeMap;
+    use crate::commands::CommandStatus;
     use crate::config::Config;
 
     fn create_test_config() -> Config {
@@ -276,12 +1861,924 @@ collection:
                 used_space_bytes: 500000,
                 available_space_bytes: 500000,
                 usage_percentage: 50.0,
+                labels: BTreeMap::new(),
+                aggregate: None,
+                severity: None,
             };
             10
         ];
 
         agent.add_to_buffer(metrics);
         assert_eq!(agent.buffer.len(), 5);
+        // Without a spool the pushed out metrics are lost and counted as such
+        assert!(agent.spool.is_none());
+        assert_eq!(agent.buffer.evicted(), 5);
+        assert_eq!(agent.overflow_dropped, 5);
+    }
+
+    fn create_large_metric() -> DiskMetric {
+        DiskMetric {
+            timestamp: 1234567890,
+            device: "/dev/sda1".to_string(),
+            mount_point: format!("/{}", "x".repeat(1024)),
+            total_space_bytes: 1000000,
+            used_space_bytes: 500000,
+            available_space_bytes: 500000,
+            usage_percentage: 0.5,
+            labels: BTreeMap::new(),
+            aggregate: None,
+            severity: None,
+        }
+    }
+
+    #[test]
+    fn test_buffer_byte_limit() {
+        let mut config = create_test_config();
+        config.collection.batch_size = Some(100_000);
+        config.collection.max_buffer_size_mb = Some(1);
+        let mut agent = SentinelAgent::new(config).unwrap();
+
+        agent.add_to_buffer(vec![create_large_metric(); 2000]);
+        assert!(agent.buffer.size_bytes() <= 1024 * 1024);
+        assert!(agent.buffer.len() > 500 && agent.buffer.len() < 2000);
+    }
+
+    #[test]
+    fn test_memory_limit_evicts_buffer() {
+        let mut config = create_test_config();
+        config.limits = Some(crate::config::LimitsConfig {
+            max_memory_mb: Some(1),
+            ..Default::default()
+        });
+        let mut agent = SentinelAgent::new(config).unwrap();
+
+        agent.add_to_buffer(vec![create_large_metric(); 4]);
+        agent.enforce_memory_limit();
+        assert!(agent.status.snapshot().memory_bytes.is_some());
+
+        // Over the limit because of the buffer: the older half goes
+        let buffered = agent.buffer.size_bytes();
+        agent.shed_buffer_for_memory(1000 + buffered, 1000);
+        assert_eq!(agent.buffer.len(), 2);
+
+        // Over the limit without it: evicting cannot help, so nothing goes
+        let buffered = agent.buffer.size_bytes();
+        agent.shed_buffer_for_memory(2000 + buffered, 1000);
+        assert_eq!(agent.buffer.len(), 2);
+        assert!(agent.memory_limit_unreachable);
+    }
+
+    #[tokio::test]
+    async fn test_flush_rate_limited_keeps_metrics_and_pauses() {
+        use wiremock::matchers::{method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let mock_server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/api/v1/metrics"))
+            .respond_with(ResponseTemplate::new(429).insert_header("Retry-After", "60"))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let config = Config::load_from_str(&format!(r#"
+agent:
+  id: "test-agent"
+api:
+  endpoint: "{}"
+collection:
+  interval_seconds: 60
+  batch_size: 5
+  disk:
+    enabled: true
+"#, mock_server.uri())).unwrap();
+        let mut agent = SentinelAgent::new(config).unwrap();
+
+        let metric = DiskMetric {
+            timestamp: 1234567890,
+            device: "/dev/sda1".to_string(),
+            mount_point: "/".to_string(),
+            total_space_bytes: 1000000,
+            used_space_bytes: 500000,
+            available_space_bytes: 500000,
+            usage_percentage: 50.0,
+            labels: BTreeMap::new(),
+            aggregate: None,
+            severity: None,
+        };
+        agent.add_to_buffer(vec![metric; 3]);
+
+        assert!(agent.flush_buffer().await.is_ok());
+        assert_eq!(agent.buffer.len(), 3);
+        assert!(agent.flush_paused_until.is_some());
+
+        // A second flush during the pause must not hit the API
+        assert!(agent.flush_buffer().await.is_ok());
+        assert_eq!(agent.buffer.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_flush_aggregates_buffered_samples() {
+        use wiremock::matchers::{body_partial_json, method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let mock_server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/api/v1/metrics"))
+            .and(body_partial_json(serde_json::json!({
+                "metrics": [{"mount_point": "/", "aggregate": {"samples": 3}}]
+            })))
+            .respond_with(ResponseTemplate::new(429).insert_header("Retry-After", "60"))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let mut config = create_test_config();
+        config.api.endpoint = mock_server.uri();
+        config.collection.aggregate = Some(true);
+        let mut agent = SentinelAgent::new(config).unwrap();
+        let mut metric = create_large_metric();
+        metric.mount_point = "/".to_string();
+        agent.add_to_buffer(vec![metric; 3]);
+
+        assert!(agent.flush_buffer().await.is_ok());
+        // Re-queued as a single rolled-up metric
+        assert_eq!(agent.buffer.len(), 1);
+        assert_eq!(agent.buffer[0].aggregate.as_ref().unwrap().samples, 3);
+    }
+
+    #[tokio::test]
+    async fn test_textfile_samples_flushed_with_batch() {
+        use wiremock::matchers::{body_partial_json, method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let mock_server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/api/v1/metrics"))
+            .and(body_partial_json(serde_json::json!({
+                "metrics": [],
+                "samples": [{"name": "backup_age_seconds", "labels": {"job": "db"}, "value": 42.0, "kind": "gauge"}]
+            })))
+            .respond_with(ResponseTemplate::new(500))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(
+            dir.path().join("backup.prom"),
+            "# TYPE backup_age_seconds gauge\nbackup_age_seconds{job=\"db\"} 42\n",
+        )
+        .unwrap();
+        let mut config = create_test_config();
+        config.api.endpoint = mock_server.uri();
+        config.collection.disk.enabled = false;
+        config.collection.textfile = Some(crate::config::TextfileConfig {
+            enabled: true,
+            directory: dir.path().to_path_buf(),
+        });
+        let mut agent = SentinelAgent::new(config).unwrap();
+
+        assert!(agent.collect_metrics().await.is_empty());
+        // The file's sample, its mtime and the scrape error gauge
+        assert_eq!(agent.samples.len(), 3);
+        agent.collect_metrics().await;
+        assert_eq!(agent.samples.len(), 3);
+
+        // Unsent samples are kept for the next flush
+        assert!(agent.flush_buffer().await.is_err());
+        assert_eq!(agent.samples.len(), 3);
+    }
+
+    #[test]
+    fn test_handle_ack_requeues_only_retryable() {
+        let config = create_test_config();
+        let mut agent = SentinelAgent::new(config).unwrap();
+
+        let metrics: Vec<DiskMetric> = ["/", "/home", "/var"]
+            .iter()
+            .map(|mount_point| DiskMetric {
+                timestamp: 1234567890,
+                device: "/dev/sda1".to_string(),
+                mount_point: mount_point.to_string(),
+                total_space_bytes: 1000000,
+                used_space_bytes: 500000,
+                available_space_bytes: 500000,
+                usage_percentage: 50.0,
+                labels: BTreeMap::new(),
+                aggregate: None,
+                severity: None,
+            })
+            .collect();
+
+        let ack: MetricsAck = serde_json::from_value(serde_json::json!({
+            "accepted": [0],
+            "rejected": [
+                {"index": 1, "reason": "timeout", "retryable": true},
+                {"index": 2, "reason": "schema violation", "retryable": false},
+                {"index": 7, "reason": "bogus index"}
+            ]
+        }))
+        .unwrap();
+
+        agent.handle_ack(ack, metrics);
+        assert_eq!(agent.buffer.len(), 1);
+        assert_eq!(agent.buffer[0].mount_point, "/home");
+    }
+
+    #[tokio::test]
+    async fn test_backend_health_tracking() {
+        use wiremock::matchers::{method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let mock_server = MockServer::start().await;
+        let config = Config::load_from_str(&format!(r#"
+agent:
+  id: "test-agent"
+api:
+  endpoint: "{}"
+collection:
+  interval_seconds: 60
+  disk:
+    enabled: true
+"#, mock_server.uri())).unwrap();
+        let mut agent = SentinelAgent::new(config).unwrap();
+
+        Mock::given(method("GET"))
+            .and(path("/health"))
+            .respond_with(ResponseTemplate::new(500))
+            .up_to_n_times(2)
+            .mount(&mock_server)
+            .await;
+
+        agent.check_backend_health().await;
+        let since = agent.backend_unreachable_since.expect("should be unreachable");
+
+        // Repeated failures keep the original timestamp
+        agent.check_backend_health().await;
+        assert_eq!(agent.backend_unreachable_since, Some(since));
+
+        Mock::given(method("GET"))
+            .and(path("/health"))
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&mock_server)
+            .await;
+
+        agent.check_backend_health().await;
+        assert!(agent.backend_unreachable_since.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_maintenance_window_tags_next_batch() {
+        use wiremock::matchers::{body_partial_json, method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let mock_server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/api/v1/metrics"))
+            .and(body_partial_json(serde_json::json!({"maintenance": {}})))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let dir = tempfile::tempdir().unwrap();
+        let maintenance_file = dir.path().join("maintenance");
+        let config = Config::load_from_str(&format!(r#"
+agent:
+  hostname: "test-host"
+  maintenance_file: "{}"
+api:
+  endpoint: "{}"
+collection:
+  interval_seconds: 60
+  disk:
+    enabled: true
+"#, maintenance_file.display(), mock_server.uri())).unwrap();
+        let mut agent = SentinelAgent::new(config).unwrap();
+
+        assert!(!agent.update_maintenance());
+
+        std::fs::write(&maintenance_file, "").unwrap();
+        assert!(agent.update_maintenance());
+        assert!(agent.maintenance_since.is_some());
+
+        std::fs::remove_file(&maintenance_file).unwrap();
+        assert!(!agent.update_maintenance());
+        assert!(agent.pending_maintenance.is_some());
+
+        agent.add_to_buffer(vec![DiskMetric {
+            timestamp: 1234567890,
+            device: "/dev/sda1".to_string(),
+            mount_point: "/".to_string(),
+            total_space_bytes: 1000000,
+            used_space_bytes: 500000,
+            available_space_bytes: 500000,
+            usage_percentage: 0.5,
+            labels: BTreeMap::new(),
+            aggregate: None,
+            severity: None,
+        }]);
+        agent.flush_buffer().await.unwrap();
+        assert!(agent.pending_maintenance.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_deregister_failure_keeps_state() {
+        use wiremock::matchers::{method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let mock_server = MockServer::start().await;
+        Mock::given(method("DELETE"))
+            .and(path("/api/v1/resources/res_123"))
+            .respond_with(ResponseTemplate::new(500))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let config = Config::load_from_str(&format!(r#"
+agent:
+  hostname: "test-host"
+  deregister_on_shutdown: true
+api:
+  endpoint: "{}"
+collection:
+  interval_seconds: 60
+  disk:
+    enabled: true
+"#, mock_server.uri())).unwrap();
+        assert!(config.get_deregister_on_shutdown());
+
+        let state_dir = tempfile::tempdir().unwrap();
+        crate::paths::use_test_state_dir(state_dir.path());
+        let mut agent = SentinelAgent::new(config).unwrap();
+        agent.resource_id = Some("res_123".to_string());
+        let state = ResourceState::new(
+            "res_123".to_string(),
+            "0.3.2".to_string(),
+            InstanceMetadata::default(),
+            SessionInfo::generate(),
+        );
+        state.save().unwrap();
+
+        // The platform rejected the call, so the local registration is left in place
+        agent.deregister_resource().await;
+        assert!(agent.resource_id.is_none());
+        assert!(ResourceState::get_state_file_path().exists());
+        assert_eq!(ResourceState::load().unwrap().unwrap().resource_id, "res_123");
+    }
+
+    #[tokio::test]
+    async fn test_new_ignores_checkpoint_of_mismatched_state() {
+        let config = Config::load_from_str(r#"
+agent:
+  hostname: "test-host"
+api:
+  endpoint: "http://127.0.0.1:1"
+  api_key: "test-key"
+collection:
+  interval_seconds: 60
+  disk:
+    enabled: true
+"#).unwrap();
+
+        let state_dir = tempfile::tempdir().unwrap();
+        crate::paths::use_test_state_dir(state_dir.path());
+        let mut state = ResourceState::new(
+            "res_123".to_string(),
+            "0.3.2".to_string(),
+            InstanceMetadata::default(),
+            SessionInfo::generate(),
+        );
+        state.checkpoint = Some(FlushCheckpoint::advance(None, 1234567890, None));
+        state.save().unwrap();
+        let agent = SentinelAgent::new(config.clone()).unwrap();
+        assert!(agent.checkpoint.is_some());
+        assert!(agent.pending_gap.is_some());
+
+        // Saved under another agent key, as on a cloned disk whose key was regenerated
+        state.public_key = Some("another-key".to_string());
+        state.save().unwrap();
+        let agent = SentinelAgent::new(config).unwrap();
+        assert!(agent.checkpoint.is_none());
+        assert!(agent.pending_gap.is_none());
+    }
+
+    #[test]
+    fn test_new_fails_on_unreadable_state() {
+        let config = create_test_config();
+        let state_dir = tempfile::tempdir().unwrap();
+        crate::paths::use_test_state_dir(state_dir.path());
+        let key = crate::encryption::ConfigKey::parse(&crate::encryption::ConfigKey::generate()).unwrap();
+        std::fs::write(ResourceState::get_state_file_path(), key.encrypt("{}")).unwrap();
+
+        // Without the state key the registration cannot be told apart from none
+        assert!(matches!(
+            SentinelAgent::new(config),
+            Err(AgentError::State(StateError::Encryption { .. }))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_new_regenerates_key_copied_from_another_machine() {
+        let Some(current) = MachineFingerprint::detect() else {
+            return;
+        };
+        let config = Config::load_from_str(r#"
+agent:
+  hostname: "test-host"
+api:
+  endpoint: "http://127.0.0.1:1"
+  api_key: "test-key"
+collection:
+  interval_seconds: 60
+  disk:
+    enabled: true
+"#).unwrap();
+
+        let state_dir = tempfile::tempdir().unwrap();
+        crate::paths::use_test_state_dir(state_dir.path());
+        let original_key = SentinelAgent::new(config.clone()).unwrap().api_client.public_key().unwrap();
+        let mut state = ResourceState::new(
+            "res_123".to_string(),
+            "0.3.2".to_string(),
+            InstanceMetadata::default(),
+            SessionInfo::generate(),
+        );
+        state.public_key = Some(original_key.clone());
+        state.fingerprint = Some(current.clone());
+        state.save().unwrap();
+
+        // Our own registration keeps its key
+        let agent = SentinelAgent::new(config.clone()).unwrap();
+        assert_eq!(agent.api_client.public_key(), Some(original_key.clone()));
+
+        // Registered by the machine the disk was cloned from
+        state.fingerprint = Some(MachineFingerprint {
+            machine_id: current.machine_id.as_ref().map(|_| "other".to_string()),
+            dmi_uuid: current.dmi_uuid.as_ref().map(|_| "other".to_string()),
+            mac: current.mac.as_ref().map(|_| "other".to_string()),
+        });
+        state.save().unwrap();
+        let agent = SentinelAgent::new(config).unwrap();
+        let new_key = agent.api_client.public_key().unwrap();
+        assert_ne!(new_key, original_key);
+        assert_eq!(
+            AgentIdentity::load_or_generate_in_state_dir().unwrap().public_key(),
+            new_key
+        );
+    }
+
+    #[tokio::test]
+    async fn test_send_heartbeat_requires_registration() {
+        use wiremock::matchers::{method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let mock_server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/api/v1/resources/res_123/heartbeat"))
+            .respond_with(ResponseTemplate::new(204))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let config = Config::load_from_str(&format!(r#"
+agent:
+  hostname: "test-host"
+api:
+  endpoint: "{}"
+collection:
+  interval_seconds: 60
+  disk:
+    enabled: true
+"#, mock_server.uri())).unwrap();
+        let mut agent = SentinelAgent::new(config).unwrap();
+
+        // Not registered yet: nothing is sent
+        assert!(agent.start_heartbeat().is_none());
+
+        agent.resource_id = Some("res_123".to_string());
+        let task = agent.start_heartbeat().unwrap();
+        for _ in 0..50 {
+            if !mock_server.received_requests().await.unwrap().is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        task.abort();
+    }
+
+    #[tokio::test]
+    async fn test_handle_set_interval_command() {
+        let config = create_test_config();
+        let mut agent = SentinelAgent::new(config).unwrap();
+        let mut timer = agent.collection_timer();
+
+        let result = agent
+            .handle_command(AgentCommand::SetInterval { interval_seconds: 15 }, &mut timer)
+            .await;
+        assert_eq!(result.status, CommandStatus::Succeeded);
+        assert_eq!(agent.config.collection.interval_seconds, 15);
+        assert_eq!(timer.period(), Duration::from_secs(15));
+
+        let result = agent
+            .handle_command(AgentCommand::SetInterval { interval_seconds: 0 }, &mut timer)
+            .await;
+        assert_eq!(result.status, CommandStatus::Failed);
+        assert_eq!(agent.config.collection.interval_seconds, 15);
+
+        let result = agent
+            .handle_command(AgentCommand::SetInterval { interval_seconds: 1 }, &mut timer)
+            .await;
+        assert_eq!(result.status, CommandStatus::Failed);
+        assert_eq!(agent.config.collection.interval_seconds, 15);
+
+        let result = agent
+            .handle_command(
+                AgentCommand::SetInterval { interval_seconds: MAX_INTERVAL_SECONDS + 1 },
+                &mut timer,
+            )
+            .await;
+        assert_eq!(result.status, CommandStatus::Failed);
+        assert_eq!(agent.config.collection.interval_seconds, 15);
+    }
+
+    #[tokio::test]
+    async fn test_execute_command_reports_result() {
+        use wiremock::matchers::{body_partial_json, method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let mock_server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/api/v1/resources/res_123/commands/cmd_1/result"))
+            .and(body_partial_json(serde_json::json!({
+                "status": "succeeded",
+                "output": {"hostname": "test-host"}
+            })))
+            .respond_with(ResponseTemplate::new(204))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let config = Config::load_from_str(&format!(r#"
+agent:
+  hostname: "test-host"
+api:
+  endpoint: "{}"
+collection:
+  interval_seconds: 60
+  disk:
+    enabled: true
+"#, mock_server.uri())).unwrap();
+        let mut agent = SentinelAgent::new(config).unwrap();
+        agent.resource_id = Some("res_123".to_string());
+        let mut timer = agent.collection_timer();
+
+        let envelope = CommandEnvelope {
+            id: "cmd_1".to_string(),
+            resource_id: "res_123".to_string(),
+            issued_at: 0,
+            command: AgentCommand::RunDiagnostic,
+        };
+        agent.execute_command(envelope, &mut timer).await;
+    }
+
+    #[tokio::test]
+    async fn test_refresh_remote_config() {
+        use wiremock::matchers::{method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let dir = tempfile::tempdir().unwrap();
+        let cache_file = dir.path().join("remote-config.json");
+
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/api/v1/agents/res_123/config"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "interval_seconds": 15,
+                "batch_size": 50
+            })))
+            .mount(&mock_server)
+            .await;
+
+        let config = Config::load_from_str(&format!(r#"
+agent:
+  id: "test-agent"
+api:
+  endpoint: "{}"
+collection:
+  interval_seconds: 60
+  disk:
+    enabled: true
+remote_config:
+  enabled: true
+  cache_file: "{}"
+"#, mock_server.uri(), cache_file.display())).unwrap();
+        let mut agent = SentinelAgent::new(config).unwrap();
+        agent.resource_id = Some("res_123".to_string());
+
+        assert!(agent.refresh_remote_config().await);
+        assert_eq!(agent.config.collection.interval_seconds, 15);
+        assert_eq!(agent.config.get_batch_size(), 50);
+        assert_eq!(agent.local_config.collection.interval_seconds, 60);
+        assert!(cache_file.exists());
+
+        // Unchanged remote config is not re-applied
+        assert!(!agent.refresh_remote_config().await);
+    }
+
+    #[tokio::test]
+    async fn test_remote_config_signature_and_cache_fallback() {
+        use hmac::{Hmac, Mac};
+        use wiremock::matchers::{method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let payload = r#"{"interval_seconds":20}"#;
+        let mut mac = Hmac::<sha2::Sha256>::new_from_slice(b"platform-secret").unwrap();
+        mac.update(payload.as_bytes());
+        let signature = hex::encode(mac.finalize().into_bytes());
+
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/api/v1/agents/res_good/config"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_string(payload)
+                    .insert_header(crate::remote_config::SIGNATURE_HEADER, signature.as_str()),
+            )
+            .mount(&mock_server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/api/v1/agents/res_forged/config"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_string(r#"{"interval_seconds":1}"#)
+                    .insert_header(crate::remote_config::SIGNATURE_HEADER, signature.as_str()),
+            )
+            .mount(&mock_server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/api/v1/agents/res_down/config"))
+            .respond_with(ResponseTemplate::new(503))
+            .mount(&mock_server)
+            .await;
+
+        let dir = tempfile::tempdir().unwrap();
+        let cache_file = dir.path().join("remote-config.json");
+        let new_agent = |resource_id: &str| {
+            let config = Config::load_from_str(&format!(r#"
+agent:
+  hostname: "test-host"
+api:
+  endpoint: "{}"
+collection:
+  interval_seconds: 60
+  disk:
+    enabled: true
+remote_config:
+  enabled: true
+  signing_key: "platform-secret"
+  cache_file: "{}"
+"#, mock_server.uri(), cache_file.display())).unwrap();
+            let mut agent = SentinelAgent::new(config).unwrap();
+            agent.resource_id = Some(resource_id.to_string());
+            agent
+        };
+
+        // A config that does not match its signature is never applied or cached
+        let mut agent = new_agent("res_forged");
+        assert!(!agent.refresh_remote_config().await);
+        assert_eq!(agent.config.collection.interval_seconds, 60);
+        assert!(!cache_file.exists());
+
+        let mut agent = new_agent("res_good");
+        assert!(agent.refresh_remote_config().await);
+        assert_eq!(agent.config.collection.interval_seconds, 20);
+
+        // After a restart with the platform unreachable, the cached config applies
+        let mut agent = new_agent("res_down");
+        assert!(agent.refresh_remote_config().await);
+        assert_eq!(agent.config.collection.interval_seconds, 20);
+    }
+
+    #[tokio::test]
+    async fn test_failed_flush_spools_and_replays() {
+        use wiremock::matchers::{method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let mock_server = MockServer::start().await;
+        let spool_dir = tempfile::tempdir().unwrap();
+        let config = Config::load_from_str(&format!(r#"
+agent:
+  id: "test-agent"
+api:
+  endpoint: "{}"
+collection:
+  interval_seconds: 60
+  disk:
+    enabled: true
+  spool:
+    enabled: true
+    directory: "{}"
+"#, mock_server.uri(), spool_dir.path().display())).unwrap();
+        let mut agent = SentinelAgent::new(config).unwrap();
+
+        let metric = DiskMetric {
+            timestamp: 1234567890,
+            device: "/dev/sda1".to_string(),
+            mount_point: "/".to_string(),
+            total_space_bytes: 1000000,
+            used_space_bytes: 500000,
+            available_space_bytes: 500000,
+            usage_percentage: 50.0,
+            labels: BTreeMap::new(),
+            aggregate: None,
+            severity: None,
+        };
+
+        Mock::given(method("POST"))
+            .and(path("/api/v1/metrics"))
+            .respond_with(ResponseTemplate::new(500))
+            .up_to_n_times(1)
+            .mount(&mock_server)
+            .await;
+
+        agent.add_to_buffer(vec![metric.clone(); 2]);
+        assert!(agent.flush_buffer().await.is_err());
+        assert_eq!(agent.spool.as_ref().unwrap().len(), 1);
+
+        Mock::given(method("POST"))
+            .and(path("/api/v1/metrics"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(2)
+            .mount(&mock_server)
+            .await;
+
+        agent.add_to_buffer(vec![metric]);
+        assert!(agent.flush_buffer().await.is_ok());
+        assert!(agent.spool.as_ref().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_offline_spools_compressed_and_sync_uploads() {
+        use wiremock::matchers::{body_partial_json, method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let mock_server = MockServer::start().await;
+        let spool_dir = tempfile::tempdir().unwrap();
+        let config = Config::load_from_str(&format!(r#"
+agent:
+  id: "test-agent"
+api:
+  endpoint: "{}"
+collection:
+  interval_seconds: 60
+  disk:
+    enabled: true
+  spool:
+    enabled: true
+    directory: "{}"
+offline:
+  enabled: true
+"#, mock_server.uri(), spool_dir.path().display())).unwrap();
+        let mut agent = SentinelAgent::new(config).unwrap();
+
+        let metric = DiskMetric {
+            timestamp: 1234567890,
+            device: "/dev/sda1".to_string(),
+            mount_point: "/".to_string(),
+            total_space_bytes: 1000000,
+            used_space_bytes: 500000,
+            available_space_bytes: 500000,
+            usage_percentage: 50.0,
+            labels: BTreeMap::new(),
+            aggregate: None,
+            severity: None,
+        };
+
+        Mock::given(method("POST"))
+            .and(path("/api/v1/metrics"))
+            .and(body_partial_json(serde_json::json!({
+                "replayed": true,
+                "metrics": [{"timestamp": 1234567890}]
+            })))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(2)
+            .mount(&mock_server)
+            .await;
+
+        // Nothing is sent while offline
+        for _ in 0..2 {
+            agent.add_to_buffer(vec![metric.clone()]);
+            assert!(agent.flush_buffer().await.is_ok());
+        }
+        assert_eq!(agent.spool.as_ref().unwrap().len(), 2);
+        assert!(std::fs::read_dir(spool_dir.path())
+            .unwrap()
+            .all(|entry| entry.unwrap().path().to_string_lossy().ends_with(".jsonl.gz")));
+
+        Mock::given(method("GET"))
+            .and(path("/health"))
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&mock_server)
+            .await;
+
+        assert_eq!(agent.sync().await.unwrap(), 2);
+        assert!(agent.spool.as_ref().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_spool_only_after_consecutive_failures() {
+        use wiremock::matchers::{method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let mock_server = MockServer::start().await;
+        let spool_dir = tempfile::tempdir().unwrap();
+        let config = Config::load_from_str(&format!(r#"
+agent:
+  id: "test-agent"
+api:
+  endpoint: "{}"
+collection:
+  interval_seconds: 60
+  disk:
+    enabled: true
+  spool:
+    enabled: true
+    directory: "{}"
+    after_failures: 2
+"#, mock_server.uri(), spool_dir.path().display())).unwrap();
+        let mut agent = SentinelAgent::new(config).unwrap();
+
+        Mock::given(method("POST"))
+            .and(path("/api/v1/metrics"))
+            .respond_with(ResponseTemplate::new(500))
+            .mount(&mock_server)
+            .await;
+
+        agent.add_to_buffer(vec![create_large_metric()]);
+        assert!(agent.flush_buffer().await.is_err());
+        assert_eq!(agent.buffer.len(), 1);
+        assert!(agent.spool.as_ref().unwrap().is_empty());
+
+        assert!(agent.flush_buffer().await.is_err());
+        assert!(agent.buffer.is_empty());
+        assert_eq!(agent.spool.as_ref().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_checkpoint_skips_delivered_segments_and_reports_gap() {
+        use wiremock::matchers::{body_partial_json, method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let mock_server = MockServer::start().await;
+        let spool_dir = tempfile::tempdir().unwrap();
+        let config = Config::load_from_str(&format!(r#"
+agent:
+  id: "test-agent"
+api:
+  endpoint: "{}"
+collection:
+  interval_seconds: 60
+  disk:
+    enabled: true
+  spool:
+    enabled: true
+    directory: "{}"
+"#, mock_server.uri(), spool_dir.path().display())).unwrap();
+        let mut agent = SentinelAgent::new(config).unwrap();
+
+        // Segment 0 was acknowledged just before a crash, segment 1 never was
+        let spool = agent.spool.as_mut().unwrap();
+        spool.append(&[create_large_metric()]).unwrap();
+        spool.append(&[create_large_metric()]).unwrap();
+        let checkpoint = FlushCheckpoint::advance(None, 1234567890, Some(0));
+        agent.pending_gap = Some(checkpoint.gap_until(1234568190));
+        agent.checkpoint = Some(checkpoint);
+
+        Mock::given(method("POST"))
+            .and(path("/api/v1/metrics"))
+            .and(body_partial_json(serde_json::json!({"gap": {"seconds": 300}})))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/api/v1/metrics"))
+            .and(body_partial_json(serde_json::json!({"replayed": true})))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        agent.add_to_buffer(vec![create_large_metric()]);
+        assert!(agent.flush_buffer().await.is_ok());
+        assert!(agent.spool.as_ref().unwrap().is_empty());
+        assert!(agent.pending_gap.is_none());
+
+        let checkpoint = agent.checkpoint.as_ref().unwrap();
+        assert_eq!(checkpoint.sequence, 3);
+        assert_eq!(checkpoint.spool_segment, Some(1));
     }
 
     #[tokio::test]
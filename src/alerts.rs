@@ -0,0 +1,596 @@
+use serde::Serialize;
+use std::collections::HashSet;
+use std::time::Duration;
+use tracing::{debug, info, warn};
+
+use crate::config::{AlertAction, AlertMetric, AlertRule, AlertsConfig, Severity, ThresholdsConfig};
+use crate::metrics::DiskMetric;
+use crate::scripting::{Script, ScriptLimits};
+
+/// Default time an exec or webhook action may take
+const DEFAULT_ACTION_TIMEOUT_SECONDS: u64 = 30;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AlertState {
+    Firing,
+    Resolved,
+}
+
+/// A threshold crossing, passed to every action of the rule
+#[derive(Debug, Clone, Serialize)]
+pub struct AlertEvent {
+    pub rule: String,
+    pub metric: &'static str,
+    /// What crossed the threshold, e.g. a mount point
+    pub subject: String,
+    pub value: f64,
+    pub threshold: f64,
+    /// Level reached against `thresholds`, if the metric has one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub severity: Option<Severity>,
+    pub state: AlertState,
+    pub hostname: String,
+    pub timestamp: u64,
+    /// Rendered by the rule's `message` script, if it has one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+}
+
+/// A rule with its scripts compiled
+struct CompiledRule {
+    rule: AlertRule,
+    /// `threshold`, or the level of `thresholds` that `severity` refers to
+    threshold: f64,
+    condition: Option<Script>,
+    message: Option<Script>,
+}
+
+/// Tracks which rules are firing so actions run once per crossing, not on every collection
+pub struct AlertEvaluator {
+    rules: Vec<CompiledRule>,
+    hostname: String,
+    firing: HashSet<(String, String)>,
+    http: reqwest::Client,
+}
+
+impl AlertEvaluator {
+    /// `http` sends webhooks; it should carry the `api.tls` settings
+    pub fn new(
+        config: &AlertsConfig,
+        thresholds: Option<&ThresholdsConfig>,
+        hostname: String,
+        limits: &ScriptLimits,
+        http: reqwest::Client,
+    ) -> Self {
+        // Scripts were compiled once already during config validation
+        let compile = |rule: &AlertRule, kind: &str, source: &Option<String>| {
+            let name = format!("alert rule {} {}", rule.name, kind);
+            source.as_ref().and_then(|source| match Script::compile(&name, source, limits) {
+                Ok(script) => Some(script),
+                Err(e) => {
+                    warn!(error = %e, "Ignoring alert script");
+                    None
+                }
+            })
+        };
+        let rules = config
+            .rules
+            .iter()
+            .map(|rule| CompiledRule {
+                // Validation guarantees a severity rule's level exists
+                threshold: rule.threshold.unwrap_or_else(|| {
+                    rule.severity
+                        .and_then(|severity| thresholds?.get(rule.metric)?.level(severity))
+                        .unwrap_or(f64::INFINITY)
+                }),
+                condition: compile(rule, "condition", &rule.condition),
+                message: compile(rule, "message", &rule.message),
+                rule: rule.clone(),
+            })
+            .collect();
+
+        Self {
+            rules,
+            hostname,
+            firing: HashSet::new(),
+            http,
+        }
+    }
+
+    pub fn has_rules_for(&self, metric: AlertMetric) -> bool {
+        self.rules.iter().any(|compiled| compiled.rule.metric == metric)
+    }
+
+    /// Compare samples (subject, value and severity) against every rule for
+    /// `metric`, returning crossings and the actions to run
+    pub fn evaluate(
+        &mut self,
+        metric: AlertMetric,
+        samples: &[(String, f64, Option<Severity>)],
+    ) -> Vec<(AlertEvent, Vec<AlertAction>)> {
+        let mut crossings = Vec::new();
+
+        for compiled in self.rules.iter().filter(|compiled| compiled.rule.metric == metric) {
+            let rule = &compiled.rule;
+            for (subject, value, severity) in samples {
+                if let Some(allowed) = &rule.mount_points {
+                    if !allowed.contains(subject) {
+                        continue;
+                    }
+                }
+
+                let key = (rule.name.clone(), subject.clone());
+                let threshold = compiled.threshold;
+                let fires = match (&compiled.condition, rule.severity) {
+                    (_, Some(level)) => severity.is_some_and(|severity| severity >= level),
+                    (Some(script), None) => script.condition(subject, *value, threshold).unwrap_or_else(|e| {
+                        warn!(error = %e, "Alert condition failed, comparing against the threshold");
+                        *value > threshold
+                    }),
+                    (None, None) => *value > threshold,
+                };
+                let state = if fires {
+                    if !self.firing.insert(key) {
+                        continue;
+                    }
+                    AlertState::Firing
+                } else {
+                    if !self.firing.remove(&key) {
+                        continue;
+                    }
+                    AlertState::Resolved
+                };
+
+                let mut event = AlertEvent {
+                    rule: rule.name.clone(),
+                    metric: metric_name(metric),
+                    subject: subject.clone(),
+                    value: *value,
+                    threshold,
+                    severity: *severity,
+                    state,
+                    hostname: self.hostname.clone(),
+                    timestamp: chrono::Utc::now().timestamp().max(0) as u64,
+                    message: None,
+                };
+                if let Some(script) = &compiled.message {
+                    match script.format(&event) {
+                        Ok(message) => event.message = Some(message),
+                        Err(e) => warn!(error = %e, "Alert message script failed"),
+                    }
+                }
+
+                match state {
+                    AlertState::Firing => warn!(
+                        rule = %event.rule,
+                        subject = %event.subject,
+                        value = event.value,
+                        threshold = event.threshold,
+                        "Alert firing"
+                    ),
+                    AlertState::Resolved => info!(
+                        rule = %event.rule,
+                        subject = %event.subject,
+                        value = event.value,
+                        "Alert resolved"
+                    ),
+                }
+
+                if state == AlertState::Firing || rule.get_on_resolve() {
+                    crossings.push((event, rule.actions.clone()));
+                }
+            }
+        }
+
+        crossings
+    }
+
+    pub fn evaluate_disks(&mut self, metrics: &[DiskMetric]) -> Vec<(AlertEvent, Vec<AlertAction>)> {
+        let samples: Vec<(String, f64, Option<Severity>)> = metrics
+            .iter()
+            .map(|metric| (metric.mount_point.clone(), metric.usage_percentage, metric.severity))
+            .collect();
+        self.evaluate(AlertMetric::DiskUsage, &samples)
+    }
+
+    /// Run the actions in the background so slow hooks never stall collection
+    pub fn dispatch(&self, event: AlertEvent, actions: Vec<AlertAction>) {
+        let http = self.http.clone();
+        tokio::spawn(async move {
+            for action in &actions {
+                if let Err(e) = run_action(&http, action, &event).await {
+                    warn!(rule = %event.rule, error = %e, "Alert action failed");
+                }
+            }
+        });
+    }
+}
+
+fn metric_name(metric: AlertMetric) -> &'static str {
+    match metric {
+        AlertMetric::DiskUsage => "disk_usage",
+        AlertMetric::FailedSystemdUnits => "failed_systemd_units",
+    }
+}
+
+async fn run_action(
+    http: &reqwest::Client,
+    action: &AlertAction,
+    event: &AlertEvent,
+) -> Result<(), AlertError> {
+    match action {
+        AlertAction::Exec {
+            command,
+            args,
+            timeout_seconds,
+        } => {
+            let timeout =
+                Duration::from_secs(timeout_seconds.unwrap_or(DEFAULT_ACTION_TIMEOUT_SECONDS));
+            let child = tokio::process::Command::new(command)
+                .args(args.iter().flatten())
+                .env("SENTINEL_ALERT_RULE", &event.rule)
+                .env("SENTINEL_ALERT_METRIC", event.metric)
+                .env("SENTINEL_ALERT_SUBJECT", &event.subject)
+                .env("SENTINEL_ALERT_VALUE", event.value.to_string())
+                .env("SENTINEL_ALERT_THRESHOLD", event.threshold.to_string())
+                .env(
+                    "SENTINEL_ALERT_SEVERITY",
+                    match event.severity {
+                        Some(Severity::Warn) => "warn",
+                        Some(Severity::Critical) => "critical",
+                        None => "",
+                    },
+                )
+                .env(
+                    "SENTINEL_ALERT_STATE",
+                    match event.state {
+                        AlertState::Firing => "firing",
+                        AlertState::Resolved => "resolved",
+                    },
+                )
+                .env("SENTINEL_ALERT_HOSTNAME", &event.hostname)
+                .env("SENTINEL_ALERT_MESSAGE", event.message.as_deref().unwrap_or_default())
+                .stdin(std::process::Stdio::null())
+                .kill_on_drop(true)
+                .output();
+
+            let output = tokio::time::timeout(timeout, child)
+                .await
+                .map_err(|_| AlertError::Timeout(command.display().to_string()))?
+                .map_err(|e| AlertError::Exec {
+                    command: command.display().to_string(),
+                    error: e.to_string(),
+                })?;
+
+            if !output.status.success() {
+                return Err(AlertError::Exec {
+                    command: command.display().to_string(),
+                    error: format!(
+                        "exited with {}: {}",
+                        output.status,
+                        String::from_utf8_lossy(&output.stderr).trim()
+                    ),
+                });
+            }
+            debug!(command = %command.display(), "Alert exec action completed");
+            Ok(())
+        }
+        AlertAction::Webhook {
+            url,
+            timeout_seconds,
+        } => {
+            let response = http
+                .post(url)
+                .timeout(Duration::from_secs(
+                    timeout_seconds.unwrap_or(DEFAULT_ACTION_TIMEOUT_SECONDS),
+                ))
+                .json(event)
+                .send()
+                .await
+                .map_err(|e| AlertError::Webhook(e.to_string()))?;
+
+            if !response.status().is_success() {
+                return Err(AlertError::Webhook(format!(
+                    "{} returned {}",
+                    url,
+                    response.status()
+                )));
+            }
+            debug!(url = %url, "Alert webhook delivered");
+            Ok(())
+        }
+        AlertAction::Syslog => write_syslog(event),
+    }
+}
+
+/// Send an RFC 3164 message to the local syslog socket (facility daemon)
+#[cfg(unix)]
+fn write_syslog(event: &AlertEvent) -> Result<(), AlertError> {
+    use std::os::unix::net::UnixDatagram;
+
+    // daemon.warning when firing, daemon.notice when resolved
+    let priority = match event.state {
+        AlertState::Firing => 3 * 8 + 4,
+        AlertState::Resolved => 3 * 8 + 5,
+    };
+    let text = event.message.clone().unwrap_or_else(|| {
+        format!(
+            "alert {} {} on {}: value {} threshold {}",
+            event.rule,
+            match event.state {
+                AlertState::Firing => "firing",
+                AlertState::Resolved => "resolved",
+            },
+            event.subject,
+            event.value,
+            event.threshold
+        )
+    });
+    let message = format!("<{}>sentinel-agent[{}]: {}", priority, std::process::id(), text);
+
+    let socket = UnixDatagram::unbound().map_err(|e| AlertError::Syslog(e.to_string()))?;
+    let path = ["/dev/log", "/var/run/syslog"]
+        .into_iter()
+        .find(|path| std::path::Path::new(path).exists())
+        .ok_or_else(|| AlertError::Syslog("no syslog socket found".to_string()))?;
+    socket
+        .send_to(message.as_bytes(), path)
+        .map_err(|e| AlertError::Syslog(e.to_string()))?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn write_syslog(_event: &AlertEvent) -> Result<(), AlertError> {
+    Err(AlertError::Syslog(
+        "syslog actions are only supported on Unix".to_string(),
+    ))
+}
+
+/// Count failed systemd units, `None` where systemd is unavailable
+pub async fn failed_systemd_units() -> Option<f64> {
+    if !cfg!(target_os = "linux") {
+        return None;
+    }
+
+    let output = tokio::process::Command::new("systemctl")
+        .args(["list-units", "--state=failed", "--no-legend", "--plain"])
+        .output()
+        .await
+        .ok()
+        .filter(|output| output.status.success())?;
+
+    let count = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .count();
+    Some(count as f64)
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum AlertError {
+    #[error("Failed to run {command}: {error}")]
+    Exec { command: String, error: String },
+    #[error("Alert action {0} timed out")]
+    Timeout(String),
+    #[error("Webhook failed: {0}")]
+    Webhook(String),
+    #[error("Failed to write syslog message: {0}")]
+    Syslog(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    fn create_evaluator(on_resolve: bool) -> AlertEvaluator {
+        let config = Config::load_from_str(&format!(
+            r#"
+agent:
+  hostname: "test-host"
+api:
+  endpoint: "https://api.example.com"
+collection:
+  interval_seconds: 60
+  disk:
+    enabled: true
+alerts:
+  enabled: true
+  rules:
+    - name: root-full
+      metric: disk_usage
+      threshold: 0.9
+      mount_points: ["/"]
+      on_resolve: {}
+      actions:
+        - type: syslog
+"#,
+            on_resolve
+        ))
+        .unwrap();
+        AlertEvaluator::new(
+            config.alerts.as_ref().unwrap(),
+            config.thresholds.as_ref(),
+            "test-host".to_string(),
+            &config.get_script_limits(),
+            reqwest::Client::new(),
+        )
+    }
+
+    fn sample(mount_point: &str, usage: f64) -> Vec<(String, f64, Option<Severity>)> {
+        vec![(mount_point.to_string(), usage, None)]
+    }
+
+    #[test]
+    fn test_fires_once_per_crossing() {
+        let mut evaluator = create_evaluator(false);
+
+        assert!(evaluator.evaluate(AlertMetric::DiskUsage, &sample("/", 0.5)).is_empty());
+
+        let crossings = evaluator.evaluate(AlertMetric::DiskUsage, &sample("/", 0.95));
+        assert_eq!(crossings.len(), 1);
+        assert_eq!(crossings[0].0.state, AlertState::Firing);
+        assert_eq!(crossings[0].0.subject, "/");
+
+        // Still above the threshold: no repeat
+        assert!(evaluator.evaluate(AlertMetric::DiskUsage, &sample("/", 0.97)).is_empty());
+
+        // Resolution is tracked but actions only run with on_resolve
+        assert!(evaluator.evaluate(AlertMetric::DiskUsage, &sample("/", 0.5)).is_empty());
+        assert_eq!(
+            evaluator.evaluate(AlertMetric::DiskUsage, &sample("/", 0.95)).len(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_condition_and_message_scripts() {
+        let config = Config::load_from_str(
+            r#"
+api:
+  endpoint: "https://api.example.com"
+agent: {}
+collection:
+  interval_seconds: 60
+  disk:
+    enabled: true
+alerts:
+  enabled: true
+  rules:
+    - name: almost-full
+      metric: disk_usage
+      threshold: 0.9
+      condition: 'value > threshold || (subject == "/var" && value > 0.5)'
+      message: '`${event.subject} at ${event.value * 100.0}%`'
+      actions:
+        - type: syslog
+"#,
+        )
+        .unwrap();
+        let mut evaluator = AlertEvaluator::new(
+            config.alerts.as_ref().unwrap(),
+            None,
+            "test-host".to_string(),
+            &config.get_script_limits(),
+            reqwest::Client::new(),
+        );
+
+        assert!(evaluator.evaluate(AlertMetric::DiskUsage, &sample("/", 0.6)).is_empty());
+        let crossings = evaluator.evaluate(AlertMetric::DiskUsage, &sample("/var", 0.6));
+        assert_eq!(crossings.len(), 1);
+        assert_eq!(crossings[0].0.message.as_deref(), Some("/var at 60.0%"));
+    }
+
+    #[test]
+    fn test_on_resolve_and_mount_filter() {
+        let mut evaluator = create_evaluator(true);
+
+        assert!(evaluator.evaluate(AlertMetric::DiskUsage, &sample("/home", 0.99)).is_empty());
+
+        evaluator.evaluate(AlertMetric::DiskUsage, &sample("/", 0.95));
+        let crossings = evaluator.evaluate(AlertMetric::DiskUsage, &sample("/", 0.5));
+        assert_eq!(crossings.len(), 1);
+        assert_eq!(crossings[0].0.state, AlertState::Resolved);
+    }
+
+    #[test]
+    fn test_severity_rule() {
+        let config = Config::load_from_str(
+            r#"
+api:
+  endpoint: "https://api.example.com"
+agent: {}
+collection:
+  interval_seconds: 60
+  disk:
+    enabled: true
+thresholds:
+  disk_usage:
+    warn: 0.8
+    critical: 0.9
+alerts:
+  enabled: true
+  rules:
+    - name: disk-critical
+      metric: disk_usage
+      severity: critical
+      actions:
+        - type: syslog
+"#,
+        )
+        .unwrap();
+        let mut evaluator = AlertEvaluator::new(
+            config.alerts.as_ref().unwrap(),
+            config.thresholds.as_ref(),
+            "test-host".to_string(),
+            &config.get_script_limits(),
+            reqwest::Client::new(),
+        );
+
+        // Rules follow the tracked severity, not the raw value
+        let warning = vec![("/".to_string(), 0.95, Some(Severity::Warn))];
+        assert!(evaluator.evaluate(AlertMetric::DiskUsage, &warning).is_empty());
+
+        let critical = vec![("/".to_string(), 0.95, Some(Severity::Critical))];
+        let crossings = evaluator.evaluate(AlertMetric::DiskUsage, &critical);
+        assert_eq!(crossings.len(), 1);
+        assert_eq!(crossings[0].0.threshold, 0.9);
+        assert_eq!(crossings[0].0.severity, Some(Severity::Critical));
+    }
+
+    #[tokio::test]
+    async fn test_webhook_action() {
+        use wiremock::matchers::{body_partial_json, method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let mock_server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/alert"))
+            .and(body_partial_json(serde_json::json!({"rule": "root-full", "state": "firing"})))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let mut evaluator = create_evaluator(false);
+        let (event, _) = evaluator
+            .evaluate(AlertMetric::DiskUsage, &sample("/", 0.95))
+            .remove(0);
+        let action = AlertAction::Webhook {
+            url: format!("{}/alert", mock_server.uri()),
+            timeout_seconds: None,
+        };
+
+        run_action(&reqwest::Client::new(), &action, &event).await.unwrap();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_exec_action_receives_alert_env() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("alert.txt");
+
+        let mut evaluator = create_evaluator(false);
+        let (event, _) = evaluator
+            .evaluate(AlertMetric::DiskUsage, &sample("/", 0.95))
+            .remove(0);
+        let action = AlertAction::Exec {
+            command: "/bin/sh".into(),
+            args: Some(vec![
+                "-c".to_string(),
+                format!(
+                    "echo \"$SENTINEL_ALERT_RULE $SENTINEL_ALERT_SUBJECT $SENTINEL_ALERT_STATE\" > {}",
+                    output.display()
+                ),
+            ]),
+            timeout_seconds: Some(5),
+        };
+
+        run_action(&reqwest::Client::new(), &action, &event).await.unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&output).unwrap().trim(),
+            "root-full / firing"
+        );
+    }
+}
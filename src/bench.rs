@@ -0,0 +1,197 @@
+//! Timings behind `sentinel-agent --bench-collectors`
+//!
+//! Runs every configured collector a number of times on this host and
+//! serializes batches of the collected metrics, so releases can be compared on
+//! real machines before a fleet rollout. The criterion benchmarks in `benches/`
+//! cover the same paths with synthetic data.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use crate::metadata::SessionInfo;
+use crate::metrics::{DiskMetric, MetricService};
+
+/// Metrics per batch in the serialization timing, about a busy host's flush
+pub const SERIALIZED_BATCH_SIZE: usize = 500;
+
+/// Latency of one collector over the runs
+#[derive(Debug)]
+pub struct CollectorTiming {
+    pub name: String,
+    pub runs: usize,
+    pub failures: usize,
+    /// Metrics returned by the last successful run
+    pub metrics: usize,
+    pub min: Duration,
+    pub median: Duration,
+    pub p95: Duration,
+    pub max: Duration,
+}
+
+#[derive(Debug)]
+pub struct SerializationTiming {
+    pub metrics: usize,
+    pub bytes: usize,
+    pub per_batch: Duration,
+}
+
+impl SerializationTiming {
+    pub fn metrics_per_second(&self) -> f64 {
+        self.metrics as f64 / self.per_batch.as_secs_f64().max(f64::EPSILON)
+    }
+
+    pub fn bytes_per_second(&self) -> f64 {
+        self.bytes as f64 / self.per_batch.as_secs_f64().max(f64::EPSILON)
+    }
+}
+
+#[derive(Debug)]
+pub struct BenchReport {
+    pub collectors: Vec<CollectorTiming>,
+    pub serialization: SerializationTiming,
+}
+
+/// Time each collector `runs` times, then serialization of a batch built from their metrics
+pub async fn run(service: &MetricService, runs: usize) -> BenchReport {
+    let runs = runs.max(1);
+    let mut collected = Vec::new();
+    let mut collectors = Vec::new();
+    for name in service.collector_names() {
+        let mut durations = Vec::with_capacity(runs);
+        let (mut failures, mut metrics) = (0, 0);
+        for _ in 0..runs {
+            let started = Instant::now();
+            let result = service.collect_from(name).await;
+            durations.push(started.elapsed());
+            match result {
+                Some(Ok(batch)) => {
+                    metrics = batch.len();
+                    if collected.len() < SERIALIZED_BATCH_SIZE {
+                        collected.extend(batch.metrics);
+                    }
+                }
+                _ => failures += 1,
+            }
+        }
+        durations.sort();
+        collectors.push(CollectorTiming {
+            name: name.to_string(),
+            runs,
+            failures,
+            metrics,
+            min: durations[0],
+            median: durations[durations.len() / 2],
+            p95: durations[(durations.len() * 95).div_ceil(100) - 1],
+            max: durations[durations.len() - 1],
+        });
+    }
+
+    // Hosts with few disks still get a full-size batch
+    if collected.is_empty() {
+        collected = synthetic_metrics(SERIALIZED_BATCH_SIZE);
+    }
+    let metrics: Vec<DiskMetric> = collected.iter().cycle().take(SERIALIZED_BATCH_SIZE).cloned().collect();
+    BenchReport {
+        collectors,
+        serialization: time_serialization(service, metrics, runs),
+    }
+}
+
+fn time_serialization(service: &MetricService, metrics: Vec<DiskMetric>, runs: usize) -> SerializationTiming {
+    let count = metrics.len();
+    let batch = service.create_batch(metrics, "bench-resource", "bench-host", SessionInfo::generate());
+    let mut bytes = 0;
+    let started = Instant::now();
+    for _ in 0..runs {
+        bytes = serde_json::to_vec(&batch).map(|json| json.len()).unwrap_or(0);
+    }
+    SerializationTiming {
+        metrics: count,
+        bytes,
+        per_batch: started.elapsed() / runs as u32,
+    }
+}
+
+/// Disk metrics with labels, shaped like a host with `count` mounts
+pub fn synthetic_metrics(count: usize) -> Vec<DiskMetric> {
+    (0..count)
+        .map(|index| DiskMetric {
+            timestamp: 1_700_000_000 + index as u64,
+            device: format!("/dev/nvme{}n1p{}", index / 8, index % 8),
+            mount_point: format!("/data/volume-{}", index),
+            total_space_bytes: 1 << 40,
+            used_space_bytes: (1 << 40) / 100 * (index as u64 % 100),
+            available_space_bytes: (1 << 40) / 100 * (100 - index as u64 % 100),
+            usage_percentage: (index % 100) as f64 / 100.0,
+            labels: BTreeMap::from([
+                ("env".to_string(), "production".to_string()),
+                ("team".to_string(), "storage".to_string()),
+            ]),
+            aggregate: None,
+            severity: None,
+        })
+        .collect()
+}
+
+impl fmt::Display for BenchReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{:<20} {:>6} {:>8} {:>8} {:>10} {:>10} {:>10} {:>10}",
+            "collector", "runs", "failed", "metrics", "min", "median", "p95", "max"
+        )?;
+        for timing in &self.collectors {
+            writeln!(
+                f,
+                "{:<20} {:>6} {:>8} {:>8} {:>10.2?} {:>10.2?} {:>10.2?} {:>10.2?}",
+                timing.name, timing.runs, timing.failures, timing.metrics, timing.min, timing.median, timing.p95, timing.max
+            )?;
+        }
+        let serialization = &self.serialization;
+        writeln!(f)?;
+        write!(
+            f,
+            "serialization: {} metrics, {} bytes per batch in {:.2?} ({:.0} metrics/s, {:.1} MiB/s)",
+            serialization.metrics,
+            serialization.bytes,
+            serialization.per_batch,
+            serialization.metrics_per_second(),
+            serialization.bytes_per_second() / (1024.0 * 1024.0)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    #[tokio::test]
+    async fn test_times_each_collector_and_serialization() {
+        let config = Config::load_from_str(
+            r#"
+agent:
+  id: "bench"
+api:
+  endpoint: "https://api.example.com"
+collection:
+  interval_seconds: 60
+  disk:
+    enabled: true
+"#,
+        )
+        .unwrap();
+        let report = run(&MetricService::new(&config), 3).await;
+
+        assert_eq!(report.collectors.len(), 1);
+        let disk = &report.collectors[0];
+        assert_eq!(disk.name, "disk");
+        assert_eq!(disk.runs, 3);
+        assert!(disk.min <= disk.median && disk.median <= disk.p95 && disk.p95 <= disk.max);
+
+        assert_eq!(report.serialization.metrics, SERIALIZED_BATCH_SIZE);
+        assert!(report.serialization.bytes > 0);
+        assert!(report.to_string().contains("serialization: 500 metrics"));
+    }
+}
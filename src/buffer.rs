@@ -0,0 +1,265 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::convert::Infallible;
+use std::ops::Index;
+
+use crate::metrics::{DiskMetric, Sample};
+
+/// Somewhere undelivered metrics wait, in memory or on disk
+pub trait MetricQueue {
+    type Error;
+
+    /// Queue metrics behind the ones already waiting
+    ///
+    /// Returns older metrics pushed out to stay within the caps, for the
+    /// caller to hand on; metrics a queue discards itself are only counted.
+    fn push(&mut self, metrics: Vec<DiskMetric>) -> Result<Vec<DiskMetric>, Self::Error>;
+
+    /// Bytes held, estimated in memory and on disk for the spool
+    fn size_bytes(&self) -> u64;
+
+    /// Metrics the queue discarded itself to stay within the caps since startup
+    ///
+    /// Metrics returned by `push` are not counted; they are the caller's.
+    fn dropped(&self) -> u64;
+}
+
+/// Bounded FIFO queue of collected metrics awaiting a flush, kept in a `VecDeque`
+///
+/// Capped both by count and by estimated size; the running byte total is kept
+/// as metrics come and go, so the caps are checked without walking the buffer.
+/// The oldest metrics are pushed out first and handed back to the caller, so
+/// the buffer itself never discards any.
+#[derive(Debug)]
+pub struct MetricBuffer {
+    items: VecDeque<DiskMetric>,
+    bytes: usize,
+    max_len: usize,
+    max_bytes: usize,
+    /// Metrics pushed out by the caps or `evict_oldest` since startup
+    evicted: u64,
+}
+
+impl MetricBuffer {
+    pub fn new(max_len: usize, max_bytes: usize) -> Self {
+        Self {
+            items: VecDeque::with_capacity(max_len.min(1024)),
+            bytes: 0,
+            max_len,
+            max_bytes,
+            evicted: 0,
+        }
+    }
+
+    /// Change the caps, returning the metrics that no longer fit
+    pub fn set_limits(&mut self, max_len: usize, max_bytes: usize) -> Vec<DiskMetric> {
+        self.max_len = max_len;
+        self.max_bytes = max_bytes;
+        self.trim()
+    }
+
+    /// Append metrics, returning the oldest ones pushed out by the caps
+    pub fn extend(&mut self, metrics: Vec<DiskMetric>) -> Vec<DiskMetric> {
+        for metric in metrics {
+            self.bytes += metric.estimated_size();
+            self.items.push_back(metric);
+        }
+        self.trim()
+    }
+
+    /// Put metrics back in front of the buffered ones, keeping their order
+    pub fn requeue(&mut self, metrics: Vec<DiskMetric>) -> Vec<DiskMetric> {
+        for metric in metrics.into_iter().rev() {
+            self.bytes += metric.estimated_size();
+            self.items.push_front(metric);
+        }
+        self.trim()
+    }
+
+    /// Take every buffered metric, oldest first
+    ///
+    /// The deque moves out whole and becomes a `Vec` in its allocation; the
+    /// metrics are shifted in place when the deque had wrapped around. The
+    /// buffer starts over empty and allocates again as metrics arrive.
+    pub fn take(&mut self) -> Vec<DiskMetric> {
+        self.bytes = 0;
+        Vec::from(std::mem::take(&mut self.items))
+    }
+
+    /// Remove up to `count` of the oldest metrics
+    pub fn evict_oldest(&mut self, count: usize) -> Vec<DiskMetric> {
+        let count = count.min(self.items.len());
+        let evicted: Vec<DiskMetric> = self.items.drain(..count).collect();
+        self.bytes -= evicted.iter().map(DiskMetric::estimated_size).sum::<usize>();
+        self.evicted += evicted.len() as u64;
+        evicted
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    /// Metrics pushed out since startup, whether the caller spooled them or not
+    pub fn evicted(&self) -> u64 {
+        self.evicted
+    }
+
+    fn trim(&mut self) -> Vec<DiskMetric> {
+        let mut overflow = Vec::new();
+        while self.items.len() > self.max_len || (self.bytes > self.max_bytes && !self.items.is_empty()) {
+            if let Some(metric) = self.items.pop_front() {
+                self.bytes -= metric.estimated_size();
+                overflow.push(metric);
+            }
+        }
+        self.evicted += overflow.len() as u64;
+        overflow
+    }
+}
+
+impl Index<usize> for MetricBuffer {
+    type Output = DiskMetric;
+
+    fn index(&self, index: usize) -> &DiskMetric {
+        &self.items[index]
+    }
+}
+
+impl MetricQueue for MetricBuffer {
+    type Error = Infallible;
+
+    fn push(&mut self, metrics: Vec<DiskMetric>) -> Result<Vec<DiskMetric>, Infallible> {
+        Ok(self.extend(metrics))
+    }
+
+    fn size_bytes(&self) -> u64 {
+        self.bytes as u64
+    }
+
+    /// Always 0: pushed out metrics are returned, not discarded
+    fn dropped(&self) -> u64 {
+        0
+    }
+}
+
+/// Latest value of each sample series awaiting a flush
+///
+/// Sample sources such as textfiles are read again every collection, so only
+/// the newest value of a series is worth sending; the set never grows beyond
+/// the number of series however long flushes fail. Samples are not spooled.
+#[derive(Debug, Default)]
+pub struct LatestSamples {
+    series: BTreeMap<(String, BTreeMap<String, String>), Sample>,
+}
+
+impl LatestSamples {
+    /// Add freshly collected samples, replacing older values of the same series
+    pub fn insert(&mut self, samples: Vec<Sample>) {
+        for sample in samples {
+            self.series.insert((sample.name.clone(), sample.labels.clone()), sample);
+        }
+    }
+
+    /// Put back samples that could not be sent, unless a newer value arrived meanwhile
+    pub fn restore(&mut self, samples: Vec<Sample>) {
+        for sample in samples {
+            self.series
+                .entry((sample.name.clone(), sample.labels.clone()))
+                .or_insert(sample);
+        }
+    }
+
+    /// Take every series, ordered by name and labels
+    pub fn take(&mut self) -> Vec<Sample> {
+        std::mem::take(&mut self.series).into_values().collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.series.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.series.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    fn create_metric(mount_point: &str) -> DiskMetric {
+        DiskMetric {
+            timestamp: 1234567890,
+            device: "/dev/sda1".to_string(),
+            mount_point: mount_point.to_string(),
+            total_space_bytes: 1000000,
+            used_space_bytes: 500000,
+            available_space_bytes: 500000,
+            usage_percentage: 0.5,
+            labels: BTreeMap::new(),
+            aggregate: None,
+            severity: None,
+        }
+    }
+
+    #[test]
+    fn test_caps_byte_accounting_and_drops() {
+        let size = create_metric("/a").estimated_size();
+        let mut buffer = MetricBuffer::new(3, size * 10);
+
+        let overflow = buffer.extend(["/a", "/b", "/c", "/d"].map(create_metric).to_vec());
+        assert_eq!(overflow.len(), 1);
+        assert_eq!(overflow[0].mount_point, "/a");
+        assert_eq!(buffer.len(), 3);
+        assert_eq!(buffer.size_bytes(), size as u64 * 3);
+        assert_eq!(buffer.evicted(), 1);
+
+        // Requeued metrics go in front and are the first pushed out
+        let overflow = buffer.requeue(vec![create_metric("/r")]);
+        assert_eq!(overflow[0].mount_point, "/r");
+        assert_eq!(buffer[0].mount_point, "/b");
+
+        let overflow = buffer.set_limits(10, size * 2);
+        assert_eq!(overflow.len(), 1);
+        assert_eq!(buffer.size_bytes(), size as u64 * 2);
+        assert_eq!(buffer.evict_oldest(1)[0].mount_point, "/c");
+        assert_eq!(buffer.evicted(), 4);
+        assert_eq!(buffer.dropped(), 0);
+
+        let taken = buffer.take();
+        assert_eq!(taken.len(), 1);
+        assert_eq!(taken[0].mount_point, "/d");
+        assert!(buffer.is_empty());
+        assert_eq!(buffer.size_bytes(), 0);
+    }
+
+    #[test]
+    fn test_latest_samples_keep_newest_value_per_series() {
+        let sample = |name: &str, value: f64| Sample {
+            name: name.to_string(),
+            labels: BTreeMap::new(),
+            value,
+            timestamp: value as u64,
+            kind: Default::default(),
+            exemplar: None,
+        };
+        let mut samples = LatestSamples::default();
+        samples.insert(vec![sample("queue_depth", 1.0), sample("backup_age_seconds", 1.0)]);
+        samples.insert(vec![sample("queue_depth", 2.0)]);
+        assert_eq!(samples.len(), 2);
+
+        let unsent = samples.take();
+        assert!(samples.is_empty());
+        assert_eq!(unsent[1].value, 2.0);
+
+        // A value collected while the flush was failing wins over the unsent one
+        samples.insert(vec![sample("queue_depth", 3.0)]);
+        samples.restore(unsent);
+        let values: Vec<f64> = samples.take().iter().map(|sample| sample.value).collect();
+        assert_eq!(values, [1.0, 3.0]);
+    }
+}
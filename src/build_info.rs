@@ -0,0 +1,72 @@
+use chrono::DateTime;
+use serde::Serialize;
+use std::fmt;
+
+/// Build provenance embedded at compile time by `build.rs`
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct BuildInfo {
+    pub version: String,
+    pub git_commit: String,
+    /// RFC 3339 build time (from `SOURCE_DATE_EPOCH` when set)
+    pub build_date: String,
+    pub rustc_version: String,
+    pub target: String,
+    pub features: Vec<String>,
+}
+
+impl BuildInfo {
+    pub fn current() -> Self {
+        let build_date = env!("SENTINEL_BUILD_TIMESTAMP")
+            .parse::<i64>()
+            .ok()
+            .and_then(|secs| DateTime::from_timestamp(secs, 0))
+            .map(|date| date.to_rfc3339())
+            .unwrap_or_else(|| "unknown".to_string());
+
+        Self {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            git_commit: env!("SENTINEL_GIT_COMMIT").to_string(),
+            build_date,
+            rustc_version: env!("SENTINEL_RUSTC_VERSION").to_string(),
+            target: env!("SENTINEL_TARGET").to_string(),
+            features: env!("SENTINEL_FEATURES")
+                .split(',')
+                .filter(|feature| !feature.is_empty())
+                .map(String::from)
+                .collect(),
+        }
+    }
+}
+
+impl fmt::Display for BuildInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "sentinel-agent {}", self.version)?;
+        writeln!(f, "  commit:   {}", self.git_commit)?;
+        writeln!(f, "  built:    {}", self.build_date)?;
+        writeln!(f, "  rustc:    {}", self.rustc_version)?;
+        writeln!(f, "  target:   {}", self.target)?;
+        write!(
+            f,
+            "  features: {}",
+            if self.features.is_empty() { "none".to_string() } else { self.features.join(", ") }
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_current_build_info() {
+        let info = BuildInfo::current();
+        assert_eq!(info.version, env!("CARGO_PKG_VERSION"));
+        assert!(!info.target.is_empty());
+        assert_ne!(info.build_date, "unknown");
+
+        let json = serde_json::to_value(&info).unwrap();
+        for key in ["version", "git_commit", "build_date", "rustc_version", "target", "features"] {
+            assert!(json.get(key).is_some(), "missing {}", key);
+        }
+    }
+}
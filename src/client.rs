@@ -1,18 +1,49 @@
-use reqwest::Client;
+use chrono::{DateTime, Utc};
+use reqwest::header::{HeaderValue, DATE, RETRY_AFTER};
+use reqwest::{Client, RequestBuilder, Response, StatusCode};
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
+use crate::build_info::BuildInfo;
+use crate::clock;
+use crate::commands::{CommandResult, SignedCommand};
 use crate::config::Config;
-use crate::metadata::InstanceMetadata;
+use crate::identity::{self, AgentIdentity};
+use crate::metadata::{InstanceMetadata, SessionInfo};
 use crate::metrics::MetricBatch;
+use crate::network::NetworkIdentity;
+use crate::oauth::{OAuthManager, Operation};
+use crate::remote_config::{SignedRemoteConfig, SIGNATURE_HEADER};
+use crate::state::AgentUpgrade;
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Serialize)]
 pub struct ResourceRegistration {
     pub hostname: String,
     pub agent_version: String,
     pub platform: String,
     pub arch: String,
     pub instance_metadata: InstanceMetadata,
+    pub build: BuildInfo,
+    /// Global labels from `agent.labels`
+    pub labels: BTreeMap<String, String>,
+    /// Tenant from `agent.org_id` and `agent.project`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub org_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub project: Option<String>,
+    /// Hash of the machine's identifiers, so the platform can tell clones apart
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fingerprint: Option<String>,
+    /// Base64 Ed25519 public key that signs this agent's requests
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub public_key: Option<String>,
+    /// Addresses from `metadata.network`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub network: Option<NetworkIdentity>,
+    /// Boot time, kernel, OS release and binary checksum of this run
+    pub session: SessionInfo,
 }
 
 #[derive(Debug, Deserialize)]
@@ -20,94 +51,383 @@ pub struct ResourceRegistrationResponse {
     pub resource_id: String,
     pub status: String,
     pub message: Option<String>,
+    /// RFC 3339 time after which the platform drops the resource unless it is renewed
+    #[serde(default)]
+    pub expires_at: Option<String>,
+}
+
+/// Liveness signal sent independently of metric flushes
+#[derive(Debug, Serialize)]
+pub struct Heartbeat {
+    pub agent_version: String,
+    pub uptime_seconds: u64,
+    pub buffer_depth: usize,
+    pub spooled_batches: usize,
+    /// The agent is paused for planned maintenance
+    pub maintenance: bool,
+    /// Failed, timed-out or panicked invocations per collector since startup
+    pub collector_failures: BTreeMap<String, u64>,
+    /// Most recent agent version change on this resource
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_upgrade: Option<AgentUpgrade>,
+    /// Unix timestamp at which the heartbeat was sent
+    pub timestamp: u64,
+}
+
+/// Acknowledgment returned by the metrics endpoint
+///
+/// An empty or unstructured body means the whole batch was accepted.
+#[derive(Debug, Default, Deserialize)]
+pub struct MetricsAck {
+    #[serde(default)]
+    pub accepted: Vec<usize>,
+    #[serde(default)]
+    pub rejected: Vec<RejectedMetric>,
 }
 
+#[derive(Debug, Deserialize)]
+pub struct RejectedMetric {
+    /// Index of the metric within the submitted batch
+    pub index: usize,
+    pub reason: Option<String>,
+    /// Whether resending the metric may succeed (e.g. transient storage error)
+    #[serde(default)]
+    pub retryable: bool,
+}
+
+#[derive(Clone)]
 pub struct ApiClient {
     client: Client,
     endpoint: String,
     api_key: Option<String>,
+    /// Issues bearer tokens instead of `api_key`, with `api.oauth`
+    oauth: Option<Arc<OAuthManager>>,
+    /// Signs every authenticated request when set
+    identity: Option<Arc<AgentIdentity>>,
+    /// Latest server-minus-local clock offset, from response `Date` headers
+    clock_offset: Arc<Mutex<Option<i64>>>,
 }
 
 impl ApiClient {
     pub fn new(config: &Config) -> Result<Self, ApiError> {
         let timeout = Duration::from_secs(config.get_api_timeout_seconds());
-        let client = Client::builder()
+        let tls = config.api.tls.as_ref();
+        let builder =
+            crate::tls::client_builder(tls).map_err(|e| ApiError::ClientCreation(e.to_string()))?;
+        let (builder, endpoint) = match tls.and_then(|tls| tls.server_name.as_deref()) {
+            Some(server_name) => crate::tls::with_server_name(builder, &config.api.endpoint, server_name)
+                .map_err(|e| ApiError::ClientCreation(e.to_string()))?,
+            None => (builder, config.api.endpoint.clone()),
+        };
+        let client = builder
             .timeout(timeout)
             .build()
             .map_err(|e| ApiError::ClientCreation(e.to_string()))?;
 
+        let oauth = match config.api.oauth.clone() {
+            Some(settings) => Some(Arc::new(
+                OAuthManager::new(client.clone(), settings).map_err(|e| ApiError::ClientCreation(e.to_string()))?,
+            )),
+            None => None,
+        };
+
         Ok(Self {
             client,
-            endpoint: config.api.endpoint.clone(),
+            endpoint,
             api_key: config.api.api_key.clone(),
+            oauth,
+            identity: None,
+            clock_offset: Arc::new(Mutex::new(None)),
         })
     }
 
-    pub async fn send_metrics(&self, batch: &MetricBatch) -> Result<(), ApiError> {
+    /// Sign requests with the agent's key
+    pub fn with_identity(mut self, identity: AgentIdentity) -> Self {
+        self.identity = Some(Arc::new(identity));
+        self
+    }
+
+    /// Sign requests with the same key as `other`
+    pub fn with_identity_of(mut self, other: &ApiClient) -> Self {
+        self.identity = other.identity.clone();
+        self
+    }
+
+    /// Base64 public key sent at registration, when requests are signed
+    pub fn public_key(&self) -> Option<String> {
+        self.identity.as_ref().map(|identity| identity.public_key())
+    }
+
+    /// Token manager of `api.oauth`, e.g. for `sentinel-agent login`
+    pub fn oauth(&self) -> Option<&OAuthManager> {
+        self.oauth.as_deref()
+    }
+
+    /// Revoke the OAuth tokens on shutdown; `forget_login` after deregistration
+    pub async fn revoke_credentials(&self, forget_login: bool) -> Result<(), ApiError> {
+        match &self.oauth {
+            Some(oauth) => oauth.revoke(forget_login).await.map_err(|e| ApiError::Auth(e.to_string())),
+            None => Ok(()),
+        }
+    }
+
+    /// The current OAuth access token for `operation`, `None` without `api.oauth`
+    pub async fn access_token(&self, operation: Operation) -> Result<Option<String>, ApiError> {
+        match &self.oauth {
+            Some(oauth) => oauth.access_token(operation).await.map(Some).map_err(|e| ApiError::Auth(e.to_string())),
+            None => Ok(None),
+        }
+    }
+
+    /// Add the API key or OAuth access token and, with an agent identity, the request signature
+    ///
+    /// The timestamp follows the server's clock when its offset is known, so a
+    /// skewed local clock does not get requests rejected as stale.
+    async fn authorize(&self, request: RequestBuilder, operation: Operation) -> Result<RequestBuilder, ApiError> {
+        let token = self.access_token(operation).await?;
+        self.authorize_with(request, token)
+    }
+
+    /// `authorize` with an access token already fetched
+    fn authorize_with(&self, request: RequestBuilder, token: Option<String>) -> Result<RequestBuilder, ApiError> {
+        let request = match token.or_else(|| self.api_key.clone()) {
+            Some(token) => request.header("Authorization", format!("Bearer {}", token)),
+            None => request,
+        };
+        let Some(identity) = &self.identity else {
+            return Ok(request);
+        };
+
+        let (client, request) = request.build_split();
+        let mut request = request.map_err(|e| ApiError::Request(e.to_string()))?;
+        let timestamp = Utc::now().timestamp() + self.clock_offset_seconds().unwrap_or(0);
+        let path_and_query = match request.url().query() {
+            Some(query) => format!("{}?{}", request.url().path(), query),
+            None => request.url().path().to_string(),
+        };
+        let body = request.body().and_then(|body| body.as_bytes()).unwrap_or_default();
+        let signature = identity.sign(request.method().as_str(), &path_and_query, timestamp, body);
+
+        let headers = request.headers_mut();
+        for (name, value) in [
+            (identity::KEY_HEADER, identity.public_key()),
+            (identity::TIMESTAMP_HEADER, timestamp.to_string()),
+            (identity::SIGNATURE_HEADER, signature),
+        ] {
+            let value = HeaderValue::from_str(&value).map_err(|e| ApiError::Request(e.to_string()))?;
+            headers.insert(name, value);
+        }
+        Ok(RequestBuilder::from_parts(client, request))
+    }
+
+    /// Send an authorized request, retrying once with a new token after a 401
+    ///
+    /// With `api.oauth` a 401 means the token was revoked or rotated before it
+    /// expired; without the retry every request would fail until it does.
+    async fn send_authorized(&self, request: RequestBuilder, operation: Operation) -> Result<Response, ApiError> {
+        let retry = self.oauth.as_ref().and_then(|_| request.try_clone());
+        let token = self.access_token(operation).await?;
+        let response = self
+            .authorize_with(request, token.clone())?
+            .send()
+            .await
+            .map_err(|e| ApiError::Request(e.to_string()))?;
+
+        match (retry, &self.oauth, token) {
+            (Some(retry), Some(oauth), Some(token)) if response.status() == StatusCode::UNAUTHORIZED => {
+                tracing::info!("API rejected the access token, retrying with a new one");
+                oauth.invalidate(operation, &token).await.map_err(|e| ApiError::Auth(e.to_string()))?;
+                self.authorize(retry, operation)
+                    .await?
+                    .send()
+                    .await
+                    .map_err(|e| ApiError::Request(e.to_string()))
+            }
+            _ => Ok(response),
+        }
+    }
+
+    /// Server time minus local time in seconds, once a response has been seen
+    pub fn clock_offset_seconds(&self) -> Option<i64> {
+        self.clock_offset.lock().ok().and_then(|offset| *offset)
+    }
+
+    /// Update the clock offset from a response's `Date` header
+    fn observe_date(&self, response: &Response, sent_at: DateTime<Utc>) {
+        let Some(date) = response.headers().get(DATE).and_then(|v| v.to_str().ok()) else {
+            return;
+        };
+        if let Some(offset) = clock::estimate_offset(date, sent_at, Utc::now()) {
+            if let Ok(mut current) = self.clock_offset.lock() {
+                *current = Some(offset);
+            }
+        }
+    }
+
+    pub async fn send_metrics(&self, batch: &MetricBatch) -> Result<MetricsAck, ApiError> {
         let url = format!("{}/api/v1/metrics", self.endpoint);
 
-        let mut request = self.client
+        let request = self.client
             .post(&url)
             .json(batch)
             .header("Content-Type", "application/json")
             .header("Accept", "application/json");
 
-        // Add API key authentication if available
-        if let Some(api_key) = &self.api_key {
-            request = request.header("Authorization", format!("Bearer {}", api_key));
+        let sent_at = Utc::now();
+        let response = self.send_authorized(request, Operation::Metrics).await?;
+        self.observe_date(&response, sent_at);
+
+        if !response.status().is_success() {
+            return Err(Self::error_from_response(response).await);
         }
 
-        let response = request
+        let body = response
+            .text()
+            .await
+            .map_err(|e| ApiError::Parse(e.to_string()))?;
+
+        Ok(serde_json::from_str(&body).unwrap_or_default())
+    }
+
+    /// Lightweight connectivity probe against the API's health endpoint
+    pub async fn check_health(&self) -> Result<(), ApiError> {
+        let url = format!("{}/health", self.endpoint);
+
+        let sent_at = Utc::now();
+        let response = self
+            .client
+            .get(&url)
             .send()
             .await
             .map_err(|e| ApiError::Request(e.to_string()))?;
+        self.observe_date(&response, sent_at);
 
         if !response.status().is_success() {
-            let status = response.status();
-            let body = response
-                .text()
-                .await
-                .unwrap_or_else(|_| "Unable to read response body".to_string());
+            return Err(Self::error_from_response(response).await);
+        }
 
-            return Err(ApiError::Response {
-                status: status.as_u16(),
-                body,
-            });
+        Ok(())
+    }
+
+    pub async fn send_heartbeat(
+        &self,
+        resource_id: &str,
+        heartbeat: &Heartbeat,
+    ) -> Result<(), ApiError> {
+        let url = format!("{}/api/v1/resources/{}/heartbeat", self.endpoint, resource_id);
+
+        let request = self.client.post(&url).json(heartbeat);
+
+        let sent_at = Utc::now();
+        let response = self.send_authorized(request, Operation::Other).await?;
+        self.observe_date(&response, sent_at);
+
+        if !response.status().is_success() {
+            return Err(Self::error_from_response(response).await);
+        }
+
+        Ok(())
+    }
+
+    /// Long-poll the platform for pending commands addressed to this resource
+    pub async fn poll_commands(
+        &self,
+        resource_id: &str,
+        wait_seconds: u64,
+    ) -> Result<Vec<SignedCommand>, ApiError> {
+        let url = format!("{}/api/v1/resources/{}/commands", self.endpoint, resource_id);
+
+        let request = self
+            .client
+            .get(&url)
+            .query(&[("wait", wait_seconds)])
+            // The server holds the request open for up to `wait_seconds`
+            .timeout(Duration::from_secs(wait_seconds + 10))
+            .header("Accept", "application/json");
+
+        let response = self.send_authorized(request, Operation::Other).await?;
+
+        if response.status() == StatusCode::NO_CONTENT {
+            return Ok(Vec::new());
+        }
+
+        if !response.status().is_success() {
+            return Err(Self::error_from_response(response).await);
+        }
+
+        response
+            .json()
+            .await
+            .map_err(|e| ApiError::Parse(e.to_string()))
+    }
+
+    /// Report the outcome of a command back to the platform
+    pub async fn post_command_result(
+        &self,
+        resource_id: &str,
+        command_id: &str,
+        result: &CommandResult,
+    ) -> Result<(), ApiError> {
+        let url = format!(
+            "{}/api/v1/resources/{}/commands/{}/result",
+            self.endpoint, resource_id, command_id
+        );
+
+        let request = self.client.post(&url).json(result);
+
+        let response = self.send_authorized(request, Operation::Other).await?;
+
+        if !response.status().is_success() {
+            return Err(Self::error_from_response(response).await);
         }
 
         Ok(())
     }
 
+    /// Fetch centrally managed configuration, `None` if the platform has none for this agent
+    pub async fn fetch_remote_config(
+        &self,
+        resource_id: &str,
+    ) -> Result<Option<SignedRemoteConfig>, ApiError> {
+        let url = format!("{}/api/v1/agents/{}/config", self.endpoint, resource_id);
+
+        let request = self.client.get(&url).header("Accept", "application/json");
+
+        let response = self.send_authorized(request, Operation::Other).await?;
+
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+
+        if !response.status().is_success() {
+            return Err(Self::error_from_response(response).await);
+        }
+
+        let signature = response
+            .headers()
+            .get(SIGNATURE_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string);
+        let payload = response
+            .text()
+            .await
+            .map_err(|e| ApiError::Parse(e.to_string()))?;
+        Ok(Some(SignedRemoteConfig { payload, signature }))
+    }
+
     pub async fn register_resource(&self, registration: &ResourceRegistration) -> Result<ResourceRegistrationResponse, ApiError> {
         let url = format!("{}/api/v1/resources", self.endpoint);
 
-        let mut request = self.client
+        let request = self.client
             .post(&url)
             .json(registration)
             .header("Content-Type", "application/json")
             .header("Accept", "application/json");
 
-        // Add API key authentication if available
-        if let Some(api_key) = &self.api_key {
-            request = request.header("Authorization", format!("Bearer {}", api_key));
-        }
-
-        let response = request
-            .send()
-            .await
-            .map_err(|e| ApiError::Request(e.to_string()))?;
+        let response = self.send_authorized(request, Operation::Registration).await?;
 
         if !response.status().is_success() {
-            let status = response.status();
-            let body = response
-                .text()
-                .await
-                .unwrap_or_else(|_| "Unable to read response body".to_string());
-
-            return Err(ApiError::Response {
-                status: status.as_u16(),
-                body,
-            });
+            return Err(Self::error_from_response(response).await);
         }
 
         let registration_response: ResourceRegistrationResponse = response
@@ -118,9 +438,105 @@ impl ApiClient {
         Ok(registration_response)
     }
 
+    /// Extend the registration before it expires
+    ///
+    /// Returns `None` when the platform no longer knows the resource (404 or
+    /// 410), so the agent has to register again.
+    pub async fn renew_registration(
+        &self,
+        resource_id: &str,
+        registration: &ResourceRegistration,
+    ) -> Result<Option<ResourceRegistrationResponse>, ApiError> {
+        let url = format!("{}/api/v1/resources/{}", self.endpoint, resource_id);
+
+        let request = self.client
+            .put(&url)
+            .json(registration)
+            .header("Accept", "application/json");
+
+        let response = self.send_authorized(request, Operation::Registration).await?;
+
+        if response.status() == StatusCode::NOT_FOUND || response.status() == StatusCode::GONE {
+            return Ok(None);
+        }
+
+        if !response.status().is_success() {
+            return Err(Self::error_from_response(response).await);
+        }
+
+        response
+            .json()
+            .await
+            .map(Some)
+            .map_err(|e| ApiError::Parse(e.to_string()))
+    }
+
+    /// Mark the resource as decommissioned; an already-deleted resource is not an error
+    pub async fn deregister_resource(&self, resource_id: &str) -> Result<(), ApiError> {
+        let url = format!("{}/api/v1/resources/{}", self.endpoint, resource_id);
+
+        let request = self.client.delete(&url);
+
+        let response = self.send_authorized(request, Operation::Registration).await?;
+
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(());
+        }
+
+        if !response.status().is_success() {
+            return Err(Self::error_from_response(response).await);
+        }
+
+        Ok(())
+    }
+
     pub fn endpoint(&self) -> &str {
         &self.endpoint
     }
+
+    /// Convert a non-success response into an `ApiError`
+    ///
+    /// 429 and 503 are reported as `RateLimited` so callers can back off
+    /// instead of treating load shedding as a hard failure.
+    async fn error_from_response(response: reqwest::Response) -> ApiError {
+        let status = response.status();
+
+        if status == StatusCode::TOO_MANY_REQUESTS || status == StatusCode::SERVICE_UNAVAILABLE {
+            let retry_after = response
+                .headers()
+                .get(RETRY_AFTER)
+                .and_then(|value| value.to_str().ok())
+                .and_then(parse_retry_after);
+
+            return ApiError::RateLimited {
+                status: status.as_u16(),
+                retry_after,
+            };
+        }
+
+        let body = response
+            .text()
+            .await
+            .unwrap_or_else(|_| "Unable to read response body".to_string());
+
+        ApiError::Response {
+            status: status.as_u16(),
+            body,
+        }
+    }
+}
+
+/// Parse a Retry-After header value, either delay-seconds or an HTTP-date
+fn parse_retry_after(value: &str) -> Option<Duration> {
+    let value = value.trim();
+
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+
+    let date = DateTime::parse_from_rfc2822(value).ok()?;
+    let delay = date.with_timezone(&Utc) - Utc::now();
+    Some(delay.to_std().unwrap_or(Duration::ZERO))
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -131,8 +547,26 @@ pub enum ApiError {
     Request(String),
     #[error("Failed to parse response: {0}")]
     Parse(String),
+    #[error("Failed to obtain an access token: {0}")]
+    Auth(String),
     #[error("API returned error status {status}: {body}")]
     Response { status: u16, body: String },
+    #[error("API is rate limiting requests (status {status})")]
+    RateLimited {
+        status: u16,
+        retry_after: Option<Duration>,
+    },
+}
+
+impl ApiError {
+    /// Failures worth retrying: the API was unreachable, overloaded or failing
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Self::Request(_) | Self::RateLimited { .. } => true,
+            Self::Response { status, .. } => *status == 408 || (500..=599).contains(status),
+            _ => false,
+        }
+    }
 }
 
 #[cfg(test)]
@@ -201,6 +635,9 @@ collection:
             used_space_bytes: 500000,
             available_space_bytes: 500000,
             usage_percentage: 50.0,
+            labels: BTreeMap::new(),
+            aggregate: None,
+            severity: None,
         };
 
         let session = crate::metadata::SessionInfo::generate();
@@ -210,6 +647,50 @@ collection:
         assert!(result.is_ok());
     }
 
+    #[tokio::test]
+    async fn test_send_metrics_partial_ack() {
+        let mock_server = MockServer::start().await;
+        let config = create_test_config(&mock_server.uri()).await;
+
+        Mock::given(method("POST"))
+            .and(path("/api/v1/metrics"))
+            .respond_with(ResponseTemplate::new(207).set_body_json(serde_json::json!({
+                "accepted": [0],
+                "rejected": [
+                    {"index": 1, "reason": "storage unavailable", "retryable": true},
+                    {"index": 2, "reason": "usage_percentage out of range"}
+                ]
+            })))
+            .mount(&mock_server)
+            .await;
+
+        let client = ApiClient::new(&config).unwrap();
+        let service = MetricService::new(&config);
+
+        let metric = DiskMetric {
+            timestamp: 1234567890,
+            device: "/dev/sda1".to_string(),
+            mount_point: "/".to_string(),
+            total_space_bytes: 1000000,
+            used_space_bytes: 500000,
+            available_space_bytes: 500000,
+            usage_percentage: 50.0,
+            labels: BTreeMap::new(),
+            aggregate: None,
+            severity: None,
+        };
+
+        let session = crate::metadata::SessionInfo::generate();
+        let batch = service.create_batch(vec![metric; 3], "test-agent", "test-host", session);
+        let ack = client.send_metrics(&batch).await.unwrap();
+
+        assert_eq!(ack.accepted, vec![0]);
+        assert_eq!(ack.rejected.len(), 2);
+        assert!(ack.rejected[0].retryable);
+        assert!(!ack.rejected[1].retryable);
+        assert_eq!(ack.rejected[1].reason.as_deref(), Some("usage_percentage out of range"));
+    }
+
     #[tokio::test]
     async fn test_send_metrics_server_error() {
         let mock_server = MockServer::start().await;
@@ -232,6 +713,9 @@ collection:
             used_space_bytes: 500000,
             available_space_bytes: 500000,
             usage_percentage: 50.0,
+            labels: BTreeMap::new(),
+            aggregate: None,
+            severity: None,
         };
 
         let session = crate::metadata::SessionInfo::generate();
@@ -248,6 +732,84 @@ collection:
         }
     }
 
+    #[tokio::test]
+    async fn test_send_metrics_rate_limited() {
+        let mock_server = MockServer::start().await;
+        let config = create_test_config(&mock_server.uri()).await;
+
+        Mock::given(method("POST"))
+            .and(path("/api/v1/metrics"))
+            .respond_with(ResponseTemplate::new(429).insert_header("Retry-After", "120"))
+            .mount(&mock_server)
+            .await;
+
+        let client = ApiClient::new(&config).unwrap();
+        let service = MetricService::new(&config);
+
+        let metric = DiskMetric {
+            timestamp: 1234567890,
+            device: "/dev/sda1".to_string(),
+            mount_point: "/".to_string(),
+            total_space_bytes: 1000000,
+            used_space_bytes: 500000,
+            available_space_bytes: 500000,
+            usage_percentage: 50.0,
+            labels: BTreeMap::new(),
+            aggregate: None,
+            severity: None,
+        };
+
+        let session = crate::metadata::SessionInfo::generate();
+        let batch = service.create_batch(vec![metric], "test-agent", "test-host", session);
+        let result = client.send_metrics(&batch).await;
+
+        match result.unwrap_err() {
+            ApiError::RateLimited { status, retry_after } => {
+                assert_eq!(status, 429);
+                assert_eq!(retry_after, Some(Duration::from_secs(120)));
+            }
+            _ => panic!("Expected ApiError::RateLimited"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_send_metrics_service_unavailable_without_retry_after() {
+        let mock_server = MockServer::start().await;
+        let config = create_test_config(&mock_server.uri()).await;
+
+        Mock::given(method("POST"))
+            .and(path("/api/v1/metrics"))
+            .respond_with(ResponseTemplate::new(503))
+            .mount(&mock_server)
+            .await;
+
+        let client = ApiClient::new(&config).unwrap();
+        let service = MetricService::new(&config);
+
+        let session = crate::metadata::SessionInfo::generate();
+        let batch = service.create_batch(vec![], "test-agent", "test-host", session);
+        let result = client.send_metrics(&batch).await;
+
+        match result.unwrap_err() {
+            ApiError::RateLimited { status, retry_after } => {
+                assert_eq!(status, 503);
+                assert_eq!(retry_after, None);
+            }
+            _ => panic!("Expected ApiError::RateLimited"),
+        }
+    }
+
+    #[test]
+    fn test_parse_retry_after() {
+        assert_eq!(parse_retry_after("30"), Some(Duration::from_secs(30)));
+        assert_eq!(parse_retry_after(" 5 "), Some(Duration::from_secs(5)));
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT"),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(parse_retry_after("soon"), None);
+    }
+
     #[tokio::test]
     async fn test_send_metrics_network_error() {
         let config = create_test_config("http://192.0.2.1:9999").await;
@@ -262,6 +824,9 @@ collection:
             used_space_bytes: 500000,
             available_space_bytes: 500000,
             usage_percentage: 50.0,
+            labels: BTreeMap::new(),
+            aggregate: None,
+            severity: None,
         };
 
         let session = crate::metadata::SessionInfo::generate();
@@ -275,14 +840,401 @@ collection:
         }
     }
 
+    #[tokio::test]
+    async fn test_check_health() {
+        let mock_server = MockServer::start().await;
+        let config = create_test_config(&mock_server.uri()).await;
+
+        Mock::given(method("GET"))
+            .and(path("/health"))
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&mock_server)
+            .await;
+
+        let client = ApiClient::new(&config).unwrap();
+        assert!(client.check_health().await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_check_health_unreachable() {
+        let config = create_test_config("http://192.0.2.1:9999").await;
+        let client = ApiClient::new(&config).unwrap();
+
+        match client.check_health().await.unwrap_err() {
+            ApiError::Request(_) => {}
+            _ => panic!("Expected ApiError::Request"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_deregister_resource() {
+        let mock_server = MockServer::start().await;
+        let config = create_test_config(&mock_server.uri()).await;
+
+        Mock::given(method("DELETE"))
+            .and(path("/api/v1/resources/res_123"))
+            .respond_with(ResponseTemplate::new(204))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+        Mock::given(method("DELETE"))
+            .and(path("/api/v1/resources/res_gone"))
+            .respond_with(ResponseTemplate::new(404))
+            .mount(&mock_server)
+            .await;
+
+        let client = ApiClient::new(&config).unwrap();
+        assert!(client.deregister_resource("res_123").await.is_ok());
+        assert!(client.deregister_resource("res_gone").await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_renew_registration() {
+        let mock_server = MockServer::start().await;
+        let config = create_test_config(&mock_server.uri()).await;
+
+        Mock::given(method("PUT"))
+            .and(path("/api/v1/resources/res_123"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "resource_id": "res_123",
+                "status": "renewed",
+                "expires_at": "2024-01-16T10:00:00Z"
+            })))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+        Mock::given(method("PUT"))
+            .and(path("/api/v1/resources/res_expired"))
+            .respond_with(ResponseTemplate::new(410))
+            .mount(&mock_server)
+            .await;
+
+        let client = ApiClient::new(&config).unwrap();
+        let registration = ResourceRegistration {
+            hostname: "test-host".to_string(),
+            agent_version: "0.1.0".to_string(),
+            platform: "linux".to_string(),
+            arch: "x86_64".to_string(),
+            instance_metadata: crate::metadata::InstanceMetadata::default(),
+            build: BuildInfo::current(),
+            labels: BTreeMap::new(),
+            org_id: None,
+            project: None,
+            fingerprint: None,
+            public_key: None,
+            network: None,
+            session: SessionInfo::generate(),
+        };
+
+        let renewed = client.renew_registration("res_123", &registration).await.unwrap().unwrap();
+        assert_eq!(renewed.expires_at.as_deref(), Some("2024-01-16T10:00:00Z"));
+        assert!(client.renew_registration("res_expired", &registration).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_send_heartbeat() {
+        use wiremock::matchers::body_partial_json;
+
+        let mock_server = MockServer::start().await;
+        let config = create_test_config(&mock_server.uri()).await;
+
+        Mock::given(method("POST"))
+            .and(path("/api/v1/resources/res_123/heartbeat"))
+            .and(body_partial_json(serde_json::json!({
+                "uptime_seconds": 42,
+                "buffer_depth": 3,
+                "collector_failures": {"disk": 2},
+                "last_upgrade": {"from_version": "0.0.9", "to_version": "0.1.0"},
+            })))
+            .respond_with(ResponseTemplate::new(204))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let client = ApiClient::new(&config).unwrap();
+        let heartbeat = Heartbeat {
+            agent_version: "0.1.0".to_string(),
+            uptime_seconds: 42,
+            buffer_depth: 3,
+            spooled_batches: 0,
+            maintenance: false,
+            collector_failures: BTreeMap::from([("disk".to_string(), 2)]),
+            last_upgrade: Some(AgentUpgrade {
+                from_version: "0.0.9".to_string(),
+                to_version: "0.1.0".to_string(),
+                upgraded_at: "2024-01-15T10:30:00Z".to_string(),
+            }),
+            timestamp: 1640995200,
+        };
+        assert!(client.send_heartbeat("res_123", &heartbeat).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_post_command_result() {
+        use wiremock::matchers::body_partial_json;
+
+        let mock_server = MockServer::start().await;
+        let config = create_test_config(&mock_server.uri()).await;
+
+        Mock::given(method("POST"))
+            .and(path("/api/v1/resources/res_123/commands/cmd_1/result"))
+            .and(body_partial_json(serde_json::json!({"status": "succeeded"})))
+            .respond_with(ResponseTemplate::new(204))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let client = ApiClient::new(&config).unwrap();
+        let result = CommandResult::succeeded(Some(serde_json::json!({"flushed": 3})));
+        assert!(client.post_command_result("res_123", "cmd_1", &result).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_poll_commands() {
+        let mock_server = MockServer::start().await;
+        let config = create_test_config(&mock_server.uri()).await;
+
+        Mock::given(method("GET"))
+            .and(path("/api/v1/resources/res_123/commands"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!([
+                {"payload": "{\"id\":\"cmd_1\"}", "signature": "abcd"}
+            ])))
+            .mount(&mock_server)
+            .await;
+
+        let client = ApiClient::new(&config).unwrap();
+        let commands = client.poll_commands("res_123", 1).await.unwrap();
+        assert_eq!(commands.len(), 1);
+        assert_eq!(commands[0].signature, "abcd");
+    }
+
+    #[tokio::test]
+    async fn test_requests_signed_with_identity() {
+        use base64::Engine;
+        use ring::signature::{UnparsedPublicKey, ED25519};
+
+        let mock_server = MockServer::start().await;
+        let config = create_test_config_with_api_key(&mock_server.uri(), "test-key").await;
+
+        Mock::given(method("POST"))
+            .and(path("/api/v1/resources/res_123/commands/cmd_1/result"))
+            .respond_with(ResponseTemplate::new(204))
+            .mount(&mock_server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/api/v1/resources/res_123/commands"))
+            .respond_with(ResponseTemplate::new(204))
+            .mount(&mock_server)
+            .await;
+
+        let dir = tempfile::tempdir().unwrap();
+        let identity = AgentIdentity::load_or_generate(dir.path(), None).unwrap();
+        let client = ApiClient::new(&config).unwrap().with_identity(identity);
+        let result = CommandResult::succeeded(None);
+        client.post_command_result("res_123", "cmd_1", &result).await.unwrap();
+        client.poll_commands("res_123", 1).await.unwrap();
+
+        let public_key = client.public_key().unwrap();
+        let verifier = UnparsedPublicKey::new(
+            &ED25519,
+            base64::engine::general_purpose::STANDARD.decode(&public_key).unwrap(),
+        );
+        let requests = mock_server.received_requests().await.unwrap();
+        let targets = [
+            "/api/v1/resources/res_123/commands/cmd_1/result",
+            "/api/v1/resources/res_123/commands?wait=1",
+        ];
+        for (request, target) in requests.iter().zip(targets) {
+            let header = |name: &str| request.headers.get(&name.into()).unwrap().last().to_string();
+            assert_eq!(header(identity::KEY_HEADER), public_key);
+            assert_eq!(header("Authorization"), "Bearer test-key");
+            let timestamp: i64 = header(identity::TIMESTAMP_HEADER).parse().unwrap();
+            let signature = base64::engine::general_purpose::STANDARD
+                .decode(header(identity::SIGNATURE_HEADER))
+                .unwrap();
+            let payload = identity::signing_payload(request.method.as_ref(), target, timestamp, &request.body);
+            assert!(verifier.verify(payload.as_bytes(), &signature).is_ok(), "{} not signed", target);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_oauth_token_sent_as_bearer() {
+        use wiremock::matchers::header;
+
+        let mock_server = MockServer::start().await;
+        let mut config = create_test_config(&mock_server.uri()).await;
+        config.api.oauth = Some(crate::config::OAuthConfig {
+            token_url: format!("{}/oauth/token", mock_server.uri()),
+            client_id: "agent".to_string(),
+            client_secret: Some("s3cret".to_string()),
+            scopes: None,
+            registration_scopes: None,
+            metrics_scopes: None,
+            audience: None,
+            resource: None,
+            device_authorization_url: None,
+            revocation_url: None,
+            token_cache: None,
+        });
+
+        Mock::given(method("POST"))
+            .and(path("/oauth/token"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "access_token": "tok-1", "token_type": "Bearer", "expires_in": 3600
+            })))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/api/v1/resources"))
+            .and(header("Authorization", "Bearer tok-1"))
+            .respond_with(ResponseTemplate::new(201).set_body_json(serde_json::json!({
+                "resource_id": "res_123", "status": "registered"
+            })))
+            .mount(&mock_server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/api/v1/metrics"))
+            .and(header("Authorization", "Bearer tok-1"))
+            .respond_with(ResponseTemplate::new(202))
+            .mount(&mock_server)
+            .await;
+
+        let client = ApiClient::new(&config).unwrap();
+        let registration = ResourceRegistration {
+            hostname: "test-host".to_string(),
+            agent_version: "0.1.0".to_string(),
+            platform: "linux".to_string(),
+            arch: "x86_64".to_string(),
+            instance_metadata: crate::metadata::InstanceMetadata::default(),
+            build: BuildInfo::current(),
+            labels: BTreeMap::new(),
+            org_id: None,
+            project: None,
+            fingerprint: None,
+            public_key: None,
+            network: None,
+            session: SessionInfo::generate(),
+        };
+        assert_eq!(client.register_resource(&registration).await.unwrap().resource_id, "res_123");
+
+        let service = MetricService::new(&config);
+        let batch = service.create_batch(vec![], "res_123", "test-host", SessionInfo::generate());
+        client.send_metrics(&batch).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_rejected_oauth_token_is_replaced_once() {
+        use wiremock::matchers::header;
+
+        let mock_server = MockServer::start().await;
+        let mut config = create_test_config(&mock_server.uri()).await;
+        config.api.api_key = None;
+        config.api.oauth = Some(crate::config::OAuthConfig {
+            token_url: format!("{}/oauth/token", mock_server.uri()),
+            client_id: "agent".to_string(),
+            client_secret: Some("s3cret".to_string()),
+            scopes: None,
+            registration_scopes: None,
+            metrics_scopes: None,
+            audience: None,
+            resource: None,
+            device_authorization_url: None,
+            revocation_url: None,
+            token_cache: None,
+        });
+
+        Mock::given(method("POST"))
+            .and(path("/oauth/token"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "access_token": "revoked", "token_type": "Bearer", "expires_in": 3600
+            })))
+            .up_to_n_times(1)
+            .mount(&mock_server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/oauth/token"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "access_token": "tok-2", "token_type": "Bearer", "expires_in": 3600
+            })))
+            .expect(2)
+            .mount(&mock_server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/api/v1/resources/res_123/heartbeat"))
+            .and(header("Authorization", "Bearer tok-2"))
+            .respond_with(ResponseTemplate::new(204))
+            .expect(2)
+            .mount(&mock_server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/api/v1/resources/res_456/heartbeat"))
+            .respond_with(ResponseTemplate::new(401))
+            .expect(2)
+            .mount(&mock_server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/api/v1/resources/res_123/heartbeat"))
+            .respond_with(ResponseTemplate::new(401))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let client = ApiClient::new(&config).unwrap();
+        let heartbeat = Heartbeat {
+            agent_version: "0.1.0".to_string(),
+            uptime_seconds: 42,
+            buffer_depth: 0,
+            spooled_batches: 0,
+            maintenance: false,
+            collector_failures: BTreeMap::new(),
+            last_upgrade: None,
+            timestamp: 1640995200,
+        };
+        client.send_heartbeat("res_123", &heartbeat).await.unwrap();
+        client.send_heartbeat("res_123", &heartbeat).await.unwrap();
+
+        // A 401 that a new token does not fix is returned after one retry
+        let result = client.send_heartbeat("res_456", &heartbeat).await;
+        assert!(matches!(result, Err(ApiError::Response { status: 401, .. })));
+    }
+
+    #[tokio::test]
+    async fn test_fetch_remote_config() {
+        let mock_server = MockServer::start().await;
+        let config = create_test_config(&mock_server.uri()).await;
+
+        Mock::given(method("GET"))
+            .and(path("/api/v1/agents/res_123/config"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_json(serde_json::json!({ "interval_seconds": 30 }))
+                    .insert_header(SIGNATURE_HEADER, "abcd"),
+            )
+            .mount(&mock_server)
+            .await;
+
+        let client = ApiClient::new(&config).unwrap();
+        let remote = client.fetch_remote_config("res_123").await.unwrap().unwrap();
+        assert_eq!(remote.signature.as_deref(), Some("abcd"));
+        assert_eq!(remote.verify(None).unwrap().interval_seconds, Some(30));
+
+        // No remote config published for other agents
+        let missing = client.fetch_remote_config("res_456").await.unwrap();
+        assert!(missing.is_none());
+    }
+
     #[tokio::test]
     async fn test_resource_registration_with_api_key() {
+        use wiremock::matchers::body_partial_json;
+
         let mock_server = MockServer::start().await;
         let config = create_test_config_with_api_key(&mock_server.uri(), "test-api-key").await;
         
         Mock::given(method("POST"))
             .and(path("/api/v1/resources"))
-            .respond_with(ResponseTemplate::new(201).set_body_json(&serde_json::json!({
+            .and(body_partial_json(serde_json::json!({"labels": {"env": "prod"}, "org_id": "org_42"})))
+            .respond_with(ResponseTemplate::new(201).set_body_json(serde_json::json!({
                 "resource_id": "res_123456789",
                 "status": "registered",
                 "message": "Resource registered successfully"
@@ -292,12 +1244,7 @@ collection:
 
         let client = ApiClient::new(&config).unwrap();
         
-        let instance_metadata = crate::metadata::InstanceMetadata {
-            instance_id: None,
-            cloud_provider: None,
-            region: None,
-            instance_type: None,
-        };
+        let instance_metadata = crate::metadata::InstanceMetadata::default();
 
         let registration = ResourceRegistration {
             hostname: "test-host".to_string(),
@@ -305,6 +1252,14 @@ collection:
             platform: "linux".to_string(),
             arch: "x86_64".to_string(),
             instance_metadata,
+            build: BuildInfo::current(),
+            labels: BTreeMap::from([("env".to_string(), "prod".to_string())]),
+            org_id: Some("org_42".to_string()),
+            project: None,
+            fingerprint: None,
+            public_key: None,
+            network: None,
+            session: SessionInfo::generate(),
         };
 
         let result = client.register_resource(&registration).await;
@@ -323,7 +1278,7 @@ collection:
         
         Mock::given(method("POST"))
             .and(path("/api/v1/resources"))
-            .respond_with(ResponseTemplate::new(201).set_body_json(&serde_json::json!({
+            .respond_with(ResponseTemplate::new(201).set_body_json(serde_json::json!({
                 "resource_id": "res_123456789",
                 "status": "registered"
             })))
@@ -332,12 +1287,7 @@ collection:
 
         let client = ApiClient::new(&config).unwrap();
         
-        let instance_metadata = crate::metadata::InstanceMetadata {
-            instance_id: None,
-            cloud_provider: None,
-            region: None,
-            instance_type: None,
-        };
+        let instance_metadata = crate::metadata::InstanceMetadata::default();
 
         let registration = ResourceRegistration {
             hostname: "test-host".to_string(),
@@ -345,9 +1295,38 @@ collection:
             platform: "linux".to_string(),
             arch: "x86_64".to_string(),
             instance_metadata,
+            build: BuildInfo::current(),
+            labels: BTreeMap::new(),
+            org_id: None,
+            project: None,
+            fingerprint: None,
+            public_key: None,
+            network: None,
+            session: SessionInfo::generate(),
         };
 
         let result = client.register_resource(&registration).await;
         assert!(result.is_ok());
     }
+
+    #[tokio::test]
+    async fn test_clock_offset_from_date_header() {
+        let mock_server = MockServer::start().await;
+        let config = create_test_config(&mock_server.uri()).await;
+
+        Mock::given(method("GET"))
+            .and(path("/health"))
+            .respond_with(
+                ResponseTemplate::new(200).insert_header("Date", "Sat, 01 Jan 2000 00:00:00 GMT"),
+            )
+            .mount(&mock_server)
+            .await;
+
+        let client = ApiClient::new(&config).unwrap();
+        assert_eq!(client.clock_offset_seconds(), None);
+
+        client.check_health().await.unwrap();
+        let offset = client.clock_offset_seconds().unwrap();
+        assert!(offset < -365 * 24 * 3600, "offset {}", offset);
+    }
 }
@@ -0,0 +1,63 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+/// HTTP dates have one-second resolution, so smaller offsets are noise
+pub const MIN_SKEW_SECONDS: i64 = 2;
+
+/// Detected difference between the API server clock and the local clock
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct ClockSkew {
+    /// Server time minus local time; positive when the local clock is behind
+    pub offset_seconds: i64,
+    /// Metric timestamps in the batch were shifted by `offset_seconds`
+    pub corrected: bool,
+}
+
+/// Estimate the clock offset from a response `Date` header
+///
+/// The server time is compared against the midpoint of the request, which
+/// cancels out symmetric network latency.
+pub fn estimate_offset(
+    date_header: &str,
+    sent_at: DateTime<Utc>,
+    received_at: DateTime<Utc>,
+) -> Option<i64> {
+    let server_time = DateTime::parse_from_rfc2822(date_header).ok()?;
+    let midpoint = sent_at + (received_at - sent_at) / 2;
+    Some((server_time.with_timezone(&Utc) - midpoint).num_seconds())
+}
+
+/// Offsets within the Date header's resolution are treated as no skew
+pub fn significant(offset_seconds: i64) -> Option<i64> {
+    (offset_seconds.abs() >= MIN_SKEW_SECONDS).then_some(offset_seconds)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_estimate_offset() {
+        let sent_at = DateTime::parse_from_rfc3339("2024-01-01T12:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        let received_at = sent_at + chrono::Duration::seconds(2);
+
+        // Server is five minutes ahead of the request midpoint
+        let offset = estimate_offset("Mon, 01 Jan 2024 12:05:01 GMT", sent_at, received_at);
+        assert_eq!(offset, Some(300));
+
+        let offset = estimate_offset("Mon, 01 Jan 2024 11:59:01 GMT", sent_at, received_at);
+        assert_eq!(offset, Some(-60));
+
+        assert_eq!(estimate_offset("yesterday", sent_at, received_at), None);
+    }
+
+    #[test]
+    fn test_significant() {
+        assert_eq!(significant(1), None);
+        assert_eq!(significant(-1), None);
+        assert_eq!(significant(-2), Some(-2));
+        assert_eq!(significant(300), Some(300));
+    }
+}
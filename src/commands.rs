@@ -0,0 +1,475 @@
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+
+use crate::client::ApiClient;
+use crate::config::CommandChannelConfig;
+use crate::status::StatusHandle;
+use crate::supervisor;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Commands are rejected once they are older than this, to limit replay
+const MAX_COMMAND_AGE_SECONDS: u64 = 300;
+
+/// How far ahead of the agent's clock a command may be dated
+const MAX_CLOCK_SKEW_SECONDS: u64 = 60;
+
+/// Command IDs remembered to refuse replays; they are forgotten once expired
+const MAX_SEEN_COMMANDS: usize = 1024;
+
+/// Delay before re-polling after the command endpoint fails
+const POLL_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// Tracing target for the command audit trail, e.g. `filters: { audit: info }`
+pub const AUDIT_TARGET: &str = "audit";
+
+/// Command as delivered by the platform: a JSON payload and its HMAC-SHA256 signature
+#[derive(Debug, Clone, Deserialize)]
+pub struct SignedCommand {
+    /// Raw JSON payload, signed byte-for-byte
+    pub payload: String,
+    /// Hex-encoded HMAC-SHA256 of `payload` using the shared signing key
+    pub signature: String,
+}
+
+/// Verified command payload
+#[derive(Debug, Clone, Deserialize)]
+pub struct CommandEnvelope {
+    pub id: String,
+    /// Resource the command is addressed to, so it cannot be replayed on another host
+    pub resource_id: String,
+    /// Unix timestamp at which the platform issued the command
+    pub issued_at: u64,
+    #[serde(flatten)]
+    pub command: AgentCommand,
+}
+
+/// The set of predefined tasks the agent knows how to execute
+///
+/// This is deliberately a closed set: the platform can never run arbitrary
+/// programs or shell commands through the command channel.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum AgentCommand {
+    /// Flush the metric buffer immediately
+    FlushNow,
+    /// Change the collection interval
+    SetInterval { interval_seconds: u64 },
+    /// Report a summary of the agent's state
+    RunDiagnostic,
+    /// Run the `sentinel-agent diagnose` checks
+    Diagnose,
+    /// Detect cloud metadata and list disks right away
+    CollectInventory,
+}
+
+impl AgentCommand {
+    /// Name used in the allowlist
+    pub fn name(&self) -> &'static str {
+        match self {
+            AgentCommand::FlushNow => "flush_now",
+            AgentCommand::SetInterval { .. } => "set_interval",
+            AgentCommand::RunDiagnostic => "run_diagnostic",
+            AgentCommand::Diagnose => "diagnose",
+            AgentCommand::CollectInventory => "collect_inventory",
+        }
+    }
+}
+
+/// Outcome of a command, posted back to the platform
+#[derive(Debug, Clone, Serialize)]
+pub struct CommandResult {
+    pub status: CommandStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub output: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Unix timestamp at which the agent finished handling the command
+    pub completed_at: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CommandStatus {
+    Succeeded,
+    Failed,
+    /// Refused before execution (not allowlisted or expired)
+    Rejected,
+}
+
+impl CommandResult {
+    pub fn succeeded(output: Option<serde_json::Value>) -> Self {
+        Self::new(CommandStatus::Succeeded, output, None)
+    }
+
+    pub fn failed(error: impl Into<String>) -> Self {
+        Self::new(CommandStatus::Failed, None, Some(error.into()))
+    }
+
+    pub fn rejected(error: impl Into<String>) -> Self {
+        Self::new(CommandStatus::Rejected, None, Some(error.into()))
+    }
+
+    fn new(
+        status: CommandStatus,
+        output: Option<serde_json::Value>,
+        error: Option<String>,
+    ) -> Self {
+        Self {
+            status,
+            output,
+            error,
+            completed_at: unix_now(),
+        }
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Verifies signatures, addressing, freshness and allowlisting of incoming commands
+pub struct CommandVerifier {
+    signing_key: Vec<u8>,
+    allowed_commands: Vec<String>,
+    resource_id: String,
+    /// IDs of accepted commands and when they were issued
+    seen: HashMap<String, u64>,
+}
+
+impl CommandVerifier {
+    pub fn new(config: &CommandChannelConfig, resource_id: &str) -> Self {
+        Self {
+            signing_key: config.signing_key.as_bytes().to_vec(),
+            allowed_commands: config.get_allowed_commands(),
+            resource_id: resource_id.to_string(),
+            seen: HashMap::new(),
+        }
+    }
+
+    pub fn verify(&mut self, signed: &SignedCommand) -> Result<CommandEnvelope, CommandError> {
+        let signature =
+            hex::decode(signed.signature.trim()).map_err(|_| CommandError::InvalidSignature)?;
+
+        let mut mac = HmacSha256::new_from_slice(&self.signing_key)
+            .map_err(|_| CommandError::InvalidSignature)?;
+        mac.update(signed.payload.as_bytes());
+        mac.verify_slice(&signature)
+            .map_err(|_| CommandError::InvalidSignature)?;
+
+        let envelope: CommandEnvelope = serde_json::from_str(&signed.payload)
+            .map_err(|e| CommandError::Parse(e.to_string()))?;
+
+        if envelope.resource_id != self.resource_id {
+            return Err(CommandError::WrongResource(envelope.id));
+        }
+
+        let now = unix_now();
+        if envelope.issued_at > now + MAX_CLOCK_SKEW_SECONDS {
+            return Err(CommandError::FutureDated(envelope.id));
+        }
+        if now.saturating_sub(envelope.issued_at) > MAX_COMMAND_AGE_SECONDS {
+            return Err(CommandError::Expired(envelope.id));
+        }
+        if self.seen.contains_key(&envelope.id) {
+            return Err(CommandError::Replayed(envelope.id));
+        }
+
+        let name = envelope.command.name();
+        if !self.allowed_commands.iter().any(|allowed| allowed == name) {
+            return Err(CommandError::NotAllowed {
+                id: envelope.id,
+                command: name.to_string(),
+            });
+        }
+
+        self.remember(&envelope, now);
+        Ok(envelope)
+    }
+
+    /// Record an accepted command until it could no longer pass the age check
+    fn remember(&mut self, envelope: &CommandEnvelope, now: u64) {
+        self.seen
+            .retain(|_, issued_at| now.saturating_sub(*issued_at) <= MAX_COMMAND_AGE_SECONDS);
+        if self.seen.len() >= MAX_SEEN_COMMANDS {
+            // Forgetting the oldest is safe only once it has expired; until then
+            // a flood of fresh commands pushes out the oldest of them
+            if let Some(oldest) = self
+                .seen
+                .iter()
+                .min_by_key(|(_, issued_at)| **issued_at)
+                .map(|(id, _)| id.clone())
+            {
+                self.seen.remove(&oldest);
+            }
+        }
+        self.seen.insert(envelope.id.clone(), envelope.issued_at);
+    }
+}
+
+/// Start long-polling the platform for commands
+///
+/// Verified commands are delivered on the returned channel; rejected ones are
+/// audited and reported back to the platform.
+pub fn spawn_command_channel(
+    api_client: ApiClient,
+    resource_id: String,
+    config: CommandChannelConfig,
+    status: StatusHandle,
+) -> (mpsc::Receiver<CommandEnvelope>, JoinHandle<()>) {
+    let (sender, receiver) = mpsc::channel(16);
+
+    let task = supervisor::supervise("command_channel", status, move || {
+        poll_commands(
+            api_client.clone(),
+            resource_id.clone(),
+            config.clone(),
+            sender.clone(),
+        )
+    });
+
+    (receiver, task)
+}
+
+/// Long-poll for commands and forward verified ones until the agent loop shuts down
+async fn poll_commands(
+    api_client: ApiClient,
+    resource_id: String,
+    config: CommandChannelConfig,
+    sender: mpsc::Sender<CommandEnvelope>,
+) -> Result<(), String> {
+    let mut verifier = CommandVerifier::new(&config, &resource_id);
+    let wait_seconds = config.get_poll_timeout_seconds();
+
+    loop {
+        let commands = match api_client.poll_commands(&resource_id, wait_seconds).await {
+            Ok(commands) => commands,
+            Err(e) => {
+                warn!(error = %e, "Command channel poll failed");
+                tokio::time::sleep(POLL_RETRY_DELAY).await;
+                continue;
+            }
+        };
+
+        for signed in commands {
+            match verifier.verify(&signed) {
+                Ok(envelope) => {
+                    info!(
+                        target: AUDIT_TARGET,
+                        command_id = %envelope.id,
+                        command = envelope.command.name(),
+                        "Command accepted"
+                    );
+                    if sender.send(envelope).await.is_err() {
+                        // Agent loop has shut down
+                        return Ok(());
+                    }
+                }
+                Err(e) => {
+                    warn!(
+                        target: AUDIT_TARGET,
+                        command_id = e.command_id().unwrap_or("unknown"),
+                        error = %e,
+                        "Command rejected"
+                    );
+                    // A replay must not overwrite the result of the first delivery
+                    if let Some(id) = e.command_id().filter(|_| !matches!(e, CommandError::Replayed(_))) {
+                        let result = CommandResult::rejected(e.to_string());
+                        if let Err(e) = api_client.post_command_result(&resource_id, id, &result).await {
+                            warn!(error = %e, "Failed to report command result");
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum CommandError {
+    #[error("Invalid command signature")]
+    InvalidSignature,
+    #[error("Failed to parse command payload: {0}")]
+    Parse(String),
+    #[error("Command {0} has expired")]
+    Expired(String),
+    #[error("Command {0} is dated in the future")]
+    FutureDated(String),
+    #[error("Command {0} was already received")]
+    Replayed(String),
+    #[error("Command {0} is addressed to another resource")]
+    WrongResource(String),
+    #[error("Command '{command}' is not in the allowlist")]
+    NotAllowed { id: String, command: String },
+}
+
+impl CommandError {
+    /// ID of the rejected command, when the payload was authentic enough to read it
+    pub fn command_id(&self) -> Option<&str> {
+        match self {
+            CommandError::Expired(id)
+            | CommandError::FutureDated(id)
+            | CommandError::Replayed(id)
+            | CommandError::WrongResource(id)
+            | CommandError::NotAllowed { id, .. } => Some(id),
+            CommandError::InvalidSignature | CommandError::Parse(_) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create_verifier(allowed: Option<Vec<&str>>) -> CommandVerifier {
+        let config = CommandChannelConfig {
+            enabled: true,
+            signing_key: "test-secret".to_string(),
+            allowed_commands: allowed.map(|a| a.into_iter().map(String::from).collect()),
+            poll_timeout_seconds: None,
+        };
+        CommandVerifier::new(&config, "res_1")
+    }
+
+    fn sign(payload: &str, key: &str) -> SignedCommand {
+        let mut mac = HmacSha256::new_from_slice(key.as_bytes()).unwrap();
+        mac.update(payload.as_bytes());
+        SignedCommand {
+            payload: payload.to_string(),
+            signature: hex::encode(mac.finalize().into_bytes()),
+        }
+    }
+
+    fn now() -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+    }
+
+    #[test]
+    fn test_verify_valid_command() {
+        let mut verifier = create_verifier(None);
+        let payload = format!(
+            r#"{{"id":"cmd_1","resource_id":"res_1","issued_at":{},"command":"set_interval","interval_seconds":30}}"#,
+            now()
+        );
+
+        let envelope = verifier.verify(&sign(&payload, "test-secret")).unwrap();
+        assert_eq!(envelope.id, "cmd_1");
+        assert_eq!(
+            envelope.command,
+            AgentCommand::SetInterval { interval_seconds: 30 }
+        );
+    }
+
+    #[test]
+    fn test_verify_rejects_bad_signature() {
+        let mut verifier = create_verifier(None);
+        let payload = format!(r#"{{"id":"cmd_1","resource_id":"res_1","issued_at":{},"command":"flush_now"}}"#, now());
+
+        let result = verifier.verify(&sign(&payload, "wrong-secret"));
+        assert!(matches!(result, Err(CommandError::InvalidSignature)));
+    }
+
+    #[test]
+    fn test_verify_rejects_expired() {
+        let mut verifier = create_verifier(None);
+        let payload = format!(
+            r#"{{"id":"cmd_1","resource_id":"res_1","issued_at":{},"command":"flush_now"}}"#,
+            now() - MAX_COMMAND_AGE_SECONDS - 60
+        );
+
+        let result = verifier.verify(&sign(&payload, "test-secret"));
+        assert!(matches!(result, Err(CommandError::Expired(_))));
+    }
+
+    #[test]
+    fn test_verify_rejects_future_dated() {
+        let mut verifier = create_verifier(None);
+        let payload = format!(
+            r#"{{"id":"cmd_1","resource_id":"res_1","issued_at":{},"command":"flush_now"}}"#,
+            now() + MAX_CLOCK_SKEW_SECONDS + 60
+        );
+
+        let result = verifier.verify(&sign(&payload, "test-secret"));
+        assert!(matches!(result, Err(CommandError::FutureDated(_))));
+    }
+
+    #[test]
+    fn test_verify_rejects_replay_and_other_resource() {
+        let mut verifier = create_verifier(None);
+        let payload = format!(
+            r#"{{"id":"cmd_1","resource_id":"res_1","issued_at":{},"command":"flush_now"}}"#,
+            now()
+        );
+        let signed = sign(&payload, "test-secret");
+        assert!(verifier.verify(&signed).is_ok());
+        assert!(matches!(verifier.verify(&signed), Err(CommandError::Replayed(_))));
+
+        let payload = format!(
+            r#"{{"id":"cmd_2","resource_id":"res_2","issued_at":{},"command":"flush_now"}}"#,
+            now()
+        );
+        let result = verifier.verify(&sign(&payload, "test-secret"));
+        assert!(matches!(result, Err(CommandError::WrongResource(_))));
+    }
+
+    #[test]
+    fn test_verify_rejects_not_allowed() {
+        let mut verifier = create_verifier(Some(vec!["flush_now"]));
+        let payload = format!(
+            r#"{{"id":"cmd_1","resource_id":"res_1","issued_at":{},"command":"run_diagnostic"}}"#,
+            now()
+        );
+
+        let result = verifier.verify(&sign(&payload, "test-secret"));
+        assert!(matches!(result, Err(CommandError::NotAllowed { .. })));
+        assert_eq!(result.unwrap_err().command_id(), Some("cmd_1"));
+    }
+
+    #[test]
+    fn test_verify_predefined_tasks() {
+        let mut verifier = create_verifier(None);
+        for (name, expected) in [
+            ("diagnose", AgentCommand::Diagnose),
+            ("collect_inventory", AgentCommand::CollectInventory),
+        ] {
+            let payload = format!(
+                r#"{{"id":"cmd_{}","resource_id":"res_1","issued_at":{},"command":"{}"}}"#,
+                name,
+                now(),
+                name
+            );
+            let envelope = verifier.verify(&sign(&payload, "test-secret")).unwrap();
+            assert_eq!(envelope.command, expected);
+            assert_eq!(envelope.command.name(), name);
+        }
+    }
+
+    #[test]
+    fn test_command_result_serialization() {
+        let json = serde_json::to_value(CommandResult::failed("boom")).unwrap();
+        assert_eq!(json["status"], "failed");
+        assert_eq!(json["error"], "boom");
+        assert!(json.get("output").is_none());
+    }
+
+    #[test]
+    fn test_verify_rejects_unknown_command() {
+        let mut verifier = create_verifier(None);
+        let payload = format!(
+            r#"{{"id":"cmd_1","resource_id":"res_1","issued_at":{},"command":"shell","script":"rm -rf /"}}"#,
+            now()
+        );
+
+        let result = verifier.verify(&sign(&payload, "test-secret"));
+        assert!(matches!(result, Err(CommandError::Parse(_))));
+    }
+}
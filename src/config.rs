@@ -1,103 +1,2671 @@
+use schemars::JsonSchema;
 use serde::Deserialize;
-use std::path::Path;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::LazyLock;
 
-#[derive(Debug, Deserialize, Clone)]
+use crate::scripting::{Script, ScriptLimits};
+use crate::units;
+
+#[derive(Debug, Deserialize, JsonSchema, Clone)]
 pub struct Config {
+    /// Layout of this file; older layouts are migrated on load (default: 1)
+    pub version: Option<u32>,
+    #[serde(deserialize_with = "null_as_default")]
     pub agent: AgentConfig,
     pub api: ApiConfig,
     pub collection: CollectionConfig,
+    pub commands: Option<CommandChannelConfig>,
+    pub remote_config: Option<RemoteConfigSettings>,
+    pub logging: Option<LoggingConfig>,
+    pub status: Option<StatusConfig>,
+    pub alerts: Option<AlertsConfig>,
+    pub thresholds: Option<ThresholdsConfig>,
+    pub limits: Option<LimitsConfig>,
+    pub scripting: Option<ScriptingConfig>,
+    pub offline: Option<OfflineConfig>,
+    pub metadata: Option<MetadataConfig>,
+    /// Refuse to start when the file has keys the agent does not recognise
+    /// (default: true); when false they are logged and ignored
+    pub strict_keys: Option<bool>,
+    /// What migrating an older layout changed, to be logged once logging is up
+    #[serde(skip)]
+    pub migration_warnings: Vec<String>,
+    /// Unknown keys tolerated with `strict_keys: false`, to be logged likewise
+    #[serde(skip)]
+    pub unknown_key_warnings: Vec<String>,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Default, Deserialize, JsonSchema, Clone)]
 pub struct AgentConfig {
+    /// Name reported to the platform; implies `hostname_mode: custom`
     pub hostname: Option<String>,
+    /// How the reported name is determined (default: `custom` with `hostname`, else `short`)
+    pub hostname_mode: Option<HostnameMode>,
+    /// Mark the resource decommissioned on clean shutdown (for ephemeral instances)
+    pub deregister_on_shutdown: Option<bool>,
+    /// Pause collection and sending while true
+    pub maintenance: Option<bool>,
+    /// Touch-file that pauses the agent while present (default: `maintenance` next to the state file)
+    pub maintenance_file: Option<PathBuf>,
+    /// Attached to registration and every metric batch, e.g. `env: prod`
+    pub labels: Option<BTreeMap<String, String>>,
+    /// Operion organization (tenant) this agent reports to, for API keys valid in several
+    pub org_id: Option<String>,
+    /// Project within `org_id`
+    pub project: Option<String>,
+    /// Unprivileged user to switch to after startup when started as root (Unix only)
+    pub user: Option<String>,
+    /// Group to switch to with `user` (default: the user's primary group)
+    pub group: Option<String>,
+}
+
+#[derive(Debug, Deserialize, JsonSchema, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum HostnameMode {
+    /// The kernel host name, as `hostname` prints it
+    Short,
+    /// The fully qualified name from the system resolver
+    Fqdn,
+    /// `agent.hostname`
+    Custom,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, JsonSchema, Clone)]
 pub struct ApiConfig {
     pub endpoint: String,
+    #[serde(default, deserialize_with = "units::option_seconds")]
+    #[schemars(schema_with = "units::duration_schema")]
     pub timeout_seconds: Option<u64>,
     pub api_key: Option<String>,
+    /// OAuth 2.0 client credentials, instead of `api_key`
+    pub oauth: Option<OAuthConfig>,
+    #[serde(default, deserialize_with = "units::option_seconds")]
+    #[schemars(schema_with = "units::duration_schema")]
+    pub health_check_interval_seconds: Option<u64>,
+    #[serde(default, deserialize_with = "units::option_seconds")]
+    #[schemars(schema_with = "units::duration_schema")]
+    pub heartbeat_interval_seconds: Option<u64>,
+    pub tls: Option<TlsConfig>,
+    /// Further endpoints or tenants to register with and copy every batch to
+    pub additional_endpoints: Option<Vec<AdditionalEndpoint>>,
+}
+
+/// OAuth 2.0 client whose short-lived access tokens are sent to the API
+#[derive(Debug, Deserialize, JsonSchema, Clone)]
+pub struct OAuthConfig {
+    pub token_url: String,
+    pub client_id: String,
+    /// Secret for the client credentials grant; without it, tokens come from `sentinel-agent login`
+    pub client_secret: Option<String>,
+    /// Scopes requested with each token (default: none, the client's defaults)
+    pub scopes: Option<Vec<String>>,
+    /// Scopes for registering, renewing and deregistering the resource (default: `scopes`)
+    pub registration_scopes: Option<Vec<String>>,
+    /// Scopes for submitting metrics (default: `scopes`)
+    pub metrics_scopes: Option<Vec<String>>,
+    /// API the tokens are minted for, sent as `audience` (e.g. Auth0)
+    pub audience: Option<String>,
+    /// Resource indicator sent as `resource` (RFC 8707, e.g. Azure AD)
+    pub resource: Option<String>,
+    /// Device authorization endpoint used by `sentinel-agent login`
+    pub device_authorization_url: Option<String>,
+    /// Token revocation endpoint, called on shutdown and deregistration
+    pub revocation_url: Option<String>,
+    /// Where the tokens from `sentinel-agent login` are kept
+    pub token_cache: Option<TokenCacheConfig>,
+}
+
+/// Storage of the tokens saved by `sentinel-agent login`
+#[derive(Debug, Deserialize, JsonSchema, Clone, Default)]
+pub struct TokenCacheConfig {
+    /// `file` (default, owner read/write only) or `keyring`, the OS credential store
+    /// (needs the keyring feature, which default builds leave out)
+    pub store: Option<TokenStoreKind>,
+    /// Token file, or with `keyring` the name of the entry (default: oauth-token.json
+    /// next to the state file; the system state directory for `login` with the system
+    /// config); give each tenant on a shared host its own
+    pub path: Option<PathBuf>,
+    /// Base64 key encrypting the token file (default: from SENTINEL_TOKEN_KEY or
+    /// SENTINEL_TOKEN_KEY_FILE; plaintext without either)
+    pub encryption_key: Option<String>,
+}
+
+#[derive(Debug, Deserialize, JsonSchema, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum TokenStoreKind {
+    File,
+    Keyring,
+}
+
+/// An endpoint reported to alongside `api.endpoint`, e.g. a customer's tenant for an MSP
+///
+/// Timeouts and TLS settings, except `server_name`, are shared with `api`.
+#[derive(Debug, Deserialize, JsonSchema, Clone)]
+pub struct AdditionalEndpoint {
+    /// Key of this endpoint's registration in the state file; keep it stable
+    pub name: String,
+    pub endpoint: String,
+    pub api_key: String,
+    /// Organization to report to here, instead of `agent.org_id`
+    pub org_id: Option<String>,
+    /// Project within `org_id`, instead of `agent.project`
+    pub project: Option<String>,
+    /// Batches waiting for this endpoint before the oldest are dropped (default: 100)
+    pub queue_size: Option<usize>,
+    /// Retries of a copy that failed with a transient error (default: 3)
+    pub max_retries: Option<u32>,
+    /// Delay before the first retry, doubling for each further one (default: 1)
+    pub retry_backoff_seconds: Option<u64>,
+}
+
+impl AdditionalEndpoint {
+    pub fn get_queue_size(&self) -> usize {
+        self.queue_size.unwrap_or(100)
+    }
+
+    pub fn get_max_retries(&self) -> u32 {
+        self.max_retries.unwrap_or(3)
+    }
+
+    pub fn get_retry_backoff_seconds(&self) -> u64 {
+        self.retry_backoff_seconds.unwrap_or(1)
+    }
+}
+
+/// TLS for outbound HTTPS: the API, alert webhooks and AWS secret lookups
+#[derive(Debug, Deserialize, JsonSchema, Clone, Default)]
+pub struct TlsConfig {
+    /// Lowest protocol version to negotiate (default: "1.2")
+    pub min_version: Option<TlsVersion>,
+    /// Cipher suites to offer, by IANA name (default: the TLS library's safe defaults)
+    pub ciphers: Option<Vec<String>>,
+    /// PEM bundle of CA certificates trusted in addition to the built-in roots
+    pub ca_file: Option<PathBuf>,
+    /// PEM certificate chain offered to servers that require mutual TLS
+    pub client_cert: Option<PathBuf>,
+    /// PEM private key of `client_cert`
+    pub client_key: Option<PathBuf>,
+    /// Name presented in SNI and verified against the API's certificate, for
+    /// endpoints given as an address or alias; also sent as the Host header
+    pub server_name: Option<String>,
+}
+
+#[derive(Debug, Deserialize, JsonSchema, Clone, Copy, PartialEq)]
+pub enum TlsVersion {
+    #[serde(rename = "1.2")]
+    V1_2,
+    #[serde(rename = "1.3")]
+    V1_3,
+}
+
+impl TlsConfig {
+    pub fn get_min_version(&self) -> TlsVersion {
+        self.min_version.unwrap_or(TlsVersion::V1_2)
+    }
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, JsonSchema, Clone)]
 pub struct CollectionConfig {
+    #[serde(deserialize_with = "units::seconds")]
+    #[schemars(schema_with = "units::duration_schema")]
     pub interval_seconds: u64,
     pub batch_size: Option<usize>,
+    /// Cap on the estimated size of buffered metrics, in addition to `batch_size`
+    #[serde(default, deserialize_with = "units::option_megabytes")]
+    #[schemars(schema_with = "units::size_schema")]
+    pub max_buffer_size_mb: Option<u64>,
+    #[serde(default, deserialize_with = "units::option_seconds")]
+    #[schemars(schema_with = "units::duration_schema")]
     pub flush_interval_seconds: Option<u64>,
+    /// Random delay of up to this many seconds added to each collection and flush
+    #[serde(default, deserialize_with = "units::option_seconds")]
+    #[schemars(schema_with = "units::duration_schema")]
+    pub jitter_seconds: Option<u64>,
+    /// Start collecting at a host-specific offset derived from the resource ID
+    pub phase_offset: Option<bool>,
+    /// Flush on wall-clock multiples of the flush interval (e.g. every minute at :00)
+    /// and report the covered window with each batch
+    pub align_flushes: Option<bool>,
+    /// Give up on a collector that has not returned after this many seconds
+    #[serde(default, deserialize_with = "units::option_seconds")]
+    #[schemars(schema_with = "units::duration_schema")]
+    pub collector_timeout_seconds: Option<u64>,
+    /// Shift metric timestamps by the clock offset detected from API responses
+    pub correct_timestamps: Option<bool>,
+    /// Roll up buffered samples per series (min/max/avg/last) before each flush
+    pub aggregate: Option<bool>,
+    /// Keep intervals and batch sizes below the agent's minimums instead of raising them
+    pub override_limits: Option<bool>,
     pub disk: DiskConfig,
+    pub delta: Option<DeltaConfig>,
+    pub spool: Option<SpoolConfig>,
+    pub adaptive: Option<AdaptiveConfig>,
+    /// Transformations applied in order to every collected metric before buffering
+    pub relabel: Option<Vec<RelabelRule>>,
+    pub plugins: Option<PluginsConfig>,
+    pub wasm: Option<WasmConfig>,
+    pub textfile: Option<TextfileConfig>,
+    pub exec: Option<ExecConfig>,
+    pub ingest: Option<IngestConfig>,
+    pub scrape: Option<ScrapeConfig>,
+    /// Rhai script run on every collection after relabeling; `metrics` in, new metrics out
+    pub script: Option<String>,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, JsonSchema, Clone)]
 pub struct DiskConfig {
     pub enabled: bool,
     pub include_mount_points: Option<Vec<String>>,
     pub exclude_mount_points: Option<Vec<String>>,
+    /// Device names to skip, as `*`/`?` wildcards on the name without `/dev/` (e.g. `loop*`)
+    pub exclude_devices: Option<Vec<String>>,
 }
 
-impl Config {
-    pub fn load_from_file<P: AsRef<Path>>(path: P) -> Result<Self, ConfigError> {
-        let contents =
-            std::fs::read_to_string(path).map_err(|e| ConfigError::FileRead(e.to_string()))?;
+#[derive(Debug, Deserialize, JsonSchema, Clone)]
+pub struct DeltaConfig {
+    pub enabled: bool,
+    /// Minimum change in usage fraction (0.0-1.0) before a metric is reported again
+    pub tolerance: Option<f64>,
+    /// Report unchanged metrics at least this often so the backend knows they still exist
+    #[serde(default, deserialize_with = "units::option_seconds")]
+    #[schemars(schema_with = "units::duration_schema")]
+    pub max_suppressed_seconds: Option<u64>,
+}
 
-        let config: Config =
-            serde_yaml::from_str(&contents).map_err(|e| ConfigError::Parse(e.to_string()))?;
+/// One step of the relabel pipeline
+///
+/// `source` and `target` name a metric field (`device`, `mount_point`) or a label.
+/// Regexes must match the whole value, as in Prometheus `relabel_configs`.
+#[derive(Debug, Deserialize, JsonSchema, Clone)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum RelabelRule {
+    /// Rewrite `target` (default: `source`) using capture groups from `regex`
+    Replace {
+        source: String,
+        regex: String,
+        replacement: String,
+        target: Option<String>,
+    },
+    /// Keep only metrics whose `source` matches
+    Keep { source: String, regex: String },
+    /// Drop metrics whose `source` matches
+    Drop { source: String, regex: String },
+    AddLabel { label: String, value: String },
+    RemoveLabel { label: String },
+    RenameLabel { from: String, to: String },
+    /// Multiply a numeric field, e.g. 100 to report usage as a percentage
+    Scale { field: String, factor: f64 },
+}
 
-        config.validate()?;
-        Ok(config)
+/// Back off collection while the host is under heavy CPU pressure
+#[derive(Debug, Deserialize, JsonSchema, Clone)]
+pub struct AdaptiveConfig {
+    pub enabled: bool,
+    /// Linux PSI `cpu some avg10` percentage that counts as pressure
+    pub cpu_pressure_threshold: Option<f64>,
+    /// 1-minute load average per CPU that counts as pressure where PSI is unavailable
+    pub load_threshold: Option<f64>,
+    /// Collection interval multiplier while under pressure
+    pub backoff_factor: Option<u32>,
+    /// Collectors skipped while under pressure
+    pub skip_collectors: Option<Vec<String>>,
+}
+
+impl AdaptiveConfig {
+    pub fn get_cpu_pressure_threshold(&self) -> f64 {
+        self.cpu_pressure_threshold.unwrap_or(50.0)
     }
 
-    #[allow(dead_code)]
-    pub fn load_from_str(contents: &str) -> Result<Self, ConfigError> {
-        let config: Config =
-            serde_yaml::from_str(contents).map_err(|e| ConfigError::Parse(e.to_string()))?;
+    pub fn get_load_threshold(&self) -> f64 {
+        self.load_threshold.unwrap_or(2.0)
+    }
 
-        config.validate()?;
-        Ok(config)
+    pub fn get_backoff_factor(&self) -> u32 {
+        self.backoff_factor.unwrap_or(4)
     }
+}
 
-    fn validate(&self) -> Result<(), ConfigError> {
-        if self.api.endpoint.is_empty() {
-            return Err(ConfigError::Validation(
-                "API endpoint cannot be empty".to_string(),
-            ));
-        }
+#[derive(Debug, Deserialize, JsonSchema, Clone)]
+pub struct PluginsConfig {
+    pub enabled: bool,
+    /// Directory of collector shared libraries (default: `plugins` in the system config directory)
+    pub directory: Option<PathBuf>,
+}
 
-        if self.collection.interval_seconds == 0 {
-            return Err(ConfigError::Validation(
-                "Collection interval must be greater than 0".to_string(),
-            ));
+/// node_exporter textfile collector settings
+#[derive(Debug, Deserialize, JsonSchema, Clone)]
+pub struct TextfileConfig {
+    pub enabled: bool,
+    /// Directory of `*.prom` files, as given to node_exporter's `--collector.textfile.directory`
+    pub directory: PathBuf,
+}
+
+/// Programs run every collection whose output is Telegraf JSON
+#[derive(Debug, Deserialize, JsonSchema, Clone)]
+pub struct ExecConfig {
+    pub enabled: bool,
+    pub commands: Vec<ExecCommand>,
+}
+
+#[derive(Debug, Deserialize, JsonSchema, Clone)]
+pub struct ExecCommand {
+    pub command: PathBuf,
+    pub args: Option<Vec<String>>,
+}
+
+/// Unix socket accepting Telegraf JSON, one document per line
+#[derive(Debug, Deserialize, JsonSchema, Clone)]
+pub struct IngestConfig {
+    pub enabled: bool,
+    /// Socket path (default: `ingest.sock` next to the state file)
+    pub path: Option<PathBuf>,
+}
+
+impl IngestConfig {
+    pub fn get_path(&self) -> PathBuf {
+        self.path.clone().unwrap_or_else(|| {
+            crate::state::ResourceState::get_state_file_path()
+                .parent()
+                .map(|dir| dir.join("ingest.sock"))
+                .unwrap_or_else(|| "ingest.sock".into())
+        })
+    }
+}
+
+/// Prometheus and OpenMetrics endpoints scraped every collection
+#[derive(Debug, Deserialize, JsonSchema, Clone)]
+pub struct ScrapeConfig {
+    pub enabled: bool,
+    pub targets: Vec<ScrapeTarget>,
+    /// Largest response read from a target; a larger one fails that scrape (default: 10)
+    #[serde(default, deserialize_with = "units::option_megabytes")]
+    #[schemars(schema_with = "units::size_schema")]
+    pub max_body_size_mb: Option<u64>,
+}
+
+#[derive(Debug, Deserialize, JsonSchema, Clone)]
+pub struct ScrapeTarget {
+    /// Collector name after `scrape:` (default: host and port of the URL)
+    pub name: Option<String>,
+    pub url: String,
+}
+
+impl ScrapeTarget {
+    pub fn get_name(&self) -> String {
+        self.name.clone().unwrap_or_else(|| {
+            reqwest::Url::parse(&self.url)
+                .ok()
+                .and_then(|url| {
+                    let host = url.host_str()?.to_string();
+                    Some(match url.port_or_known_default() {
+                        Some(port) => format!("{}:{}", host, port),
+                        None => host,
+                    })
+                })
+                .unwrap_or_else(|| self.url.clone())
+        })
+    }
+}
+
+#[derive(Debug, Deserialize, JsonSchema, Clone)]
+pub struct WasmConfig {
+    pub enabled: bool,
+    /// Directory of `.wasm` collector and transform modules (default: `wasm` in the system config directory)
+    pub directory: Option<PathBuf>,
+    /// Files, or directories, modules may read through `read_file` (default: none)
+    pub allowed_paths: Option<Vec<PathBuf>>,
+    /// Instructions a single module call may execute (default: 100000000)
+    pub fuel: Option<u64>,
+    /// Linear memory cap per module instance (default: 16)
+    #[serde(default, deserialize_with = "units::option_megabytes")]
+    #[schemars(schema_with = "units::size_schema")]
+    pub max_memory_mb: Option<u64>,
+}
+
+impl WasmConfig {
+    pub fn get_directory(&self) -> PathBuf {
+        self.directory
+            .clone()
+            .unwrap_or_else(|| crate::paths::system_config_dir().join("wasm"))
+    }
+
+    pub fn get_fuel(&self) -> u64 {
+        self.fuel.unwrap_or(100_000_000)
+    }
+
+    pub fn get_max_memory_bytes(&self) -> usize {
+        (self.max_memory_mb.unwrap_or(16) as usize).saturating_mul(1024 * 1024)
+    }
+}
+
+#[derive(Debug, Deserialize, JsonSchema, Clone)]
+pub struct SpoolConfig {
+    pub enabled: bool,
+    /// Directory for spooled batches (default: `spool` next to the state file)
+    pub directory: Option<PathBuf>,
+    #[serde(default, deserialize_with = "units::option_megabytes")]
+    #[schemars(schema_with = "units::size_schema")]
+    pub max_size_mb: Option<u64>,
+    /// Gzip spooled batches (default: true in offline mode, false otherwise)
+    pub compress: Option<bool>,
+    /// Consecutive failed flushes before batches go to disk; until then they stay buffered (default: 1)
+    pub after_failures: Option<u32>,
+}
+
+impl SpoolConfig {
+    pub fn get_max_size_bytes(&self) -> u64 {
+        self.max_size_mb.unwrap_or(100).saturating_mul(1024 * 1024)
+    }
+
+    pub fn get_after_failures(&self) -> u32 {
+        self.after_failures.unwrap_or(1).max(1)
+    }
+
+    pub fn is_compressed(&self) -> bool {
+        self.compress.unwrap_or(false)
+    }
+
+    pub fn get_directory(&self) -> PathBuf {
+        self.directory.clone().unwrap_or_else(|| {
+            crate::state::ResourceState::get_state_file_path()
+                .parent()
+                .map(|dir| dir.join("spool"))
+                .unwrap_or_else(|| "spool".into())
+        })
+    }
+}
+
+/// Run without contacting the API, keeping every batch in the spool
+#[derive(Debug, Deserialize, JsonSchema, Clone, Default)]
+pub struct OfflineConfig {
+    pub enabled: bool,
+    /// Leave offline mode and upload the backlog once the API health check passes (default: false)
+    pub auto_sync: Option<bool>,
+}
+
+impl OfflineConfig {
+    pub fn auto_sync(&self) -> bool {
+        self.auto_sync.unwrap_or(false)
+    }
+}
+
+/// Instance metadata detection
+#[derive(Debug, Deserialize, JsonSchema, Clone, Default)]
+pub struct MetadataConfig {
+    /// Cloud instance tags to report, as `*`/`?` wildcards on the tag key
+    /// (default: `Name`, `env`, `environment`, `service`); `[]` reports none
+    pub tags: Option<Vec<String>>,
+    /// Report the primary IP addresses, MAC addresses and cloud public IP at registration (default: false)
+    pub network: Option<bool>,
+    /// How often to re-detect metadata and update the registration when it changed (default: 3600, 0 disables)
+    #[serde(default, deserialize_with = "units::option_seconds")]
+    #[schemars(schema_with = "units::duration_schema")]
+    pub refresh_interval_seconds: Option<u64>,
+    /// Refuse to fall back to IMDSv1 when no IMDSv2 token can be had on AWS (default: false)
+    pub imdsv2_only: Option<bool>,
+    /// Endpoint and timeout overrides per provider
+    pub providers: Option<MetadataProvidersConfig>,
+    /// Only probe this provider, or `none` to skip cloud detection (default: probe all)
+    pub provider: Option<MetadataProvider>,
+    /// Deadline for each provider's first metadata request, all providers being probed at once;
+    /// the detected provider's remaining metadata is not held to it (default: 2000)
+    #[serde(default, deserialize_with = "units::option_millis")]
+    #[schemars(schema_with = "units::duration_schema")]
+    pub detection_timeout_ms: Option<u64>,
+    /// Extra key/values reported with the instance metadata, e.g. rack or cost center
+    pub custom: Option<BTreeMap<String, String>>,
+    /// JSON object file with more custom metadata; `custom` and
+    /// `SENTINEL_METADATA_<KEY>` variables take precedence over it
+    pub custom_file: Option<String>,
+}
+
+#[derive(Debug, Deserialize, JsonSchema, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum MetadataProvider {
+    Aws,
+    Azure,
+    Gcp,
+    Digitalocean,
+    Alibaba,
+    Linode,
+    Vultr,
+    Scaleway,
+    /// Not in a cloud; skips detection
+    None,
+}
+
+/// Metadata service overrides, one entry per provider
+#[derive(Debug, Deserialize, JsonSchema, Clone, Default)]
+pub struct MetadataProvidersConfig {
+    pub aws: Option<MetadataProviderConfig>,
+    pub azure: Option<MetadataProviderConfig>,
+    pub gcp: Option<MetadataProviderConfig>,
+    pub digitalocean: Option<MetadataProviderConfig>,
+    pub alibaba: Option<MetadataProviderConfig>,
+    pub linode: Option<MetadataProviderConfig>,
+    pub vultr: Option<MetadataProviderConfig>,
+    pub scaleway: Option<MetadataProviderConfig>,
+}
+
+impl MetadataProvidersConfig {
+    /// Overrides by provider key
+    pub fn entries(&self) -> [(&'static str, Option<&MetadataProviderConfig>); 8] {
+        [
+            ("aws", self.aws.as_ref()),
+            ("azure", self.azure.as_ref()),
+            ("gcp", self.gcp.as_ref()),
+            ("digitalocean", self.digitalocean.as_ref()),
+            ("alibaba", self.alibaba.as_ref()),
+            ("linode", self.linode.as_ref()),
+            ("vultr", self.vultr.as_ref()),
+            ("scaleway", self.scaleway.as_ref()),
+        ]
+    }
+}
+
+#[derive(Debug, Deserialize, JsonSchema, Clone, Default)]
+pub struct MetadataProviderConfig {
+    /// Base URL of the metadata service, e.g. a proxied IMDS (`http://imds-proxy:8080`)
+    pub endpoint: Option<String>,
+    /// Timeout of each request to the metadata service (default: 500ms)
+    #[serde(default, deserialize_with = "units::option_millis")]
+    #[schemars(schema_with = "units::duration_schema")]
+    pub timeout_ms: Option<u64>,
+}
+
+impl MetadataConfig {
+    /// Whether cloud detection probes `provider`
+    pub fn probes(&self, provider: MetadataProvider) -> bool {
+        self.provider.is_none_or(|pinned| pinned == provider)
+    }
+
+    pub fn get_detection_timeout_ms(&self) -> u64 {
+        self.detection_timeout_ms.unwrap_or(2000)
+    }
+
+    pub fn is_imdsv2_only(&self) -> bool {
+        self.imdsv2_only.unwrap_or(false)
+    }
+
+    pub fn get_refresh_interval_seconds(&self) -> u64 {
+        self.refresh_interval_seconds.unwrap_or(3600)
+    }
+
+    pub fn reports_network(&self) -> bool {
+        self.network.unwrap_or(false)
+    }
+
+    pub fn get_tags(&self) -> Vec<String> {
+        self.tags.clone().unwrap_or_else(|| {
+            ["Name", "env", "environment", "service"].iter().map(|tag| tag.to_string()).collect()
+        })
+    }
+}
+
+#[derive(Debug, Deserialize, JsonSchema, Clone, Default)]
+pub struct LimitsConfig {
+    /// CPU niceness applied at startup, -20 (highest priority) to 19 (lowest)
+    pub nice: Option<i32>,
+    /// I/O scheduling class applied at startup (Linux only)
+    pub io_class: Option<IoClass>,
+    /// Priority within the best-effort class, 0 (highest) to 7 (lowest; default: 7)
+    pub io_priority: Option<u8>,
+    /// Share of one CPU the agent's cgroup must be capped at, checked at startup
+    pub cpu_quota_percent: Option<f64>,
+    /// Evict buffered metrics to the spool when the agent's resident memory exceeds this
+    #[serde(default, deserialize_with = "units::option_megabytes")]
+    #[schemars(schema_with = "units::size_schema")]
+    pub max_memory_mb: Option<u64>,
+    /// Refuse to start when a limit, including `max_memory_mb`, cannot be honored
+    pub strict: Option<bool>,
+}
+
+#[derive(Debug, Deserialize, JsonSchema, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum IoClass {
+    BestEffort,
+    Idle,
+}
+
+impl LimitsConfig {
+    pub fn get_io_priority(&self) -> u8 {
+        self.io_priority.unwrap_or(7)
+    }
+
+    pub fn is_strict(&self) -> bool {
+        self.strict.unwrap_or(false)
+    }
+}
+
+#[derive(Debug, Deserialize, JsonSchema, Clone)]
+pub struct CommandChannelConfig {
+    pub enabled: bool,
+    /// Shared secret used to verify HMAC-SHA256 signatures on commands
+    pub signing_key: String,
+    /// Commands the platform may trigger (default: all supported commands)
+    pub allowed_commands: Option<Vec<String>>,
+    #[serde(default, deserialize_with = "units::option_seconds")]
+    #[schemars(schema_with = "units::duration_schema")]
+    pub poll_timeout_seconds: Option<u64>,
+}
+
+impl CommandChannelConfig {
+    pub fn get_allowed_commands(&self) -> Vec<String> {
+        self.allowed_commands.clone().unwrap_or_else(|| {
+            SUPPORTED_COMMANDS.iter().map(|c| c.to_string()).collect()
+        })
+    }
+
+    pub fn get_poll_timeout_seconds(&self) -> u64 {
+        self.poll_timeout_seconds.unwrap_or(30)
+    }
+}
+
+#[derive(Debug, Deserialize, JsonSchema, Clone)]
+pub struct RemoteConfigSettings {
+    pub enabled: bool,
+    #[serde(default, deserialize_with = "units::option_seconds")]
+    #[schemars(schema_with = "units::duration_schema")]
+    pub poll_interval_seconds: Option<u64>,
+    /// Shared secret the platform signs remote configs with (HMAC-SHA256); unsigned configs are rejected when set
+    pub signing_key: Option<String>,
+    /// Last good remote config, used when the platform cannot be reached (default: `remote-config.json` next to the state file)
+    pub cache_file: Option<PathBuf>,
+}
+
+impl RemoteConfigSettings {
+    pub fn get_poll_interval_seconds(&self) -> u64 {
+        self.poll_interval_seconds.unwrap_or(300)
+    }
+
+    pub fn get_cache_file(&self) -> PathBuf {
+        self.cache_file.clone().unwrap_or_else(|| {
+            crate::state::ResourceState::get_state_file_path()
+                .parent()
+                .map(|dir| dir.join("remote-config.json"))
+                .unwrap_or_else(|| "remote-config.json".into())
+        })
+    }
+}
+
+#[derive(Debug, Deserialize, JsonSchema, Clone)]
+pub struct AlertsConfig {
+    pub enabled: bool,
+    pub rules: Vec<AlertRule>,
+}
+
+/// Local threshold evaluated on every collection, independent of the backend
+#[derive(Debug, Deserialize, JsonSchema, Clone)]
+pub struct AlertRule {
+    pub name: String,
+    pub metric: AlertMetric,
+    /// Fires when the value is strictly greater than this
+    pub threshold: Option<f64>,
+    /// Fires when the metric reaches this level of its `thresholds` entry,
+    /// instead of comparing against `threshold`
+    pub severity: Option<Severity>,
+    /// Limit disk rules to these mount points (exact match; default: all)
+    pub mount_points: Option<Vec<String>>,
+    pub actions: Vec<AlertAction>,
+    /// Also run the actions when the value drops back below the threshold
+    pub on_resolve: Option<bool>,
+    /// Rhai expression deciding whether the rule fires, given `subject`, `value`
+    /// and `threshold` (default: `value > threshold`)
+    pub condition: Option<String>,
+    /// Rhai expression rendering the alert message from `event`
+    pub message: Option<String>,
+}
+
+#[derive(Debug, Deserialize, JsonSchema, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertMetric {
+    /// Used fraction of a filesystem (0.0-1.0)
+    DiskUsage,
+    /// Number of failed systemd units (Linux only)
+    FailedSystemdUnits,
+}
+
+/// Warning and critical levels per metric, shared by alert rules and the
+/// severity annotations on shipped metrics
+#[derive(Debug, Deserialize, JsonSchema, Clone, Default)]
+pub struct ThresholdsConfig {
+    pub disk_usage: Option<Threshold>,
+    pub failed_systemd_units: Option<Threshold>,
+}
+
+#[derive(Debug, Deserialize, JsonSchema, Clone, Copy, Default)]
+pub struct Threshold {
+    /// A value strictly greater than this is a warning
+    pub warn: Option<f64>,
+    /// A value strictly greater than this is critical
+    pub critical: Option<f64>,
+    /// How long a value must stay above a level before it takes that severity (default: 0)
+    #[serde(default, deserialize_with = "units::option_seconds")]
+    #[schemars(schema_with = "units::duration_schema")]
+    pub duration_seconds: Option<u64>,
+}
+
+/// How far a value is over its thresholds, lowest first
+#[derive(Debug, Deserialize, serde::Serialize, JsonSchema, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Warn,
+    Critical,
+}
+
+impl ThresholdsConfig {
+    pub fn get(&self, metric: AlertMetric) -> Option<&Threshold> {
+        match metric {
+            AlertMetric::DiskUsage => self.disk_usage.as_ref(),
+            AlertMetric::FailedSystemdUnits => self.failed_systemd_units.as_ref(),
         }
+    }
+}
 
-        // Validate API key if present
-        if let Some(api_key) = &self.api.api_key {
-            if api_key.trim().is_empty() {
-                return Err(ConfigError::Validation(
-                    "API key cannot be empty".to_string(),
-                ));
-            }
+impl Threshold {
+    pub fn level(&self, severity: Severity) -> Option<f64> {
+        match severity {
+            Severity::Warn => self.warn,
+            Severity::Critical => self.critical,
         }
+    }
 
-        Ok(())
+    pub fn get_duration_seconds(&self) -> u64 {
+        self.duration_seconds.unwrap_or(0)
     }
+}
 
-    pub fn get_hostname(&self) -> String {
-        self.agent
-            .hostname
+#[derive(Debug, Deserialize, JsonSchema, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum AlertAction {
+    /// Run a program with alert details in `SENTINEL_ALERT_*` environment variables
+    Exec {
+        command: PathBuf,
+        args: Option<Vec<String>>,
+        #[serde(default, deserialize_with = "units::option_seconds")]
+        #[schemars(schema_with = "units::duration_schema")]
+        timeout_seconds: Option<u64>,
+    },
+    /// POST the alert as JSON
+    Webhook {
+        url: String,
+        #[serde(default, deserialize_with = "units::option_seconds")]
+        #[schemars(schema_with = "units::duration_schema")]
+        timeout_seconds: Option<u64>,
+    },
+    /// Write the alert to the local syslog (Unix only)
+    Syslog,
+}
+
+impl AlertRule {
+    pub fn get_on_resolve(&self) -> bool {
+        self.on_resolve.unwrap_or(false)
+    }
+}
+
+/// Limits applied to every run of a config script
+#[derive(Debug, Deserialize, JsonSchema, Clone, Default)]
+pub struct ScriptingConfig {
+    /// Rhai operations a single run may perform (default: 100000)
+    pub max_operations: Option<u64>,
+    /// Wall-clock limit on a single run (default: 100)
+    #[serde(default, deserialize_with = "units::option_millis")]
+    #[schemars(schema_with = "units::duration_schema")]
+    pub timeout_ms: Option<u64>,
+    /// Longest string a script may build (default: 65536)
+    pub max_string_size: Option<usize>,
+    /// Largest array or map a script may build (default: 10000)
+    pub max_array_size: Option<usize>,
+}
+
+impl ScriptingConfig {
+    pub fn get_max_operations(&self) -> u64 {
+        self.max_operations.unwrap_or(100_000)
+    }
+
+    pub fn get_timeout_ms(&self) -> u64 {
+        self.timeout_ms.unwrap_or(100)
+    }
+
+    pub fn get_max_string_size(&self) -> usize {
+        self.max_string_size.unwrap_or(65_536)
+    }
+
+    pub fn get_max_array_size(&self) -> usize {
+        self.max_array_size.unwrap_or(10_000)
+    }
+}
+
+#[derive(Debug, Deserialize, JsonSchema, Clone, Default)]
+pub struct StatusConfig {
+    /// Serve the local status endpoint used by `sentinel-agent status` (default: true)
+    pub enabled: Option<bool>,
+    /// Address to listen on; keep this on loopback (default: 127.0.0.1:9176)
+    pub listen_address: Option<String>,
+    /// Serve CPU and heap profiles under `/debug/pprof/` (default: false)
+    pub profiling: Option<bool>,
+}
+
+impl StatusConfig {
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.unwrap_or(true)
+    }
+
+    pub fn get_listen_address(&self) -> String {
+        self.listen_address
             .clone()
-            .unwrap_or_else(|| gethostname::gethostname().to_string_lossy().to_string())
+            .unwrap_or_else(|| "127.0.0.1:9176".to_string())
+    }
+
+    pub fn profiling_enabled(&self) -> bool {
+        self.profiling.unwrap_or(false)
+    }
+}
+
+#[derive(Debug, Deserialize, JsonSchema, Clone, Default)]
+pub struct LoggingConfig {
+    /// Default level: trace, debug, info, warn, error or off (default: info)
+    pub level: Option<String>,
+    pub format: Option<LogFormat>,
+    /// Per-module level overrides, e.g. `sentinel_agent::metadata: debug`
+    pub filters: Option<HashMap<String, String>>,
+    /// Write logs to a rotating file instead of stdout
+    pub file: Option<LogFileConfig>,
+    /// Log to journald (Linux only; default: auto-detect when running under systemd)
+    pub journald: Option<bool>,
+    /// Publish task instrumentation for tokio-console on 127.0.0.1:6669 (default: false)
+    pub tokio_console: Option<bool>,
+}
+
+#[derive(Debug, Deserialize, JsonSchema, Clone)]
+pub struct LogFileConfig {
+    pub path: PathBuf,
+    /// Rotate once the file would exceed this size
+    #[serde(default, deserialize_with = "units::option_megabytes")]
+    #[schemars(schema_with = "units::size_schema")]
+    pub max_size_mb: Option<u64>,
+    pub rotation: Option<LogRotation>,
+    /// Number of rotated files to keep (default: 5)
+    pub retention: Option<usize>,
+}
+
+#[derive(Debug, Deserialize, JsonSchema, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogRotation {
+    Hourly,
+    Daily,
+    Never,
+}
+
+impl LogFileConfig {
+    pub fn get_max_size_bytes(&self) -> Option<u64> {
+        self.max_size_mb.map(|mb| mb.saturating_mul(1024 * 1024))
+    }
+
+    pub fn get_rotation(&self) -> LogRotation {
+        self.rotation.unwrap_or(LogRotation::Daily)
+    }
+
+    pub fn get_retention(&self) -> usize {
+        self.retention.unwrap_or(5)
+    }
+}
+
+#[derive(Debug, Deserialize, JsonSchema, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+impl LoggingConfig {
+    pub fn get_level(&self) -> String {
+        self.level.clone().unwrap_or_else(|| "info".to_string())
+    }
+
+    pub fn get_format(&self) -> LogFormat {
+        self.format.unwrap_or(LogFormat::Text)
+    }
+}
+
+/// Layout of the config file this agent writes and reads natively
+pub const CONFIG_VERSION: u32 = 3;
+
+/// How a key from an older layout is carried forward
+#[derive(Debug, Clone, Copy)]
+enum Change {
+    /// Moved to a new dotted path
+    Rename { to: &'static str },
+    /// No longer read; the guidance says what to do instead
+    Remove(&'static str),
+}
+
+/// A change to a dotted key in files older than `before`
+#[derive(Debug, Clone, Copy)]
+struct Migration {
+    before: u32,
+    key: &'static str,
+    change: Change,
+}
+
+/// Changes between layouts, applied in order to files declaring an older version
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        before: 2,
+        key: "agent.id",
+        change: Change::Remove("the resource ID is assigned by the platform at registration; remove this key"),
+    },
+    Migration {
+        before: 3,
+        key: "agent.max_memory_mb",
+        change: Change::Rename { to: "limits.max_memory_mb" },
+    },
+];
+
+/// Result of checking a config file with `sentinel-agent check-config`
+#[derive(Debug)]
+pub struct ConfigReport {
+    pub config: Config,
+    /// Keys the agent does not recognise (often typos), e.g. `collection.intervl_seconds`
+    pub unknown_keys: Vec<String>,
+    /// Keys from an older layout that were mapped or dropped, with guidance
+    pub deprecations: Vec<String>,
+}
+
+/// Unknown keys, each followed by the closest valid key at the same level when one is close
+pub fn describe_unknown_keys(keys: &[String]) -> Vec<String> {
+    if keys.is_empty() {
+        return Vec::new();
+    }
+    let schema = serde_json::to_value(Config::json_schema()).unwrap_or_default();
+    keys.iter()
+        .map(|key| match closest_key(&schema, key) {
+            Some(suggestion) => format!("{} (did you mean {}?)", key, suggestion),
+            None => key.clone(),
+        })
+        .collect()
+}
+
+/// Closest key the schema allows next to the last segment of `path`, if any is close enough
+fn closest_key(schema: &serde_json::Value, path: &str) -> Option<String> {
+    let (parent, key) = match path.rsplit_once('.') {
+        Some((parent, key)) => (Some(parent), key),
+        None => (None, path),
+    };
+
+    let mut nodes = vec![schema];
+    for segment in parent.into_iter().flat_map(|parent| parent.split('.')) {
+        nodes = nodes
+            .into_iter()
+            .flat_map(|node| schema_alternatives(schema, node))
+            .filter_map(|node| schema_child(node, segment))
+            .collect();
+    }
+
+    let (distance, candidate) = nodes
+        .into_iter()
+        .flat_map(|node| schema_alternatives(schema, node))
+        .filter_map(|node| node.get("properties").and_then(|p| p.as_object()))
+        .flat_map(|properties| properties.keys())
+        .map(|candidate| (edit_distance(key, candidate), candidate))
+        .min()?;
+    if distance > (key.chars().count() / 3).max(1) {
+        return None;
+    }
+    Some(match parent {
+        Some(parent) => format!("{}.{}", parent, candidate),
+        None => candidate.clone(),
+    })
+}
+
+/// The schemas `node` stands for once `$ref`s are followed and `anyOf`/`oneOf` are expanded
+fn schema_alternatives<'a>(root: &'a serde_json::Value, node: &'a serde_json::Value) -> Vec<&'a serde_json::Value> {
+    if let Some(reference) = node.get("$ref").and_then(|r| r.as_str()) {
+        return reference
+            .strip_prefix('#')
+            .and_then(|pointer| root.pointer(pointer))
+            .map(|target| schema_alternatives(root, target))
+            .unwrap_or_default();
+    }
+    let variants: Vec<_> = ["anyOf", "oneOf"]
+        .iter()
+        .filter_map(|keyword| node.get(*keyword).and_then(|v| v.as_array()))
+        .flatten()
+        .flat_map(|variant| schema_alternatives(root, variant))
+        .collect();
+    if variants.is_empty() {
+        vec![node]
+    } else {
+        variants
+    }
+}
+
+/// Schema of `segment` under `node`: a property, a list item or a map value
+fn schema_child<'a>(node: &'a serde_json::Value, segment: &str) -> Option<&'a serde_json::Value> {
+    if let Some(property) = node.get("properties").and_then(|p| p.get(segment)) {
+        return Some(property);
+    }
+    if segment.parse::<usize>().is_ok() {
+        if let Some(items) = node.get("items") {
+            return Some(items);
+        }
+    }
+    node.get("additionalProperties").filter(|v| v.is_object())
+}
+
+/// Levenshtein distance between two keys
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substituted = diagonal + usize::from(ca != *cb);
+            diagonal = row[j + 1];
+            row[j + 1] = substituted.min(row[j] + 1).min(diagonal + 1);
+        }
+    }
+    row[b.len()]
+}
+
+/// Levels accepted in `logging.level` and `logging.filters`
+const LOG_LEVELS: &[&str] = &["trace", "debug", "info", "warn", "error", "off"];
+
+/// Lowest collection interval the agent runs with unless `collection.override_limits` is set
+///
+/// Across a fleet, second-level intervals and tiny batches multiply into a
+/// request rate the ingest API was never sized for.
+pub const MIN_INTERVAL_SECONDS: u64 = 5;
+/// Highest collection interval, from the config or a `set_interval` command
+pub const MAX_INTERVAL_SECONDS: u64 = 86_400;
+/// Lowest flush interval, as above
+pub const MIN_FLUSH_INTERVAL_SECONDS: u64 = 5;
+/// Smallest batch size, as above
+pub const MIN_BATCH_SIZE: usize = 5;
+
+/// Commands that may appear in `commands.allowed_commands`
+pub const SUPPORTED_COMMANDS: &[&str] = &[
+    "flush_now",
+    "set_interval",
+    "run_diagnostic",
+    "diagnose",
+    "collect_inventory",
+];
+
+/// `${VAR}`, `${VAR:-default}`, or the escaped `$${...}`
+static ENV_REFERENCE: LazyLock<regex::Regex> = LazyLock::new(|| {
+    regex::Regex::new(r"\$(\$)?\{([A-Za-z_][A-Za-z0-9_]*)(?::-([^}]*))?\}")
+        .expect("valid interpolation pattern")
+});
+
+/// Config values holding Rhai source, where `${...}` is Rhai's own interpolation
+const SCRIPT_FIELDS: &[&str] = &["collection.script", "alerts.rules.condition", "alerts.rules.message"];
+
+/// Expand environment references in every string value of a parsed config
+///
+/// Only values are expanded, so comments, keys and the script fields are never
+/// touched. A value that is exactly one reference takes the type of what it
+/// expands to, so `timeout_seconds: ${TIMEOUT}` still reads as a number.
+fn interpolate_yaml(
+    value: &mut serde_yaml::Value,
+    path: &str,
+    lookup: &impl Fn(&str) -> Option<String>,
+) -> Result<(), ConfigError> {
+    use serde_yaml::Value;
+
+    match value {
+        Value::Mapping(mapping) => {
+            for (key, value) in mapping.iter_mut() {
+                let key = key.as_str().unwrap_or_default();
+                let path = if path.is_empty() { key.to_string() } else { format!("{}.{}", path, key) };
+                interpolate_yaml(value, &path, lookup)?;
+            }
+        }
+        // Items share their sequence's path, e.g. `alerts.rules.message`
+        Value::Sequence(items) => {
+            for item in items {
+                interpolate_yaml(item, path, lookup)?;
+            }
+        }
+        Value::Tagged(tagged) => interpolate_yaml(&mut tagged.value, path, lookup)?,
+        Value::String(text) if !SCRIPT_FIELDS.contains(&path) => {
+            let whole_reference = ENV_REFERENCE
+                .captures(text)
+                .is_some_and(|captures| captures.get(1).is_none() && captures[0].len() == text.len());
+            let expanded = interpolate_env(text, lookup)?;
+            let typed = serde_yaml::from_str::<Value>(&expanded)
+                .ok()
+                .filter(|typed| whole_reference && (typed.is_number() || typed.is_bool()));
+            *value = typed.unwrap_or(Value::String(expanded));
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Replace `${VAR}` and `${VAR:-default}` with values from the environment
+///
+/// The default applies when the variable is unset or empty; an unset variable
+/// without a default is an error. `$${` is kept as a literal `${`, and anything
+/// that is not a plain variable name is left alone.
+fn interpolate_env(
+    contents: &str,
+    lookup: impl Fn(&str) -> Option<String>,
+) -> Result<String, ConfigError> {
+    let pattern = &*ENV_REFERENCE;
+
+    let mut output = String::with_capacity(contents.len());
+    let mut last = 0;
+    for captures in pattern.captures_iter(contents) {
+        let whole = captures.get(0).expect("group 0 always matches");
+        output.push_str(&contents[last..whole.start()]);
+        last = whole.end();
+
+        if captures.get(1).is_some() {
+            output.push_str(&whole.as_str()[1..]);
+            continue;
+        }
+
+        let name = &captures[2];
+        match (lookup(name).filter(|value| !value.is_empty()), captures.get(3)) {
+            (Some(value), _) => output.push_str(&value),
+            (None, Some(default)) => output.push_str(default.as_str()),
+            (None, None) => {
+                return Err(ConfigError::Interpolation(format!(
+                    "environment variable {} is not set",
+                    name
+                )))
+            }
+        }
+    }
+    output.push_str(&contents[last..]);
+    Ok(output)
+}
+
+/// Drop-in directory for a config file: `agent.yaml` -> `agent.yaml.d`
+pub fn drop_in_dir(path: &Path) -> PathBuf {
+    let mut dir = path.as_os_str().to_owned();
+    dir.push(".d");
+    PathBuf::from(dir)
+}
+
+/// `.yaml` / `.yml` files in a drop-in directory, in lexical order
+pub fn drop_in_files(dir: &Path) -> Vec<PathBuf> {
+    let mut files: Vec<PathBuf> = std::fs::read_dir(dir)
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                .filter(|path| {
+                    path.is_file()
+                        && path.extension().is_some_and(|ext| ext == "yaml" || ext == "yml")
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    files.sort();
+    files
+}
+
+/// Merge `overlay` into `base`: mappings merge key by key, anything else is replaced
+fn merge_yaml(base: &mut serde_yaml::Value, overlay: serde_yaml::Value) {
+    match (base, overlay) {
+        (serde_yaml::Value::Mapping(base), serde_yaml::Value::Mapping(overlay)) => {
+            for (key, value) in overlay {
+                match base.get_mut(&key) {
+                    Some(existing) => merge_yaml(existing, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, overlay) => *base = overlay,
+    }
+}
+
+/// Parse a config file, with environment variables expanded in its values
+fn read_expanded(path: &Path) -> Result<serde_yaml::Value, ConfigError> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| ConfigError::FileRead(format!("{}: {}", path.display(), e)))?;
+    let mut value = serde_yaml::from_str::<serde_yaml::Value>(&contents)
+        .map_err(|e| ConfigError::Parse(format!("{}: {}", path.display(), e)))?;
+    interpolate_yaml(&mut value, "", &|name| std::env::var(name).ok())?;
+    Ok(value)
+}
+
+/// Read a config file with environment variables expanded and drop-in
+/// fragments from `<file>.d/` merged over it, later files winning
+fn read_config_file<P: AsRef<Path>>(path: P) -> Result<String, ConfigError> {
+    let path = path.as_ref();
+    let mut merged = read_expanded(path)?;
+    for fragment in drop_in_files(&drop_in_dir(path)) {
+        let overlay = read_expanded(&fragment)?;
+        // An empty fragment parses as null and contributes nothing
+        if !overlay.is_null() {
+            merge_yaml(&mut merged, overlay);
+        }
+    }
+    serde_yaml::to_string(&merged).map_err(|e| ConfigError::Parse(e.to_string()))
+}
+
+/// A section holding only comments is empty in the file but `null` once the
+/// parsed config is written back out, as it is after expansion; read both as empty
+fn null_as_default<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: Deserialize<'de> + Default,
+{
+    // A visitor rather than `Option<T>`, which would put a `?` into unknown key paths
+    struct NullAsDefault<T>(std::marker::PhantomData<T>);
+
+    impl<'de, T: Deserialize<'de> + Default> serde::de::Visitor<'de> for NullAsDefault<T> {
+        type Value = T;
+
+        fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+            formatter.write_str("a mapping")
+        }
+
+        fn visit_unit<E: serde::de::Error>(self) -> Result<T, E> {
+            Ok(T::default())
+        }
+
+        fn visit_none<E: serde::de::Error>(self) -> Result<T, E> {
+            Ok(T::default())
+        }
+
+        fn visit_map<A: serde::de::MapAccess<'de>>(self, map: A) -> Result<T, A::Error> {
+            T::deserialize(serde::de::value::MapAccessDeserializer::new(map))
+        }
+    }
+
+    deserializer.deserialize_any(NullAsDefault(std::marker::PhantomData))
+}
+
+/// Take the value at a dotted path out of `root`, if present
+fn take_key(root: &mut serde_yaml::Value, key: &str) -> Option<serde_yaml::Value> {
+    let (parent, last) = match key.rsplit_once('.') {
+        Some((parent, last)) => (parent.split('.').try_fold(root, |node, part| node.get_mut(part))?, last),
+        None => (root, key),
+    };
+    parent.as_mapping_mut()?.remove(last)
+}
+
+/// Insert `value` at a dotted path, creating mappings on the way; an existing
+/// value wins and `false` is returned
+fn insert_key(root: &mut serde_yaml::Value, key: &str, value: serde_yaml::Value) -> bool {
+    let mut node = root;
+    let mut parts = key.split('.').peekable();
+    while let Some(part) = parts.next() {
+        if node.is_null() {
+            *node = serde_yaml::Value::Mapping(Default::default());
+        }
+        let Some(mapping) = node.as_mapping_mut() else {
+            return false;
+        };
+        let part = serde_yaml::Value::from(part);
+        if parts.peek().is_none() {
+            if mapping.contains_key(&part) {
+                return false;
+            }
+            mapping.insert(part, value);
+            return true;
+        }
+        node = mapping
+            .entry(part)
+            .or_insert_with(|| serde_yaml::Value::Mapping(Default::default()));
+    }
+    false
+}
+
+/// Bring a parsed config up to [`CONFIG_VERSION`], describing every change made
+///
+/// A file without `version:` predates the field and is treated as version 1.
+fn migrate(root: &mut serde_yaml::Value, migrations: &[Migration]) -> Result<Vec<String>, ConfigError> {
+    let version = match root.get("version") {
+        None => 1,
+        Some(version) => version
+            .as_u64()
+            .and_then(|version| u32::try_from(version).ok())
+            .filter(|version| *version >= 1)
+            .ok_or_else(|| ConfigError::Validation(format!("Invalid config version: {:?}", version)))?,
+    };
+    if version > CONFIG_VERSION {
+        return Err(ConfigError::Validation(format!(
+            "Config version {} is newer than this agent supports ({}); upgrade the agent",
+            version, CONFIG_VERSION
+        )));
+    }
+
+    let mut warnings = Vec::new();
+    for migration in migrations.iter().filter(|migration| version < migration.before) {
+        let Some(value) = take_key(root, migration.key) else {
+            continue;
+        };
+        match migration.change {
+            Change::Rename { to } if insert_key(root, to, value) => warnings.push(format!(
+                "{} is deprecated: moved to {}; the value was carried over",
+                migration.key, to
+            )),
+            Change::Rename { to } => warnings.push(format!(
+                "{} is deprecated: moved to {}, which is also set and wins",
+                migration.key, to
+            )),
+            Change::Remove(guidance) => {
+                warnings.push(format!("{} is deprecated: {}", migration.key, guidance))
+            }
+        }
+    }
+    Ok(warnings)
+}
+
+/// Migrate `contents` if it uses an older layout and decrypt `ENC[...]` values
+///
+/// Returns the rewritten document only when something changed, so files that
+/// need no changes are deserialized from the text and parse errors keep their
+/// line numbers.
+fn migrated(
+    contents: &str,
+    key: impl FnOnce() -> Result<Option<crate::encryption::ConfigKey>, crate::encryption::EncryptionError>,
+) -> Result<(Option<serde_yaml::Value>, Vec<String>), ConfigError> {
+    let mut root: serde_yaml::Value =
+        serde_yaml::from_str(contents).map_err(|e| ConfigError::Parse(e.to_string()))?;
+    let warnings = migrate(&mut root, MIGRATIONS)?;
+    let decrypted = crate::encryption::decrypt_document(&mut root, key)
+        .map_err(|e| ConfigError::Decryption(e.to_string()))?;
+    Ok((Some(root).filter(|_| !warnings.is_empty() || decrypted > 0), warnings))
+}
+
+/// Settings given on the command line or their environment variables,
+/// which take precedence over the config file
+#[derive(Debug, Clone, Default)]
+pub struct Overrides {
+    pub endpoint: Option<String>,
+    pub interval_seconds: Option<u64>,
+    /// File holding the API key, e.g. a container secret; surrounding whitespace is ignored
+    pub api_key_file: Option<PathBuf>,
+    pub log_level: Option<String>,
+    pub hostname: Option<String>,
+}
+
+impl Config {
+    /// Declared layout version; files without one predate the field
+    pub fn get_version(&self) -> u32 {
+        self.version.unwrap_or(1)
+    }
+
+    /// JSON Schema of the config file, for editors and pre-deploy validation
+    pub fn json_schema() -> schemars::Schema {
+        schemars::schema_for!(Config)
+    }
+
+    /// Apply `overrides` over the loaded settings and validate the result
+    pub fn apply_overrides(&mut self, overrides: &Overrides) -> Result<(), ConfigError> {
+        if let Some(endpoint) = &overrides.endpoint {
+            self.api.endpoint = endpoint.clone();
+        }
+        if let Some(interval) = overrides.interval_seconds {
+            self.collection.interval_seconds = interval;
+        }
+        if let Some(path) = &overrides.api_key_file {
+            let key = std::fs::read_to_string(path)
+                .map_err(|e| ConfigError::FileRead(format!("{}: {}", path.display(), e)))?;
+            self.api.api_key = Some(key.trim().to_string());
+        }
+        if let Some(level) = &overrides.log_level {
+            self.logging.get_or_insert_with(LoggingConfig::default).level = Some(level.clone());
+        }
+        if let Some(hostname) = &overrides.hostname {
+            self.agent.hostname = Some(hostname.clone());
+            self.agent.hostname_mode = Some(HostnameMode::Custom);
+        }
+        self.validate()
+    }
+
+    pub fn load_from_file<P: AsRef<Path>>(path: P) -> Result<Self, ConfigError> {
+        let contents = read_config_file(path)?;
+        Self::load_from_str(&contents)
+    }
+
+    pub fn load_from_str(contents: &str) -> Result<Self, ConfigError> {
+        let report = Self::check_str(contents)?;
+        let mut config = report.config;
+        config.unknown_key_warnings = describe_unknown_keys(&report.unknown_keys);
+        Ok(config)
+    }
+
+    /// Whether unknown keys are rejected rather than ignored
+    pub fn is_strict(&self) -> bool {
+        self.strict_keys.unwrap_or(true)
+    }
+
+    /// Parse and validate a config file, reporting unknown and deprecated keys
+    ///
+    /// Unknown keys fail the check unless the file sets `strict_keys: false`.
+    pub fn check_file<P: AsRef<Path>>(path: P) -> Result<ConfigReport, ConfigError> {
+        let contents = read_config_file(path)?;
+        Self::check_str(&contents)
+    }
+
+    pub fn check_str(contents: &str) -> Result<ConfigReport, ConfigError> {
+        Self::check_str_with_key(contents, crate::encryption::ConfigKey::from_env)
+    }
+
+    /// [`Config::check_str`] with `ENC[...]` values decrypted using `key` rather than the environment's
+    fn check_str_with_key(
+        contents: &str,
+        key: impl FnOnce() -> Result<Option<crate::encryption::ConfigKey>, crate::encryption::EncryptionError>,
+    ) -> Result<ConfigReport, ConfigError> {
+        let (migrated, warnings) = migrated(contents, key)?;
+        let mut unknown_keys = Vec::new();
+        let mut config: Config = match migrated {
+            Some(root) => serde_ignored::deserialize(root, |path| unknown_keys.push(path.to_string())),
+            None => serde_ignored::deserialize(serde_yaml::Deserializer::from_str(contents), |path| {
+                unknown_keys.push(path.to_string())
+            }),
+        }
+        .map_err(|e| ConfigError::Parse(e.to_string()))?;
+        config.migration_warnings = warnings.clone();
+
+        if config.is_strict() && !unknown_keys.is_empty() {
+            return Err(ConfigError::UnknownKeys(describe_unknown_keys(&unknown_keys).join(", ")));
+        }
+
+        config.validate()?;
+
+        Ok(ConfigReport {
+            config,
+            unknown_keys,
+            deprecations: warnings,
+        })
+    }
+
+    /// Effective settings with defaults filled in, for display
+    pub fn resolved_settings(&self) -> Vec<(&'static str, String)> {
+        let logging = self.get_logging();
+        let status = self.get_status();
+        let enabled = |on: bool| if on { "enabled" } else { "disabled" }.to_string();
+
+        let mut settings = vec![
+            ("version", self.get_version().to_string()),
+            ("strict_keys", self.is_strict().to_string()),
+            ("agent.hostname", self.get_hostname()),
+            (
+                "agent.hostname_mode",
+                match self.get_hostname_mode() {
+                    HostnameMode::Short => "short",
+                    HostnameMode::Fqdn => "fqdn",
+                    HostnameMode::Custom => "custom",
+                }
+                .to_string(),
+            ),
+            (
+                "agent.deregister_on_shutdown",
+                self.get_deregister_on_shutdown().to_string(),
+            ),
+            ("agent.maintenance", self.get_maintenance().to_string()),
+            (
+                "agent.maintenance_file",
+                self.get_maintenance_file().display().to_string(),
+            ),
+            (
+                "agent.labels",
+                self.get_labels()
+                    .iter()
+                    .map(|(key, value)| format!("{}={}", key, value))
+                    .collect::<Vec<_>>()
+                    .join(","),
+            ),
+            ("agent.org_id", self.get_org_id().unwrap_or_else(|| "not set".to_string())),
+            ("agent.project", self.get_project().unwrap_or_else(|| "not set".to_string())),
+            (
+                "agent.user",
+                match (&self.agent.user, &self.agent.group) {
+                    (None, None) => "unchanged".to_string(),
+                    (user, group) => format!(
+                        "{}:{}",
+                        user.as_deref().unwrap_or("-"),
+                        group.as_deref().unwrap_or("-")
+                    ),
+                },
+            ),
+            ("api.endpoint", self.api.endpoint.clone()),
+            (
+                "api.additional_endpoints",
+                match self.get_additional_endpoints() {
+                    [] => "none".to_string(),
+                    endpoints => endpoints
+                        .iter()
+                        .map(|e| format!("{}={}", e.name, e.endpoint))
+                        .collect::<Vec<_>>()
+                        .join(","),
+                },
+            ),
+            ("api.timeout_seconds", self.get_api_timeout_seconds().to_string()),
+            (
+                "api.tls",
+                match &self.api.tls {
+                    Some(tls) => {
+                        let mut parts = vec![format!(
+                            "min {}",
+                            match tls.get_min_version() {
+                                TlsVersion::V1_2 => "1.2",
+                                TlsVersion::V1_3 => "1.3",
+                            }
+                        )];
+                        if let Some(ciphers) = &tls.ciphers {
+                            parts.push(format!("ciphers {}", ciphers.join(",")));
+                        }
+                        if let Some(ca_file) = &tls.ca_file {
+                            parts.push(format!("ca {}", ca_file.display()));
+                        }
+                        if let Some(client_cert) = &tls.client_cert {
+                            parts.push(format!("client cert {}", client_cert.display()));
+                        }
+                        if let Some(server_name) = &tls.server_name {
+                            parts.push(format!("server name {}", server_name));
+                        }
+                        parts.join(", ")
+                    }
+                    None => "default".to_string(),
+                },
+            ),
+            (
+                "api.health_check_interval_seconds",
+                self.get_health_check_interval_seconds().to_string(),
+            ),
+            (
+                "api.heartbeat_interval_seconds",
+                self.get_heartbeat_interval_seconds().to_string(),
+            ),
+            (
+                "api.api_key",
+                match self.api.api_key.as_deref().map(crate::secrets::SecretRef::parse) {
+                    Some(Ok(Some(reference))) => format!("from {} (resolved at startup)", reference),
+                    Some(_) => "set".to_string(),
+                    None if self.api.oauth.is_some() => "not set (using api.oauth)".to_string(),
+                    None => "not set (registration disabled)".to_string(),
+                },
+            ),
+            (
+                "api.oauth",
+                match &self.api.oauth {
+                    Some(oauth) => match &oauth.audience {
+                        Some(audience) => format!("client {} at {} for {}", oauth.client_id, oauth.token_url, audience),
+                        None => format!("client {} at {}", oauth.client_id, oauth.token_url),
+                    },
+                    None => "disabled".to_string(),
+                },
+            ),
+            (
+                "api.oauth.token_cache",
+                match self.api.oauth.as_ref().map(|oauth| oauth.token_cache.clone().unwrap_or_default()) {
+                    Some(cache) => format!(
+                        "{} at {}{}",
+                        format!("{:?}", cache.store.unwrap_or(TokenStoreKind::File)).to_lowercase(),
+                        cache.path.unwrap_or_else(crate::token_store::default_path).display(),
+                        if cache.encryption_key.is_some() { ", encrypted" } else { "" }
+                    ),
+                    None => "disabled".to_string(),
+                },
+            ),
+            ("collection.interval_seconds", self.collection.interval_seconds.to_string()),
+            (
+                "collection.flush_interval_seconds",
+                self.get_flush_interval_seconds().to_string(),
+            ),
+            ("collection.batch_size", self.get_batch_size().to_string()),
+            (
+                "collection.max_buffer_size_mb",
+                (self.get_max_buffer_bytes() / (1024 * 1024)).to_string(),
+            ),
+            ("collection.jitter_seconds", self.get_jitter_seconds().to_string()),
+            ("collection.phase_offset", enabled(self.get_phase_offset())),
+            ("collection.align_flushes", enabled(self.get_align_flushes())),
+            ("collection.aggregate", enabled(self.get_aggregate())),
+            ("collection.override_limits", self.get_override_limits().to_string()),
+            ("collection.correct_timestamps", enabled(self.get_correct_timestamps())),
+            (
+                "collection.collector_timeout_seconds",
+                self.get_collector_timeout_seconds().to_string(),
+            ),
+            ("collection.disk", enabled(self.collection.disk.enabled)),
+            (
+                "collection.delta",
+                enabled(self.collection.delta.as_ref().is_some_and(|d| d.enabled)),
+            ),
+            (
+                "collection.spool",
+                match self.get_spool() {
+                    Some(spool) if spool.is_compressed() => {
+                        format!("{} (compressed)", spool.get_directory().display())
+                    }
+                    Some(spool) => spool.get_directory().display().to_string(),
+                    None => "disabled".to_string(),
+                },
+            ),
+            (
+                "collection.relabel",
+                format!(
+                    "{} rules",
+                    self.collection.relabel.as_ref().map(|rules| rules.len()).unwrap_or(0)
+                ),
+            ),
+            (
+                "collection.plugins",
+                self.get_plugins_directory()
+                    .map(|dir| dir.display().to_string())
+                    .unwrap_or_else(|| "disabled".to_string()),
+            ),
+            (
+                "collection.textfile",
+                self.get_textfile_directory()
+                    .map(|dir| dir.display().to_string())
+                    .unwrap_or_else(|| "disabled".to_string()),
+            ),
+            (
+                "collection.exec",
+                format!("{} commands", self.get_exec_commands().len()),
+            ),
+            (
+                "collection.scrape",
+                format!("{} targets", self.get_scrape_targets().len()),
+            ),
+            (
+                "collection.ingest",
+                self.get_ingest()
+                    .map(|ingest| ingest.get_path().display().to_string())
+                    .unwrap_or_else(|| "disabled".to_string()),
+            ),
+            (
+                "collection.wasm",
+                self.get_wasm()
+                    .map(|wasm| wasm.get_directory().display().to_string())
+                    .unwrap_or_else(|| "disabled".to_string()),
+            ),
+            (
+                "collection.script",
+                enabled(self.collection.script.is_some()),
+            ),
+            (
+                "collection.adaptive",
+                enabled(self.collection.adaptive.as_ref().is_some_and(|a| a.enabled)),
+            ),
+            ("logging.level", logging.get_level()),
+            ("logging.format", format!("{:?}", logging.get_format()).to_lowercase()),
+        ];
+
+        if let Some(file) = &logging.file {
+            settings.push(("logging.file.path", file.path.display().to_string()));
+        }
+
+        settings.push((
+            "status.listen_address",
+            if status.is_enabled() { status.get_listen_address() } else { "disabled".to_string() },
+        ));
+        settings.push((
+            "status.profiling",
+            enabled(status.is_enabled() && status.profiling_enabled()),
+        ));
+        settings.push((
+            "alerts",
+            match self.alerts.as_ref().filter(|a| a.enabled) {
+                Some(alerts) => format!("enabled ({} rules)", alerts.rules.len()),
+                None => "disabled".to_string(),
+            },
+        ));
+        let thresholds = self.thresholds.clone().unwrap_or_default();
+        for (key, metric) in [
+            ("thresholds.disk_usage", AlertMetric::DiskUsage),
+            ("thresholds.failed_systemd_units", AlertMetric::FailedSystemdUnits),
+        ] {
+            let value = match thresholds.get(metric) {
+                Some(threshold) => format!(
+                    "warn {}, critical {}, for {}s",
+                    threshold.warn.map_or("-".to_string(), |level| level.to_string()),
+                    threshold.critical.map_or("-".to_string(), |level| level.to_string()),
+                    threshold.get_duration_seconds()
+                ),
+                None => "none".to_string(),
+            };
+            settings.push((key, value));
+        }
+        let limits = self.get_limits();
+        settings.push((
+            "limits.nice",
+            limits.nice.map(|nice| nice.to_string()).unwrap_or_else(|| "unchanged".to_string()),
+        ));
+        settings.push((
+            "limits.io_class",
+            match limits.io_class {
+                Some(IoClass::BestEffort) => format!("best_effort ({})", limits.get_io_priority()),
+                Some(IoClass::Idle) => "idle".to_string(),
+                None => "unchanged".to_string(),
+            },
+        ));
+        settings.push((
+            "limits.cpu_quota_percent",
+            limits
+                .cpu_quota_percent
+                .map(|percent| percent.to_string())
+                .unwrap_or_else(|| "unchecked".to_string()),
+        ));
+        settings.push((
+            "limits.max_memory_mb",
+            limits
+                .max_memory_mb
+                .map(|mb| mb.to_string())
+                .unwrap_or_else(|| "unlimited".to_string()),
+        ));
+        settings.push(("limits.strict", limits.is_strict().to_string()));
+        settings.push((
+            "remote_config",
+            match self.remote_config.as_ref().filter(|r| r.enabled) {
+                Some(remote) => format!(
+                    "enabled ({}, cache {})",
+                    if remote.signing_key.is_some() { "signed" } else { "unsigned" },
+                    remote.get_cache_file().display()
+                ),
+                None => "disabled".to_string(),
+            },
+        ));
+        settings.push((
+            "commands",
+            enabled(self.commands.as_ref().is_some_and(|c| c.enabled)),
+        ));
+        settings.push((
+            "offline",
+            match (self.is_offline(), self.get_auto_sync()) {
+                (false, _) => "disabled".to_string(),
+                (true, false) => "enabled (sync manually)".to_string(),
+                (true, true) => "enabled (auto sync)".to_string(),
+            },
+        ));
+        settings.push((
+            "metadata.tags",
+            match self.get_metadata().get_tags().as_slice() {
+                [] => "none".to_string(),
+                tags => tags.join(","),
+            },
+        ));
+        settings.push(("metadata.network", enabled(self.get_metadata().reports_network())));
+        settings.push((
+            "metadata.provider",
+            match self.get_metadata().provider {
+                Some(provider) => format!("{:?}", provider).to_lowercase(),
+                None => "auto".to_string(),
+            },
+        ));
+        settings.push((
+            "metadata.detection_timeout_ms",
+            self.get_metadata().get_detection_timeout_ms().to_string(),
+        ));
+        settings.push(("metadata.imdsv2_only", self.get_metadata().is_imdsv2_only().to_string()));
+        settings.push((
+            "metadata.custom_file",
+            self.get_metadata().custom_file.unwrap_or_else(|| "none".to_string()),
+        ));
+        settings.push((
+            "metadata.refresh_interval_seconds",
+            match self.get_metadata().get_refresh_interval_seconds() {
+                0 => "disabled".to_string(),
+                seconds => seconds.to_string(),
+            },
+        ));
+
+        settings
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.api.endpoint.is_empty() {
+            return Err(ConfigError::Validation(
+                "API endpoint cannot be empty".to_string(),
+            ));
+        }
+
+        let mut names = std::collections::HashSet::new();
+        for additional in self.get_additional_endpoints() {
+            if additional.name.trim().is_empty() || additional.name == crate::state::PRIMARY_REGISTRATION {
+                return Err(ConfigError::Validation(format!(
+                    "api.additional_endpoints name cannot be empty or \"{}\"",
+                    crate::state::PRIMARY_REGISTRATION
+                )));
+            }
+            if !names.insert(additional.name.as_str()) {
+                return Err(ConfigError::Validation(format!(
+                    "Duplicate api.additional_endpoints name: {}",
+                    additional.name
+                )));
+            }
+            if additional.endpoint.is_empty() || additional.api_key.trim().is_empty() {
+                return Err(ConfigError::Validation(format!(
+                    "api.additional_endpoints {} needs an endpoint and an api_key",
+                    additional.name
+                )));
+            }
+            if additional.queue_size == Some(0) {
+                return Err(ConfigError::Validation(format!(
+                    "api.additional_endpoints {} queue_size must be greater than 0",
+                    additional.name
+                )));
+            }
+        }
+        if !names.is_empty() && !self.has_api_credentials() {
+            return Err(ConfigError::Validation(
+                "api.additional_endpoints requires api.api_key or api.oauth".to_string(),
+            ));
+        }
+
+        for (provider, settings) in self.get_metadata().providers.unwrap_or_default().entries() {
+            let Some(endpoint) = settings.and_then(|settings| settings.endpoint.as_deref()) else {
+                continue;
+            };
+            if !endpoint.starts_with("http://") && !endpoint.starts_with("https://") {
+                return Err(ConfigError::Validation(format!(
+                    "metadata.providers.{}.endpoint must be an http:// or https:// URL",
+                    provider
+                )));
+            }
+        }
+
+        if self.collection.interval_seconds == 0 {
+            return Err(ConfigError::Validation(
+                "Collection interval must be greater than 0".to_string(),
+            ));
+        }
+        if self.collection.interval_seconds > MAX_INTERVAL_SECONDS {
+            return Err(ConfigError::Validation(format!(
+                "Collection interval must be at most {} seconds",
+                MAX_INTERVAL_SECONDS
+            )));
+        }
+
+        let jitter = self.get_jitter_seconds();
+        if jitter > 0
+            && (jitter >= self.collection.interval_seconds
+                || jitter >= self.get_flush_interval_seconds())
+        {
+            return Err(ConfigError::Validation(
+                "Collection jitter must be less than the collection and flush intervals".to_string(),
+            ));
+        }
+
+        if self.collection.max_buffer_size_mb == Some(0) {
+            return Err(ConfigError::Validation(
+                "Buffer size limit must be greater than 0".to_string(),
+            ));
+        }
+
+        if let Some(labels) = &self.agent.labels {
+            if labels.keys().any(|key| key.trim().is_empty()) {
+                return Err(ConfigError::Validation(
+                    "Agent label names cannot be empty".to_string(),
+                ));
+            }
+        }
+
+        for (key, value) in [("agent.user", &self.agent.user), ("agent.group", &self.agent.group)] {
+            match value {
+                Some(value) if value.trim().is_empty() => {
+                    return Err(ConfigError::Validation(format!("{} cannot be empty", key)));
+                }
+                Some(_) if !cfg!(unix) => {
+                    return Err(ConfigError::Validation(format!("{} is only supported on Unix", key)));
+                }
+                _ => {}
+            }
+        }
+
+        if self.limits.as_ref().and_then(|limits| limits.max_memory_mb) == Some(0) {
+            return Err(ConfigError::Validation(
+                "Memory limit must be greater than 0".to_string(),
+            ));
+        }
+
+        if let Some(wasm) = self.get_wasm() {
+            if !cfg!(feature = "wasm") {
+                return Err(ConfigError::Validation(
+                    "collection.wasm requires a build with the wasm feature".to_string(),
+                ));
+            }
+
+            if wasm.fuel == Some(0) || wasm.max_memory_mb == Some(0) {
+                return Err(ConfigError::Validation(
+                    "collection.wasm fuel and max_memory_mb must be greater than 0".to_string(),
+                ));
+            }
+        }
+
+        if self
+            .get_textfile_directory()
+            .is_some_and(|directory| directory.as_os_str().is_empty())
+        {
+            return Err(ConfigError::Validation(
+                "collection.textfile directory cannot be empty".to_string(),
+            ));
+        }
+
+        if self
+            .get_exec_commands()
+            .iter()
+            .any(|exec| exec.command.as_os_str().is_empty())
+        {
+            return Err(ConfigError::Validation(
+                "collection.exec command cannot be empty".to_string(),
+            ));
+        }
+
+        if self.collection.scrape.as_ref().and_then(|scrape| scrape.max_body_size_mb) == Some(0) {
+            return Err(ConfigError::Validation(
+                "collection.scrape.max_body_size_mb must be greater than 0".to_string(),
+            ));
+        }
+
+        for target in self.get_scrape_targets() {
+            let valid = reqwest::Url::parse(&target.url)
+                .is_ok_and(|url| matches!(url.scheme(), "http" | "https") && url.host_str().is_some());
+            if !valid {
+                return Err(ConfigError::Validation(format!(
+                    "collection.scrape target {} must be an http or https URL",
+                    target.url
+                )));
+            }
+        }
+
+        if self.get_ingest().is_some() && !cfg!(unix) {
+            return Err(ConfigError::Validation(
+                "collection.ingest needs Unix sockets, which this platform does not have".to_string(),
+            ));
+        }
+
+        if let Some(scripting) = &self.scripting {
+            if scripting.max_operations == Some(0) || scripting.timeout_ms == Some(0) {
+                return Err(ConfigError::Validation(
+                    "scripting.max_operations and timeout_ms must be greater than 0".to_string(),
+                ));
+            }
+        }
+
+        let limits = self.get_script_limits();
+        let mut scripts = vec![("collection.script".to_string(), self.collection.script.as_ref())];
+        for rule in self.alerts.iter().flat_map(|alerts| &alerts.rules) {
+            scripts.push((format!("alert rule {} condition", rule.name), rule.condition.as_ref()));
+            scripts.push((format!("alert rule {} message", rule.name), rule.message.as_ref()));
+        }
+        for (name, source) in scripts {
+            if let Some(source) = source {
+                Script::compile(&name, source, &limits)
+                    .map_err(|e| ConfigError::Validation(e.to_string()))?;
+            }
+        }
+
+        if let Some(limits) = &self.limits {
+            if limits.nice.is_some_and(|nice| !(-20..=19).contains(&nice)) {
+                return Err(ConfigError::Validation(
+                    "limits.nice must be between -20 and 19".to_string(),
+                ));
+            }
+
+            if limits.io_priority.is_some_and(|priority| priority > 7) {
+                return Err(ConfigError::Validation(
+                    "limits.io_priority must be between 0 and 7".to_string(),
+                ));
+            }
+
+            if limits.cpu_quota_percent.is_some_and(|percent| percent <= 0.0) {
+                return Err(ConfigError::Validation(
+                    "limits.cpu_quota_percent must be greater than 0".to_string(),
+                ));
+            }
+        }
+
+        if self.collection.collector_timeout_seconds == Some(0) {
+            return Err(ConfigError::Validation(
+                "Collector timeout must be greater than 0".to_string(),
+            ));
+        }
+
+        if self.api.health_check_interval_seconds == Some(0) {
+            return Err(ConfigError::Validation(
+                "Health check interval must be greater than 0".to_string(),
+            ));
+        }
+
+        if self.api.heartbeat_interval_seconds == Some(0) {
+            return Err(ConfigError::Validation(
+                "Heartbeat interval must be greater than 0".to_string(),
+            ));
+        }
+
+        if let Some(delta) = &self.collection.delta {
+            if let Some(tolerance) = delta.tolerance {
+                if !(0.0..=1.0).contains(&tolerance) {
+                    return Err(ConfigError::Validation(
+                        "Delta tolerance must be between 0.0 and 1.0".to_string(),
+                    ));
+                }
+            }
+        }
+
+        if let Some(rules) = &self.collection.relabel {
+            crate::relabel::Relabeler::new(rules)
+                .map_err(|e| ConfigError::Validation(e.to_string()))?;
+        }
+
+        if let Some(adaptive) = &self.collection.adaptive {
+            if !(0.0..=100.0).contains(&adaptive.get_cpu_pressure_threshold()) {
+                return Err(ConfigError::Validation(
+                    "Adaptive cpu_pressure_threshold must be between 0 and 100".to_string(),
+                ));
+            }
+            if adaptive.get_load_threshold() <= 0.0 {
+                return Err(ConfigError::Validation(
+                    "Adaptive load_threshold must be greater than 0".to_string(),
+                ));
+            }
+            if adaptive.get_backoff_factor() == 0 {
+                return Err(ConfigError::Validation(
+                    "Adaptive backoff_factor must be at least 1".to_string(),
+                ));
+            }
+        }
+
+        if let Some(logging) = &self.logging {
+            let levels = logging
+                .level
+                .iter()
+                .chain(logging.filters.iter().flat_map(|filters| filters.values()));
+            for level in levels {
+                if !LOG_LEVELS.contains(&level.to_lowercase().as_str()) {
+                    return Err(ConfigError::Validation(format!(
+                        "Invalid log level: {}",
+                        level
+                    )));
+                }
+            }
+
+            if logging.journald == Some(true) && logging.file.is_some() {
+                return Err(ConfigError::Validation(
+                    "Log file and journald output cannot both be enabled".to_string(),
+                ));
+            }
+
+            if logging.tokio_console == Some(true) && !cfg!(feature = "tokio-console") {
+                return Err(ConfigError::Validation(
+                    "logging.tokio_console requires a build with the tokio-console feature".to_string(),
+                ));
+            }
+
+            if let Some(file) = &logging.file {
+                if file.path.as_os_str().is_empty() {
+                    return Err(ConfigError::Validation(
+                        "Log file path cannot be empty".to_string(),
+                    ));
+                }
+                if file.max_size_mb == Some(0) {
+                    return Err(ConfigError::Validation(
+                        "Log file max size must be greater than 0".to_string(),
+                    ));
+                }
+            }
+        }
+
+        if let Some(thresholds) = &self.thresholds {
+            for (name, metric) in [
+                ("disk_usage", AlertMetric::DiskUsage),
+                ("failed_systemd_units", AlertMetric::FailedSystemdUnits),
+            ] {
+                let Some(threshold) = thresholds.get(metric) else {
+                    continue;
+                };
+                if threshold.warn.is_none() && threshold.critical.is_none() {
+                    return Err(ConfigError::Validation(format!(
+                        "thresholds.{} needs a warn or critical level",
+                        name
+                    )));
+                }
+                if let (Some(warn), Some(critical)) = (threshold.warn, threshold.critical) {
+                    if warn > critical {
+                        return Err(ConfigError::Validation(format!(
+                            "thresholds.{}: warn must not be above critical",
+                            name
+                        )));
+                    }
+                }
+                let levels = [threshold.warn, threshold.critical];
+                if metric == AlertMetric::DiskUsage
+                    && levels.iter().flatten().any(|level| !(0.0..=1.0).contains(level))
+                {
+                    return Err(ConfigError::Validation(
+                        "thresholds.disk_usage levels must be between 0.0 and 1.0".to_string(),
+                    ));
+                }
+            }
+        }
+
+        if let Some(alerts) = &self.alerts {
+            let mut names = std::collections::HashSet::new();
+            for rule in &alerts.rules {
+                if rule.name.trim().is_empty() {
+                    return Err(ConfigError::Validation(
+                        "Alert rule name cannot be empty".to_string(),
+                    ));
+                }
+                if !names.insert(rule.name.as_str()) {
+                    return Err(ConfigError::Validation(format!(
+                        "Duplicate alert rule name: {}",
+                        rule.name
+                    )));
+                }
+                match (rule.threshold, rule.severity) {
+                    (Some(threshold), None) => {
+                        if rule.metric == AlertMetric::DiskUsage && !(0.0..=1.0).contains(&threshold) {
+                            return Err(ConfigError::Validation(format!(
+                                "Alert rule {}: disk_usage threshold must be between 0.0 and 1.0",
+                                rule.name
+                            )));
+                        }
+                    }
+                    (None, Some(severity)) => {
+                        let level = self
+                            .thresholds
+                            .as_ref()
+                            .and_then(|thresholds| thresholds.get(rule.metric))
+                            .and_then(|threshold| threshold.level(severity));
+                        if level.is_none() {
+                            return Err(ConfigError::Validation(format!(
+                                "Alert rule {}: severity {:?} needs a matching level in thresholds",
+                                rule.name, severity
+                            )));
+                        }
+                        if rule.condition.is_some() {
+                            return Err(ConfigError::Validation(format!(
+                                "Alert rule {}: condition cannot be combined with severity",
+                                rule.name
+                            )));
+                        }
+                    }
+                    _ => {
+                        return Err(ConfigError::Validation(format!(
+                            "Alert rule {} needs exactly one of threshold and severity",
+                            rule.name
+                        )));
+                    }
+                }
+                if rule.mount_points.is_some() && rule.metric != AlertMetric::DiskUsage {
+                    return Err(ConfigError::Validation(format!(
+                        "Alert rule {}: mount_points only applies to disk_usage rules",
+                        rule.name
+                    )));
+                }
+                if rule.actions.is_empty() {
+                    return Err(ConfigError::Validation(format!(
+                        "Alert rule {} has no actions",
+                        rule.name
+                    )));
+                }
+                for action in &rule.actions {
+                    match action {
+                        AlertAction::Exec { command, .. } if command.as_os_str().is_empty() => {
+                            return Err(ConfigError::Validation(format!(
+                                "Alert rule {}: exec command cannot be empty",
+                                rule.name
+                            )));
+                        }
+                        AlertAction::Webhook { url, .. }
+                            if !url.starts_with("http://") && !url.starts_with("https://") =>
+                        {
+                            return Err(ConfigError::Validation(format!(
+                                "Alert rule {}: webhook URL must start with http:// or https://",
+                                rule.name
+                            )));
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        }
+
+        if let Some(status) = &self.status {
+            if let Some(address) = &status.listen_address {
+                if address.parse::<std::net::SocketAddr>().is_err() {
+                    return Err(ConfigError::Validation(format!(
+                        "Invalid status listen address: {}",
+                        address
+                    )));
+                }
+            }
+
+            if status.profiling == Some(true) && !crate::profiling::supported() {
+                return Err(ConfigError::Validation(
+                    "status.profiling requires a Unix build with the profiling feature".to_string(),
+                ));
+            }
+        }
+
+        if let Some(remote) = &self.remote_config {
+            if remote.poll_interval_seconds == Some(0) {
+                return Err(ConfigError::Validation(
+                    "Remote config poll interval must be greater than 0".to_string(),
+                ));
+            }
+            if remote.signing_key.as_ref().is_some_and(|key| key.trim().is_empty()) {
+                return Err(ConfigError::Validation(
+                    "Remote config signing key cannot be empty".to_string(),
+                ));
+            }
+        }
+
+        if let Some(commands) = &self.commands {
+            if commands.enabled && commands.signing_key.trim().is_empty() {
+                return Err(ConfigError::Validation(
+                    "Command channel signing key cannot be empty".to_string(),
+                ));
+            }
+
+            for command in commands.allowed_commands.iter().flatten() {
+                if !SUPPORTED_COMMANDS.contains(&command.as_str()) {
+                    return Err(ConfigError::Validation(format!(
+                        "Unsupported command in allowlist: {}",
+                        command
+                    )));
+                }
+            }
+        }
+
+        // Validate API key if present
+        if let Some(api_key) = &self.api.api_key {
+            if api_key.trim().is_empty() {
+                return Err(ConfigError::Validation(
+                    "API key cannot be empty".to_string(),
+                ));
+            }
+        }
+
+        if let Some(oauth) = &self.api.oauth {
+            if self.api.api_key.is_some() {
+                return Err(ConfigError::Validation(
+                    "api.api_key and api.oauth cannot both be set".to_string(),
+                ));
+            }
+            if oauth.token_url.trim().is_empty() || oauth.client_id.trim().is_empty() {
+                return Err(ConfigError::Validation(
+                    "api.oauth needs a token_url and client_id".to_string(),
+                ));
+            }
+            match (&oauth.client_secret, &oauth.device_authorization_url) {
+                (Some(secret), _) if secret.trim().is_empty() => {
+                    return Err(ConfigError::Validation(
+                        "api.oauth.client_secret cannot be empty".to_string(),
+                    ));
+                }
+                (None, None) => {
+                    return Err(ConfigError::Validation(
+                        "api.oauth needs a client_secret or a device_authorization_url for `sentinel-agent login`"
+                            .to_string(),
+                    ));
+                }
+                _ => {}
+            }
+            let token_cache = oauth.token_cache.clone().unwrap_or_default();
+            if token_cache.store == Some(TokenStoreKind::Keyring) {
+                if !cfg!(feature = "keyring") {
+                    return Err(ConfigError::Validation(
+                        "api.oauth.token_cache.store keyring requires a build with the keyring feature".to_string(),
+                    ));
+                }
+                if token_cache.encryption_key.is_some() {
+                    return Err(ConfigError::Validation(
+                        "api.oauth.token_cache.encryption_key only applies to the file store".to_string(),
+                    ));
+                }
+            }
+            if let Some(key) = &token_cache.encryption_key {
+                // Secret references are resolved after validation
+                if let Ok(None) = crate::secrets::SecretRef::parse(key) {
+                    if let Err(e) = crate::encryption::ConfigKey::parse(key) {
+                        return Err(ConfigError::Validation(format!(
+                            "api.oauth.token_cache.encryption_key: {}",
+                            e
+                        )));
+                    }
+                }
+            }
+            if let Some(resource) = &oauth.resource {
+                // RFC 8707: an absolute URI without a fragment
+                if !reqwest::Url::parse(resource).is_ok_and(|url| url.fragment().is_none()) {
+                    return Err(ConfigError::Validation(format!(
+                        "api.oauth.resource must be an absolute URI without a fragment, got {}",
+                        resource
+                    )));
+                }
+            }
+        }
+
+        match (self.get_hostname_mode(), &self.agent.hostname) {
+            (HostnameMode::Custom, None) => {
+                return Err(ConfigError::Validation(
+                    "agent.hostname_mode custom requires agent.hostname".to_string(),
+                ));
+            }
+            (HostnameMode::Custom, Some(hostname)) if hostname.trim().is_empty() => {
+                return Err(ConfigError::Validation(
+                    "agent.hostname cannot be empty".to_string(),
+                ));
+            }
+            (HostnameMode::Short | HostnameMode::Fqdn, Some(_)) => {
+                return Err(ConfigError::Validation(
+                    "agent.hostname is only used with hostname_mode custom".to_string(),
+                ));
+            }
+            _ => {}
+        }
+
+        for (key, value) in [("agent.org_id", &self.agent.org_id), ("agent.project", &self.agent.project)] {
+            if value.as_ref().is_some_and(|value| value.trim().is_empty()) {
+                return Err(ConfigError::Validation(format!("{} cannot be empty", key)));
+            }
+        }
+        if self.agent.project.is_some() && self.agent.org_id.is_none() {
+            return Err(ConfigError::Validation(
+                "agent.project requires agent.org_id".to_string(),
+            ));
+        }
+
+        if let Some(tls) = &self.api.tls {
+            if tls.client_cert.is_some() != tls.client_key.is_some() {
+                return Err(ConfigError::Validation(
+                    "api.tls.client_cert and client_key must be set together".to_string(),
+                ));
+            }
+            if tls.server_name.as_ref().is_some_and(|name| name.trim().is_empty()) {
+                return Err(ConfigError::Validation(
+                    "api.tls.server_name cannot be empty".to_string(),
+                ));
+            }
+            // Reads the certificate files, so check-config catches them too
+            crate::tls::client_config(tls)
+                .map_err(|e| ConfigError::Validation(format!("api.tls: {}", e)))?;
+        }
+
+        Ok(())
+    }
+
+    pub fn get_additional_endpoints(&self) -> &[AdditionalEndpoint] {
+        self.api.additional_endpoints.as_deref().unwrap_or_default()
+    }
+
+    /// This config as seen by one of `api.additional_endpoints`: its endpoint,
+    /// key and tenant in place of the primary ones
+    pub fn for_additional_endpoint(&self, additional: &AdditionalEndpoint) -> Config {
+        let mut config = self.clone();
+        config.api.endpoint = additional.endpoint.clone();
+        config.api.api_key = Some(additional.api_key.clone());
+        // The primary endpoint's token would be sent to another tenant
+        config.api.oauth = None;
+        config.api.additional_endpoints = None;
+        // The primary endpoint's alias does not apply here
+        if let Some(tls) = config.api.tls.as_mut() {
+            tls.server_name = None;
+        }
+        config.agent.org_id = additional.org_id.clone().or(config.agent.org_id);
+        config.agent.project = additional.project.clone().or(config.agent.project);
+        config
+    }
+
+    pub fn get_hostname_mode(&self) -> HostnameMode {
+        match (self.agent.hostname_mode, &self.agent.hostname) {
+            (Some(mode), _) => mode,
+            (None, Some(_)) => HostnameMode::Custom,
+            (None, None) => HostnameMode::Short,
+        }
+    }
+
+    pub fn get_hostname(&self) -> String {
+        crate::hostname::resolve(self.get_hostname_mode(), self.agent.hostname.as_deref())
+    }
+
+    pub fn get_labels(&self) -> BTreeMap<String, String> {
+        self.agent.labels.clone().unwrap_or_default()
+    }
+
+    pub fn get_org_id(&self) -> Option<String> {
+        self.agent.org_id.clone()
+    }
+
+    pub fn get_project(&self) -> Option<String> {
+        self.agent.project.clone()
+    }
+
+    pub fn get_deregister_on_shutdown(&self) -> bool {
+        self.agent.deregister_on_shutdown.unwrap_or(false)
+    }
+
+    pub fn get_maintenance(&self) -> bool {
+        self.agent.maintenance.unwrap_or(false)
+    }
+
+    pub fn get_maintenance_file(&self) -> PathBuf {
+        self.agent
+            .maintenance_file
+            .clone()
+            .unwrap_or_else(crate::maintenance::default_path)
+    }
+
+    pub fn get_max_memory_bytes(&self) -> Option<u64> {
+        self.limits
+            .as_ref()
+            .and_then(|limits| limits.max_memory_mb)
+            .map(|mb| mb.saturating_mul(1024 * 1024))
+    }
+
+    pub fn get_metadata(&self) -> MetadataConfig {
+        self.metadata.clone().unwrap_or_default()
+    }
+
+    pub fn get_logging(&self) -> LoggingConfig {
+        self.logging.clone().unwrap_or_default()
+    }
+
+    pub fn get_status(&self) -> StatusConfig {
+        self.status.clone().unwrap_or_default()
+    }
+
+    /// Plugin directory to load collectors from, when plugins are enabled
+    pub fn get_plugins_directory(&self) -> Option<PathBuf> {
+        self.collection
+            .plugins
+            .as_ref()
+            .filter(|plugins| plugins.enabled)
+            .map(|plugins| {
+                plugins
+                    .directory
+                    .clone()
+                    .unwrap_or_else(|| crate::paths::system_config_dir().join("plugins"))
+            })
+    }
+
+    /// Directory of node_exporter textfiles, when the textfile collector is enabled
+    pub fn get_textfile_directory(&self) -> Option<PathBuf> {
+        self.collection
+            .textfile
+            .as_ref()
+            .filter(|textfile| textfile.enabled)
+            .map(|textfile| textfile.directory.clone())
+    }
+
+    /// Commands of the exec collector, when enabled
+    pub fn get_exec_commands(&self) -> Vec<ExecCommand> {
+        self.collection
+            .exec
+            .as_ref()
+            .filter(|exec| exec.enabled)
+            .map(|exec| exec.commands.clone())
+            .unwrap_or_default()
+    }
+
+    /// Endpoints of the scrape collector, when enabled
+    pub fn get_scrape_targets(&self) -> Vec<ScrapeTarget> {
+        self.collection
+            .scrape
+            .as_ref()
+            .filter(|scrape| scrape.enabled)
+            .map(|scrape| scrape.targets.clone())
+            .unwrap_or_default()
+    }
+
+    /// Largest scrape response read, in bytes
+    pub fn get_scrape_max_body_bytes(&self) -> u64 {
+        self.collection
+            .scrape
+            .as_ref()
+            .and_then(|scrape| scrape.max_body_size_mb)
+            .unwrap_or(10)
+            .saturating_mul(1024 * 1024)
+    }
+
+    /// Ingestion socket settings, when enabled
+    pub fn get_ingest(&self) -> Option<&IngestConfig> {
+        self.collection.ingest.as_ref().filter(|ingest| ingest.enabled)
+    }
+
+    /// Spool settings; offline mode always spools, compressed unless configured otherwise
+    pub fn get_spool(&self) -> Option<SpoolConfig> {
+        let spool = self.collection.spool.clone().filter(|spool| spool.enabled);
+        if !self.is_offline() {
+            return spool;
+        }
+        let mut spool = spool.unwrap_or(SpoolConfig {
+            enabled: true,
+            directory: None,
+            max_size_mb: None,
+            compress: None,
+            after_failures: None,
+        });
+        spool.compress.get_or_insert(true);
+        Some(spool)
+    }
+
+    pub fn is_offline(&self) -> bool {
+        self.offline.as_ref().is_some_and(|offline| offline.enabled)
+    }
+
+    pub fn get_auto_sync(&self) -> bool {
+        self.offline.as_ref().is_some_and(|offline| offline.enabled && offline.auto_sync())
+    }
+
+    /// WASM module settings, when enabled
+    pub fn get_wasm(&self) -> Option<&WasmConfig> {
+        self.collection.wasm.as_ref().filter(|wasm| wasm.enabled)
+    }
+
+    pub fn get_script_limits(&self) -> ScriptLimits {
+        ScriptLimits::from_config(&self.scripting.clone().unwrap_or_default())
+    }
+
+    pub fn get_limits(&self) -> LimitsConfig {
+        self.limits.clone().unwrap_or_default()
+    }
+
+    /// Whether the agent can authenticate to the platform, with an API key or OAuth
+    pub fn has_api_credentials(&self) -> bool {
+        self.api.api_key.is_some() || self.api.oauth.is_some()
+    }
+
+    pub fn get_api_timeout_seconds(&self) -> u64 {
+        self.api.timeout_seconds.unwrap_or(30)
+    }
+
+    pub fn get_health_check_interval_seconds(&self) -> u64 {
+        self.api.health_check_interval_seconds.unwrap_or(60)
+    }
+
+    pub fn get_heartbeat_interval_seconds(&self) -> u64 {
+        self.api.heartbeat_interval_seconds.unwrap_or(60)
+    }
+
+    pub fn get_batch_size(&self) -> usize {
+        self.collection.batch_size.unwrap_or(100)
+    }
+
+    pub fn get_max_buffer_bytes(&self) -> u64 {
+        self.collection.max_buffer_size_mb.unwrap_or(16).saturating_mul(1024 * 1024)
+    }
+
+    pub fn get_flush_interval_seconds(&self) -> u64 {
+        self.collection.flush_interval_seconds.unwrap_or(30)
+    }
+
+    pub fn get_jitter_seconds(&self) -> u64 {
+        self.collection.jitter_seconds.unwrap_or(0)
+    }
+
+    pub fn get_phase_offset(&self) -> bool {
+        self.collection.phase_offset.unwrap_or(false)
+    }
+
+    pub fn get_align_flushes(&self) -> bool {
+        self.collection.align_flushes.unwrap_or(false)
+    }
+
+    pub fn get_correct_timestamps(&self) -> bool {
+        self.collection.correct_timestamps.unwrap_or(false)
+    }
+
+    pub fn get_aggregate(&self) -> bool {
+        self.collection.aggregate.unwrap_or(false)
     }
 
-    pub fn get_api_timeout_seconds(&self) -> u64 {
-        self.api.timeout_seconds.unwrap_or(30)
+    pub fn get_collector_timeout_seconds(&self) -> u64 {
+        self.collection.collector_timeout_seconds.unwrap_or(10)
     }
 
-    pub fn get_batch_size(&self) -> usize {
-        self.collection.batch_size.unwrap_or(100)
+    pub fn get_override_limits(&self) -> bool {
+        self.collection.override_limits.unwrap_or(false)
     }
 
-    pub fn get_flush_interval_seconds(&self) -> u64 {
-        self.collection.flush_interval_seconds.unwrap_or(10)
+    /// Raise settings below the minimums above, returning a warning for each
+    ///
+    /// With `collection.override_limits` the settings are kept as they are.
+    pub fn clamp_limits(&mut self) -> Vec<String> {
+        if self.get_override_limits() {
+            return Vec::new();
+        }
+
+        let mut warnings = Vec::new();
+        let collection = &mut self.collection;
+        if collection.interval_seconds < MIN_INTERVAL_SECONDS {
+            warnings.push(format!(
+                "collection.interval_seconds {} raised to {} (set collection.override_limits to keep it)",
+                collection.interval_seconds, MIN_INTERVAL_SECONDS
+            ));
+            collection.interval_seconds = MIN_INTERVAL_SECONDS;
+        }
+        if let Some(flush) = collection.flush_interval_seconds.filter(|&flush| flush < MIN_FLUSH_INTERVAL_SECONDS) {
+            warnings.push(format!(
+                "collection.flush_interval_seconds {} raised to {} (set collection.override_limits to keep it)",
+                flush, MIN_FLUSH_INTERVAL_SECONDS
+            ));
+            collection.flush_interval_seconds = Some(MIN_FLUSH_INTERVAL_SECONDS);
+        }
+        if let Some(batch_size) = collection.batch_size.filter(|&size| size < MIN_BATCH_SIZE) {
+            warnings.push(format!(
+                "collection.batch_size {} raised to {} (set collection.override_limits to keep it)",
+                batch_size, MIN_BATCH_SIZE
+            ));
+            collection.batch_size = Some(MIN_BATCH_SIZE);
+        }
+
+        // Not clamped, as either value may be deliberate
+        let (flush, timeout) = (self.get_flush_interval_seconds(), self.get_api_timeout_seconds());
+        if flush < timeout {
+            warnings.push(format!(
+                "collection.flush_interval_seconds {} is shorter than api.timeout_seconds {}, so a slow flush can delay the next ones",
+                flush, timeout
+            ));
+        }
+        warnings
     }
 }
 
@@ -107,8 +2675,14 @@ pub enum ConfigError {
     FileRead(String),
     #[error("Failed to parse config file: {0}")]
     Parse(String),
+    #[error("Failed to expand config file: {0}")]
+    Interpolation(String),
+    #[error("Failed to decrypt config file: {0}")]
+    Decryption(String),
     #[error("Config validation error: {0}")]
     Validation(String),
+    #[error("Unknown config keys (fix them, or set strict_keys: false to ignore them): {0}")]
+    UnknownKeys(String),
 }
 
 #[cfg(test)]
@@ -125,7 +2699,7 @@ api:
 collection:
   interval_seconds: 60
   batch_size: 100
-  flush_interval_seconds: 10
+  flush_interval_seconds: 30
   disk:
     enabled: true
 "#.to_string()
@@ -155,12 +2729,786 @@ collection:
         assert!(result.is_err());
     }
 
+    #[test]
+    fn test_config_delta_tolerance_out_of_range() {
+        let yaml = r#"
+agent:
+  hostname: "test-host"
+api:
+  endpoint: "https://api.example.com"
+collection:
+  interval_seconds: 60
+  disk:
+    enabled: true
+  delta:
+    enabled: true
+    tolerance: 5.0
+"#;
+        let result = Config::load_from_str(yaml);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_config_commands_unknown_allowlist_entry() {
+        let yaml = r#"
+agent:
+  hostname: "test-host"
+api:
+  endpoint: "https://api.example.com"
+collection:
+  interval_seconds: 60
+  disk:
+    enabled: true
+commands:
+  enabled: true
+  signing_key: "secret"
+  allowed_commands: ["flush_now", "reboot"]
+"#;
+        let result = Config::load_from_str(yaml);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_config_logging() {
+        let yaml = r#"
+agent:
+  hostname: "test-host"
+api:
+  endpoint: "https://api.example.com"
+collection:
+  interval_seconds: 60
+  disk:
+    enabled: true
+logging:
+  level: debug
+  format: json
+  filters:
+    reqwest: warn
+"#;
+        let config = Config::load_from_str(yaml).unwrap();
+        let logging = config.get_logging();
+        assert_eq!(logging.get_level(), "debug");
+        assert_eq!(logging.get_format(), LogFormat::Json);
+        assert!(logging.file.is_none());
+
+        let invalid = yaml.replace("level: debug", "level: verbose");
+        assert!(Config::load_from_str(&invalid).is_err());
+    }
+
+    #[test]
+    fn test_config_log_file() {
+        let yaml = r#"
+agent:
+  hostname: "test-host"
+api:
+  endpoint: "https://api.example.com"
+collection:
+  interval_seconds: 60
+  disk:
+    enabled: true
+logging:
+  file:
+    path: "/var/log/operion/agent.log"
+    max_size_mb: 10
+    rotation: hourly
+"#;
+        let config = Config::load_from_str(yaml).unwrap();
+        let file = config.get_logging().file.unwrap();
+        assert_eq!(file.get_max_size_bytes(), Some(10 * 1024 * 1024));
+        assert_eq!(file.get_rotation(), LogRotation::Hourly);
+        assert_eq!(file.get_retention(), 5);
+    }
+
     #[test]
     fn test_config_defaults() {
         let yaml = create_valid_config_yaml();
         let config = Config::load_from_str(&yaml).unwrap();
         assert_eq!(config.get_api_timeout_seconds(), 30);
         assert_eq!(config.get_batch_size(), 100);
-        assert_eq!(config.get_flush_interval_seconds(), 10);
+        assert_eq!(config.get_flush_interval_seconds(), 30);
+        assert_eq!(config.get_health_check_interval_seconds(), 60);
+        assert_eq!(config.get_heartbeat_interval_seconds(), 60);
+        assert_eq!(config.get_jitter_seconds(), 0);
+        assert!(!config.get_phase_offset());
+        assert_eq!(config.get_collector_timeout_seconds(), 10);
+        assert_eq!(config.get_max_buffer_bytes(), 16 * 1024 * 1024);
+        assert_eq!(config.get_max_memory_bytes(), None);
+        assert!(config.get_status().is_enabled());
+        assert_eq!(config.get_status().get_listen_address(), "127.0.0.1:9176");
+    }
+
+    #[test]
+    fn test_spool_size_saturates() {
+        let spool: SpoolConfig = serde_yaml::from_str(&format!("enabled: true\nmax_size_mb: {}", u64::MAX)).unwrap();
+        assert_eq!(spool.get_max_size_bytes(), u64::MAX);
+    }
+
+    #[test]
+    fn test_check_reports_unknown_and_deprecated_keys() {
+        let yaml = r#"
+strict_keys: false
+agent:
+  id: "web-server-01"
+  hostname: "test-host"
+api:
+  endpoint: "https://api.example.com"
+collection:
+  interval_seconds: 60
+  intervl_seconds: 30
+  disk:
+    enabled: true
+"#;
+        let report = Config::check_str(yaml).unwrap();
+        assert_eq!(report.unknown_keys, vec!["collection.intervl_seconds".to_string()]);
+        assert_eq!(report.deprecations.len(), 1);
+        assert!(report.deprecations[0].starts_with("agent.id is deprecated"));
+
+        let clean = Config::check_str(&create_valid_config_yaml()).unwrap();
+        assert!(clean.unknown_keys.is_empty());
+        assert!(clean.deprecations.is_empty());
+    }
+
+    #[test]
+    fn test_strict_keys_reject_unknown_with_suggestion() {
+        let yaml = r#"
+agent:
+  hostname: "test-host"
+api:
+  endpoint: "https://api.example.com"
+collection:
+  interval_seconds: 60
+  disk:
+    enabled: true
+    exlude_mount_points: ["/boot"]
+  frobnicate: true
+"#;
+        let err = Config::load_from_str(yaml).unwrap_err().to_string();
+        assert!(err.contains("collection.disk.exlude_mount_points (did you mean collection.disk.exclude_mount_points?)"));
+        assert!(err.ends_with("collection.frobnicate"));
+
+        let relaxed = Config::load_from_str(&format!("strict_keys: false\n{}", yaml)).unwrap();
+        assert_eq!(relaxed.unknown_key_warnings.len(), 2);
+        assert!(relaxed.unknown_key_warnings.iter().any(|w| w == "collection.frobnicate"));
+    }
+
+    #[test]
+    fn test_interpolate_env() {
+        let env = |name: &str| match name {
+            "API_KEY" => Some("secret".to_string()),
+            "EMPTY" => Some(String::new()),
+            _ => None,
+        };
+
+        let yaml = r#"api_key: "${API_KEY}"
+endpoint: "${ENDPOINT:-https://api.example.com}"
+region: "${EMPTY:-us-east-1}"
+literal: "$${API_KEY}"
+message: '`${event.subject} at ${event.value * 100.0}%`'"#;
+        let expanded = interpolate_env(yaml, env).unwrap();
+        assert!(expanded.contains(r#"api_key: "secret""#));
+        assert!(expanded.contains(r#"endpoint: "https://api.example.com""#));
+        assert!(expanded.contains(r#"region: "us-east-1""#));
+        assert!(expanded.contains(r#"literal: "${API_KEY}""#));
+        assert!(expanded.contains("`${event.subject} at ${event.value * 100.0}%`"));
+
+        let result = interpolate_env("api_key: ${MISSING}", env);
+        assert!(matches!(result, Err(ConfigError::Interpolation(message)) if message.contains("MISSING")));
+    }
+
+    #[test]
+    fn test_interpolation_skips_comments_and_scripts() {
+        let env = |name: &str| match name {
+            "API_KEY" => Some("secret".to_string()),
+            "TIMEOUT" => Some("15".to_string()),
+            _ => None,
+        };
+
+        let yaml = r#"# Set ${UNSET_IN_COMMENT} before starting
+api:
+  api_key: "${API_KEY}"
+  timeout_seconds: ${TIMEOUT}
+collection:
+  script: 'let tag = `${metric}`; metrics'
+alerts:
+  rules:
+    - name: full
+      condition: "value > ${threshold}"
+      message: "${API_KEY} is not expanded in scripts""#;
+        let mut value: serde_yaml::Value = serde_yaml::from_str(yaml).unwrap();
+        interpolate_yaml(&mut value, "", &env).unwrap();
+
+        assert_eq!(value["api"]["api_key"].as_str(), Some("secret"));
+        assert_eq!(value["api"]["timeout_seconds"].as_u64(), Some(15));
+        assert_eq!(value["collection"]["script"].as_str(), Some("let tag = `${metric}`; metrics"));
+        let rule = &value["alerts"]["rules"][0];
+        assert_eq!(rule["condition"].as_str(), Some("value > ${threshold}"));
+        assert_eq!(rule["message"].as_str(), Some("${API_KEY} is not expanded in scripts"));
+    }
+
+    #[test]
+    fn test_drop_in_fragments_merge_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("agent.yaml");
+        std::fs::write(&path, create_valid_config_yaml()).unwrap();
+
+        let drop_in = drop_in_dir(&path);
+        assert!(drop_in.ends_with("agent.yaml.d"));
+        std::fs::create_dir(&drop_in).unwrap();
+        std::fs::write(
+            drop_in.join("10-auth.yaml"),
+            "api:\n  api_key: first\n  timeout_seconds: 5\n",
+        )
+        .unwrap();
+        std::fs::write(drop_in.join("20-auth.yml"), "api:\n  api_key: second\n").unwrap();
+        std::fs::write(drop_in.join("30-labels.yaml"), "agent:\n  labels:\n    env: prod\n").unwrap();
+        std::fs::write(drop_in.join("40-empty.yaml"), "").unwrap();
+        std::fs::write(drop_in.join("README"), "not: [yaml").unwrap();
+
+        let config = Config::load_from_file(&path).unwrap();
+        assert_eq!(config.api.api_key.as_deref(), Some("second"));
+        assert_eq!(config.api.timeout_seconds, Some(5));
+        // Keys not in any fragment keep the main file's values
+        assert_eq!(config.api.endpoint, "https://api.example.com");
+        assert_eq!(config.agent.hostname.as_deref(), Some("test-host"));
+        assert_eq!(config.get_labels().get("env").map(String::as_str), Some("prod"));
+
+        std::fs::write(drop_in.join("50-broken.yaml"), "api: [").unwrap();
+        assert!(matches!(Config::load_from_file(&path), Err(ConfigError::Parse(e)) if e.contains("50-broken.yaml")));
+    }
+
+    #[test]
+    fn test_durations_and_sizes_with_units() {
+        let yaml = create_valid_config_yaml()
+            .replace("interval_seconds: 60", "interval_seconds: 1m")
+            .replace("flush_interval_seconds: 30", "flush_interval_seconds: \"90s\"")
+            .replace("  batch_size: 100\n", "  batch_size: 100\n  max_buffer_size_mb: 1GiB\n");
+        let config = Config::load_from_str(&yaml).unwrap();
+        assert_eq!(config.collection.interval_seconds, 60);
+        assert_eq!(config.get_flush_interval_seconds(), 90);
+        assert_eq!(config.get_max_buffer_bytes(), 1 << 30);
+
+        let yaml = create_valid_config_yaml().replace("interval_seconds: 60", "interval_seconds: 60 minutes");
+        assert!(matches!(Config::load_from_str(&yaml), Err(ConfigError::Parse(_))));
+    }
+
+    #[test]
+    fn test_clamp_limits() {
+        let yaml = create_valid_config_yaml()
+            .replace("interval_seconds: 60", "interval_seconds: 1")
+            .replace("batch_size: 100", "batch_size: 1");
+        let mut config = Config::load_from_str(&yaml).unwrap();
+        let warnings = config.clamp_limits();
+        assert_eq!(warnings.len(), 2);
+        assert_eq!(config.collection.interval_seconds, MIN_INTERVAL_SECONDS);
+        assert_eq!(config.get_batch_size(), MIN_BATCH_SIZE);
+
+        // Settings within the limits are left alone
+        assert!(config.clamp_limits().is_empty());
+
+        let overridden = yaml.replace("  batch_size: 1\n", "  batch_size: 1\n  override_limits: true\n");
+        let mut config = Config::load_from_str(&overridden).unwrap();
+        assert!(config.clamp_limits().is_empty());
+        assert_eq!(config.collection.interval_seconds, 1);
+        assert_eq!(config.get_batch_size(), 1);
+    }
+
+    #[test]
+    fn test_clamp_limits_warns_when_flushes_outlast_their_interval() {
+        let yaml = create_valid_config_yaml().replace("timeout_seconds: 30", "timeout_seconds: 60");
+        let mut config = Config::load_from_str(&yaml).unwrap();
+        let warnings = config.clamp_limits();
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].contains("api.timeout_seconds 60"));
+        assert_eq!(config.get_flush_interval_seconds(), 30);
+
+        // An explicit 10 second flush against the default timeout is warned about
+        let short = create_valid_config_yaml()
+            .replace("  timeout_seconds: 30\n", "")
+            .replace("flush_interval_seconds: 30", "flush_interval_seconds: 10");
+        let warnings = Config::load_from_str(&short).unwrap().clamp_limits();
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].contains("flush_interval_seconds 10 is shorter than api.timeout_seconds 30"));
+
+        // The defaults, as written by `init` or left unset, are accepted
+        assert!(Config::load_from_str(&create_valid_config_yaml()).unwrap().clamp_limits().is_empty());
+        let defaults = create_valid_config_yaml()
+            .replace("  timeout_seconds: 30\n", "")
+            .replace("  flush_interval_seconds: 30\n", "");
+        assert!(Config::load_from_str(&defaults).unwrap().clamp_limits().is_empty());
+    }
+
+    #[test]
+    fn test_apply_overrides() {
+        let dir = tempfile::tempdir().unwrap();
+        let key_file = dir.path().join("api-key");
+        std::fs::write(&key_file, "from-secret\n").unwrap();
+
+        let mut config = Config::load_from_str(&create_valid_config_yaml()).unwrap();
+        config
+            .apply_overrides(&Overrides {
+                endpoint: Some("https://staging.example.com".to_string()),
+                interval_seconds: Some(15),
+                api_key_file: Some(key_file),
+                log_level: Some("debug".to_string()),
+                hostname: Some("container-1".to_string()),
+            })
+            .unwrap();
+        assert_eq!(config.api.endpoint, "https://staging.example.com");
+        assert_eq!(config.collection.interval_seconds, 15);
+        assert_eq!(config.api.api_key.as_deref(), Some("from-secret"));
+        assert_eq!(config.get_logging().get_level(), "debug");
+        assert_eq!(config.get_hostname(), "container-1");
+
+        // Overridden values are validated like the file's
+        let result = config.apply_overrides(&Overrides {
+            log_level: Some("loud".to_string()),
+            ..Overrides::default()
+        });
+        assert!(matches!(result, Err(ConfigError::Validation(_))));
+    }
+
+    #[test]
+    fn test_migrate_older_layouts() {
+        const CHANGES: &[Migration] = &[
+            Migration { before: 2, key: "agent.id", change: Change::Remove("remove this key") },
+            Migration { before: 3, key: "collection.interval", change: Change::Rename { to: "collection.interval_seconds" } },
+            Migration { before: 3, key: "hostname", change: Change::Rename { to: "agent.hostname" } },
+        ];
+
+        let mut root: serde_yaml::Value = serde_yaml::from_str(
+            "hostname: web-01\nagent:\n  id: x\ncollection:\n  interval: 30\n  interval_seconds: 60\n",
+        )
+        .unwrap();
+        let warnings = migrate(&mut root, CHANGES).unwrap();
+        assert_eq!(
+            warnings,
+            [
+                "agent.id is deprecated: remove this key",
+                "collection.interval is deprecated: moved to collection.interval_seconds, which is also set and wins",
+                "hostname is deprecated: moved to agent.hostname; the value was carried over",
+            ]
+        );
+        assert!(root["agent"].get("id").is_none());
+        assert!(root["collection"].get("interval").is_none());
+        assert_eq!(root["collection"]["interval_seconds"], serde_yaml::Value::from(60));
+        assert_eq!(root["agent"]["hostname"], serde_yaml::Value::from("web-01"));
+
+        // Only migrations newer than the file's layout apply
+        let mut current: serde_yaml::Value =
+            serde_yaml::from_str("version: 2\nagent:\n  id: x\nhostname: web-01\n").unwrap();
+        assert_eq!(migrate(&mut current, CHANGES).unwrap().len(), 1);
+        assert_eq!(current["agent"]["id"], serde_yaml::Value::from("x"));
+        assert_eq!(current["agent"]["hostname"], serde_yaml::Value::from("web-01"));
+    }
+
+    #[test]
+    fn test_memory_limit_moved_to_limits() {
+        let older = format!(
+            "version: 2\n{}",
+            create_valid_config_yaml().replace("agent:\n", "agent:\n  max_memory_mb: 64\n")
+        );
+        let config = Config::load_from_str(&older).unwrap();
+        assert_eq!(config.get_max_memory_bytes(), Some(64 * 1024 * 1024));
+        assert_eq!(
+            config.migration_warnings,
+            ["agent.max_memory_mb is deprecated: moved to limits.max_memory_mb; the value was carried over"]
+        );
+
+        // At the current layout the old key is unknown
+        let current = older.replace("version: 2\n", &format!("version: {}\n", CONFIG_VERSION));
+        assert!(matches!(Config::check_str(&current), Err(ConfigError::UnknownKeys(_))));
+        let moved = format!("{}limits:\n  max_memory_mb: 64\n", create_valid_config_yaml());
+        assert_eq!(Config::load_from_str(&moved).unwrap().get_max_memory_bytes(), Some(64 * 1024 * 1024));
+    }
+
+    #[test]
+    fn test_config_version() {
+        let legacy = create_valid_config_yaml().replace("agent:\n", "agent:\n  id: \"web-01\"\n");
+        let config = Config::load_from_str(&legacy).unwrap();
+        assert_eq!(config.get_version(), 1);
+        assert_eq!(config.migration_warnings.len(), 1);
+
+        // agent.id is only understood in files older than version 2
+        let current = format!("version: 2\n{}", legacy);
+        assert!(matches!(Config::check_str(&current), Err(ConfigError::UnknownKeys(_))));
+        let report = Config::check_str(&format!("strict_keys: false\n{}", current)).unwrap();
+        assert_eq!(report.unknown_keys, vec!["agent.id".to_string()]);
+        assert!(report.deprecations.is_empty());
+
+        let newer = format!("version: {}\n{}", CONFIG_VERSION + 1, create_valid_config_yaml());
+        assert!(matches!(Config::load_from_str(&newer), Err(ConfigError::Validation(_))));
+        let invalid = format!("version: \"two\"\n{}", create_valid_config_yaml());
+        assert!(Config::load_from_str(&invalid).is_err());
+    }
+
+    #[test]
+    fn test_json_schema_describes_config() {
+        let schema = serde_json::to_value(Config::json_schema()).unwrap();
+        let required: Vec<&str> = schema["required"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter_map(|key| key.as_str())
+            .collect();
+        assert_eq!(required, ["agent", "api", "collection"]);
+
+        let properties = schema["properties"].as_object().unwrap();
+        for section in ["limits", "scripting", "offline", "alerts", "logging"] {
+            assert!(properties.contains_key(section), "missing {}", section);
+        }
+        // Doc comments become descriptions
+        assert!(serde_json::to_string(&schema).unwrap().contains("Pause collection and sending while true"));
+    }
+
+    #[test]
+    fn test_check_fails_validation() {
+        let yaml = create_valid_config_yaml().replace("interval_seconds: 60", "interval_seconds: 0");
+        assert!(matches!(Config::check_str(&yaml), Err(ConfigError::Validation(_))));
+        let yaml = create_valid_config_yaml().replace("interval_seconds: 60", "interval_seconds: 86401");
+        assert!(matches!(Config::check_str(&yaml), Err(ConfigError::Validation(_))));
+    }
+
+    #[test]
+    fn test_jitter_must_be_below_intervals() {
+        let yaml = create_valid_config_yaml()
+            .replace("interval_seconds: 60", "interval_seconds: 60\n  jitter_seconds: 5");
+        let config = Config::load_from_str(&yaml).unwrap();
+        assert_eq!(config.get_jitter_seconds(), 5);
+
+        let yaml = create_valid_config_yaml()
+            .replace("interval_seconds: 60", "interval_seconds: 60\n  jitter_seconds: 30");
+        assert!(matches!(Config::load_from_str(&yaml), Err(ConfigError::Validation(_))));
+    }
+
+    #[test]
+    fn test_resolved_settings_fill_defaults() {
+        let yaml = r#"
+agent:
+  hostname: "test-host"
+api:
+  endpoint: "https://api.example.com"
+collection:
+  interval_seconds: 60
+  disk:
+    enabled: true
+"#;
+        let config = Config::load_from_str(yaml).unwrap();
+        let settings = config.resolved_settings();
+        let get = |key: &str| settings.iter().find(|(k, _)| *k == key).map(|(_, v)| v.clone());
+        assert_eq!(get("collection.batch_size"), Some("100".to_string()));
+        assert_eq!(get("logging.level"), Some("info".to_string()));
+        assert_eq!(get("status.listen_address"), Some("127.0.0.1:9176".to_string()));
+    }
+
+    #[test]
+    fn test_config_alerts() {
+        let yaml = format!(
+            r#"{}alerts:
+  enabled: true
+  rules:
+    - name: root-full
+      metric: disk_usage
+      threshold: 0.9
+      mount_points: ["/"]
+      actions:
+        - type: exec
+          command: /usr/local/bin/cleanup.sh
+        - type: webhook
+          url: https://hooks.example.com/alert
+        - type: syslog
+"#,
+            create_valid_config_yaml()
+        );
+        let config = Config::load_from_str(&yaml).unwrap();
+        let alerts = config.alerts.unwrap();
+        assert_eq!(alerts.rules[0].metric, AlertMetric::DiskUsage);
+        assert_eq!(alerts.rules[0].actions.len(), 3);
+        assert!(!alerts.rules[0].get_on_resolve());
+
+        let invalid = yaml.replace("threshold: 0.9", "threshold: 90");
+        assert!(Config::load_from_str(&invalid).is_err());
+
+        let invalid = yaml.replace("https://hooks", "ftp://hooks");
+        assert!(Config::load_from_str(&invalid).is_err());
+
+        let invalid = yaml
+            .replace("metric: disk_usage", "metric: failed_systemd_units")
+            .replace("threshold: 0.9", "threshold: 0");
+        assert!(matches!(Config::load_from_str(&invalid), Err(ConfigError::Validation(_))));
+    }
+
+    #[test]
+    fn test_config_thresholds() {
+        let yaml = format!(
+            r#"{}thresholds:
+  disk_usage:
+    warn: 0.8
+    critical: 0.9
+    duration_seconds: 300
+alerts:
+  enabled: true
+  rules:
+    - name: disk-critical
+      metric: disk_usage
+      severity: critical
+      actions:
+        - type: syslog
+"#,
+            create_valid_config_yaml()
+        );
+        let config = Config::load_from_str(&yaml).unwrap();
+        let threshold = config.thresholds.as_ref().unwrap().get(AlertMetric::DiskUsage).unwrap();
+        assert_eq!(threshold.level(Severity::Critical), Some(0.9));
+        assert_eq!(threshold.get_duration_seconds(), 300);
+        assert_eq!(config.alerts.unwrap().rules[0].severity, Some(Severity::Critical));
+
+        let inverted = yaml.replace("warn: 0.8", "warn: 0.95");
+        assert!(Config::load_from_str(&inverted).is_err());
+
+        // The rule's level must exist, and a rule uses either severity or threshold
+        let missing_level = yaml.replace("    critical: 0.9\n", "");
+        assert!(Config::load_from_str(&missing_level).is_err());
+        let both = yaml.replace("severity: critical", "severity: critical\n      threshold: 0.5");
+        assert!(Config::load_from_str(&both).is_err());
+    }
+
+    #[test]
+    fn test_config_encrypted_values() {
+        use crate::encryption::ConfigKey;
+
+        // The key is passed in rather than set in the environment other tests share
+        let encoded = ConfigKey::generate();
+        let key = || ConfigKey::parse(&encoded).map(Some);
+        let sealed = ConfigKey::parse(&encoded).unwrap().encrypt("s3cr3t");
+
+        let yaml = create_valid_config_yaml()
+            .replace("  timeout_seconds: 30\n", &format!("  timeout_seconds: 30\n  api_key: \"{}\"\n", sealed));
+        let config = Config::check_str_with_key(&yaml, key).unwrap().config;
+        assert_eq!(config.api.api_key.as_deref(), Some("s3cr3t"));
+
+        let tampered = yaml.replace("data:", "data:AA");
+        assert!(matches!(Config::check_str_with_key(&tampered, key), Err(ConfigError::Decryption(_))));
+    }
+
+    #[test]
+    fn test_config_hostname_mode() {
+        let config = Config::load_from_str(&create_valid_config_yaml()).unwrap();
+        assert_eq!(config.get_hostname_mode(), HostnameMode::Custom);
+        assert_eq!(config.get_hostname(), "test-host");
+
+        let fqdn = create_valid_config_yaml().replace("  hostname: \"test-host\"\n", "  hostname_mode: fqdn\n");
+        assert_eq!(Config::load_from_str(&fqdn).unwrap().get_hostname_mode(), HostnameMode::Fqdn);
+
+        let conflicting = create_valid_config_yaml().replace("agent:\n", "agent:\n  hostname_mode: short\n");
+        assert!(Config::load_from_str(&conflicting).is_err());
+        let missing = fqdn.replace("hostname_mode: fqdn", "hostname_mode: custom");
+        assert!(Config::load_from_str(&missing).is_err());
+
+        // A command-line hostname replaces the mode from the file
+        let mut config = Config::load_from_str(&fqdn).unwrap();
+        let overrides = Overrides { hostname: Some("db-7".to_string()), ..Default::default() };
+        config.apply_overrides(&overrides).unwrap();
+        assert_eq!(config.get_hostname(), "db-7");
+    }
+
+    #[test]
+    fn test_config_additional_endpoints() {
+        let yaml = create_valid_config_yaml().replace(
+            "  timeout_seconds: 30\n",
+            "  timeout_seconds: 30\n  api_key: \"primary\"\n  additional_endpoints:\n    - name: customer-a\n      endpoint: \"https://a.example.com\"\n      api_key: \"key-a\"\n",
+        );
+        let config = Config::load_from_str(&yaml).unwrap();
+        let additional = config.for_additional_endpoint(&config.get_additional_endpoints()[0]);
+        assert_eq!(additional.api.endpoint, "https://a.example.com");
+        assert_eq!(additional.api.api_key.as_deref(), Some("key-a"));
+        assert_eq!(config.get_additional_endpoints()[0].get_queue_size(), 100);
+        assert_eq!(config.get_additional_endpoints()[0].get_max_retries(), 3);
+        let empty_queue = yaml.replace("      api_key: \"key-a\"\n", "      api_key: \"key-a\"\n      queue_size: 0\n");
+        assert!(Config::load_from_str(&empty_queue).is_err());
+
+        let duplicate = yaml.replace(
+            "      api_key: \"key-a\"\n",
+            "      api_key: \"key-a\"\n    - name: customer-a\n      endpoint: \"https://b.example.com\"\n      api_key: \"key-b\"\n",
+        );
+        assert!(Config::load_from_str(&duplicate).is_err());
+        let reserved = yaml.replace("name: customer-a", "name: default");
+        assert!(Config::load_from_str(&reserved).is_err());
+        let without_primary_key = yaml.replace("  api_key: \"primary\"\n", "");
+        assert!(Config::load_from_str(&without_primary_key).is_err());
+        let with_primary_oauth = yaml.replace(
+            "  api_key: \"primary\"\n",
+            "  oauth:\n    token_url: \"https://auth.example.com/token\"\n    client_id: agent\n    client_secret: s3cret\n",
+        );
+        assert!(Config::load_from_str(&with_primary_oauth).is_ok());
+    }
+
+    #[test]
+    fn test_config_oauth() {
+        let oauth = "  oauth:\n    token_url: \"https://auth.example.com/token\"\n    client_id: agent\n    client_secret: s3cret\n";
+        let yaml = create_valid_config_yaml().replace("  timeout_seconds: 30\n", &format!("  timeout_seconds: 30\n{}", oauth));
+        let config = Config::load_from_str(&yaml).unwrap();
+        assert!(config.has_api_credentials());
+        assert_eq!(config.api.oauth.as_ref().unwrap().client_id, "agent");
+        // Additional endpoints keep their own API keys
+        assert!(config.for_additional_endpoint(&AdditionalEndpoint {
+            name: "customer-a".to_string(),
+            endpoint: "https://a.example.com".to_string(),
+            api_key: "key-a".to_string(),
+            org_id: None,
+            project: None,
+            queue_size: None,
+            max_retries: None,
+            retry_backoff_seconds: None,
+        })
+        .api
+        .oauth
+        .is_none());
+
+        let both = yaml.replace("  timeout_seconds: 30\n", "  timeout_seconds: 30\n  api_key: \"key\"\n");
+        assert!(Config::load_from_str(&both).is_err());
+        let empty_secret = yaml.replace("s3cret", "\"\"");
+        assert!(Config::load_from_str(&empty_secret).is_err());
+        let without_secret = yaml.replace("    client_secret: s3cret\n", "");
+        assert!(Config::load_from_str(&without_secret).is_err());
+        let device_login = yaml.replace(
+            "    client_secret: s3cret\n",
+            "    device_authorization_url: \"https://auth.example.com/device\"\n",
+        );
+        assert!(Config::load_from_str(&device_login).is_ok());
+
+        let scoped = yaml.replace(
+            "    client_secret: s3cret\n",
+            "    client_secret: s3cret\n    audience: \"https://api.example.com\"\n    resource: \"https://api.example.com/\"\n    metrics_scopes: [\"metrics:write\"]\n",
+        );
+        let config = Config::load_from_str(&scoped).unwrap();
+        let oauth = config.api.oauth.unwrap();
+        assert_eq!(oauth.audience.as_deref(), Some("https://api.example.com"));
+        assert_eq!(oauth.metrics_scopes, Some(vec!["metrics:write".to_string()]));
+        let relative_resource = scoped.replace("\"https://api.example.com/\"", "api");
+        assert!(Config::load_from_str(&relative_resource).is_err());
+
+        let key = crate::encryption::ConfigKey::generate();
+        let cached = yaml.replace(
+            "    client_secret: s3cret\n",
+            &format!("    client_secret: s3cret\n    token_cache:\n      path: /tmp/tenant-a.json\n      encryption_key: \"{}\"\n", key),
+        );
+        let config = Config::load_from_str(&cached).unwrap();
+        let cache = config.api.oauth.unwrap().token_cache.unwrap();
+        assert_eq!(cache.path, Some(PathBuf::from("/tmp/tenant-a.json")));
+        assert!(Config::load_from_str(&cached.replace(&key, "not-a-key")).is_err());
+        let keyring = cached.replace(&format!("      encryption_key: \"{}\"\n", key), "      store: keyring\n");
+        assert_eq!(Config::load_from_str(&keyring).is_ok(), cfg!(feature = "keyring"));
+    }
+
+    #[test]
+    fn test_config_metadata_providers() {
+        let yaml = format!(
+            "{}metadata:\n  imdsv2_only: true\n  providers:\n    aws:\n      endpoint: \"http://imds-proxy:8080\"\n      timeout_ms: 2s\n",
+            create_valid_config_yaml()
+        );
+        let config = Config::load_from_str(&yaml).unwrap();
+        let metadata = config.get_metadata();
+        assert!(metadata.is_imdsv2_only());
+        assert!(metadata.probes(MetadataProvider::Aws));
+        let aws = metadata.providers.unwrap().aws.unwrap();
+        assert_eq!(aws.endpoint.as_deref(), Some("http://imds-proxy:8080"));
+        assert_eq!(aws.timeout_ms, Some(2000));
+
+        let pinned = Config::load_from_str(&format!("{}  provider: azure\n", yaml)).unwrap();
+        assert!(pinned.get_metadata().probes(MetadataProvider::Azure));
+        assert!(!pinned.get_metadata().probes(MetadataProvider::Aws));
+        assert!(Config::load_from_str(&format!("{}  provider: openstack\n", yaml)).is_err());
+
+        let invalid = yaml.replace("http://imds-proxy:8080", "imds-proxy:8080");
+        assert!(Config::load_from_str(&invalid).is_err());
+    }
+
+    #[test]
+    fn test_config_tenant() {
+        let yaml = create_valid_config_yaml().replace("agent:\n", "agent:\n  project: storage\n");
+        assert!(Config::load_from_str(&yaml).is_err());
+
+        let yaml = yaml.replace("agent:\n", "agent:\n  org_id: org_42\n");
+        let config = Config::load_from_str(&yaml).unwrap();
+        assert_eq!(config.get_org_id().as_deref(), Some("org_42"));
+        assert_eq!(config.get_project().as_deref(), Some("storage"));
+    }
+
+    #[test]
+    fn test_config_textfile() {
+        let yaml = format!(
+            "{}  textfile:\n    enabled: true\n    directory: /var/lib/node_exporter/textfile_collector\n",
+            create_valid_config_yaml()
+        );
+        let config = Config::load_from_str(&yaml).unwrap();
+        assert_eq!(
+            config.get_textfile_directory(),
+            Some(PathBuf::from("/var/lib/node_exporter/textfile_collector"))
+        );
+
+        let disabled = yaml.replace("enabled: true\n    directory", "enabled: false\n    directory");
+        assert!(Config::load_from_str(&disabled).unwrap().get_textfile_directory().is_none());
+        let empty = yaml.replace("/var/lib/node_exporter/textfile_collector", "\"\"");
+        assert!(Config::load_from_str(&empty).is_err());
+    }
+
+    #[test]
+    fn test_config_exec_and_ingest() {
+        let yaml = format!(
+            "{}  exec:\n    enabled: true\n    commands:\n      - command: /usr/local/bin/raid-status\n        args: [\"--json\"]\n  ingest:\n    enabled: true\n",
+            create_valid_config_yaml()
+        );
+        let config = Config::load_from_str(&yaml).unwrap();
+        let commands = config.get_exec_commands();
+        assert_eq!(commands.len(), 1);
+        assert_eq!(commands[0].args.as_deref(), Some(&["--json".to_string()][..]));
+        assert!(config.get_ingest().unwrap().get_path().ends_with("ingest.sock"));
+
+        let empty = yaml.replace("/usr/local/bin/raid-status", "\"\"");
+        assert!(Config::load_from_str(&empty).is_err());
+    }
+
+    #[test]
+    fn test_config_scrape_targets() {
+        let yaml = format!(
+            "{}  scrape:\n    enabled: true\n    targets:\n      - url: http://localhost:9100/metrics\n      - name: app\n        url: https://app.internal/metrics\n",
+            create_valid_config_yaml()
+        );
+        let config = Config::load_from_str(&yaml).unwrap();
+        let names: Vec<String> = config.get_scrape_targets().iter().map(ScrapeTarget::get_name).collect();
+        assert_eq!(names, ["localhost:9100", "app"]);
+        assert_eq!(config.get_scrape_max_body_bytes(), 10 * 1024 * 1024);
+
+        let capped = yaml.replace("    targets:\n", "    max_body_size_mb: 2MiB\n    targets:\n");
+        assert_eq!(Config::load_from_str(&capped).unwrap().get_scrape_max_body_bytes(), 2 * 1024 * 1024);
+        let zero = yaml.replace("    targets:\n", "    max_body_size_mb: 0\n    targets:\n");
+        assert!(Config::load_from_str(&zero).is_err());
+
+        let relative = yaml.replace("http://localhost:9100/metrics", "/metrics");
+        assert!(Config::load_from_str(&relative).is_err());
+        let ftp = yaml.replace("http://localhost:9100/metrics", "ftp://localhost/metrics");
+        assert!(Config::load_from_str(&ftp).is_err());
+    }
+
+    #[test]
+    fn test_config_tls() {
+        let yaml = create_valid_config_yaml().replace(
+            "  timeout_seconds: 30\n",
+            "  timeout_seconds: 30\n  tls:\n    min_version: \"1.3\"\n    server_name: api.example.com\n",
+        );
+        let config = Config::load_from_str(&yaml).unwrap();
+        let tls = config.api.tls.as_ref().unwrap();
+        assert_eq!(tls.get_min_version(), TlsVersion::V1_3);
+
+        let half_identity = yaml.replace("    server_name", "    client_cert: /etc/agent.crt\n    server_name");
+        assert!(Config::load_from_str(&half_identity).is_err());
+        let bad_cipher = yaml.replace("    server_name", "    ciphers: [\"TLS_NULL\"]\n    server_name");
+        assert!(Config::load_from_str(&bad_cipher).is_err());
+    }
+
+    #[test]
+    fn test_config_status_invalid_address() {
+        let yaml = format!("{}status:\n  listen_address: \"localhost\"\n", create_valid_config_yaml());
+        assert!(Config::load_from_str(&yaml).is_err());
     }
 }
@@ -0,0 +1,169 @@
+//! Detection of the container runtime the agent runs under
+//!
+//! Inside a container, disk metrics describe the container's mounts rather
+//! than the host's, so the runtime is reported with the instance metadata.
+//! Detection uses marker files, the `container` environment variable set by
+//! Podman and LXC, and container IDs found in cgroup and mount paths.
+
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ContainerRuntime {
+    Docker,
+    Containerd,
+    Podman,
+    CriO,
+    Lxc,
+    /// Containerized, but the runtime could not be told
+    Other,
+}
+
+/// The container the agent runs in
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ContainerInfo {
+    pub runtime: ContainerRuntime,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub container_id: Option<String>,
+}
+
+impl ContainerInfo {
+    /// The container the agent runs in, `None` on a host or VM
+    pub fn detect() -> Option<Self> {
+        Self::from_sources(
+            Path::new("/.dockerenv").exists(),
+            Path::new("/run/.containerenv").exists(),
+            std::env::var("container").ok().as_deref(),
+            &std::fs::read_to_string("/proc/self/cgroup").unwrap_or_default(),
+            &std::fs::read_to_string("/proc/self/mountinfo").unwrap_or_default(),
+        )
+    }
+
+    fn from_sources(
+        dockerenv: bool,
+        containerenv: bool,
+        container_env: Option<&str>,
+        cgroup: &str,
+        mountinfo: &str,
+    ) -> Option<Self> {
+        let from_cgroup = cgroup.lines().find_map(|line| {
+            // hierarchy-ID:controllers:path
+            let path = line.splitn(3, ':').nth(2)?;
+            runtime_from_path(path)
+        });
+        // With cgroup v2 namespaces the cgroup path is just "/", but mounts still name the container
+        let from_mounts = || {
+            mountinfo.lines().find_map(|line| {
+                let root = line.split_whitespace().nth(3)?;
+                runtime_from_mount(root)
+            })
+        };
+        let (found_runtime, container_id) = match from_cgroup.or_else(from_mounts) {
+            Some((runtime, id)) => (Some(runtime), id),
+            None => (None, None),
+        };
+
+        let runtime = if containerenv || container_env == Some("podman") {
+            ContainerRuntime::Podman
+        } else if container_env == Some("lxc") {
+            ContainerRuntime::Lxc
+        } else if let Some(runtime) = found_runtime {
+            runtime
+        } else if dockerenv || container_env == Some("docker") {
+            ContainerRuntime::Docker
+        } else if container_env.is_some_and(|value| !value.is_empty()) {
+            ContainerRuntime::Other
+        } else {
+            return None;
+        };
+
+        Some(Self { runtime, container_id })
+    }
+}
+
+/// Runtime and container ID from a cgroup path
+fn runtime_from_path(path: &str) -> Option<(ContainerRuntime, Option<String>)> {
+    for segment in path.split('/').rev() {
+        let name = segment.trim_end_matches(".scope");
+        let prefixed = [
+            ("docker-", ContainerRuntime::Docker),
+            ("cri-containerd-", ContainerRuntime::Containerd),
+            ("crio-", ContainerRuntime::CriO),
+            ("libpod-", ContainerRuntime::Podman),
+        ];
+        for (prefix, runtime) in prefixed {
+            if let Some(id) = name.strip_prefix(prefix).filter(|id| is_container_id(id)) {
+                return Some((runtime, Some(id.to_string())));
+            }
+        }
+    }
+
+    let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+    let id = segments.last().filter(|id| is_container_id(id)).map(|id| id.to_string());
+    match segments.first().copied() {
+        Some("docker") if id.is_some() => Some((ContainerRuntime::Docker, id)),
+        // Kubernetes with the cgroupfs driver does not name the runtime
+        Some("kubepods") if id.is_some() => Some((ContainerRuntime::Other, id)),
+        Some("lxc") | Some("lxc.payload") => Some((ContainerRuntime::Lxc, segments.get(1).map(|name| name.to_string()))),
+        _ => None,
+    }
+}
+
+/// Runtime and container ID from the root of a mount, e.g. the bind-mounted `/etc/hostname`
+fn runtime_from_mount(root: &str) -> Option<(ContainerRuntime, Option<String>)> {
+    let markers = [
+        ("/docker/containers/", ContainerRuntime::Docker),
+        ("/containers/overlay-containers/", ContainerRuntime::Podman),
+        ("/io.containerd.runtime.v2.task/", ContainerRuntime::Containerd),
+    ];
+    for (marker, runtime) in markers {
+        if let Some((_, rest)) = root.split_once(marker) {
+            let id = rest.split('/').find(|segment| is_container_id(segment));
+            if let Some(id) = id {
+                return Some((runtime, Some(id.to_string())));
+            }
+        }
+    }
+    None
+}
+
+/// Docker, containerd, CRI-O and Podman use 64 hex digit IDs
+fn is_container_id(value: &str) -> bool {
+    value.len() == 64 && value.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ID: &str = "3f4e5d6c7b8a99887766554433221100ffeeddccbbaa00112233445566778899";
+
+    #[test]
+    fn test_detect_from_cgroup_and_mounts() {
+        let docker = format!("12:memory:/docker/{}\n0::/docker/{}\n", ID, ID);
+        let info = ContainerInfo::from_sources(true, false, None, &docker, "").unwrap();
+        assert_eq!(info.runtime, ContainerRuntime::Docker);
+        assert_eq!(info.container_id.as_deref(), Some(ID));
+
+        let systemd = format!("0::/system.slice/cri-containerd-{}.scope\n", ID);
+        let info = ContainerInfo::from_sources(false, false, None, &systemd, "").unwrap();
+        assert_eq!(info.runtime, ContainerRuntime::Containerd);
+
+        // cgroup v2 namespace hides the path; the hostname bind mount gives the container away
+        let mountinfo = format!(
+            "612 590 0:52 /var/lib/docker/containers/{}/hostname /etc/hostname rw,relatime - ext4 /dev/sda1 rw\n",
+            ID
+        );
+        let info = ContainerInfo::from_sources(true, false, None, "0::/\n", &mountinfo).unwrap();
+        assert_eq!((info.runtime, info.container_id.as_deref()), (ContainerRuntime::Docker, Some(ID)));
+
+        let info = ContainerInfo::from_sources(false, true, Some("podman"), "0::/\n", "").unwrap();
+        assert_eq!((info.runtime, info.container_id), (ContainerRuntime::Podman, None));
+
+        let info = ContainerInfo::from_sources(false, false, Some("lxc"), "0::/lxc.payload/web01\n", "").unwrap();
+        assert_eq!((info.runtime, info.container_id.as_deref()), (ContainerRuntime::Lxc, Some("web01")));
+
+        assert!(ContainerInfo::from_sources(false, false, None, "0::/user.slice/session-2.scope\n", "").is_none());
+    }
+}
@@ -0,0 +1,310 @@
+use serde::Serialize;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use crate::client::ApiClient;
+use crate::config::{Config, Overrides};
+use crate::metadata::InstanceMetadata;
+use crate::metrics::MetricService;
+use crate::oauth::Operation;
+use crate::state::ResourceState;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CheckStatus {
+    Pass,
+    Fail,
+    Skip,
+}
+
+impl fmt::Display for CheckStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CheckStatus::Pass => write!(f, "PASS"),
+            CheckStatus::Fail => write!(f, "FAIL"),
+            CheckStatus::Skip => write!(f, "SKIP"),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct CheckResult {
+    pub name: &'static str,
+    pub status: CheckStatus,
+    pub detail: String,
+}
+
+impl CheckResult {
+    fn pass(name: &'static str, detail: impl Into<String>) -> Self {
+        Self { name, status: CheckStatus::Pass, detail: detail.into() }
+    }
+
+    fn fail(name: &'static str, detail: impl Into<String>) -> Self {
+        Self { name, status: CheckStatus::Fail, detail: detail.into() }
+    }
+
+    fn skip(name: &'static str, detail: impl Into<String>) -> Self {
+        Self { name, status: CheckStatus::Skip, detail: detail.into() }
+    }
+}
+
+impl fmt::Display for CheckResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[{}] {}: {}", self.status, self.name, self.detail)
+    }
+}
+
+/// Run every diagnostic check in order; later checks are skipped if the config is unusable
+pub async fn run_checks(config_path: &Path, overrides: &Overrides) -> Vec<CheckResult> {
+    // Loaded the way `run` loads it, so the checks see the config the agent would use
+    let loaded = Config::load_from_file(config_path).and_then(|mut config| {
+        config.apply_overrides(overrides)?;
+        Ok(config)
+    });
+    let mut config = match loaded {
+        Ok(config) => config,
+        Err(e) => {
+            return vec![
+                CheckResult::fail("config", format!("{}: {}", config_path.display(), e)),
+                CheckResult::skip("remaining checks", "configuration could not be loaded"),
+            ];
+        }
+    };
+
+    let mut results = vec![CheckResult::pass("config", config_path.display().to_string())];
+    if let Err(e) = crate::secrets::resolve(&mut config).await {
+        results.push(CheckResult::fail("secrets", e.to_string()));
+        results.push(CheckResult::skip("remaining checks", "secret references could not be resolved"));
+        return results;
+    }
+    results.extend(run_config_checks(&config).await);
+    results
+}
+
+/// Checks that need a loaded config; also run remotely via the `diagnose` command
+pub async fn run_config_checks(config: &Config) -> Vec<CheckResult> {
+    let mut results = Vec::new();
+
+    let state_path = ResourceState::get_state_file_path();
+    results.push(match state_path.parent() {
+        Some(dir) => check_writable_dir("state file", dir),
+        None => CheckResult::fail("state file", format!("{} has no parent directory", state_path.display())),
+    });
+
+    if let Some(directory) = config.get_spool().and_then(|spool| spool.directory) {
+        results.push(check_writable_dir("spool directory", &directory));
+    }
+
+    results.push(check_dns(&config.api.endpoint).await);
+    results.push(check_api(config).await);
+    results.push(check_credentials(config).await);
+    results.push(check_cloud_metadata(config).await);
+    results.push(check_disk_collector(config).await);
+
+    results
+}
+
+/// Write and remove a probe file to prove the directory is usable
+fn check_writable_dir(name: &'static str, dir: &Path) -> CheckResult {
+    if let Err(e) = fs::create_dir_all(dir) {
+        return CheckResult::fail(name, format!("cannot create {}: {}", dir.display(), e));
+    }
+
+    let probe = dir.join(format!(".sentinel-diagnose-{}", std::process::id()));
+    match fs::write(&probe, b"probe") {
+        Ok(()) => {
+            let _ = fs::remove_file(&probe);
+            CheckResult::pass(name, format!("{} is writable", dir.display()))
+        }
+        Err(e) => CheckResult::fail(name, format!("{} is not writable: {}", dir.display(), e)),
+    }
+}
+
+async fn check_dns(endpoint: &str) -> CheckResult {
+    let url = match reqwest::Url::parse(endpoint) {
+        Ok(url) => url,
+        Err(e) => return CheckResult::fail("dns", format!("invalid endpoint {}: {}", endpoint, e)),
+    };
+
+    let (Some(host), Some(port)) = (url.host_str(), url.port_or_known_default()) else {
+        return CheckResult::fail("dns", format!("endpoint {} has no host", endpoint));
+    };
+
+    let result = match tokio::net::lookup_host((host, port)).await {
+        Ok(addresses) => {
+            let addresses: Vec<String> = addresses.map(|a| a.ip().to_string()).collect();
+            CheckResult::pass("dns", format!("{} resolved to {}", host, addresses.join(", ")))
+        }
+        Err(e) => CheckResult::fail("dns", format!("cannot resolve {}: {}", host, e)),
+    };
+    result
+}
+
+/// GET /health exercises TCP, TLS and HTTP in one request
+async fn check_api(config: &Config) -> CheckResult {
+    let client = match ApiClient::new(config) {
+        Ok(client) => client,
+        Err(e) => return CheckResult::fail("api connectivity", e.to_string()),
+    };
+
+    let transport = if config.api.endpoint.starts_with("https://") { "TLS" } else { "plain HTTP" };
+    match client.check_health().await {
+        Ok(()) => CheckResult::pass(
+            "api connectivity",
+            format!("GET {}/health succeeded over {}", client.endpoint(), transport),
+        ),
+        Err(e) => CheckResult::fail("api connectivity", e.to_string()),
+    }
+}
+
+/// A static API key is taken as is; OAuth credentials are exchanged for a token
+async fn check_credentials(config: &Config) -> CheckResult {
+    if let Some(settings) = &config.api.oauth {
+        let token = match ApiClient::new(config) {
+            Ok(client) => client.access_token(Operation::Metrics).await,
+            Err(e) => Err(e),
+        };
+        return match token {
+            Ok(_) => CheckResult::pass("credentials", format!("OAuth token issued by {}", settings.token_url)),
+            Err(e) => CheckResult::fail("credentials", e.to_string()),
+        };
+    }
+    match &config.api.api_key {
+        Some(_) => CheckResult::pass("credentials", "API key configured"),
+        None => CheckResult::skip("credentials", "no API key configured, registration is disabled"),
+    }
+}
+
+async fn check_cloud_metadata(config: &Config) -> CheckResult {
+    let metadata = InstanceMetadata::detect(&config.get_metadata()).await;
+    match metadata.cloud_provider {
+        Some(provider) => CheckResult::pass(
+            "cloud metadata",
+            format!(
+                "{:?} instance {}",
+                provider,
+                metadata.instance_id.as_deref().unwrap_or("unknown")
+            ),
+        ),
+        None => CheckResult::skip(
+            "cloud metadata",
+            "no metadata service reachable (on-premises or unsupported cloud)",
+        ),
+    }
+}
+
+async fn check_disk_collector(config: &Config) -> CheckResult {
+    if !config.collection.disk.enabled {
+        return CheckResult::skip("collector: disk", "disabled in configuration");
+    }
+
+    let report = MetricService::new(config).collect_all_metrics().await;
+    if let Some(failure) = report.failures.first() {
+        return CheckResult::fail("collector: disk", failure.error.to_string());
+    }
+
+    match report.metrics {
+        metrics if metrics.is_empty() => CheckResult::fail(
+            "collector: disk",
+            "no mount points matched include/exclude filters",
+        ),
+        metrics => {
+            let unreadable: Vec<&str> = metrics
+                .iter()
+                .filter(|m| fs::read_dir(&m.mount_point).is_err())
+                .map(|m| m.mount_point.as_str())
+                .collect();
+            if unreadable.is_empty() {
+                CheckResult::pass("collector: disk", format!("{} mount points readable", metrics.len()))
+            } else {
+                CheckResult::fail(
+                    "collector: disk",
+                    format!("permission denied for {}", unreadable.join(", ")),
+                )
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    fn create_test_config(endpoint: &str) -> Config {
+        Config::load_from_str(&format!(
+            r#"
+agent:
+  hostname: "test-host"
+api:
+  endpoint: "{}"
+collection:
+  interval_seconds: 60
+  disk:
+    enabled: true
+"#,
+            endpoint
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_check_writable_dir() {
+        let dir = tempdir().unwrap();
+        let result = check_writable_dir("state file", &dir.path().join("nested"));
+        assert_eq!(result.status, CheckStatus::Pass);
+        assert_eq!(fs::read_dir(dir.path().join("nested")).unwrap().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_check_dns() {
+        assert_eq!(check_dns("http://127.0.0.1:8080").await.status, CheckStatus::Pass);
+        assert_eq!(check_dns("not a url").await.status, CheckStatus::Fail);
+    }
+
+    #[tokio::test]
+    async fn test_check_api() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/health"))
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&mock_server)
+            .await;
+
+        let result = check_api(&create_test_config(&mock_server.uri())).await;
+        assert_eq!(result.status, CheckStatus::Pass);
+        assert!(result.detail.contains("plain HTTP"));
+    }
+
+    #[tokio::test]
+    async fn test_run_checks_stops_on_missing_config() {
+        let dir = tempdir().unwrap();
+        let results = run_checks(&dir.path().join("missing.yaml"), &Overrides::default()).await;
+        assert_eq!(results[0].status, CheckStatus::Fail);
+        assert_eq!(results.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_run_checks_applies_overrides() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("agent.yaml");
+        std::fs::write(
+            &path,
+            "agent: {}\napi:\n  endpoint: https://api.example.com\ncollection:\n  interval_seconds: 60\n  disk:\n    enabled: true\n",
+        )
+        .unwrap();
+        assert!(Config::load_from_file(&path).is_ok());
+
+        // An override the agent would reject fails the config check too
+        let overrides = Overrides {
+            log_level: Some("loud".to_string()),
+            ..Overrides::default()
+        };
+        let results = run_checks(&path, &overrides).await;
+        assert_eq!(results[0].status, CheckStatus::Fail);
+        assert_eq!(results.len(), 2);
+    }
+}
@@ -0,0 +1,218 @@
+//! Encrypted values in the config file
+//!
+//! Any string in agent.yaml may be written as
+//! `ENC[AES256_GCM,data:<base64>,iv:<base64>,tag:<base64>]` and is decrypted
+//! when the config is loaded, so secrets never sit on disk in plaintext. The
+//! 256-bit key comes from `SENTINEL_CONFIG_KEY` (base64) or the file named by
+//! `SENTINEL_CONFIG_KEY_FILE`; `sentinel-agent config generate-key` and
+//! `sentinel-agent config encrypt` produce keys and values.
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::rand::{SecureRandom, SystemRandom};
+use thiserror::Error;
+
+/// Environment variable holding the base64 key
+pub const KEY_ENV: &str = "SENTINEL_CONFIG_KEY";
+/// Environment variable naming a file that holds the base64 key
+pub const KEY_FILE_ENV: &str = "SENTINEL_CONFIG_KEY_FILE";
+
+const PREFIX: &str = "ENC[AES256_GCM,";
+const KEY_LEN: usize = 32;
+
+#[derive(Error, Debug)]
+pub enum EncryptionError {
+    #[error("no config key, set {KEY_ENV} or {KEY_FILE_ENV}")]
+    MissingKey,
+    #[error("invalid config key: {0}")]
+    InvalidKey(String),
+    #[error("failed to read config key file {path}: {message}")]
+    KeyFile { path: String, message: String },
+    #[error("malformed encrypted value: {0}")]
+    Malformed(String),
+    #[error("failed to decrypt a value; wrong key or tampered data")]
+    Decrypt,
+}
+
+pub struct ConfigKey {
+    key: LessSafeKey,
+}
+
+impl ConfigKey {
+    /// A new random key, base64-encoded
+    pub fn generate() -> String {
+        let mut bytes = [0u8; KEY_LEN];
+        SystemRandom::new().fill(&mut bytes).expect("system RNG available");
+        BASE64.encode(bytes)
+    }
+
+    pub fn parse(encoded: &str) -> Result<Self, EncryptionError> {
+        let bytes = BASE64
+            .decode(encoded.trim())
+            .map_err(|e| EncryptionError::InvalidKey(e.to_string()))?;
+        if bytes.len() != KEY_LEN {
+            return Err(EncryptionError::InvalidKey(format!(
+                "expected {} bytes, got {}",
+                KEY_LEN,
+                bytes.len()
+            )));
+        }
+        let key = UnboundKey::new(&AES_256_GCM, &bytes)
+            .map_err(|_| EncryptionError::InvalidKey("rejected by AES-256-GCM".to_string()))?;
+        Ok(Self { key: LessSafeKey::new(key) })
+    }
+
+    /// The key from the environment, if one is configured
+    pub fn from_env() -> Result<Option<Self>, EncryptionError> {
+        Self::from_env_vars(KEY_ENV, KEY_FILE_ENV)
+    }
+
+    /// The key in `key_env` (base64), else in the file named by `file_env`
+    pub fn from_env_vars(key_env: &str, file_env: &str) -> Result<Option<Self>, EncryptionError> {
+        if let Ok(encoded) = std::env::var(key_env) {
+            return Self::parse(&encoded).map(Some);
+        }
+        if let Ok(path) = std::env::var(file_env) {
+            let encoded = std::fs::read_to_string(&path).map_err(|e| EncryptionError::KeyFile {
+                path,
+                message: e.to_string(),
+            })?;
+            return Self::parse(&encoded).map(Some);
+        }
+        Ok(None)
+    }
+
+    pub fn encrypt(&self, plaintext: &str) -> String {
+        let mut iv = [0u8; NONCE_LEN];
+        SystemRandom::new().fill(&mut iv).expect("system RNG available");
+        let mut data = plaintext.as_bytes().to_vec();
+        let tag = self
+            .key
+            .seal_in_place_separate_tag(Nonce::assume_unique_for_key(iv), Aad::empty(), &mut data)
+            .expect("plaintext within AES-GCM limits");
+        format!(
+            "{}data:{},iv:{},tag:{}]",
+            PREFIX,
+            BASE64.encode(&data),
+            BASE64.encode(iv),
+            BASE64.encode(tag.as_ref())
+        )
+    }
+
+    pub fn decrypt(&self, value: &str) -> Result<String, EncryptionError> {
+        let fields = value
+            .strip_prefix(PREFIX)
+            .and_then(|rest| rest.strip_suffix(']'))
+            .ok_or_else(|| EncryptionError::Malformed("expected ENC[AES256_GCM,...]".to_string()))?;
+
+        let mut data = None;
+        let mut iv = None;
+        let mut tag = None;
+        for field in fields.split(',') {
+            let (name, encoded) = field
+                .split_once(':')
+                .ok_or_else(|| EncryptionError::Malformed(format!("field {}", field)))?;
+            let decoded = BASE64
+                .decode(encoded)
+                .map_err(|e| EncryptionError::Malformed(format!("{}: {}", name, e)))?;
+            match name {
+                "data" => data = Some(decoded),
+                "iv" => iv = Some(decoded),
+                "tag" => tag = Some(decoded),
+                _ => {}
+            }
+        }
+        let missing = |name: &str| EncryptionError::Malformed(format!("missing {}", name));
+        let mut sealed = data.ok_or_else(|| missing("data"))?;
+        let iv = iv.ok_or_else(|| missing("iv"))?;
+        sealed.extend(tag.ok_or_else(|| missing("tag"))?);
+
+        let nonce = Nonce::try_assume_unique_for_key(&iv)
+            .map_err(|_| EncryptionError::Malformed("iv must be 12 bytes".to_string()))?;
+        let plaintext = self
+            .key
+            .open_in_place(nonce, Aad::empty(), &mut sealed)
+            .map_err(|_| EncryptionError::Decrypt)?;
+        String::from_utf8(plaintext.to_vec()).map_err(|_| EncryptionError::Decrypt)
+    }
+}
+
+pub fn is_encrypted(value: &str) -> bool {
+    value.starts_with(PREFIX)
+}
+
+/// Decrypt every encrypted string in `root`, returning how many there were
+///
+/// `key` is only called when there is something to decrypt.
+pub fn decrypt_document(
+    root: &mut serde_yaml::Value,
+    key: impl FnOnce() -> Result<Option<ConfigKey>, EncryptionError>,
+) -> Result<usize, EncryptionError> {
+    fn collect<'a>(value: &'a mut serde_yaml::Value, found: &mut Vec<&'a mut String>) {
+        match value {
+            serde_yaml::Value::String(text) if is_encrypted(text) => found.push(text),
+            serde_yaml::Value::Sequence(items) => items.iter_mut().for_each(|item| collect(item, found)),
+            serde_yaml::Value::Mapping(mapping) => mapping.values_mut().for_each(|item| collect(item, found)),
+            serde_yaml::Value::Tagged(tagged) => collect(&mut tagged.value, found),
+            _ => {}
+        }
+    }
+
+    let mut found = Vec::new();
+    collect(root, &mut found);
+    if found.is_empty() {
+        return Ok(0);
+    }
+
+    let key = key()?.ok_or(EncryptionError::MissingKey)?;
+    for text in &mut found {
+        **text = key.decrypt(text)?;
+    }
+    Ok(found.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let key = ConfigKey::parse(&ConfigKey::generate()).unwrap();
+        let sealed = key.encrypt("s3cr3t-api-key");
+        assert!(is_encrypted(&sealed));
+        assert_eq!(key.decrypt(&sealed).unwrap(), "s3cr3t-api-key");
+
+        // Same plaintext, fresh IV
+        assert_ne!(key.encrypt("s3cr3t-api-key"), sealed);
+
+        let other = ConfigKey::parse(&ConfigKey::generate()).unwrap();
+        assert!(matches!(other.decrypt(&sealed), Err(EncryptionError::Decrypt)));
+        assert!(matches!(key.decrypt("ENC[AES256_GCM,data:AA==]"), Err(EncryptionError::Malformed(_))));
+        assert!(ConfigKey::parse("c2hvcnQ=").is_err());
+    }
+
+    #[test]
+    fn test_decrypt_document() {
+        let encoded = ConfigKey::generate();
+        let key = ConfigKey::parse(&encoded).unwrap();
+        let yaml = format!(
+            "api:\n  api_key: \"{}\"\nlabels: [\"{}\"]\nplain: value\n",
+            key.encrypt("key-1"),
+            key.encrypt("label")
+        );
+        let mut root: serde_yaml::Value = serde_yaml::from_str(&yaml).unwrap();
+
+        let count = decrypt_document(&mut root, || ConfigKey::parse(&encoded).map(Some)).unwrap();
+        assert_eq!(count, 2);
+        assert_eq!(root["api"]["api_key"], serde_yaml::Value::from("key-1"));
+        assert_eq!(root["labels"][0], serde_yaml::Value::from("label"));
+
+        let mut again: serde_yaml::Value = serde_yaml::from_str(&yaml).unwrap();
+        assert!(matches!(decrypt_document(&mut again, || Ok(None)), Err(EncryptionError::MissingKey)));
+
+        // Documents without encrypted values never need a key
+        let mut plain: serde_yaml::Value = serde_yaml::from_str("plain: value").unwrap();
+        assert_eq!(decrypt_document(&mut plain, || panic!("key not needed")).unwrap(), 0);
+    }
+}
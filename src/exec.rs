@@ -0,0 +1,149 @@
+//! Programs run as collectors, as Telegraf's `exec` input runs them
+//!
+//! Each entry of `collection.exec.commands` is its own collector, named
+//! `exec:<file name>`, so a slow or failing program is timed out and counted
+//! on its own. A program prints Telegraf JSON (see [`crate::telegraf`]) on
+//! stdout and exits 0; anything else fails that collection, as does printing
+//! more than `MAX_OUTPUT_BYTES`.
+
+use async_trait::async_trait;
+use std::process::Stdio;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::config::ExecCommand;
+use crate::metrics::{CollectContext, Collected, MetricCollector, MetricError};
+use crate::telegraf;
+
+/// Most stdout read from a program; more fails the collection and kills it
+const MAX_OUTPUT_BYTES: usize = 4 * 1024 * 1024;
+/// Start of stderr kept for the error message; the rest is read and discarded
+const MAX_STDERR_BYTES: usize = 4096;
+
+pub struct ExecCollector {
+    command: ExecCommand,
+}
+
+impl ExecCollector {
+    pub fn new(command: ExecCommand) -> Self {
+        Self { command }
+    }
+
+    /// Collector name, `exec:` and the program's file name
+    pub fn name(&self) -> String {
+        let program = self
+            .command
+            .command
+            .file_name()
+            .unwrap_or(self.command.command.as_os_str());
+        format!("exec:{}", program.to_string_lossy())
+    }
+
+    fn error(&self, message: String) -> MetricError {
+        MetricError::Exec {
+            command: self.command.command.display().to_string(),
+            message,
+        }
+    }
+}
+
+#[async_trait]
+impl MetricCollector for ExecCollector {
+    async fn collect(&self, _ctx: CollectContext) -> Result<Collected, MetricError> {
+        // Dropped with the run at the collector deadline or on an error, which kills the program
+        let mut child = tokio::process::Command::new(&self.command.command)
+            .args(self.command.args.iter().flatten())
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|e| self.error(e.to_string()))?;
+        let stdout = child.stdout.take().expect("stdout is piped");
+        let stderr = child.stderr.take().expect("stderr is piped");
+
+        let read_stdout = async {
+            let mut output = Vec::new();
+            stdout
+                .take(MAX_OUTPUT_BYTES as u64 + 1)
+                .read_to_end(&mut output)
+                .await
+                .map_err(|e| self.error(e.to_string()))?;
+            if output.len() > MAX_OUTPUT_BYTES {
+                return Err(self.error(format!("printed more than {} bytes", MAX_OUTPUT_BYTES)));
+            }
+            Ok(output)
+        };
+        let read_stderr = async { read_start(stderr, MAX_STDERR_BYTES).await.map_err(|e| self.error(e.to_string())) };
+        let (output, errors) = tokio::try_join!(read_stdout, read_stderr)?;
+        let status = child.wait().await.map_err(|e| self.error(e.to_string()))?;
+
+        if !status.success() {
+            return Err(self.error(format!(
+                "exited with {}: {}",
+                status,
+                String::from_utf8_lossy(&errors).trim()
+            )));
+        }
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let stdout = String::from_utf8_lossy(&output);
+        let samples = telegraf::parse(&stdout, timestamp).map_err(|e| self.error(e.to_string()))?;
+        Ok(samples.into())
+    }
+}
+
+/// The first `limit` bytes of `reader`, read to the end so the writer never blocks
+async fn read_start(mut reader: impl AsyncRead + Unpin, limit: usize) -> std::io::Result<Vec<u8>> {
+    let mut start = Vec::new();
+    let mut chunk = [0u8; 4096];
+    loop {
+        let read = reader.read(&mut chunk).await?;
+        if read == 0 {
+            return Ok(start);
+        }
+        let room = limit.saturating_sub(start.len());
+        start.extend_from_slice(&chunk[..read.min(room)]);
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    fn shell(script: &str) -> ExecCollector {
+        ExecCollector::new(ExecCommand {
+            command: PathBuf::from("/bin/sh"),
+            args: Some(vec!["-c".to_string(), script.to_string()]),
+        })
+    }
+
+    #[tokio::test]
+    async fn test_runs_program_and_parses_its_output() {
+        let collector = shell(r#"echo '{"name": "raid", "tags": {"array": "md0"}, "fields": {"degraded": 1}}'"#);
+        assert_eq!(collector.name(), "exec:sh");
+        let collected = collector.collect(CollectContext::new(Duration::from_secs(5))).await.unwrap();
+        assert_eq!(collected.samples.len(), 1);
+        assert_eq!(collected.samples[0].name, "raid_degraded");
+        assert_eq!(collected.samples[0].labels["array"], "md0");
+
+        let failing = shell("echo broken >&2; exit 3");
+        match failing.collect(CollectContext::new(Duration::from_secs(5))).await {
+            Err(MetricError::Exec { message, .. }) => assert!(message.contains("broken"), "{}", message),
+            other => panic!("unexpected result: {:?}", other.map(|collected| collected.len())),
+        }
+        let garbled = shell("echo not json");
+        assert!(garbled.collect(CollectContext::new(Duration::from_secs(5))).await.is_err());
+
+        // Endless output fails the collection instead of filling memory
+        let endless = shell("yes");
+        match endless.collect(CollectContext::new(Duration::from_secs(5))).await {
+            Err(MetricError::Exec { message, .. }) => assert!(message.contains("printed more than"), "{}", message),
+            other => panic!("unexpected result: {:?}", other.map(|collected| collected.len())),
+        }
+    }
+}
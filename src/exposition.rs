@@ -0,0 +1,563 @@
+//! Prometheus text and OpenMetrics exposition formats
+//!
+//! One parser for both: node_exporter textfiles and most `/metrics` endpoints
+//! use the Prometheus text format (version 0.0.4), while endpoints asked for
+//! `application/openmetrics-text` answer in OpenMetrics 1.0. Samples are
+//! written as `name{label="value",...} value [timestamp]`, with label values
+//! unescaping `\\`, `\"` and `\n`. Every sample takes the type of the family
+//! its `# TYPE` line declares, so counters stay counters in the batch:
+//! `_bucket`, `_sum` and `_count` (and in OpenMetrics `_total`, `_created`,
+//! `_gsum`, `_gcount` and `_info`) resolve to their family.
+//!
+//! OpenMetrics differs in what is checked: the exposition ends with `# EOF`,
+//! has no other comments and no blank lines, keeps each family's lines
+//! together, names counter families without `_total`, gives timestamps in
+//! seconds rather than milliseconds, and may end counter and bucket samples
+//! with an exemplar (`# {trace_id="..."} 0.5`).
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use thiserror::Error;
+
+use crate::metrics::{Exemplar, Sample, SampleKind};
+
+/// Suffixes that tie a sample to its family
+const SUFFIXES: [&str; 8] = ["_total", "_created", "_bucket", "_gcount", "_count", "_gsum", "_sum", "_info"];
+
+/// Longest exemplar label set, in characters of names and values, allowed by OpenMetrics
+const MAX_EXEMPLAR_LABEL_CHARS: usize = 128;
+
+#[derive(Error, Debug, PartialEq)]
+#[error("line {line}: {message}")]
+pub struct ParseError {
+    pub line: usize,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Prometheus,
+    OpenMetrics,
+}
+
+/// Whether samples may carry their own timestamp
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Timestamps {
+    Allow,
+    Reject,
+}
+
+impl Format {
+    /// Format of a scrape response, from its `Content-Type`
+    pub fn from_content_type(content_type: &str) -> Self {
+        if content_type
+            .trim_start()
+            .to_ascii_lowercase()
+            .starts_with("application/openmetrics-text")
+        {
+            Self::OpenMetrics
+        } else {
+            Self::Prometheus
+        }
+    }
+
+    fn kind(self, kind: &str) -> Option<SampleKind> {
+        match (self, kind) {
+            (_, "counter") => Some(SampleKind::Counter),
+            (_, "gauge") => Some(SampleKind::Gauge),
+            (_, "histogram") => Some(SampleKind::Histogram),
+            (_, "summary") => Some(SampleKind::Summary),
+            (Self::Prometheus, "untyped") => Some(SampleKind::Untyped),
+            (Self::OpenMetrics, "unknown") => Some(SampleKind::Untyped),
+            (Self::OpenMetrics, "gaugehistogram") => Some(SampleKind::GaugeHistogram),
+            (Self::OpenMetrics, "info") => Some(SampleKind::Info),
+            (Self::OpenMetrics, "stateset") => Some(SampleKind::StateSet),
+            _ => None,
+        }
+    }
+
+    /// Suffixes the samples of a family may have, `""` for the family name itself
+    fn suffixes(self, kind: SampleKind) -> &'static [&'static str] {
+        match (self, kind) {
+            (Self::Prometheus, SampleKind::Histogram) => &["_bucket", "_sum", "_count"],
+            (Self::Prometheus, SampleKind::Summary) => &["", "_sum", "_count"],
+            (Self::Prometheus, _) => &[""],
+            (Self::OpenMetrics, SampleKind::Counter) => &["_total", "_created"],
+            (Self::OpenMetrics, SampleKind::Histogram) => &["_bucket", "_sum", "_count", "_created"],
+            (Self::OpenMetrics, SampleKind::GaugeHistogram) => &["_bucket", "_gsum", "_gcount"],
+            (Self::OpenMetrics, SampleKind::Summary) => &["", "_sum", "_count", "_created"],
+            (Self::OpenMetrics, SampleKind::Info) => &["_info"],
+            (Self::OpenMetrics, _) => &[""],
+        }
+    }
+}
+
+/// Parse an exposition; samples without a timestamp get `timestamp` (Unix seconds)
+pub fn parse(text: &str, format: Format, timestamp: u64, timestamps: Timestamps) -> Result<Vec<Sample>, ParseError> {
+    let mut families = Families::default();
+    let mut samples = Vec::new();
+    let mut lines = 0;
+
+    for (index, line) in text.lines().enumerate() {
+        lines = index + 1;
+        let error = |message: String| ParseError { line: index + 1, message };
+        if families.eof {
+            return Err(error("content after # EOF".to_string()));
+        }
+        let line = line.trim();
+        if line.is_empty() {
+            if format == Format::OpenMetrics {
+                return Err(error("empty line".to_string()));
+            }
+            continue;
+        }
+
+        if let Some(comment) = line.strip_prefix('#') {
+            families.descriptor(comment, format).map_err(error)?;
+            continue;
+        }
+
+        let parsed = parse_sample(line).map_err(error)?;
+        let (family, kind, suffix) = families.resolve(parsed.name, format);
+        families.enter(family, format).map_err(error)?;
+        families.started.insert(family);
+
+        let sample_timestamp = match parsed.timestamp {
+            Some(_) if timestamps == Timestamps::Reject => {
+                return Err(error(format!(
+                    "sample {} has a timestamp, which is not supported here",
+                    parsed.name
+                )));
+            }
+            Some(raw) => parse_timestamp(raw, format).map_err(error)?,
+            None => timestamp,
+        };
+        let exemplar = match parsed.exemplar {
+            None => None,
+            Some(_) if format == Format::Prometheus => {
+                return Err(error(format!("unexpected '#' after sample {}", parsed.name)));
+            }
+            Some(_) if !takes_exemplar(kind, suffix) => {
+                return Err(error(format!("sample {} cannot have an exemplar", parsed.name)));
+            }
+            Some(exemplar) => Some(parse_exemplar(exemplar).map_err(error)?),
+        };
+
+        samples.push(Sample {
+            name: parsed.name.to_string(),
+            labels: parsed.labels,
+            value: parsed.value,
+            timestamp: sample_timestamp,
+            kind,
+            exemplar,
+        });
+    }
+
+    if format == Format::OpenMetrics && !families.eof {
+        return Err(ParseError {
+            line: lines + 1,
+            message: "missing # EOF".to_string(),
+        });
+    }
+    Ok(samples)
+}
+
+/// What the descriptors so far say about the families
+#[derive(Default)]
+struct Families<'a> {
+    types: HashMap<&'a str, SampleKind>,
+    helps: HashSet<&'a str>,
+    units: HashSet<&'a str>,
+    /// Families with samples so far; their descriptors must come first
+    started: HashSet<&'a str>,
+    /// Family of the latest line, and the families left behind, for OpenMetrics' contiguity rule
+    current: Option<&'a str>,
+    finished: HashSet<&'a str>,
+    eof: bool,
+}
+
+impl<'a> Families<'a> {
+    /// `# HELP`, `# TYPE`, `# UNIT` or `# EOF`; other comments only in the Prometheus format
+    fn descriptor(&mut self, comment: &'a str, format: Format) -> Result<(), String> {
+        let mut words = comment.split_whitespace();
+        let (keyword, name) = match (words.next(), words.next()) {
+            (Some("EOF"), None) if format == Format::OpenMetrics => {
+                self.eof = true;
+                return Ok(());
+            }
+            (Some(keyword @ ("HELP" | "TYPE")), Some(name)) => (keyword, name),
+            (Some("UNIT"), Some(name)) if format == Format::OpenMetrics => ("UNIT", name),
+            _ if format == Format::Prometheus => return Ok(()),
+            _ => return Err(format!("unexpected comment {:?}", comment.trim())),
+        };
+        check_metric_name(name)?;
+        self.enter(name, format)?;
+
+        match keyword {
+            "TYPE" => {
+                let kind = match (words.next(), words.next()) {
+                    (Some(kind), None) => format
+                        .kind(kind)
+                        .ok_or_else(|| format!("unknown metric type {:?}", kind))?,
+                    _ => return Err(format!("TYPE line for {} needs exactly one type", name)),
+                };
+                if self.started.contains(name) {
+                    return Err(format!("TYPE line for {} after its samples", name));
+                }
+                if self.types.insert(name, kind).is_some() {
+                    return Err(format!("second TYPE line for {}", name));
+                }
+            }
+            "UNIT" => {
+                let unit = words.next().unwrap_or_default();
+                if !unit.is_empty() && !name.ends_with(&format!("_{}", unit)) {
+                    return Err(format!("unit {} is not a suffix of {}", unit, name));
+                }
+                if !self.units.insert(name) {
+                    return Err(format!("second UNIT line for {}", name));
+                }
+            }
+            _ => {
+                if !self.helps.insert(name) {
+                    return Err(format!("second HELP line for {}", name));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Family a sample belongs to, its type and the suffix that tied it to the family
+    fn resolve(&self, name: &'a str, format: Format) -> (&'a str, SampleKind, &'static str) {
+        if let Some(kind) = self.types.get(name) {
+            if format.suffixes(*kind).contains(&"") {
+                return (name, *kind, "");
+            }
+        }
+        for suffix in SUFFIXES {
+            let Some(base) = name.strip_suffix(suffix) else {
+                continue;
+            };
+            if let Some(kind) = self.types.get(base) {
+                if format.suffixes(*kind).contains(&suffix) {
+                    return (base, *kind, suffix);
+                }
+            }
+        }
+        (name, SampleKind::Untyped, "")
+    }
+
+    /// Move on to `family`; in OpenMetrics a family cannot come back once another started
+    fn enter(&mut self, family: &'a str, format: Format) -> Result<(), String> {
+        if format != Format::OpenMetrics || self.current == Some(family) {
+            return Ok(());
+        }
+        if self.finished.contains(family) {
+            return Err(format!("lines of metric family {} are not together", family));
+        }
+        if let Some(previous) = self.current.replace(family) {
+            self.finished.insert(previous);
+        }
+        Ok(())
+    }
+}
+
+/// Counters and histogram buckets may carry an exemplar
+fn takes_exemplar(kind: SampleKind, suffix: &str) -> bool {
+    matches!(
+        (kind, suffix),
+        (SampleKind::Counter, "_total") | (SampleKind::Histogram | SampleKind::GaugeHistogram, "_bucket")
+    )
+}
+
+/// Unix seconds from milliseconds (Prometheus) or fractional seconds (OpenMetrics)
+fn parse_timestamp(raw: &str, format: Format) -> Result<u64, String> {
+    let invalid = || format!("invalid timestamp {:?}", raw);
+    let seconds = match format {
+        Format::Prometheus => raw.parse::<i64>().map_err(|_| invalid())?.div_euclid(1000) as f64,
+        Format::OpenMetrics => raw.parse::<f64>().map_err(|_| invalid())?.floor(),
+    };
+    // Before 1970 cannot be represented in a batch
+    if !seconds.is_finite() || seconds < 0.0 {
+        return Err(format!("timestamp {} is before 1970", raw));
+    }
+    Ok(seconds as u64)
+}
+
+struct ParsedSample<'a> {
+    name: &'a str,
+    labels: BTreeMap<String, String>,
+    value: f64,
+    timestamp: Option<&'a str>,
+    /// Text after `#`, for formats with exemplars
+    exemplar: Option<&'a str>,
+}
+
+/// `name{labels} value [timestamp] [# exemplar]`
+fn parse_sample(line: &str) -> Result<ParsedSample<'_>, String> {
+    let name_end = line
+        .find(|c: char| !is_metric_name_char(c))
+        .unwrap_or(line.len());
+    let name = &line[..name_end];
+    check_metric_name(name)?;
+
+    let rest = line[name_end..].trim_start();
+    let (labels, rest) = match rest.strip_prefix('{') {
+        Some(rest) => parse_labels(rest)?,
+        None => (BTreeMap::new(), rest),
+    };
+    // Label values, the only place '#' could be quoted, are behind us
+    let (rest, exemplar) = match rest.split_once('#') {
+        Some((rest, exemplar)) => (rest, Some(exemplar)),
+        None => (rest, None),
+    };
+
+    let mut fields = rest.split_whitespace();
+    let value = fields.next().ok_or_else(|| format!("sample {} has no value", name))?;
+    let value = value
+        .parse::<f64>()
+        .map_err(|_| format!("sample {} has invalid value {:?}", name, value))?;
+    let timestamp = fields.next();
+    if let Some(extra) = fields.next() {
+        return Err(format!("unexpected {:?} after sample {}", extra, name));
+    }
+    Ok(ParsedSample {
+        name,
+        labels,
+        value,
+        timestamp,
+        exemplar,
+    })
+}
+
+/// ` {labels} value [timestamp]` after the `#` of an OpenMetrics sample
+fn parse_exemplar(text: &str) -> Result<Exemplar, String> {
+    let rest = text
+        .trim_start()
+        .strip_prefix('{')
+        .ok_or_else(|| "exemplar must start with a label set".to_string())?;
+    let (labels, rest) = parse_labels(rest)?;
+    let label_chars: usize = labels
+        .iter()
+        .map(|(name, value)| name.chars().count() + value.chars().count())
+        .sum();
+    if label_chars > MAX_EXEMPLAR_LABEL_CHARS {
+        return Err(format!(
+            "exemplar labels are {} characters, more than {}",
+            label_chars, MAX_EXEMPLAR_LABEL_CHARS
+        ));
+    }
+
+    let mut fields = rest.split_whitespace();
+    let value = fields.next().ok_or_else(|| "exemplar has no value".to_string())?;
+    let value = value
+        .parse::<f64>()
+        .map_err(|_| format!("exemplar has invalid value {:?}", value))?;
+    let timestamp = fields
+        .next()
+        .map(|timestamp| {
+            timestamp
+                .parse::<f64>()
+                .map_err(|_| format!("exemplar has invalid timestamp {:?}", timestamp))
+        })
+        .transpose()?;
+    if let Some(extra) = fields.next() {
+        return Err(format!("unexpected {:?} after exemplar", extra));
+    }
+    Ok(Exemplar {
+        labels,
+        value,
+        timestamp,
+    })
+}
+
+/// Labels up to the closing brace, and the rest of the line
+fn parse_labels(input: &str) -> Result<(BTreeMap<String, String>, &str), String> {
+    let mut labels = BTreeMap::new();
+    let mut rest = input.trim_start();
+    loop {
+        if let Some(after) = rest.strip_prefix('}') {
+            return Ok((labels, after));
+        }
+
+        let name_end = rest
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+            .unwrap_or(rest.len());
+        let name = &rest[..name_end];
+        if name.is_empty() || name.starts_with(|c: char| c.is_ascii_digit()) {
+            return Err(format!("invalid label name at {:?}", rest));
+        }
+        rest = rest[name_end..].trim_start();
+        rest = rest
+            .strip_prefix('=')
+            .ok_or_else(|| format!("expected '=' after label {}", name))?
+            .trim_start();
+        rest = rest
+            .strip_prefix('"')
+            .ok_or_else(|| format!("value of label {} must be quoted", name))?;
+
+        let mut value = String::new();
+        let mut chars = rest.char_indices();
+        let end = loop {
+            match chars.next() {
+                Some((index, '"')) => break index,
+                Some((_, '\\')) => match chars.next() {
+                    Some((_, '\\')) => value.push('\\'),
+                    Some((_, '"')) => value.push('"'),
+                    Some((_, 'n')) => value.push('\n'),
+                    other => {
+                        return Err(format!(
+                            "invalid escape {:?} in label {}",
+                            other.map(|(_, c)| c).unwrap_or(' '),
+                            name
+                        ))
+                    }
+                },
+                Some((_, c)) => value.push(c),
+                None => return Err(format!("unterminated value of label {}", name)),
+            }
+        };
+        if labels.insert(name.to_string(), value).is_some() {
+            return Err(format!("duplicate label {}", name));
+        }
+
+        rest = rest[end + 1..].trim_start();
+        if let Some(after) = rest.strip_prefix(',') {
+            rest = after.trim_start();
+        } else if !rest.starts_with('}') {
+            return Err(format!("expected ',' or '}}' after label {}", name));
+        }
+    }
+}
+
+fn is_metric_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == ':'
+}
+
+fn check_metric_name(name: &str) -> Result<(), String> {
+    if name.is_empty() || name.starts_with(|c: char| c.is_ascii_digit()) || !name.chars().all(is_metric_name_char) {
+        return Err(format!("invalid metric name {:?}", name));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parses_families_labels_and_values() {
+        let text = r#"
+# HELP backup_last_success_seconds Last successful backup.
+# TYPE backup_last_success_seconds gauge
+backup_last_success_seconds{job="db",path="C:\\data \"main\""} 1.7e9
+# TYPE request_duration_seconds histogram
+request_duration_seconds_bucket{le="0.5"} 3
+request_duration_seconds_bucket{le="+Inf",} 4
+request_duration_seconds_sum 1.25
+request_duration_seconds_count 4
+# A plain comment
+apt_upgrades_pending NaN
+up{  } -Inf
+"#;
+        let samples = parse(text, Format::Prometheus, 100, Timestamps::Reject).unwrap();
+        assert_eq!(samples.len(), 7);
+
+        assert_eq!(samples[0].name, "backup_last_success_seconds");
+        assert_eq!(samples[0].labels["path"], r#"C:\data "main""#);
+        assert_eq!(samples[0].value, 1.7e9);
+        assert_eq!(samples[0].timestamp, 100);
+        assert_eq!(samples[0].kind, SampleKind::Gauge);
+
+        assert_eq!(samples[2].labels["le"], "+Inf");
+        assert!(samples[1..5].iter().all(|sample| sample.kind == SampleKind::Histogram));
+        assert!(samples[5].value.is_nan());
+        assert_eq!(samples[5].kind, SampleKind::Untyped);
+        assert_eq!(samples[6].value, f64::NEG_INFINITY);
+    }
+
+    #[test]
+    fn test_timestamps_and_errors() {
+        let samples = parse("jobs_total 5 1700000000500", Format::Prometheus, 100, Timestamps::Allow).unwrap();
+        assert_eq!(samples[0].timestamp, 1_700_000_000);
+
+        let error = parse("# ok\njobs_total 5 1700000000500", Format::Prometheus, 100, Timestamps::Reject).unwrap_err();
+        assert_eq!(error.line, 2);
+
+        for bad in [
+            "jobs_total",
+            "jobs_total five",
+            "jobs-total 1",
+            "jobs_total{path=\"/a} 1",
+            "jobs_total{path=\"\\t\"} 1",
+            "jobs_total{a=\"1\",a=\"2\"} 1",
+            "jobs_total 1 2 3",
+            "jobs_total 1 # {trace_id=\"a\"} 1",
+            "# TYPE jobs_total meter",
+            "# TYPE jobs_total gaugehistogram",
+            "jobs_total 1\n# TYPE jobs_total counter",
+            "# TYPE jobs_total counter\n# TYPE jobs_total gauge",
+        ] {
+            assert!(parse(bad, Format::Prometheus, 100, Timestamps::Allow).is_err(), "{:?} should not parse", bad);
+        }
+    }
+
+    #[test]
+    fn test_openmetrics_types_exemplars_and_eof() {
+        let text = r#"# TYPE http_requests counter
+# HELP http_requests Requests served.
+http_requests_total{code="200"} 1027 1700000000.250 # {trace_id="4bf92f3577b34da6"} 1 1700000000.1
+http_requests_created{code="200"} 1699990000
+# TYPE request_seconds histogram
+# UNIT request_seconds seconds
+request_seconds_bucket{le="0.1"} 8 # {trace_id="a"} 0.05
+request_seconds_bucket{le="+Inf"} 10
+request_seconds_count 10
+request_seconds_sum 1.5
+# TYPE build info
+build_info{version="1.2.0"} 1
+# TYPE queue_size gaugehistogram
+queue_size_bucket{le="10"} 4
+queue_size_gcount 4
+queue_size_gsum 12
+# TYPE temperature unknown
+temperature 21.5
+# EOF
+"#;
+        let samples = parse(text, Format::OpenMetrics, 100, Timestamps::Allow).unwrap();
+        let kinds: Vec<SampleKind> = samples.iter().map(|sample| sample.kind).collect();
+        use SampleKind::*;
+        assert_eq!(
+            kinds,
+            [Counter, Counter, Histogram, Histogram, Histogram, Histogram, Info, GaugeHistogram, GaugeHistogram, GaugeHistogram, Untyped]
+        );
+
+        // OpenMetrics timestamps are seconds
+        assert_eq!(samples[0].timestamp, 1_700_000_000);
+        let exemplar = samples[0].exemplar.as_ref().unwrap();
+        assert_eq!(exemplar.labels["trace_id"], "4bf92f3577b34da6");
+        assert_eq!(exemplar.value, 1.0);
+        assert_eq!(exemplar.timestamp, Some(1700000000.1));
+        assert_eq!(samples[2].exemplar.as_ref().unwrap().value, 0.05);
+        assert!(samples[3].exemplar.is_none());
+        assert_eq!(samples[1].timestamp, 100);
+
+        let error = parse("# TYPE a counter\na_total 1\n", Format::OpenMetrics, 100, Timestamps::Allow).unwrap_err();
+        assert_eq!(error, ParseError { line: 3, message: "missing # EOF".to_string() });
+
+        for bad in [
+            "a_total 1\n# EOF\nb 1\n",
+            "a 1\n\nb 1\n# EOF\n",
+            "# a comment\n# EOF\n",
+            "# TYPE a gauge\na 1 # {trace_id=\"x\"} 1\n# EOF\n",
+            "# TYPE a counter\na_total 1 # trace 1\n# EOF\n",
+            "# TYPE a counter\na_total 1\nb 1\na_created 5\n# EOF\n",
+            "# TYPE a_seconds gauge\n# UNIT a_seconds bytes\n# EOF\n",
+            "# TYPE a untyped\n# EOF\n",
+            &format!("# TYPE a counter\na_total 1 # {{id=\"{}\"}} 1\n# EOF\n", "x".repeat(130)),
+        ] {
+            assert!(parse(bad, Format::OpenMetrics, 100, Timestamps::Allow).is_err(), "{:?} should not parse", bad);
+        }
+
+        assert_eq!(Format::from_content_type("application/openmetrics-text; version=1.0.0"), Format::OpenMetrics);
+        assert_eq!(Format::from_content_type("text/plain; version=0.0.4"), Format::Prometheus);
+    }
+}
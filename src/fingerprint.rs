@@ -0,0 +1,197 @@
+//! Stable identity of the machine the agent runs on
+//!
+//! Saved with the resource state and sent with registration, so a state file
+//! copied along with a golden image or cloned VM is recognised as belonging to
+//! another machine. Identifiers are hashed before they are stored or sent.
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// Hashed machine identifiers; any of them may be unavailable on a given host
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MachineFingerprint {
+    /// systemd/D-Bus machine ID, or the Windows MachineGuid
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub machine_id: Option<String>,
+    /// SMBIOS system UUID (the hypervisor's VM UUID on virtual machines)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dmi_uuid: Option<String>,
+    /// MAC address of the first physical network interface
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mac: Option<String>,
+}
+
+impl MachineFingerprint {
+    /// Fingerprint of this machine, `None` if no identifier could be read
+    ///
+    /// Reading the DMI UUID needs root on Linux, so call this before dropping privileges.
+    pub fn detect() -> Option<Self> {
+        Self::from_raw(read_machine_id(), read_dmi_uuid(), read_mac())
+    }
+
+    fn from_raw(machine_id: Option<String>, dmi_uuid: Option<String>, mac: Option<String>) -> Option<Self> {
+        let fingerprint = Self {
+            machine_id: machine_id.map(|value| hash("machine-id", &value)),
+            dmi_uuid: dmi_uuid.map(|value| hash("dmi-uuid", &value.to_lowercase())),
+            mac: mac.map(|value| hash("mac", &value.to_lowercase())),
+        };
+        (fingerprint.machine_id.is_some() || fingerprint.dmi_uuid.is_some() || fingerprint.mac.is_some())
+            .then_some(fingerprint)
+    }
+
+    /// Whether `other` was taken on the same machine
+    ///
+    /// Compares the most stable identifier both have. A clone gets a new DMI
+    /// UUID and MAC from the hypervisor even when the image kept its
+    /// machine ID, while replacing a NIC leaves the DMI UUID alone. Without a
+    /// common identifier there is nothing to contradict, so they match.
+    pub fn same_machine(&self, other: &Self) -> bool {
+        [
+            (&self.dmi_uuid, &other.dmi_uuid),
+            (&self.machine_id, &other.machine_id),
+            (&self.mac, &other.mac),
+        ]
+        .into_iter()
+        .find_map(|(ours, theirs)| Some(ours.as_ref()? == theirs.as_ref()?))
+        .unwrap_or(true)
+    }
+
+    /// Single identifier sent with registration
+    pub fn id(&self) -> String {
+        let parts = [&self.dmi_uuid, &self.machine_id, &self.mac];
+        let joined: Vec<&str> = parts.iter().map(|part| part.as_deref().unwrap_or("")).collect();
+        hash("fingerprint", &joined.join(":"))
+    }
+}
+
+/// Hash with a per-purpose prefix, so the raw machine ID cannot be matched against other tools' uses of it
+fn hash(kind: &str, value: &str) -> String {
+    hex::encode(Sha256::digest(format!("operion-sentinel:{}:{}", kind, value.trim())))
+}
+
+fn read_trimmed(path: &str) -> Option<String> {
+    std::fs::read_to_string(path)
+        .ok()
+        .map(|value| value.trim().to_string())
+        .filter(|value| !value.is_empty())
+}
+
+#[cfg(target_os = "linux")]
+fn read_machine_id() -> Option<String> {
+    read_trimmed("/etc/machine-id").or_else(|| read_trimmed("/var/lib/dbus/machine-id"))
+}
+
+#[cfg(target_os = "linux")]
+fn read_dmi_uuid() -> Option<String> {
+    read_trimmed("/sys/class/dmi/id/product_uuid")
+}
+
+/// First interface, by name, backed by a device (skipping loopback, bridges and veths)
+#[cfg(target_os = "linux")]
+fn read_mac() -> Option<String> {
+    let mut interfaces: Vec<_> = std::fs::read_dir("/sys/class/net")
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().join("device").exists())
+        .map(|entry| entry.path())
+        .collect();
+    interfaces.sort();
+    interfaces.iter().find_map(|interface| {
+        read_trimmed(&interface.join("address").to_string_lossy())
+            .filter(|mac| mac != "00:00:00:00:00:00")
+    })
+}
+
+#[cfg(target_os = "macos")]
+fn read_machine_id() -> Option<String> {
+    None
+}
+
+/// IOPlatformUUID, the hardware UUID shown in System Information
+#[cfg(target_os = "macos")]
+fn read_dmi_uuid() -> Option<String> {
+    let output = std::process::Command::new("ioreg")
+        .args(["-rd1", "-c", "IOPlatformExpertDevice"])
+        .output()
+        .ok()?;
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .find(|line| line.contains("\"IOPlatformUUID\""))
+        .and_then(|line| line.split('"').nth(3))
+        .map(str::to_string)
+}
+
+#[cfg(target_os = "macos")]
+fn read_mac() -> Option<String> {
+    None
+}
+
+#[cfg(windows)]
+fn read_machine_id() -> Option<String> {
+    let output = std::process::Command::new("reg")
+        .args(["query", r"HKLM\SOFTWARE\Microsoft\Cryptography", "/v", "MachineGuid"])
+        .output()
+        .ok()?;
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .find(|line| line.trim_start().starts_with("MachineGuid"))
+        .and_then(|line| line.split_whitespace().last())
+        .map(str::to_string)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+fn read_machine_id() -> Option<String> {
+    None
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn read_dmi_uuid() -> Option<String> {
+    None
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn read_mac() -> Option<String> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fingerprint(machine_id: &str, dmi_uuid: Option<&str>, mac: &str) -> MachineFingerprint {
+        MachineFingerprint::from_raw(
+            Some(machine_id.to_string()),
+            dmi_uuid.map(str::to_string),
+            Some(mac.to_string()),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_clone_detected() {
+        let original = fingerprint("abc123", Some("4C4C4544-0001"), "52:54:00:aa:bb:cc");
+
+        // Golden image: same machine-id, new VM UUID and MAC
+        let clone = fingerprint("abc123", Some("4c4c4544-0002"), "52:54:00:dd:ee:ff");
+        assert!(!original.same_machine(&clone));
+        assert_ne!(original.id(), clone.id());
+
+        // New NIC on the same machine
+        let new_nic = fingerprint("abc123", Some("4c4c4544-0001"), "52:54:00:11:22:33");
+        assert!(original.same_machine(&new_nic));
+
+        // DMI unreadable without root: fall back to the machine-id
+        let unprivileged = fingerprint("abc123", None, "52:54:00:aa:bb:cc");
+        assert!(original.same_machine(&unprivileged));
+        assert!(!fingerprint("other", None, "52:54:00:aa:bb:cc").same_machine(&original));
+    }
+
+    #[test]
+    fn test_raw_identifiers_not_stored() {
+        let fingerprint = fingerprint("abc123", Some("4C4C4544-0001"), "52:54:00:aa:bb:cc");
+        let json = serde_json::to_string(&fingerprint).unwrap();
+        assert!(!json.contains("abc123"));
+        assert!(!json.to_lowercase().contains("4c4c4544"));
+        assert_eq!(MachineFingerprint::from_raw(None, None, None), None);
+    }
+}
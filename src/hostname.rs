@@ -0,0 +1,119 @@
+//! Host name reported to the platform, per `agent.hostname_mode`
+
+use std::sync::OnceLock;
+
+use crate::config::HostnameMode;
+
+/// The kernel host name, as `hostname` prints it
+pub fn short() -> String {
+    gethostname::gethostname().to_string_lossy().to_string()
+}
+
+/// Fully qualified name of this host, falling back to the kernel host name
+///
+/// Resolved once per process, since it may need a DNS lookup.
+pub fn fqdn() -> String {
+    static FQDN: OnceLock<String> = OnceLock::new();
+    FQDN.get_or_init(|| {
+        let short = short();
+        pick_fqdn(&short, canonical_name(&short)).unwrap_or_else(|| {
+            tracing::warn!(hostname = %short, "Could not resolve the FQDN, using the kernel host name");
+            short
+        })
+    })
+    .clone()
+}
+
+pub fn resolve(mode: HostnameMode, custom: Option<&str>) -> String {
+    match (mode, custom) {
+        (HostnameMode::Custom, Some(name)) => name.to_string(),
+        (HostnameMode::Fqdn, _) => fqdn(),
+        _ => short(),
+    }
+}
+
+/// A kernel host name with a domain already is the FQDN; otherwise use the
+/// resolver's canonical name when it is qualified
+fn pick_fqdn(short: &str, canonical: Option<String>) -> Option<String> {
+    if short.contains('.') {
+        return Some(short.to_string());
+    }
+    canonical
+        .map(|name| name.trim_end_matches('.').to_string())
+        .filter(|name| name.contains('.') && !name.starts_with("localhost"))
+}
+
+/// Canonical name of `host` from the system resolver (hosts file, DNS)
+#[cfg(unix)]
+fn canonical_name(host: &str) -> Option<String> {
+    use std::ffi::{CStr, CString};
+
+    let host = CString::new(host).ok()?;
+    // SAFETY: addrinfo is plain data; all-zero is the documented empty hints value
+    let mut hints: libc::addrinfo = unsafe { std::mem::zeroed() };
+    hints.ai_flags = libc::AI_CANONNAME;
+    hints.ai_family = libc::AF_UNSPEC;
+    hints.ai_socktype = libc::SOCK_STREAM;
+
+    let mut result: *mut libc::addrinfo = std::ptr::null_mut();
+    // SAFETY: `host` is NUL-terminated and `result` is freed below on success
+    if unsafe { libc::getaddrinfo(host.as_ptr(), std::ptr::null(), &hints, &mut result) } != 0 {
+        return None;
+    }
+    // SAFETY: on success `result` is a valid list whose first entry holds the canonical name
+    let name = unsafe {
+        let canonical = (*result).ai_canonname;
+        let name = (!canonical.is_null()).then(|| CStr::from_ptr(canonical).to_string_lossy().into_owned());
+        libc::freeaddrinfo(result);
+        name
+    };
+    name
+}
+
+#[cfg(windows)]
+fn canonical_name(_host: &str) -> Option<String> {
+    use windows_sys::Win32::System::SystemInformation::{ComputerNameDnsFullyQualified, GetComputerNameExW};
+
+    let mut size: u32 = 0;
+    // SAFETY: a null buffer asks for the required size, including the NUL
+    unsafe { GetComputerNameExW(ComputerNameDnsFullyQualified, std::ptr::null_mut(), &mut size) };
+    if size == 0 {
+        return None;
+    }
+    let mut buffer = vec![0u16; size as usize];
+    // SAFETY: `buffer` holds `size` UTF-16 units
+    if unsafe { GetComputerNameExW(ComputerNameDnsFullyQualified, buffer.as_mut_ptr(), &mut size) } == 0 {
+        return None;
+    }
+    Some(String::from_utf16_lossy(&buffer[..size as usize]))
+}
+
+#[cfg(not(any(unix, windows)))]
+fn canonical_name(_host: &str) -> Option<String> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pick_fqdn() {
+        assert_eq!(pick_fqdn("web01.dc1.example.com", None).as_deref(), Some("web01.dc1.example.com"));
+        assert_eq!(
+            pick_fqdn("web01", Some("web01.dc1.example.com.".to_string())).as_deref(),
+            Some("web01.dc1.example.com")
+        );
+        // An unqualified answer or a loopback alias is not an FQDN
+        assert_eq!(pick_fqdn("web01", Some("web01".to_string())), None);
+        assert_eq!(pick_fqdn("web01", Some("localhost.localdomain".to_string())), None);
+        assert_eq!(pick_fqdn("web01", None), None);
+    }
+
+    #[test]
+    fn test_resolve_modes() {
+        assert_eq!(resolve(HostnameMode::Custom, Some("db-7")), "db-7");
+        assert_eq!(resolve(HostnameMode::Short, None), short());
+        assert!(resolve(HostnameMode::Fqdn, None).starts_with(short().split('.').next().unwrap()));
+    }
+}
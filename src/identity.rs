@@ -0,0 +1,291 @@
+//! Per-agent Ed25519 identity
+//!
+//! Generated on first run and kept in `agent.key` next to the state file
+//! (owner read/write only). The public key is sent at registration and every
+//! API request is signed, so the platform can tell the registered agent from
+//! anyone who merely holds the shared API key.
+//!
+//! With a state key configured (see [`crate::state`]) the key file is
+//! encrypted like the state file; a plain one is encrypted when next read.
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use ring::rand::SystemRandom;
+use ring::signature::{Ed25519KeyPair, KeyPair};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+use crate::encryption::{self, ConfigKey};
+
+pub const KEY_FILE_NAME: &str = "agent.key";
+
+/// Base64 public key of the signing agent
+pub const KEY_HEADER: &str = "X-Operion-Agent-Key";
+/// Unix seconds when the request was signed, so captured requests cannot be replayed later
+pub const TIMESTAMP_HEADER: &str = "X-Operion-Agent-Timestamp";
+/// Base64 Ed25519 signature over `signing_payload`
+pub const SIGNATURE_HEADER: &str = "X-Operion-Agent-Signature";
+
+#[derive(Error, Debug)]
+pub enum IdentityError {
+    #[error("failed to read agent key {path}: {message}")]
+    Read { path: String, message: String },
+    #[error("failed to write agent key {path}: {message}")]
+    Write { path: String, message: String },
+    #[error("agent key {path} is not a valid Ed25519 key; remove it to generate a new identity")]
+    Invalid { path: String },
+    #[error("failed to decrypt agent key {path}: {message}")]
+    Encryption { path: String, message: String },
+}
+
+#[derive(Debug)]
+pub struct AgentIdentity {
+    key_pair: Ed25519KeyPair,
+}
+
+impl AgentIdentity {
+    /// The key in `dir`, generating and saving one on first run
+    ///
+    /// With `key`, the file is saved encrypted and a plain one is rewritten encrypted.
+    pub fn load_or_generate(dir: &Path, key: Option<&ConfigKey>) -> Result<Self, IdentityError> {
+        let path = dir.join(KEY_FILE_NAME);
+        match read_key_file(&path, key)? {
+            Some((encoded, was_encrypted)) => {
+                let identity = Self::parse(&encoded).ok_or_else(|| IdentityError::Invalid {
+                    path: path.display().to_string(),
+                })?;
+                if let (Some(key), false) = (key, was_encrypted) {
+                    write_private(&path, &key.encrypt(encoded.trim()))?;
+                    tracing::info!(path = %path.display(), "Encrypted agent identity key");
+                }
+                Ok(identity)
+            }
+            None => {
+                let identity = Self::generate(&path, key)?;
+                tracing::info!(path = %path.display(), "Generated agent identity key");
+                Ok(identity)
+            }
+        }
+    }
+
+    /// A new key in `dir`, replacing the one there
+    ///
+    /// For keys copied from another machine with a cloned disk, which the
+    /// copy must not keep signing with.
+    pub fn regenerate(dir: &Path, key: Option<&ConfigKey>) -> Result<Self, IdentityError> {
+        let path = dir.join(KEY_FILE_NAME);
+        let identity = Self::generate(&path, key)?;
+        tracing::warn!(path = %path.display(), "Replaced agent identity key copied from another machine");
+        Ok(identity)
+    }
+
+    /// Identity kept in the state directory
+    pub fn load_or_generate_in_state_dir() -> Result<Self, IdentityError> {
+        Self::load_or_generate(&state_dir(), state_key()?.as_ref())
+    }
+
+    /// New identity in the state directory, replacing the current one
+    pub fn regenerate_in_state_dir() -> Result<Self, IdentityError> {
+        Self::regenerate(&state_dir(), state_key()?.as_ref())
+    }
+
+    fn generate(path: &Path, key: Option<&ConfigKey>) -> Result<Self, IdentityError> {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).expect("system RNG available");
+        let encoded = BASE64.encode(pkcs8.as_ref());
+        write_private(path, &seal(&encoded, key))?;
+        Self::parse(&encoded).ok_or(IdentityError::Invalid {
+            path: path.display().to_string(),
+        })
+    }
+
+    /// The key in the state directory, decrypted, if there is one
+    pub fn export_encoded() -> Result<Option<String>, IdentityError> {
+        let path = state_dir().join(KEY_FILE_NAME);
+        Ok(read_key_file(&path, state_key()?.as_ref())?.map(|(encoded, _)| encoded.trim().to_string()))
+    }
+
+    /// Replace the key in the state directory with an exported one
+    pub fn import_encoded(encoded: &str) -> Result<Self, IdentityError> {
+        let path = state_dir().join(KEY_FILE_NAME);
+        let identity = Self::parse(encoded).ok_or_else(|| IdentityError::Invalid {
+            path: path.display().to_string(),
+        })?;
+        write_private(&path, &seal(encoded.trim(), state_key()?.as_ref()))?;
+        Ok(identity)
+    }
+
+    fn parse(encoded: &str) -> Option<Self> {
+        let pkcs8 = BASE64.decode(encoded.trim()).ok()?;
+        let key_pair = Ed25519KeyPair::from_pkcs8(&pkcs8).ok()?;
+        Some(Self { key_pair })
+    }
+
+    /// Base64 public key, as sent at registration
+    pub fn public_key(&self) -> String {
+        BASE64.encode(self.key_pair.public_key().as_ref())
+    }
+
+    /// Base64 signature of a request
+    pub fn sign(&self, method: &str, path_and_query: &str, timestamp: i64, body: &[u8]) -> String {
+        let payload = signing_payload(method, path_and_query, timestamp, body);
+        BASE64.encode(self.key_pair.sign(payload.as_bytes()).as_ref())
+    }
+}
+
+/// What is signed: method, path with query, timestamp and body hash, one per line
+pub fn signing_payload(method: &str, path_and_query: &str, timestamp: i64, body: &[u8]) -> String {
+    format!(
+        "{}\n{}\n{}\n{}",
+        method,
+        path_and_query,
+        timestamp,
+        hex::encode(Sha256::digest(body))
+    )
+}
+
+fn state_key() -> Result<Option<ConfigKey>, IdentityError> {
+    crate::state::state_key().map_err(|e| IdentityError::Encryption {
+        path: KEY_FILE_NAME.to_string(),
+        message: e.to_string(),
+    })
+}
+
+fn seal(encoded: &str, key: Option<&ConfigKey>) -> String {
+    match key {
+        Some(key) => key.encrypt(encoded),
+        None => encoded.to_string(),
+    }
+}
+
+/// Contents of the key file, decrypted, and whether it was encrypted; `None` if there is none
+fn read_key_file(path: &Path, key: Option<&ConfigKey>) -> Result<Option<(String, bool)>, IdentityError> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => {
+            return Err(IdentityError::Read {
+                path: path.display().to_string(),
+                message: e.to_string(),
+            })
+        }
+    };
+    if !encryption::is_encrypted(contents.trim()) {
+        return Ok(Some((contents, false)));
+    }
+    let encryption_error = |message: String| IdentityError::Encryption {
+        path: path.display().to_string(),
+        message,
+    };
+    let key = key.ok_or_else(|| {
+        encryption_error(format!(
+            "the file is encrypted; set {} or {}",
+            crate::state::STATE_KEY_ENV,
+            crate::state::STATE_KEY_FILE_ENV
+        ))
+    })?;
+    let encoded = key.decrypt(contents.trim()).map_err(|e| encryption_error(e.to_string()))?;
+    Ok(Some((encoded, true)))
+}
+
+fn state_dir() -> PathBuf {
+    crate::state::ResourceState::get_state_file_path()
+        .parent()
+        .map(Path::to_path_buf)
+        .unwrap_or_else(|| PathBuf::from("."))
+}
+
+/// Write through a temp file that is created owner-only, so the key is never readable by others
+fn write_private(path: &Path, contents: &str) -> Result<(), IdentityError> {
+    let write_error = |e: std::io::Error| IdentityError::Write {
+        path: path.display().to_string(),
+        message: e.to_string(),
+    };
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).map_err(write_error)?;
+    }
+    let temp_path = path.with_extension("key.tmp");
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(&temp_path).map_err(write_error)?;
+    file.write_all(contents.as_bytes()).map_err(write_error)?;
+    file.sync_all().map_err(write_error)?;
+    fs::rename(&temp_path, path).map_err(write_error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::signature::{UnparsedPublicKey, ED25519};
+
+    #[test]
+    fn test_generated_once_and_reloaded() {
+        let dir = tempfile::tempdir().unwrap();
+        let identity = AgentIdentity::load_or_generate(dir.path(), None).unwrap();
+        let reloaded = AgentIdentity::load_or_generate(dir.path(), None).unwrap();
+        assert_eq!(identity.public_key(), reloaded.public_key());
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(dir.path().join(KEY_FILE_NAME)).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        let regenerated = AgentIdentity::regenerate(dir.path(), None).unwrap();
+        assert_ne!(regenerated.public_key(), identity.public_key());
+        let reloaded = AgentIdentity::load_or_generate(dir.path(), None).unwrap();
+        assert_eq!(regenerated.public_key(), reloaded.public_key());
+
+        fs::write(dir.path().join(KEY_FILE_NAME), "not a key").unwrap();
+        assert!(matches!(
+            AgentIdentity::load_or_generate(dir.path(), None),
+            Err(IdentityError::Invalid { .. })
+        ));
+    }
+
+    #[test]
+    fn test_encrypted_with_state_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let key = ConfigKey::parse(&ConfigKey::generate()).unwrap();
+        let plain = AgentIdentity::load_or_generate(dir.path(), None).unwrap();
+
+        // A plain key from before encryption was turned on is rewritten encrypted
+        let identity = AgentIdentity::load_or_generate(dir.path(), Some(&key)).unwrap();
+        assert_eq!(identity.public_key(), plain.public_key());
+        let contents = fs::read_to_string(dir.path().join(KEY_FILE_NAME)).unwrap();
+        assert!(encryption::is_encrypted(&contents));
+
+        let reloaded = AgentIdentity::load_or_generate(dir.path(), Some(&key)).unwrap();
+        assert_eq!(reloaded.public_key(), plain.public_key());
+        let other = ConfigKey::parse(&ConfigKey::generate()).unwrap();
+        for key in [None, Some(&other)] {
+            assert!(matches!(
+                AgentIdentity::load_or_generate(dir.path(), key),
+                Err(IdentityError::Encryption { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn test_signature_verifies() {
+        let dir = tempfile::tempdir().unwrap();
+        let identity = AgentIdentity::load_or_generate(dir.path(), None).unwrap();
+        let signature = BASE64
+            .decode(identity.sign("POST", "/api/v1/metrics", 1_700_000_000, b"{}"))
+            .unwrap();
+        let public_key = UnparsedPublicKey::new(&ED25519, BASE64.decode(identity.public_key()).unwrap());
+
+        let payload = signing_payload("POST", "/api/v1/metrics", 1_700_000_000, b"{}");
+        assert!(public_key.verify(payload.as_bytes(), &signature).is_ok());
+        let tampered = signing_payload("POST", "/api/v1/metrics", 1_700_000_000, b"{\"x\":1}");
+        assert!(public_key.verify(tampered.as_bytes(), &signature).is_err());
+    }
+}
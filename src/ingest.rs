@@ -0,0 +1,271 @@
+//! Unix socket accepting metrics pushed by other programs
+//!
+//! Clients, such as Telegraf's `socket_writer` output with `data_format =
+//! "json"`, write Telegraf JSON documents (see [`crate::telegraf`]), one per
+//! line, on one connection or many. Samples wait here until the next
+//! collection picks them up. The socket is owner- and group-writable only;
+//! it is bound in a private directory and moved into place once its mode is
+//! set, so it is never reachable with looser permissions.
+
+use async_trait::async_trait;
+use std::collections::{BTreeMap, VecDeque};
+use std::fs;
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, PoisonError, Weak};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::task::{JoinHandle, JoinSet};
+use tracing::{debug, warn};
+
+use crate::metrics::{CollectContext, Collected, MetricCollector, MetricError, Sample};
+use crate::telegraf;
+
+/// Longest document accepted; a longer line closes the connection
+const MAX_LINE_BYTES: usize = 1024 * 1024;
+/// Samples held between collections; the oldest are dropped beyond this
+const MAX_PENDING_SAMPLES: usize = 100_000;
+/// Connections read at once; further clients wait in the listen backlog
+const MAX_CONNECTIONS: usize = 64;
+
+/// Samples received since the last collection
+#[derive(Default)]
+struct Pending {
+    samples: VecDeque<Sample>,
+    dropped: u64,
+}
+
+/// Sockets this process listens on, shared by the collectors of reloaded configs
+static SOCKETS: Mutex<BTreeMap<PathBuf, Weak<Socket>>> = Mutex::new(BTreeMap::new());
+
+struct Socket {
+    path: PathBuf,
+    /// Bound at startup, handed to the accept task on the first collection
+    listener: Mutex<Option<std::os::unix::net::UnixListener>>,
+    pending: Arc<Mutex<Pending>>,
+    task: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl Drop for Socket {
+    fn drop(&mut self) {
+        if let Some(task) = self.task.lock().unwrap_or_else(PoisonError::into_inner).take() {
+            task.abort();
+        }
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+pub struct IngestCollector {
+    socket: Arc<Socket>,
+}
+
+impl IngestCollector {
+    /// Listen on `path`, or share the socket when this process already does
+    ///
+    /// A socket file left by an earlier run is replaced; one another process
+    /// still listens on is not, nor is anything at `path` that is not a socket.
+    pub fn bind(path: &Path) -> Result<Self, MetricError> {
+        let mut sockets = SOCKETS.lock().unwrap_or_else(PoisonError::into_inner);
+        sockets.retain(|_, socket| socket.strong_count() > 0);
+        if let Some(socket) = sockets.get(path).and_then(Weak::upgrade) {
+            return Ok(Self { socket });
+        }
+
+        let listen_error = |message: String| {
+            MetricError::Ingest(format!("failed to listen on {}: {}", path.display(), message))
+        };
+        if std::os::unix::net::UnixStream::connect(path).is_ok() {
+            return Err(listen_error("another process is listening on it".to_string()));
+        }
+        let parent = path
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+            .unwrap_or(Path::new("."));
+        fs::create_dir_all(parent).map_err(|e| listen_error(e.to_string()))?;
+        match fs::symlink_metadata(path) {
+            Ok(metadata) if !metadata.file_type().is_socket() => {
+                return Err(listen_error("the path exists and is not a socket".to_string()));
+            }
+            Ok(_) => fs::remove_file(path).map_err(|e| listen_error(e.to_string()))?,
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(listen_error(e.to_string())),
+            Err(_) => {}
+        }
+        let listener = bind_private(parent, path).map_err(|e| listen_error(e.to_string()))?;
+        listener.set_nonblocking(true).map_err(|e| listen_error(e.to_string()))?;
+        // From here on dropping `socket` removes the file again
+        let socket = Arc::new(Socket {
+            path: path.to_path_buf(),
+            listener: Mutex::new(None),
+            pending: Arc::default(),
+            task: Mutex::new(None),
+        });
+        *socket.listener.lock().unwrap_or_else(PoisonError::into_inner) = Some(listener);
+        sockets.insert(path.to_path_buf(), Arc::downgrade(&socket));
+        Ok(Self { socket })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.socket.path
+    }
+
+    /// Start accepting connections, once a runtime is there to run them
+    fn start(&self) -> Result<(), MetricError> {
+        let listener = self.socket.listener.lock().unwrap_or_else(PoisonError::into_inner).take();
+        let Some(listener) = listener else {
+            return Ok(());
+        };
+        let listener = UnixListener::from_std(listener).map_err(|e| MetricError::Ingest(e.to_string()))?;
+        let task = tokio::spawn(accept(listener, self.socket.pending.clone()));
+        *self.socket.task.lock().unwrap_or_else(PoisonError::into_inner) = Some(task);
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl MetricCollector for IngestCollector {
+    async fn collect(&self, _ctx: CollectContext) -> Result<Collected, MetricError> {
+        self.start()?;
+        let mut pending = self.socket.pending.lock().unwrap_or_else(PoisonError::into_inner);
+        if pending.dropped > 0 {
+            warn!(
+                dropped = pending.dropped,
+                "Ingestion socket received more samples than it holds between collections"
+            );
+            pending.dropped = 0;
+        }
+        Ok(Vec::from(std::mem::take(&mut pending.samples)).into())
+    }
+}
+
+/// Bind `path` in a new owner-only directory under `parent`, set its mode and move it into place
+fn bind_private(parent: &Path, path: &Path) -> std::io::Result<std::os::unix::net::UnixListener> {
+    use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+
+    let mut staging_name = std::ffi::OsString::from(".");
+    staging_name.push(path.file_name().unwrap_or_default());
+    staging_name.push(format!(".{}", std::process::id()));
+    let staging = parent.join(staging_name);
+    // Left behind by a crashed run with the same process ID
+    let _ = fs::remove_dir_all(&staging);
+    fs::DirBuilder::new().mode(0o700).create(&staging)?;
+    let staged = staging.join("socket");
+    let result = std::os::unix::net::UnixListener::bind(&staged).and_then(|listener| {
+        fs::set_permissions(&staged, fs::Permissions::from_mode(0o660))?;
+        fs::rename(&staged, path)?;
+        Ok(listener)
+    });
+    let _ = fs::remove_dir_all(&staging);
+    result
+}
+
+async fn accept(listener: UnixListener, pending: Arc<Mutex<Pending>>) {
+    // Connections end with this task
+    let mut connections = JoinSet::new();
+    loop {
+        while connections.try_join_next().is_some() {}
+        if connections.len() >= MAX_CONNECTIONS {
+            connections.join_next().await;
+            continue;
+        }
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                connections.spawn(read_documents(stream, pending.clone()));
+            }
+            Err(e) => {
+                warn!(error = %e, "Failed to accept on ingestion socket");
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            }
+        }
+    }
+}
+
+async fn read_documents(stream: UnixStream, pending: Arc<Mutex<Pending>>) {
+    let mut reader = BufReader::new(stream);
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        match (&mut reader).take(MAX_LINE_BYTES as u64 + 1).read_until(b'\n', &mut line).await {
+            Ok(0) => return,
+            Ok(_) if line.len() > MAX_LINE_BYTES => {
+                warn!(max_bytes = MAX_LINE_BYTES, "Closing ingestion connection sending an oversized document");
+                return;
+            }
+            Ok(_) => {}
+            Err(e) => {
+                debug!(error = %e, "Ingestion connection failed");
+                return;
+            }
+        }
+
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        match telegraf::parse(&String::from_utf8_lossy(&line), timestamp) {
+            Ok(samples) => {
+                let mut pending = pending.lock().unwrap_or_else(PoisonError::into_inner);
+                pending.samples.extend(samples);
+                let excess = pending.samples.len().saturating_sub(MAX_PENDING_SAMPLES);
+                pending.samples.drain(..excess);
+                pending.dropped += excess as u64;
+            }
+            Err(e) => warn!(error = %e, "Skipping document on ingestion socket"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+    use tokio::io::AsyncWriteExt;
+
+    #[tokio::test]
+    async fn test_collects_documents_written_to_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("run").join("ingest.sock");
+        let collector = IngestCollector::bind(&path).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o660);
+        assert_eq!(fs::read_dir(path.parent().unwrap()).unwrap().count(), 1);
+        // Nothing is accepted before the first collection, but connections queue
+        assert!(collector.collect(CollectContext::new(Duration::from_secs(5))).await.unwrap().is_empty());
+
+        let mut client = UnixStream::connect(&path).await.unwrap();
+        client
+            .write_all(b"{\"name\": \"queue\", \"fields\": {\"depth\": 3}}\nnot json\n{\"name\": \"queue\", \"fields\": {\"depth\": 4}}\n")
+            .await
+            .unwrap();
+        client.shutdown().await.unwrap();
+        drop(client);
+
+        let mut samples = Vec::new();
+        for _ in 0..50 {
+            samples.extend(collector.collect(CollectContext::new(Duration::from_secs(5))).await.unwrap().samples);
+            if samples.len() == 2 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        let values: Vec<f64> = samples.iter().map(|sample| sample.value).collect();
+        assert_eq!(values, [3.0, 4.0]);
+        assert_eq!(samples[0].name, "queue_depth");
+
+        // A reloaded config shares the socket; the last collector removes it
+        let reloaded = IngestCollector::bind(&path).unwrap();
+        drop(collector);
+        assert!(path.exists());
+        drop(reloaded);
+        assert!(!path.exists());
+        assert!(IngestCollector::bind(&path).is_ok());
+    }
+
+    #[test]
+    fn test_does_not_replace_other_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ingest.sock");
+        fs::write(&path, "keep").unwrap();
+        assert!(matches!(IngestCollector::bind(&path), Err(MetricError::Ingest(_))));
+        assert_eq!(fs::read_to_string(&path).unwrap(), "keep");
+    }
+}
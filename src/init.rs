@@ -0,0 +1,216 @@
+use std::fs;
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::path::Path;
+
+/// Endpoint written when none is given
+pub const DEFAULT_ENDPOINT: &str = "https://api.operion.co";
+
+/// Values substituted into the generated config
+#[derive(Debug, Default)]
+pub struct InitOptions {
+    pub endpoint: Option<String>,
+    pub api_key: Option<String>,
+    /// Overwrite an existing config file
+    pub force: bool,
+    /// Ask for missing values on the terminal
+    pub prompt: bool,
+}
+
+/// Render a commented default config
+pub fn render_config(endpoint: &str, api_key: Option<&str>) -> String {
+    let api_key_line = match api_key {
+        Some(key) => format!("api_key: \"{}\"", yaml_escape(key)),
+        None => "# api_key: \"your-api-key-here\"".to_string(),
+    };
+
+    format!(
+        r#"# Operion Sentinel Agent Configuration
+
+# Config file layout; older layouts are migrated with a warning
+version: {version}
+
+agent:
+  # Optional: Override hostname detection
+  # hostname: "custom-hostname"
+
+api:
+  # REST API endpoint for metric ingestion
+  endpoint: "{endpoint}"
+  # Optional: Request timeout in seconds (default: 30)
+  timeout_seconds: 30
+
+  # API key for Operion platform authentication
+  # Required for server registration and billing tracking
+  # Get your API key from https://app.operion.co/settings/api-keys
+  {api_key_line}
+
+collection:
+  # How often to collect metrics (seconds)
+  interval_seconds: 60
+  # How often to flush buffered metrics to API (seconds)
+  flush_interval_seconds: 30
+  # Maximum metrics to buffer before dropping old ones
+  batch_size: 100
+
+  # Disk monitoring configuration
+  disk:
+    enabled: true
+    # Optional: Only monitor these mount points (contains match)
+    # include_mount_points:
+    #   - "/"
+    #   - "/home"
+    # Optional: Exclude these mount points (contains match)
+    exclude_mount_points:
+      - "/dev"
+      - "/proc"
+      - "/sys"
+      - "/run"
+      - "/tmp"
+    # Optional: Skip devices by name (* and ? wildcards), e.g. snap loop devices
+    # exclude_devices:
+    #   - "loop*"
+    #   - "zram*"
+"#,
+        endpoint = yaml_escape(endpoint),
+        api_key_line = api_key_line,
+        version = crate::config::CONFIG_VERSION,
+    )
+}
+
+fn yaml_escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// Write a default config to `path`, prompting for missing values when interactive
+pub fn write_config(path: &Path, options: InitOptions) -> Result<(), InitError> {
+    if path.exists() && !options.force {
+        return Err(InitError::AlreadyExists(path.display().to_string()));
+    }
+
+    let interactive = options.prompt && io::stdin().is_terminal();
+    let endpoint = match options.endpoint {
+        Some(endpoint) => endpoint,
+        None if interactive => prompt("API endpoint", Some(DEFAULT_ENDPOINT))?
+            .unwrap_or_else(|| DEFAULT_ENDPOINT.to_string()),
+        None => DEFAULT_ENDPOINT.to_string(),
+    };
+    let api_key = match options.api_key {
+        Some(api_key) => Some(api_key),
+        None if interactive => prompt("API key (leave empty to skip registration)", None)?,
+        None => None,
+    };
+
+    let contents = render_config(&endpoint, api_key.as_deref());
+
+    // Make sure what we write would actually load
+    crate::config::Config::load_from_str(&contents)
+        .map_err(|e| InitError::InvalidValue(e.to_string()))?;
+
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        fs::create_dir_all(parent).map_err(|e| InitError::Io {
+            path: parent.display().to_string(),
+            error: e.to_string(),
+        })?;
+    }
+
+    let io_error = |e: io::Error| InitError::Io {
+        path: path.display().to_string(),
+        error: e.to_string(),
+    };
+    if options.force {
+        match fs::remove_file(path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(io_error(e)),
+            _ => {}
+        }
+    }
+
+    // The file may hold an API key, so it is never readable by others, not even briefly
+    let mut file = fs::OpenOptions::new();
+    file.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        file.mode(0o600);
+    }
+    file.open(path)
+        .and_then(|mut file| file.write_all(contents.as_bytes()))
+        .map_err(io_error)?;
+
+    Ok(())
+}
+
+/// Read one line from the terminal; an empty answer yields `None`
+fn prompt(label: &str, default: Option<&str>) -> Result<Option<String>, InitError> {
+    match default {
+        Some(default) => print!("{} [{}]: ", label, default),
+        None => print!("{}: ", label),
+    }
+    io::stdout().flush().map_err(|e| InitError::Prompt(e.to_string()))?;
+
+    let mut answer = String::new();
+    io::stdin()
+        .lock()
+        .read_line(&mut answer)
+        .map_err(|e| InitError::Prompt(e.to_string()))?;
+
+    let answer = answer.trim();
+    Ok((!answer.is_empty()).then(|| answer.to_string()))
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum InitError {
+    #[error("Configuration file already exists: {0} (use --force to overwrite)")]
+    AlreadyExists(String),
+    #[error("Generated configuration is invalid: {0}")]
+    InvalidValue(String),
+    #[error("Failed to write {path}: {error}")]
+    Io { path: String, error: String },
+    #[error("Failed to read input: {0}")]
+    Prompt(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_rendered_config_is_valid() {
+        let report = Config::check_str(&render_config(DEFAULT_ENDPOINT, None)).unwrap();
+        assert!(report.unknown_keys.is_empty());
+        assert!(report.config.api.api_key.is_none());
+
+        let config =
+            Config::load_from_str(&render_config("https://api.example.com", Some("key \"1\"")))
+                .unwrap();
+        assert_eq!(config.api.endpoint, "https://api.example.com");
+        assert_eq!(config.api.api_key.as_deref(), Some("key \"1\""));
+    }
+
+    #[test]
+    fn test_write_config_refuses_to_overwrite() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("operion").join("agent.yaml");
+
+        write_config(&path, InitOptions::default()).unwrap();
+        assert!(Config::load_from_file(&path).is_ok());
+
+        let result = write_config(&path, InitOptions::default());
+        assert!(matches!(result, Err(InitError::AlreadyExists(_))));
+
+        let options = InitOptions {
+            api_key: Some("secret".to_string()),
+            force: true,
+            ..Default::default()
+        };
+        write_config(&path, options).unwrap();
+        let config = Config::load_from_file(&path).unwrap();
+        assert_eq!(config.api.api_key.as_deref(), Some("secret"));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        }
+    }
+}
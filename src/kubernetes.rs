@@ -0,0 +1,253 @@
+//! Pod identity when the agent runs in Kubernetes
+//!
+//! Namespace, pod name, node name and pod labels are read from the downward
+//! API (environment variables and the labels file) and the service account.
+//! Whatever the downward API does not provide is looked up from the API
+//! server with the pod's service account token, which needs `get` on pods.
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::time::Duration;
+
+const SERVICE_ACCOUNT_DIR: &str = "/var/run/secrets/kubernetes.io/serviceaccount";
+
+/// Where the downward API volume with `metadata.labels` is expected
+const POD_LABELS_FILE: &str = "/etc/podinfo/labels";
+
+/// Prefix of the labels added to every batch
+const LABEL_PREFIX: &str = "kubernetes.";
+
+/// The pod the agent runs in
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct PodMetadata {
+    pub namespace: String,
+    pub pod_name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub node_name: Option<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub labels: BTreeMap<String, String>,
+}
+
+impl PodMetadata {
+    /// The pod the agent runs in, `None` outside Kubernetes
+    pub async fn detect() -> Option<Self> {
+        let host = std::env::var("KUBERNETES_SERVICE_HOST").ok()?;
+        let service_account = Path::new(SERVICE_ACCOUNT_DIR);
+
+        let namespace = env_var("POD_NAMESPACE")
+            .or_else(|| read_trimmed(&service_account.join("namespace")))
+            .unwrap_or_else(|| "default".to_string());
+        // A pod's hostname is its name unless the spec overrides it
+        let pod_name = env_var("POD_NAME")
+            .or_else(|| env_var("HOSTNAME"))
+            .unwrap_or_else(crate::hostname::short);
+        let labels = std::fs::read_to_string(POD_LABELS_FILE)
+            .map(|content| parse_labels(&content))
+            .ok();
+
+        let mut pod = Self {
+            namespace,
+            pod_name,
+            node_name: env_var("NODE_NAME"),
+            labels: labels.clone().unwrap_or_default(),
+        };
+
+        if pod.node_name.is_none() || labels.is_none() {
+            let port = env_var("KUBERNETES_SERVICE_PORT").unwrap_or_else(|| "443".to_string());
+            let host = if host.contains(':') { format!("[{}]", host) } else { host };
+            match api_client(service_account) {
+                Some((client, token)) => match reqwest::Url::parse(&format!("https://{}:{}", host, port)) {
+                    Ok(base_url) => {
+                        match fetch_pod(&client, &base_url, &token, &pod.namespace, &pod.pod_name).await {
+                            Ok(found) => {
+                                pod.node_name = pod.node_name.or(found.node_name);
+                                if labels.is_none() {
+                                    pod.labels = found.labels;
+                                }
+                            }
+                            Err(e) => tracing::debug!(error = %e, "Could not look up pod from the API server"),
+                        }
+                    }
+                    Err(e) => tracing::debug!(error = %e, "Invalid API server address, skipping pod lookup"),
+                },
+                None => tracing::debug!("No service account token, skipping pod lookup"),
+            }
+        }
+
+        Some(pod)
+    }
+
+    /// Labels added to every batch, prefixed so they cannot collide with `agent.labels`
+    pub fn batch_labels(&self) -> BTreeMap<String, String> {
+        let mut labels = BTreeMap::from([
+            (format!("{}namespace", LABEL_PREFIX), self.namespace.clone()),
+            (format!("{}pod", LABEL_PREFIX), self.pod_name.clone()),
+        ]);
+        if let Some(node_name) = &self.node_name {
+            labels.insert(format!("{}node", LABEL_PREFIX), node_name.clone());
+        }
+        for (key, value) in &self.labels {
+            labels.insert(format!("{}label.{}", LABEL_PREFIX, key), value.clone());
+        }
+        labels
+    }
+}
+
+fn env_var(name: &str) -> Option<String> {
+    std::env::var(name).ok().filter(|value| !value.trim().is_empty())
+}
+
+fn read_trimmed(path: &Path) -> Option<String> {
+    std::fs::read_to_string(path)
+        .ok()
+        .map(|content| content.trim().to_string())
+        .filter(|content| !content.is_empty())
+}
+
+/// Parse the downward API labels file, one `key="value"` per line
+fn parse_labels(content: &str) -> BTreeMap<String, String> {
+    content
+        .lines()
+        .filter_map(|line| {
+            let (key, value) = line.split_once('=')?;
+            let value = value.trim();
+            let value = value
+                .strip_prefix('"')
+                .and_then(|v| v.strip_suffix('"'))
+                .unwrap_or(value)
+                .replace("\\\"", "\"")
+                .replace("\\\\", "\\");
+            Some((key.trim().to_string(), value))
+        })
+        .filter(|(key, _)| !key.is_empty())
+        .collect()
+}
+
+/// HTTP client trusting the cluster CA, with the service account token
+fn api_client(service_account: &Path) -> Option<(reqwest::Client, String)> {
+    let token = read_trimmed(&service_account.join("token"))?;
+    let mut builder = reqwest::Client::builder().timeout(Duration::from_secs(2));
+    if let Some(certificate) = std::fs::read(service_account.join("ca.crt"))
+        .ok()
+        .and_then(|pem| reqwest::Certificate::from_pem(&pem).ok())
+    {
+        builder = builder.add_root_certificate(certificate);
+    }
+    Some((builder.build().ok()?, token))
+}
+
+/// Look up a pod's node and labels from the API server at `base_url`
+async fn fetch_pod(
+    client: &reqwest::Client,
+    base_url: &reqwest::Url,
+    token: &str,
+    namespace: &str,
+    pod_name: &str,
+) -> Result<PodMetadata, reqwest::Error> {
+    #[derive(Deserialize)]
+    struct Pod {
+        metadata: PodObjectMeta,
+        #[serde(default)]
+        spec: PodSpec,
+    }
+
+    #[derive(Deserialize)]
+    struct PodObjectMeta {
+        name: String,
+        namespace: String,
+        #[serde(default)]
+        labels: BTreeMap<String, String>,
+    }
+
+    #[derive(Deserialize, Default)]
+    struct PodSpec {
+        #[serde(rename = "nodeName")]
+        node_name: Option<String>,
+    }
+
+    // Names come from the environment, so they are escaped rather than trusted to be one segment
+    let mut url = base_url.clone();
+    if let Ok(mut segments) = url.path_segments_mut() {
+        segments
+            .pop_if_empty()
+            .extend(["api", "v1", "namespaces", namespace, "pods", pod_name]);
+    }
+    let pod: Pod = client
+        .get(url)
+        .bearer_auth(token)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+
+    Ok(PodMetadata {
+        namespace: pod.metadata.namespace,
+        pod_name: pod.metadata.name,
+        node_name: pod.spec.node_name,
+        labels: pod.metadata.labels,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_labels_file_and_batch_labels() {
+        let labels = parse_labels("app=\"web\"\npod-template-hash=\"7d9f\"\nnote=\"say \\\"hi\\\"\"\n\n");
+        assert_eq!(labels.get("app").map(String::as_str), Some("web"));
+        assert_eq!(labels.get("note").map(String::as_str), Some("say \"hi\""));
+        assert_eq!(labels.len(), 3);
+
+        let pod = PodMetadata {
+            namespace: "shop".to_string(),
+            pod_name: "web-7d9f-x2x".to_string(),
+            node_name: Some("node-1".to_string()),
+            labels,
+        };
+        let batch_labels = pod.batch_labels();
+        assert_eq!(batch_labels.get("kubernetes.namespace").map(String::as_str), Some("shop"));
+        assert_eq!(batch_labels.get("kubernetes.node").map(String::as_str), Some("node-1"));
+        assert_eq!(batch_labels.get("kubernetes.label.app").map(String::as_str), Some("web"));
+    }
+
+    #[tokio::test]
+    async fn test_fetch_pod_from_api_server() {
+        use wiremock::matchers::{header, method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/api/v1/namespaces/shop/pods/web-0"))
+            .and(header("Authorization", "Bearer sa-token"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "metadata": {"name": "web-0", "namespace": "shop", "labels": {"app": "web"}},
+                "spec": {"nodeName": "node-2"},
+            })))
+            .mount(&server)
+            .await;
+
+        Mock::given(method("GET"))
+            .and(path("/api/v1/namespaces/shop/pods/web-0%2F..%2Fsecrets"))
+            .respond_with(ResponseTemplate::new(404))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let base_url = reqwest::Url::parse(&server.uri()).unwrap();
+        let pod = fetch_pod(&reqwest::Client::new(), &base_url, "sa-token", "shop", "web-0")
+            .await
+            .unwrap();
+        assert_eq!(pod.node_name.as_deref(), Some("node-2"));
+        assert_eq!(pod.labels.get("app").map(String::as_str), Some("web"));
+
+        assert!(fetch_pod(&reqwest::Client::new(), &base_url, "sa-token", "shop", "web-1")
+            .await
+            .is_err());
+        assert!(fetch_pod(&reqwest::Client::new(), &base_url, "sa-token", "shop", "web-0/../secrets")
+            .await
+            .is_err());
+    }
+}
@@ -0,0 +1,129 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// launchd job label, also used as the plist file name
+pub const LABEL: &str = "com.operion.sentinel-agent";
+
+/// System-wide daemons live here and run as root at boot
+const LAUNCH_DAEMONS_DIR: &str = "/Library/LaunchDaemons";
+
+/// Where launchd redirects the agent's stdout/stderr
+const LOG_DIR: &str = "/Library/Logs/Operion";
+
+pub fn plist_path() -> PathBuf {
+    Path::new(LAUNCH_DAEMONS_DIR).join(format!("{}.plist", LABEL))
+}
+
+/// Render the launchd property list for the agent
+pub fn render_plist(executable: &Path, config_path: &Path) -> String {
+    let log_path = Path::new(LOG_DIR).join("agent.log");
+
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+<dict>
+    <key>Label</key>
+    <string>{label}</string>
+    <key>ProgramArguments</key>
+    <array>
+        <string>{executable}</string>
+        <string>--config</string>
+        <string>{config}</string>
+    </array>
+    <key>RunAtLoad</key>
+    <true/>
+    <key>KeepAlive</key>
+    <true/>
+    <key>StandardOutPath</key>
+    <string>{log}</string>
+    <key>StandardErrorPath</key>
+    <string>{log}</string>
+</dict>
+</plist>
+"#,
+        label = LABEL,
+        executable = xml_escape(&executable.to_string_lossy()),
+        config = xml_escape(&config_path.to_string_lossy()),
+        log = xml_escape(&log_path.to_string_lossy()),
+    )
+}
+
+fn xml_escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Write the LaunchDaemon plist and load it into launchd
+pub fn install(config_path: &Path) -> Result<(), LaunchdError> {
+    let executable = std::env::current_exe().map_err(|e| LaunchdError::Io(e.to_string()))?;
+
+    fs::create_dir_all(LOG_DIR).map_err(|e| LaunchdError::Io(e.to_string()))?;
+    fs::write(plist_path(), render_plist(&executable, config_path))
+        .map_err(|e| LaunchdError::Io(e.to_string()))?;
+
+    launchctl(&["bootstrap", "system", &plist_path().to_string_lossy()])
+}
+
+/// Unload the job and remove its plist
+pub fn uninstall() -> Result<(), LaunchdError> {
+    // Not being loaded is fine; we still want the plist gone
+    let _ = launchctl(&["bootout", &format!("system/{}", LABEL)]);
+
+    let path = plist_path();
+    if path.exists() {
+        fs::remove_file(&path).map_err(|e| LaunchdError::Io(e.to_string()))?;
+    }
+    Ok(())
+}
+
+fn launchctl(args: &[&str]) -> Result<(), LaunchdError> {
+    let output = Command::new("launchctl")
+        .args(args)
+        .output()
+        .map_err(|e| LaunchdError::Launchctl(e.to_string()))?;
+
+    if !output.status.success() {
+        return Err(LaunchdError::Launchctl(
+            String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        ));
+    }
+    Ok(())
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum LaunchdError {
+    #[error("Failed to write launchd files: {0}")]
+    Io(String),
+    #[error("launchctl failed: {0}")]
+    Launchctl(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_plist() {
+        let plist = render_plist(
+            Path::new("/usr/local/bin/sentinel-agent"),
+            Path::new("/Library/Application Support/Operion/agent.yaml"),
+        );
+
+        assert!(plist.contains("<string>com.operion.sentinel-agent</string>"));
+        assert!(plist.contains("<string>/usr/local/bin/sentinel-agent</string>"));
+        assert!(plist.contains("<string>/Library/Application Support/Operion/agent.yaml</string>"));
+        assert!(plist.contains("<key>KeepAlive</key>"));
+    }
+
+    #[test]
+    fn test_render_plist_escapes_paths() {
+        let plist = render_plist(Path::new("/opt/a&b/agent"), Path::new("/tmp/<cfg>.yaml"));
+        assert!(plist.contains("/opt/a&amp;b/agent"));
+        assert!(plist.contains("/tmp/&lt;cfg&gt;.yaml"));
+    }
+}
@@ -0,0 +1,63 @@
+pub mod agent;
+pub(crate) mod alerts;
+#[cfg(feature = "bench")]
+pub mod bench;
+pub(crate) mod buffer;
+pub mod build_info;
+pub mod client;
+pub(crate) mod clock;
+pub(crate) mod commands;
+pub mod config;
+pub(crate) mod container;
+pub mod diagnose;
+pub mod encryption;
+#[cfg(windows)]
+pub(crate) mod eventlog;
+pub(crate) mod exec;
+pub(crate) mod exposition;
+pub mod fingerprint;
+pub(crate) mod hostname;
+pub(crate) mod identity;
+#[cfg(unix)]
+pub(crate) mod ingest;
+pub mod init;
+pub(crate) mod kubernetes;
+#[cfg(target_os = "macos")]
+pub mod launchd;
+pub mod limits;
+pub mod lock;
+pub(crate) mod log_file;
+pub mod logging;
+pub mod maintenance;
+pub mod metadata;
+pub mod metrics;
+pub(crate) mod network;
+pub(crate) mod oauth;
+pub mod paths;
+pub(crate) mod plugins;
+pub(crate) mod pressure;
+pub mod privileges;
+pub(crate) mod profiling;
+pub(crate) mod relabel;
+pub(crate) mod remote_config;
+pub(crate) mod schedule;
+pub(crate) mod scrape;
+pub(crate) mod scripting;
+pub mod secrets;
+pub(crate) mod sinks;
+pub(crate) mod spool;
+pub mod state;
+pub mod state_bundle;
+pub mod status;
+pub(crate) mod supervisor;
+pub(crate) mod telegraf;
+pub(crate) mod textfile;
+pub(crate) mod thresholds;
+pub(crate) mod tls;
+pub mod token_store;
+pub(crate) mod units;
+pub(crate) mod virtualization;
+pub(crate) mod wasm;
+#[cfg(windows)]
+pub mod winservice;
+pub(crate) mod wsl;
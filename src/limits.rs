@@ -0,0 +1,217 @@
+//! Limits the agent imposes on itself at startup
+//!
+//! Lowers the agent's CPU and I/O priority and checks that its memory ceiling
+//! and CPU quota can actually be enforced. With `limits.strict` the agent
+//! refuses to start when anything cannot be honored; otherwise it warns.
+
+use thiserror::Error;
+use tracing::{info, warn};
+
+use crate::config::{Config, IoClass};
+
+#[derive(Error, Debug)]
+pub enum LimitsError {
+    #[error("refusing to start, resource limits cannot be honored: {0}")]
+    NotHonored(String),
+}
+
+/// Apply and verify the configured limits
+pub fn apply(config: &Config) -> Result<(), LimitsError> {
+    let limits = config.get_limits();
+    let mut problems = Vec::new();
+
+    if let Some(nice) = limits.nice {
+        match set_nice(nice) {
+            Ok(()) => info!(nice, "Set agent CPU priority"),
+            Err(e) => problems.push(format!("cannot set nice {}: {}", nice, e)),
+        }
+    }
+
+    if let Some(class) = limits.io_class {
+        let priority = limits.get_io_priority();
+        match set_io_priority(class, priority) {
+            Ok(()) => info!(class = ?class, priority, "Set agent I/O priority"),
+            Err(e) => problems.push(format!("cannot set I/O class {:?}: {}", class, e)),
+        }
+    }
+
+    if let Some(limit) = config.get_max_memory_bytes() {
+        match process_memory_bytes() {
+            Some(used) if used >= limit => problems.push(format!(
+                "resident memory {} bytes already above limits.max_memory_mb",
+                used
+            )),
+            Some(_) => {}
+            None => problems.push(
+                "resident memory cannot be measured, limits.max_memory_mb would not be enforced"
+                    .to_string(),
+            ),
+        }
+    }
+
+    if let Some(percent) = limits.cpu_quota_percent {
+        match cgroup_cpu_quota_percent() {
+            Some(quota) if quota <= percent => {
+                info!(quota_percent = quota, "CPU quota verified")
+            }
+            Some(quota) => problems.push(format!(
+                "cgroup CPU quota {:.0}% exceeds limits.cpu_quota_percent {:.0}%",
+                quota, percent
+            )),
+            None => problems.push(format!(
+                "no cgroup CPU quota found to enforce limits.cpu_quota_percent {:.0}%",
+                percent
+            )),
+        }
+    }
+
+    if problems.is_empty() {
+        return Ok(());
+    }
+    if limits.is_strict() {
+        return Err(LimitsError::NotHonored(problems.join("; ")));
+    }
+    for problem in &problems {
+        warn!(problem = %problem, "Resource limit not honored");
+    }
+    Ok(())
+}
+
+/// Resident memory of this process, if the platform reports it
+pub fn process_memory_bytes() -> Option<u64> {
+    let pid = sysinfo::get_current_pid().ok()?;
+    let mut system = sysinfo::System::new();
+    system.refresh_process(pid);
+    system.process(pid).map(|process| process.memory())
+}
+
+/// Thread IDs of this process; Linux applies priorities per thread and the
+/// runtime's worker threads already exist by the time limits are applied
+#[cfg(target_os = "linux")]
+fn thread_ids() -> std::io::Result<Vec<libc::id_t>> {
+    Ok(std::fs::read_dir("/proc/self/task")?
+        .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse().ok())
+        .collect())
+}
+
+#[cfg(target_os = "linux")]
+fn set_nice(nice: i32) -> std::io::Result<()> {
+    for tid in thread_ids()? {
+        // SAFETY: setpriority has no memory-safety preconditions
+        if unsafe { libc::setpriority(libc::PRIO_PROCESS, tid, nice) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+fn set_nice(nice: i32) -> std::io::Result<()> {
+    // SAFETY: setpriority has no memory-safety preconditions
+    if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, nice) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn set_nice(_nice: i32) -> std::io::Result<()> {
+    Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "only supported on Unix"))
+}
+
+#[cfg(target_os = "linux")]
+fn set_io_priority(class: IoClass, priority: u8) -> std::io::Result<()> {
+    const IOPRIO_WHO_PROCESS: libc::c_long = 1;
+    const IOPRIO_CLASS_SHIFT: u32 = 13;
+
+    let value = match class {
+        IoClass::BestEffort => (2 << IOPRIO_CLASS_SHIFT) | u32::from(priority),
+        IoClass::Idle => 3 << IOPRIO_CLASS_SHIFT,
+    };
+    for tid in thread_ids()? {
+        // SAFETY: ioprio_set takes plain integers
+        let result = unsafe {
+            libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, tid as libc::c_long, value as libc::c_long)
+        };
+        if result != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_io_priority(_class: IoClass, _priority: u8) -> std::io::Result<()> {
+    Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "only supported on Linux"))
+}
+
+/// Tightest cgroup v2 CPU quota over this process's cgroup and its ancestors,
+/// as a percentage of one CPU
+#[cfg(target_os = "linux")]
+fn cgroup_cpu_quota_percent() -> Option<f64> {
+    let membership = std::fs::read_to_string("/proc/self/cgroup").ok()?;
+    let path = membership.lines().find_map(|line| line.strip_prefix("0::"))?;
+
+    let mut dir = std::path::Path::new("/sys/fs/cgroup").join(path.trim_start_matches('/'));
+    let mut tightest: Option<f64> = None;
+    loop {
+        if let Some(percent) = std::fs::read_to_string(dir.join("cpu.max"))
+            .ok()
+            .and_then(|contents| parse_cpu_max(&contents))
+        {
+            tightest = Some(tightest.map_or(percent, |t| t.min(percent)));
+        }
+        if dir == std::path::Path::new("/sys/fs/cgroup") || !dir.pop() {
+            return tightest;
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn cgroup_cpu_quota_percent() -> Option<f64> {
+    None
+}
+
+/// Parse cgroup v2 `cpu.max` ("<quota> <period>" or "max <period>")
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn parse_cpu_max(contents: &str) -> Option<f64> {
+    let mut fields = contents.split_whitespace();
+    let quota: f64 = fields.next()?.parse().ok()?;
+    let period: f64 = fields.next()?.parse().ok()?;
+    (period > 0.0).then(|| quota / period * 100.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_cpu_max() {
+        assert_eq!(parse_cpu_max("20000 100000\n"), Some(20.0));
+        assert_eq!(parse_cpu_max("200000 100000"), Some(200.0));
+        assert_eq!(parse_cpu_max("max 100000\n"), None);
+        assert_eq!(parse_cpu_max(""), None);
+    }
+
+    #[test]
+    fn test_strict_refuses_unverifiable_quota() {
+        let yaml = r#"
+agent: {}
+api:
+  endpoint: "https://api.example.com"
+collection:
+  interval_seconds: 60
+  disk:
+    enabled: true
+limits:
+  cpu_quota_percent: 0.0001
+  max_memory_mb: 1048576
+  strict: true
+"#;
+        let config = Config::load_from_str(yaml).unwrap();
+        assert!(matches!(apply(&config), Err(LimitsError::NotHonored(_))));
+
+        let relaxed = Config::load_from_str(&yaml.replace("strict: true", "strict: false")).unwrap();
+        assert!(apply(&relaxed).is_ok());
+    }
+}
@@ -0,0 +1,259 @@
+//! One agent per state directory
+//!
+//! The running agent holds an exclusive lock on `agent.lock` in the state
+//! directory, so a second instance cannot report the same host twice or
+//! interleave writes to the state file and spool. The file also records the
+//! holder's PID, which `--force` uses to stop it and take over.
+//!
+//! `--force` asks the holder to shut down as on a normal stop, so it spools
+//! its buffer: with SIGTERM on Unix, and on Windows by setting a named event
+//! the holder waits on (see [`stop_requested`]).
+
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::{Read, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+pub const LOCK_FILE_NAME: &str = "agent.lock";
+
+/// How long `--force` waits for the running agent to exit
+const TAKEOVER_TIMEOUT: Duration = Duration::from_secs(30);
+const TAKEOVER_POLL: Duration = Duration::from_millis(250);
+
+#[derive(Error, Debug)]
+pub enum LockError {
+    #[error("failed to open lock file {path}: {message}")]
+    Io { path: String, message: String },
+    #[error("another agent (PID {pid}) is already running with state in {dir}; stop it or start with --force to take over")]
+    Held { dir: String, pid: String },
+    #[error("failed to stop the running agent (PID {pid}): {message}")]
+    Takeover { pid: String, message: String },
+}
+
+/// Held for the lifetime of the agent; dropping it releases the lock
+#[derive(Debug)]
+pub struct InstanceLock {
+    _file: File,
+}
+
+/// Lock `dir` for this process; with `force`, stop the current holder first
+pub async fn acquire(dir: &Path, force: bool) -> Result<InstanceLock, LockError> {
+    let path = dir.join(LOCK_FILE_NAME);
+    let io_error = |e: std::io::Error| LockError::Io {
+        path: path.display().to_string(),
+        message: e.to_string(),
+    };
+    std::fs::create_dir_all(dir).map_err(io_error)?;
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&path)
+        .map_err(io_error)?;
+
+    if !try_lock(&file).map_err(io_error)? {
+        let pid = holder_pid(&mut file);
+        if !force {
+            return Err(LockError::Held {
+                dir: dir.display().to_string(),
+                pid,
+            });
+        }
+        take_over(&file, &pid).await.map_err(|message| LockError::Takeover { pid, message })?;
+    }
+
+    file.set_len(0).map_err(io_error)?;
+    file.rewind().map_err(io_error)?;
+    write!(file, "{}", std::process::id()).map_err(io_error)?;
+    file.flush().map_err(io_error)?;
+    #[cfg(windows)]
+    stop_event::listen();
+    Ok(InstanceLock { _file: file })
+}
+
+/// Lock the directory of the state file
+pub async fn acquire_state_dir(force: bool) -> Result<InstanceLock, LockError> {
+    acquire(&state_dir(), force).await
+}
+
+/// Resolve once another agent started with `--force` asks this one to stop
+///
+/// Only Windows needs this; on Unix the request arrives as SIGTERM.
+pub async fn stop_requested() {
+    #[cfg(windows)]
+    stop_event::STOP.notified().await;
+
+    #[cfg(not(windows))]
+    std::future::pending::<()>().await;
+}
+
+fn state_dir() -> PathBuf {
+    crate::state::ResourceState::get_state_file_path()
+        .parent()
+        .map(Path::to_path_buf)
+        .unwrap_or_else(|| PathBuf::from("."))
+}
+
+/// `Ok(false)` when another process holds the lock
+fn try_lock(file: &File) -> std::io::Result<bool> {
+    match file.try_lock() {
+        Ok(()) => Ok(true),
+        Err(TryLockError::WouldBlock) => Ok(false),
+        Err(TryLockError::Error(e)) => Err(e),
+    }
+}
+
+fn holder_pid(file: &mut File) -> String {
+    let mut contents = String::new();
+    let _ = file.rewind().and_then(|_| file.read_to_string(&mut contents));
+    match contents.trim() {
+        "" => "unknown".to_string(),
+        pid => pid.to_string(),
+    }
+}
+
+async fn take_over(file: &File, pid: &str) -> Result<(), String> {
+    let pid: u32 = pid
+        .parse()
+        .map_err(|_| "the lock file does not name a process".to_string())?;
+    if pid == std::process::id() {
+        return Err("the lock is held by this process".to_string());
+    }
+    tracing::warn!(pid, "Stopping the running agent to take over (--force)");
+    terminate(pid)?;
+
+    let deadline = Instant::now() + TAKEOVER_TIMEOUT;
+    while Instant::now() < deadline {
+        if try_lock(file).map_err(|e| e.to_string())? {
+            return Ok(());
+        }
+        tokio::time::sleep(TAKEOVER_POLL).await;
+    }
+    Err(format!("still running after {} seconds", TAKEOVER_TIMEOUT.as_secs()))
+}
+
+/// Ask the agent to shut down, so it flushes and spools like on a normal stop
+#[cfg(unix)]
+fn terminate(pid: u32) -> Result<(), String> {
+    let pid = libc::pid_t::try_from(pid).map_err(|e| e.to_string())?;
+    // SAFETY: kill has no memory-safety preconditions
+    if unsafe { libc::kill(pid, libc::SIGTERM) } != 0 {
+        return Err(std::io::Error::last_os_error().to_string());
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+fn terminate(pid: u32) -> Result<(), String> {
+    use windows_sys::Win32::Foundation::CloseHandle;
+    use windows_sys::Win32::System::Threading::{OpenProcess, TerminateProcess, PROCESS_TERMINATE};
+
+    if stop_event::signal(pid) {
+        return Ok(());
+    }
+    // Agents from before the stop event cannot be asked, only killed
+    tracing::warn!(pid, "The running agent does not accept stop requests; terminating it without spooling");
+
+    // SAFETY: the handle is checked before use and closed afterwards
+    unsafe {
+        let process = OpenProcess(PROCESS_TERMINATE, 0, pid);
+        if process == 0 {
+            return Err(std::io::Error::last_os_error().to_string());
+        }
+        let terminated = TerminateProcess(process, 1);
+        CloseHandle(process);
+        if terminated == 0 {
+            return Err(std::io::Error::last_os_error().to_string());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(any(unix, windows)))]
+fn terminate(_pid: u32) -> Result<(), String> {
+    Err("--force is not supported on this platform".to_string())
+}
+
+/// The named event a Windows agent waits on for `--force` stop requests
+#[cfg(windows)]
+mod stop_event {
+    use tokio::sync::Notify;
+    use windows_sys::Win32::Foundation::CloseHandle;
+    use windows_sys::Win32::System::Threading::{
+        CreateEventW, OpenEventW, SetEvent, WaitForSingleObject, EVENT_MODIFY_STATE, INFINITE,
+    };
+
+    /// Notified once the event is set; the permit is kept until someone waits
+    pub static STOP: Notify = Notify::const_new();
+
+    /// A service creates the event in the global namespace; a console agent
+    /// may lack the privilege and falls back to its session's
+    fn names(pid: u32) -> [Vec<u16>; 2] {
+        ["Global", "Local"].map(|scope| {
+            format!("{}\\sentinel-agent-stop-{}", scope, pid)
+                .encode_utf16()
+                .chain(Some(0))
+                .collect()
+        })
+    }
+
+    /// Create this process's event and wait on it from a background thread
+    pub fn listen() {
+        // SAFETY: the names are NUL-terminated and outlive the calls; a null
+        // security descriptor gives the creator's default access
+        let event = names(std::process::id())
+            .iter()
+            .map(|name| unsafe { CreateEventW(std::ptr::null(), 1, 0, name.as_ptr()) })
+            .find(|&event| event != 0);
+        let Some(event) = event else {
+            tracing::warn!("Could not create the stop event; --force will terminate this agent");
+            return;
+        };
+        std::thread::spawn(move || {
+            // SAFETY: the handle stays open for the life of the process
+            unsafe { WaitForSingleObject(event, INFINITE) };
+            STOP.notify_one();
+        });
+    }
+
+    /// Set the stop event of `pid`; false when it has none
+    pub fn signal(pid: u32) -> bool {
+        names(pid).iter().any(|name| {
+            // SAFETY: the name is NUL-terminated; the handle is checked before use and closed afterwards
+            unsafe {
+                let event = OpenEventW(EVENT_MODIFY_STATE, 0, name.as_ptr());
+                if event == 0 {
+                    return false;
+                }
+                let set = SetEvent(event) != 0;
+                CloseHandle(event);
+                set
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_second_instance_is_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let lock = acquire(dir.path(), false).await.unwrap();
+        let recorded = std::fs::read_to_string(dir.path().join(LOCK_FILE_NAME)).unwrap();
+        assert_eq!(recorded, std::process::id().to_string());
+
+        match acquire(dir.path(), false).await {
+            Err(LockError::Held { pid, .. }) => assert_eq!(pid, std::process::id().to_string()),
+            other => panic!("expected the lock to be held, got {:?}", other),
+        }
+        // Never signals its own process
+        assert!(matches!(acquire(dir.path(), true).await, Err(LockError::Takeover { .. })));
+
+        drop(lock);
+        assert!(acquire(dir.path(), false).await.is_ok());
+    }
+}
@@ -0,0 +1,189 @@
+use chrono::Utc;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use crate::config::{LogFileConfig, LogRotation};
+
+/// Log file writer with size- and time-based rotation
+///
+/// Rotated files are renamed `<path>.1`, `<path>.2`, ... with `.1` the most
+/// recent; files beyond the retention count are deleted.
+pub struct RotatingFile {
+    path: PathBuf,
+    max_size_bytes: Option<u64>,
+    rotation: LogRotation,
+    retention: usize,
+    file: File,
+    size: u64,
+    period: i64,
+}
+
+impl RotatingFile {
+    pub fn open(config: &LogFileConfig) -> io::Result<Self> {
+        if let Some(parent) = config.path.parent() {
+            if !parent.as_os_str().is_empty() {
+                fs::create_dir_all(parent)?;
+            }
+        }
+
+        let file = Self::open_file(&config.path)?;
+        let size = file.metadata()?.len();
+        let rotation = config.get_rotation();
+
+        Ok(Self {
+            path: config.path.clone(),
+            max_size_bytes: config.get_max_size_bytes(),
+            rotation,
+            retention: config.get_retention(),
+            file,
+            size,
+            period: Self::current_period(rotation),
+        })
+    }
+
+    fn open_file(path: &Path) -> io::Result<File> {
+        OpenOptions::new().create(true).append(true).open(path)
+    }
+
+    /// Identifier of the current rotation window; a change triggers rotation
+    fn current_period(rotation: LogRotation) -> i64 {
+        let now = Utc::now().timestamp();
+        match rotation {
+            LogRotation::Hourly => now / 3600,
+            LogRotation::Daily => now / 86400,
+            LogRotation::Never => 0,
+        }
+    }
+
+    fn rotated_path(&self, index: usize) -> PathBuf {
+        let mut name = self.path.as_os_str().to_os_string();
+        name.push(format!(".{}", index));
+        PathBuf::from(name)
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+
+        if self.retention == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            let _ = fs::remove_file(self.rotated_path(self.retention));
+            for index in (1..self.retention).rev() {
+                let from = self.rotated_path(index);
+                if from.exists() {
+                    fs::rename(&from, self.rotated_path(index + 1))?;
+                }
+            }
+            fs::rename(&self.path, self.rotated_path(1))?;
+        }
+
+        self.file = Self::open_file(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+
+    fn should_rotate(&mut self, incoming: usize) -> bool {
+        let period = Self::current_period(self.rotation);
+        if period != self.period {
+            self.period = period;
+            return self.size > 0;
+        }
+
+        match self.max_size_bytes {
+            Some(max) => self.size > 0 && self.size + incoming as u64 > max,
+            None => false,
+        }
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.should_rotate(buf.len()) {
+            self.rotate()?;
+        }
+
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Cloneable handle so the file can be used as a tracing `MakeWriter`
+#[derive(Clone)]
+pub struct SharedRotatingFile(Arc<Mutex<RotatingFile>>);
+
+impl SharedRotatingFile {
+    pub fn new(file: RotatingFile) -> Self {
+        Self(Arc::new(Mutex::new(file)))
+    }
+}
+
+impl Write for SharedRotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut file = self.0.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        // Write whole records so a log line never straddles two files
+        file.write_all(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let mut file = self.0.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_size_rotation_and_retention() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("agent.log");
+        let config = LogFileConfig {
+            path: path.clone(),
+            max_size_mb: None,
+            rotation: None,
+            retention: Some(2),
+        };
+
+        let mut file = RotatingFile::open(&config).unwrap();
+        file.max_size_bytes = Some(10);
+        for line in ["first-log\n", "second-log\n", "third-log\n", "fourth-log\n"] {
+            file.write_all(line.as_bytes()).unwrap();
+        }
+        file.flush().unwrap();
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "fourth-log\n");
+        assert_eq!(fs::read_to_string(dir.path().join("agent.log.1")).unwrap(), "third-log\n");
+        assert_eq!(fs::read_to_string(dir.path().join("agent.log.2")).unwrap(), "second-log\n");
+        assert!(!dir.path().join("agent.log.3").exists());
+    }
+
+    #[test]
+    fn test_appends_to_existing_file() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("agent.log");
+        fs::write(&path, "existing\n").unwrap();
+
+        let config = LogFileConfig {
+            path: path.clone(),
+            max_size_mb: None,
+            rotation: Some(LogRotation::Never),
+            retention: None,
+        };
+
+        let mut file = SharedRotatingFile::new(RotatingFile::open(&config).unwrap());
+        file.write_all(b"new\n").unwrap();
+        file.flush().unwrap();
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "existing\nnew\n");
+    }
+}
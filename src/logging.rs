@@ -0,0 +1,209 @@
+use std::io::IsTerminal;
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, Layer, Registry};
+
+use crate::config::{LogFormat, LoggingConfig};
+use crate::log_file::{RotatingFile, SharedRotatingFile};
+
+/// Environment variable that overrides the configured log filter
+pub const LOG_ENV_VAR: &str = "SENTINEL_LOG";
+
+/// Build the filter directive string from config, e.g. `info,sentinel_agent::metadata=debug`
+pub fn filter_directives(config: &LoggingConfig) -> String {
+    let mut directives = vec![config.get_level()];
+
+    if let Some(filters) = &config.filters {
+        let mut modules: Vec<_> = filters.iter().collect();
+        modules.sort();
+        for (module, level) in modules {
+            directives.push(format!("{}={}", module, level));
+        }
+    }
+
+    directives.join(",")
+}
+
+/// Install the global tracing subscriber
+///
+/// `level` (from `--log-level`) takes precedence over `SENTINEL_LOG`, which
+/// takes precedence over the `logging` section of the config.
+pub fn init(config: &LoggingConfig, level: Option<&str>) -> Result<(), LoggingError> {
+    let filter = match (level, std::env::var(LOG_ENV_VAR)) {
+        (Some(level), _) => EnvFilter::try_new(level),
+        (None, Ok(directives)) => EnvFilter::try_new(directives),
+        (None, Err(_)) => EnvFilter::try_new(filter_directives(config)),
+    }
+    .map_err(|e| LoggingError::Filter(e.to_string()))?;
+
+    tracing_subscriber::registry()
+        .with(output_layer(config)?.with_filter(filter))
+        .with(console_layer(config))
+        .try_init()
+        .map_err(|e| LoggingError::Init(e.to_string()))
+}
+
+type BoxedLayer = Box<dyn Layer<Registry> + Send + Sync>;
+
+/// The layer that writes log lines: journald, a rotating file or stdout
+fn output_layer(config: &LoggingConfig) -> Result<BoxedLayer, LoggingError> {
+    #[cfg(target_os = "linux")]
+    if use_journald(config) {
+        // Unprefixed fields so span/event fields map to e.g. RESOURCE_ID and COLLECTOR
+        let layer = tracing_journald::layer()
+            .map_err(|e| LoggingError::Journald(e.to_string()))?
+            .with_field_prefix(None)
+            .with_syslog_identifier("sentinel-agent".to_string());
+        return Ok(layer.boxed());
+    }
+
+    let (writer, ansi) = match &config.file {
+        Some(file_config) => {
+            let file = RotatingFile::open(file_config).map_err(|e| LoggingError::File {
+                path: file_config.path.to_string_lossy().to_string(),
+                error: e.to_string(),
+            })?;
+            let shared = SharedRotatingFile::new(file);
+            (BoxMakeWriter::new(move || shared.clone()), false)
+        }
+        None => (
+            BoxMakeWriter::new(std::io::stdout),
+            std::io::stdout().is_terminal(),
+        ),
+    };
+
+    let layer = tracing_subscriber::fmt::layer().with_writer(writer);
+
+    Ok(match config.get_format() {
+        LogFormat::Json => layer.json().with_current_span(false).boxed(),
+        LogFormat::Text => layer.with_ansi(ansi).boxed(),
+    })
+}
+
+/// tokio-console instrumentation, when enabled
+///
+/// Sits beside the log filter rather than behind it, since the console needs
+/// tokio's trace-level runtime spans.
+#[cfg(feature = "tokio-console")]
+fn console_layer<S>(config: &LoggingConfig) -> Option<impl Layer<S>>
+where
+    S: tracing::Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>,
+{
+    config
+        .tokio_console
+        .unwrap_or(false)
+        .then(|| console_subscriber::ConsoleLayer::builder().with_default_env().spawn())
+}
+
+#[cfg(not(feature = "tokio-console"))]
+fn console_layer<S>(_config: &LoggingConfig) -> Option<impl Layer<S>>
+where
+    S: tracing::Subscriber,
+{
+    None::<tracing_subscriber::layer::Identity>
+}
+
+/// Install the global tracing subscriber for the Windows service
+///
+/// Services have no console, so logs go to the Application event log unless a
+/// log file is configured.
+#[cfg(windows)]
+pub fn init_event_log(config: &LoggingConfig) -> Result<(), LoggingError> {
+    if config.file.is_some() {
+        return init(config);
+    }
+
+    let filter = match std::env::var(LOG_ENV_VAR) {
+        Ok(directives) => EnvFilter::try_new(directives),
+        Err(_) => EnvFilter::try_new(filter_directives(config)),
+    }
+    .map_err(|e| LoggingError::Filter(e.to_string()))?;
+
+    let layer =
+        crate::eventlog::EventLogLayer::new().map_err(|e| LoggingError::EventLog(e.to_string()))?;
+
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(layer)
+        .try_init()
+        .map_err(|e| LoggingError::Init(e.to_string()))
+}
+
+/// Whether to log to journald
+///
+/// An explicit `logging.journald` setting wins; otherwise journald is used when
+/// systemd has connected our output to the journal and no log file is configured.
+#[cfg(target_os = "linux")]
+pub fn use_journald(config: &LoggingConfig) -> bool {
+    match config.journald {
+        Some(enabled) => enabled,
+        None => config.file.is_none() && std::env::var_os("JOURNAL_STREAM").is_some(),
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum LoggingError {
+    #[error("Invalid log filter: {0}")]
+    Filter(String),
+    #[cfg(target_os = "linux")]
+    #[error("Failed to connect to journald: {0}")]
+    Journald(String),
+    #[cfg(windows)]
+    #[error("Failed to register event log source: {0}")]
+    EventLog(String),
+    #[error("Failed to open log file {path}: {error}")]
+    File { path: String, error: String },
+    #[error("Failed to initialize logging: {0}")]
+    Init(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_filter_directives() {
+        let config = LoggingConfig {
+            level: Some("warn".to_string()),
+            format: None,
+            filters: Some(HashMap::from([
+                ("sentinel_agent::metadata".to_string(), "debug".to_string()),
+                ("reqwest".to_string(), "error".to_string()),
+            ])),
+            file: None,
+            journald: None,
+            tokio_console: None,
+        };
+
+        let directives = filter_directives(&config);
+        assert_eq!(
+            directives,
+            "warn,reqwest=error,sentinel_agent::metadata=debug"
+        );
+        assert!(EnvFilter::try_new(directives).is_ok());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_use_journald_explicit_setting_wins() {
+        let config = LoggingConfig {
+            journald: Some(false),
+            ..Default::default()
+        };
+        assert!(!use_journald(&config));
+
+        let config = LoggingConfig {
+            journald: Some(true),
+            ..Default::default()
+        };
+        assert!(use_journald(&config));
+    }
+
+    #[test]
+    fn test_filter_directives_default() {
+        let config = LoggingConfig::default();
+        assert_eq!(filter_directives(&config), "info");
+    }
+}
@@ -1,53 +1,59 @@
-mod agent;
-mod client;
-mod config;
-mod metadata;
-mod metrics;
-mod state;
+use sentinel_agent::{
+    agent, build_info, client, config, diagnose, encryption, fingerprint, init, limits, lock, logging,
+    maintenance, metadata, paths, privileges, secrets, state, state_bundle, status,
+    token_store,
+};
+#[cfg(feature = "bench")]
+use sentinel_agent::{bench, metrics};
+#[cfg(target_os = "macos")]
+use sentinel_agent::launchd;
+#[cfg(windows)]
+use sentinel_agent::winservice;
 
-use clap::{Arg, Command};
-use std::path::PathBuf;
+use clap::{Arg, ArgAction, Command};
+use std::io::Write;
+use std::path::{Path, PathBuf};
 
 use agent::SentinelAgent;
 use config::Config;
 
+/// jemalloc with heap profiling, so `/debug/pprof/heap` can dump sampled allocations
+#[cfg(all(feature = "profiling", unix))]
+#[global_allocator]
+static ALLOCATOR: tikv_jemallocator::Jemalloc = tikv_jemallocator::Jemalloc;
+
 fn find_default_config_path() -> PathBuf {
-    // Priority order for config file locations:
-    let candidates = vec![
-        // 1. XDG config directory (industry standard, highest priority)
-        dirs::home_dir().map(|dir| dir.join(".config").join("operion").join("agent.yaml")),
-        // 2. Platform-specific config directory (fallback)
-        dirs::config_dir().map(|dir| dir.join("operion").join("agent.yaml")),
-        // 3. System-wide config
-        Some(PathBuf::from("/etc/operion/agent.yaml")),
-        // 4. Current directory (development/testing)
-        Some(PathBuf::from("agent.yaml")),
-    ];
-
-    // Return the first config file that exists
-    for candidate in candidates {
-        if let Some(path) = candidate {
-            if path.exists() {
-                return path;
-            }
+    // Return the first config file that exists, in priority order
+    let candidates = paths::config_file_candidates();
+    for path in &candidates {
+        if path.exists() {
+            return path.clone();
         }
     }
 
-    // If no config file exists, prefer XDG config directory
-    if let Some(home_dir) = dirs::home_dir() {
-        home_dir.join(".config").join("operion").join("agent.yaml")
-    } else if let Some(config_dir) = dirs::config_dir() {
-        config_dir.join("operion").join("agent.yaml")
-    } else {
-        // Fallback to system location
-        PathBuf::from("/etc/operion/agent.yaml")
+    // If no config file exists, prefer the user config directory
+    candidates
+        .into_iter()
+        .next()
+        .unwrap_or_else(|| paths::system_config_dir().join(paths::CONFIG_FILE_NAME))
+}
+
+fn print_config_not_found(config_path: &Path) {
+    eprintln!("Configuration file not found: {}", config_path.display());
+    eprintln!();
+    eprintln!("Sentinel Agent looks for configuration files in this order:");
+    for (index, candidate) in paths::config_file_candidates().iter().enumerate() {
+        eprintln!("  {}. {}", index + 1, candidate.display());
     }
+    eprintln!();
+    eprintln!("Create a configuration file in one of these locations, or specify a path with --config");
+    eprintln!("Run `sentinel-agent init` to generate a commented default configuration");
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let matches = Command::new("Operion Sentinel Agent")
-        .version("0.1.0")
+    let command = Command::new("Operion Sentinel Agent")
+        .version(env!("CARGO_PKG_VERSION"))
         .about("Operion monitoring agent for system metrics")
         .arg(
             Arg::new("config")
@@ -55,36 +61,648 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 .long("config")
                 .value_name("FILE")
                 .help("Configuration file path (auto-detected if not specified)")
+                .global(true)
+                .value_parser(clap::value_parser!(PathBuf)),
+        )
+        .arg(
+            Arg::new("endpoint")
+                .long("endpoint")
+                .value_name("URL")
+                .env("SENTINEL_ENDPOINT")
+                .help("Override api.endpoint")
+                .global(true),
+        )
+        .arg(
+            Arg::new("interval")
+                .long("interval")
+                .value_name("SECONDS")
+                .env("SENTINEL_INTERVAL")
+                .help("Override collection.interval_seconds")
+                .global(true)
+                .value_parser(clap::value_parser!(u64).range(1..)),
+        )
+        .arg(
+            Arg::new("api-key-file")
+                .long("api-key-file")
+                .value_name("FILE")
+                .env("SENTINEL_API_KEY_FILE")
+                .help("Read api.api_key from a file, e.g. a container secret")
+                .global(true)
                 .value_parser(clap::value_parser!(PathBuf)),
         )
-        .get_matches();
+        .arg(
+            Arg::new("log-level")
+                .long("log-level")
+                .value_name("LEVEL")
+                .help("Override logging.level, taking precedence over SENTINEL_LOG")
+                .global(true),
+        )
+        .arg(
+            Arg::new("hostname")
+                .long("hostname")
+                .value_name("NAME")
+                .env("SENTINEL_HOSTNAME")
+                .help("Override agent.hostname")
+                .global(true),
+        )
+        .arg(
+            Arg::new("force")
+                .long("force")
+                .help("Stop an agent already running with the same state directory and take over")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("service")
+                .long("service")
+                .help("Run under the Windows Service Control Manager")
+                .action(ArgAction::SetTrue),
+        )
+        .subcommand(
+            Command::new("init")
+                .about("Write a commented default configuration file")
+                .arg(
+                    Arg::new("endpoint")
+                        .long("endpoint")
+                        .value_name("URL")
+                        .help("API endpoint to write into the config"),
+                )
+                .arg(
+                    Arg::new("api-key")
+                        .long("api-key")
+                        .value_name("KEY")
+                        .help("API key to write into the config"),
+                )
+                .arg(
+                    Arg::new("force")
+                        .long("force")
+                        .help("Overwrite an existing configuration file")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("no-prompt")
+                        .long("no-prompt")
+                        .help("Do not ask for missing values")
+                        .action(ArgAction::SetTrue),
+                ),
+        )
+        .subcommand(
+            Command::new("version")
+                .about("Show version and build information")
+                .arg(
+                    Arg::new("json")
+                        .long("json")
+                        .help("Print build information as JSON")
+                        .action(ArgAction::SetTrue),
+                ),
+        )
+        .subcommand(
+            Command::new("status")
+                .about("Show the state of the running agent")
+                .arg(
+                    Arg::new("json")
+                        .long("json")
+                        .help("Print status as JSON")
+                        .action(ArgAction::SetTrue),
+                ),
+        )
+        .subcommand(
+            Command::new("pause")
+                .about("Enter maintenance mode: pause collection and sending")
+                .arg(
+                    Arg::new("duration")
+                        .long("duration")
+                        .value_name("DURATION")
+                        .help("Resume automatically after e.g. 30m, 1h, 2d (default: until resumed)"),
+                )
+                .arg(
+                    Arg::new("reason")
+                        .long("reason")
+                        .value_name("TEXT")
+                        .help("Why the agent is paused"),
+                ),
+        )
+        .subcommand(
+            Command::new("resume")
+                .about("Leave maintenance mode"),
+        )
+        .subcommand(
+            Command::new("sync")
+                .about("Upload metrics spooled while offline, then exit"),
+        )
+        .subcommand(
+            Command::new("login")
+                .about("Authorize this host with api.oauth in a browser (device authorization)"),
+        )
+        .subcommand(
+            Command::new("config")
+                .about("Configuration file tools")
+                .subcommand_required(true)
+                .subcommand(
+                    Command::new("schema")
+                        .about("Print the JSON Schema of the configuration file"),
+                )
+                .subcommand(
+                    Command::new("generate-key")
+                        .about("Print a new key for encrypted config values"),
+                )
+                .subcommand(Command::new("encrypt").about(
+                    "Encrypt a value read from stdin with SENTINEL_CONFIG_KEY(_FILE), for use in the config file",
+                )),
+        )
+        .subcommand(
+            Command::new("state")
+                .about("Move the registration to another host or back it up")
+                .subcommand_required(true)
+                .subcommand(
+                    Command::new("export")
+                        .about("Write the registration state and agent key as a portable bundle")
+                        .arg(
+                            Arg::new("output")
+                                .short('o')
+                                .long("output")
+                                .value_name("FILE")
+                                .help("Write the bundle to a file (default: stdout)")
+                                .value_parser(clap::value_parser!(PathBuf)),
+                        )
+                        .arg(
+                            Arg::new("encrypt")
+                                .long("encrypt")
+                                .help("Encrypt the bundle with SENTINEL_CONFIG_KEY(_FILE)")
+                                .action(ArgAction::SetTrue),
+                        ),
+                )
+                .subcommand(
+                    Command::new("import")
+                        .about("Install an exported bundle as this host's registration (stop the agent first)")
+                        .arg(
+                            Arg::new("file")
+                                .value_name("FILE")
+                                .help("Bundle to import, - for stdin")
+                                .required(true)
+                                .value_parser(clap::value_parser!(PathBuf)),
+                        )
+                        .arg(
+                            Arg::new("force")
+                                .long("force")
+                                .help("Replace an existing registration")
+                                .action(ArgAction::SetTrue),
+                        ),
+                ),
+        )
+        .subcommand(
+            Command::new("check-config")
+                .about("Validate the configuration file and show effective settings"),
+        )
+        .subcommand(
+            Command::new("diagnose")
+                .about("Check config, permissions and connectivity, printing pass/fail per check"),
+        )
+        .subcommand(
+            Command::new("install-service")
+                .about("Install the agent as a system service"),
+        )
+        .subcommand(
+            Command::new("uninstall-service")
+                .about("Stop and remove the agent system service"),
+        );
+    // Only builds with the `bench` feature carry the collector timings
+    #[cfg(feature = "bench")]
+    let command = command.arg(
+        Arg::new("bench-collectors")
+            .long("bench-collectors")
+            .value_name("RUNS")
+            .help("Time each configured collector and batch serialization, then exit")
+            .hide(true)
+            .num_args(0..=1)
+            .default_missing_value("20")
+            .value_parser(clap::value_parser!(u64).range(1..)),
+    );
+    let matches = command.get_matches();
+
+    if let Some(("init", init_matches)) = matches.subcommand() {
+        // Without --config, write to the per-user location rather than an existing file
+        let path = matches
+            .get_one::<PathBuf>("config")
+            .cloned()
+            .or_else(|| paths::user_config_dir().map(|dir| dir.join(paths::CONFIG_FILE_NAME)))
+            .unwrap_or_else(|| paths::system_config_dir().join(paths::CONFIG_FILE_NAME));
+        let options = init::InitOptions {
+            endpoint: init_matches.get_one::<String>("endpoint").cloned(),
+            api_key: init_matches.get_one::<String>("api-key").cloned(),
+            force: init_matches.get_flag("force"),
+            prompt: !init_matches.get_flag("no-prompt"),
+        };
+        if let Err(e) = init::write_config(&path, options) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        println!("Wrote configuration to {}", path.display());
+        return Ok(());
+    }
+
+    if let Some(("config", config_matches)) = matches.subcommand() {
+        match config_matches.subcommand() {
+            Some(("schema", _)) => {
+                println!("{}", serde_json::to_string_pretty(&Config::json_schema())?)
+            }
+            Some(("generate-key", _)) => println!("{}", encryption::ConfigKey::generate()),
+            Some(("encrypt", _)) => encrypt_value()?,
+            _ => {}
+        }
+        return Ok(());
+    }
 
     let config_path = if let Some(config_path) = matches.get_one::<PathBuf>("config") {
         config_path.clone()
     } else {
         find_default_config_path()
     };
+    let overrides = config::Overrides {
+        endpoint: matches.get_one::<String>("endpoint").cloned(),
+        interval_seconds: matches.get_one::<u64>("interval").copied(),
+        api_key_file: matches.get_one::<PathBuf>("api-key-file").cloned(),
+        log_level: matches.get_one::<String>("log-level").cloned(),
+        hostname: matches.get_one::<String>("hostname").cloned(),
+    };
 
-    if !config_path.exists() {
-        eprintln!("Configuration file not found: {}", config_path.display());
-        eprintln!("");
-        eprintln!("Sentinel Agent looks for configuration files in this order:");
-        if let Some(home_dir) = dirs::home_dir() {
-            eprintln!("  1. {}", home_dir.join(".config").join("operion").join("agent.yaml").display());
+    if let Some(("state", state_matches)) = matches.subcommand() {
+        let result = match state_matches.subcommand() {
+            Some(("export", export_matches)) => export_state(
+                export_matches.get_one::<PathBuf>("output").map(PathBuf::as_path),
+                export_matches.get_flag("encrypt"),
+            ),
+            Some(("import", import_matches)) => {
+                let file = import_matches.get_one::<PathBuf>("file").expect("required argument");
+                import_state(file, &config_path, &overrides, import_matches.get_flag("force")).await
+            }
+            _ => Ok(()),
+        };
+        if let Err(e) = result {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    match matches.subcommand() {
+        Some(("version", version_matches)) => {
+            let info = build_info::BuildInfo::current();
+            if version_matches.get_flag("json") {
+                println!("{}", serde_json::to_string_pretty(&info)?);
+            } else {
+                println!("{}", info);
+            }
+            return Ok(());
+        }
+        Some(("status", status_matches)) => {
+            return show_status(&config_path, status_matches.get_flag("json")).await
+        }
+        Some(("pause", pause_matches)) => {
+            let duration = pause_matches
+                .get_one::<String>("duration")
+                .map(|value| maintenance::parse_duration(value))
+                .transpose()?;
+            let path = maintenance_file(&config_path);
+            let file = maintenance::pause(
+                &path,
+                duration,
+                pause_matches.get_one::<String>("reason").cloned(),
+            )?;
+            match file.until {
+                Some(until) => println!("Maintenance mode until {} ({})", until, path.display()),
+                None => println!("Maintenance mode until resumed ({})", path.display()),
+            }
+            return Ok(());
         }
-        if let Some(config_dir) = dirs::config_dir() {
-            eprintln!("  2. {}", config_dir.join("operion").join("agent.yaml").display());
+        Some(("resume", _)) => {
+            let path = maintenance_file(&config_path);
+            maintenance::resume(&path)?;
+            println!("Maintenance mode ended ({})", path.display());
+            return Ok(());
         }
-        eprintln!("  3. /etc/operion/agent.yaml");
-        eprintln!("  4. ./agent.yaml");
-        eprintln!("");
-        eprintln!("Create a configuration file in one of these locations, or specify a path with --config");
+        Some(("sync", _)) => return sync_spool(&config_path, &overrides).await,
+        Some(("login", _)) => return login(&config_path, &overrides).await,
+        Some(("check-config", _)) => check_config(&config_path, &overrides),
+        Some(("diagnose", _)) => run_diagnostics(&config_path, &overrides).await,
+        Some(("install-service", _)) => return install_service(config_path),
+        Some(("uninstall-service", _)) => return uninstall_service(),
+        _ => {}
+    }
+
+    if !config_path.exists() {
+        print_config_not_found(&config_path);
         std::process::exit(1);
     }
 
-    let config = Config::load_from_file(&config_path)?;
+    #[cfg(feature = "bench")]
+    if let Some(&runs) = matches.get_one::<u64>("bench-collectors") {
+        return bench_collectors(&config_path, &overrides, runs as usize).await;
+    }
+
+    if matches.get_flag("service") {
+        return run_as_service(config_path);
+    }
+
+    let mut config = Config::load_from_file(&config_path)?;
+    config.apply_overrides(&overrides)?;
+    logging::init(&config.get_logging(), overrides.log_level.as_deref())?;
+    // Before dropping privileges and with logging up, so failures are reported
+    secrets::resolve(&mut config).await?;
+    // Before dropping privileges, since raising priority back needs root
+    limits::apply(&config)?;
+    let (user, group) = (config.agent.user.clone(), config.agent.group.clone());
+    // Held until exit, so a second agent cannot double-report this host
+    let _lock = lock::acquire_state_dir(matches.get_flag("force")).await.unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    });
     let mut agent = SentinelAgent::new(config)?;
+    // Log file, journald socket and spool are open; give up root before running
+    privileges::drop_privileges(user.as_deref(), group.as_deref())?;
     agent.run().await?;
 
     Ok(())
 }
+
+/// Read a secret from stdin (so it stays out of shell history) and print it encrypted
+fn encrypt_value() -> Result<(), Box<dyn std::error::Error>> {
+    let key = encryption::ConfigKey::from_env()?.ok_or(encryption::EncryptionError::MissingKey)?;
+    let mut value = String::new();
+    std::io::stdin().read_line(&mut value)?;
+    let value = value.trim_end_matches(['\r', '\n']);
+    if value.is_empty() {
+        return Err("no value given on stdin".into());
+    }
+    println!("{}", key.encrypt(value));
+    Ok(())
+}
+
+/// Print or write the registration bundle; files are owner-only since they hold the agent key
+fn export_state(output: Option<&Path>, encrypt: bool) -> Result<(), Box<dyn std::error::Error>> {
+    let key = if encrypt {
+        Some(encryption::ConfigKey::from_env()?.ok_or(encryption::EncryptionError::MissingKey)?)
+    } else {
+        None
+    };
+    let bundle = state_bundle::StateBundle::export()?;
+    let encoded = bundle.encode(key.as_ref())?;
+    let Some(path) = output else {
+        println!("{}", encoded);
+        return Ok(());
+    };
+
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    writeln!(options.open(path)?, "{}", encoded)?;
+    eprintln!("Exported resource {} to {}", bundle.state.resource_id, path.display());
+    Ok(())
+}
+
+/// Install a bundle while no agent runs, adopting this machine's identifiers
+///
+/// Cloud metadata is detected with the config's `metadata` settings when
+/// there is a config, so pinned providers and endpoints apply as in the agent.
+async fn import_state(
+    file: &Path,
+    config_path: &Path,
+    overrides: &config::Overrides,
+    force: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let text = if file == Path::new("-") {
+        std::io::read_to_string(std::io::stdin())?
+    } else {
+        std::fs::read_to_string(file)?
+    };
+    let bundle = state_bundle::StateBundle::decode(&text, encryption::ConfigKey::from_env)?;
+    let metadata_settings = if config_path.exists() {
+        let mut config = Config::load_from_file(config_path)?;
+        config.apply_overrides(overrides)?;
+        config.get_metadata()
+    } else {
+        config::MetadataConfig::default()
+    };
+    let _lock = lock::acquire_state_dir(false).await?;
+    let exported_from = bundle.hostname.clone();
+    let state = bundle.install(
+        fingerprint::MachineFingerprint::detect(),
+        metadata::InstanceMetadata::detect(&metadata_settings).await,
+        force,
+    )?;
+    println!(
+        "Imported resource {} (exported from {}) to {}",
+        state.resource_id,
+        exported_from,
+        state::ResourceState::get_state_file_path().display()
+    );
+    Ok(())
+}
+
+/// Maintenance touch-file the running agent watches
+fn maintenance_file(config_path: &Path) -> PathBuf {
+    Config::load_from_file(config_path)
+        .map(|config| config.get_maintenance_file())
+        .unwrap_or_else(|_| maintenance::default_path())
+}
+
+/// Upload the spool in one go, e.g. after bringing an offline host's backlog online
+async fn sync_spool(
+    config_path: &Path,
+    overrides: &config::Overrides,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut config = Config::load_from_file(config_path)?;
+    config.apply_overrides(overrides)?;
+    logging::init(&config.get_logging(), overrides.log_level.as_deref())?;
+    secrets::resolve(&mut config).await?;
+    let _lock = lock::acquire_state_dir(false).await?;
+    let mut agent = SentinelAgent::new(config)?;
+    let delivered = agent.sync().await?;
+    println!("Uploaded {} spooled metrics", delivered);
+    Ok(())
+}
+
+/// Device authorization: print the code to approve in a browser and wait for the tokens
+async fn login(config_path: &Path, overrides: &config::Overrides) -> Result<(), Box<dyn std::error::Error>> {
+    let mut config = Config::load_from_file(config_path)?;
+    config.apply_overrides(overrides)?;
+    logging::init(&config.get_logging(), overrides.log_level.as_deref())?;
+    // The token cache key may be a secret reference
+    secrets::resolve(&mut config).await?;
+    // A system config belongs to the service, which reads the tokens from the system state
+    // directory; without this, login run as another user would save them to that user's
+    if config_path.starts_with(paths::system_config_dir()) {
+        if let Some(oauth) = config.api.oauth.as_mut() {
+            let token_cache = oauth.token_cache.get_or_insert_with(Default::default);
+            token_cache.path.get_or_insert_with(token_store::service_path);
+        }
+    }
+    let client = client::ApiClient::new(&config)?;
+    let oauth = client.oauth().ok_or("api.oauth is not configured")?;
+
+    let authorization = oauth.start_device_authorization().await?;
+    match &authorization.verification_uri_complete {
+        Some(uri) => println!("Open {} to authorize this agent", uri),
+        None => println!("Open {} to authorize this agent", authorization.verification_uri),
+    }
+    println!("and confirm the code: {}", authorization.user_code);
+    println!("Waiting for approval (the code expires in {}s)...", authorization.expires_in);
+
+    oauth.complete_device_authorization(&authorization).await?;
+    println!("Logged in; tokens saved to {}", oauth.token_store());
+    Ok(())
+}
+
+/// Exit nonzero when the config cannot be used, so CI can gate rollouts on it
+fn check_config(config_path: &Path, overrides: &config::Overrides) -> ! {
+    let mut report = match Config::check_file(config_path).and_then(|mut report| {
+        report.config.apply_overrides(overrides)?;
+        Ok(report)
+    }) {
+        Ok(report) => report,
+        Err(e) => {
+            eprintln!("{}: {}", config_path.display(), e);
+            std::process::exit(1);
+        }
+    };
+
+    let clamped = report.config.clamp_limits();
+
+    println!("Configuration: {}", config_path.display());
+    for fragment in config::drop_in_files(&config::drop_in_dir(config_path)) {
+        println!("  merged: {}", fragment.display());
+    }
+    println!();
+    println!("Effective settings:");
+    for (key, value) in report.config.resolved_settings() {
+        println!("  {}: {}", key, value);
+    }
+
+    if !report.deprecations.is_empty() || !report.unknown_keys.is_empty() || !clamped.is_empty() {
+        println!();
+        println!("Warnings:");
+        for deprecation in &report.deprecations {
+            println!("  {}", deprecation);
+        }
+        for warning in &clamped {
+            println!("  {}", warning);
+        }
+        for key in config::describe_unknown_keys(&report.unknown_keys) {
+            println!("  Unknown key ignored: {}", key);
+        }
+    }
+
+    println!();
+    println!("Configuration OK");
+    std::process::exit(0);
+}
+
+/// Print collector latency and serialization throughput for this host's configuration
+#[cfg(feature = "bench")]
+async fn bench_collectors(
+    config_path: &Path,
+    overrides: &config::Overrides,
+    runs: usize,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut config = Config::load_from_file(config_path)?;
+    config.apply_overrides(overrides)?;
+    let service = metrics::MetricService::new(&config);
+    println!("{}", bench::run(&service, runs).await);
+    Ok(())
+}
+
+async fn run_diagnostics(config_path: &Path, overrides: &config::Overrides) -> ! {
+    let results = diagnose::run_checks(config_path, overrides).await;
+    for result in &results {
+        println!("{}", result);
+    }
+
+    let failed = results
+        .iter()
+        .filter(|r| r.status == diagnose::CheckStatus::Fail)
+        .count();
+    println!();
+    if failed > 0 {
+        println!("{} check(s) failed", failed);
+        std::process::exit(1);
+    }
+    println!("All checks passed");
+    std::process::exit(0);
+}
+
+async fn show_status(config_path: &Path, json: bool) -> Result<(), Box<dyn std::error::Error>> {
+    // Fall back to the default address so status works without a readable config
+    let status_config = Config::load_from_file(config_path)
+        .map(|config| config.get_status())
+        .unwrap_or_default();
+    if !status_config.is_enabled() {
+        return Err("Status endpoint is disabled in the configuration (status.enabled: false)".into());
+    }
+
+    let status = status::fetch(&status_config.get_listen_address()).await?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&status)?);
+    } else {
+        print!("{}", status.render_text());
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+fn run_as_service(config_path: PathBuf) -> Result<(), Box<dyn std::error::Error>> {
+    // The SCM runs the agent on its own thread with a dedicated runtime
+    tokio::task::block_in_place(|| winservice::run(config_path))?;
+    Ok(())
+}
+
+#[cfg(not(windows))]
+fn run_as_service(_config_path: PathBuf) -> Result<(), Box<dyn std::error::Error>> {
+    Err("--service is only supported on Windows; use install-service (launchd) or the systemd unit on this platform".into())
+}
+
+#[cfg(windows)]
+fn install_service(config_path: PathBuf) -> Result<(), Box<dyn std::error::Error>> {
+    let config_path = std::fs::canonicalize(&config_path).unwrap_or(config_path);
+    winservice::install(config_path.clone())?;
+    println!("Installed service {} using {}", winservice::SERVICE_NAME, config_path.display());
+    Ok(())
+}
+
+#[cfg(target_os = "macos")]
+fn install_service(config_path: PathBuf) -> Result<(), Box<dyn std::error::Error>> {
+    let config_path = std::fs::canonicalize(&config_path).unwrap_or(config_path);
+    launchd::install(&config_path)?;
+    println!(
+        "Installed launchd daemon {} using {}",
+        launchd::LABEL,
+        config_path.display()
+    );
+    Ok(())
+}
+
+#[cfg(not(any(windows, target_os = "macos")))]
+fn install_service(_config_path: PathBuf) -> Result<(), Box<dyn std::error::Error>> {
+    Err("install-service is not supported on this platform; use install.sh to set up systemd".into())
+}
+
+#[cfg(windows)]
+fn uninstall_service() -> Result<(), Box<dyn std::error::Error>> {
+    winservice::uninstall()?;
+    println!("Removed service {}", winservice::SERVICE_NAME);
+    Ok(())
+}
+
+#[cfg(target_os = "macos")]
+fn uninstall_service() -> Result<(), Box<dyn std::error::Error>> {
+    launchd::uninstall()?;
+    println!("Removed launchd daemon {}", launchd::LABEL);
+    Ok(())
+}
+
+#[cfg(not(any(windows, target_os = "macos")))]
+fn uninstall_service() -> Result<(), Box<dyn std::error::Error>> {
+    Err("uninstall-service is not supported on this platform".into())
+}
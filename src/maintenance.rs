@@ -0,0 +1,173 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::state::ResourceState;
+
+/// Touching this file next to the state file pauses the agent until it is removed
+pub const MAINTENANCE_FILE_NAME: &str = "maintenance";
+
+/// Contents written by `sentinel-agent pause`; an empty file means "until resumed"
+#[derive(Debug, Default, Serialize, Deserialize, PartialEq)]
+pub struct MaintenanceFile {
+    /// RFC 3339 time at which maintenance ends on its own
+    pub until: Option<String>,
+    pub reason: Option<String>,
+}
+
+/// Planned gap reported with the first batch sent after maintenance ends
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct MaintenanceWindow {
+    pub started_at: String,
+    pub ended_at: String,
+}
+
+pub fn default_path() -> PathBuf {
+    ResourceState::get_state_file_path()
+        .parent()
+        .map(|dir| dir.join(MAINTENANCE_FILE_NAME))
+        .unwrap_or_else(|| PathBuf::from(MAINTENANCE_FILE_NAME))
+}
+
+/// Whether the maintenance file exists and has not expired
+pub fn is_active(path: &Path, now: DateTime<Utc>) -> bool {
+    let Ok(contents) = fs::read_to_string(path) else {
+        return false;
+    };
+
+    if contents.trim().is_empty() {
+        return true;
+    }
+
+    // An unreadable file still pauses; the operator clearly meant something
+    let Ok(file) = serde_json::from_str::<MaintenanceFile>(&contents) else {
+        return true;
+    };
+
+    match file.until.as_deref().map(DateTime::parse_from_rfc3339) {
+        Some(Ok(until)) => now < until,
+        _ => true,
+    }
+}
+
+/// Start maintenance, for `duration` or until resumed
+pub fn pause(
+    path: &Path,
+    duration: Option<Duration>,
+    reason: Option<String>,
+) -> Result<MaintenanceFile, MaintenanceError> {
+    let until = match duration {
+        Some(duration) => {
+            let duration = chrono::Duration::from_std(duration)
+                .map_err(|e| MaintenanceError::InvalidDuration(e.to_string()))?;
+            Some((Utc::now() + duration).to_rfc3339())
+        }
+        None => None,
+    };
+    let file = MaintenanceFile { until, reason };
+
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        fs::create_dir_all(parent).map_err(|e| MaintenanceError::Io {
+            path: parent.display().to_string(),
+            error: e.to_string(),
+        })?;
+    }
+
+    let json = serde_json::to_string_pretty(&file)
+        .map_err(|e| MaintenanceError::Io {
+            path: path.display().to_string(),
+            error: e.to_string(),
+        })?;
+    fs::write(path, json).map_err(|e| MaintenanceError::Io {
+        path: path.display().to_string(),
+        error: e.to_string(),
+    })?;
+
+    Ok(file)
+}
+
+/// End maintenance; succeeds if the agent was not paused
+pub fn resume(path: &Path) -> Result<(), MaintenanceError> {
+    match fs::remove_file(path) {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(MaintenanceError::Io {
+            path: path.display().to_string(),
+            error: e.to_string(),
+        }),
+    }
+}
+
+/// Parse durations such as `90s`, `30m`, `1h` or `2d`
+pub fn parse_duration(value: &str) -> Result<Duration, MaintenanceError> {
+    let value = value.trim();
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+
+    let number: u64 = number
+        .parse()
+        .map_err(|_| MaintenanceError::InvalidDuration(value.to_string()))?;
+    let multiplier = match unit {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return Err(MaintenanceError::InvalidDuration(value.to_string())),
+    };
+
+    Ok(Duration::from_secs(number * multiplier))
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum MaintenanceError {
+    #[error("Invalid duration: {0} (expected e.g. 90s, 30m, 1h, 2d)")]
+    InvalidDuration(String),
+    #[error("Failed to update maintenance file {path}: {error}")]
+    Io { path: String, error: String },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("90").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_duration("30m").unwrap(), Duration::from_secs(1800));
+        assert_eq!(parse_duration("1h").unwrap(), Duration::from_secs(3600));
+        assert_eq!(parse_duration("2d").unwrap(), Duration::from_secs(172800));
+        assert!(parse_duration("1w").is_err());
+        assert!(parse_duration("h").is_err());
+    }
+
+    #[test]
+    fn test_pause_and_resume() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join(MAINTENANCE_FILE_NAME);
+        let now = Utc::now();
+
+        assert!(!is_active(&path, now));
+
+        pause(&path, Some(Duration::from_secs(3600)), Some("reboot".to_string())).unwrap();
+        assert!(is_active(&path, now));
+        assert!(!is_active(&path, now + chrono::Duration::hours(2)));
+
+        resume(&path).unwrap();
+        assert!(!is_active(&path, now));
+        resume(&path).unwrap();
+    }
+
+    #[test]
+    fn test_touch_file_pauses_indefinitely() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join(MAINTENANCE_FILE_NAME);
+        fs::write(&path, "").unwrap();
+
+        assert!(is_active(&path, Utc::now() + chrono::Duration::days(365)));
+    }
+}
@@ -1,83 +1,492 @@
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::sync::OnceLock;
 use std::time::Duration;
+use tokio::time::Instant;
+
+use crate::config::{MetadataConfig, MetadataProvider, MetadataProviderConfig};
+use crate::container::ContainerInfo;
+use crate::kubernetes::PodMetadata;
+use crate::metrics::wildcard_match;
+use crate::virtualization::Virtualization;
+use crate::wsl::WslVersion;
+
+/// Timeout of metadata requests, short so hosts outside a cloud are not held up
+const DEFAULT_PROBE_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// AWS instance metadata service
+const AWS_IMDS_URL: &str = "http://169.254.169.254";
+
+/// Azure instance metadata service
+const AZURE_IMDS_URL: &str = "http://169.254.169.254";
+
+/// Requests made to Azure IMDS while it answers with a retryable status
+const AZURE_IMDS_ATTEMPTS: u32 = 3;
+
+/// GCP metadata server
+const GCP_METADATA_URL: &str = "http://metadata.google.internal";
+
+/// DigitalOcean, Linode and Vultr serve metadata at the link-local address too
+const DIGITALOCEAN_METADATA_URL: &str = "http://169.254.169.254";
+const LINODE_METADATA_URL: &str = "http://169.254.169.254";
+const VULTR_METADATA_URL: &str = "http://169.254.169.254";
+
+const ALIBABA_METADATA_URL: &str = "http://100.100.100.200";
+
+const SCALEWAY_METADATA_URL: &str = "http://169.254.42.42";
+
+/// GCP Compute API, for instance labels
+const GCP_COMPUTE_API_URL: &str = "https://compute.googleapis.com";
+
+/// Environment variable ECS sets to the task metadata endpoint (v4)
+const ECS_METADATA_URI_ENV: &str = "ECS_CONTAINER_METADATA_URI_V4";
+
+/// Prefix of environment variables with custom metadata, e.g. `SENTINEL_METADATA_RACK=r12`
+const CUSTOM_METADATA_ENV_PREFIX: &str = "SENTINEL_METADATA_";
+
+/// Where to reach one provider's metadata service, and how long to wait for it
+#[derive(Debug, Clone)]
+struct Probe {
+    base_url: String,
+    timeout: Duration,
+    /// When detection gives up on this provider; only its identity request is held to it
+    deadline: Option<Instant>,
+}
+
+impl Probe {
+    fn new(default_url: &str, settings: Option<&MetadataProviderConfig>) -> Self {
+        Self {
+            base_url: settings
+                .and_then(|s| s.endpoint.as_deref())
+                .unwrap_or(default_url)
+                .trim_end_matches('/')
+                .to_string(),
+            timeout: settings
+                .and_then(|s| s.timeout_ms)
+                .map(Duration::from_millis)
+                .unwrap_or(DEFAULT_PROBE_TIMEOUT),
+            deadline: None,
+        }
+    }
+
+    fn with_deadline(self, deadline: Instant) -> Self {
+        Self {
+            deadline: Some(deadline),
+            ..self
+        }
+    }
+
+    /// `request`, the one telling whether the provider is there at all, by the detection deadline
+    ///
+    /// Once it answered, the rest of the provider's metadata is fetched
+    /// without the deadline, each request bounded by the probe timeout only.
+    async fn identify<T>(&self, request: impl Future<Output = Option<T>>) -> Option<T> {
+        let Some(deadline) = self.deadline else {
+            return request.await;
+        };
+        match tokio::time::timeout_at(deadline, request).await {
+            Ok(result) => result,
+            Err(_) => {
+                tracing::debug!(url = %self.base_url, "Cloud metadata probe ran past the detection deadline");
+                None
+            }
+        }
+    }
+
+    /// The probe for `provider` with the overrides from `metadata.providers`
+    fn for_provider(provider: &CloudProvider, settings: &MetadataConfig) -> Self {
+        let providers = settings.providers.clone().unwrap_or_default();
+        let (default_url, overrides) = match provider {
+            CloudProvider::AWS => (AWS_IMDS_URL, providers.aws),
+            CloudProvider::Azure => (AZURE_IMDS_URL, providers.azure),
+            CloudProvider::GCP => (GCP_METADATA_URL, providers.gcp),
+            CloudProvider::DigitalOcean => (DIGITALOCEAN_METADATA_URL, providers.digitalocean),
+            CloudProvider::AlibabaCloud => (ALIBABA_METADATA_URL, providers.alibaba),
+            CloudProvider::Linode => (LINODE_METADATA_URL, providers.linode),
+            CloudProvider::Vultr => (VULTR_METADATA_URL, providers.vultr),
+            CloudProvider::Scaleway => (SCALEWAY_METADATA_URL, providers.scaleway),
+            CloudProvider::Unknown => ("", None),
+        };
+        Self::new(default_url, overrides.as_ref())
+    }
+
+    fn client(&self) -> Option<reqwest::Client> {
+        reqwest::Client::builder().timeout(self.timeout).build().ok()
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}{}", self.base_url, path)
+    }
+}
 
 /// Cloud provider instance metadata
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
 pub struct InstanceMetadata {
     pub instance_id: Option<String>,
     pub cloud_provider: Option<CloudProvider>,
     pub region: Option<String>,
     pub instance_type: Option<String>,
+    /// Cloud instance tags allowed by `metadata.tags`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub tags: BTreeMap<String, String>,
+    /// Public IP address assigned by the cloud provider, with `metadata.network`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub public_ip: Option<String>,
+    /// Azure placement, on Azure VMs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub azure: Option<AzureInstance>,
+    /// GCP project and zone, on Compute Engine
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gcp: Option<GcpInstance>,
+    /// The ECS task the agent runs in, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ecs: Option<EcsTask>,
+    /// The Kubernetes pod the agent runs in, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kubernetes: Option<PodMetadata>,
+    /// The container the agent runs in, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub container: Option<ContainerInfo>,
+    /// Hypervisor the machine runs under, or bare metal
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub virtualization: Option<Virtualization>,
+    /// WSL version, when running under the Windows Subsystem for Linux
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub wsl: Option<WslVersion>,
+    /// Operator supplied key/values from `metadata.custom`, `metadata.custom_file`
+    /// and `SENTINEL_METADATA_*` variables
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub custom: BTreeMap<String, String>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// Where an Azure VM lives, for grouping by subscription, resource group and scale set
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AzureInstance {
+    pub vm_name: Option<String>,
+    pub subscription_id: Option<String>,
+    pub resource_group: Option<String>,
+    /// Virtual machine scale set the VM belongs to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scale_set: Option<String>,
+    /// Instance number within a uniform scale set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scale_set_instance: Option<String>,
+}
+
+/// Where a Compute Engine instance lives
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GcpInstance {
+    pub project_id: Option<String>,
+    /// e.g. `us-central1-a`
+    pub zone: Option<String>,
+}
+
+/// ECS task metadata, from the task metadata endpoint
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EcsTask {
+    pub cluster: String,
+    pub task_arn: String,
+    /// `EC2` or `FARGATE`
+    pub launch_type: Option<String>,
+    pub availability_zone: Option<String>,
+    /// CPU units reserved for the agent's container (1024 = one vCPU)
+    pub cpu_limit: Option<f64>,
+    /// Memory limit of the agent's container in MiB
+    pub memory_limit_mb: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum CloudProvider {
     AWS,
     Azure,
     GCP,
     DigitalOcean,
+    AlibabaCloud,
+    Linode,
+    Vultr,
+    Scaleway,
     Unknown,
 }
 
 impl InstanceMetadata {
     /// Detect cloud instance metadata from the environment
-    pub async fn detect() -> Self {
-        // Try AWS first (most common)
-        if let Some(aws_meta) = Self::fetch_aws_metadata().await {
-            return aws_meta;
+    pub async fn detect(settings: &MetadataConfig) -> Self {
+        Self::detect_with_pod(settings, PodMetadata::detect().await).await
+    }
+
+    /// Like `detect`, with the pod already looked up
+    pub async fn detect_with_pod(settings: &MetadataConfig, pod: Option<PodMetadata>) -> Self {
+        let mut metadata = Self::detect_cloud(settings).await;
+        if settings.reports_network() && metadata.ecs.is_none() {
+            if let Some(provider) = &metadata.cloud_provider {
+                metadata.public_ip = fetch_public_ip(provider, &Probe::for_provider(provider, settings)).await;
+            }
         }
+        metadata.kubernetes = pod;
+        metadata.container = ContainerInfo::detect();
+        metadata.virtualization = Virtualization::detect();
+        metadata.wsl = WslVersion::detect();
+        metadata.custom = custom_metadata(settings, std::env::vars());
+        metadata
+    }
 
-        // Try Azure
-        if let Some(azure_meta) = Self::fetch_azure_metadata().await {
-            return azure_meta;
+    /// `current`, with the cloud fields that could not be fetched this time
+    /// taken from these (saved) ones
+    ///
+    /// A metadata request that failed leaves its field empty, which must not
+    /// be mistaken for the value having gone away.
+    pub fn refreshed(&self, current: Self) -> Self {
+        let gcp = match (current.gcp, &self.gcp) {
+            (Some(gcp), Some(saved)) => Some(GcpInstance {
+                project_id: gcp.project_id.or_else(|| saved.project_id.clone()),
+                zone: gcp.zone.or_else(|| saved.zone.clone()),
+            }),
+            (gcp, saved) => gcp.or_else(|| saved.clone()),
+        };
+        Self {
+            region: current.region.or_else(|| self.region.clone()),
+            instance_type: current.instance_type.or_else(|| self.instance_type.clone()),
+            tags: if current.tags.is_empty() { self.tags.clone() } else { current.tags },
+            public_ip: current.public_ip.or_else(|| self.public_ip.clone()),
+            azure: current.azure.or_else(|| self.azure.clone()),
+            gcp,
+            ecs: current.ecs.or_else(|| self.ecs.clone()),
+            ..current
         }
+    }
 
-        // Try GCP
-        if let Some(gcp_meta) = Self::fetch_gcp_metadata().await {
-            return gcp_meta;
+    /// Fields of `current` that differ from these (saved) ones, for drift reporting
+    ///
+    /// Only fields read successfully both times count; see `refreshed`. Empty
+    /// when `current` looks like a failed detection or another instance,
+    /// which registration handles on the next start.
+    pub fn drift(&self, current: &Self) -> Vec<String> {
+        if (self.cloud_provider.is_some() && current.cloud_provider.is_none())
+            || self.instance_id != current.instance_id
+        {
+            return Vec::new();
         }
+        let (Ok(serde_json::Value::Object(saved)), Ok(serde_json::Value::Object(current))) =
+            (serde_json::to_value(self), serde_json::to_value(self.refreshed(current.clone())))
+        else {
+            return Vec::new();
+        };
+        let mut fields: Vec<String> = saved
+            .keys()
+            .chain(current.keys())
+            .filter(|key| saved.get(*key) != current.get(*key))
+            .cloned()
+            .collect();
+        fields.sort();
+        fields.dedup();
+        fields
+    }
+
+    /// Cloud tags as batch labels, e.g. `tag.Name`
+    pub fn tag_labels(&self) -> BTreeMap<String, String> {
+        self.tags
+            .iter()
+            .map(|(key, value)| (format!("tag.{}", key), value.clone()))
+            .collect()
+    }
+
+    async fn detect_cloud(settings: &MetadataConfig) -> Self {
+        let tags = settings.get_tags();
+        let deadline = Instant::now() + Duration::from_millis(settings.get_detection_timeout_ms());
+        let probe = |provider: CloudProvider| Probe::for_provider(&provider, settings).with_deadline(deadline);
 
-        // Try DigitalOcean
-        if let Some(do_meta) = Self::fetch_digitalocean_metadata().await {
-            return do_meta;
+        if settings.provider == Some(MetadataProvider::None) {
+            return Self::default();
         }
 
-        // Not in a recognized cloud environment
-        Self {
-            instance_id: None,
-            cloud_provider: None,
-            region: None,
-            instance_type: None,
+        // Inside an ECS task the task is more telling than the EC2 host (if any)
+        if settings.probes(MetadataProvider::Aws) {
+            if let Ok(uri) = std::env::var(ECS_METADATA_URI_ENV) {
+                if let Some(ecs_meta) = Self::fetch_ecs_metadata(&uri).await {
+                    return ecs_meta;
+                }
+            }
         }
+
+        // Probe every provider at once, so hosts outside a cloud wait for one timeout, not eight
+        let aws_probe = probe(CloudProvider::AWS);
+        let azure_probe = probe(CloudProvider::Azure);
+        let gcp_probe = probe(CloudProvider::GCP);
+        let digitalocean_probe = probe(CloudProvider::DigitalOcean);
+        let alibaba_probe = probe(CloudProvider::AlibabaCloud);
+        let linode_probe = probe(CloudProvider::Linode);
+        let vultr_probe = probe(CloudProvider::Vultr);
+        let scaleway_probe = probe(CloudProvider::Scaleway);
+        let (aws, azure, gcp, digitalocean, alibaba, linode, vultr, scaleway) = tokio::join!(
+            probed(
+                settings.probes(MetadataProvider::Aws),
+                Self::fetch_aws_metadata(&aws_probe, settings.is_imdsv2_only(), &tags)
+            ),
+            probed(
+                settings.probes(MetadataProvider::Azure),
+                Self::fetch_azure_metadata(&azure_probe, &tags)
+            ),
+            probed(
+                settings.probes(MetadataProvider::Gcp),
+                Self::fetch_gcp_metadata(&gcp_probe)
+            ),
+            probed(
+                settings.probes(MetadataProvider::Digitalocean),
+                Self::fetch_digitalocean_metadata(&digitalocean_probe)
+            ),
+            probed(
+                settings.probes(MetadataProvider::Alibaba),
+                Self::fetch_alibaba_metadata(&alibaba_probe)
+            ),
+            probed(
+                settings.probes(MetadataProvider::Linode),
+                Self::fetch_linode_metadata(&linode_probe)
+            ),
+            probed(
+                settings.probes(MetadataProvider::Vultr),
+                Self::fetch_vultr_metadata(&vultr_probe)
+            ),
+            probed(
+                settings.probes(MetadataProvider::Scaleway),
+                Self::fetch_scaleway_metadata(&scaleway_probe)
+            ),
+        );
+
+        // Several providers share 169.254.169.254; the stricter probes win
+        let mut metadata = aws
+            .or(azure)
+            .or(gcp)
+            .or(digitalocean)
+            .or(alibaba)
+            .or(linode)
+            .or(vultr)
+            .or(scaleway)
+            // Not in a recognized cloud environment
+            .unwrap_or_default();
+
+        // Labels come from the Compute API, which is slower than the metadata server
+        if metadata.cloud_provider == Some(CloudProvider::GCP) && !tags.is_empty() {
+            metadata.tags = Self::fetch_gcp_tags(&probe(CloudProvider::GCP), GCP_COMPUTE_API_URL, &tags).await;
+        }
+        metadata
     }
 
-    /// Fetch AWS EC2 instance metadata
-    async fn fetch_aws_metadata() -> Option<Self> {
-        // AWS IMDSv2 (Instance Metadata Service v2) - more secure
-        // First get the token
+    /// Fetch ECS task metadata from the task metadata endpoint at `uri`
+    async fn fetch_ecs_metadata(uri: &str) -> Option<Self> {
         let client = reqwest::Client::builder()
-            .timeout(Duration::from_millis(500)) // Fast timeout for non-AWS environments
+            .timeout(DEFAULT_PROBE_TIMEOUT)
             .build()
             .ok()?;
 
-        // Try to get IMDSv2 token
-        let token_response = client
-            .put("http://169.254.169.254/latest/api/token")
-            .header("X-aws-ec2-metadata-token-ttl-seconds", "21600")
+        #[derive(Deserialize)]
+        #[serde(rename_all = "PascalCase")]
+        struct TaskMetadata {
+            cluster: String,
+            #[serde(rename = "TaskARN")]
+            task_arn: String,
+            launch_type: Option<String>,
+            availability_zone: Option<String>,
+        }
+
+        #[derive(Deserialize)]
+        #[serde(rename_all = "PascalCase")]
+        struct ContainerMetadata {
+            limits: Option<ContainerLimits>,
+        }
+
+        #[derive(Deserialize)]
+        struct ContainerLimits {
+            #[serde(rename = "CPU")]
+            cpu: Option<f64>,
+            #[serde(rename = "Memory")]
+            memory: Option<u64>,
+        }
+
+        let uri = uri.trim_end_matches('/');
+        let task: TaskMetadata = client
+            .get(format!("{}/task", uri))
             .send()
             .await
+            .ok()?
+            .json()
+            .await
             .ok()?;
 
+        let limits = match client.get(uri).send().await {
+            Ok(response) => response
+                .json::<ContainerMetadata>()
+                .await
+                .ok()
+                .and_then(|container| container.limits),
+            Err(_) => None,
+        };
+
+        // Task ARNs look like arn:aws:ecs:us-east-1:123456789012:task/cluster/id
+        let region = task.task_arn.split(':').nth(3).filter(|r| !r.is_empty()).map(str::to_string);
+
+        Some(Self {
+            instance_id: Some(task.task_arn.clone()),
+            cloud_provider: Some(CloudProvider::AWS),
+            region,
+            instance_type: None,
+            tags: BTreeMap::new(),
+            public_ip: None,
+            azure: None,
+            gcp: None,
+            ecs: Some(EcsTask {
+                cluster: task.cluster,
+                task_arn: task.task_arn,
+                launch_type: task.launch_type,
+                availability_zone: task.availability_zone,
+                // Unset limits are reported as 0
+                cpu_limit: limits.as_ref().and_then(|l| l.cpu).filter(|cpu| *cpu > 0.0),
+                memory_limit_mb: limits.and_then(|l| l.memory).filter(|memory| *memory > 0),
+            }),
+            kubernetes: None,
+            container: None,
+            virtualization: None,
+            wsl: None,
+            custom: BTreeMap::new(),
+        })
+    }
+
+    /// Fetch AWS EC2 instance metadata, with the instance tags matching `tags`
+    ///
+    /// Uses IMDSv2 and, unless `imdsv2_only`, falls back to IMDSv1 when no token is issued.
+    async fn fetch_aws_metadata(probe: &Probe, imdsv2_only: bool, tags: &[String]) -> Option<Self> {
+        // AWS IMDSv2 (Instance Metadata Service v2) - more secure
+        // First get the token
+        let client = probe.client()?;
+
+        // Try to get IMDSv2 token
+        let token_response = probe
+            .identify(async {
+                client
+                    .put(probe.url("/latest/api/token"))
+                    .header("X-aws-ec2-metadata-token-ttl-seconds", "21600")
+                    .send()
+                    .await
+                    .ok()
+            })
+            .await?;
+
         if !token_response.status().is_success() {
+            if imdsv2_only {
+                tracing::debug!(status = %token_response.status(), "No IMDSv2 token and IMDSv1 is disabled");
+                return None;
+            }
             // Try IMDSv1 fallback
-            return Self::fetch_aws_metadata_v1().await;
+            return Self::fetch_aws_metadata_v1(probe, tags).await;
         }
 
         let token = token_response.text().await.ok()?;
 
         // Fetch instance ID
         let instance_id = client
-            .get("http://169.254.169.254/latest/meta-data/instance-id")
+            .get(probe.url("/latest/meta-data/instance-id"))
             .header("X-aws-ec2-metadata-token", &token)
             .send()
             .await
@@ -88,7 +497,7 @@ impl InstanceMetadata {
 
         // Fetch instance type
         let instance_type = client
-            .get("http://169.254.169.254/latest/meta-data/instance-type")
+            .get(probe.url("/latest/meta-data/instance-type"))
             .header("X-aws-ec2-metadata-token", &token)
             .send()
             .await
@@ -99,7 +508,7 @@ impl InstanceMetadata {
 
         // Fetch region
         let region = client
-            .get("http://169.254.169.254/latest/meta-data/placement/region")
+            .get(probe.url("/latest/meta-data/placement/region"))
             .header("X-aws-ec2-metadata-token", &token)
             .send()
             .await
@@ -108,44 +517,52 @@ impl InstanceMetadata {
             .await
             .ok();
 
+        let tags = fetch_aws_tags(&client, &probe.base_url, Some(&token), tags).await;
+
         Some(Self {
             instance_id: Some(instance_id),
             cloud_provider: Some(CloudProvider::AWS),
             region,
             instance_type,
+            tags,
+            ..Default::default()
         })
     }
 
     /// Fetch AWS metadata using IMDSv1 (fallback)
-    async fn fetch_aws_metadata_v1() -> Option<Self> {
-        let client = reqwest::Client::builder()
-            .timeout(Duration::from_millis(500))
-            .build()
-            .ok()?;
+    async fn fetch_aws_metadata_v1(probe: &Probe, tags: &[String]) -> Option<Self> {
+        let client = probe.client()?;
 
-        let instance_id = client
-            .get("http://169.254.169.254/latest/meta-data/instance-id")
-            .send()
-            .await
-            .ok()?
-            .text()
-            .await
-            .ok()?;
+        let instance_id = probe
+            .identify(async {
+                client
+                    .get(probe.url("/latest/meta-data/instance-id"))
+                    .send()
+                    .await
+                    .ok()?
+                    .error_for_status()
+                    .ok()?
+                    .text()
+                    .await
+                    .ok()
+            })
+            .await?;
+
+        let tags = fetch_aws_tags(&client, &probe.base_url, None, tags).await;
 
         Some(Self {
             instance_id: Some(instance_id),
             cloud_provider: Some(CloudProvider::AWS),
             region: None,
             instance_type: None,
+            tags,
+            ..Default::default()
         })
     }
 
-    /// Fetch Azure instance metadata
-    async fn fetch_azure_metadata() -> Option<Self> {
-        let client = reqwest::Client::builder()
-            .timeout(Duration::from_millis(500))
-            .build()
-            .ok()?;
+    /// Fetch Azure instance metadata, with the VM tags matching `tags`
+    async fn fetch_azure_metadata(probe: &Probe, tags: &[String]) -> Option<Self> {
+        let client = probe.client()?;
 
         #[derive(Deserialize)]
         struct AzureMetadata {
@@ -153,78 +570,157 @@ impl InstanceMetadata {
         }
 
         #[derive(Deserialize)]
+        #[serde(rename_all = "camelCase")]
         struct AzureCompute {
-            #[serde(rename = "vmId")]
             vm_id: String,
+            name: Option<String>,
             location: Option<String>,
-            #[serde(rename = "vmSize")]
             vm_size: Option<String>,
+            subscription_id: Option<String>,
+            resource_group_name: Option<String>,
+            vm_scale_set_name: Option<String>,
+            #[serde(default)]
+            tags_list: Vec<AzureTag>,
         }
 
-        let response = client
-            .get("http://169.254.169.254/metadata/instance")
-            .header("Metadata", "true")
-            .query(&[("api-version", "2021-02-01")])
-            .send()
-            .await
-            .ok()?;
+        #[derive(Deserialize)]
+        struct AzureTag {
+            name: String,
+            value: String,
+        }
+
+        let request = || {
+            client
+                .get(probe.url("/metadata/instance"))
+                .header("Metadata", "true")
+                .query(&[("api-version", "2021-02-01")])
+                .send()
+        };
+        let mut response = probe.identify(async { request().await.ok() }).await?;
 
-        let metadata: AzureMetadata = response.json().await.ok()?;
+        // IMDS asks clients to retry throttling and transient errors with backoff; it answered,
+        // so the retries are not held to the detection deadline
+        let mut backoff = Duration::from_millis(100);
+        let mut attempt = 1;
+        while matches!(response.status().as_u16(), 410 | 429 | 500..=599) && attempt < AZURE_IMDS_ATTEMPTS {
+            tokio::time::sleep(backoff).await;
+            backoff *= 2;
+            attempt += 1;
+            response = request().await.ok()?;
+        }
+        let response = response.error_for_status().ok()?;
+
+        let compute = response.json::<AzureMetadata>().await.ok()?.compute;
+
+        // Uniform scale set instances are named <scale set>_<instance number>
+        let scale_set = compute.vm_scale_set_name.filter(|name| !name.is_empty());
+        let scale_set_instance = scale_set.as_ref().and_then(|scale_set| {
+            let name = compute.name.as_deref()?;
+            name.strip_prefix(scale_set.as_str())?.strip_prefix('_').map(str::to_string)
+        });
 
         Some(Self {
-            instance_id: Some(metadata.compute.vm_id),
+            instance_id: Some(compute.vm_id),
             cloud_provider: Some(CloudProvider::Azure),
-            region: metadata.compute.location,
-            instance_type: metadata.compute.vm_size,
+            region: compute.location,
+            instance_type: compute.vm_size,
+            tags: compute
+                .tags_list
+                .into_iter()
+                .filter(|tag| tags.iter().any(|pattern| wildcard_match(pattern, &tag.name)))
+                .map(|tag| (tag.name, tag.value))
+                .collect(),
+            azure: Some(AzureInstance {
+                vm_name: compute.name,
+                subscription_id: compute.subscription_id,
+                resource_group: compute.resource_group_name,
+                scale_set,
+                scale_set_instance,
+            }),
+            ..Default::default()
         })
     }
 
-    /// Fetch GCP instance metadata
-    async fn fetch_gcp_metadata() -> Option<Self> {
-        let client = reqwest::Client::builder()
-            .timeout(Duration::from_millis(500))
-            .build()
-            .ok()?;
+    /// Fetch GCP instance metadata; the labels are fetched separately, see `fetch_gcp_tags`
+    async fn fetch_gcp_metadata(probe: &Probe) -> Option<Self> {
+        let client = probe.client()?;
 
-        let instance_id = client
-            .get("http://metadata.google.internal/computeMetadata/v1/instance/id")
-            .header("Metadata-Flavor", "Google")
-            .send()
-            .await
-            .ok()?
-            .text()
-            .await
-            .ok()?;
+        let get = |path: &str| {
+            client
+                .get(probe.url(&format!("/computeMetadata/v1/{}", path)))
+                .header("Metadata-Flavor", "Google")
+                .send()
+        };
+        let text = |response: reqwest::Response| async move {
+            response.error_for_status().ok()?.text().await.ok()
+        };
 
-        let zone = client
-            .get("http://metadata.google.internal/computeMetadata/v1/instance/zone")
-            .header("Metadata-Flavor", "Google")
-            .send()
-            .await
-            .ok()?
-            .text()
-            .await
-            .ok();
+        let instance_id = probe.identify(async { text(get("instance/id").await.ok()?).await }).await?;
+
+        let zone = match get("instance/zone").await {
+            Ok(response) => text(response).await,
+            Err(_) => None,
+        };
 
         // Extract region from zone (e.g., "projects/123/zones/us-central1-a" -> "us-central1")
         let region = zone.as_ref().and_then(|z| {
-            z.split('/').last()?.rsplit_once('-').map(|(r, _)| r.to_string())
+            z.split('/').next_back()?.rsplit_once('-').map(|(r, _)| r.to_string())
         });
 
+        // e.g. "projects/123/machineTypes/e2-medium" -> "e2-medium"
+        let instance_type = match get("instance/machine-type").await {
+            Ok(response) => text(response).await.and_then(|t| t.rsplit('/').next().map(str::to_string)),
+            Err(_) => None,
+        };
+
+        let project_id = match get("project/project-id").await {
+            Ok(response) => text(response).await,
+            Err(_) => None,
+        };
+
         Some(Self {
             instance_id: Some(instance_id),
             cloud_provider: Some(CloudProvider::GCP),
             region,
-            instance_type: None,
+            instance_type,
+            gcp: Some(GcpInstance {
+                project_id,
+                zone: zone.map(|z| z.rsplit('/').next().unwrap_or(&z).to_string()),
+            }),
+            ..Default::default()
         })
     }
 
+    /// Instance labels matching `tags`, from the Compute API
+    ///
+    /// Labels are not served by the metadata server, so this runs once the
+    /// instance was detected, not within the detection deadline.
+    async fn fetch_gcp_tags(probe: &Probe, compute_api_url: &str, tags: &[String]) -> BTreeMap<String, String> {
+        let Some(client) = probe.client() else {
+            return BTreeMap::new();
+        };
+        let get = |path: &str| {
+            let request = client
+                .get(probe.url(&format!("/computeMetadata/v1/{}", path)))
+                .header("Metadata-Flavor", "Google");
+            async move { request.send().await.ok()?.error_for_status().ok()?.text().await.ok() }
+        };
+        let (Some(project_id), Some(zone), Some(name)) =
+            (get("project/project-id").await, get("instance/zone").await, get("instance/name").await)
+        else {
+            return BTreeMap::new();
+        };
+        let zone = zone.rsplit('/').next().unwrap_or(&zone);
+        fetch_gcp_labels(&client, &probe.base_url, compute_api_url, &project_id, zone, &name)
+            .await
+            .into_iter()
+            .filter(|(key, _)| tags.iter().any(|pattern| wildcard_match(pattern, key)))
+            .collect()
+    }
+
     /// Fetch DigitalOcean droplet metadata
-    async fn fetch_digitalocean_metadata() -> Option<Self> {
-        let client = reqwest::Client::builder()
-            .timeout(Duration::from_millis(500))
-            .build()
-            .ok()?;
+    async fn fetch_digitalocean_metadata(probe: &Probe) -> Option<Self> {
+        let client = probe.client()?;
 
         #[derive(Deserialize)]
         struct DropletMetadata {
@@ -232,11 +728,9 @@ impl InstanceMetadata {
             region: Option<String>,
         }
 
-        let response = client
-            .get("http://169.254.169.254/metadata/v1.json")
-            .send()
-            .await
-            .ok()?;
+        let response = probe
+            .identify(async { client.get(probe.url("/metadata/v1.json")).send().await.ok() })
+            .await?;
 
         let metadata: DropletMetadata = response.json().await.ok()?;
 
@@ -245,10 +739,351 @@ impl InstanceMetadata {
             cloud_provider: Some(CloudProvider::DigitalOcean),
             region: metadata.region,
             instance_type: None,
+            ..Default::default()
+        })
+    }
+
+    /// Fetch Alibaba Cloud ECS instance metadata
+    async fn fetch_alibaba_metadata(probe: &Probe) -> Option<Self> {
+        let client = probe.client()?;
+        // An error page must not be taken for the value
+        let get = |path: &str| {
+            let request = client.get(probe.url(path));
+            async move {
+                request
+                    .send()
+                    .await
+                    .ok()?
+                    .error_for_status()
+                    .ok()?
+                    .text()
+                    .await
+                    .ok()
+            }
+        };
+
+        let instance_id = probe.identify(get("/latest/meta-data/instance-id")).await?;
+        let region = get("/latest/meta-data/region-id").await;
+        let instance_type = get("/latest/meta-data/instance/instance-type").await;
+
+        Some(Self {
+            instance_id: Some(instance_id),
+            cloud_provider: Some(CloudProvider::AlibabaCloud),
+            region,
+            instance_type,
+            ..Default::default()
+        })
+    }
+
+    /// Fetch Linode instance metadata
+    async fn fetch_linode_metadata(probe: &Probe) -> Option<Self> {
+        let client = probe.client()?;
+
+        #[derive(Deserialize)]
+        struct LinodeInstance {
+            id: u64,
+            region: Option<String>,
+            #[serde(rename = "type")]
+            plan: Option<String>,
+        }
+
+        // The metadata service only answers requests carrying a token
+        let token = probe
+            .identify(async {
+                client
+                    .put(probe.url("/v1/token"))
+                    .header("Metadata-Token-Expiry-Seconds", "300")
+                    .send()
+                    .await
+                    .ok()?
+                    .error_for_status()
+                    .ok()?
+                    .text()
+                    .await
+                    .ok()
+            })
+            .await?;
+
+        let instance: LinodeInstance = client
+            .get(probe.url("/v1/instance"))
+            .header("Metadata-Token", token.trim())
+            .header("Accept", "application/json")
+            .send()
+            .await
+            .ok()?
+            .error_for_status()
+            .ok()?
+            .json()
+            .await
+            .ok()?;
+
+        Some(Self {
+            instance_id: Some(instance.id.to_string()),
+            cloud_provider: Some(CloudProvider::Linode),
+            region: instance.region,
+            instance_type: instance.plan,
+            ..Default::default()
+        })
+    }
+
+    /// Fetch Vultr instance metadata
+    async fn fetch_vultr_metadata(probe: &Probe) -> Option<Self> {
+        let client = probe.client()?;
+
+        #[derive(Deserialize)]
+        struct VultrMetadata {
+            #[serde(rename = "instance-v2-id")]
+            instance_v2_id: Option<String>,
+            instanceid: Option<String>,
+            region: Option<VultrRegion>,
+        }
+
+        #[derive(Deserialize)]
+        struct VultrRegion {
+            regioncode: Option<String>,
+        }
+
+        let response = probe
+            .identify(async { client.get(probe.url("/v1.json")).send().await.ok() })
+            .await?;
+
+        let metadata: VultrMetadata = response.error_for_status().ok()?.json().await.ok()?;
+
+        Some(Self {
+            instance_id: Some(metadata.instance_v2_id.or(metadata.instanceid)?),
+            cloud_provider: Some(CloudProvider::Vultr),
+            region: metadata.region.and_then(|r| r.regioncode),
+            // The plan is not exposed by the metadata service
+            instance_type: None,
+            ..Default::default()
+        })
+    }
+
+    /// Fetch Scaleway instance metadata
+    async fn fetch_scaleway_metadata(probe: &Probe) -> Option<Self> {
+        let client = probe.client()?;
+
+        #[derive(Deserialize)]
+        struct ScalewayMetadata {
+            id: String,
+            commercial_type: Option<String>,
+            location: Option<ScalewayLocation>,
+        }
+
+        #[derive(Deserialize)]
+        struct ScalewayLocation {
+            zone_id: Option<String>,
+        }
+
+        let response = probe
+            .identify(async { client.get(probe.url("/conf")).query(&[("format", "json")]).send().await.ok() })
+            .await?;
+
+        let metadata: ScalewayMetadata = response.error_for_status().ok()?.json().await.ok()?;
+
+        // Extract region from zone (e.g., "fr-par-1" -> "fr-par")
+        let region = metadata
+            .location
+            .and_then(|l| l.zone_id)
+            .and_then(|z| z.rsplit_once('-').map(|(r, _)| r.to_string()));
+
+        Some(Self {
+            instance_id: Some(metadata.id),
+            cloud_provider: Some(CloudProvider::Scaleway),
+            region,
+            instance_type: metadata.commercial_type,
+            ..Default::default()
         })
     }
 }
 
+/// Result of a provider probe, `None` when not probed
+async fn probed(
+    enabled: bool,
+    probe: impl Future<Output = Option<InstanceMetadata>>,
+) -> Option<InstanceMetadata> {
+    if !enabled {
+        return None;
+    }
+    probe.await
+}
+
+/// Custom metadata from `metadata.custom_file`, then `metadata.custom`, then the environment
+///
+/// An unreadable file is logged and skipped rather than holding up registration.
+fn custom_metadata(settings: &MetadataConfig, env: impl Iterator<Item = (String, String)>) -> BTreeMap<String, String> {
+    let mut custom = BTreeMap::new();
+
+    if let Some(path) = &settings.custom_file {
+        match read_custom_file(path) {
+            Ok(values) => custom.extend(values),
+            Err(e) => tracing::warn!(path = %path, error = %e, "Could not read custom metadata file"),
+        }
+    }
+    custom.extend(settings.custom.clone().unwrap_or_default());
+    for (name, value) in env {
+        if let Some(key) = name.strip_prefix(CUSTOM_METADATA_ENV_PREFIX).filter(|key| !key.is_empty()) {
+            custom.insert(key.to_lowercase(), value);
+        }
+    }
+
+    custom
+}
+
+/// A JSON object whose values are strings, numbers or booleans
+fn read_custom_file(path: &str) -> Result<BTreeMap<String, String>, String> {
+    let content = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+    let values: BTreeMap<String, serde_json::Value> = serde_json::from_str(&content).map_err(|e| e.to_string())?;
+    values
+        .into_iter()
+        .map(|(key, value)| match value {
+            serde_json::Value::String(value) => Ok((key, value)),
+            serde_json::Value::Number(_) | serde_json::Value::Bool(_) => Ok((key, value.to_string())),
+            _ => Err(format!("value of {} must be a string, number or boolean", key)),
+        })
+        .collect()
+}
+
+/// Public IPv4 address from the provider's metadata service, on AWS, Azure and GCP
+async fn fetch_public_ip(provider: &CloudProvider, probe: &Probe) -> Option<String> {
+    let client = probe.client()?;
+
+    let request = match provider {
+        CloudProvider::AWS => {
+            let token = client
+                .put(probe.url("/latest/api/token"))
+                .header("X-aws-ec2-metadata-token-ttl-seconds", "60")
+                .send()
+                .await
+                .ok()?
+                .error_for_status()
+                .ok()?
+                .text()
+                .await
+                .ok()?;
+            client
+                .get(probe.url("/latest/meta-data/public-ipv4"))
+                .header("X-aws-ec2-metadata-token", token)
+        }
+        CloudProvider::Azure => client
+            .get(probe.url("/metadata/instance/network/interface/0/ipv4/ipAddress/0/publicIpAddress"))
+            .header("Metadata", "true")
+            .query(&[("api-version", "2021-02-01"), ("format", "text")]),
+        CloudProvider::GCP => client
+            .get(probe.url("/computeMetadata/v1/instance/network-interfaces/0/access-configs/0/external-ip"))
+            .header("Metadata-Flavor", "Google"),
+        _ => return None,
+    };
+
+    // Instances without a public address get a 404 or an empty body
+    let address = request.send().await.ok()?.error_for_status().ok()?.text().await.ok()?;
+    let address = address.trim();
+    address.parse::<std::net::IpAddr>().is_ok().then(|| address.to_string())
+}
+
+/// Instance tags matching `allowed`, empty unless tags are enabled in the instance's metadata options
+async fn fetch_aws_tags(
+    client: &reqwest::Client,
+    base_url: &str,
+    token: Option<&str>,
+    allowed: &[String],
+) -> BTreeMap<String, String> {
+    let mut tags = BTreeMap::new();
+    if allowed.is_empty() {
+        return tags;
+    }
+
+    let get = |path: String| {
+        let request = client.get(format!("{}/latest/meta-data/tags/instance{}", base_url, path));
+        match token {
+            Some(token) => request.header("X-aws-ec2-metadata-token", token),
+            None => request,
+        }
+    };
+    let keys = match get(String::new()).send().await.and_then(|r| r.error_for_status()) {
+        Ok(response) => response.text().await.unwrap_or_default(),
+        Err(_) => return tags,
+    };
+
+    for key in keys.lines().map(str::trim).filter(|key| !key.is_empty()) {
+        if !allowed.iter().any(|pattern| wildcard_match(pattern, key)) {
+            continue;
+        }
+        // Keys may contain characters that need escaping in a path
+        let escaped: String = key
+            .bytes()
+            .map(|b| match b {
+                b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (b as char).to_string(),
+                _ => format!("%{:02X}", b),
+            })
+            .collect();
+        if let Ok(response) = get(format!("/{}", escaped)).send().await.and_then(|r| r.error_for_status()) {
+            if let Ok(value) = response.text().await {
+                tags.insert(key.to_string(), value);
+            }
+        }
+    }
+    tags
+}
+
+/// Instance labels from the Compute API, with the instance service account's token
+///
+/// `client` is the metadata server's probe client, reused for both requests;
+/// the Compute API call only gets a longer timeout. Needs the
+/// `compute.instances.get` permission and a Compute scope; empty otherwise.
+async fn fetch_gcp_labels(
+    client: &reqwest::Client,
+    metadata_url: &str,
+    compute_api_url: &str,
+    project_id: &str,
+    zone: &str,
+    name: &str,
+) -> BTreeMap<String, String> {
+    #[derive(Deserialize)]
+    struct AccessToken {
+        access_token: String,
+    }
+
+    #[derive(Deserialize)]
+    struct Instance {
+        #[serde(default)]
+        labels: BTreeMap<String, String>,
+    }
+
+    let token = client
+        .get(format!("{}/computeMetadata/v1/instance/service-accounts/default/token", metadata_url))
+        .header("Metadata-Flavor", "Google")
+        .send()
+        .await
+        .and_then(|r| r.error_for_status());
+    let token = match token {
+        Ok(response) => match response.json::<AccessToken>().await {
+            Ok(token) => token.access_token,
+            Err(_) => return BTreeMap::new(),
+        },
+        Err(_) => return BTreeMap::new(),
+    };
+
+    let instance = client
+        .get(format!(
+            "{}/compute/v1/projects/{}/zones/{}/instances/{}",
+            compute_api_url, project_id, zone, name
+        ))
+        .query(&[("fields", "labels")])
+        .bearer_auth(token)
+        .timeout(Duration::from_secs(2))
+        .send()
+        .await
+        .and_then(|r| r.error_for_status());
+    match instance {
+        Ok(response) => response.json::<Instance>().await.map(|i| i.labels).unwrap_or_default(),
+        Err(e) => {
+            tracing::debug!(error = %e, "Could not read instance labels from the Compute API");
+            BTreeMap::new()
+        }
+    }
+}
+
 /// Session information for tracking agent runtime
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SessionInfo {
@@ -258,6 +1093,39 @@ pub struct SessionInfo {
     pub agent_start_time: u64,
     /// Current system uptime in seconds
     pub uptime_seconds: u64,
+    /// Running kernel, e.g. `6.1.0-18-amd64`, so kernel upgrades show up next to reboots
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kernel_version: Option<String>,
+    /// OS distribution, e.g. `Ubuntu`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub os_name: Option<String>,
+    /// OS release, e.g. `22.04`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub os_version: Option<String>,
+    /// SHA-256 of the agent executable, so a replaced binary is noticed even without a version change
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub binary_sha256: Option<String>,
+}
+
+/// The parts of a session that do not change while the agent runs
+#[derive(Debug, Clone)]
+struct ProcessIdentity {
+    kernel_version: Option<String>,
+    os_name: Option<String>,
+    os_version: Option<String>,
+}
+
+/// SHA-256 of the agent executable, once `agent_binary_sha256` hashed it
+static BINARY_SHA256: tokio::sync::OnceCell<Option<String>> = tokio::sync::OnceCell::const_new();
+
+/// SHA-256 of the agent executable, hashed once on a blocking thread
+///
+/// Sessions generated before this completes carry no checksum.
+pub async fn agent_binary_sha256() -> Option<String> {
+    BINARY_SHA256
+        .get_or_init(|| async { tokio::task::spawn_blocking(binary_sha256).await.ok().flatten() })
+        .await
+        .clone()
 }
 
 impl SessionInfo {
@@ -265,6 +1133,15 @@ impl SessionInfo {
     pub fn generate() -> Self {
         use std::time::{SystemTime, UNIX_EPOCH};
 
+        static PROCESS: OnceLock<ProcessIdentity> = OnceLock::new();
+        let process = PROCESS
+            .get_or_init(|| ProcessIdentity {
+                kernel_version: sysinfo::System::kernel_version(),
+                os_name: sysinfo::System::name(),
+                os_version: sysinfo::System::os_version(),
+            })
+            .clone();
+
         let agent_start_time = SystemTime::now()
             .duration_since(UNIX_EPOCH)
             .unwrap_or_default()
@@ -274,23 +1151,93 @@ impl SessionInfo {
             boot_time: sysinfo::System::boot_time(),
             agent_start_time,
             uptime_seconds: sysinfo::System::uptime(),
+            kernel_version: process.kernel_version,
+            os_name: process.os_name,
+            os_version: process.os_version,
+            binary_sha256: BINARY_SHA256.get().cloned().flatten(),
         }
     }
 
+    /// Which of the host and binary fields differ in `current`, a later session
+    ///
+    /// A new boot time means a reboot. Fields either session lacks, e.g. from
+    /// an earlier release, are not compared.
+    pub fn changes(&self, current: &SessionInfo) -> Vec<&'static str> {
+        fn differs(saved: &Option<String>, current: &Option<String>) -> bool {
+            matches!((saved, current), (Some(saved), Some(current)) if saved != current)
+        }
+        let mut changes = Vec::new();
+        if self.boot_time != current.boot_time {
+            changes.push("boot_time");
+        }
+        for (name, saved, current) in [
+            ("kernel_version", &self.kernel_version, &current.kernel_version),
+            ("os_name", &self.os_name, &current.os_name),
+            ("os_version", &self.os_version, &current.os_version),
+            ("binary_sha256", &self.binary_sha256, &current.binary_sha256),
+        ] {
+            if differs(saved, current) {
+                changes.push(name);
+            }
+        }
+        changes
+    }
+}
+
+/// SHA-256 of the running executable, hex encoded
+fn binary_sha256() -> Option<String> {
+    let path = std::env::current_exe().ok()?;
+    match hash_file(&path) {
+        Ok(digest) => Some(digest),
+        Err(e) => {
+            tracing::debug!(path = %path.display(), error = %e, "Could not hash the agent executable");
+            None
+        }
+    }
+}
+
+fn hash_file(path: &std::path::Path) -> std::io::Result<String> {
+    let mut file = std::fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher)?;
+    Ok(hex::encode(hasher.finalize()))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
-    #[test]
-    fn test_session_info_generation() {
+    #[tokio::test]
+    async fn test_session_info_generation() {
+        let digest = agent_binary_sha256().await;
+        assert_eq!(digest.as_ref().map(String::len), Some(64));
         let session = SessionInfo::generate();
 
         assert!(session.boot_time > 0);
         assert!(session.agent_start_time > 0);
         assert!(session.uptime_seconds > 0);
         assert!(session.agent_start_time >= session.boot_time);
+        assert_eq!(session.binary_sha256, digest);
+
+        let saved = SessionInfo {
+            kernel_version: Some("6.1.0-18-amd64".to_string()),
+            ..session.clone()
+        };
+        assert!(saved.changes(&saved.clone()).is_empty());
+        let upgraded = SessionInfo {
+            boot_time: saved.boot_time + 60,
+            kernel_version: Some("6.1.0-99-amd64".to_string()),
+            binary_sha256: None,
+            ..saved.clone()
+        };
+        assert_eq!(saved.changes(&upgraded), vec!["boot_time", "kernel_version"]);
+
+        let file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(file.path(), "abc").unwrap();
+        assert_eq!(
+            hash_file(file.path()).unwrap(),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
     }
 
 
@@ -298,7 +1245,7 @@ mod tests {
     async fn test_instance_metadata_detection() {
         // This will return empty metadata in dev environment
         // but will detect actual cloud metadata when running in cloud
-        let metadata = InstanceMetadata::detect().await;
+        let metadata = InstanceMetadata::detect(&MetadataConfig::default()).await;
 
         // In development, we expect no cloud provider
         if metadata.cloud_provider.is_none() {
@@ -306,4 +1253,478 @@ mod tests {
             assert!(metadata.region.is_none());
         }
     }
+
+    #[test]
+    fn test_drift() {
+        let saved = InstanceMetadata {
+            instance_id: Some("i-0aaa".to_string()),
+            cloud_provider: Some(CloudProvider::AWS),
+            region: Some("eu-west-1".to_string()),
+            instance_type: Some("m5.large".to_string()),
+            ..Default::default()
+        };
+        assert!(saved.drift(&saved.clone()).is_empty());
+
+        let resized = InstanceMetadata {
+            instance_type: Some("m5.xlarge".to_string()),
+            tags: BTreeMap::from([("env".to_string(), "prod".to_string())]),
+            ..saved.clone()
+        };
+        assert_eq!(saved.drift(&resized), vec!["instance_type", "tags"]);
+
+        // Fields whose requests failed keep their saved values and are not drift
+        let partial = InstanceMetadata { region: None, instance_type: None, ..resized.clone() };
+        assert_eq!(saved.drift(&partial), vec!["tags"]);
+        let refreshed = saved.refreshed(partial);
+        assert_eq!(refreshed.region.as_deref(), Some("eu-west-1"));
+        assert_eq!(refreshed.instance_type.as_deref(), Some("m5.large"));
+        assert_eq!(refreshed.tags, resized.tags);
+
+        // IMDS unreachable for a moment, or a different instance
+        assert!(saved.drift(&InstanceMetadata::default()).is_empty());
+        let other = InstanceMetadata { instance_id: Some("i-0bbb".to_string()), ..resized };
+        assert!(saved.drift(&other).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_aws_tags_filtered_by_allowlist() {
+        use wiremock::matchers::{header, method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/latest/meta-data/tags/instance"))
+            .and(header("X-aws-ec2-metadata-token", "tok"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("Name\nenv\nsecret-owner\nteam:billing\n"))
+            .mount(&server)
+            .await;
+        for (key, value) in [("Name", "web01"), ("env", "prod"), ("team%3Abilling", "payments")] {
+            Mock::given(method("GET"))
+                .and(path(format!("/latest/meta-data/tags/instance/{}", key)))
+                .respond_with(ResponseTemplate::new(200).set_body_string(value))
+                .mount(&server)
+                .await;
+        }
+
+        let client = Probe::new(&server.uri(), None).client().unwrap();
+        let allowed = vec!["Name".to_string(), "env".to_string(), "team:*".to_string()];
+        let tags = fetch_aws_tags(&client, &server.uri(), Some("tok"), &allowed).await;
+        assert_eq!(tags.get("Name").map(String::as_str), Some("web01"));
+        assert_eq!(tags.get("team:billing").map(String::as_str), Some("payments"));
+        assert!(!tags.contains_key("secret-owner"));
+
+        // Tags not enabled in the instance metadata options
+        let tags = fetch_aws_tags(&client, &server.uri(), None, &allowed).await;
+        assert!(tags.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_detection_deadline_and_pinned_provider() {
+        let deadline = Instant::now() + Duration::from_millis(50);
+        let probe = Probe::new("http://127.0.0.1:1", None).with_deadline(deadline);
+        assert!(probe.identify(std::future::pending::<Option<()>>()).await.is_none());
+        // Past the deadline, an identity request that answers still counts
+        assert!(probe.identify(async { Some(()) }).await.is_some());
+
+        let found = || async { Some(InstanceMetadata::default()) };
+        assert!(probed(false, found()).await.is_none());
+        assert!(probed(true, found()).await.is_some());
+
+        let settings = MetadataConfig {
+            provider: Some(MetadataProvider::None),
+            ..Default::default()
+        };
+        assert_eq!(InstanceMetadata::detect_cloud(&settings).await, InstanceMetadata::default());
+    }
+
+    #[test]
+    fn test_custom_metadata_sources() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("custom.json");
+        std::fs::write(&file, r#"{"rack": "r01", "owner": "storage", "u": 12, "spare": false}"#).unwrap();
+
+        let settings = MetadataConfig {
+            custom: Some(BTreeMap::from([("owner".to_string(), "platform".to_string())])),
+            custom_file: Some(file.to_string_lossy().into_owned()),
+            ..Default::default()
+        };
+        let env = vec![
+            ("SENTINEL_METADATA_RACK".to_string(), "r12".to_string()),
+            ("SENTINEL_METADATA_".to_string(), "ignored".to_string()),
+            ("SENTINEL_LOG".to_string(), "debug".to_string()),
+        ];
+        let custom = custom_metadata(&settings, env.into_iter());
+        assert_eq!(custom.get("rack").map(String::as_str), Some("r12"));
+        assert_eq!(custom.get("owner").map(String::as_str), Some("platform"));
+        assert_eq!(custom.get("u").map(String::as_str), Some("12"));
+        assert_eq!(custom.get("spare").map(String::as_str), Some("false"));
+        assert_eq!(custom.len(), 4);
+
+        std::fs::write(&file, r#"{"rack": {"row": 3}}"#).unwrap();
+        assert!(!custom_metadata(&settings, std::iter::empty()).contains_key("rack"));
+    }
+
+    #[tokio::test]
+    async fn test_imdsv2_only_refuses_v1_fallback() {
+        use wiremock::matchers::{method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        // A proxied IMDS that hands out no tokens but answers IMDSv1 requests
+        let server = MockServer::start().await;
+        Mock::given(method("PUT"))
+            .and(path("/latest/api/token"))
+            .respond_with(ResponseTemplate::new(403))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/latest/meta-data/instance-id"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("i-0aaa"))
+            .mount(&server)
+            .await;
+
+        let probe = Probe::new(&format!("{}/", server.uri()), None);
+        let metadata = InstanceMetadata::fetch_aws_metadata(&probe, false, &[]).await.unwrap();
+        assert_eq!(metadata.instance_id.as_deref(), Some("i-0aaa"));
+        assert!(InstanceMetadata::fetch_aws_metadata(&probe, true, &[]).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_azure_scale_set_and_retry() {
+        use wiremock::matchers::{header, method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/metadata/instance"))
+            .respond_with(ResponseTemplate::new(429))
+            .up_to_n_times(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/metadata/instance"))
+            .and(header("Metadata", "true"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "compute": {
+                    "vmId": "02aab8a4-74ef-476e-8182-f6d2ba4166a6",
+                    "name": "web-vmss_4",
+                    "location": "westeurope",
+                    "vmSize": "Standard_D2s_v3",
+                    "subscriptionId": "8d10da13-8125-4ba9-a717-bf7490507b3d",
+                    "resourceGroupName": "rg-web",
+                    "vmScaleSetName": "web-vmss",
+                    "tagsList": [{"name": "env", "value": "prod"}, {"name": "owner", "value": "ops"}],
+                }
+            })))
+            .mount(&server)
+            .await;
+
+        let metadata = InstanceMetadata::fetch_azure_metadata(&Probe::new(&server.uri(), None), &["env".to_string()])
+            .await
+            .unwrap();
+        assert_eq!(metadata.instance_type.as_deref(), Some("Standard_D2s_v3"));
+        assert_eq!(metadata.tags, BTreeMap::from([("env".to_string(), "prod".to_string())]));
+        let azure = metadata.azure.unwrap();
+        assert_eq!(azure.resource_group.as_deref(), Some("rg-web"));
+        assert_eq!(azure.scale_set.as_deref(), Some("web-vmss"));
+        assert_eq!(azure.scale_set_instance.as_deref(), Some("4"));
+    }
+
+    #[tokio::test]
+    async fn test_gcp_machine_type_project_and_labels() {
+        use wiremock::matchers::{header, method, path, query_param};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        for (key, value) in [
+            ("instance/id", "4520031799277581759"),
+            ("instance/zone", "projects/123/zones/europe-west1-b"),
+            ("instance/machine-type", "projects/123/machineTypes/e2-medium"),
+            ("instance/name", "web-1"),
+            ("project/project-id", "shop-prod"),
+        ] {
+            Mock::given(method("GET"))
+                .and(path(format!("/computeMetadata/v1/{}", key)))
+                .and(header("Metadata-Flavor", "Google"))
+                .respond_with(ResponseTemplate::new(200).set_body_string(value))
+                .mount(&server)
+                .await;
+        }
+        Mock::given(method("GET"))
+            .and(path("/computeMetadata/v1/instance/service-accounts/default/token"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "access_token": "ya29.token", "expires_in": 3599, "token_type": "Bearer"
+            })))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/compute/v1/projects/shop-prod/zones/europe-west1-b/instances/web-1"))
+            .and(query_param("fields", "labels"))
+            .and(header("Authorization", "Bearer ya29.token"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "labels": {"env": "prod", "cost-center": "42"}
+            })))
+            .mount(&server)
+            .await;
+
+        let probe = Probe::new(&server.uri(), None);
+        let metadata = InstanceMetadata::fetch_gcp_metadata(&probe).await.unwrap();
+        assert_eq!(metadata.region.as_deref(), Some("europe-west1"));
+        assert_eq!(metadata.instance_type.as_deref(), Some("e2-medium"));
+        let tags = InstanceMetadata::fetch_gcp_tags(&probe, &server.uri(), &["env".to_string()]).await;
+        assert_eq!(tags, BTreeMap::from([("env".to_string(), "prod".to_string())]));
+        let gcp = metadata.gcp.unwrap();
+        assert_eq!(gcp.project_id.as_deref(), Some("shop-prod"));
+        assert_eq!(gcp.zone.as_deref(), Some("europe-west1-b"));
+    }
+
+    #[tokio::test]
+    async fn test_alibaba_ignores_error_responses() {
+        use wiremock::matchers::{method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/latest/meta-data/instance-id"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("i-bp1abc"))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/latest/meta-data/region-id"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("cn-hangzhou"))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/latest/meta-data/instance/instance-type"))
+            .respond_with(ResponseTemplate::new(404).set_body_string("<html>Not Found</html>"))
+            .mount(&server)
+            .await;
+
+        let metadata = InstanceMetadata::fetch_alibaba_metadata(&Probe::new(&server.uri(), None)).await.unwrap();
+        assert_eq!(metadata.instance_id.as_deref(), Some("i-bp1abc"));
+        assert_eq!(metadata.region.as_deref(), Some("cn-hangzhou"));
+        assert_eq!(metadata.instance_type, None);
+    }
+
+    #[tokio::test]
+    async fn test_linode_metadata() {
+        use wiremock::matchers::{header, method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        Mock::given(method("PUT"))
+            .and(path("/v1/token"))
+            .and(header("Metadata-Token-Expiry-Seconds", "300"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("tok-123\n"))
+            .expect(1)
+            .mount(&server)
+            .await;
+        // Only answered with the token from the handshake
+        Mock::given(method("GET"))
+            .and(path("/v1/instance"))
+            .and(header("Metadata-Token", "tok-123"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "id": 40112233,
+                "label": "web-1",
+                "region": "us-east",
+                "type": "g6-standard-2"
+            })))
+            .mount(&server)
+            .await;
+
+        let metadata = InstanceMetadata::fetch_linode_metadata(&Probe::new(&server.uri(), None)).await.unwrap();
+        assert_eq!(metadata.instance_id.as_deref(), Some("40112233"));
+        assert_eq!(metadata.cloud_provider, Some(CloudProvider::Linode));
+        assert_eq!(metadata.region.as_deref(), Some("us-east"));
+        assert_eq!(metadata.instance_type.as_deref(), Some("g6-standard-2"));
+    }
+
+    #[tokio::test]
+    async fn test_linode_ignores_failed_token_and_error_responses() {
+        use wiremock::matchers::{method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        Mock::given(method("PUT"))
+            .and(path("/v1/token"))
+            .respond_with(ResponseTemplate::new(403))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/v1/instance"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({"id": 1})))
+            .expect(0)
+            .mount(&server)
+            .await;
+        assert!(InstanceMetadata::fetch_linode_metadata(&Probe::new(&server.uri(), None)).await.is_none());
+
+        let server = MockServer::start().await;
+        Mock::given(method("PUT"))
+            .and(path("/v1/token"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("tok-123"))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/v1/instance"))
+            .respond_with(ResponseTemplate::new(500).set_body_json(serde_json::json!({"id": 1})))
+            .mount(&server)
+            .await;
+        assert!(InstanceMetadata::fetch_linode_metadata(&Probe::new(&server.uri(), None)).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_vultr_metadata() {
+        use wiremock::matchers::{method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/v1.json"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "instance-v2-id": "a1b2c3d4-0000-4000-8000-000000000001",
+                "instanceid": "12345678",
+                "hostname": "web-1",
+                "region": {"regioncode": "EWR"}
+            })))
+            .mount(&server)
+            .await;
+
+        let metadata = InstanceMetadata::fetch_vultr_metadata(&Probe::new(&server.uri(), None)).await.unwrap();
+        assert_eq!(metadata.instance_id.as_deref(), Some("a1b2c3d4-0000-4000-8000-000000000001"));
+        assert_eq!(metadata.cloud_provider, Some(CloudProvider::Vultr));
+        assert_eq!(metadata.region.as_deref(), Some("EWR"));
+        // The plan is not in the metadata
+        assert_eq!(metadata.instance_type, None);
+
+        // Older instances only carry the v1 ID
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/v1.json"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "instanceid": "12345678",
+                "region": {"regioncode": "AMS"}
+            })))
+            .mount(&server)
+            .await;
+
+        let metadata = InstanceMetadata::fetch_vultr_metadata(&Probe::new(&server.uri(), None)).await.unwrap();
+        assert_eq!(metadata.instance_id.as_deref(), Some("12345678"));
+        assert_eq!(metadata.region.as_deref(), Some("AMS"));
+    }
+
+    #[tokio::test]
+    async fn test_vultr_ignores_error_and_non_json_responses() {
+        use wiremock::matchers::{method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/v1.json"))
+            .respond_with(ResponseTemplate::new(503).set_body_json(serde_json::json!({"instanceid": "12345678"})))
+            .mount(&server)
+            .await;
+        assert!(InstanceMetadata::fetch_vultr_metadata(&Probe::new(&server.uri(), None)).await.is_none());
+
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/v1.json"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("<html>captive portal</html>"))
+            .mount(&server)
+            .await;
+        assert!(InstanceMetadata::fetch_vultr_metadata(&Probe::new(&server.uri(), None)).await.is_none());
+
+        // Neither ID present
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/v1.json"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({"hostname": "web-1"})))
+            .mount(&server)
+            .await;
+        assert!(InstanceMetadata::fetch_vultr_metadata(&Probe::new(&server.uri(), None)).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_scaleway_metadata() {
+        use wiremock::matchers::{method, path, query_param};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/conf"))
+            .and(query_param("format", "json"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "id": "9f1c7c4e-0000-4000-8000-000000000002",
+                "name": "web-1",
+                "commercial_type": "DEV1-S",
+                "location": {"zone_id": "fr-par-1"}
+            })))
+            .mount(&server)
+            .await;
+
+        let metadata = InstanceMetadata::fetch_scaleway_metadata(&Probe::new(&server.uri(), None)).await.unwrap();
+        assert_eq!(metadata.instance_id.as_deref(), Some("9f1c7c4e-0000-4000-8000-000000000002"));
+        assert_eq!(metadata.cloud_provider, Some(CloudProvider::Scaleway));
+        assert_eq!(metadata.region.as_deref(), Some("fr-par"));
+        assert_eq!(metadata.instance_type.as_deref(), Some("DEV1-S"));
+    }
+
+    #[tokio::test]
+    async fn test_scaleway_ignores_error_and_non_json_responses() {
+        use wiremock::matchers::{method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/conf"))
+            .respond_with(ResponseTemplate::new(500).set_body_json(serde_json::json!({"id": "9f1c7c4e"})))
+            .mount(&server)
+            .await;
+        assert!(InstanceMetadata::fetch_scaleway_metadata(&Probe::new(&server.uri(), None)).await.is_none());
+
+        // Without the format parameter the service answers in shell-variable form
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/conf"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("ID=9f1c7c4e\nCOMMERCIAL_TYPE=DEV1-S\n"))
+            .mount(&server)
+            .await;
+        assert!(InstanceMetadata::fetch_scaleway_metadata(&Probe::new(&server.uri(), None)).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_ecs_task_metadata() {
+        use wiremock::matchers::{method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/v4/abc/task"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "Cluster": "arn:aws:ecs:eu-west-1:123456789012:cluster/prod",
+                "TaskARN": "arn:aws:ecs:eu-west-1:123456789012:task/prod/0f1e2d",
+                "LaunchType": "FARGATE",
+                "AvailabilityZone": "eu-west-1b",
+                "Limits": {"CPU": 0.5, "Memory": 1024},
+            })))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/v4/abc"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "Name": "sentinel-agent",
+                "Limits": {"CPU": 256, "Memory": 512},
+            })))
+            .mount(&server)
+            .await;
+
+        let metadata = InstanceMetadata::fetch_ecs_metadata(&format!("{}/v4/abc", server.uri()))
+            .await
+            .unwrap();
+        assert_eq!(metadata.instance_id.as_deref(), Some("arn:aws:ecs:eu-west-1:123456789012:task/prod/0f1e2d"));
+        assert_eq!(metadata.region.as_deref(), Some("eu-west-1"));
+        let ecs = metadata.ecs.unwrap();
+        assert_eq!(ecs.cluster, "arn:aws:ecs:eu-west-1:123456789012:cluster/prod");
+        assert_eq!(ecs.launch_type.as_deref(), Some("FARGATE"));
+        assert_eq!(ecs.cpu_limit, Some(256.0));
+        assert_eq!(ecs.memory_limit_mb, Some(512));
+    }
 }
\ No newline at end of file
@@ -1,9 +1,26 @@
+use async_trait::async_trait;
 use serde::{Deserialize, Serialize};
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use sysinfo::Disks;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, Instrument};
 
-use crate::config::{Config, DiskConfig};
+use crate::clock::ClockSkew;
+use crate::config::{Config, DeltaConfig, DiskConfig, Severity, TlsConfig};
+use crate::exec::ExecCollector;
+use crate::maintenance::MaintenanceWindow;
 use crate::metadata::SessionInfo;
+use crate::plugins;
+use crate::schedule::BatchWindow;
+use crate::scrape::ScrapeCollector;
+use crate::state::DeliveryGap;
+use crate::wasm;
+use crate::supervisor::panic_message;
+use crate::textfile::TextfileCollector;
+use crate::wsl::{self, WslVersion};
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct DiskMetric {
@@ -14,32 +31,374 @@ pub struct DiskMetric {
     pub used_space_bytes: u64,
     pub available_space_bytes: u64,
     pub usage_percentage: f64,
+    /// Labels added by the relabel pipeline
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub labels: BTreeMap<String, String>,
+    /// Set when this metric rolls up several samples; the other fields hold the latest one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub aggregate: Option<MetricAggregate>,
+    /// Level reached against `thresholds.disk_usage`, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub severity: Option<Severity>,
 }
 
-#[derive(Serialize, Debug)]
+/// A named value from a Prometheus-style source, such as node_exporter textfiles
+///
+/// Samples travel in the batch next to the disk metrics but skip the disk
+/// pipeline (thresholds, relabeling, scripts, delta filtering).
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Sample {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub labels: BTreeMap<String, String>,
+    /// NaN and infinities are sent as `null`, which JSON has no other way to carry
+    pub value: f64,
+    pub timestamp: u64,
+    #[serde(default)]
+    pub kind: SampleKind,
+    /// Example observation behind a counter or bucket, such as a trace ID
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exemplar: Option<Exemplar>,
+}
+
+/// Type of the metric family a sample belongs to, from its `# TYPE` line
+///
+/// OpenMetrics `unknown` is the same as Prometheus `untyped`.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SampleKind {
+    Counter,
+    Gauge,
+    Histogram,
+    GaugeHistogram,
+    Summary,
+    Info,
+    StateSet,
+    #[default]
+    Untyped,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Exemplar {
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub labels: BTreeMap<String, String>,
+    pub value: f64,
+    /// Unix seconds, with a fraction
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<f64>,
+}
+
+/// Statistics over the samples a rolled-up metric replaces
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct MetricAggregate {
+    pub samples: u32,
+    /// Timestamp of the earliest sample in the window
+    pub window_start: u64,
+    pub usage_percentage: SampleSummary,
+    pub used_space_bytes: SampleSummary,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct SampleSummary {
+    pub min: f64,
+    pub max: f64,
+    pub avg: f64,
+}
+
+impl SampleSummary {
+    fn single(value: f64) -> Self {
+        Self { min: value, max: value, avg: value }
+    }
+
+    fn merge(self, self_samples: u32, other: Self, other_samples: u32) -> Self {
+        let total = (self_samples + other_samples) as f64;
+        Self {
+            min: self.min.min(other.min),
+            max: self.max.max(other.max),
+            avg: (self.avg * self_samples as f64 + other.avg * other_samples as f64) / total,
+        }
+    }
+}
+
+impl MetricAggregate {
+    fn of(metric: &DiskMetric) -> Self {
+        metric.aggregate.clone().unwrap_or_else(|| Self {
+            samples: 1,
+            window_start: metric.timestamp,
+            usage_percentage: SampleSummary::single(metric.usage_percentage),
+            used_space_bytes: SampleSummary::single(metric.used_space_bytes as f64),
+        })
+    }
+
+    fn merge(self, other: Self) -> Self {
+        Self {
+            samples: self.samples + other.samples,
+            window_start: self.window_start.min(other.window_start),
+            usage_percentage: self.usage_percentage.merge(
+                self.samples,
+                other.usage_percentage,
+                other.samples,
+            ),
+            used_space_bytes: self.used_space_bytes.merge(
+                self.samples,
+                other.used_space_bytes,
+                other.samples,
+            ),
+        }
+    }
+}
+
+/// Roll up samples per series (device and mount point) into one metric each
+///
+/// The result keeps the latest sample's values and adds min/max/avg over the window.
+/// Already aggregated metrics merge losslessly, so re-queued batches can be aggregated again.
+pub fn aggregate(metrics: Vec<DiskMetric>) -> Vec<DiskMetric> {
+    let mut series: HashMap<(String, String), usize> = HashMap::new();
+    let mut aggregated: Vec<DiskMetric> = Vec::new();
+
+    for metric in metrics {
+        let key = (metric.device.clone(), metric.mount_point.clone());
+        let summary = MetricAggregate::of(&metric);
+
+        match series.get(&key) {
+            Some(&index) => {
+                let existing = &mut aggregated[index];
+                let merged = MetricAggregate::of(existing).merge(summary);
+                if metric.timestamp >= existing.timestamp {
+                    *existing = metric;
+                }
+                existing.aggregate = Some(merged);
+            }
+            None => {
+                series.insert(key, aggregated.len());
+                aggregated.push(DiskMetric { aggregate: Some(summary), ..metric });
+            }
+        }
+    }
+
+    aggregated
+}
+
+impl DiskMetric {
+    /// Approximate heap plus inline size, used for buffer accounting
+    pub fn estimated_size(&self) -> usize {
+        let labels: usize = self
+            .labels
+            .iter()
+            .map(|(name, value)| name.capacity() + value.capacity())
+            .sum();
+        std::mem::size_of::<Self>() + self.device.capacity() + self.mount_point.capacity() + labels
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
 pub struct MetricBatch {
     pub resource_id: String,
     pub hostname: String,
     pub timestamp: u64,
     pub metrics: Vec<DiskMetric>,
+    /// Latest value of each series from sample sources such as textfiles
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub samples: Vec<Sample>,
     pub session: SessionInfo,
+    /// Global labels from `agent.labels`
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub labels: BTreeMap<String, String>,
+    /// Tenant from `agent.org_id` and `agent.project`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub org_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub project: Option<String>,
+    /// Detected difference between the API server clock and this host's clock
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub clock_skew: Option<ClockSkew>,
+    /// Set on the first batch after a maintenance window so the gap is not alerted on
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub maintenance: Option<MaintenanceWindow>,
+    /// Delivered late from the spool; metric timestamps are when they were collected
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub replayed: bool,
+    /// Set on the first batch after a restart: how long nothing had been acknowledged
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub gap: Option<DeliveryGap>,
+    /// Wall-clock window the batch covers when `collection.align_flushes` is on
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub window: Option<BatchWindow>,
+}
+
+/// A source of metrics, run once per collection cycle
+///
+/// Collectors that wait on the network await inside `collect`; ones that make
+/// blocking calls are wrapped in a [`BlockingCollector`] instead of tying up a
+/// runtime thread.
+#[async_trait]
+pub trait MetricCollector: Send + Sync {
+    async fn collect(&self, ctx: CollectContext) -> Result<Collected, MetricError>;
+
+    fn is_enabled(&self) -> bool {
+        true
+    }
+}
+
+/// Output of one collector run
+#[derive(Debug, Default)]
+pub struct Collected {
+    pub metrics: Vec<DiskMetric>,
+    pub samples: Vec<Sample>,
+}
+
+impl Collected {
+    pub fn len(&self) -> usize {
+        self.metrics.len() + self.samples.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.metrics.is_empty() && self.samples.is_empty()
+    }
+}
+
+impl From<Vec<DiskMetric>> for Collected {
+    fn from(metrics: Vec<DiskMetric>) -> Self {
+        Self { metrics, samples: Vec::new() }
+    }
+}
+
+impl From<Vec<Sample>> for Collected {
+    fn from(samples: Vec<Sample>) -> Self {
+        Self { metrics: Vec::new(), samples }
+    }
+}
+
+/// Limits of one collector run
+///
+/// The run is abandoned at `deadline`, and `cancel` fires then so work the
+/// collector started elsewhere (requests, child tasks) can stop too.
+#[derive(Debug, Clone)]
+pub struct CollectContext {
+    pub deadline: tokio::time::Instant,
+    pub cancel: CancellationToken,
+}
+
+impl CollectContext {
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            deadline: tokio::time::Instant::now() + timeout,
+            cancel: CancellationToken::new(),
+        }
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancel.is_cancelled()
+    }
 }
 
-pub trait MetricCollector {
-    type Metric;
-    type Error;
+/// Runs a synchronous collector on the blocking pool
+///
+/// A run abandoned at its deadline keeps its thread (blocking syscalls such as
+/// statfs on a dead NFS mount cannot be cancelled), so the collector is skipped
+/// until that call finally returns.
+pub struct BlockingCollector<F> {
+    name: Arc<str>,
+    collect: Arc<F>,
+    /// Set while a collection is running, including one abandoned after a timeout
+    busy: Arc<AtomicBool>,
+}
 
-    fn collect(&self) -> Result<Vec<Self::Metric>, Self::Error>;
-    fn is_enabled(&self) -> bool;
+impl<F> BlockingCollector<F>
+where
+    F: Fn(&CollectContext) -> Result<Collected, MetricError> + Send + Sync + 'static,
+{
+    pub fn new(name: &str, collect: F) -> Self {
+        Self {
+            name: name.into(),
+            collect: Arc::new(collect),
+            busy: Arc::new(AtomicBool::new(false)),
+        }
+    }
 }
 
+#[async_trait]
+impl<F> MetricCollector for BlockingCollector<F>
+where
+    F: Fn(&CollectContext) -> Result<Collected, MetricError> + Send + Sync + 'static,
+{
+    async fn collect(&self, ctx: CollectContext) -> Result<Collected, MetricError> {
+        if self.busy.swap(true, Ordering::AcqRel) {
+            return Err(MetricError::StillRunning(self.name.to_string()));
+        }
+        let guard = BusyGuard(self.busy.clone());
+
+        let collect = self.collect.clone();
+        let task = tokio::task::spawn_blocking(move || {
+            let _guard = guard;
+            collect(&ctx)
+        });
+        task.await.map_err(|e| MetricError::Panicked {
+            collector: self.name.to_string(),
+            message: if e.is_panic() { panic_message(e.into_panic()) } else { e.to_string() },
+        })?
+    }
+}
+
+/// Disks are listed again at least this often, which also picks up file systems grown in place
+const DISK_RESCAN_INTERVAL: Duration = Duration::from_secs(300);
+
+/// Disks from the last scan, refreshed in place between scans
+struct DiskCache {
+    disks: Disks,
+    /// Signature of the mount table at the last scan, where the platform has one
+    mounts: Option<u64>,
+    scanned_at: Option<Instant>,
+}
+
+impl DiskCache {
+    /// Listing disks stats every mount, so only rescan when mounts change or the interval is up
+    fn needs_rescan(&self, mounts: Option<u64>, now: Instant) -> bool {
+        mounts != self.mounts
+            || self
+                .scanned_at
+                .is_none_or(|scanned_at| now.duration_since(scanned_at) >= DISK_RESCAN_INTERVAL)
+    }
+}
+
+#[derive(Clone)]
 pub struct DiskCollector {
     config: DiskConfig,
+    /// Running under WSL, where Windows drives and WSL plumbing are skipped
+    wsl: bool,
+    cache: Arc<Mutex<DiskCache>>,
 }
 
 impl DiskCollector {
     pub fn new(config: DiskConfig) -> Self {
-        Self { config }
+        Self {
+            config,
+            wsl: WslVersion::detect().is_some(),
+            cache: Arc::new(Mutex::new(DiskCache {
+                disks: Disks::new(),
+                mounts: None,
+                scanned_at: None,
+            })),
+        }
+    }
+
+    fn should_include(&self, disk: &sysinfo::Disk) -> bool {
+        let mount_point = disk.mount_point().to_string_lossy();
+        self.should_include_mount_point(&mount_point)
+            && self.should_include_device(&disk.name().to_string_lossy())
+            && self.should_include_file_system(&disk.file_system().to_string_lossy(), &mount_point)
+    }
+
+    /// Under WSL, skip Windows drives and WSL's own mounts unless listed in `include_mount_points`
+    fn should_include_file_system(&self, file_system: &str, mount_point: &str) -> bool {
+        if !self.wsl || !wsl::is_host_mount(file_system, mount_point) {
+            return true;
+        }
+        self.config
+            .include_mount_points
+            .iter()
+            .flatten()
+            .any(|pattern| mount_point.contains(pattern.as_str()))
     }
 
     fn should_include_mount_point(&self, mount_point: &str) -> bool {
@@ -66,6 +425,16 @@ impl DiskCollector {
         true
     }
 
+    fn should_include_device(&self, device: &str) -> bool {
+        let name = device.rsplit(['/', '\\']).next().unwrap_or(device);
+        !self
+            .config
+            .exclude_devices
+            .iter()
+            .flatten()
+            .any(|pattern| wildcard_match(pattern, name) || wildcard_match(pattern, device))
+    }
+
     fn create_disk_metric(&self, disk: &sysinfo::Disk, timestamp: u64) -> DiskMetric {
         let total_space = disk.total_space();
         let available_space = disk.available_space();
@@ -84,64 +453,326 @@ impl DiskCollector {
             used_space_bytes: used_space,
             available_space_bytes: available_space,
             usage_percentage,
+            labels: BTreeMap::new(),
+            aggregate: None,
+            severity: None,
         }
     }
 }
 
-impl MetricCollector for DiskCollector {
-    type Metric = DiskMetric;
-    type Error = MetricError;
-
-    fn collect(&self) -> Result<Vec<Self::Metric>, Self::Error> {
+impl DiskCollector {
+    /// Stat the included mounts; blocking, so registered as a [`BlockingCollector`]
+    ///
+    /// Once the run is cancelled the remaining mounts are not statted.
+    pub fn collect(&self, ctx: &CollectContext) -> Result<Vec<DiskMetric>, MetricError> {
         if !self.is_enabled() {
             return Ok(Vec::new());
         }
 
-        let disks = Disks::new_with_refreshed_list();
         let timestamp = SystemTime::now()
             .duration_since(UNIX_EPOCH)
             .map_err(|_| MetricError::TimestampError)?
             .as_secs();
 
-        let metrics = disks
-            .iter()
+        let mut cache = self.cache.lock().unwrap_or_else(PoisonError::into_inner);
+        let mounts = mount_table_signature();
+        let now = Instant::now();
+        let rescanned = cache.needs_rescan(mounts, now);
+        if rescanned {
+            cache.disks.refresh_list();
+            cache.mounts = mounts;
+            cache.scanned_at = Some(now);
+        }
+
+        // Filtered-out mounts are never statted between scans
+        let mut vanished = false;
+        let metrics = cache
+            .disks
+            .list_mut()
+            .iter_mut()
+            .filter(|disk| self.should_include(disk))
+            .take_while(|_| !ctx.is_cancelled())
             .filter_map(|disk| {
-                let mount_point = disk.mount_point().to_string_lossy();
-                if self.should_include_mount_point(&mount_point) {
-                    Some(self.create_disk_metric(disk, timestamp))
-                } else {
-                    None
+                if !rescanned && !disk.refresh() {
+                    vanished = true;
+                    return None;
                 }
+                Some(self.create_disk_metric(disk, timestamp))
             })
             .collect();
+        if vanished {
+            cache.scanned_at = None;
+        }
 
         Ok(metrics)
     }
 
-    fn is_enabled(&self) -> bool {
+    pub fn is_enabled(&self) -> bool {
         self.config.enabled
     }
 }
 
+/// Hash of the mount table, so a mount or unmount triggers a rescan on the next cycle
+#[cfg(target_os = "linux")]
+fn mount_table_signature() -> Option<u64> {
+    use std::hash::{Hash, Hasher};
+
+    let mounts = std::fs::read("/proc/self/mounts").ok()?;
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    mounts.hash(&mut hasher);
+    Some(hasher.finish())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn mount_table_signature() -> Option<u64> {
+    None
+}
+
+/// Whole-string match where `*` is any run of characters and `?` any one character
+pub fn wildcard_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // Position of the last `*` and the text it currently covers up to
+    let mut star: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match star {
+                Some((star_p, star_t)) => {
+                    p = star_p + 1;
+                    t = star_t + 1;
+                    star = Some((star_p, star_t + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+struct RegisteredCollector {
+    name: Arc<str>,
+    collector: Arc<dyn MetricCollector>,
+}
+
 pub struct MetricService {
-    disk_collector: DiskCollector,
+    collectors: Vec<RegisteredCollector>,
+    collector_timeout: Duration,
+    labels: BTreeMap<String, String>,
+    /// Labels learned from the environment (pod, cloud tags), below `labels` in precedence
+    metadata_labels: BTreeMap<String, String>,
+    org_id: Option<String>,
+    project: Option<String>,
+}
+
+/// Outcome of one collection cycle; a failing collector does not discard the others' metrics
+#[derive(Debug, Default)]
+pub struct CollectionReport {
+    pub metrics: Vec<DiskMetric>,
+    pub samples: Vec<Sample>,
+    pub failures: Vec<CollectorFailure>,
+}
+
+#[derive(Debug)]
+pub struct CollectorFailure {
+    pub collector: Arc<str>,
+    pub error: MetricError,
 }
 
 impl MetricService {
     pub fn new(config: &Config) -> Self {
-        Self {
-            disk_collector: DiskCollector::new(config.collection.disk.clone()),
+        let mut service = Self {
+            collectors: Vec::new(),
+            collector_timeout: Duration::from_secs(config.get_collector_timeout_seconds()),
+            labels: config.get_labels(),
+            metadata_labels: BTreeMap::new(),
+            org_id: config.get_org_id(),
+            project: config.get_project(),
+        };
+
+        let disk_collector = DiskCollector::new(config.collection.disk.clone());
+        if disk_collector.is_enabled() {
+            let collector = BlockingCollector::new("disk", move |ctx| {
+                disk_collector.collect(ctx).map(Collected::from)
+            });
+            service.register("disk", collector);
+        }
+
+        if let Some(directory) = config.get_textfile_directory() {
+            let textfile = TextfileCollector::new(directory);
+            let collector = BlockingCollector::new("textfile", move |ctx| Ok(textfile.collect(ctx).into()));
+            service.register("textfile", collector);
+        }
+
+        for command in config.get_exec_commands() {
+            let collector = ExecCollector::new(command);
+            service.register(&collector.name(), collector);
+        }
+
+        let targets = config.get_scrape_targets();
+        if !targets.is_empty() {
+            // The protocol, cipher and CA settings of every other outbound request,
+            // without the API's client certificate, which is not for third parties
+            let tls = config.api.tls.as_ref().map(|tls| TlsConfig {
+                client_cert: None,
+                client_key: None,
+                ..tls.clone()
+            });
+            let client = crate::tls::client_builder(tls.as_ref())
+                .map_err(|e| e.to_string())
+                .and_then(|builder| builder.build().map_err(|e| e.to_string()));
+            match client {
+                Ok(client) => {
+                    let max_body_bytes = config.get_scrape_max_body_bytes();
+                    for target in targets {
+                        let collector = ScrapeCollector::new(target.url.clone(), client.clone(), max_body_bytes);
+                        service.register(&format!("scrape:{}", target.get_name()), collector);
+                    }
+                }
+                Err(e) => error!(error = %e, "Skipping scrape targets"),
+            }
+        }
+
+        #[cfg(unix)]
+        if let Some(ingest) = config.get_ingest() {
+            match crate::ingest::IngestCollector::bind(&ingest.get_path()) {
+                Ok(collector) => {
+                    debug!(path = %collector.path().display(), "Listening on ingestion socket");
+                    service.register("ingest", collector);
+                }
+                Err(e) => error!(error = %e, "Skipping ingestion socket"),
+            }
         }
+
+        if let Some(directory) = config.get_plugins_directory() {
+            let (loaded, errors) = plugins::load_directory(&directory);
+            for e in errors {
+                error!(error = %e, "Skipping collector plugin");
+            }
+            for plugin in loaded {
+                debug!(collector = plugin.name(), "Loaded collector plugin");
+                let name = plugin.name().to_string();
+                let collector = BlockingCollector::new(&name, move |_| {
+                    plugin
+                        .collect()
+                        .map(Collected::from)
+                        .map_err(|e| MetricError::Plugin(e.to_string()))
+                });
+                service.register(&name, collector);
+            }
+        }
+
+        if let Some(wasm_config) = config.get_wasm() {
+            let (modules, errors) = wasm::load_directory(wasm_config);
+            for e in errors {
+                error!(error = %e, "Skipping WASM module");
+            }
+            for module in modules.into_iter().filter(|module| module.is_collector()) {
+                debug!(collector = module.name(), "Loaded WASM collector");
+                let name = module.name().to_string();
+                let collector = BlockingCollector::new(&name, move |_| {
+                    module
+                        .collect()
+                        .map(Collected::from)
+                        .map_err(|e| MetricError::Plugin(e.to_string()))
+                });
+                service.register(&name, collector);
+            }
+        }
+
+        service
+    }
+
+    /// Replace the labels learned from the environment; labels from `agent.labels` take precedence
+    pub fn set_metadata_labels(&mut self, labels: BTreeMap<String, String>) {
+        self.metadata_labels = labels;
+    }
+
+    fn register<C: MetricCollector + 'static>(&mut self, name: &str, collector: C) {
+        if !collector.is_enabled() {
+            debug!(collector = name, "Collector disabled, not registering");
+            return;
+        }
+        self.collectors.push(RegisteredCollector {
+            name: name.into(),
+            collector: Arc::new(collector),
+        });
+    }
+
+    pub async fn collect_all_metrics(&self) -> CollectionReport {
+        self.collect_metrics_except(|_| false).await
     }
 
-    pub fn collect_all_metrics(&self) -> Result<Vec<DiskMetric>, MetricError> {
-        let mut all_metrics = Vec::new();
+    /// Run collectors concurrently, so a cycle takes as long as the slowest collector
+    pub async fn collect_metrics_except<F>(&self, skip: F) -> CollectionReport
+    where
+        F: Fn(&str) -> bool,
+    {
+        let mut tasks = tokio::task::JoinSet::new();
+        for (index, collector) in self.collectors.iter().enumerate() {
+            if skip(&collector.name) {
+                debug!(collector = &*collector.name, "Skipping collector under host pressure");
+                continue;
+            }
+            let span = tracing::info_span!("collector", collector = &*collector.name);
+            let run = run_collector(collector.name.clone(), self.collector_timeout, collector.collector.clone());
+            tasks.spawn(
+                async move {
+                    let result = run.await;
+                    match &result {
+                        Ok(collected) => debug!(count = collected.len(), "Collected metrics"),
+                        Err(e) => error!(error = %e, "Collector failed"),
+                    }
+                    (index, result)
+                }
+                .instrument(span),
+            );
+        }
 
-        // Collect disk metrics
-        let disk_metrics = self.disk_collector.collect()?;
-        all_metrics.extend(disk_metrics);
+        let mut results = Vec::with_capacity(self.collectors.len());
+        while let Some(joined) = tasks.join_next().await {
+            // run_collector already turns collector panics into errors
+            if let Ok(result) = joined {
+                results.push(result);
+            }
+        }
+        // Keep the output in registration order regardless of which collector finished first
+        results.sort_by_key(|(index, _)| *index);
 
-        Ok(all_metrics)
+        let mut report = CollectionReport::default();
+        for (index, result) in results {
+            match result {
+                Ok(collected) => {
+                    report.metrics.extend(collected.metrics);
+                    report.samples.extend(collected.samples);
+                }
+                Err(error) => report.failures.push(CollectorFailure {
+                    collector: self.collectors[index].name.clone(),
+                    error,
+                }),
+            }
+        }
+        report
+    }
+
+    /// Registered collectors, in registration order
+    pub fn collector_names(&self) -> impl Iterator<Item = &str> {
+        self.collectors.iter().map(|collector| &*collector.name)
+    }
+
+    /// Run one collector as a cycle would, with the same timeout; `None` if there is no such collector
+    pub async fn collect_from(&self, name: &str) -> Option<Result<Collected, MetricError>> {
+        let collector = self.collectors.iter().find(|collector| &*collector.name == name)?;
+        Some(run_collector(collector.name.clone(), self.collector_timeout, collector.collector.clone()).await)
     }
 
     pub fn create_batch(
@@ -161,15 +792,131 @@ impl MetricService {
             hostname: hostname.to_string(),
             timestamp,
             metrics,
+            samples: Vec::new(),
             session,
+            labels: self
+                .metadata_labels
+                .iter()
+                .chain(&self.labels)
+                .map(|(key, value)| (key.clone(), value.clone()))
+                .collect(),
+            org_id: self.org_id.clone(),
+            project: self.project.clone(),
+            clock_skew: None,
+            maintenance: None,
+            replayed: false,
+            gap: None,
+            window: None,
         }
     }
 }
 
+/// Suppresses metrics whose values have not changed since they were last reported
+pub struct DeltaFilter {
+    tolerance: f64,
+    max_suppressed_seconds: Option<u64>,
+    last_reported: HashMap<(String, String), DiskMetric>,
+}
+
+impl DeltaFilter {
+    pub fn new(config: &DeltaConfig) -> Self {
+        Self {
+            tolerance: config.tolerance.unwrap_or(0.0),
+            max_suppressed_seconds: config.max_suppressed_seconds,
+            last_reported: HashMap::new(),
+        }
+    }
+
+    /// Return only the metrics that changed beyond the tolerance, remembering them as reported
+    pub fn filter(&mut self, metrics: Vec<DiskMetric>) -> Vec<DiskMetric> {
+        metrics
+            .into_iter()
+            .filter(|metric| {
+                let key = (metric.device.clone(), metric.mount_point.clone());
+                if let Some(previous) = self.last_reported.get(&key) {
+                    if !self.has_changed(previous, metric) {
+                        return false;
+                    }
+                }
+                self.last_reported.insert(key, metric.clone());
+                true
+            })
+            .collect()
+    }
+
+    fn has_changed(&self, previous: &DiskMetric, current: &DiskMetric) -> bool {
+        if let Some(max_suppressed) = self.max_suppressed_seconds {
+            if current.timestamp.saturating_sub(previous.timestamp) >= max_suppressed {
+                return true;
+            }
+        }
+
+        previous.total_space_bytes != current.total_space_bytes
+            || (current.usage_percentage - previous.usage_percentage).abs() > self.tolerance
+    }
+}
+
+/// Clears a collector's busy flag when its blocking task finishes, even by panicking
+struct BusyGuard(Arc<AtomicBool>);
+
+impl Drop for BusyGuard {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::Release);
+    }
+}
+
+/// Run a collector on its own task, bounded by `timeout` and isolated from panics
+///
+/// At the deadline the run's token is cancelled and the task aborted.
+async fn run_collector(
+    name: Arc<str>,
+    timeout: Duration,
+    collector: Arc<dyn MetricCollector>,
+) -> Result<Collected, MetricError> {
+    let ctx = CollectContext::new(timeout);
+    let (deadline, cancel) = (ctx.deadline, ctx.cancel.clone());
+    let mut task = tokio::spawn(async move { collector.collect(ctx).await });
+
+    let result = tokio::time::timeout_at(deadline, &mut task).await;
+    if result.is_err() {
+        cancel.cancel();
+        task.abort();
+    }
+    match result {
+        Ok(Ok(result)) => result,
+        Ok(Err(e)) if e.is_panic() => Err(MetricError::Panicked {
+            collector: name.to_string(),
+            message: panic_message(e.into_panic()),
+        }),
+        Ok(Err(e)) => Err(MetricError::Panicked {
+            collector: name.to_string(),
+            message: e.to_string(),
+        }),
+        Err(_) => Err(MetricError::Timeout {
+            collector: name.to_string(),
+            seconds: timeout.as_secs(),
+        }),
+    }
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum MetricError {
     #[error("Failed to get system timestamp")]
     TimestampError,
+    #[error("Collector {collector} did not finish within {seconds}s")]
+    Timeout { collector: String, seconds: u64 },
+    #[error("Collector {collector} panicked: {message}")]
+    Panicked { collector: String, message: String },
+    #[error("Collector {0} is still running from a previous cycle")]
+    StillRunning(String),
+    #[error("{0}")]
+    Plugin(String),
+    #[error("Command {command} failed: {message}")]
+    Exec { command: String, message: String },
+    #[error("Ingestion socket failed: {0}")]
+    Ingest(String),
+    #[error("Scrape of {url} failed: {message}")]
+    Scrape { url: String, message: String },
 }
 
 #[cfg(test)]
@@ -181,6 +928,7 @@ mod tests {
             enabled: true,
             include_mount_points: None,
             exclude_mount_points: None,
+            exclude_devices: None,
         }
     }
 
@@ -222,6 +970,73 @@ mod tests {
         assert!(!collector.should_include_mount_point("/proc/fs"));
     }
 
+    #[test]
+    fn test_device_filtering_exclude() {
+        let mut config = create_disk_config();
+        config.exclude_devices = Some(vec!["loop*".to_string(), "dm-?".to_string()]);
+        let collector = DiskCollector::new(config);
+
+        assert!(!collector.should_include_device("/dev/loop12"));
+        assert!(!collector.should_include_device("dm-0"));
+        assert!(collector.should_include_device("/dev/dm-10"));
+        assert!(collector.should_include_device("/dev/sda1"));
+        assert!(collector.should_include_device("/dev/nvme0n1p1"));
+    }
+
+    #[test]
+    fn test_wsl_skips_windows_drives() {
+        let mut collector = DiskCollector::new(create_disk_config());
+        collector.wsl = true;
+
+        assert!(collector.should_include_file_system("ext4", "/"));
+        assert!(!collector.should_include_file_system("overlay", "/usr/lib/wsl/drivers"));
+
+        // Explicitly listed drives are kept
+        collector.config.include_mount_points = Some(vec!["/mnt/d".to_string()]);
+        assert!(collector.should_include_file_system("9p", "/mnt/d"));
+        assert!(!collector.should_include_file_system("9p", "/mnt/c"));
+
+        collector.wsl = false;
+        assert!(collector.should_include_file_system("9p", "/mnt/c"));
+    }
+
+    #[test]
+    fn test_disks_are_rescanned_on_mount_changes_and_interval() {
+        let now = Instant::now();
+        let mut cache = DiskCache {
+            disks: Disks::new(),
+            mounts: None,
+            scanned_at: None,
+        };
+        assert!(cache.needs_rescan(None, now));
+
+        cache.mounts = Some(1);
+        cache.scanned_at = Some(now);
+        assert!(!cache.needs_rescan(Some(1), now + Duration::from_secs(60)));
+        assert!(cache.needs_rescan(Some(2), now + Duration::from_secs(60)));
+        assert!(cache.needs_rescan(Some(1), now + DISK_RESCAN_INTERVAL));
+
+        // Refreshed in place, the same disks are reported again
+        let collector = DiskCollector::new(create_disk_config());
+        let mount_points = |metrics: Vec<DiskMetric>| -> Vec<String> {
+            metrics.into_iter().map(|metric| metric.mount_point).collect()
+        };
+        let ctx = CollectContext::new(Duration::from_secs(5));
+        let first = mount_points(collector.collect(&ctx).unwrap());
+        assert!(collector.cache.lock().unwrap().scanned_at.is_some());
+        assert_eq!(mount_points(collector.collect(&ctx).unwrap()), first);
+    }
+
+    #[test]
+    fn test_wildcard_match() {
+        assert!(wildcard_match("zram*", "zram0"));
+        assert!(wildcard_match("*", ""));
+        assert!(wildcard_match("sd*1", "sda11"));
+        assert!(!wildcard_match("sd*1", "sda12"));
+        assert!(!wildcard_match("loop", "loop0"));
+        assert!(wildcard_match("/dev/mapper/*", "/dev/mapper/vg-root"));
+    }
+
     #[test]
     fn test_metric_batch_creation() {
         let metric = DiskMetric {
@@ -232,11 +1047,18 @@ mod tests {
             used_space_bytes: 500000,
             available_space_bytes: 500000,
             usage_percentage: 0.5,
+            labels: BTreeMap::new(),
+            aggregate: None,
+            severity: None,
         };
 
         let config = Config::load_from_str(r#"
 agent:
   id: "test-agent"
+  labels:
+    env: prod
+  org_id: org_42
+  project: storage
 api:
   endpoint: "https://api.example.com"
 collection:
@@ -245,13 +1067,62 @@ collection:
     enabled: true
 "#).unwrap();
 
-        let service = MetricService::new(&config);
+        let mut service = MetricService::new(&config);
+        service.set_metadata_labels(BTreeMap::from([
+            ("env".to_string(), "staging".to_string()),
+            ("tag.Name".to_string(), "web01".to_string()),
+        ]));
         let session = crate::metadata::SessionInfo::generate();
         let batch = service.create_batch(vec![metric], "test-id", "test-host", session);
 
         assert_eq!(batch.resource_id, "test-id");
         assert_eq!(batch.hostname, "test-host");
         assert_eq!(batch.metrics.len(), 1);
+        assert_eq!(batch.labels.get("env").map(String::as_str), Some("prod"));
+        assert_eq!(batch.labels.get("tag.Name").map(String::as_str), Some("web01"));
+        assert_eq!(batch.org_id.as_deref(), Some("org_42"));
+        assert_eq!(batch.project.as_deref(), Some("storage"));
+    }
+
+    fn create_metric(timestamp: u64, usage_percentage: f64) -> DiskMetric {
+        DiskMetric {
+            timestamp,
+            device: "/dev/sda1".to_string(),
+            mount_point: "/".to_string(),
+            total_space_bytes: 1000000,
+            used_space_bytes: (1000000.0 * usage_percentage) as u64,
+            available_space_bytes: 1000000 - (1000000.0 * usage_percentage) as u64,
+            usage_percentage,
+            labels: BTreeMap::new(),
+            aggregate: None,
+            severity: None,
+        }
+    }
+
+    #[test]
+    fn test_delta_filter_suppresses_unchanged() {
+        let mut filter = DeltaFilter::new(&DeltaConfig {
+            enabled: true,
+            tolerance: Some(0.01),
+            max_suppressed_seconds: None,
+        });
+
+        assert_eq!(filter.filter(vec![create_metric(100, 0.50)]).len(), 1);
+        assert!(filter.filter(vec![create_metric(160, 0.505)]).is_empty());
+        assert_eq!(filter.filter(vec![create_metric(220, 0.52)]).len(), 1);
+    }
+
+    #[test]
+    fn test_delta_filter_reports_after_max_suppressed() {
+        let mut filter = DeltaFilter::new(&DeltaConfig {
+            enabled: true,
+            tolerance: None,
+            max_suppressed_seconds: Some(300),
+        });
+
+        assert_eq!(filter.filter(vec![create_metric(100, 0.5)]).len(), 1);
+        assert!(filter.filter(vec![create_metric(200, 0.5)]).is_empty());
+        assert_eq!(filter.filter(vec![create_metric(400, 0.5)]).len(), 1);
     }
 
     #[test]
@@ -260,7 +1131,154 @@ collection:
         config.enabled = false;
         let collector = DiskCollector::new(config);
 
-        let result = collector.collect().unwrap();
+        let result = collector.collect(&CollectContext::new(Duration::from_secs(5))).unwrap();
         assert!(result.is_empty());
     }
+
+    fn slow_metric(mount_point: &'static str) -> Result<Collected, MetricError> {
+        std::thread::sleep(Duration::from_millis(300));
+        let mut metric = create_metric(0, 0.5);
+        metric.mount_point = mount_point.to_string();
+        Ok(vec![metric].into())
+    }
+
+    #[tokio::test]
+    async fn test_collectors_run_concurrently() {
+        let mut service = MetricService {
+            collectors: Vec::new(),
+            collector_timeout: Duration::from_secs(5),
+            labels: BTreeMap::new(),
+            metadata_labels: BTreeMap::new(),
+            org_id: None,
+            project: None,
+        };
+        service.register("first", BlockingCollector::new("first", |_| slow_metric("/first")));
+        service.register("second", BlockingCollector::new("second", |_| slow_metric("/second")));
+        service.register("third", BlockingCollector::new("third", |_| slow_metric("/third")));
+
+        let started = std::time::Instant::now();
+        let report = service.collect_all_metrics().await;
+        assert!(started.elapsed() < Duration::from_millis(800));
+
+        let mount_points: Vec<&str> =
+            report.metrics.iter().map(|m| m.mount_point.as_str()).collect();
+        assert_eq!(mount_points, vec!["/first", "/second", "/third"]);
+        assert!(report.failures.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_run_collector_times_out_and_skips_while_hung() {
+        let calls = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counter = calls.clone();
+        // A panic on the blocking thread would go unnoticed, so the hung call reports what it saw
+        let (observed, cancellation) = std::sync::mpsc::channel();
+        let collector: Arc<dyn MetricCollector> = Arc::new(BlockingCollector::new("slow", move |ctx| {
+            if counter.fetch_add(1, Ordering::SeqCst) == 0 {
+                std::thread::sleep(Duration::from_millis(500));
+                let _ = observed.send(ctx.is_cancelled());
+            }
+            Ok(vec![create_metric(0, 0.5)].into())
+        }));
+
+        let result = run_collector("slow".into(), Duration::from_millis(50), collector.clone()).await;
+        assert!(matches!(result, Err(MetricError::Timeout { .. })));
+
+        let result = run_collector("slow".into(), Duration::from_millis(50), collector.clone()).await;
+        assert!(matches!(result, Err(MetricError::StillRunning(_))));
+
+        tokio::time::sleep(Duration::from_millis(600)).await;
+        // The timed out call saw its cancellation once it woke up
+        assert_eq!(cancellation.recv_timeout(Duration::from_secs(1)), Ok(true));
+        let result = run_collector("slow".into(), Duration::from_secs(1), collector).await;
+        assert_eq!(result.unwrap().len(), 1);
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_run_collector_catches_panics() {
+        let collector = BlockingCollector::new("broken", |_| panic!("statfs failed"));
+        let busy = collector.busy.clone();
+        let result = run_collector("broken".into(), Duration::from_secs(1), Arc::new(collector)).await;
+        match result {
+            Err(MetricError::Panicked { collector, message }) => {
+                assert_eq!(collector, "broken");
+                assert_eq!(message, "statfs failed");
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert!(!busy.load(Ordering::Acquire));
+    }
+
+    /// Waits on the network until told to stop
+    struct HangingCollector {
+        cancelled: Arc<AtomicBool>,
+    }
+
+    #[async_trait]
+    impl MetricCollector for HangingCollector {
+        async fn collect(&self, ctx: CollectContext) -> Result<Collected, MetricError> {
+            let cancelled = self.cancelled.clone();
+            tokio::spawn(async move {
+                ctx.cancel.cancelled().await;
+                cancelled.store(true, Ordering::SeqCst);
+            });
+            std::future::pending().await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_async_collector_is_cancelled_at_its_deadline() {
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let collector = Arc::new(HangingCollector { cancelled: cancelled.clone() });
+
+        let started = std::time::Instant::now();
+        let result = run_collector("hanging".into(), Duration::from_millis(50), collector.clone()).await;
+        assert!(matches!(result, Err(MetricError::Timeout { .. })));
+        assert!(started.elapsed() < Duration::from_millis(500));
+
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(cancelled.load(Ordering::SeqCst));
+
+        // Nothing was left running, so the next cycle runs it again
+        let result = run_collector("hanging".into(), Duration::from_millis(50), collector).await;
+        assert!(matches!(result, Err(MetricError::Timeout { .. })));
+    }
+
+    #[test]
+    fn test_aggregate_rolls_up_series() {
+        let mut other = create_metric(150, 0.9);
+        other.mount_point = "/home".to_string();
+        let metrics = vec![
+            create_metric(100, 0.2),
+            other,
+            create_metric(160, 0.6),
+            create_metric(130, 0.4),
+        ];
+
+        let aggregated = aggregate(metrics);
+        assert_eq!(aggregated.len(), 2);
+
+        let root = &aggregated[0];
+        assert_eq!(root.timestamp, 160);
+        assert_eq!(root.usage_percentage, 0.6);
+        let summary = root.aggregate.as_ref().unwrap();
+        assert_eq!(summary.samples, 3);
+        assert_eq!(summary.window_start, 100);
+        assert_eq!(summary.usage_percentage.min, 0.2);
+        assert_eq!(summary.usage_percentage.max, 0.6);
+        assert!((summary.usage_percentage.avg - 0.4).abs() < 1e-9);
+
+        assert_eq!(aggregated[1].aggregate.as_ref().unwrap().samples, 1);
+    }
+
+    #[test]
+    fn test_aggregate_merges_previous_rollups() {
+        let first = aggregate(vec![create_metric(100, 0.2), create_metric(110, 0.4)]);
+        let merged = aggregate(first.into_iter().chain([create_metric(120, 0.9)]).collect());
+
+        let summary = merged[0].aggregate.as_ref().unwrap();
+        assert_eq!(summary.samples, 3);
+        assert_eq!(summary.usage_percentage.max, 0.9);
+        assert!((summary.usage_percentage.avg - 0.5).abs() < 1e-9);
+    }
 }
@@ -0,0 +1,97 @@
+//! Network identity reported at registration with `metadata.network`
+//!
+//! Lets the platform link a resource to load balancer targets and CMDB
+//! entries. The primary addresses are the ones the host uses for its default
+//! route; no packet is sent to find them.
+
+use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use sysinfo::Networks;
+
+/// Documentation addresses (RFC 5737, RFC 3849), only used for a route lookup
+const ROUTE_PROBE_V4: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)), 9);
+const ROUTE_PROBE_V6: SocketAddr =
+    SocketAddr::new(IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1)), 9);
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct NetworkIdentity {
+    /// Source address of the IPv4 default route
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub primary_ipv4: Option<Ipv4Addr>,
+    /// Source address of the IPv6 default route
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub primary_ipv6: Option<Ipv6Addr>,
+    /// MAC addresses of the physical interfaces, sorted
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub mac_addresses: Vec<String>,
+}
+
+impl NetworkIdentity {
+    pub fn detect() -> Self {
+        let primary_ipv4 = match route_source(ROUTE_PROBE_V4) {
+            Some(IpAddr::V4(address)) => Some(address),
+            _ => None,
+        };
+        let primary_ipv6 = match route_source(ROUTE_PROBE_V6) {
+            Some(IpAddr::V6(address)) => Some(address),
+            _ => None,
+        };
+
+        let networks = Networks::new_with_refreshed_list();
+        let mut mac_addresses: Vec<String> = networks
+            .iter()
+            .filter(|(name, _)| is_physical(name))
+            .map(|(_, data)| data.mac_address())
+            .filter(|mac| !mac.is_unspecified())
+            .map(|mac| mac.to_string().to_lowercase())
+            .collect();
+        mac_addresses.sort();
+        mac_addresses.dedup();
+
+        Self {
+            primary_ipv4,
+            primary_ipv6,
+            mac_addresses,
+        }
+    }
+}
+
+/// Local address the kernel would send from to reach `target`
+fn route_source(target: SocketAddr) -> Option<IpAddr> {
+    let bind: SocketAddr = match target {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = UdpSocket::bind(bind).ok()?;
+    // Connecting a UDP socket only selects a route
+    socket.connect(target).ok()?;
+    let address = socket.local_addr().ok()?.ip();
+    (!address.is_unspecified() && !address.is_loopback()).then_some(address)
+}
+
+/// Whether an interface is backed by a device, rather than a bridge, veth or tunnel
+#[cfg(target_os = "linux")]
+fn is_physical(name: &str) -> bool {
+    std::path::Path::new("/sys/class/net").join(name).join("device").exists()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn is_physical(name: &str) -> bool {
+    !name.starts_with("lo")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detect_skips_loopback() {
+        let identity = NetworkIdentity::detect();
+        assert!(identity.primary_ipv4.is_none_or(|address| !address.is_loopback()));
+        assert!(identity.mac_addresses.iter().all(|mac| mac != "00:00:00:00:00:00"));
+        assert!(identity.mac_addresses.windows(2).all(|pair| pair[0] < pair[1]));
+
+        let json = serde_json::to_value(NetworkIdentity::default()).unwrap();
+        assert_eq!(json, serde_json::json!({}));
+    }
+}
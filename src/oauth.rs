@@ -0,0 +1,1167 @@
+//! OAuth 2.0 access tokens for the API
+//!
+//! With `api.oauth`, the agent sends a short-lived access token as the bearer
+//! token instead of a static API key. Servers use the client credentials
+//! grant with `client_secret`. Laptops and workstations run `sentinel-agent
+//! login` instead, the device authorization grant (RFC 8628): the user
+//! approves the agent in a browser, and the tokens are saved (see
+//! `token_store`), so no client secret is kept on the host. Tokens are cached
+//! and fetched or refreshed again shortly before they expire; transient
+//! token endpoint failures are retried with backoff while the old token is
+//! still served, and a failed background refresh waits before the next.
+//! Expiry is counted on the monotonic clock; saved tokens keep it on the
+//! token endpoint's clock, read from its `Date` header, so a skewed host
+//! clock does not matter.
+//!
+//! On a clean shutdown the access token is revoked (RFC 7009) and dropped;
+//! after deregistration the refresh token is revoked too and the token file
+//! removed, so a decommissioned host keeps no valid credentials. Refreshes
+//! still in flight then are discarded instead of saved.
+
+use chrono::Utc;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use tokio::sync::Mutex;
+
+use crate::clock;
+use crate::config::OAuthConfig;
+use crate::token_store::TokenStore;
+
+const DEVICE_CODE_GRANT: &str = "urn:ietf:params:oauth:grant-type:device_code";
+
+/// Polling interval when the authorization server does not name one (RFC 8628)
+const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Fetch a new token this long before the current one expires
+const REFRESH_MARGIN: Duration = Duration::from_secs(60);
+
+/// Lifetime assumed when the token response has no `expires_in`
+const DEFAULT_TOKEN_LIFETIME: Duration = Duration::from_secs(300);
+
+/// Token requests made before a transient failure is returned
+const TOKEN_ATTEMPTS: u32 = 4;
+
+/// Backoff after the first failed token request, doubled after each further one
+const TOKEN_RETRY_BACKOFF: Duration = Duration::from_millis(250);
+
+/// Wait after a failed background refresh before the next one; the cached token is served meanwhile
+const REFRESH_FAILURE_BACKOFF: Duration = Duration::from_secs(30);
+
+#[derive(Error, Debug)]
+pub enum OAuthError {
+    #[error("token request failed: {0}")]
+    Request(String),
+    #[error("token endpoint returned error status {status}: {body}")]
+    Response { status: u16, body: String },
+    #[error("failed to parse token response: {0}")]
+    Parse(String),
+    #[error("no OAuth token saved; run `sentinel-agent login`")]
+    LoginRequired,
+    #[error("login failed: {0}")]
+    Login(String),
+    #[error("api.oauth.device_authorization_url is not set")]
+    DeviceFlowUnavailable,
+    #[error("tokens were revoked while a new one was requested")]
+    Revoked,
+    #[error("failed to {action} saved tokens in {path}: {message}")]
+    TokenFile {
+        action: &'static str,
+        path: String,
+        message: String,
+    },
+}
+
+impl OAuthError {
+    /// Failures worth retrying: the endpoint was unreachable, overloaded or failing
+    fn is_transient(&self) -> bool {
+        match self {
+            Self::Request(_) => true,
+            Self::Response { status, .. } => *status == 429 || (500..=599).contains(status),
+            _ => false,
+        }
+    }
+}
+
+/// Error body of the token endpoint (RFC 6749 section 5.2)
+#[derive(Debug, Deserialize)]
+struct TokenErrorResponse {
+    error: String,
+    #[serde(default)]
+    error_description: Option<String>,
+}
+
+/// Answer of the device authorization endpoint, shown to the user by `login`
+#[derive(Debug, Clone, Deserialize)]
+pub struct DeviceAuthorization {
+    device_code: String,
+    pub user_code: String,
+    pub verification_uri: String,
+    /// `verification_uri` with the code filled in, when supported
+    #[serde(default)]
+    pub verification_uri_complete: Option<String>,
+    pub expires_in: u64,
+    #[serde(default)]
+    interval: Option<u64>,
+}
+
+/// Tokens from `login`, refreshed by the agent as they expire
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StoredToken {
+    pub access_token: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub refresh_token: Option<String>,
+    /// Unix timestamp at which `access_token` expires, on the token endpoint's clock
+    pub expires_at: i64,
+    /// Token endpoint clock minus local clock when the token was issued, in seconds
+    #[serde(default)]
+    pub clock_offset: i64,
+}
+
+impl StoredToken {
+    /// `requested_at` is when the request was sent, `clock_offset` the server's lead over the local clock
+    fn from_response(
+        token: TokenResponse,
+        previous_refresh_token: Option<String>,
+        requested_at: Instant,
+        clock_offset: i64,
+    ) -> Self {
+        let requested_at_unix = Utc::now().timestamp() - requested_at.elapsed().as_secs() as i64;
+        Self {
+            expires_at: requested_at_unix + clock_offset + token.lifetime().as_secs() as i64,
+            access_token: token.access_token,
+            // Servers may keep the refresh token and not send it again
+            refresh_token: token.refresh_token.or(previous_refresh_token),
+            clock_offset,
+        }
+    }
+
+    /// Time left until expiry, as an `Instant` for the in-memory cache
+    ///
+    /// `expires_at` is compared with the server's time, from the latest known
+    /// `clock_offset` or the one saved with the token, so a skewed or stepped
+    /// local clock neither hides the expiry nor expires the token early.
+    fn cached(&self, clock_offset: Option<i64>) -> AccessToken {
+        let server_now = Utc::now().timestamp() + clock_offset.unwrap_or(self.clock_offset);
+        let remaining = (self.expires_at - server_now).max(0) as u64;
+        AccessToken {
+            value: self.access_token.clone(),
+            expires_at: Instant::now() + Duration::from_secs(remaining),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    #[serde(default)]
+    token_type: Option<String>,
+    #[serde(default)]
+    expires_in: Option<u64>,
+    #[serde(default)]
+    refresh_token: Option<String>,
+}
+
+impl TokenResponse {
+    fn lifetime(&self) -> Duration {
+        self.expires_in.map(Duration::from_secs).unwrap_or(DEFAULT_TOKEN_LIFETIME)
+    }
+}
+
+/// What a token is used for, which selects the scopes it is requested with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    /// Registering, renewing and deregistering the resource (`registration_scopes`)
+    Registration,
+    /// Submitting metrics (`metrics_scopes`)
+    Metrics,
+    /// Heartbeats, commands and remote configuration (`scopes`)
+    Other,
+}
+
+#[derive(Debug, Clone)]
+struct AccessToken {
+    value: String,
+    expires_at: Instant,
+}
+
+impl AccessToken {
+    /// Expiry counted on the monotonic clock from the request, so the token is
+    /// never used past its expiry and wall clock changes do not move it
+    fn issued(token: &TokenResponse, requested_at: Instant) -> Self {
+        Self {
+            value: token.access_token.clone(),
+            expires_at: requested_at + token.lifetime(),
+        }
+    }
+
+    fn is_fresh(&self, now: Instant) -> bool {
+        now + REFRESH_MARGIN < self.expires_at
+    }
+
+    fn is_valid(&self, now: Instant) -> bool {
+        now < self.expires_at
+    }
+}
+
+/// Hands out access tokens for `api.oauth`, fetching them as needed
+#[derive(Debug)]
+pub struct OAuthManager {
+    client: reqwest::Client,
+    settings: OAuthConfig,
+    /// Where `login` saves tokens, used without `client_secret`
+    store: TokenStore,
+    /// Tokens by requested scope; held while fetching, so concurrent requests wait for one token
+    tokens: Mutex<HashMap<String, AccessToken>>,
+    /// Scopes whose token a background task is replacing before it expires
+    refreshing: std::sync::Mutex<HashSet<String>>,
+    /// Scopes whose background refresh failed, with when to try again
+    refresh_backoff: std::sync::Mutex<HashMap<String, Instant>>,
+    /// Held from reading the saved refresh token until its successor is saved, so a rotated
+    /// refresh token is never sent twice and `revoke` waits for a refresh in flight
+    refresh_lock: Mutex<()>,
+    /// Bumped by `revoke`, so requests started before it neither cache nor save their tokens
+    generation: AtomicU64,
+    /// Token endpoint clock minus local clock, from response `Date` headers
+    clock_offset: std::sync::Mutex<Option<i64>>,
+}
+
+impl OAuthManager {
+    /// `client` carries the API's TLS settings and timeout
+    pub fn new(client: reqwest::Client, settings: OAuthConfig) -> Result<Self, OAuthError> {
+        let store = TokenStore::from_settings(settings.token_cache.as_ref())?;
+        Ok(Self::with_store(client, settings, store))
+    }
+
+    pub fn with_store(client: reqwest::Client, settings: OAuthConfig, store: TokenStore) -> Self {
+        Self {
+            client,
+            settings,
+            store,
+            tokens: Mutex::new(HashMap::new()),
+            refreshing: std::sync::Mutex::new(HashSet::new()),
+            refresh_backoff: std::sync::Mutex::new(HashMap::new()),
+            refresh_lock: Mutex::new(()),
+            generation: AtomicU64::new(0),
+            clock_offset: std::sync::Mutex::new(None),
+        }
+    }
+
+    pub fn token_store(&self) -> &TokenStore {
+        &self.store
+    }
+
+    /// A valid access token for `operation`, from the cache or the token endpoint
+    ///
+    /// A cached token within the refresh margin of its expiry is still handed
+    /// out while a background task fetches the next one, so a slow or failing
+    /// token endpoint only blocks requests once the token has expired.
+    /// Operations with the same scopes share a token.
+    pub async fn access_token(self: &Arc<Self>, operation: Operation) -> Result<String, OAuthError> {
+        let scope = self.scope(operation);
+        let key = scope.clone().unwrap_or_default();
+        let mut cached = self.tokens.lock().await;
+        let generation = self.generation.load(Ordering::SeqCst);
+        let now = Instant::now();
+        if let Some(token) = cached.get(&key) {
+            if token.is_fresh(now) {
+                return Ok(token.value.clone());
+            }
+            if token.is_valid(now) {
+                self.refresh_in_background(scope);
+                return Ok(token.value.clone());
+            }
+        }
+
+        let token = self.request_new_token(scope.as_deref(), generation).await?;
+        let value = token.value.clone();
+        cached.insert(key, token);
+        Ok(value)
+    }
+
+    /// Drop the access token `rejected` after the API refused it, so the next request fetches a new one
+    ///
+    /// A token that already replaced `rejected`, e.g. after concurrent
+    /// requests were refused, is kept. A token saved by `login` is marked
+    /// expired, so it is refreshed rather than read back from the file.
+    pub async fn invalidate(&self, operation: Operation, rejected: &str) -> Result<(), OAuthError> {
+        let key = self.scope(operation).unwrap_or_default();
+        {
+            let mut cached = self.tokens.lock().await;
+            if cached.get(&key).is_some_and(|token| token.value == rejected) {
+                cached.remove(&key);
+            }
+        }
+        if self.settings.client_secret.is_none() {
+            let _refresh = self.refresh_lock.lock().await;
+            if let Some(stored) = self.store.load()?.filter(|stored| stored.access_token == rejected) {
+                self.store.save(&StoredToken { expires_at: 0, ..stored })?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Replace the token for `scope` without blocking the caller
+    ///
+    /// After a failure the next attempt waits `REFRESH_FAILURE_BACKOFF`, so a
+    /// rejected refresh token is not sent again with every request until the
+    /// cached token expires.
+    fn refresh_in_background(self: &Arc<Self>, scope: Option<String>) {
+        let key = scope.clone().unwrap_or_default();
+        if let Some(retry_at) = self.refresh_backoff.lock().unwrap().get(&key) {
+            if Instant::now() < *retry_at {
+                return;
+            }
+        }
+        if !self.refreshing.lock().unwrap().insert(key.clone()) {
+            return;
+        }
+        let manager = Arc::clone(self);
+        let generation = self.generation.load(Ordering::SeqCst);
+        tokio::spawn(async move {
+            let _in_progress = RefreshInProgress {
+                refreshing: &manager.refreshing,
+                key: key.clone(),
+            };
+            match manager.request_new_token(scope.as_deref(), generation).await {
+                Ok(token) => {
+                    manager.refresh_backoff.lock().unwrap().remove(&key);
+                    let mut cached = manager.tokens.lock().await;
+                    // `revoke` bumps the generation before draining the cache under this lock
+                    if manager.generation.load(Ordering::SeqCst) == generation {
+                        cached.insert(key, token);
+                    }
+                }
+                Err(OAuthError::Revoked) => {}
+                Err(e) => {
+                    tracing::warn!(error = %e, "Failed to refresh API access token");
+                    let retry_at = Instant::now() + REFRESH_FAILURE_BACKOFF;
+                    manager.refresh_backoff.lock().unwrap().insert(key, retry_at);
+                }
+            }
+        });
+    }
+
+    /// A new token from the token endpoint, retrying transient failures with backoff
+    ///
+    /// `generation` is the one the request started in; a token saved by
+    /// `login` is not saved again once `revoke` has moved past it.
+    async fn request_new_token(&self, scope: Option<&str>, generation: u64) -> Result<AccessToken, OAuthError> {
+        let mut backoff = TOKEN_RETRY_BACKOFF;
+        let mut attempt = 1;
+        loop {
+            let result = match &self.settings.client_secret {
+                Some(secret) => self.fetch(secret, scope).await,
+                None => self.stored_token(scope, generation).await,
+            };
+            match result {
+                Err(e) if e.is_transient() && attempt < TOKEN_ATTEMPTS => {
+                    // Jitter keeps a fleet of agents from retrying in lockstep
+                    let jitter = rand::thread_rng().gen_range(0..=backoff.as_millis() as u64 / 2);
+                    let delay = backoff + Duration::from_millis(jitter);
+                    tracing::debug!(attempt, error = %e, "Token request failed, retrying in {:?}", delay);
+                    tokio::time::sleep(delay).await;
+                    backoff *= 2;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Revoke and drop the access token; with `forget_login`, also the saved refresh token
+    ///
+    /// Without `revocation_url` the tokens are only dropped. The token file
+    /// keeps the refresh token on a plain shutdown, so the next start does not
+    /// need another `login`.
+    pub async fn revoke(&self, forget_login: bool) -> Result<(), OAuthError> {
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        let cached: Vec<AccessToken> = self.tokens.lock().await.drain().map(|(_, token)| token).collect();
+        let _refresh = self.refresh_lock.lock().await;
+        let stored = self.store.load()?;
+
+        let mut tokens: Vec<(&str, String)> = Vec::new();
+        for token in cached {
+            tokens.push(("access_token", token.value));
+        }
+        if let Some(stored) = &stored {
+            if !stored.access_token.is_empty() && !tokens.iter().any(|(_, value)| *value == stored.access_token) {
+                tokens.push(("access_token", stored.access_token.clone()));
+            }
+            if let Some(refresh_token) = stored.refresh_token.clone().filter(|_| forget_login) {
+                tokens.push(("refresh_token", refresh_token));
+            }
+        }
+
+        let mut result = Ok(());
+        if let Some(url) = &self.settings.revocation_url {
+            for (hint, token) in &tokens {
+                if let Err(e) = self.revoke_token(url, token, hint).await {
+                    result = Err(e);
+                }
+            }
+        }
+
+        if let Some(stored) = stored {
+            if forget_login || stored.refresh_token.is_none() {
+                self.store.remove()?;
+            } else {
+                self.store.save(&StoredToken {
+                    access_token: String::new(),
+                    expires_at: 0,
+                    ..stored
+                })?;
+            }
+        }
+        result
+    }
+
+    async fn revoke_token(&self, url: &str, token: &str, hint: &str) -> Result<(), OAuthError> {
+        let mut form = vec![("token", token), ("token_type_hint", hint)];
+        let request = match &self.settings.client_secret {
+            Some(secret) => self.client.post(url).basic_auth(&self.settings.client_id, Some(secret)),
+            // Public clients identify themselves in the body
+            None => {
+                form.push(("client_id", &self.settings.client_id));
+                self.client.post(url)
+            }
+        };
+        let request = request.form(&form);
+        let response = request.send().await.map_err(|e| OAuthError::Request(e.to_string()))?;
+        if !response.status().is_success() {
+            let status = response.status().as_u16();
+            let body = response.text().await.unwrap_or_default();
+            return Err(OAuthError::Response { status, body });
+        }
+        tracing::debug!(token_type = hint, "Revoked API token");
+        Ok(())
+    }
+
+    /// Client credentials grant, authenticating with HTTP Basic (`client_secret_basic`)
+    async fn fetch(&self, secret: &str, scope: Option<&str>) -> Result<AccessToken, OAuthError> {
+        let mut form = vec![("grant_type", "client_credentials".to_string())];
+        form.extend(self.token_parameters(scope));
+
+        let request = self
+            .client
+            .post(&self.settings.token_url)
+            .basic_auth(&self.settings.client_id, Some(secret))
+            .header("Accept", "application/json")
+            .form(&form);
+        let (response, requested_at) = self.send(request).await?;
+        let token = Self::token_response(response).await?;
+
+        tracing::debug!(expires_in = token.lifetime().as_secs(), "Obtained API access token");
+        Ok(AccessToken::issued(&token, requested_at))
+    }
+
+    /// The token saved by `login`, refreshed first when it is about to expire
+    ///
+    /// The saved access token serves `scopes`; tokens for other scopes are
+    /// minted from the refresh token and only kept in memory.
+    async fn stored_token(&self, scope: Option<&str>, generation: u64) -> Result<AccessToken, OAuthError> {
+        // Read under the lock, so a waiter sees the token the previous holder saved
+        let _refresh = self.refresh_lock.lock().await;
+        let stored = self.store.load()?.ok_or(OAuthError::LoginRequired)?;
+        let saved_scope = scope == self.scope(Operation::Other).as_deref();
+        let cached = stored.cached(self.clock_offset());
+        if saved_scope && cached.is_fresh(Instant::now()) {
+            return Ok(cached);
+        }
+        let Some(refresh_token) = stored.refresh_token.clone() else {
+            return Err(OAuthError::LoginRequired);
+        };
+
+        let mut form = vec![
+            ("grant_type", "refresh_token".to_string()),
+            ("refresh_token", refresh_token.clone()),
+            ("client_id", self.settings.client_id.clone()),
+        ];
+        form.extend(self.token_parameters(scope));
+        let request = self
+            .client
+            .post(&self.settings.token_url)
+            .header("Accept", "application/json")
+            .form(&form);
+        let (response, requested_at) = self.send(request).await?;
+        let token = Self::token_response(response).await?;
+        let access_token = AccessToken::issued(&token, requested_at);
+        let clock_offset = self.clock_offset().unwrap_or(stored.clock_offset);
+        let refreshed = StoredToken::from_response(token, Some(refresh_token), requested_at, clock_offset);
+        if self.generation.load(Ordering::SeqCst) != generation {
+            return Err(OAuthError::Revoked);
+        }
+        if saved_scope {
+            self.store.save(&refreshed)?;
+        } else if refreshed.refresh_token != stored.refresh_token {
+            // A rotated refresh token replaces the saved one
+            self.store.save(&StoredToken {
+                refresh_token: refreshed.refresh_token.clone(),
+                ..stored
+            })?;
+        }
+        tracing::debug!("Refreshed saved API access token");
+        Ok(access_token)
+    }
+
+    /// Ask the authorization server for a device and user code, the first step of `login`
+    pub async fn start_device_authorization(&self) -> Result<DeviceAuthorization, OAuthError> {
+        let url = self
+            .settings
+            .device_authorization_url
+            .as_deref()
+            .ok_or(OAuthError::DeviceFlowUnavailable)?;
+        let mut form = vec![("client_id", self.settings.client_id.clone())];
+        // The refresh token has to cover the scopes of every operation
+        form.extend(self.token_parameters(self.login_scope().as_deref()));
+
+        let response = self
+            .client
+            .post(url)
+            .header("Accept", "application/json")
+            .form(&form)
+            .send()
+            .await
+            .map_err(|e| OAuthError::Request(e.to_string()))?;
+        if !response.status().is_success() {
+            let status = response.status().as_u16();
+            let body = response.text().await.unwrap_or_default();
+            return Err(OAuthError::Response { status, body });
+        }
+        response.json().await.map_err(|e| OAuthError::Parse(e.to_string()))
+    }
+
+    /// Poll the token endpoint until the user approves or denies the device, then save the tokens
+    pub async fn complete_device_authorization(
+        &self,
+        authorization: &DeviceAuthorization,
+    ) -> Result<StoredToken, OAuthError> {
+        let mut interval = authorization
+            .interval
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_POLL_INTERVAL);
+        let deadline = Instant::now() + Duration::from_secs(authorization.expires_in);
+        let form = [
+            ("grant_type", DEVICE_CODE_GRANT),
+            ("device_code", authorization.device_code.as_str()),
+            ("client_id", self.settings.client_id.as_str()),
+        ];
+
+        loop {
+            if Instant::now() >= deadline {
+                return Err(OAuthError::Login("the code expired before it was approved".to_string()));
+            }
+            tokio::time::sleep(interval).await;
+
+            let request = self
+                .client
+                .post(&self.settings.token_url)
+                .header("Accept", "application/json")
+                .form(&form);
+            let (response, requested_at) = self.send(request).await?;
+            if response.status().is_success() {
+                let token: TokenResponse = response.json().await.map_err(|e| OAuthError::Parse(e.to_string()))?;
+                let access_token = AccessToken::issued(&token, requested_at);
+                let stored =
+                    StoredToken::from_response(token, None, requested_at, self.clock_offset().unwrap_or(0));
+                self.store.save(&stored)?;
+                let key = self.scope(Operation::Other).unwrap_or_default();
+                self.tokens.lock().await.insert(key, access_token);
+                return Ok(stored);
+            }
+
+            let status = response.status().as_u16();
+            let body = response.text().await.unwrap_or_default();
+            let Ok(error) = serde_json::from_str::<TokenErrorResponse>(&body) else {
+                return Err(OAuthError::Response { status, body });
+            };
+            match error.error.as_str() {
+                "authorization_pending" => {}
+                "slow_down" => interval += Duration::from_secs(5),
+                "access_denied" => return Err(OAuthError::Login("access was denied".to_string())),
+                "expired_token" => {
+                    return Err(OAuthError::Login("the code expired before it was approved".to_string()))
+                }
+                _ => {
+                    return Err(OAuthError::Login(
+                        error.error_description.unwrap_or(error.error),
+                    ))
+                }
+            }
+        }
+    }
+
+    /// Send a request to the token endpoint, noting the server's clock from its `Date` header
+    ///
+    /// Also returns when the request was sent, which the token's expiry is counted from.
+    async fn send(&self, request: reqwest::RequestBuilder) -> Result<(reqwest::Response, Instant), OAuthError> {
+        let requested_at = Instant::now();
+        let sent_at = Utc::now();
+        let response = request.send().await.map_err(|e| OAuthError::Request(e.to_string()))?;
+        let date = response.headers().get(reqwest::header::DATE).and_then(|value| value.to_str().ok());
+        if let Some(offset) = date.and_then(|date| clock::estimate_offset(date, sent_at, Utc::now())) {
+            *self.clock_offset.lock().unwrap() = Some(offset);
+        }
+        Ok((response, requested_at))
+    }
+
+    /// Token endpoint clock minus local clock, once a response has been seen
+    fn clock_offset(&self) -> Option<i64> {
+        *self.clock_offset.lock().unwrap()
+    }
+
+    /// Space-separated scopes for `operation`, `None` when there are none
+    fn scope(&self, operation: Operation) -> Option<String> {
+        let scopes = match operation {
+            Operation::Registration => self.settings.registration_scopes.as_ref(),
+            Operation::Metrics => self.settings.metrics_scopes.as_ref(),
+            Operation::Other => None,
+        }
+        .or(self.settings.scopes.as_ref());
+        scopes.filter(|scopes| !scopes.is_empty()).map(|scopes| scopes.join(" "))
+    }
+
+    /// Scopes of all operations, requested by `login`
+    fn login_scope(&self) -> Option<String> {
+        let mut scopes: Vec<String> = Vec::new();
+        for operation in [Operation::Other, Operation::Registration, Operation::Metrics] {
+            for scope in self.scope(operation).unwrap_or_default().split_whitespace() {
+                if !scopes.iter().any(|existing| existing == scope) {
+                    scopes.push(scope.to_string());
+                }
+            }
+        }
+        (!scopes.is_empty()).then(|| scopes.join(" "))
+    }
+
+    /// `scope`, `audience` and `resource` parameters of a token request
+    fn token_parameters(&self, scope: Option<&str>) -> Vec<(&'static str, String)> {
+        let mut parameters = Vec::new();
+        if let Some(scope) = scope {
+            parameters.push(("scope", scope.to_string()));
+        }
+        if let Some(audience) = &self.settings.audience {
+            parameters.push(("audience", audience.clone()));
+        }
+        if let Some(resource) = &self.settings.resource {
+            parameters.push(("resource", resource.clone()));
+        }
+        parameters
+    }
+
+    /// Access token from a token endpoint response, which must be a bearer token
+    async fn token_response(response: reqwest::Response) -> Result<TokenResponse, OAuthError> {
+        if !response.status().is_success() {
+            let status = response.status().as_u16();
+            let body = response.text().await.unwrap_or_default();
+            return Err(OAuthError::Response { status, body });
+        }
+
+        let token: TokenResponse = response
+            .json()
+            .await
+            .map_err(|e| OAuthError::Parse(e.to_string()))?;
+        if token
+            .token_type
+            .as_deref()
+            .is_some_and(|token_type| !token_type.eq_ignore_ascii_case("bearer"))
+        {
+            return Err(OAuthError::Parse(format!(
+                "unsupported token type {}",
+                token.token_type.unwrap_or_default()
+            )));
+        }
+        Ok(token)
+    }
+}
+
+/// Clears the refreshing mark of a scope when the background refresh ends, also on panic or cancellation
+struct RefreshInProgress<'a> {
+    refreshing: &'a std::sync::Mutex<HashSet<String>>,
+    key: String,
+}
+
+impl Drop for RefreshInProgress<'_> {
+    fn drop(&mut self) {
+        if let Ok(mut refreshing) = self.refreshing.lock() {
+            refreshing.remove(&self.key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::token_store::TOKEN_FILE_NAME;
+    use wiremock::matchers::{body_string_contains, header, method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    fn token_store(dir: &tempfile::TempDir) -> TokenStore {
+        TokenStore::File {
+            path: dir.path().join(TOKEN_FILE_NAME),
+            key: None,
+        }
+    }
+
+    fn settings(token_url: String) -> OAuthConfig {
+        OAuthConfig {
+            token_url,
+            client_id: "agent".to_string(),
+            client_secret: Some("s3cret".to_string()),
+            scopes: Some(vec!["metrics:write".to_string(), "resources:write".to_string()]),
+            registration_scopes: None,
+            metrics_scopes: None,
+            audience: None,
+            resource: None,
+            device_authorization_url: None,
+            revocation_url: None,
+            token_cache: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_client_credentials_token_is_cached_until_expiry() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/oauth/token"))
+            // agent:s3cret
+            .and(header("Authorization", "Basic YWdlbnQ6czNjcmV0"))
+            .and(body_string_contains("grant_type=client_credentials"))
+            .and(body_string_contains("scope=metrics%3Awrite+resources%3Awrite"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "access_token": "tok-1", "token_type": "Bearer", "expires_in": 3600
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let manager = Arc::new(OAuthManager::new(reqwest::Client::new(), settings(format!("{}/oauth/token", server.uri()))).unwrap());
+        assert_eq!(manager.access_token(Operation::Other).await.unwrap(), "tok-1");
+        assert_eq!(manager.access_token(Operation::Other).await.unwrap(), "tok-1");
+
+        // A rejection of a token that was already replaced keeps the current one
+        manager.invalidate(Operation::Other, "tok-0").await.unwrap();
+        assert_eq!(manager.access_token(Operation::Other).await.unwrap(), "tok-1");
+
+        // Within the refresh margin of its expiry the token is fetched again
+        let expiring = AccessToken {
+            value: "old".to_string(),
+            expires_at: Instant::now() + Duration::from_secs(30),
+        };
+        assert!(!expiring.is_fresh(Instant::now()));
+    }
+
+    #[tokio::test]
+    async fn test_scopes_per_operation_and_audience() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/oauth/token"))
+            .and(body_string_contains("scope=metrics%3Awrite&"))
+            .and(body_string_contains("audience=https%3A%2F%2Fapi.example.com"))
+            .and(body_string_contains("resource=https%3A%2F%2Fapi.example.com%2F"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "access_token": "tok-metrics", "expires_in": 3600
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/oauth/token"))
+            .and(body_string_contains("scope=agent&"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "access_token": "tok-agent", "expires_in": 3600
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let settings = OAuthConfig {
+            scopes: Some(vec!["agent".to_string()]),
+            metrics_scopes: Some(vec!["metrics:write".to_string()]),
+            audience: Some("https://api.example.com".to_string()),
+            resource: Some("https://api.example.com/".to_string()),
+            ..settings(format!("{}/oauth/token", server.uri()))
+        };
+        let manager = Arc::new(OAuthManager::new(reqwest::Client::new(), settings).unwrap());
+        assert_eq!(manager.access_token(Operation::Metrics).await.unwrap(), "tok-metrics");
+        // Registration falls back to `scopes`, sharing the token of the other operations
+        assert_eq!(manager.access_token(Operation::Registration).await.unwrap(), "tok-agent");
+        assert_eq!(manager.access_token(Operation::Other).await.unwrap(), "tok-agent");
+        assert_eq!(manager.login_scope().as_deref(), Some("agent metrics:write"));
+    }
+
+    #[tokio::test]
+    async fn test_token_endpoint_errors() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/oauth/token"))
+            .respond_with(ResponseTemplate::new(401).set_body_string(r#"{"error":"invalid_client"}"#))
+            .mount(&server)
+            .await;
+
+        let manager = Arc::new(OAuthManager::new(reqwest::Client::new(), settings(format!("{}/oauth/token", server.uri()))).unwrap());
+        match manager.access_token(Operation::Other).await {
+            Err(OAuthError::Response { status, body }) => {
+                assert_eq!(status, 401);
+                assert!(body.contains("invalid_client"));
+            }
+            other => panic!("expected an error response, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_transient_failures_are_retried_behind_the_cached_token() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/oauth/token"))
+            .respond_with(ResponseTemplate::new(503))
+            .up_to_n_times(2)
+            .expect(2)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/oauth/token"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "access_token": "tok-2", "expires_in": 3600
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let manager = Arc::new(OAuthManager::new(reqwest::Client::new(), settings(format!("{}/oauth/token", server.uri()))).unwrap());
+        manager.tokens.lock().await.insert(
+            "metrics:write resources:write".to_string(),
+            AccessToken {
+                value: "tok-1".to_string(),
+                expires_at: Instant::now() + Duration::from_secs(30),
+            },
+        );
+
+        // The expiring token is served at once while the refresh retries behind it
+        assert_eq!(manager.access_token(Operation::Other).await.unwrap(), "tok-1");
+        assert_eq!(manager.access_token(Operation::Other).await.unwrap(), "tok-1");
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while manager.access_token(Operation::Other).await.unwrap() != "tok-2" {
+            assert!(Instant::now() < deadline, "background refresh did not finish");
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+
+        // Client errors are not retried
+        let rejected = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(400).set_body_string(r#"{"error":"invalid_scope"}"#))
+            .expect(1)
+            .mount(&rejected)
+            .await;
+        let manager = Arc::new(OAuthManager::new(reqwest::Client::new(), settings(rejected.uri())).unwrap());
+        assert!(matches!(manager.access_token(Operation::Other).await, Err(OAuthError::Response { status: 400, .. })));
+    }
+
+    #[tokio::test]
+    async fn test_failed_background_refresh_backs_off() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/oauth/token"))
+            .respond_with(ResponseTemplate::new(400).set_body_string(r#"{"error":"invalid_grant"}"#))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let manager = Arc::new(OAuthManager::new(reqwest::Client::new(), settings(format!("{}/oauth/token", server.uri()))).unwrap());
+        manager.tokens.lock().await.insert(
+            "metrics:write resources:write".to_string(),
+            AccessToken {
+                value: "tok-1".to_string(),
+                expires_at: Instant::now() + Duration::from_secs(30),
+            },
+        );
+        assert_eq!(manager.access_token(Operation::Other).await.unwrap(), "tok-1");
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !manager.refreshing.lock().unwrap().is_empty() || manager.refresh_backoff.lock().unwrap().is_empty() {
+            assert!(Instant::now() < deadline, "background refresh did not finish");
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+
+        // The cached token is served without asking the token endpoint again
+        for _ in 0..3 {
+            assert_eq!(manager.access_token(Operation::Other).await.unwrap(), "tok-1");
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(manager.refreshing.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_rotated_refresh_token_is_used_once() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/oauth/token"))
+            .and(body_string_contains("refresh_token=ref-1"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_json(serde_json::json!({ "access_token": "tok-a", "refresh_token": "ref-2", "expires_in": 3600 }))
+                    .set_delay(Duration::from_millis(200)),
+            )
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/oauth/token"))
+            .and(body_string_contains("refresh_token=ref-2"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "access_token": "tok-b", "refresh_token": "ref-3", "expires_in": 3600
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let dir = tempfile::tempdir().unwrap();
+        let settings = OAuthConfig {
+            client_secret: None,
+            scopes: Some(vec!["agent".to_string()]),
+            metrics_scopes: Some(vec!["metrics:write".to_string()]),
+            ..settings(format!("{}/oauth/token", server.uri()))
+        };
+        token_store(&dir)
+            .save(&StoredToken {
+                access_token: "tok-1".to_string(),
+                refresh_token: Some("ref-1".to_string()),
+                expires_at: Utc::now().timestamp() + 30,
+                clock_offset: 0,
+            })
+            .unwrap();
+        let manager = Arc::new(OAuthManager::with_store(reqwest::Client::new(), settings, token_store(&dir)));
+        for scope in ["agent", "metrics:write"] {
+            manager.tokens.lock().await.insert(
+                scope.to_string(),
+                AccessToken {
+                    value: "tok-1".to_string(),
+                    expires_at: Instant::now() + Duration::from_secs(30),
+                },
+            );
+        }
+
+        // Both scopes refresh at once; the second waits for the rotated refresh token
+        assert_eq!(manager.access_token(Operation::Other).await.unwrap(), "tok-1");
+        assert_eq!(manager.access_token(Operation::Metrics).await.unwrap(), "tok-1");
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !manager.refreshing.lock().unwrap().is_empty() {
+            assert!(Instant::now() < deadline, "background refresh did not finish");
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert!(manager.refresh_backoff.lock().unwrap().is_empty());
+        let saved = token_store(&dir).load().unwrap().unwrap();
+        assert_eq!(saved.refresh_token.as_deref(), Some("ref-3"));
+    }
+
+    #[tokio::test]
+    async fn test_refresh_in_flight_does_not_outlive_revoke() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/oauth/token"))
+            .and(body_string_contains("grant_type=refresh_token"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_json(serde_json::json!({ "access_token": "tok-2", "refresh_token": "ref-2", "expires_in": 3600 }))
+                    .set_delay(Duration::from_millis(300)),
+            )
+            .mount(&server)
+            .await;
+
+        let dir = tempfile::tempdir().unwrap();
+        let settings = OAuthConfig {
+            client_secret: None,
+            scopes: None,
+            ..settings(format!("{}/oauth/token", server.uri()))
+        };
+        token_store(&dir)
+            .save(&StoredToken {
+                access_token: "tok-1".to_string(),
+                refresh_token: Some("ref-1".to_string()),
+                expires_at: Utc::now().timestamp() + 30,
+                clock_offset: 0,
+            })
+            .unwrap();
+        let manager = Arc::new(OAuthManager::with_store(reqwest::Client::new(), settings, token_store(&dir)));
+        manager.tokens.lock().await.insert(
+            String::new(),
+            AccessToken {
+                value: "tok-1".to_string(),
+                expires_at: Instant::now() + Duration::from_secs(30),
+            },
+        );
+
+        // Deregistration while the background refresh waits for the token endpoint
+        assert_eq!(manager.access_token(Operation::Other).await.unwrap(), "tok-1");
+        manager.revoke(true).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(600)).await;
+        assert!(manager.refreshing.lock().unwrap().is_empty());
+        assert!(token_store(&dir).load().unwrap().is_none());
+        assert!(manager.tokens.lock().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_device_login_saves_and_refreshes_tokens() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/oauth/device"))
+            .and(body_string_contains("client_id=agent"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "device_code": "dev-1", "user_code": "WDJB-MJHT",
+                "verification_uri": "https://auth.example.com/device", "expires_in": 60, "interval": 0
+            })))
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/oauth/token"))
+            .and(body_string_contains("device_code=dev-1"))
+            .respond_with(ResponseTemplate::new(400).set_body_json(serde_json::json!({"error": "authorization_pending"})))
+            .up_to_n_times(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/oauth/token"))
+            .and(body_string_contains("device_code=dev-1"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "access_token": "tok-1", "token_type": "Bearer", "expires_in": 30, "refresh_token": "ref-1"
+            })))
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/oauth/token"))
+            .and(body_string_contains("grant_type=refresh_token"))
+            .and(body_string_contains("refresh_token=ref-1"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "access_token": "tok-2", "token_type": "Bearer", "expires_in": 3600
+            })))
+            .mount(&server)
+            .await;
+
+        let dir = tempfile::tempdir().unwrap();
+        let settings = OAuthConfig {
+            client_secret: None,
+            device_authorization_url: Some(format!("{}/oauth/device", server.uri())),
+            scopes: None,
+            ..settings(format!("{}/oauth/token", server.uri()))
+        };
+        let manager = OAuthManager::with_store(reqwest::Client::new(), settings.clone(), token_store(&dir));
+        let authorization = manager.start_device_authorization().await.unwrap();
+        assert_eq!(authorization.user_code, "WDJB-MJHT");
+        let stored = manager.complete_device_authorization(&authorization).await.unwrap();
+        assert_eq!(token_store(&dir).load().unwrap(), Some(stored));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(dir.path().join(TOKEN_FILE_NAME)).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        // The agent finds the saved token about to expire and refreshes it, keeping the refresh token
+        let agent = Arc::new(OAuthManager::with_store(reqwest::Client::new(), settings.clone(), token_store(&dir)));
+        assert_eq!(agent.access_token(Operation::Other).await.unwrap(), "tok-2");
+        let saved = token_store(&dir).load().unwrap().unwrap();
+        assert_eq!(saved.refresh_token.as_deref(), Some("ref-1"));
+
+        let empty = tempfile::tempdir().unwrap();
+        let unauthenticated = Arc::new(OAuthManager::with_store(reqwest::Client::new(), settings, token_store(&empty)));
+        assert!(matches!(unauthenticated.access_token(Operation::Other).await, Err(OAuthError::LoginRequired)));
+    }
+
+    #[tokio::test]
+    async fn test_saved_expiry_follows_the_token_endpoint_clock() {
+        // The token endpoint's clock is an hour behind the local one
+        let server_date = (Utc::now() - chrono::Duration::hours(1)).to_rfc2822();
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/oauth/token"))
+            .and(body_string_contains("grant_type=refresh_token"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_json(serde_json::json!({ "access_token": "tok-2", "expires_in": 600 }))
+                    .insert_header("Date", server_date.as_str()),
+            )
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let dir = tempfile::tempdir().unwrap();
+        let settings = OAuthConfig {
+            client_secret: None,
+            scopes: None,
+            ..settings(format!("{}/oauth/token", server.uri()))
+        };
+        token_store(&dir)
+            .save(&StoredToken {
+                access_token: "tok-1".to_string(),
+                refresh_token: Some("ref-1".to_string()),
+                expires_at: 0,
+                clock_offset: 0,
+            })
+            .unwrap();
+        let agent = Arc::new(OAuthManager::with_store(reqwest::Client::new(), settings.clone(), token_store(&dir)));
+        assert_eq!(agent.access_token(Operation::Other).await.unwrap(), "tok-2");
+        let saved = token_store(&dir).load().unwrap().unwrap();
+        assert!((saved.clock_offset + 3600).abs() <= 2, "offset {}", saved.clock_offset);
+        assert!((saved.expires_at - (Utc::now().timestamp() - 3600 + 600)).abs() <= 2);
+
+        // After a restart the token is still fresh, although its expiry is in the local past
+        let restarted = Arc::new(OAuthManager::with_store(reqwest::Client::new(), settings, token_store(&dir)));
+        assert_eq!(restarted.access_token(Operation::Other).await.unwrap(), "tok-2");
+        assert!(saved.cached(Some(0)).expires_at <= Instant::now());
+    }
+
+    #[tokio::test]
+    async fn test_revoke_on_shutdown_and_deregistration() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/oauth/revoke"))
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&server)
+            .await;
+
+        let dir = tempfile::tempdir().unwrap();
+        let settings = OAuthConfig {
+            client_secret: None,
+            revocation_url: Some(format!("{}/oauth/revoke", server.uri())),
+            ..settings(format!("{}/oauth/token", server.uri()))
+        };
+        token_store(&dir)
+            .save(&StoredToken {
+                access_token: "tok-1".to_string(),
+                refresh_token: Some("ref-1".to_string()),
+                expires_at: Utc::now().timestamp() + 3600,
+                clock_offset: 0,
+            })
+            .unwrap();
+        let manager = Arc::new(OAuthManager::with_store(reqwest::Client::new(), settings, token_store(&dir)));
+        assert_eq!(manager.access_token(Operation::Other).await.unwrap(), "tok-1");
+
+        // Shutdown: the access token is revoked, the login survives
+        manager.revoke(false).await.unwrap();
+        let saved = token_store(&dir).load().unwrap().unwrap();
+        assert!(saved.access_token.is_empty());
+        assert_eq!(saved.refresh_token.as_deref(), Some("ref-1"));
+
+        // Deregistration: the refresh token is revoked and the file removed
+        manager.revoke(true).await.unwrap();
+        assert!(token_store(&dir).load().unwrap().is_none());
+
+        let bodies: Vec<String> = server
+            .received_requests()
+            .await
+            .unwrap()
+            .iter()
+            .map(|request| String::from_utf8_lossy(&request.body).into_owned())
+            .collect();
+        assert_eq!(
+            bodies,
+            vec![
+                "token=tok-1&token_type_hint=access_token&client_id=agent",
+                "token=ref-1&token_type_hint=refresh_token&client_id=agent",
+            ]
+        );
+    }
+}
@@ -0,0 +1,207 @@
+use std::path::PathBuf;
+
+/// File name of the agent configuration
+pub const CONFIG_FILE_NAME: &str = "agent.yaml";
+
+/// File name of the persisted resource state
+pub const STATE_FILE_NAME: &str = "resource-state.json";
+
+/// System-wide configuration directory for the current platform
+pub fn system_config_dir() -> PathBuf {
+    #[cfg(windows)]
+    {
+        program_data_dir().join("Operion")
+    }
+
+    #[cfg(target_os = "macos")]
+    {
+        PathBuf::from("/Library/Application Support/Operion")
+    }
+
+    #[cfg(not(any(windows, target_os = "macos")))]
+    {
+        PathBuf::from("/etc/operion")
+    }
+}
+
+/// System-wide state directory for the current platform
+pub fn system_state_dir() -> PathBuf {
+    #[cfg(windows)]
+    {
+        program_data_dir().join("Operion")
+    }
+
+    #[cfg(target_os = "macos")]
+    {
+        PathBuf::from("/Library/Application Support/Operion")
+    }
+
+    #[cfg(not(any(windows, target_os = "macos")))]
+    {
+        PathBuf::from("/var/lib/operion")
+    }
+}
+
+/// Per-user configuration directory
+///
+/// `~/.config/operion` on Linux, `~/Library/Application Support/Operion` on
+/// macOS and `%APPDATA%\Operion` on Windows.
+pub fn user_config_dir() -> Option<PathBuf> {
+    #[cfg(any(windows, target_os = "macos"))]
+    {
+        dirs::config_dir().map(|dir| dir.join("Operion"))
+    }
+
+    #[cfg(not(any(windows, target_os = "macos")))]
+    {
+        dirs::home_dir().map(|dir| dir.join(".config").join("operion"))
+    }
+}
+
+/// Per-user state directory, used when the system state directory is not writable
+pub fn user_state_dir() -> PathBuf {
+    #[cfg(any(windows, target_os = "macos"))]
+    {
+        user_config_dir().unwrap_or_else(|| PathBuf::from("."))
+    }
+
+    #[cfg(not(any(windows, target_os = "macos")))]
+    {
+        dirs::config_dir()
+            .unwrap_or_else(|| PathBuf::from("."))
+            .join("operion")
+    }
+}
+
+#[cfg(windows)]
+fn program_data_dir() -> PathBuf {
+    std::env::var_os("ProgramData")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(r"C:\ProgramData"))
+}
+
+/// Config file locations in priority order
+pub fn config_file_candidates() -> Vec<PathBuf> {
+    let mut candidates = Vec::new();
+
+    // 1. User config directory (highest priority)
+    candidates.extend(user_config_dir().map(|dir| dir.join(CONFIG_FILE_NAME)));
+
+    // 2. XDG and platform config directories used by earlier releases
+    candidates.extend(
+        dirs::home_dir().map(|dir| dir.join(".config").join("operion").join(CONFIG_FILE_NAME)),
+    );
+    candidates.extend(dirs::config_dir().map(|dir| dir.join("operion").join(CONFIG_FILE_NAME)));
+
+    // 3. System-wide config
+    candidates.push(system_config_dir().join(CONFIG_FILE_NAME));
+
+    // 4. Current directory (development/testing)
+    candidates.push(PathBuf::from(CONFIG_FILE_NAME));
+
+    dedup_paths(candidates)
+}
+
+/// State file locations in priority order; the last entry is the per-user fallback
+pub fn state_file_candidates() -> Vec<PathBuf> {
+    #[cfg(test)]
+    if let Some(dir) = TEST_STATE_DIR.with(|dir| dir.borrow().clone()) {
+        return vec![dir.join(STATE_FILE_NAME)];
+    }
+
+    let mut candidates = vec![system_state_dir().join(STATE_FILE_NAME)];
+
+    // Legacy system-wide location
+    #[cfg(not(any(windows, target_os = "macos")))]
+    candidates.push(system_config_dir().join(STATE_FILE_NAME));
+
+    candidates.push(user_state_dir().join(STATE_FILE_NAME));
+    dedup_paths(candidates)
+}
+
+/// State files written by earlier releases that no longer match platform conventions
+///
+/// These are only read, so existing registrations survive an upgrade.
+pub fn legacy_state_files() -> Vec<PathBuf> {
+    #[cfg(test)]
+    if TEST_STATE_DIR.with(|dir| dir.borrow().is_some()) {
+        return Vec::new();
+    }
+
+    #[cfg(target_os = "macos")]
+    {
+        vec![
+            PathBuf::from("/var/lib/operion").join(STATE_FILE_NAME),
+            PathBuf::from("/etc/operion").join(STATE_FILE_NAME),
+        ]
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    {
+        Vec::new()
+    }
+}
+
+#[cfg(test)]
+thread_local! {
+    static TEST_STATE_DIR: std::cell::RefCell<Option<PathBuf>> = const { std::cell::RefCell::new(None) };
+}
+
+/// Keep the state file (and the agent key next to it) in `dir` for the rest of
+/// the calling test, so tests never touch the real state directory
+///
+/// The setting is per thread, which covers `#[tokio::test]` bodies.
+#[cfg(test)]
+pub fn use_test_state_dir(dir: &std::path::Path) {
+    TEST_STATE_DIR.with(|state_dir| *state_dir.borrow_mut() = Some(dir.to_path_buf()));
+}
+
+/// Remove duplicates, compared case-insensitively where the filesystem usually is (macOS, Windows)
+fn dedup_paths(paths: Vec<PathBuf>) -> Vec<PathBuf> {
+    let mut seen = Vec::new();
+    let mut unique = Vec::new();
+    for path in paths {
+        let key = path.to_string_lossy();
+        let key = if cfg!(any(target_os = "macos", windows)) {
+            key.to_lowercase()
+        } else {
+            key.into_owned()
+        };
+        if !seen.contains(&key) {
+            seen.push(key);
+            unique.push(path);
+        }
+    }
+    unique
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config_candidates_end_with_current_directory() {
+        let candidates = config_file_candidates();
+        assert!(candidates.contains(&system_config_dir().join(CONFIG_FILE_NAME)));
+        assert_eq!(candidates.last(), Some(&PathBuf::from(CONFIG_FILE_NAME)));
+    }
+
+    #[test]
+    fn test_state_candidates_prefer_system_dir() {
+        let candidates = state_file_candidates();
+        assert_eq!(candidates[0], system_state_dir().join(STATE_FILE_NAME));
+        assert!(candidates.len() >= 2);
+    }
+
+    #[test]
+    fn test_dedup_paths_folds_case_only_where_the_filesystem_does() {
+        let paths = dedup_paths(vec![
+            PathBuf::from("/Users/a/Library/Application Support/Operion/agent.yaml"),
+            PathBuf::from("/Users/a/Library/Application Support/operion/agent.yaml"),
+            PathBuf::from("agent.yaml"),
+            PathBuf::from("agent.yaml"),
+        ]);
+        let expected = if cfg!(any(target_os = "macos", windows)) { 2 } else { 3 };
+        assert_eq!(paths.len(), expected);
+    }
+}
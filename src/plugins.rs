@@ -0,0 +1,306 @@
+//! External collectors loaded from shared libraries
+//!
+//! Every `.so` / `.dylib` / `.dll` in the plugins directory is loaded at startup
+//! and run as a collector alongside the built-in ones. A plugin exports this
+//! C ABI (version 1):
+//!
+//! ```c
+//! uint32_t    sentinel_plugin_abi_version(void);  /* must return 1 */
+//! const char *sentinel_plugin_name(void);         /* static, NUL-terminated */
+//! char       *sentinel_plugin_collect(void);      /* JSON, see below */
+//! void        sentinel_plugin_free(char *);       /* frees collect's result */
+//! ```
+//!
+//! `sentinel_plugin_collect` returns a JSON array of metrics in the batch
+//! format (`timestamp`, `device`, `mount_point`, `total_space_bytes`,
+//! `used_space_bytes`, `available_space_bytes`, `usage_percentage` and optional
+//! `labels`), or `{"error": "..."}` to report a failure. It is called from a
+//! worker thread, never concurrently with itself.
+
+use libloading::{Library, Symbol};
+use std::ffi::{c_char, CStr};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use thiserror::Error;
+
+use crate::metrics::DiskMetric;
+
+/// Version of the C ABI above; bumped on any incompatible change
+pub const ABI_VERSION: u32 = 1;
+
+type AbiVersionFn = unsafe extern "C" fn() -> u32;
+type NameFn = unsafe extern "C" fn() -> *const c_char;
+type CollectFn = unsafe extern "C" fn() -> *mut c_char;
+type FreeFn = unsafe extern "C" fn(*mut c_char);
+
+#[derive(Error, Debug)]
+pub enum PluginError {
+    #[error("failed to read plugin directory {path}: {message}")]
+    Directory { path: PathBuf, message: String },
+    #[error("plugin {path} could be replaced by other users: {reason}")]
+    InsecurePermissions { path: PathBuf, reason: String },
+    #[error("failed to load plugin {path}: {message}")]
+    Load { path: PathBuf, message: String },
+    #[error("plugin {path} uses ABI version {found}, expected {expected}", expected = ABI_VERSION)]
+    AbiVersion { path: PathBuf, found: u32 },
+    #[error("plugin {name} returned no data")]
+    NoData { name: String },
+    #[error("plugin {name} returned invalid output: {message}")]
+    InvalidOutput { name: String, message: String },
+    #[error("plugin {name} failed: {message}")]
+    Collect { name: String, message: String },
+}
+
+/// A loaded plugin; the library stays loaded while any clone is alive
+#[derive(Clone)]
+pub struct PluginCollector {
+    name: String,
+    collect: CollectFn,
+    free: FreeFn,
+    _library: Arc<Library>,
+}
+
+impl PluginCollector {
+    pub fn load(path: &Path) -> Result<Self, PluginError> {
+        check_permissions(path)?;
+
+        let load_error = |e: libloading::Error| PluginError::Load {
+            path: path.to_path_buf(),
+            message: e.to_string(),
+        };
+
+        // SAFETY: loading runs the library's initializers; plugins are trusted
+        // code installed by the administrator in a directory only they can write
+        let library = unsafe { Library::new(path) }.map_err(load_error)?;
+
+        // SAFETY: symbol types match the documented ABI; the version is checked
+        // before anything else is called
+        unsafe {
+            let version: Symbol<AbiVersionFn> =
+                library.get(b"sentinel_plugin_abi_version\0").map_err(load_error)?;
+            let found = version();
+            if found != ABI_VERSION {
+                return Err(PluginError::AbiVersion { path: path.to_path_buf(), found });
+            }
+
+            let name: Symbol<NameFn> = library.get(b"sentinel_plugin_name\0").map_err(load_error)?;
+            let name = name();
+            if name.is_null() {
+                return Err(PluginError::Load {
+                    path: path.to_path_buf(),
+                    message: "sentinel_plugin_name returned NULL".to_string(),
+                });
+            }
+            let name = CStr::from_ptr(name).to_string_lossy().into_owned();
+
+            let collect = *library
+                .get::<CollectFn>(b"sentinel_plugin_collect\0")
+                .map_err(load_error)?;
+            let free = *library.get::<FreeFn>(b"sentinel_plugin_free\0").map_err(load_error)?;
+
+            Ok(Self { name, collect, free, _library: Arc::new(library) })
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn collect(&self) -> Result<Vec<DiskMetric>, PluginError> {
+        // SAFETY: the library outlives this call; the result is only read
+        // before being handed back to the plugin's own free function
+        let output = unsafe {
+            let raw = (self.collect)();
+            if raw.is_null() {
+                return Err(PluginError::NoData { name: self.name.clone() });
+            }
+            let output = CStr::from_ptr(raw).to_string_lossy().into_owned();
+            (self.free)(raw);
+            output
+        };
+        parse_output(&self.name, &output)
+    }
+}
+
+/// Output is either an array of metrics or an `{"error": ...}` object
+#[derive(serde::Deserialize)]
+#[serde(untagged)]
+enum PluginOutput {
+    Metrics(Vec<DiskMetric>),
+    Error { error: String },
+}
+
+fn parse_output(name: &str, output: &str) -> Result<Vec<DiskMetric>, PluginError> {
+    match serde_json::from_str(output) {
+        Ok(PluginOutput::Metrics(metrics)) => Ok(metrics),
+        Ok(PluginOutput::Error { error }) => Err(PluginError::Collect {
+            name: name.to_string(),
+            message: error,
+        }),
+        Err(e) => Err(PluginError::InvalidOutput {
+            name: name.to_string(),
+            message: e.to_string(),
+        }),
+    }
+}
+
+/// Plugins run with the agent's privileges, so refuse files others could replace
+///
+/// The file and every directory above it must be owned by root or the agent's
+/// user and not writable by group or others. Sticky directories such as /tmp
+/// are allowed, since others cannot rename or remove entries they do not own.
+#[cfg(unix)]
+fn check_permissions(path: &Path) -> Result<(), PluginError> {
+    use std::os::unix::fs::MetadataExt;
+
+    let load_error = |e: std::io::Error| PluginError::Load {
+        path: path.to_path_buf(),
+        message: e.to_string(),
+    };
+    let insecure = |reason: String| PluginError::InsecurePermissions {
+        path: path.to_path_buf(),
+        reason,
+    };
+    // SAFETY: geteuid has no preconditions and cannot fail
+    let agent_uid = unsafe { libc::geteuid() };
+
+    // Resolved first, so a symlink cannot point the checks at a different file
+    let resolved = path.canonicalize().map_err(load_error)?;
+    for (index, ancestor) in resolved.ancestors().enumerate() {
+        let metadata = std::fs::metadata(ancestor).map_err(load_error)?;
+        if metadata.uid() != 0 && metadata.uid() != agent_uid {
+            return Err(insecure(format!("{} is owned by uid {}", ancestor.display(), metadata.uid())));
+        }
+        let sticky_directory = index > 0 && metadata.mode() & 0o1000 != 0;
+        if metadata.mode() & 0o022 != 0 && !sticky_directory {
+            return Err(insecure(format!("{} is writable by group or others", ancestor.display())));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn check_permissions(_path: &Path) -> Result<(), PluginError> {
+    Ok(())
+}
+
+/// Load every shared library in `directory`, in file name order
+///
+/// A plugin that fails to load is reported and skipped; the others still load.
+pub fn load_directory(directory: &Path) -> (Vec<PluginCollector>, Vec<PluginError>) {
+    let entries = match std::fs::read_dir(directory) {
+        Ok(entries) => entries,
+        Err(e) => {
+            let error = PluginError::Directory {
+                path: directory.to_path_buf(),
+                message: e.to_string(),
+            };
+            return (Vec::new(), vec![error]);
+        }
+    };
+
+    let mut paths: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            path.is_file()
+                && path.extension().is_some_and(|ext| ext == std::env::consts::DLL_EXTENSION)
+        })
+        .collect();
+    paths.sort();
+
+    let mut plugins: Vec<PluginCollector> = Vec::new();
+    let mut errors = Vec::new();
+    for path in paths {
+        match PluginCollector::load(&path) {
+            Ok(plugin) if plugins.iter().any(|p| p.name == plugin.name) => {
+                errors.push(PluginError::Load {
+                    path,
+                    message: format!("another plugin is already named {}", plugin.name),
+                });
+            }
+            Ok(plugin) => plugins.push(plugin),
+            Err(e) => errors.push(e),
+        }
+    }
+    (plugins, errors)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_output_metrics() {
+        let output = r#"[{"timestamp": 1, "device": "raid0", "mount_point": "/data",
+            "total_space_bytes": 100, "used_space_bytes": 40, "available_space_bytes": 60,
+            "usage_percentage": 0.4, "labels": {"vendor": "acme"}}]"#;
+        let metrics = parse_output("acme", output).unwrap();
+        assert_eq!(metrics.len(), 1);
+        assert_eq!(metrics[0].labels.get("vendor").map(String::as_str), Some("acme"));
+    }
+
+    #[test]
+    fn test_parse_output_errors() {
+        let result = parse_output("acme", r#"{"error": "controller offline"}"#);
+        assert!(matches!(result, Err(PluginError::Collect { message, .. }) if message == "controller offline"));
+
+        let result = parse_output("acme", "not json");
+        assert!(matches!(result, Err(PluginError::InvalidOutput { .. })));
+    }
+
+    #[test]
+    fn test_load_directory_skips_invalid_libraries() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(format!("broken.{}", std::env::consts::DLL_EXTENSION));
+        std::fs::write(&path, b"not a library").unwrap();
+        std::fs::write(dir.path().join("README.txt"), b"ignored").unwrap();
+
+        let (plugins, errors) = load_directory(dir.path());
+        assert!(plugins.is_empty());
+        assert_eq!(errors.len(), 1);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_load_rejects_world_writable_plugin() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(format!("open.{}", std::env::consts::DLL_EXTENSION));
+        std::fs::write(&path, b"").unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o666)).unwrap();
+
+        let result = PluginCollector::load(&path);
+        assert!(matches!(result, Err(PluginError::InsecurePermissions { .. })));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_load_rejects_plugin_in_writable_directory_or_foreign_owned() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let plugins = dir.path().join("plugins");
+        std::fs::create_dir(&plugins).unwrap();
+        let path = plugins.join(format!("shared.{}", std::env::consts::DLL_EXTENSION));
+        std::fs::write(&path, b"").unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+
+        std::fs::set_permissions(&plugins, std::fs::Permissions::from_mode(0o777)).unwrap();
+        let result = PluginCollector::load(&path);
+        assert!(
+            matches!(&result, Err(PluginError::InsecurePermissions { reason, .. }) if reason.contains("plugins is writable")),
+            "{:?}",
+            result.err()
+        );
+        std::fs::set_permissions(&plugins, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        // Only root can hand a file to another user
+        // SAFETY: geteuid has no preconditions and cannot fail
+        if unsafe { libc::geteuid() } == 0 {
+            std::os::unix::fs::chown(&path, Some(65534), None).unwrap();
+            let result = PluginCollector::load(&path);
+            assert!(matches!(&result, Err(PluginError::InsecurePermissions { reason, .. }) if reason.contains("owned by uid 65534")));
+        }
+    }
+}
@@ -0,0 +1,170 @@
+use std::fs;
+use tracing::debug;
+
+use crate::config::AdaptiveConfig;
+
+/// Leave pressure mode only once readings fall below this fraction of the threshold,
+/// so the agent does not flap around the limit
+const RECOVERY_RATIO: f64 = 0.8;
+
+const PSI_CPU_PATH: &str = "/proc/pressure/cpu";
+
+/// Current CPU pressure reading and the threshold it is compared against
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PressureReading {
+    /// Linux PSI `some avg10`, percentage of time tasks were stalled on CPU
+    Psi(f64),
+    /// 1-minute load average divided by the number of CPUs
+    LoadPerCpu(f64),
+}
+
+impl PressureReading {
+    fn value(&self) -> f64 {
+        match self {
+            PressureReading::Psi(value) | PressureReading::LoadPerCpu(value) => *value,
+        }
+    }
+
+    fn threshold(&self, config: &AdaptiveConfig) -> f64 {
+        match self {
+            PressureReading::Psi(_) => config.get_cpu_pressure_threshold(),
+            PressureReading::LoadPerCpu(_) => config.get_load_threshold(),
+        }
+    }
+}
+
+/// Tracks whether the host is under pressure, with hysteresis
+pub struct PressureMonitor {
+    config: AdaptiveConfig,
+    under_pressure: bool,
+}
+
+impl PressureMonitor {
+    pub fn new(config: AdaptiveConfig) -> Self {
+        Self {
+            config,
+            under_pressure: false,
+        }
+    }
+
+    pub fn under_pressure(&self) -> bool {
+        self.under_pressure
+    }
+
+    pub fn backoff_factor(&self) -> u32 {
+        if self.under_pressure {
+            self.config.get_backoff_factor()
+        } else {
+            1
+        }
+    }
+
+    /// Whether `collector` should be skipped this cycle
+    pub fn skips(&self, collector: &str) -> bool {
+        self.under_pressure
+            && self
+                .config
+                .skip_collectors
+                .as_ref()
+                .is_some_and(|skip| skip.iter().any(|name| name == collector))
+    }
+
+    /// Feed a new reading; returns true when the pressure state changed
+    pub fn update(&mut self, reading: Option<PressureReading>) -> bool {
+        let Some(reading) = reading else {
+            return false;
+        };
+
+        let threshold = reading.threshold(&self.config);
+        let under_pressure = if self.under_pressure {
+            reading.value() >= threshold * RECOVERY_RATIO
+        } else {
+            reading.value() >= threshold
+        };
+
+        let changed = under_pressure != self.under_pressure;
+        self.under_pressure = under_pressure;
+        changed
+    }
+}
+
+/// Read host CPU pressure, preferring PSI and falling back to the load average
+pub fn read() -> Option<PressureReading> {
+    if let Ok(contents) = fs::read_to_string(PSI_CPU_PATH) {
+        if let Some(avg10) = parse_psi_some_avg10(&contents) {
+            return Some(PressureReading::Psi(avg10));
+        }
+    }
+
+    // Windows has no load average; sysinfo reports zeros there
+    if cfg!(windows) {
+        return None;
+    }
+
+    let cpus = std::thread::available_parallelism().ok()?.get() as f64;
+    let load = sysinfo::System::load_average().one;
+    debug!(load, cpus, "PSI unavailable, using load average");
+    Some(PressureReading::LoadPerCpu(load / cpus))
+}
+
+/// Extract `avg10` from the `some` line of a PSI file
+fn parse_psi_some_avg10(contents: &str) -> Option<f64> {
+    contents
+        .lines()
+        .find(|line| line.starts_with("some "))?
+        .split_whitespace()
+        .find_map(|field| field.strip_prefix("avg10="))?
+        .parse()
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create_config() -> AdaptiveConfig {
+        AdaptiveConfig {
+            enabled: true,
+            cpu_pressure_threshold: Some(50.0),
+            load_threshold: None,
+            backoff_factor: Some(3),
+            skip_collectors: Some(vec!["disk".to_string()]),
+        }
+    }
+
+    #[test]
+    fn test_parse_psi() {
+        let contents = "some avg10=62.50 avg60=40.10 avg300=12.00 total=123456\n\
+                        full avg10=0.00 avg60=0.00 avg300=0.00 total=0\n";
+        assert_eq!(parse_psi_some_avg10(contents), Some(62.5));
+        assert_eq!(parse_psi_some_avg10("garbage"), None);
+    }
+
+    #[test]
+    fn test_monitor_hysteresis() {
+        let mut monitor = PressureMonitor::new(create_config());
+        assert!(!monitor.skips("disk"));
+        assert_eq!(monitor.backoff_factor(), 1);
+
+        assert!(!monitor.update(Some(PressureReading::Psi(30.0))));
+        assert!(monitor.update(Some(PressureReading::Psi(55.0))));
+        assert!(monitor.under_pressure());
+        assert!(monitor.skips("disk"));
+        assert_eq!(monitor.backoff_factor(), 3);
+
+        // Between the recovery level and the threshold: stay backed off
+        assert!(!monitor.update(Some(PressureReading::Psi(45.0))));
+        assert!(!monitor.update(None));
+        assert!(monitor.under_pressure());
+
+        assert!(monitor.update(Some(PressureReading::Psi(20.0))));
+        assert!(!monitor.under_pressure());
+    }
+
+    #[test]
+    fn test_monitor_uses_load_threshold() {
+        let mut monitor = PressureMonitor::new(create_config());
+        assert!(!monitor.update(Some(PressureReading::LoadPerCpu(1.5))));
+        assert!(monitor.update(Some(PressureReading::LoadPerCpu(2.5))));
+    }
+}
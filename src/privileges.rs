@@ -0,0 +1,252 @@
+//! Switching to an unprivileged user once startup is done
+//!
+//! The agent may be started as root so it can open privileged files (the log
+//! file, spool directory, journald socket) and then continue as the
+//! `agent.user` / `agent.group` from the config. Only supported on Unix.
+
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum PrivilegeError {
+    #[error("unknown user: {0}")]
+    UnknownUser(String),
+    #[error("unknown group: {0}")]
+    UnknownGroup(String),
+    #[error("failed to {operation}: {source}")]
+    Syscall {
+        operation: &'static str,
+        source: std::io::Error,
+    },
+    #[error("root privileges could be regained after switching user")]
+    NotDropped,
+    #[cfg(not(unix))]
+    #[error("agent.user and agent.group are only supported on Unix")]
+    Unsupported,
+}
+
+/// Identity the agent switches to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Identity {
+    /// Account name used to look up supplementary groups, if known
+    pub user: Option<String>,
+    pub uid: Option<u32>,
+    pub gid: u32,
+}
+
+#[cfg(unix)]
+mod imp {
+    use super::{Identity, PrivilegeError};
+    use std::ffi::{CStr, CString};
+
+    /// Buffer size for getpwnam_r/getgrnam_r when sysconf has no suggestion
+    const DEFAULT_BUFFER_SIZE: usize = 16 * 1024;
+
+    fn last_error(operation: &'static str) -> PrivilegeError {
+        PrivilegeError::Syscall {
+            operation,
+            source: std::io::Error::last_os_error(),
+        }
+    }
+
+    fn lookup_buffer() -> Vec<libc::c_char> {
+        // SAFETY: sysconf has no preconditions
+        let suggested = unsafe { libc::sysconf(libc::_SC_GETPW_R_SIZE_MAX) };
+        vec![0; usize::try_from(suggested).unwrap_or(DEFAULT_BUFFER_SIZE).max(DEFAULT_BUFFER_SIZE)]
+    }
+
+    /// Resolve a user name or numeric uid to (name, uid, primary gid)
+    fn lookup_user(user: &str) -> Result<(Option<String>, u32, u32), PrivilegeError> {
+        let name = CString::new(user).map_err(|_| PrivilegeError::UnknownUser(user.to_string()))?;
+        let mut buffer = lookup_buffer();
+        // SAFETY: passwd is plain data; getpwnam_r fills it from `buffer`
+        let mut entry: libc::passwd = unsafe { std::mem::zeroed() };
+        let mut result = std::ptr::null_mut();
+
+        // SAFETY: all pointers are valid for the duration of the call
+        let code = unsafe {
+            libc::getpwnam_r(name.as_ptr(), &mut entry, buffer.as_mut_ptr(), buffer.len(), &mut result)
+        };
+        if code == 0 && !result.is_null() {
+            // SAFETY: pw_name points into `buffer`, which is still alive
+            let name = unsafe { CStr::from_ptr(entry.pw_name) }.to_string_lossy().into_owned();
+            return Ok((Some(name), entry.pw_uid, entry.pw_gid));
+        }
+
+        // Numeric IDs without a passwd entry run with the same number as group
+        match user.parse() {
+            Ok(uid) => Ok((None, uid, uid)),
+            Err(_) => Err(PrivilegeError::UnknownUser(user.to_string())),
+        }
+    }
+
+    /// Resolve a group name or numeric gid
+    fn lookup_group(group: &str) -> Result<u32, PrivilegeError> {
+        let name =
+            CString::new(group).map_err(|_| PrivilegeError::UnknownGroup(group.to_string()))?;
+        let mut buffer = lookup_buffer();
+        // SAFETY: group is plain data; getgrnam_r fills it from `buffer`
+        let mut entry: libc::group = unsafe { std::mem::zeroed() };
+        let mut result = std::ptr::null_mut();
+
+        // SAFETY: all pointers are valid for the duration of the call
+        let code = unsafe {
+            libc::getgrnam_r(name.as_ptr(), &mut entry, buffer.as_mut_ptr(), buffer.len(), &mut result)
+        };
+        if code == 0 && !result.is_null() {
+            return Ok(entry.gr_gid);
+        }
+
+        group
+            .parse()
+            .map_err(|_| PrivilegeError::UnknownGroup(group.to_string()))
+    }
+
+    pub fn resolve(user: Option<&str>, group: Option<&str>) -> Result<Option<Identity>, PrivilegeError> {
+        let (name, uid, primary_gid) = match user {
+            Some(user) => {
+                let (name, uid, gid) = lookup_user(user)?;
+                (name, Some(uid), Some(gid))
+            }
+            None => (None, None, None),
+        };
+
+        let gid = match group {
+            Some(group) => lookup_group(group)?,
+            None => match primary_gid {
+                Some(gid) => gid,
+                None => return Ok(None),
+            },
+        };
+
+        Ok(Some(Identity { user: name, uid, gid }))
+    }
+
+    pub fn effective_uid() -> u32 {
+        // SAFETY: geteuid has no preconditions and cannot fail
+        unsafe { libc::geteuid() }
+    }
+
+    pub fn switch_to(identity: &Identity) -> Result<(), PrivilegeError> {
+        // Supplementary groups first: they cannot be changed once root is gone
+        match &identity.user {
+            Some(user) => {
+                let user = CString::new(user.as_str())
+                    .map_err(|_| PrivilegeError::UnknownUser(user.clone()))?;
+                // SAFETY: `user` is a valid NUL-terminated string
+                if unsafe { libc::initgroups(user.as_ptr(), identity.gid as _) } != 0 {
+                    return Err(last_error("set supplementary groups"));
+                }
+            }
+            None => {
+                let groups = [identity.gid as libc::gid_t];
+                // SAFETY: `groups` holds exactly one entry
+                if unsafe { libc::setgroups(1, groups.as_ptr()) } != 0 {
+                    return Err(last_error("set supplementary groups"));
+                }
+            }
+        }
+
+        // SAFETY: setgid/setuid have no memory-safety preconditions; the C library
+        // applies them to every thread of the process
+        if unsafe { libc::setgid(identity.gid) } != 0 {
+            return Err(last_error("set group ID"));
+        }
+
+        if let Some(uid) = identity.uid {
+            // SAFETY: as above
+            if unsafe { libc::setuid(uid) } != 0 {
+                return Err(last_error("set user ID"));
+            }
+
+            // SAFETY: as above; this is expected to fail
+            if uid != 0 && unsafe { libc::setuid(0) } == 0 {
+                return Err(PrivilegeError::NotDropped);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Look up the configured user and group; `None` when neither is set
+///
+/// Without `group`, the user's primary group is used.
+#[cfg(unix)]
+pub fn resolve(user: Option<&str>, group: Option<&str>) -> Result<Option<Identity>, PrivilegeError> {
+    imp::resolve(user, group)
+}
+
+#[cfg(not(unix))]
+pub fn resolve(user: Option<&str>, group: Option<&str>) -> Result<Option<Identity>, PrivilegeError> {
+    if user.is_some() || group.is_some() {
+        return Err(PrivilegeError::Unsupported);
+    }
+    Ok(None)
+}
+
+/// Switch to the configured user and group if running as root
+///
+/// When already running unprivileged there is nothing to give up, so the
+/// settings are only logged. Must run before any file the agent writes later
+/// is created as root.
+pub fn drop_privileges(user: Option<&str>, group: Option<&str>) -> Result<(), PrivilegeError> {
+    let Some(identity) = resolve(user, group)? else {
+        return Ok(());
+    };
+
+    #[cfg(unix)]
+    {
+        let current = imp::effective_uid();
+        if current != 0 {
+            if identity.uid.is_some_and(|uid| uid != current) {
+                tracing::warn!(
+                    uid = current,
+                    target_uid = identity.uid,
+                    "Not running as root, cannot switch to agent.user"
+                );
+            }
+            return Ok(());
+        }
+
+        imp::switch_to(&identity)?;
+        tracing::info!(
+            user = identity.user.as_deref().unwrap_or(""),
+            uid = identity.uid,
+            gid = identity.gid,
+            "Dropped root privileges"
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_root_by_name() {
+        let identity = resolve(Some("root"), None).unwrap().unwrap();
+        assert_eq!(identity.user.as_deref(), Some("root"));
+        assert_eq!(identity.uid, Some(0));
+        assert_eq!(identity.gid, 0);
+    }
+
+    #[test]
+    fn test_resolve_numeric_ids() {
+        let identity = resolve(Some("65533"), Some("65532")).unwrap().unwrap();
+        assert_eq!(identity.uid, Some(65533));
+        assert_eq!(identity.gid, 65532);
+    }
+
+    #[test]
+    fn test_resolve_nothing_configured() {
+        assert!(resolve(None, None).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_resolve_unknown_user() {
+        let result = resolve(Some("no-such-sentinel-user"), None);
+        assert!(matches!(result, Err(PrivilegeError::UnknownUser(_))));
+    }
+}
@@ -0,0 +1,131 @@
+//! On-demand CPU and heap profiles for the status endpoint
+//!
+//! Only available in builds with the `profiling` feature on Unix; other builds
+//! report an error instead of a profile.
+
+use thiserror::Error;
+
+/// CPU profile length when the request does not specify one
+pub const DEFAULT_CPU_PROFILE_SECONDS: u64 = 30;
+
+/// Upper bound on a single CPU profile so a request cannot pin the sampler forever
+pub const MAX_CPU_PROFILE_SECONDS: u64 = 300;
+
+#[derive(Error, Debug)]
+pub enum ProfilingError {
+    #[cfg(not(all(feature = "profiling", unix)))]
+    #[error("agent was built without the profiling feature")]
+    Unsupported,
+    #[cfg(all(feature = "profiling", unix))]
+    #[error("CPU profiler failed: {0}")]
+    Cpu(String),
+    #[cfg(all(feature = "profiling", unix))]
+    #[error("heap profiler failed: {0}")]
+    Heap(String),
+}
+
+/// Whether this build can serve profiles
+pub fn supported() -> bool {
+    cfg!(all(feature = "profiling", unix))
+}
+
+#[cfg(all(feature = "profiling", unix))]
+mod imp {
+    use super::ProfilingError;
+    use pprof::protos::Message;
+    use std::ffi::CString;
+    use std::fs::OpenOptions;
+    use std::os::unix::fs::OpenOptionsExt;
+    use std::time::Duration;
+
+    const SAMPLE_FREQUENCY: i32 = 99;
+
+    /// jemalloc reads its options from this symbol at startup. Profiling has to
+    /// be compiled in from the start, but sampling stays off, and costs nothing,
+    /// until [`enable_heap_sampling`] turns it on.
+    #[export_name = "_rjem_malloc_conf"]
+    pub static MALLOC_CONF: &[u8] = b"prof:true,prof_active:false,lg_prof_sample:19\0";
+
+    pub fn enable_heap_sampling() -> Result<(), ProfilingError> {
+        // SAFETY: prof.active takes a bool
+        unsafe { tikv_jemalloc_ctl::raw::write(b"prof.active\0", true) }
+            .map_err(|e| ProfilingError::Heap(e.to_string()))
+    }
+
+    pub async fn cpu_profile(seconds: u64) -> Result<Vec<u8>, ProfilingError> {
+        let guard = pprof::ProfilerGuardBuilder::default()
+            .frequency(SAMPLE_FREQUENCY)
+            .blocklist(&["libc", "libgcc", "pthread", "vdso"])
+            .build()
+            .map_err(|e| ProfilingError::Cpu(e.to_string()))?;
+
+        tokio::time::sleep(Duration::from_secs(seconds)).await;
+
+        let profile = guard
+            .report()
+            .build()
+            .and_then(|report| report.pprof())
+            .map_err(|e| ProfilingError::Cpu(e.to_string()))?;
+
+        let mut body = Vec::new();
+        profile
+            .encode(&mut body)
+            .map_err(|e| ProfilingError::Cpu(e.to_string()))?;
+        Ok(body)
+    }
+
+    pub fn heap_profile() -> Result<Vec<u8>, ProfilingError> {
+        let state_dir = crate::state::ResourceState::get_state_file_path()
+            .parent()
+            .map(std::path::Path::to_path_buf)
+            .unwrap_or_else(|| ".".into());
+        let path = state_dir.join(format!(".heap-{}.prof", uuid::Uuid::new_v4()));
+        // Created owner-only and exclusively up front; jemalloc then truncates and fills it
+        OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&path)
+            .map_err(|e| ProfilingError::Heap(format!("{}: {}", path.display(), e)))?;
+        let c_path = CString::new(path.to_string_lossy().as_bytes())
+            .map_err(|e| ProfilingError::Heap(e.to_string()))?;
+
+        // SAFETY: prof.dump takes a NUL-terminated path that outlives the call
+        unsafe { tikv_jemalloc_ctl::raw::write(b"prof.dump\0", c_path.as_ptr()) }
+            .map_err(|e| ProfilingError::Heap(e.to_string()))?;
+
+        let body = std::fs::read(&path).map_err(|e| ProfilingError::Heap(e.to_string()));
+        let _ = std::fs::remove_file(&path);
+        body
+    }
+}
+
+/// Sample the CPU for `seconds` and return an uncompressed pprof protobuf
+pub async fn cpu_profile(seconds: u64) -> Result<Vec<u8>, ProfilingError> {
+    #[cfg(all(feature = "profiling", unix))]
+    return imp::cpu_profile(seconds).await;
+
+    #[cfg(not(all(feature = "profiling", unix)))]
+    {
+        let _ = seconds;
+        Err(ProfilingError::Unsupported)
+    }
+}
+
+/// Start sampling allocations so later heap profiles have something to show
+pub fn enable_heap_sampling() -> Result<(), ProfilingError> {
+    #[cfg(all(feature = "profiling", unix))]
+    return imp::enable_heap_sampling();
+
+    #[cfg(not(all(feature = "profiling", unix)))]
+    Err(ProfilingError::Unsupported)
+}
+
+/// Dump the jemalloc heap sampled since [`enable_heap_sampling`], in jeprof format
+pub fn heap_profile() -> Result<Vec<u8>, ProfilingError> {
+    #[cfg(all(feature = "profiling", unix))]
+    return imp::heap_profile();
+
+    #[cfg(not(all(feature = "profiling", unix)))]
+    Err(ProfilingError::Unsupported)
+}
@@ -0,0 +1,286 @@
+use regex::Regex;
+
+use crate::config::RelabelRule;
+use crate::metrics::DiskMetric;
+
+/// Numeric fields a `scale` rule may target
+const SCALABLE_FIELDS: &[&str] = &[
+    "total_space_bytes",
+    "used_space_bytes",
+    "available_space_bytes",
+    "usage_percentage",
+];
+
+enum Step {
+    Replace {
+        source: String,
+        regex: Regex,
+        replacement: String,
+        target: String,
+    },
+    Keep { source: String, regex: Regex },
+    Drop { source: String, regex: Regex },
+    AddLabel { label: String, value: String },
+    RemoveLabel { label: String },
+    RenameLabel { from: String, to: String },
+    Scale { field: String, factor: f64 },
+}
+
+/// Compiled relabel pipeline applied to metrics before they are buffered
+pub struct Relabeler {
+    steps: Vec<Step>,
+}
+
+impl Relabeler {
+    pub fn new(rules: &[RelabelRule]) -> Result<Self, RelabelError> {
+        let steps = rules
+            .iter()
+            .enumerate()
+            .map(|(index, rule)| compile(rule).map_err(|e| RelabelError { index, message: e }))
+            .collect::<Result<_, _>>()?;
+        Ok(Self { steps })
+    }
+
+    /// Run every metric through the pipeline, dropping those a keep/drop rule rejects
+    pub fn apply(&self, metrics: Vec<DiskMetric>) -> Vec<DiskMetric> {
+        metrics
+            .into_iter()
+            .filter_map(|metric| self.apply_one(metric))
+            .collect()
+    }
+
+    fn apply_one(&self, mut metric: DiskMetric) -> Option<DiskMetric> {
+        for step in &self.steps {
+            match step {
+                Step::Replace { source, regex, replacement, target } => {
+                    let value = get(&metric, source);
+                    if let Some(captures) = regex.captures(&value) {
+                        let mut rewritten = String::new();
+                        captures.expand(replacement, &mut rewritten);
+                        set(&mut metric, target, rewritten);
+                    }
+                }
+                Step::Keep { source, regex } => {
+                    if !regex.is_match(&get(&metric, source)) {
+                        return None;
+                    }
+                }
+                Step::Drop { source, regex } => {
+                    if regex.is_match(&get(&metric, source)) {
+                        return None;
+                    }
+                }
+                Step::AddLabel { label, value } => {
+                    metric.labels.insert(label.clone(), value.clone());
+                }
+                Step::RemoveLabel { label } => {
+                    metric.labels.remove(label);
+                }
+                Step::RenameLabel { from, to } => {
+                    if let Some(value) = metric.labels.remove(from) {
+                        metric.labels.insert(to.clone(), value);
+                    }
+                }
+                Step::Scale { field, factor } => scale(&mut metric, field, *factor),
+            }
+        }
+        Some(metric)
+    }
+}
+
+fn compile(rule: &RelabelRule) -> Result<Step, String> {
+    Ok(match rule {
+        RelabelRule::Replace { source, regex, replacement, target } => Step::Replace {
+            source: source.clone(),
+            regex: anchored(regex)?,
+            replacement: replacement.clone(),
+            target: target.clone().unwrap_or_else(|| source.clone()),
+        },
+        RelabelRule::Keep { source, regex } => Step::Keep {
+            source: source.clone(),
+            regex: anchored(regex)?,
+        },
+        RelabelRule::Drop { source, regex } => Step::Drop {
+            source: source.clone(),
+            regex: anchored(regex)?,
+        },
+        RelabelRule::AddLabel { label, value } => {
+            check_label(label)?;
+            Step::AddLabel { label: label.clone(), value: value.clone() }
+        }
+        RelabelRule::RemoveLabel { label } => Step::RemoveLabel { label: label.clone() },
+        RelabelRule::RenameLabel { from, to } => {
+            check_label(to)?;
+            Step::RenameLabel { from: from.clone(), to: to.clone() }
+        }
+        RelabelRule::Scale { field, factor } => {
+            if !SCALABLE_FIELDS.contains(&field.as_str()) {
+                return Err(format!(
+                    "cannot scale {}, expected one of {}",
+                    field,
+                    SCALABLE_FIELDS.join(", ")
+                ));
+            }
+            if !factor.is_finite() || *factor <= 0.0 {
+                return Err(format!("scale factor must be positive, got {}", factor));
+            }
+            Step::Scale { field: field.clone(), factor: *factor }
+        }
+    })
+}
+
+/// Whole-value match, as in Prometheus
+fn anchored(pattern: &str) -> Result<Regex, String> {
+    Regex::new(&format!("^(?:{})$", pattern)).map_err(|e| e.to_string())
+}
+
+/// Labels must not shadow the built-in fields
+fn check_label(label: &str) -> Result<(), String> {
+    if label.is_empty() || label == "device" || label == "mount_point" {
+        return Err(format!("invalid label name {:?}", label));
+    }
+    Ok(())
+}
+
+fn get(metric: &DiskMetric, name: &str) -> String {
+    match name {
+        "device" => metric.device.clone(),
+        "mount_point" => metric.mount_point.clone(),
+        label => metric.labels.get(label).cloned().unwrap_or_default(),
+    }
+}
+
+fn set(metric: &mut DiskMetric, name: &str, value: String) {
+    match name {
+        "device" => metric.device = value,
+        "mount_point" => metric.mount_point = value,
+        label => {
+            metric.labels.insert(label.to_string(), value);
+        }
+    }
+}
+
+fn scale(metric: &mut DiskMetric, field: &str, factor: f64) {
+    let scale_bytes = |value: u64| (value as f64 * factor).round() as u64;
+    match field {
+        "total_space_bytes" => metric.total_space_bytes = scale_bytes(metric.total_space_bytes),
+        "used_space_bytes" => metric.used_space_bytes = scale_bytes(metric.used_space_bytes),
+        "available_space_bytes" => {
+            metric.available_space_bytes = scale_bytes(metric.available_space_bytes)
+        }
+        "usage_percentage" => metric.usage_percentage *= factor,
+        _ => {}
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("Invalid relabel rule #{}: {message}", index + 1)]
+pub struct RelabelError {
+    index: usize,
+    message: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use std::collections::BTreeMap;
+
+    fn create_metric(device: &str, mount_point: &str) -> DiskMetric {
+        DiskMetric {
+            timestamp: 1234567890,
+            device: device.to_string(),
+            mount_point: mount_point.to_string(),
+            total_space_bytes: 1000,
+            used_space_bytes: 250,
+            available_space_bytes: 750,
+            usage_percentage: 0.25,
+            labels: BTreeMap::new(),
+            aggregate: None,
+            severity: None,
+        }
+    }
+
+    fn parse_rules(yaml: &str) -> Vec<RelabelRule> {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    #[test]
+    fn test_pipeline() {
+        let rules = parse_rules(
+            r#"
+- action: drop
+  source: mount_point
+  regex: "/snap/.*"
+- action: replace
+  source: device
+  regex: "/dev/(.*)"
+  replacement: "$1"
+- action: replace
+  source: device
+  regex: "(nvme\\d+).*"
+  replacement: "$1"
+  target: controller
+- action: add_label
+  label: tier
+  value: ssd
+- action: rename_label
+  from: controller
+  to: disk
+- action: scale
+  field: usage_percentage
+  factor: 100
+"#,
+        );
+        let relabeler = Relabeler::new(&rules).unwrap();
+
+        let metrics = relabeler.apply(vec![
+            create_metric("/dev/nvme0n1p1", "/"),
+            create_metric("/dev/loop3", "/snap/core/123"),
+        ]);
+        assert_eq!(metrics.len(), 1);
+
+        let metric = &metrics[0];
+        assert_eq!(metric.device, "nvme0n1p1");
+        assert_eq!(metric.labels.get("disk").map(String::as_str), Some("nvme0"));
+        assert_eq!(metric.labels.get("tier").map(String::as_str), Some("ssd"));
+        assert!(!metric.labels.contains_key("controller"));
+        assert_eq!(metric.usage_percentage, 25.0);
+    }
+
+    #[test]
+    fn test_keep_matches_whole_value() {
+        let rules = parse_rules("- action: keep\n  source: mount_point\n  regex: \"/home\"\n");
+        let relabeler = Relabeler::new(&rules).unwrap();
+
+        let metrics = relabeler.apply(vec![
+            create_metric("/dev/sda1", "/home"),
+            create_metric("/dev/sda2", "/home/backup"),
+        ]);
+        assert_eq!(metrics.len(), 1);
+        assert_eq!(metrics[0].mount_point, "/home");
+    }
+
+    #[test]
+    fn test_invalid_rules_fail_validation() {
+        let yaml = r#"
+agent:
+  hostname: "test-host"
+api:
+  endpoint: "https://api.example.com"
+collection:
+  interval_seconds: 60
+  disk:
+    enabled: true
+  relabel:
+    - action: scale
+      field: device
+      factor: 2
+"#;
+        let error = Config::load_from_str(yaml).unwrap_err().to_string();
+        assert!(error.contains("relabel rule #1"), "{}", error);
+
+        let rules = parse_rules("- action: drop\n  source: device\n  regex: \"(\"\n");
+        assert!(Relabeler::new(&rules).is_err());
+    }
+}
@@ -0,0 +1,241 @@
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::fs;
+use std::path::Path;
+use thiserror::Error;
+
+use crate::config::{Config, ConfigError};
+
+/// Response header carrying the hex HMAC-SHA256 of the body
+pub const SIGNATURE_HEADER: &str = "X-Operion-Signature";
+
+#[derive(Error, Debug)]
+pub enum RemoteConfigError {
+    #[error("remote config is not signed")]
+    Unsigned,
+    #[error("remote config signature is invalid")]
+    InvalidSignature,
+    #[error("failed to parse remote config: {0}")]
+    Parse(String),
+    #[error("failed to {action} remote config cache {path}: {message}")]
+    Cache {
+        action: &'static str,
+        path: String,
+        message: String,
+    },
+}
+
+/// Remote config as served by the platform, kept byte-for-byte so the
+/// signature can be checked again when it is loaded from the cache
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SignedRemoteConfig {
+    pub payload: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>,
+}
+
+impl SignedRemoteConfig {
+    /// Check the signature when `signing_key` is set, and parse the payload
+    pub fn verify(&self, signing_key: Option<&str>) -> Result<RemoteConfig, RemoteConfigError> {
+        if let Some(key) = signing_key {
+            let signature = self.signature.as_deref().ok_or(RemoteConfigError::Unsigned)?;
+            let signature =
+                hex::decode(signature.trim()).map_err(|_| RemoteConfigError::InvalidSignature)?;
+            let mut mac = Hmac::<Sha256>::new_from_slice(key.as_bytes())
+                .map_err(|_| RemoteConfigError::InvalidSignature)?;
+            mac.update(self.payload.as_bytes());
+            mac.verify_slice(&signature)
+                .map_err(|_| RemoteConfigError::InvalidSignature)?;
+        }
+        serde_json::from_str(&self.payload).map_err(|e| RemoteConfigError::Parse(e.to_string()))
+    }
+
+    /// The cached config at `path`, `None` if nothing was cached yet
+    pub fn load_cache(path: &Path) -> Result<Option<Self>, RemoteConfigError> {
+        let cache_error = |message: String| RemoteConfigError::Cache {
+            action: "read",
+            path: path.display().to_string(),
+            message,
+        };
+        match fs::read_to_string(path) {
+            Ok(contents) => serde_json::from_str(&contents)
+                .map(Some)
+                .map_err(|e| cache_error(e.to_string())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(cache_error(e.to_string())),
+        }
+    }
+
+    /// Replace the cache at `path` with this config
+    pub fn save_cache(&self, path: &Path) -> Result<(), RemoteConfigError> {
+        let cache_error = |message: String| RemoteConfigError::Cache {
+            action: "write",
+            path: path.display().to_string(),
+            message,
+        };
+        if let Some(parent) = path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+            fs::create_dir_all(parent).map_err(|e| cache_error(e.to_string()))?;
+        }
+        let json = serde_json::to_string_pretty(self).map_err(|e| cache_error(e.to_string()))?;
+        let temp_path = path.with_extension("tmp");
+        fs::write(&temp_path, json).map_err(|e| cache_error(e.to_string()))?;
+        fs::rename(&temp_path, path).map_err(|e| cache_error(e.to_string()))
+    }
+
+    /// Forget the cached config, e.g. once the platform no longer publishes one
+    pub fn remove_cache(path: &Path) -> Result<(), RemoteConfigError> {
+        match fs::remove_file(path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(RemoteConfigError::Cache {
+                action: "remove",
+                path: path.display().to_string(),
+                message: e.to_string(),
+            }),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Collection settings managed centrally by the Operion platform
+///
+/// Precedence: values present here override the local YAML; anything absent
+/// keeps its local value. API and authentication settings are never remote-managed.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+pub struct RemoteConfig {
+    pub interval_seconds: Option<u64>,
+    pub flush_interval_seconds: Option<u64>,
+    pub batch_size: Option<usize>,
+    pub disk: Option<RemoteDiskConfig>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+pub struct RemoteDiskConfig {
+    pub enabled: Option<bool>,
+    pub include_mount_points: Option<Vec<String>>,
+    pub exclude_mount_points: Option<Vec<String>>,
+    pub exclude_devices: Option<Vec<String>>,
+}
+
+impl RemoteConfig {
+    /// Overlay the remote settings on top of the local config
+    pub fn apply(&self, local: &Config) -> Result<Config, ConfigError> {
+        let mut config = local.clone();
+
+        if let Some(interval_seconds) = self.interval_seconds {
+            config.collection.interval_seconds = interval_seconds;
+        }
+        if let Some(flush_interval_seconds) = self.flush_interval_seconds {
+            config.collection.flush_interval_seconds = Some(flush_interval_seconds);
+        }
+        if let Some(batch_size) = self.batch_size {
+            config.collection.batch_size = Some(batch_size);
+        }
+
+        if let Some(disk) = &self.disk {
+            if let Some(enabled) = disk.enabled {
+                config.collection.disk.enabled = enabled;
+            }
+            if let Some(include) = &disk.include_mount_points {
+                config.collection.disk.include_mount_points = Some(include.clone());
+            }
+            if let Some(exclude) = &disk.exclude_mount_points {
+                config.collection.disk.exclude_mount_points = Some(exclude.clone());
+            }
+            if let Some(devices) = &disk.exclude_devices {
+                config.collection.disk.exclude_devices = Some(devices.clone());
+            }
+        }
+
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create_local_config() -> Config {
+        Config::load_from_str(r#"
+agent:
+  hostname: "test-host"
+api:
+  endpoint: "https://api.example.com"
+collection:
+  interval_seconds: 60
+  batch_size: 100
+  disk:
+    enabled: true
+    exclude_mount_points: ["/dev"]
+"#).unwrap()
+    }
+
+    #[test]
+    fn test_remote_values_override_local() {
+        let remote: RemoteConfig = serde_json::from_value(serde_json::json!({
+            "interval_seconds": 30,
+            "disk": { "exclude_mount_points": ["/dev", "/proc"] }
+        }))
+        .unwrap();
+
+        let config = remote.apply(&create_local_config()).unwrap();
+        assert_eq!(config.collection.interval_seconds, 30);
+        assert_eq!(config.get_batch_size(), 100);
+        assert!(config.collection.disk.enabled);
+        assert_eq!(
+            config.collection.disk.exclude_mount_points,
+            Some(vec!["/dev".to_string(), "/proc".to_string()])
+        );
+    }
+
+    #[test]
+    fn test_invalid_remote_config_rejected() {
+        let remote = RemoteConfig {
+            interval_seconds: Some(0),
+            ..Default::default()
+        };
+
+        assert!(remote.apply(&create_local_config()).is_err());
+    }
+
+    fn sign(payload: &str, key: &str) -> SignedRemoteConfig {
+        let mut mac = Hmac::<Sha256>::new_from_slice(key.as_bytes()).unwrap();
+        mac.update(payload.as_bytes());
+        SignedRemoteConfig {
+            payload: payload.to_string(),
+            signature: Some(hex::encode(mac.finalize().into_bytes())),
+        }
+    }
+
+    #[test]
+    fn test_signature_verification() {
+        let signed = sign(r#"{"interval_seconds":30}"#, "platform-secret");
+        assert_eq!(signed.verify(Some("platform-secret")).unwrap().interval_seconds, Some(30));
+        assert!(matches!(
+            signed.verify(Some("other-secret")),
+            Err(RemoteConfigError::InvalidSignature)
+        ));
+
+        let unsigned = SignedRemoteConfig { signature: None, ..signed };
+        assert!(matches!(unsigned.verify(Some("platform-secret")), Err(RemoteConfigError::Unsigned)));
+        // Without a key signatures are not required
+        assert!(unsigned.verify(None).is_ok());
+    }
+
+    #[test]
+    fn test_cache_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cache").join("remote-config.json");
+        assert!(SignedRemoteConfig::load_cache(&path).unwrap().is_none());
+
+        let signed = sign(r#"{"batch_size":10}"#, "platform-secret");
+        signed.save_cache(&path).unwrap();
+        let cached = SignedRemoteConfig::load_cache(&path).unwrap().unwrap();
+        assert_eq!(cached, signed);
+        assert!(cached.verify(Some("platform-secret")).is_ok());
+
+        SignedRemoteConfig::remove_cache(&path).unwrap();
+        SignedRemoteConfig::remove_cache(&path).unwrap();
+        assert!(SignedRemoteConfig::load_cache(&path).unwrap().is_none());
+    }
+}
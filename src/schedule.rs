@@ -0,0 +1,215 @@
+use rand::Rng;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::time::{sleep_until, Duration, Instant};
+
+/// Periodic timer with optional random jitter and a fixed phase
+///
+/// Unlike `tokio::time::Interval`, every tick is delayed by a fresh random
+/// amount up to `jitter`, so a fleet started at the same instant spreads out.
+/// Jitter does not accumulate: ticks stay anchored to the base schedule.
+pub struct JitteredTimer {
+    period: Duration,
+    jitter: Duration,
+    /// Next tick before jitter is applied
+    base: Instant,
+    /// Next tick including jitter
+    next: Instant,
+}
+
+impl JitteredTimer {
+    /// The first tick fires after `phase` (immediately when zero)
+    pub fn new(period: Duration, jitter: Duration, phase: Duration) -> Self {
+        let base = Instant::now() + phase;
+        Self {
+            period,
+            jitter,
+            base,
+            next: base + random_delay(jitter),
+        }
+    }
+
+    /// For a timer replacing a running one: a first tick that would fire right
+    /// away waits a period instead, so rebuilding the schedule (a new interval,
+    /// a config reload) neither adds a tick nor lines up the fleet
+    pub fn deferred(mut self) -> Self {
+        if self.base <= Instant::now() {
+            self.base += self.period;
+            self.next = self.base + random_delay(self.jitter);
+        }
+        self
+    }
+
+    pub fn period(&self) -> Duration {
+        self.period
+    }
+
+    /// Wait for the next tick; cancel-safe, so it can be used in `tokio::select!`
+    pub async fn tick(&mut self) {
+        sleep_until(self.next).await;
+
+        let now = Instant::now();
+        self.base += self.period;
+        // Skip missed ticks instead of bursting after a long stall
+        if self.base < now {
+            self.base = now + self.period;
+        }
+        self.next = self.base + random_delay(self.jitter);
+    }
+}
+
+fn random_delay(jitter: Duration) -> Duration {
+    if jitter.is_zero() {
+        return Duration::ZERO;
+    }
+    Duration::from_millis(rand::thread_rng().gen_range(0..=jitter.as_millis() as u64))
+}
+
+/// Delay until this host's slot within `period`, derived from a stable key such as the resource ID
+///
+/// Hosts with different keys tick at different offsets of the wall clock, and the
+/// same host keeps its offset across restarts.
+pub fn phase_offset(key: &str, period: Duration, now: SystemTime) -> Duration {
+    let period_ms = period.as_millis() as u64;
+    if period_ms == 0 {
+        return Duration::ZERO;
+    }
+
+    let digest = Sha256::digest(key.as_bytes());
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&digest[..8]);
+    let slot_ms = u64::from_be_bytes(bytes) % period_ms;
+
+    let now_ms = now
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+        % period_ms;
+
+    Duration::from_millis((slot_ms + period_ms - now_ms) % period_ms)
+}
+
+/// Wall-clock interval a batch covers, in Unix seconds (`start` inclusive, `end` exclusive)
+#[derive(Debug, Clone, Copy, Serialize, PartialEq)]
+pub struct BatchWindow {
+    pub start: u64,
+    pub end: u64,
+}
+
+/// Delay until the next wall-clock multiple of `period` (zero when exactly on one)
+pub fn align_offset(period: Duration, now: SystemTime) -> Duration {
+    let period_ms = period.as_millis() as u64;
+    if period_ms == 0 {
+        return Duration::ZERO;
+    }
+
+    let now_ms = now
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+        % period_ms;
+    Duration::from_millis((period_ms - now_ms) % period_ms)
+}
+
+/// The aligned window of length `period` that most recently ended at or before `now`
+pub fn aligned_window(period: Duration, now: SystemTime) -> BatchWindow {
+    let period = period.as_secs().max(1);
+    let now = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let end = now - now % period;
+    BatchWindow {
+        start: end.saturating_sub(period),
+        end,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_phase_offset_is_stable_and_aligned() {
+        let period = Duration::from_secs(60);
+        let now = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+
+        let offset = phase_offset("res_abc123", period, now);
+        assert!(offset < period);
+        assert_eq!(offset, phase_offset("res_abc123", period, now));
+
+        // The tick lands on the same wall-clock slot regardless of start time
+        let later = now + Duration::from_secs(17);
+        let slot = |start: SystemTime, offset: Duration| {
+            (start + offset).duration_since(UNIX_EPOCH).unwrap().as_millis() % period.as_millis()
+        };
+        assert_eq!(slot(now, offset), slot(later, phase_offset("res_abc123", period, later)));
+
+        assert_ne!(
+            phase_offset("res_abc123", period, now),
+            phase_offset("res_def456", period, now)
+        );
+    }
+
+    #[test]
+    fn test_alignment_to_wall_clock() {
+        let period = Duration::from_secs(60);
+        let now = UNIX_EPOCH + Duration::from_secs(1_700_000_017);
+
+        assert_eq!(align_offset(period, now), Duration::from_secs(23));
+        assert_eq!(align_offset(period, UNIX_EPOCH + Duration::from_secs(1_700_000_040)), Duration::ZERO);
+
+        // Flushing a little after :00 (jitter) still reports the minute that just ended
+        let window = aligned_window(period, UNIX_EPOCH + Duration::from_secs(1_700_000_043));
+        assert_eq!(window, BatchWindow { start: 1_699_999_980, end: 1_700_000_040 });
+    }
+
+    #[test]
+    fn test_random_delay_bounds() {
+        assert_eq!(random_delay(Duration::ZERO), Duration::ZERO);
+        for _ in 0..100 {
+            assert!(random_delay(Duration::from_secs(5)) <= Duration::from_secs(5));
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_timer_ticks_on_schedule() {
+        let start = Instant::now();
+        let mut timer = JitteredTimer::new(Duration::from_secs(10), Duration::ZERO, Duration::ZERO);
+
+        timer.tick().await;
+        assert_eq!(Instant::now() - start, Duration::ZERO);
+        timer.tick().await;
+        assert_eq!(Instant::now() - start, Duration::from_secs(10));
+        timer.tick().await;
+        assert_eq!(Instant::now() - start, Duration::from_secs(20));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_deferred_timer_waits_out_its_first_period() {
+        let start = Instant::now();
+        let mut timer = JitteredTimer::new(Duration::from_secs(10), Duration::ZERO, Duration::ZERO).deferred();
+        timer.tick().await;
+        assert_eq!(Instant::now() - start, Duration::from_secs(10));
+
+        // A phase already delays the first tick, and is kept
+        let start = Instant::now();
+        let mut timer =
+            JitteredTimer::new(Duration::from_secs(10), Duration::ZERO, Duration::from_secs(3)).deferred();
+        timer.tick().await;
+        assert_eq!(Instant::now() - start, Duration::from_secs(3));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_timer_jitter_stays_within_period_slot() {
+        let start = Instant::now();
+        let mut timer =
+            JitteredTimer::new(Duration::from_secs(10), Duration::from_secs(2), Duration::from_secs(3));
+
+        timer.tick().await;
+        let first = Instant::now() - start;
+        assert!(first >= Duration::from_secs(3) && first <= Duration::from_secs(5));
+
+        timer.tick().await;
+        let second = Instant::now() - start;
+        assert!(second >= Duration::from_secs(13) && second <= Duration::from_secs(15));
+    }
+}
@@ -0,0 +1,153 @@
+//! Prometheus and OpenMetrics endpoints scraped as collectors
+//!
+//! Each entry of `collection.scrape.targets` is its own collector, named
+//! `scrape:<name>`. The request asks for OpenMetrics first, as Prometheus
+//! does, and the response's `Content-Type` picks the parser; see
+//! [`crate::exposition`]. Timestamps the endpoint sends are kept. Requests
+//! use the agent's TLS settings, and a response larger than
+//! `collection.scrape.max_body_size_mb` fails the scrape without being read
+//! whole.
+
+use async_trait::async_trait;
+use reqwest::header::{HeaderMap, HeaderValue, ACCEPT, CONTENT_TYPE};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::exposition::{self, Format, Timestamps};
+use crate::metrics::{CollectContext, Collected, MetricCollector, MetricError};
+
+/// Formats we parse, most preferred first
+const ACCEPT_HEADER: &str =
+    "application/openmetrics-text;version=1.0.0,text/plain;version=0.0.4;q=0.5,*/*;q=0.1";
+
+pub struct ScrapeCollector {
+    url: String,
+    client: reqwest::Client,
+    max_body_bytes: u64,
+}
+
+impl ScrapeCollector {
+    pub fn new(url: String, client: reqwest::Client, max_body_bytes: u64) -> Self {
+        Self {
+            url,
+            client,
+            max_body_bytes,
+        }
+    }
+
+    /// The response body, read in chunks and given up on past `max_body_bytes`
+    async fn read_body(&self, mut response: reqwest::Response) -> Result<String, MetricError> {
+        let too_large = || self.error(format!("response larger than {} bytes", self.max_body_bytes));
+        if response.content_length().is_some_and(|length| length > self.max_body_bytes) {
+            return Err(too_large());
+        }
+        let mut body = Vec::new();
+        while let Some(chunk) = response.chunk().await.map_err(|e| self.error(e.to_string()))? {
+            if (body.len() + chunk.len()) as u64 > self.max_body_bytes {
+                return Err(too_large());
+            }
+            body.extend_from_slice(&chunk);
+        }
+        Ok(String::from_utf8_lossy(&body).into_owned())
+    }
+
+    fn error(&self, message: String) -> MetricError {
+        MetricError::Scrape {
+            url: self.url.clone(),
+            message,
+        }
+    }
+}
+
+#[async_trait]
+impl MetricCollector for ScrapeCollector {
+    async fn collect(&self, _ctx: CollectContext) -> Result<Collected, MetricError> {
+        // `header` would append to the client's default `Accept: */*`
+        let mut headers = HeaderMap::new();
+        headers.insert(ACCEPT, HeaderValue::from_static(ACCEPT_HEADER));
+        // The run is dropped at the collector deadline, taking the request with it
+        let response = self
+            .client
+            .get(&self.url)
+            .headers(headers)
+            .send()
+            .await
+            .map_err(|e| self.error(e.to_string()))?;
+        if !response.status().is_success() {
+            return Err(self.error(format!("HTTP {}", response.status())));
+        }
+
+        let format = response
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .map(Format::from_content_type)
+            .unwrap_or(Format::Prometheus);
+        let body = self.read_body(response).await?;
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let samples = exposition::parse(&body, format, timestamp, Timestamps::Allow)
+            .map_err(|e| self.error(e.to_string()))?;
+        Ok(samples.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::SampleKind;
+    use std::time::Duration;
+    use wiremock::matchers::{headers, method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    #[tokio::test]
+    async fn test_scrape_picks_parser_from_content_type() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/openmetrics"))
+            .and(headers("accept", ACCEPT_HEADER.split(',').collect()))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_raw(
+                    "# TYPE jobs counter\njobs_total 3 # {trace_id=\"abc\"} 1\n# EOF\n",
+                    "application/openmetrics-text; version=1.0.0; charset=utf-8",
+                ),
+            )
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/metrics"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_raw(
+                    "# TYPE jobs_total counter\njobs_total 3 1700000000000\n",
+                    "text/plain; version=0.0.4",
+                ),
+            )
+            .mount(&server)
+            .await;
+        let client = reqwest::Client::new();
+
+        let openmetrics = ScrapeCollector::new(format!("{}/openmetrics", server.uri()), client.clone(), 1024);
+        let samples = openmetrics.collect(CollectContext::new(Duration::from_secs(5))).await.unwrap().samples;
+        assert_eq!(samples[0].kind, SampleKind::Counter);
+        assert_eq!(samples[0].exemplar.as_ref().unwrap().labels["trace_id"], "abc");
+
+        let prometheus = ScrapeCollector::new(format!("{}/metrics", server.uri()), client.clone(), 1024);
+        let samples = prometheus.collect(CollectContext::new(Duration::from_secs(5))).await.unwrap().samples;
+        assert_eq!(samples[0].kind, SampleKind::Counter);
+        assert_eq!(samples[0].timestamp, 1_700_000_000);
+
+        let missing = ScrapeCollector::new(format!("{}/missing", server.uri()), client.clone(), 1024);
+        assert!(matches!(
+            missing.collect(CollectContext::new(Duration::from_secs(5))).await,
+            Err(MetricError::Scrape { .. })
+        ));
+
+        // A response over the cap fails the scrape
+        let oversized = ScrapeCollector::new(format!("{}/metrics", server.uri()), client, 16);
+        match oversized.collect(CollectContext::new(Duration::from_secs(5))).await {
+            Err(MetricError::Scrape { message, .. }) => assert!(message.contains("larger than 16 bytes"), "{}", message),
+            other => panic!("unexpected result: {:?}", other.map(|collected| collected.len())),
+        }
+    }
+}
@@ -0,0 +1,233 @@
+//! Rhai scripts embedded in the config
+//!
+//! Scripts transform each collection (`collection.script`), decide whether an
+//! alert fires (`condition` on an alert rule) and format alert messages
+//! (`message`). Every run is bounded by an operation count, a wall-clock
+//! timeout and size limits on strings and arrays, and scripts have no access
+//! to files, the network or other processes.
+
+use rhai::{Dynamic, Engine, Scope, AST};
+use std::cell::Cell;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+use crate::config::ScriptingConfig;
+use crate::metrics::DiskMetric;
+
+#[derive(Error, Debug)]
+pub enum ScriptError {
+    #[error("failed to compile {name} script: {message}")]
+    Compile { name: String, message: String },
+    #[error("{name} script failed: {message}")]
+    Eval { name: String, message: String },
+}
+
+/// Bounds on a single script run
+#[derive(Debug, Clone)]
+pub struct ScriptLimits {
+    pub max_operations: u64,
+    pub timeout: Duration,
+    pub max_string_size: usize,
+    pub max_array_size: usize,
+}
+
+impl ScriptLimits {
+    pub fn from_config(config: &ScriptingConfig) -> Self {
+        Self {
+            max_operations: config.get_max_operations(),
+            timeout: Duration::from_millis(config.get_timeout_ms()),
+            max_string_size: config.get_max_string_size(),
+            max_array_size: config.get_max_array_size(),
+        }
+    }
+}
+
+thread_local! {
+    /// Deadline of the script running on this thread; checked from the progress callback
+    static DEADLINE: Cell<Option<Instant>> = const { Cell::new(None) };
+}
+
+/// A compiled script; cheap to clone
+#[derive(Clone)]
+pub struct Script {
+    name: String,
+    engine: Arc<Engine>,
+    ast: Arc<AST>,
+    timeout: Duration,
+}
+
+impl Script {
+    /// Compile `source`; `name` identifies the script in errors and logs
+    pub fn compile(name: &str, source: &str, limits: &ScriptLimits) -> Result<Self, ScriptError> {
+        let mut engine = Engine::new();
+        engine
+            .set_max_operations(limits.max_operations)
+            .set_max_string_size(limits.max_string_size)
+            .set_max_array_size(limits.max_array_size)
+            .set_max_map_size(limits.max_array_size)
+            .on_progress(|_| {
+                let expired = DEADLINE.with(|deadline| deadline.get().is_some_and(|d| Instant::now() > d));
+                expired.then(|| Dynamic::from("timed out"))
+            });
+        engine.disable_symbol("eval");
+
+        let script_name = name.to_string();
+        engine.on_print(move |message| tracing::debug!(script = %script_name, "{}", message));
+        let script_name = name.to_string();
+        engine.on_debug(move |message, _, _| tracing::debug!(script = %script_name, "{}", message));
+
+        let ast = engine.compile(source).map_err(|e| ScriptError::Compile {
+            name: name.to_string(),
+            message: e.to_string(),
+        })?;
+
+        Ok(Self {
+            name: name.to_string(),
+            engine: Arc::new(engine),
+            ast: Arc::new(ast),
+            timeout: limits.timeout,
+        })
+    }
+
+    fn eval(&self, scope: &mut Scope) -> Result<Dynamic, ScriptError> {
+        DEADLINE.with(|deadline| deadline.set(Some(Instant::now() + self.timeout)));
+        let result = self.engine.eval_ast_with_scope::<Dynamic>(scope, &self.ast);
+        DEADLINE.with(|deadline| deadline.set(None));
+        result.map_err(|e| self.error(e.to_string()))
+    }
+
+    fn error(&self, message: impl Into<String>) -> ScriptError {
+        ScriptError::Eval {
+            name: self.name.clone(),
+            message: message.into(),
+        }
+    }
+
+    /// Run a transform script over `metrics`, which it sees as an array of maps
+    ///
+    /// The script's value is the new list of metrics.
+    pub fn transform(&self, metrics: &[DiskMetric]) -> Result<Vec<DiskMetric>, ScriptError> {
+        let mut input = Vec::with_capacity(metrics.len());
+        for metric in metrics {
+            let mut map = rhai::serde::to_dynamic(metric)
+                .map_err(|e| self.error(e.to_string()))?
+                .try_cast::<rhai::Map>()
+                .ok_or_else(|| self.error("metric is not a map"))?;
+            // Empty labels are not serialized, but scripts should always be able to add some
+            map.entry("labels".into()).or_insert_with(|| rhai::Map::new().into());
+            input.push(Dynamic::from_map(map));
+        }
+        let mut scope = Scope::new();
+        scope.push("metrics", input);
+
+        let output = self.eval(&mut scope)?;
+        rhai::serde::from_dynamic(&output)
+            .map_err(|e| self.error(format!("must return an array of metrics: {}", e)))
+    }
+
+    /// Evaluate an alert condition with `subject`, `value` and `threshold` in scope
+    pub fn condition(&self, subject: &str, value: f64, threshold: f64) -> Result<bool, ScriptError> {
+        let mut scope = Scope::new();
+        scope.push("subject", subject.to_string());
+        scope.push("value", value);
+        scope.push("threshold", threshold);
+
+        self.eval(&mut scope)?
+            .as_bool()
+            .map_err(|found| self.error(format!("must return a bool, not {}", found)))
+    }
+
+    /// Render a message from `event`, any serializable value exposed as a map
+    pub fn format<T: serde::Serialize>(&self, event: &T) -> Result<String, ScriptError> {
+        let event = rhai::serde::to_dynamic(event).map_err(|e| self.error(e.to_string()))?;
+        let mut scope = Scope::new();
+        scope.push("event", event);
+
+        let output = self.eval(&mut scope)?;
+        match output.into_immutable_string() {
+            Ok(message) => Ok(message.to_string()),
+            Err(found) => Err(self.error(format!("must return a string, not {}", found))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    fn limits() -> ScriptLimits {
+        ScriptLimits {
+            max_operations: 100_000,
+            timeout: Duration::from_millis(500),
+            max_string_size: 1024,
+            max_array_size: 100,
+        }
+    }
+
+    fn metric(mount_point: &str, usage_percentage: f64) -> DiskMetric {
+        DiskMetric {
+            timestamp: 1_700_000_000,
+            device: "/dev/sda1".to_string(),
+            mount_point: mount_point.to_string(),
+            total_space_bytes: 100,
+            used_space_bytes: 40,
+            available_space_bytes: 60,
+            usage_percentage,
+            labels: BTreeMap::new(),
+            aggregate: None,
+            severity: None,
+        }
+    }
+
+    #[test]
+    fn test_transform_filters_and_labels() {
+        let script = Script::compile(
+            "transform",
+            r#"
+                metrics
+                    .filter(|m| m.mount_point != "/boot")
+                    .map(|m| { m.labels.tier = "ssd"; m.usage_percentage *= 100.0; m })
+            "#,
+            &limits(),
+        )
+        .unwrap();
+
+        let output = script.transform(&[metric("/", 0.5), metric("/boot", 0.1)]).unwrap();
+        assert_eq!(output.len(), 1);
+        assert_eq!(output[0].usage_percentage, 50.0);
+        assert_eq!(output[0].labels.get("tier").map(String::as_str), Some("ssd"));
+        assert_eq!(output[0].timestamp, 1_700_000_000);
+    }
+
+    #[test]
+    fn test_condition_and_format() {
+        let condition =
+            Script::compile("condition", r#"subject == "/" && value > threshold * 2.0"#, &limits()).unwrap();
+        assert!(condition.condition("/", 0.9, 0.4).unwrap());
+        assert!(!condition.condition("/data", 0.9, 0.4).unwrap());
+
+        let format = Script::compile("message", r#"`${event.rule} on ${event.subject}`"#, &limits()).unwrap();
+        let event = serde_json::json!({ "rule": "disk-full", "subject": "/" });
+        assert_eq!(format.format(&event).unwrap(), "disk-full on /");
+    }
+
+    #[test]
+    fn test_limits_stop_runaway_scripts() {
+        let script = Script::compile("condition", "loop { }", &limits()).unwrap();
+        assert!(matches!(script.condition("/", 0.0, 0.0), Err(ScriptError::Eval { .. })));
+
+        let slow = ScriptLimits { max_operations: 0, timeout: Duration::from_millis(20), ..limits() };
+        let script = Script::compile("condition", "loop { }", &slow).unwrap();
+        let started = Instant::now();
+        assert!(script.condition("/", 0.0, 0.0).is_err());
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_compile_error() {
+        let result = Script::compile("message", "let x = ;", &limits());
+        assert!(matches!(result, Err(ScriptError::Compile { .. })));
+    }
+}
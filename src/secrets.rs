@@ -0,0 +1,490 @@
+//! Secret config values stored in AWS
+//!
+//! `api.api_key`, `api.oauth.client_secret`, `api.oauth.token_cache.encryption_key`,
+//! the `api_key` of each additional endpoint, `commands.signing_key` and
+//! `remote_config.signing_key` may be written as
+//! `aws-sm://<secret-id>` (Secrets Manager; `#field` picks a key from a JSON
+//! secret) or `aws-ssm://<parameter-name>` (SSM Parameter Store, decrypted).
+//! They are resolved once at startup with the instance role's credentials from
+//! IMDSv2, so images and config files never contain the secret itself.
+
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::time::Duration;
+use thiserror::Error;
+
+use crate::config::{Config, TlsConfig};
+
+type HmacSha256 = Hmac<Sha256>;
+
+const IMDS_ENDPOINT: &str = "http://169.254.169.254";
+
+#[derive(Error, Debug)]
+pub enum SecretError {
+    #[error("invalid secret reference {reference}: {message}")]
+    InvalidReference { reference: String, message: String },
+    #[error("failed to get instance role credentials: {0}")]
+    Credentials(String),
+    #[error("failed to resolve {reference}: {message}")]
+    Fetch { reference: String, message: String },
+}
+
+/// Where a secret config value lives
+#[derive(Debug, Clone, PartialEq)]
+pub enum SecretRef {
+    SecretsManager { secret_id: String, field: Option<String> },
+    Parameter { name: String },
+}
+
+impl SecretRef {
+    /// Parse a config value; `None` when it is a plain value rather than a reference
+    pub fn parse(value: &str) -> Result<Option<Self>, SecretError> {
+        let invalid = |message: &str| SecretError::InvalidReference {
+            reference: value.to_string(),
+            message: message.to_string(),
+        };
+
+        if let Some(rest) = value.strip_prefix("aws-sm://") {
+            let (secret_id, field) = match rest.split_once('#') {
+                Some((id, field)) if !field.is_empty() => (id, Some(field.to_string())),
+                Some(_) => return Err(invalid("empty field after '#'")),
+                None => (rest, None),
+            };
+            if secret_id.is_empty() {
+                return Err(invalid("missing secret name"));
+            }
+            return Ok(Some(Self::SecretsManager { secret_id: secret_id.to_string(), field }));
+        }
+
+        if let Some(name) = value.strip_prefix("aws-ssm://") {
+            if name.is_empty() {
+                return Err(invalid("missing parameter name"));
+            }
+            // Parameter paths are absolute; accept both aws-ssm:///a/b and aws-ssm://a/b
+            let name = if name.starts_with('/') || !name.contains('/') {
+                name.to_string()
+            } else {
+                format!("/{}", name)
+            };
+            return Ok(Some(Self::Parameter { name }));
+        }
+
+        Ok(None)
+    }
+}
+
+impl std::fmt::Display for SecretRef {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::SecretsManager { secret_id, field: Some(field) } => {
+                write!(f, "aws-sm://{}#{}", secret_id, field)
+            }
+            Self::SecretsManager { secret_id, field: None } => write!(f, "aws-sm://{}", secret_id),
+            Self::Parameter { name } => write!(f, "aws-ssm://{}", name),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Credentials {
+    access_key_id: String,
+    secret_access_key: String,
+    token: Option<String>,
+}
+
+/// Minimal signed client for the two AWS APIs used here
+pub struct AwsClient {
+    http: reqwest::Client,
+    credentials: Credentials,
+    region: String,
+    /// Replaces `https://<service>.<region>.amazonaws.com` (tests)
+    endpoint: Option<String>,
+}
+
+impl AwsClient {
+    /// Credentials and region of the instance role, from IMDSv2 at `imds`;
+    /// AWS requests use the `api.tls` settings
+    pub async fn from_instance_role(imds: &str, tls: Option<&TlsConfig>) -> Result<Self, SecretError> {
+        let http = crate::tls::client_builder(tls)
+            .map_err(|e| SecretError::Credentials(e.to_string()))?
+            .timeout(Duration::from_secs(5))
+            .build()
+            .map_err(|e| SecretError::Credentials(e.to_string()))?;
+
+        let token = http
+            .put(format!("{}/latest/api/token", imds))
+            .header("X-aws-ec2-metadata-token-ttl-seconds", "300")
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| SecretError::Credentials(format!("IMDS token: {}", e)))?
+            .text()
+            .await
+            .map_err(|e| SecretError::Credentials(e.to_string()))?;
+
+        let get = |path: &str| {
+            let request = http
+                .get(format!("{}/latest/meta-data/{}", imds, path))
+                .header("X-aws-ec2-metadata-token", &token);
+            let path = path.to_string();
+            async move {
+                request
+                    .send()
+                    .await
+                    .and_then(|response| response.error_for_status())
+                    .map_err(|e| SecretError::Credentials(format!("IMDS {}: {}", path, e)))?
+                    .text()
+                    .await
+                    .map_err(|e| SecretError::Credentials(e.to_string()))
+            }
+        };
+
+        let region = get("placement/region").await?;
+        let roles = get("iam/security-credentials/").await?;
+        let role = roles
+            .lines()
+            .next()
+            .filter(|role| !role.is_empty())
+            .ok_or_else(|| SecretError::Credentials("no instance role attached".to_string()))?
+            .to_string();
+        let credentials: Credentials =
+            serde_json::from_str(&get(&format!("iam/security-credentials/{}", role)).await?)
+                .map_err(|e| SecretError::Credentials(e.to_string()))?;
+
+        Ok(Self { http, credentials, region: region.trim().to_string(), endpoint: None })
+    }
+
+    /// Call a JSON 1.1 API action, e.g. `secretsmanager.GetSecretValue`
+    async fn call(
+        &self,
+        service: &str,
+        target: &str,
+        body: serde_json::Value,
+    ) -> Result<serde_json::Value, String> {
+        let host = format!("{}.{}.amazonaws.com", service, self.region);
+        let url = self.endpoint.clone().unwrap_or_else(|| format!("https://{}", host));
+        let payload = body.to_string();
+        let amz_date = Utc::now().format("%Y%m%dT%H%M%SZ").to_string();
+
+        let mut headers = vec![
+            ("content-type", "application/x-amz-json-1.1".to_string()),
+            ("host", host),
+            ("x-amz-date", amz_date.clone()),
+            ("x-amz-target", target.to_string()),
+        ];
+        if let Some(token) = &self.credentials.token {
+            headers.push(("x-amz-security-token", token.clone()));
+        }
+        let authorization = sign(
+            &self.credentials,
+            &self.region,
+            service,
+            &amz_date,
+            "POST",
+            "/",
+            "",
+            &headers,
+            payload.as_bytes(),
+        );
+
+        let mut request = self.http.post(format!("{}/", url)).body(payload);
+        for (name, value) in &headers {
+            // reqwest sets Host from the URL, which matches outside of tests
+            if *name != "host" {
+                request = request.header(*name, value);
+            }
+        }
+        let response = request
+            .header("authorization", authorization)
+            .send()
+            .await
+            .map_err(|e| e.to_string())?;
+
+        let status = response.status();
+        let body = response.text().await.map_err(|e| e.to_string())?;
+        if !status.is_success() {
+            return Err(format!("{}: {}", status, body));
+        }
+        serde_json::from_str(&body).map_err(|e| e.to_string())
+    }
+
+    pub async fn resolve(&self, reference: &SecretRef) -> Result<String, SecretError> {
+        let fetch_error = |message: String| SecretError::Fetch {
+            reference: reference.to_string(),
+            message,
+        };
+
+        match reference {
+            SecretRef::SecretsManager { secret_id, field } => {
+                let response = self
+                    .call(
+                        "secretsmanager",
+                        "secretsmanager.GetSecretValue",
+                        serde_json::json!({ "SecretId": secret_id }),
+                    )
+                    .await
+                    .map_err(fetch_error)?;
+                let secret = response["SecretString"]
+                    .as_str()
+                    .ok_or_else(|| fetch_error("secret has no SecretString".to_string()))?;
+                match field {
+                    None => Ok(secret.to_string()),
+                    Some(field) => serde_json::from_str::<serde_json::Value>(secret)
+                        .ok()
+                        .and_then(|json| json[field].as_str().map(str::to_string))
+                        .ok_or_else(|| fetch_error(format!("secret has no string field {}", field))),
+                }
+            }
+            SecretRef::Parameter { name } => {
+                let response = self
+                    .call(
+                        "ssm",
+                        "AmazonSSM.GetParameter",
+                        serde_json::json!({ "Name": name, "WithDecryption": true }),
+                    )
+                    .await
+                    .map_err(fetch_error)?;
+                response["Parameter"]["Value"]
+                    .as_str()
+                    .map(str::to_string)
+                    .ok_or_else(|| fetch_error("parameter has no value".to_string()))
+            }
+        }
+    }
+}
+
+/// AWS Signature Version 4 `Authorization` header for a request
+///
+/// `headers` must have lowercase names; all of them are signed.
+#[allow(clippy::too_many_arguments)]
+fn sign(
+    credentials: &Credentials,
+    region: &str,
+    service: &str,
+    amz_date: &str,
+    method: &str,
+    path: &str,
+    query: &str,
+    headers: &[(&str, String)],
+    payload: &[u8],
+) -> String {
+    let mut headers: Vec<(&str, &str)> =
+        headers.iter().map(|(name, value)| (*name, value.trim())).collect();
+    headers.sort();
+    let canonical_headers: String =
+        headers.iter().map(|(name, value)| format!("{}:{}\n", name, value)).collect();
+    let signed_headers = headers.iter().map(|(name, _)| *name).collect::<Vec<_>>().join(";");
+
+    let canonical_request = format!(
+        "{}\n{}\n{}\n{}\n{}\n{}",
+        method,
+        path,
+        query,
+        canonical_headers,
+        signed_headers,
+        hex::encode(Sha256::digest(payload))
+    );
+
+    let date = &amz_date[..8];
+    let scope = format!("{}/{}/{}/aws4_request", date, region, service);
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+        amz_date,
+        scope,
+        hex::encode(Sha256::digest(canonical_request.as_bytes()))
+    );
+
+    let hmac = |key: &[u8], data: &str| {
+        let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts any key length");
+        mac.update(data.as_bytes());
+        mac.finalize().into_bytes().to_vec()
+    };
+    let key = hmac(format!("AWS4{}", credentials.secret_access_key).as_bytes(), date);
+    let key = hmac(&key, region);
+    let key = hmac(&key, service);
+    let key = hmac(&key, "aws4_request");
+    let signature = hex::encode(hmac(&key, &string_to_sign));
+
+    format!(
+        "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+        credentials.access_key_id, scope, signed_headers, signature
+    )
+}
+
+/// Secret-capable settings of `config`, by config key
+fn secret_fields(config: &mut Config) -> Vec<(&'static str, &mut String)> {
+    let mut fields = Vec::new();
+    if let Some(api_key) = config.api.api_key.as_mut() {
+        fields.push(("api.api_key", api_key));
+    }
+    if let Some(oauth) = config.api.oauth.as_mut() {
+        if let Some(secret) = oauth.client_secret.as_mut() {
+            fields.push(("api.oauth.client_secret", secret));
+        }
+        if let Some(key) = oauth.token_cache.as_mut().and_then(|cache| cache.encryption_key.as_mut()) {
+            fields.push(("api.oauth.token_cache.encryption_key", key));
+        }
+    }
+    for additional in config.api.additional_endpoints.iter_mut().flatten() {
+        fields.push(("api.additional_endpoints.api_key", &mut additional.api_key));
+    }
+    if let Some(commands) = config.commands.as_mut() {
+        fields.push(("commands.signing_key", &mut commands.signing_key));
+    }
+    if let Some(key) = config.remote_config.as_mut().and_then(|r| r.signing_key.as_mut()) {
+        fields.push(("remote_config.signing_key", key));
+    }
+    fields
+}
+
+/// Replace every secret reference in `config` with its value
+pub async fn resolve(config: &mut Config) -> Result<(), SecretError> {
+    resolve_with(config, IMDS_ENDPOINT, None).await
+}
+
+async fn resolve_with(
+    config: &mut Config,
+    imds: &str,
+    endpoint: Option<&str>,
+) -> Result<(), SecretError> {
+    let tls = config.api.tls.clone();
+    let mut client: Option<AwsClient> = None;
+    for (key, value) in secret_fields(config) {
+        let Some(reference) = SecretRef::parse(value)? else {
+            continue;
+        };
+
+        if client.is_none() {
+            let mut aws = AwsClient::from_instance_role(imds, tls.as_ref()).await?;
+            aws.endpoint = endpoint.map(str::to_string);
+            client = Some(aws);
+        }
+        let aws = client.as_ref().expect("client created above");
+        *value = aws.resolve(&reference).await?;
+        tracing::info!(key, "Resolved secret from AWS");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_references() {
+        assert_eq!(SecretRef::parse("plain-key").unwrap(), None);
+        assert_eq!(
+            SecretRef::parse("aws-sm://prod/sentinel#api_key").unwrap(),
+            Some(SecretRef::SecretsManager {
+                secret_id: "prod/sentinel".to_string(),
+                field: Some("api_key".to_string())
+            })
+        );
+        assert_eq!(
+            SecretRef::parse("aws-ssm://sentinel/api-key").unwrap(),
+            Some(SecretRef::Parameter { name: "/sentinel/api-key".to_string() })
+        );
+        assert_eq!(
+            SecretRef::parse("aws-ssm:///sentinel/api-key").unwrap(),
+            Some(SecretRef::Parameter { name: "/sentinel/api-key".to_string() })
+        );
+        assert!(SecretRef::parse("aws-sm://").is_err());
+        assert!(SecretRef::parse("aws-sm://name#").is_err());
+    }
+
+    #[test]
+    fn test_sign_matches_aws_example() {
+        // Example request from the AWS Signature Version 4 documentation
+        let credentials = Credentials {
+            access_key_id: "AKIDEXAMPLE".to_string(),
+            secret_access_key: "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY".to_string(),
+            token: None,
+        };
+        let headers = [
+            ("content-type", "application/x-www-form-urlencoded; charset=utf-8".to_string()),
+            ("host", "iam.amazonaws.com".to_string()),
+            ("x-amz-date", "20150830T123600Z".to_string()),
+        ];
+        let authorization = sign(
+            &credentials,
+            "us-east-1",
+            "iam",
+            "20150830T123600Z",
+            "GET",
+            "/",
+            "Action=ListUsers&Version=2010-05-08",
+            &headers,
+            b"",
+        );
+        assert_eq!(
+            authorization,
+            "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20150830/us-east-1/iam/aws4_request, \
+             SignedHeaders=content-type;host;x-amz-date, \
+             Signature=5d672d79c15b13162d9279b0855cfba6789a8edb4c82c400e06b5924a6f2b5d7"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_resolve_from_instance_role() {
+        use wiremock::matchers::{header, header_exists, method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        let respond = |status: u16, body: &str| ResponseTemplate::new(status).set_body_string(body);
+        Mock::given(method("PUT"))
+            .and(path("/latest/api/token"))
+            .respond_with(respond(200, "imds-token"))
+            .mount(&server)
+            .await;
+        for (route, body) in [
+            ("/latest/meta-data/placement/region", "eu-west-1"),
+            ("/latest/meta-data/iam/security-credentials/", "sentinel-role"),
+            (
+                "/latest/meta-data/iam/security-credentials/sentinel-role",
+                r#"{"AccessKeyId": "AKID", "SecretAccessKey": "secret", "Token": "session"}"#,
+            ),
+        ] {
+            Mock::given(method("GET"))
+                .and(path(route))
+                .and(header("X-aws-ec2-metadata-token", "imds-token"))
+                .respond_with(respond(200, body))
+                .mount(&server)
+                .await;
+        }
+        Mock::given(method("POST"))
+            .and(header("x-amz-target", "secretsmanager.GetSecretValue"))
+            .and(header("x-amz-security-token", "session"))
+            .and(header_exists("authorization"))
+            .respond_with(respond(200, r#"{"SecretString": "{\"api_key\": \"from-sm\"}"}"#))
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(header("x-amz-target", "AmazonSSM.GetParameter"))
+            .respond_with(respond(200, r#"{"Parameter": {"Value": "from-ssm"}}"#))
+            .mount(&server)
+            .await;
+
+        let mut config = Config::load_from_str(
+            r#"
+agent: {}
+api:
+  endpoint: "https://api.example.com"
+  api_key: "aws-sm://prod/sentinel#api_key"
+collection:
+  interval_seconds: 60
+  disk:
+    enabled: true
+commands:
+  enabled: true
+  signing_key: "aws-ssm:///sentinel/signing-key"
+"#,
+        )
+        .unwrap();
+        resolve_with(&mut config, &server.uri(), Some(&server.uri())).await.unwrap();
+        assert_eq!(config.api.api_key.as_deref(), Some("from-sm"));
+        assert_eq!(config.commands.as_ref().unwrap().signing_key, "from-ssm");
+    }
+}
@@ -0,0 +1,524 @@
+//! Additional endpoints reported to alongside `api.endpoint`
+//!
+//! Each one registers a resource of its own, saved in the state file under the
+//! endpoint's name, and receives a copy of every batch the primary endpoint
+//! accepts. Copies wait in a bounded queue per endpoint and are sent by that
+//! endpoint's own worker, so a slow or failing endpoint never holds up the
+//! primary one or the others. Transient failures are retried with backoff; a
+//! full queue drops its oldest copy. Copies are never spooled. Heartbeats,
+//! commands and remote config stay with the primary.
+
+use chrono::Utc;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, Duration};
+use tracing::{debug, info, warn};
+
+use crate::client::{ApiClient, ApiError, ResourceRegistration};
+use crate::config::Config;
+use crate::fingerprint::MachineFingerprint;
+use crate::metadata::SessionInfo;
+use crate::metrics::MetricBatch;
+use crate::state::ResourceState;
+use crate::status::SinkStatus;
+
+/// Longest backoff between retries of a copy, unless the endpoint asks for more
+const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(60);
+
+/// One of `api.additional_endpoints`
+pub struct Sink {
+    name: String,
+    api_client: ApiClient,
+    /// Tenant sent to this endpoint
+    org_id: Option<String>,
+    project: Option<String>,
+    state: Option<ResourceState>,
+    queue: Arc<SinkQueue>,
+    retry: RetryPolicy,
+    /// Sends the queued copies; started with the first one
+    worker: Option<JoinHandle<()>>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct RetryPolicy {
+    max_retries: u32,
+    backoff: Duration,
+}
+
+impl RetryPolicy {
+    /// Delay before retry `attempt`, counting from 1; a `Retry-After` from the endpoint wins
+    fn delay(&self, attempt: u32, error: &ApiError) -> Duration {
+        if let ApiError::RateLimited { retry_after: Some(retry_after), .. } = error {
+            return *retry_after;
+        }
+        self.backoff
+            .saturating_mul(1 << (attempt - 1).min(16))
+            .min(MAX_RETRY_BACKOFF)
+    }
+}
+
+/// Copies waiting for one endpoint, shared with its worker
+struct SinkQueue {
+    batches: Mutex<VecDeque<MetricBatch>>,
+    capacity: usize,
+    ready: Notify,
+    delivered: AtomicU64,
+    retried: AtomicU64,
+    dropped: AtomicU64,
+    failed: AtomicU64,
+}
+
+impl SinkQueue {
+    fn new(capacity: usize) -> Self {
+        Self {
+            batches: Mutex::new(VecDeque::with_capacity(capacity)),
+            capacity,
+            ready: Notify::new(),
+            delivered: AtomicU64::new(0),
+            retried: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+            failed: AtomicU64::new(0),
+        }
+    }
+
+    /// Add a copy, dropping the oldest when full; returns whether one was dropped
+    fn push(&self, batch: MetricBatch) -> bool {
+        let mut batches = self.batches.lock().unwrap_or_else(PoisonError::into_inner);
+        let full = batches.len() >= self.capacity;
+        if full {
+            batches.pop_front();
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+        batches.push_back(batch);
+        drop(batches);
+        self.ready.notify_one();
+        full
+    }
+
+    fn pop(&self) -> Option<MetricBatch> {
+        self.batches.lock().unwrap_or_else(PoisonError::into_inner).pop_front()
+    }
+
+    fn status(&self) -> SinkStatus {
+        SinkStatus {
+            queued: self.batches.lock().unwrap_or_else(PoisonError::into_inner).len(),
+            delivered: self.delivered.load(Ordering::Relaxed),
+            retried: self.retried.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+            failed: self.failed.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl Sink {
+    /// A sink per additional endpoint, signing requests like `primary`
+    pub fn from_config(config: &Config, primary: &ApiClient) -> Result<Vec<Self>, ApiError> {
+        config
+            .get_additional_endpoints()
+            .iter()
+            .map(|additional| {
+                let config = config.for_additional_endpoint(additional);
+                Ok(Self {
+                    name: additional.name.clone(),
+                    api_client: ApiClient::new(&config)?.with_identity_of(primary),
+                    org_id: config.get_org_id(),
+                    project: config.get_project(),
+                    state: None,
+                    queue: Arc::new(SinkQueue::new(additional.get_queue_size())),
+                    retry: RetryPolicy {
+                        max_retries: additional.get_max_retries(),
+                        backoff: Duration::from_secs(additional.get_retry_backoff_seconds()),
+                    },
+                    worker: None,
+                })
+            })
+            .collect()
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn status(&self) -> SinkStatus {
+        self.queue.status()
+    }
+
+    /// Sign with the same key as `primary` from now on
+    pub fn use_identity_of(&mut self, primary: &ApiClient) {
+        self.api_client = self.api_client.clone().with_identity_of(primary);
+    }
+
+    pub fn resource_id(&self) -> Option<&str> {
+        self.state.as_ref().map(|state| state.resource_id.as_str())
+    }
+
+    /// `registration` as sent to this endpoint
+    fn registration(&self, registration: &ResourceRegistration) -> ResourceRegistration {
+        ResourceRegistration {
+            org_id: self.org_id.clone(),
+            project: self.project.clone(),
+            ..registration.clone()
+        }
+    }
+
+    /// Reuse the saved registration with this endpoint or register a new resource
+    ///
+    /// A saved registration for another machine or instance is replaced, as
+    /// for the primary endpoint. Failures are logged and retried by `maintain`.
+    pub async fn register(
+        &mut self,
+        registration: &ResourceRegistration,
+        fingerprint: Option<&MachineFingerprint>,
+        session: &SessionInfo,
+    ) {
+        match ResourceState::load_named(&self.name) {
+            Ok(Some(state)) => match state.mismatch(
+                fingerprint,
+                &registration.instance_metadata,
+                registration.public_key.as_deref(),
+            ) {
+                Some(reason) => warn!(
+                    endpoint = %self.name,
+                    resource_id = %state.resource_id,
+                    reason = %reason,
+                    "Saved registration is for another machine, registering a new resource"
+                ),
+                None => {
+                    info!(endpoint = %self.name, resource_id = %state.resource_id, "Found existing resource registration");
+                    self.state = Some(state);
+                    return;
+                }
+            },
+            Ok(None) => {}
+            // Registering anew would orphan the saved resource; `maintain` tries again
+            Err(e) => {
+                warn!(endpoint = %self.name, error = %e, "Error loading resource state, not registering");
+                return;
+            }
+        }
+
+        let registration = self.registration(registration);
+        match self.api_client.register_resource(&registration).await {
+            Ok(response) => {
+                info!(endpoint = %self.name, resource_id = %response.resource_id, "Resource registered successfully");
+                let mut state = ResourceState::new(
+                    response.resource_id,
+                    env!("CARGO_PKG_VERSION").to_string(),
+                    registration.instance_metadata,
+                    session.clone(),
+                );
+                state.fingerprint = fingerprint.cloned();
+                state.expires_at = response.expires_at;
+                state.public_key = registration.public_key;
+                if let Err(e) = state.save_named(&self.name) {
+                    warn!(endpoint = %self.name, error = %e, "Failed to save resource state");
+                }
+                self.state = Some(state);
+            }
+            Err(e) => {
+                warn!(endpoint = %self.name, error = %e, "Resource registration failed, will retry");
+            }
+        }
+    }
+
+    /// Register if that failed before, renew when due, and register again if the platform dropped the resource
+    pub async fn maintain(
+        &mut self,
+        registration: &ResourceRegistration,
+        fingerprint: Option<&MachineFingerprint>,
+        session: &SessionInfo,
+    ) {
+        let Some(state) = self.state.as_ref() else {
+            self.register(registration, fingerprint, session).await;
+            return;
+        };
+        if !state.renewal_due(Utc::now()) {
+            return;
+        }
+
+        let resource_id = state.resource_id.clone();
+        let renewal = ResourceRegistration {
+            instance_metadata: state.instance_metadata.clone(),
+            ..self.registration(registration)
+        };
+        match self.api_client.renew_registration(&resource_id, &renewal).await {
+            Ok(Some(response)) => {
+                let Some(state) = self.state.as_mut() else {
+                    return;
+                };
+                state.renewed(response.expires_at);
+                info!(endpoint = %self.name, resource_id = %resource_id, "Registration renewed");
+                if let Err(e) = state.save_named(&self.name) {
+                    warn!(endpoint = %self.name, error = %e, "Failed to save renewed registration");
+                }
+            }
+            Ok(None) => {
+                warn!(endpoint = %self.name, resource_id = %resource_id, "Registration expired on the platform, registering again");
+                self.state = None;
+                if let Err(e) = ResourceState::forget(&self.name) {
+                    warn!(endpoint = %self.name, error = %e, "Failed to remove resource state");
+                }
+                self.register(registration, fingerprint, session).await;
+            }
+            Err(e) => {
+                warn!(endpoint = %self.name, resource_id = %resource_id, error = %e, "Failed to renew registration, will retry");
+            }
+        }
+    }
+
+    /// Queue a copy of a batch the primary endpoint accepted; returns whether this endpoint takes copies yet
+    pub fn enqueue(&mut self, batch: &MetricBatch) -> bool {
+        let Some(resource_id) = self.resource_id() else {
+            return false;
+        };
+        let copy = MetricBatch {
+            resource_id: resource_id.to_string(),
+            org_id: self.org_id.clone(),
+            project: self.project.clone(),
+            ..batch.clone()
+        };
+        if self.queue.push(copy) {
+            warn!(endpoint = %self.name, "Additional endpoint is falling behind, dropped its oldest queued batch");
+        }
+
+        if self.worker.as_ref().is_none_or(JoinHandle::is_finished) {
+            self.worker = Some(tokio::spawn(deliver(
+                self.name.clone(),
+                self.api_client.clone(),
+                self.queue.clone(),
+                self.retry,
+            )));
+        }
+        true
+    }
+
+    /// Decommission this endpoint's resource and forget it
+    pub async fn deregister(&mut self) {
+        let Some(resource_id) = self.resource_id().map(str::to_string) else {
+            return;
+        };
+        // Copies still queued are for the resource going away
+        if let Some(worker) = self.worker.take() {
+            worker.abort();
+        }
+        self.queue.batches.lock().unwrap_or_else(PoisonError::into_inner).clear();
+        match self.api_client.deregister_resource(&resource_id).await {
+            Ok(()) => {
+                info!(endpoint = %self.name, resource_id = %resource_id, "Resource deregistered");
+                self.state = None;
+                if let Err(e) = ResourceState::forget(&self.name) {
+                    warn!(endpoint = %self.name, error = %e, "Failed to remove resource state");
+                }
+            }
+            Err(e) => {
+                warn!(endpoint = %self.name, resource_id = %resource_id, error = %e, "Failed to deregister resource");
+            }
+        }
+    }
+}
+
+impl Drop for Sink {
+    fn drop(&mut self) {
+        if let Some(worker) = self.worker.take() {
+            worker.abort();
+        }
+    }
+}
+
+/// Send queued copies in order, retrying transient failures, until the sink goes away
+async fn deliver(name: String, api_client: ApiClient, queue: Arc<SinkQueue>, retry: RetryPolicy) {
+    loop {
+        let Some(batch) = queue.pop() else {
+            queue.ready.notified().await;
+            continue;
+        };
+
+        let mut attempt = 0;
+        loop {
+            match api_client.send_metrics(&batch).await {
+                Ok(_) => {
+                    queue.delivered.fetch_add(1, Ordering::Relaxed);
+                    break;
+                }
+                Err(e) if e.is_transient() && attempt < retry.max_retries => {
+                    attempt += 1;
+                    let delay = retry.delay(attempt, &e);
+                    debug!(endpoint = %name, attempt, delay_ms = delay.as_millis() as u64, error = %e, "Copy to additional endpoint failed, retrying");
+                    queue.retried.fetch_add(1, Ordering::Relaxed);
+                    sleep(delay).await;
+                }
+                Err(e) => {
+                    warn!(endpoint = %name, count = batch.metrics.len(), error = %e, "Failed to copy metrics to additional endpoint");
+                    queue.failed.fetch_add(1, Ordering::Relaxed);
+                    break;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metadata::InstanceMetadata;
+    use crate::metrics::MetricService;
+    use wiremock::matchers::{body_partial_json, header, method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    fn registered(sink: &mut Sink, resource_id: &str) {
+        sink.state = Some(ResourceState::new(
+            resource_id.to_string(),
+            "0.3.2".to_string(),
+            InstanceMetadata::default(),
+            SessionInfo::generate(),
+        ));
+    }
+
+    async fn wait_for(sink: &Sink, done: impl Fn(&SinkStatus) -> bool) -> SinkStatus {
+        for _ in 0..100 {
+            let status = sink.status();
+            if done(&status) {
+                return status;
+            }
+            sleep(Duration::from_millis(20)).await;
+        }
+        panic!("sink did not get there: {:?}", sink.status());
+    }
+
+    #[tokio::test]
+    async fn test_send_copies_batch_under_own_resource() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/api/v1/metrics"))
+            .and(header("Authorization", "Bearer customer-key"))
+            .and(body_partial_json(serde_json::json!({
+                "resource_id": "res_customer",
+                "org_id": "org_customer",
+                "project": "primary-project"
+            })))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let config = Config::load_from_str(&format!(
+            r#"
+agent:
+  org_id: "org_msp"
+  project: "primary-project"
+api:
+  endpoint: "https://api.example.com"
+  api_key: "msp-key"
+  additional_endpoints:
+    - name: customer
+      endpoint: "{}"
+      api_key: "customer-key"
+      org_id: "org_customer"
+collection:
+  interval_seconds: 60
+  disk:
+    enabled: true
+"#,
+            mock_server.uri()
+        ))
+        .unwrap();
+        let primary = ApiClient::new(&config).unwrap();
+        let mut sinks = Sink::from_config(&config, &primary).unwrap();
+        assert_eq!(sinks.len(), 1);
+        let sink = &mut sinks[0];
+
+        let batch = MetricService::new(&config).create_batch(
+            Vec::new(),
+            "res_primary",
+            "test-host",
+            SessionInfo::generate(),
+        );
+        // Not registered with this endpoint yet: nothing is sent
+        assert!(!sink.enqueue(&batch));
+
+        registered(sink, "res_customer");
+        assert!(sink.enqueue(&batch));
+        wait_for(sink, |status| status.delivered == 1).await;
+    }
+
+    #[tokio::test]
+    async fn test_each_endpoint_retries_and_drops_on_its_own() {
+        let failing = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/api/v1/metrics"))
+            .respond_with(ResponseTemplate::new(503))
+            .up_to_n_times(1)
+            .mount(&failing)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/api/v1/metrics"))
+            .respond_with(ResponseTemplate::new(400))
+            .mount(&failing)
+            .await;
+        let slow = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/api/v1/metrics"))
+            .respond_with(ResponseTemplate::new(200).set_delay(Duration::from_secs(2)))
+            .mount(&slow)
+            .await;
+
+        let config = Config::load_from_str(&format!(
+            r#"
+agent:
+  org_id: "org_msp"
+api:
+  endpoint: "https://api.example.com"
+  api_key: "msp-key"
+  additional_endpoints:
+    - name: failing
+      endpoint: "{}"
+      api_key: "key-a"
+      retry_backoff_seconds: 0
+    - name: slow
+      endpoint: "{}"
+      api_key: "key-b"
+      queue_size: 2
+collection:
+  interval_seconds: 60
+  disk:
+    enabled: true
+"#,
+            failing.uri(),
+            slow.uri()
+        ))
+        .unwrap();
+        let primary = ApiClient::new(&config).unwrap();
+        let mut sinks = Sink::from_config(&config, &primary).unwrap();
+        registered(&mut sinks[0], "res_a");
+        registered(&mut sinks[1], "res_b");
+        let batch = MetricService::new(&config).create_batch(
+            Vec::new(),
+            "res_primary",
+            "test-host",
+            SessionInfo::generate(),
+        );
+
+        // Queueing never waits on the endpoints
+        let started = std::time::Instant::now();
+        for _ in 0..4 {
+            for sink in &mut sinks {
+                sink.enqueue(&batch);
+            }
+        }
+        assert!(started.elapsed() < Duration::from_millis(500));
+
+        // A 503 is retried, the 400 that follows is not
+        let status = wait_for(&sinks[0], |status| status.failed + status.delivered == 4).await;
+        assert_eq!(status.retried, 1);
+        assert_eq!(status.failed, 4);
+
+        // The slow endpoint kept the newest two copies and is still sending the first of them
+        let status = sinks[1].status();
+        assert_eq!(status.dropped, 2);
+        assert_eq!(status.queued, 1);
+        assert_eq!(status.delivered, 0);
+    }
+}
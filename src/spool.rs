@@ -0,0 +1,353 @@
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::fs;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use tracing::warn;
+
+use crate::buffer::MetricQueue;
+use crate::metrics::DiskMetric;
+
+/// On-disk spool of metric batches that could not be delivered
+///
+/// Each batch is stored as its own JSON Lines segment so a torn write or a
+/// corrupt line only loses the affected metrics. Segments are named so that
+/// lexical order matches write order; the oldest are evicted once the spool
+/// exceeds its size cap. Compressed segments are gzipped (`.jsonl.gz`); both
+/// kinds are read back regardless of the current setting.
+pub struct Spool {
+    directory: PathBuf,
+    max_size_bytes: u64,
+    next_sequence: u64,
+    compress: bool,
+    dropped: u64,
+}
+
+impl Spool {
+    /// Open (and create if needed) a spool directory
+    pub fn open<P: AsRef<Path>>(directory: P, max_size_bytes: u64) -> Result<Self, SpoolError> {
+        let directory = directory.as_ref().to_path_buf();
+        fs::create_dir_all(&directory).map_err(|e| SpoolError::Io {
+            path: directory.to_string_lossy().to_string(),
+            error: e.to_string(),
+        })?;
+
+        let mut spool = Self {
+            directory,
+            max_size_bytes,
+            next_sequence: 0,
+            compress: false,
+            dropped: 0,
+        };
+
+        // Continue numbering after any segments left by a previous run
+        spool.next_sequence = spool
+            .segments()
+            .last()
+            .and_then(|path| Self::sequence_of(path))
+            .map(|sequence| sequence + 1)
+            .unwrap_or(0);
+
+        Ok(spool)
+    }
+
+    /// Number new segments above `sequence`, e.g. the last one known to be delivered
+    pub fn continue_after(&mut self, sequence: u64) {
+        self.next_sequence = self.next_sequence.max(sequence + 1);
+    }
+
+    /// Gzip segments written from now on
+    pub fn with_compression(mut self, compress: bool) -> Self {
+        self.compress = compress;
+        self
+    }
+
+    /// Write metrics to a new segment, evicting the oldest segments if over the cap
+    pub fn append(&mut self, metrics: &[DiskMetric]) -> Result<(), SpoolError> {
+        if metrics.is_empty() {
+            return Ok(());
+        }
+
+        let mut contents = String::new();
+        for metric in metrics {
+            let line = serde_json::to_string(metric)
+                .map_err(|e| SpoolError::Serialize(e.to_string()))?;
+            contents.push_str(&line);
+            contents.push('\n');
+        }
+
+        let extension = if self.compress { "jsonl.gz" } else { "jsonl" };
+        let path = self
+            .directory
+            .join(format!("{:020}.{}", self.next_sequence, extension));
+        let temp_path = self.directory.join(format!("{:020}.tmp", self.next_sequence));
+        self.next_sequence += 1;
+
+        // Write to a temporary file first so readers never see a partial segment
+        let compress = self.compress;
+        let write = || -> std::io::Result<()> {
+            let mut file = fs::File::create(&temp_path)?;
+            if compress {
+                let mut encoder = GzEncoder::new(&mut file, Compression::default());
+                encoder.write_all(contents.as_bytes())?;
+                encoder.finish()?;
+            } else {
+                file.write_all(contents.as_bytes())?;
+            }
+            file.sync_all()?;
+            fs::rename(&temp_path, &path)
+        };
+        write().map_err(|e| SpoolError::Io {
+            path: path.to_string_lossy().to_string(),
+            error: e.to_string(),
+        })?;
+
+        self.enforce_size_cap();
+        Ok(())
+    }
+
+    /// Read the oldest segment, skipping unparseable lines
+    ///
+    /// Segments that cannot be read at all are discarded.
+    pub fn oldest(&self) -> Option<(PathBuf, Vec<DiskMetric>)> {
+        for path in self.segments() {
+            let contents = match Self::read_segment(&path) {
+                Ok(contents) => contents,
+                Err(e) => {
+                    warn!(path = %path.display(), error = %e, "Discarding unreadable spool segment");
+                    let _ = fs::remove_file(&path);
+                    continue;
+                }
+            };
+
+            let metrics: Vec<DiskMetric> = contents
+                .lines()
+                .filter_map(|line| serde_json::from_str(line).ok())
+                .collect();
+
+            if metrics.is_empty() {
+                let _ = fs::remove_file(&path);
+                continue;
+            }
+
+            return Some((path, metrics));
+        }
+
+        None
+    }
+
+    fn read_segment(path: &Path) -> std::io::Result<String> {
+        if !Self::is_compressed(path) {
+            return fs::read_to_string(path);
+        }
+        let mut contents = String::new();
+        GzDecoder::new(fs::File::open(path)?).read_to_string(&mut contents)?;
+        Ok(contents)
+    }
+
+    fn is_compressed(path: &Path) -> bool {
+        path.extension().is_some_and(|ext| ext == "gz")
+    }
+
+    /// Remove a segment once its metrics have been delivered
+    pub fn remove(&self, path: &Path) -> Result<(), SpoolError> {
+        fs::remove_file(path).map_err(|e| SpoolError::Io {
+            path: path.to_string_lossy().to_string(),
+            error: e.to_string(),
+        })
+    }
+
+    /// Number of segments waiting to be replayed
+    pub fn len(&self) -> usize {
+        self.segments().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn size_bytes(&self) -> u64 {
+        self.segments()
+            .iter()
+            .filter_map(|path| fs::metadata(path).ok())
+            .map(|metadata| metadata.len())
+            .sum()
+    }
+
+    fn segments(&self) -> Vec<PathBuf> {
+        let mut segments: Vec<PathBuf> = fs::read_dir(&self.directory)
+            .map(|entries| {
+                entries
+                    .filter_map(|entry| entry.ok())
+                    .map(|entry| entry.path())
+                    .filter(|path| {
+                        path.extension().is_some_and(|ext| ext == "jsonl") || Self::is_compressed(path)
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        segments.sort();
+        segments
+    }
+
+    /// Sequence number of a segment, from its file name
+    pub fn sequence_of(path: &Path) -> Option<u64> {
+        path.file_name()?.to_str()?.split('.').next()?.parse().ok()
+    }
+
+    fn enforce_size_cap(&mut self) {
+        let mut segments = self.segments();
+        let mut total = self.size_bytes();
+
+        while total > self.max_size_bytes && segments.len() > 1 {
+            let oldest = segments.remove(0);
+            let size = fs::metadata(&oldest).map(|m| m.len()).unwrap_or(0);
+            // Eviction is rare, so the segment is read back to count what is lost
+            let count = Self::read_segment(&oldest).map(|c| c.lines().count()).unwrap_or(0);
+            if fs::remove_file(&oldest).is_ok() {
+                self.dropped += count as u64;
+                warn!(path = %oldest.display(), count, "Spool over size cap, dropped oldest segment");
+            }
+            total = total.saturating_sub(size);
+        }
+    }
+}
+
+impl MetricQueue for Spool {
+    type Error = SpoolError;
+
+    /// Write the metrics as a new segment; over the cap, whole segments are discarded
+    fn push(&mut self, metrics: Vec<DiskMetric>) -> Result<Vec<DiskMetric>, SpoolError> {
+        self.append(&metrics)?;
+        Ok(Vec::new())
+    }
+
+    fn size_bytes(&self) -> u64 {
+        Spool::size_bytes(self)
+    }
+
+    fn dropped(&self) -> u64 {
+        self.dropped
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum SpoolError {
+    #[error("Spool I/O error at {path}: {error}")]
+    Io { path: String, error: String },
+    #[error("Failed to serialize spooled metric: {0}")]
+    Serialize(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+    use tempfile::tempdir;
+
+    fn create_metric(mount_point: &str) -> DiskMetric {
+        DiskMetric {
+            timestamp: 1234567890,
+            device: "/dev/sda1".to_string(),
+            mount_point: mount_point.to_string(),
+            total_space_bytes: 1000000,
+            used_space_bytes: 500000,
+            available_space_bytes: 500000,
+            usage_percentage: 0.5,
+            labels: BTreeMap::new(),
+            aggregate: None,
+            severity: None,
+        }
+    }
+
+    #[test]
+    fn test_append_and_replay_in_order() {
+        let dir = tempdir().unwrap();
+        let mut spool = Spool::open(dir.path(), 1024 * 1024).unwrap();
+
+        spool.append(&[create_metric("/")]).unwrap();
+        spool.append(&[create_metric("/home"), create_metric("/var")]).unwrap();
+        assert_eq!(spool.len(), 2);
+
+        let (path, metrics) = spool.oldest().unwrap();
+        assert_eq!(metrics.len(), 1);
+        assert_eq!(metrics[0].mount_point, "/");
+        spool.remove(&path).unwrap();
+
+        let (_, metrics) = spool.oldest().unwrap();
+        assert_eq!(metrics.len(), 2);
+    }
+
+    #[test]
+    fn test_sequence_survives_reopen() {
+        let dir = tempdir().unwrap();
+        let mut spool = Spool::open(dir.path(), 1024 * 1024).unwrap();
+        spool.append(&[create_metric("/")]).unwrap();
+
+        let mut reopened = Spool::open(dir.path(), 1024 * 1024).unwrap();
+        reopened.append(&[create_metric("/home")]).unwrap();
+
+        let (_, metrics) = reopened.oldest().unwrap();
+        assert_eq!(metrics[0].mount_point, "/");
+    }
+
+    #[test]
+    fn test_corrupt_lines_skipped() {
+        let dir = tempdir().unwrap();
+        let spool = Spool::open(dir.path(), 1024 * 1024).unwrap();
+
+        let valid = serde_json::to_string(&create_metric("/")).unwrap();
+        fs::write(
+            dir.path().join(format!("{:020}.jsonl", 0)),
+            format!("{}\n{{\"timestamp\": 12", valid),
+        )
+        .unwrap();
+        fs::write(dir.path().join(format!("{:020}.jsonl", 1)), "garbage\n").unwrap();
+
+        let (path, metrics) = spool.oldest().unwrap();
+        assert_eq!(metrics.len(), 1);
+        spool.remove(&path).unwrap();
+
+        // Fully corrupt segment is discarded
+        assert!(spool.oldest().is_none());
+        assert_eq!(spool.len(), 0);
+    }
+
+    #[test]
+    fn test_compressed_segments_replay_with_plain_ones() {
+        let dir = tempdir().unwrap();
+        let mut spool = Spool::open(dir.path(), 1024 * 1024).unwrap();
+        spool.append(&[create_metric("/")]).unwrap();
+
+        let mut spool = Spool::open(dir.path(), 1024 * 1024).unwrap().with_compression(true);
+        spool.append(&[create_metric("/home")]).unwrap();
+        assert!(dir.path().join(format!("{:020}.jsonl.gz", 1)).exists());
+
+        let (path, metrics) = spool.oldest().unwrap();
+        assert_eq!(metrics[0].mount_point, "/");
+        spool.remove(&path).unwrap();
+
+        let (_, metrics) = spool.oldest().unwrap();
+        assert_eq!(metrics[0].mount_point, "/home");
+        assert_eq!(metrics[0].timestamp, 1234567890);
+
+        let reopened = Spool::open(dir.path(), 1024 * 1024).unwrap();
+        assert_eq!(reopened.next_sequence, 2);
+    }
+
+    #[test]
+    fn test_size_cap_evicts_oldest() {
+        let dir = tempdir().unwrap();
+        let segment_size = serde_json::to_string(&create_metric("/")).unwrap().len() as u64 + 1;
+        let mut spool = Spool::open(dir.path(), segment_size * 2).unwrap();
+
+        spool.append(&[create_metric("/")]).unwrap();
+        spool.append(&[create_metric("/")]).unwrap();
+        spool.append(&[create_metric("/")]).unwrap();
+
+        assert_eq!(spool.len(), 2);
+        assert!(spool.size_bytes() <= segment_size * 2);
+        assert_eq!(MetricQueue::dropped(&spool), 1);
+    }
+}
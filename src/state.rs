@@ -1,149 +1,111 @@
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::fs;
 use std::io::Write;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use tracing::warn;
 // Time utilities provided by chrono
 use chrono::{DateTime, Utc};
+use crate::encryption::{self, ConfigKey};
+use crate::fingerprint::MachineFingerprint;
 use crate::metadata::{InstanceMetadata, SessionInfo};
-
-/// Represents the persisted state of a registered resource
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct ResourceState {
-    /// The resource ID assigned by the Operion platform
-    pub resource_id: String,
-    /// ISO 8601 timestamp of when the resource was registered
-    pub registered_at: String,
-    /// The agent version that performed the registration
-    pub agent_version: String,
-    /// Cloud instance metadata from registration
-    pub instance_metadata: InstanceMetadata,
-    /// Session info from when the agent started
-    pub session: SessionInfo,
+use crate::paths;
+
+/// Name of the registration with `api.endpoint`; each of `api.additional_endpoints`
+/// is saved under its own name
+pub const PRIMARY_REGISTRATION: &str = "default";
+
+/// Environment variable holding the base64 key that encrypts the state file
+pub const STATE_KEY_ENV: &str = "SENTINEL_STATE_KEY";
+/// Environment variable naming a file that holds the state key
+pub const STATE_KEY_FILE_ENV: &str = "SENTINEL_STATE_KEY_FILE";
+
+/// Key for the state file, if one is configured
+///
+/// With a key, the state is saved encrypted; without one it is plain JSON as
+/// in earlier releases. Plain files are read either way and encrypted on the
+/// next save, so turning encryption on needs no migration.
+pub(crate) fn state_key() -> Result<Option<ConfigKey>, StateError> {
+    ConfigKey::from_env_vars(STATE_KEY_ENV, STATE_KEY_FILE_ENV).map_err(|e| StateError::Encryption {
+        path: STATE_KEY_ENV.to_string(),
+        error: e.to_string(),
+    })
 }
 
-impl ResourceState {
-    /// Create a new ResourceState
-    pub fn new(
-        resource_id: String,
-        agent_version: String,
-        instance_metadata: InstanceMetadata,
-        session: SessionInfo,
-    ) -> Self {
-        let now: DateTime<Utc> = Utc::now();
-        Self {
-            resource_id,
-            registered_at: now.to_rfc3339(),
-            agent_version,
-            instance_metadata,
-            session,
-        }
-    }
-
-    /// Get the path to the state file based on runtime context
-    ///
-    /// Priority order:
-    /// 1. /var/lib/operion (preferred for system services - writable by service user)
-    /// 2. /etc/operion (legacy system-wide location)
-    /// 3. ~/.config/operion (user installation fallback)
-    pub fn get_state_file_path() -> PathBuf {
-        // Try /var/lib/operion first (best practice for system service state)
-        let var_lib_path = PathBuf::from("/var/lib/operion/resource-state.json");
-        if let Some(parent) = var_lib_path.parent() {
-            if parent.exists() || Self::can_create_directory(parent) {
-                return var_lib_path;
-            }
-        }
-
-        // Try /etc/operion (legacy location)
-        let etc_path = PathBuf::from("/etc/operion/resource-state.json");
-        if let Some(parent) = etc_path.parent() {
-            if parent.exists() || Self::can_create_directory(parent) {
-                return etc_path;
-            }
-        }
+fn parse_timestamp(value: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(value).ok().map(|time| time.with_timezone(&Utc))
+}
 
-        // Fallback to user config directory
-        let config_dir = dirs::config_dir()
-            .unwrap_or_else(|| PathBuf::from("."))
-            .join("operion");
-        config_dir.join("resource-state.json")
-    }
+/// Contents of the state file: the registration with each endpoint, by name
+///
+/// Files written before additional endpoints existed hold a single
+/// registration, which is read as the primary one.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Registrations {
+    pub resources: BTreeMap<String, ResourceState>,
+}
 
-    /// Check if we can create a directory (by attempting to create it)
-    fn can_create_directory(path: &std::path::Path) -> bool {
-        // If parent doesn't exist, we can't create it
-        if let Some(parent) = path.parent() {
-            if !parent.exists() {
-                return false;
-            }
+impl Registrations {
+    fn parse(contents: &str) -> Result<Self, serde_json::Error> {
+        let value: serde_json::Value = serde_json::from_str(contents)?;
+        if value.get("resources").is_some() {
+            return serde_json::from_value(value);
         }
-
-        // Try to create the directory
-        fs::create_dir_all(path).is_ok()
+        let primary: ResourceState = serde_json::from_value(value)?;
+        Ok(Self {
+            resources: BTreeMap::from([(PRIMARY_REGISTRATION.to_string(), primary)]),
+        })
     }
 
-    /// Load state from the JSON file
+    /// Every saved registration; empty when there is no state file
     ///
     /// Searches for the state file in multiple locations in priority order
-    pub fn load() -> Result<Option<Self>, StateError> {
-        // Try loading from different locations in priority order
-        let paths_to_try = vec![
-            PathBuf::from("/var/lib/operion/resource-state.json"),
-            PathBuf::from("/etc/operion/resource-state.json"),
-            {
-                let config_dir = dirs::config_dir()
-                    .unwrap_or_else(|| PathBuf::from("."))
-                    .join("operion");
-                config_dir.join("resource-state.json")
-            },
-        ];
+    pub fn load() -> Result<Self, StateError> {
+        let key = state_key()?;
+
+        // Try loading from different locations in priority order, then legacy locations
+        let paths_to_try = paths::state_file_candidates()
+            .into_iter()
+            .chain(paths::legacy_state_files());
 
         for path in paths_to_try {
-            if !path.exists() {
-                continue;
+            if let Some(registrations) = Self::load_from_path(&path, key.as_ref())? {
+                return Ok(registrations);
             }
-
-            let contents = fs::read_to_string(&path)
-                .map_err(|e| StateError::ReadError {
-                    path: path.to_string_lossy().to_string(),
-                    error: e.to_string(),
-                })?;
-
-            let state: ResourceState = serde_json::from_str(&contents)
-                .map_err(|e| StateError::ParseError {
-                    path: path.to_string_lossy().to_string(),
-                    error: e.to_string(),
-                })?;
-
-            return Ok(Some(state));
         }
 
         // No state file found in any location
-        Ok(None)
+        Ok(Self::default())
     }
 
-    /// Save state to the JSON file
+    /// Like `load`, but a corrupt file (already moved aside) counts as empty,
+    /// so saving a new registration does not fail because of it
+    fn load_for_update() -> Result<Self, StateError> {
+        match Self::load() {
+            Err(StateError::ParseError { .. }) => Ok(Self::default()),
+            result => result,
+        }
+    }
+
+    /// Save every registration to the state file, encrypted when a state key is configured
     pub fn save(&self) -> Result<(), StateError> {
+        let key = state_key()?;
+
         // Serialize to pretty JSON once
         let json = serde_json::to_string_pretty(self)
             .map_err(|e| StateError::SerializeError(e.to_string()))?;
+        let json = match &key {
+            Some(key) => key.encrypt(&json),
+            None => json,
+        };
 
         // Try saving to different locations in priority order
-        let paths_to_try = vec![
-            PathBuf::from("/var/lib/operion/resource-state.json"),
-            PathBuf::from("/etc/operion/resource-state.json"),
-            {
-                let config_dir = dirs::config_dir()
-                    .unwrap_or_else(|| PathBuf::from("."))
-                    .join("operion");
-                config_dir.join("resource-state.json")
-            },
-        ];
+        let paths_to_try = paths::state_file_candidates();
 
         let mut last_error = None;
 
         for path in paths_to_try {
-            match Self::try_save_to_path(&path, &json) {
+            match Self::try_save_to_path(&path, &json, key.as_ref()) {
                 Ok(()) => return Ok(()),
                 Err(e) => last_error = Some(e),
             }
@@ -156,8 +118,95 @@ impl ResourceState {
         }))
     }
 
+    /// Load the state at `path`, recovering from its backup if it is missing or corrupt
+    ///
+    /// A file that does not parse is moved aside for diagnostics rather than
+    /// overwritten by the next save.
+    fn load_from_path(path: &Path, key: Option<&ConfigKey>) -> Result<Option<Self>, StateError> {
+        let parse_error = match Self::read_state(path, key) {
+            Ok(Some(state)) => return Ok(Some(state)),
+            Ok(None) => None,
+            Err(e @ StateError::ParseError { .. }) => {
+                let quarantined = Self::quarantine(path)?;
+                warn!(error = %e, quarantined = %quarantined.display(), "State file is corrupt, moved it aside");
+                Some(e)
+            }
+            Err(e) => return Err(e),
+        };
+
+        let backup = backup_path(path);
+        match Self::read_state(&backup, key) {
+            Ok(Some(registrations)) => {
+                fs::copy(&backup, path).map_err(|e| StateError::WriteError {
+                    path: path.to_string_lossy().to_string(),
+                    error: e.to_string(),
+                })?;
+                warn!(
+                    path = %path.display(),
+                    registrations = registrations.resources.len(),
+                    "Restored state from backup"
+                );
+                Ok(Some(registrations))
+            }
+            Ok(None) => parse_error.map_or(Ok(None), Err),
+            Err(e) => {
+                warn!(error = %e, "State backup is unusable");
+                Err(parse_error.unwrap_or(e))
+            }
+        }
+    }
+
+    /// Parse the state at `path`, `None` if there is no file
+    ///
+    /// An encrypted file that cannot be decrypted is an error of its own, not
+    /// corruption: the fix is the right key, not a new registration.
+    fn read_state(path: &Path, key: Option<&ConfigKey>) -> Result<Option<Self>, StateError> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => {
+                return Err(StateError::ReadError {
+                    path: path.to_string_lossy().to_string(),
+                    error: e.to_string(),
+                })
+            }
+        };
+
+        let contents = if encryption::is_encrypted(contents.trim()) {
+            let key = key.ok_or_else(|| StateError::Encryption {
+                path: path.to_string_lossy().to_string(),
+                error: format!("the file is encrypted; set {} or {}", STATE_KEY_ENV, STATE_KEY_FILE_ENV),
+            })?;
+            key.decrypt(contents.trim()).map_err(|e| StateError::Encryption {
+                path: path.to_string_lossy().to_string(),
+                error: e.to_string(),
+            })?
+        } else {
+            contents
+        };
+
+        Self::parse(&contents)
+            .map(Some)
+            .map_err(|e| StateError::ParseError {
+                path: path.to_string_lossy().to_string(),
+                error: e.to_string(),
+            })
+    }
+
+    /// Rename a corrupt state file to `<name>.corrupt-<unix time>`
+    fn quarantine(path: &Path) -> Result<PathBuf, StateError> {
+        let mut name = path.file_name().unwrap_or_default().to_os_string();
+        name.push(format!(".corrupt-{}", Utc::now().timestamp()));
+        let quarantined = path.with_file_name(name);
+        fs::rename(path, &quarantined).map_err(|e| StateError::WriteError {
+            path: quarantined.to_string_lossy().to_string(),
+            error: e.to_string(),
+        })?;
+        Ok(quarantined)
+    }
+
     /// Attempt to save state to a specific path
-    fn try_save_to_path(path: &PathBuf, json: &str) -> Result<(), StateError> {
+    fn try_save_to_path(path: &Path, json: &str, key: Option<&ConfigKey>) -> Result<(), StateError> {
         // Ensure the directory exists
         if let Some(parent) = path.parent() {
             fs::create_dir_all(parent)
@@ -167,9 +216,16 @@ impl ResourceState {
                 })?;
         }
 
-        // Write to a temporary file first (atomic write)
+        // Write to a temporary file first (atomic write), created owner read/write only
         let temp_path = path.with_extension("tmp");
-        let mut file = fs::File::create(&temp_path)
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let mut file = options.open(&temp_path)
             .map_err(|e| StateError::WriteError {
                 path: temp_path.to_string_lossy().to_string(),
                 error: e.to_string(),
@@ -187,39 +243,341 @@ impl ResourceState {
                 error: e.to_string(),
             })?;
 
+        // Keep the current state as the backup, unless it is itself corrupt
+        if matches!(Self::read_state(path, key), Ok(Some(_))) {
+            fs::rename(path, backup_path(path))
+                .map_err(|e| StateError::WriteError {
+                    path: backup_path(path).to_string_lossy().to_string(),
+                    error: e.to_string(),
+                })?;
+        }
+
         // Atomically rename temp file to actual file
-        fs::rename(&temp_path, &path)
+        fs::rename(&temp_path, path)
             .map_err(|e| StateError::WriteError {
                 path: path.to_string_lossy().to_string(),
                 error: e.to_string(),
             })?;
 
-        // Set restrictive permissions (owner read/write only)
-        #[cfg(unix)]
-        {
-            use std::os::unix::fs::PermissionsExt;
-            let metadata = fs::metadata(&path)
-                .map_err(|e| StateError::PermissionError {
-                    path: path.to_string_lossy().to_string(),
-                    error: e.to_string(),
-                })?;
+        Ok(())
+    }
+}
 
-            let mut permissions = metadata.permissions();
-            permissions.set_mode(0o600);
-            fs::set_permissions(&path, permissions)
-                .map_err(|e| StateError::PermissionError {
-                    path: path.to_string_lossy().to_string(),
-                    error: e.to_string(),
-                })?;
+/// Represents the persisted state of a registered resource
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ResourceState {
+    /// The resource ID assigned by the Operion platform
+    pub resource_id: String,
+    /// ISO 8601 timestamp of when the resource was registered
+    pub registered_at: String,
+    /// The agent version that performed the registration
+    pub agent_version: String,
+    /// Cloud instance metadata from registration
+    pub instance_metadata: InstanceMetadata,
+    /// Session info from when the agent started
+    pub session: SessionInfo,
+    /// Last batch the API acknowledged
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub checkpoint: Option<FlushCheckpoint>,
+    /// Machine the resource was registered on
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fingerprint: Option<MachineFingerprint>,
+    /// ISO 8601 timestamp of when the platform forgets the resource unless renewed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<String>,
+    /// ISO 8601 timestamp of the last renewal
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub renewed_at: Option<String>,
+    /// Public key the platform was given at registration
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub public_key: Option<String>,
+    /// Agent version changes since registration, oldest first
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub upgrades: Vec<AgentUpgrade>,
+}
+
+/// Upgrade history entries kept in the state; older ones are dropped
+const MAX_UPGRADES: usize = 10;
+
+/// A start on a different agent version than the one that last ran (usually an upgrade)
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct AgentUpgrade {
+    pub from_version: String,
+    pub to_version: String,
+    /// ISO 8601 timestamp of the first start on `to_version`
+    pub upgraded_at: String,
+}
+
+/// Progress of delivery, saved after every acknowledged batch so a restart
+/// after a crash knows what already reached the platform
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct FlushCheckpoint {
+    /// Number of acknowledged batches, continued across restarts
+    pub sequence: u64,
+    /// Collection time of the newest acknowledged metric (Unix seconds)
+    pub metric_timestamp: u64,
+    /// Last spool segment delivered; segments up to it are not sent again
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub spool_segment: Option<u64>,
+    /// ISO 8601 timestamp of the acknowledgement
+    pub acknowledged_at: String,
+}
+
+impl FlushCheckpoint {
+    /// Record an acknowledged batch on top of the previous checkpoint, if any
+    pub fn advance(
+        previous: Option<&FlushCheckpoint>,
+        metric_timestamp: u64,
+        spool_segment: Option<u64>,
+    ) -> Self {
+        Self {
+            sequence: previous.map_or(0, |p| p.sequence) + 1,
+            // Replayed batches are older than what was already delivered live
+            metric_timestamp: previous.map_or(metric_timestamp, |p| p.metric_timestamp.max(metric_timestamp)),
+            spool_segment: spool_segment.or_else(|| previous.and_then(|p| p.spool_segment)),
+            acknowledged_at: Utc::now().to_rfc3339(),
+        }
+    }
+
+    /// Gap between the newest acknowledged metric and `until` (Unix seconds)
+    pub fn gap_until(&self, until: u64) -> DeliveryGap {
+        DeliveryGap {
+            since: self.metric_timestamp,
+            until,
+            seconds: until.saturating_sub(self.metric_timestamp),
+        }
+    }
+}
+
+/// Period without acknowledged data before the agent (re)started, reported
+/// with the first batch afterwards; spooled metrics may still fill part of it
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct DeliveryGap {
+    pub since: u64,
+    pub until: u64,
+    pub seconds: u64,
+}
+
+impl ResourceState {
+    /// Create a new ResourceState
+    pub fn new(
+        resource_id: String,
+        agent_version: String,
+        instance_metadata: InstanceMetadata,
+        session: SessionInfo,
+    ) -> Self {
+        let now: DateTime<Utc> = Utc::now();
+        Self {
+            resource_id,
+            registered_at: now.to_rfc3339(),
+            agent_version,
+            instance_metadata,
+            session,
+            checkpoint: None,
+            fingerprint: None,
+            expires_at: None,
+            renewed_at: None,
+            public_key: None,
+            upgrades: Vec::new(),
+        }
+    }
+
+    /// Agent version that last ran with this registration
+    pub fn running_version(&self) -> &str {
+        self.upgrades
+            .last()
+            .map_or(&self.agent_version, |upgrade| &upgrade.to_version)
+    }
+
+    /// Note a start on `version`, returning the new history entry if it differs
+    /// from the version that last ran
+    pub fn record_version(&mut self, version: &str) -> Option<&AgentUpgrade> {
+        if self.running_version() == version {
+            return None;
+        }
+        self.upgrades.push(AgentUpgrade {
+            from_version: self.running_version().to_string(),
+            to_version: version.to_string(),
+            upgraded_at: Utc::now().to_rfc3339(),
+        });
+        if self.upgrades.len() > MAX_UPGRADES {
+            self.upgrades.remove(0);
+        }
+        self.upgrades.last()
+    }
+
+    /// Whether the registration should be renewed at `now`
+    ///
+    /// Renews once two thirds of the lifetime granted by the last registration
+    /// or renewal have passed, leaving room for a few failed attempts before
+    /// it expires. Registrations without an expiry never need renewing.
+    pub fn renewal_due(&self, now: DateTime<Utc>) -> bool {
+        let Some(expires_at) = self.expires_at.as_deref().and_then(parse_timestamp) else {
+            return false;
+        };
+        let granted_at = parse_timestamp(self.renewed_at.as_deref().unwrap_or(&self.registered_at))
+            .unwrap_or(expires_at);
+        let lifetime = expires_at - granted_at;
+        now >= granted_at + lifetime * 2 / 3
+    }
+
+    /// Record a successful renewal and the new expiry
+    pub fn renewed(&mut self, expires_at: Option<String>) {
+        self.renewed_at = Some(Utc::now().to_rfc3339());
+        self.expires_at = expires_at;
+    }
+
+    /// Whether this state is another machine's and was registered with `public_key`
+    ///
+    /// Then the agent key was copied along with it, and both machines would
+    /// sign as the same agent until one of them generates a new key.
+    pub fn shares_key_with_other_machine(
+        &self,
+        fingerprint: Option<&MachineFingerprint>,
+        instance: &InstanceMetadata,
+        public_key: &str,
+    ) -> bool {
+        self.public_key.as_deref() == Some(public_key)
+            && self.mismatch(fingerprint, instance, Some(public_key)).is_some()
+    }
+
+    /// Whether this state was saved on another machine, e.g. copied with a cloned image
+    ///
+    /// States without a fingerprint, from earlier releases, are assumed to be local.
+    pub fn is_from_other_machine(&self, current: Option<&MachineFingerprint>) -> bool {
+        match (&self.fingerprint, current) {
+            (Some(saved), Some(current)) => !saved.same_machine(current),
+            _ => false,
+        }
+    }
+
+    /// Why this registration cannot be reused here, if it cannot
+    ///
+    /// A different cloud instance ID (an AMI or snapshot restored on a new
+    /// instance) or machine fingerprint means the resource is someone else's.
+    /// A different agent key means the platform would reject our signatures.
+    pub fn mismatch(
+        &self,
+        fingerprint: Option<&MachineFingerprint>,
+        instance: &InstanceMetadata,
+        public_key: Option<&str>,
+    ) -> Option<String> {
+        if let (Some(saved), Some(current)) = (&self.instance_metadata.instance_id, &instance.instance_id) {
+            if saved != current {
+                return Some(format!("instance ID changed from {} to {}", saved, current));
+            }
+        }
+        if self.is_from_other_machine(fingerprint) {
+            return Some("machine fingerprint changed".to_string());
+        }
+        match (self.public_key.as_deref(), public_key) {
+            (Some(saved), Some(current)) if saved != current => Some("agent key changed".to_string()),
+            _ => None,
+        }
+    }
+
+    /// Get the path to the state file based on runtime context
+    ///
+    /// Priority order (see `paths::state_file_candidates`):
+    /// 1. System state directory (`/var/lib/operion`, `/Library/Application Support/Operion`
+    ///    on macOS, `%ProgramData%\Operion` on Windows)
+    /// 2. /etc/operion (legacy system-wide location, Linux only)
+    /// 3. User config directory (user installation fallback)
+    pub fn get_state_file_path() -> PathBuf {
+        let mut candidates = paths::state_file_candidates();
+        let fallback = candidates.pop().unwrap_or_else(|| PathBuf::from(paths::STATE_FILE_NAME));
+
+        for path in candidates {
+            if let Some(parent) = path.parent() {
+                if parent.exists() || Self::can_create_directory(parent) {
+                    return path;
+                }
+            }
+        }
+
+        fallback
+    }
+
+    /// Check if we can create a directory (by attempting to create it)
+    fn can_create_directory(path: &std::path::Path) -> bool {
+        // If parent doesn't exist, we can't create it
+        if let Some(parent) = path.parent() {
+            if !parent.exists() {
+                return false;
+            }
+        }
+
+        // Try to create the directory
+        fs::create_dir_all(path).is_ok()
+    }
+
+    /// Load the registration with `api.endpoint`
+    pub fn load() -> Result<Option<Self>, StateError> {
+        Self::load_named(PRIMARY_REGISTRATION)
+    }
+
+    /// Load the registration saved under `name`
+    pub fn load_named(name: &str) -> Result<Option<Self>, StateError> {
+        Ok(Registrations::load()?.resources.remove(name))
+    }
+
+    /// Save this as the registration with `api.endpoint`
+    pub fn save(&self) -> Result<(), StateError> {
+        self.save_named(PRIMARY_REGISTRATION)
+    }
+
+    /// Save this as the registration named `name`, keeping the others
+    pub fn save_named(&self, name: &str) -> Result<(), StateError> {
+        let mut registrations = Registrations::load_for_update()?;
+        registrations.resources.insert(name.to_string(), self.clone());
+        registrations.save()
+    }
+
+    /// Drop the registration named `name`, keeping the others
+    pub fn forget(name: &str) -> Result<(), StateError> {
+        let mut registrations = Registrations::load_for_update()?;
+        if registrations.resources.remove(name).is_none() {
+            return Ok(());
+        }
+        if registrations.resources.is_empty() {
+            return Self::remove();
+        }
+        registrations.save()
+    }
+
+    /// Delete every state file so the next start registers new resources
+    pub fn remove() -> Result<(), StateError> {
+        let paths_to_remove = paths::state_file_candidates()
+            .into_iter()
+            .chain(paths::legacy_state_files());
+
+        for path in paths_to_remove.flat_map(|path| [backup_path(&path), path]) {
+            match fs::remove_file(&path) {
+                Ok(()) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => {
+                    return Err(StateError::DeleteError {
+                        path: path.to_string_lossy().to_string(),
+                        error: e.to_string(),
+                    })
+                }
+            }
         }
 
         Ok(())
     }
+}
 
+/// Last good state before the most recent save, e.g. `resource-state.json.bak`
+fn backup_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".bak");
+    path.with_file_name(name)
 }
 
 /// Errors that can occur when working with resource state
 #[derive(Debug, thiserror::Error)]
+#[allow(clippy::enum_variant_names)]
 pub enum StateError {
     #[error("Failed to read state file at {path}: {error}")]
     ReadError { path: String, error: String },
@@ -230,30 +588,27 @@ pub enum StateError {
     #[error("Failed to write state file at {path}: {error}")]
     WriteError { path: String, error: String },
 
+    #[error("Failed to delete state file at {path}: {error}")]
+    DeleteError { path: String, error: String },
+
     #[error("Failed to create directory {path}: {error}")]
     CreateDirectoryError { path: String, error: String },
 
-    #[error("Failed to set permissions on {path}: {error}")]
-    PermissionError { path: String, error: String },
-
     #[error("Failed to serialize state: {0}")]
     SerializeError(String),
+
+    #[error("Failed to decrypt state file at {path}: {error}")]
+    Encryption { path: String, error: String },
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use tempfile::tempdir;
-    use std::env;
 
     #[test]
     fn test_resource_state_creation() {
-        let instance_metadata = InstanceMetadata {
-            instance_id: None,
-            cloud_provider: None,
-            region: None,
-            instance_type: None,
-        };
+        let instance_metadata = InstanceMetadata::default();
         let session = SessionInfo::generate();
 
         let state = ResourceState::new(
@@ -270,12 +625,7 @@ mod tests {
 
     #[test]
     fn test_state_serialization() {
-        let instance_metadata = InstanceMetadata {
-            instance_id: None,
-            cloud_provider: None,
-            region: None,
-            instance_type: None,
-        };
+        let instance_metadata = InstanceMetadata::default();
         let session = SessionInfo::generate();
 
         let state = ResourceState::new(
@@ -295,21 +645,197 @@ mod tests {
         assert_eq!(deserialized.agent_version, state.agent_version);
     }
 
+    #[test]
+    fn test_checkpoint_advance() {
+        let first = FlushCheckpoint::advance(None, 1_700_000_060, None);
+        assert_eq!(first.sequence, 1);
+
+        // A replayed segment is older but still moves the sequence and segment forward
+        let second = FlushCheckpoint::advance(Some(&first), 1_600_000_000, Some(4));
+        assert_eq!(second.sequence, 2);
+        assert_eq!(second.metric_timestamp, 1_700_000_060);
+        assert_eq!(second.spool_segment, Some(4));
+
+        let third = FlushCheckpoint::advance(Some(&second), 1_700_000_120, None);
+        assert_eq!(third.spool_segment, Some(4));
+        assert_eq!(third.gap_until(1_700_000_420).seconds, 300);
+
+        // State files written before checkpoints existed still load
+        let json = r#"{"resource_id": "res_1", "registered_at": "2024-01-15T10:30:00Z",
+            "agent_version": "0.2.1", "instance_metadata": {}, "session": {"boot_time": 1, "agent_start_time": 2, "uptime_seconds": 3}}"#;
+        assert!(serde_json::from_str::<ResourceState>(json).is_ok_and(|s| s.checkpoint.is_none()));
+    }
+
+    fn test_state(resource_id: &str) -> ResourceState {
+        let instance_metadata = InstanceMetadata::default();
+        ResourceState::new(resource_id.to_string(), "0.2.1".to_string(), instance_metadata, SessionInfo::generate())
+    }
+
+    #[test]
+    fn test_renewal_due() {
+        let mut state = test_state("res_1");
+        state.registered_at = "2024-01-15T10:00:00Z".to_string();
+        let at = |time: &str| parse_timestamp(time).unwrap();
+
+        // No expiry, nothing to renew
+        assert!(!state.renewal_due(at("2030-01-01T00:00:00Z")));
+
+        state.expires_at = Some("2024-01-15T13:00:00Z".to_string());
+        assert!(!state.renewal_due(at("2024-01-15T11:59:00Z")));
+        assert!(state.renewal_due(at("2024-01-15T12:00:00Z")));
+
+        // The next lifetime counts from the renewal
+        state.renewed_at = Some("2024-01-15T12:00:00Z".to_string());
+        state.expires_at = Some("2024-01-15T15:00:00Z".to_string());
+        assert!(!state.renewal_due(at("2024-01-15T13:30:00Z")));
+        assert!(state.renewal_due(at("2024-01-15T14:00:00Z")));
+    }
+
+    #[test]
+    fn test_record_version() {
+        let mut state = test_state("res_1");
+        assert!(state.record_version("0.2.1").is_none());
+
+        let upgrade = state.record_version("0.3.0").unwrap();
+        assert_eq!((upgrade.from_version.as_str(), upgrade.to_version.as_str()), ("0.2.1", "0.3.0"));
+        assert_eq!(state.running_version(), "0.3.0");
+        assert!(state.record_version("0.3.0").is_none());
+
+        for patch in 1..=MAX_UPGRADES {
+            state.record_version(&format!("0.3.{}", patch));
+        }
+        assert_eq!(state.upgrades.len(), MAX_UPGRADES);
+        assert_eq!(state.upgrades[0].from_version, "0.3.0");
+        // The registering version is kept as is
+        assert_eq!(state.agent_version, "0.2.1");
+    }
+
+    #[test]
+    fn test_mismatch_on_new_instance() {
+        let mut state = test_state("res_1");
+        state.instance_metadata.instance_id = Some("i-0aaa".to_string());
+        let instance = |id: Option<&str>| InstanceMetadata {
+            instance_id: id.map(str::to_string),
+            ..Default::default()
+        };
+
+        assert_eq!(state.mismatch(None, &instance(Some("i-0aaa")), None), None);
+        // Metadata service unreachable: nothing to compare
+        assert_eq!(state.mismatch(None, &instance(None), None), None);
+        assert_eq!(
+            state.mismatch(None, &instance(Some("i-0bbb")), None).as_deref(),
+            Some("instance ID changed from i-0aaa to i-0bbb")
+        );
+
+        // Registered before agent keys existed: the key is adopted, not a mismatch
+        assert_eq!(state.mismatch(None, &instance(Some("i-0aaa")), Some("key-1")), None);
+        state.public_key = Some("key-1".to_string());
+        assert_eq!(
+            state.mismatch(None, &instance(Some("i-0aaa")), Some("key-2")).as_deref(),
+            Some("agent key changed")
+        );
+    }
+
+    #[test]
+    fn test_corrupt_state_restored_from_backup() {
+        let temp_dir = tempdir().unwrap();
+        let state_path = temp_dir.path().join("resource-state.json");
+
+        for resource_id in ["res_first", "res_second"] {
+            let json = serde_json::to_string_pretty(&test_state(resource_id)).unwrap();
+            Registrations::try_save_to_path(&state_path, &json, None).unwrap();
+        }
+        let backup: ResourceState =
+            serde_json::from_str(&fs::read_to_string(backup_path(&state_path)).unwrap()).unwrap();
+        assert_eq!(backup.resource_id, "res_first");
+
+        // A corrupt file is quarantined and the backup takes its place
+        fs::write(&state_path, "{\"resource_id\": \"res_sec").unwrap();
+        let loaded = Registrations::load_from_path(&state_path, None).unwrap().unwrap();
+        assert_eq!(loaded.resources[PRIMARY_REGISTRATION].resource_id, "res_first");
+        assert!(Registrations::read_state(&state_path, None).unwrap().is_some());
+        let quarantined: Vec<_> = fs::read_dir(temp_dir.path())
+            .unwrap()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_name().to_string_lossy().contains(".corrupt-"))
+            .collect();
+        assert_eq!(quarantined.len(), 1);
+
+        // A corrupt file without a usable backup still fails, so the agent re-registers
+        fs::remove_file(backup_path(&state_path)).unwrap();
+        fs::write(&state_path, "not json").unwrap();
+        assert!(matches!(
+            Registrations::load_from_path(&state_path, None),
+            Err(StateError::ParseError { .. })
+        ));
+        assert!(!state_path.exists());
+        assert!(Registrations::load_from_path(&state_path, None).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_registrations_by_name() {
+        let temp_dir = tempdir().unwrap();
+        let state_path = temp_dir.path().join("resource-state.json");
+
+        // A single registration from an earlier release is the primary one
+        let legacy = serde_json::to_string_pretty(&test_state("res_primary")).unwrap();
+        fs::write(&state_path, legacy).unwrap();
+        let mut registrations = Registrations::load_from_path(&state_path, None).unwrap().unwrap();
+        assert_eq!(registrations.resources[PRIMARY_REGISTRATION].resource_id, "res_primary");
+
+        registrations.resources.insert("customer-a".to_string(), test_state("res_a"));
+        let json = serde_json::to_string_pretty(&registrations).unwrap();
+        Registrations::try_save_to_path(&state_path, &json, None).unwrap();
+        let loaded = Registrations::load_from_path(&state_path, None).unwrap().unwrap();
+        assert_eq!(loaded.resources.len(), 2);
+        assert_eq!(loaded.resources["customer-a"].resource_id, "res_a");
+        assert_eq!(loaded.resources[PRIMARY_REGISTRATION].resource_id, "res_primary");
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&state_path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+    }
+
+    #[test]
+    fn test_encrypted_state() {
+        let temp_dir = tempdir().unwrap();
+        let state_path = temp_dir.path().join("resource-state.json");
+        let encoded_key = ConfigKey::generate();
+        let key = ConfigKey::parse(&encoded_key).unwrap();
+
+        // A plaintext state from an earlier release still loads with a key set
+        let json = serde_json::to_string_pretty(&test_state("res_plain")).unwrap();
+        Registrations::try_save_to_path(&state_path, &json, Some(&key)).unwrap();
+        let loaded = Registrations::load_from_path(&state_path, Some(&key)).unwrap().unwrap();
+        assert_eq!(loaded.resources[PRIMARY_REGISTRATION].resource_id, "res_plain");
+
+        let json = serde_json::to_string_pretty(&test_state("res_sealed")).unwrap();
+        Registrations::try_save_to_path(&state_path, &key.encrypt(&json), Some(&key)).unwrap();
+        assert!(!fs::read_to_string(&state_path).unwrap().contains("res_sealed"));
+        let loaded = Registrations::load_from_path(&state_path, Some(&key)).unwrap().unwrap();
+        assert_eq!(loaded.resources[PRIMARY_REGISTRATION].resource_id, "res_sealed");
+
+        // Without the right key the file is an error, and it is not quarantined
+        let other = ConfigKey::parse(&ConfigKey::generate()).unwrap();
+        for key in [None, Some(&other)] {
+            assert!(matches!(
+                Registrations::load_from_path(&state_path, key),
+                Err(StateError::Encryption { .. })
+            ));
+        }
+        assert!(state_path.exists());
+    }
+
     #[test]
     fn test_state_file_operations() {
         // Create a temporary directory for testing
         let temp_dir = tempdir().unwrap();
         let state_path = temp_dir.path().join("resource-state.json");
 
-        // Override the state file path for testing
-        env::set_var("HOME", temp_dir.path());
-
-        let instance_metadata = InstanceMetadata {
-            instance_id: None,
-            cloud_provider: None,
-            region: None,
-            instance_type: None,
-        };
+        let instance_metadata = InstanceMetadata::default();
         let session = SessionInfo::generate();
 
         let state = ResourceState {
@@ -318,6 +844,12 @@ mod tests {
             agent_version: "0.2.1".to_string(),
             instance_metadata,
             session,
+            checkpoint: None,
+            fingerprint: None,
+            expires_at: None,
+            renewed_at: None,
+            public_key: None,
+            upgrades: Vec::new(),
         };
 
         // Test saving
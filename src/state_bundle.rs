@@ -0,0 +1,176 @@
+//! Portable copy of the registration state
+//!
+//! `sentinel-agent state export` bundles the resource state and the agent key
+//! into one JSON document, optionally encrypted with the config key, and
+//! `state import` installs it on another host (or the same host after
+//! reimaging) so it keeps reporting as the same resource.
+
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use thiserror::Error;
+
+use crate::encryption::{self, ConfigKey, EncryptionError};
+use crate::fingerprint::MachineFingerprint;
+use crate::identity::{AgentIdentity, IdentityError};
+use crate::metadata::InstanceMetadata;
+use crate::state::{Registrations, ResourceState, StateError, PRIMARY_REGISTRATION};
+
+const BUNDLE_FORMAT: u32 = 1;
+
+#[derive(Error, Debug)]
+pub enum BundleError {
+    #[error("no registration state to export; the agent has not registered yet")]
+    NoState,
+    #[error("a registration state already exists (resource {0}); pass --force to replace it")]
+    Exists(String),
+    #[error("unsupported state bundle format {0}")]
+    Format(u32),
+    #[error("invalid state bundle: {0}")]
+    Parse(String),
+    #[error(transparent)]
+    State(#[from] StateError),
+    #[error(transparent)]
+    Identity(#[from] IdentityError),
+    #[error(transparent)]
+    Encryption(#[from] EncryptionError),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StateBundle {
+    pub format: u32,
+    /// ISO 8601 timestamp of the export
+    pub exported_at: String,
+    /// Host the bundle was exported on
+    pub hostname: String,
+    pub state: ResourceState,
+    /// Registrations with `api.additional_endpoints`, by name
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub additional: BTreeMap<String, ResourceState>,
+    /// Base64 PKCS#8 agent key, when the agent has one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub agent_key: Option<String>,
+}
+
+impl StateBundle {
+    /// Bundle of this host's registration
+    pub fn export() -> Result<Self, BundleError> {
+        let mut additional = Registrations::load()?.resources;
+        let state = additional.remove(PRIMARY_REGISTRATION).ok_or(BundleError::NoState)?;
+        Ok(Self {
+            format: BUNDLE_FORMAT,
+            exported_at: Utc::now().to_rfc3339(),
+            hostname: crate::hostname::short(),
+            state,
+            additional,
+            agent_key: AgentIdentity::export_encoded()?,
+        })
+    }
+
+    /// JSON, or an `ENC[...]` value when a key is given
+    pub fn encode(&self, key: Option<&ConfigKey>) -> Result<String, BundleError> {
+        let json = serde_json::to_string_pretty(self).map_err(|e| BundleError::Parse(e.to_string()))?;
+        Ok(match key {
+            Some(key) => key.encrypt(&json),
+            None => json,
+        })
+    }
+
+    /// Parse a bundle; `key` is only called for an encrypted one
+    pub fn decode(
+        text: &str,
+        key: impl FnOnce() -> Result<Option<ConfigKey>, EncryptionError>,
+    ) -> Result<Self, BundleError> {
+        let text = text.trim();
+        let json = if encryption::is_encrypted(text) {
+            key()?.ok_or(EncryptionError::MissingKey)?.decrypt(text)?
+        } else {
+            text.to_string()
+        };
+        let bundle: Self = serde_json::from_str(&json).map_err(|e| BundleError::Parse(e.to_string()))?;
+        if bundle.format != BUNDLE_FORMAT {
+            return Err(BundleError::Format(bundle.format));
+        }
+        Ok(bundle)
+    }
+
+    /// Install the bundle as this host's registrations
+    ///
+    /// Each registration takes on this machine's fingerprint and instance metadata,
+    /// since moving the identity here is deliberate and must not trigger the
+    /// clone detection that re-registers.
+    pub fn install(
+        self,
+        fingerprint: Option<MachineFingerprint>,
+        instance_metadata: InstanceMetadata,
+        force: bool,
+    ) -> Result<ResourceState, BundleError> {
+        // An unreadable state may still be a registration, so only --force replaces it
+        if !force {
+            if let Some(existing) = Registrations::load()?.resources.into_values().next() {
+                return Err(BundleError::Exists(existing.resource_id));
+            }
+        }
+        let mut registrations = Registrations {
+            resources: self.additional,
+        };
+        registrations.resources.insert(PRIMARY_REGISTRATION.to_string(), self.state);
+        for state in registrations.resources.values_mut() {
+            state.fingerprint = fingerprint.clone();
+            state.instance_metadata = instance_metadata.clone();
+        }
+        if let Some(agent_key) = &self.agent_key {
+            AgentIdentity::import_encoded(agent_key)?;
+        }
+        registrations.save()?;
+        Ok(registrations.resources.remove(PRIMARY_REGISTRATION).expect("inserted above"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metadata::SessionInfo;
+
+    fn bundle() -> StateBundle {
+        let instance_metadata = InstanceMetadata {
+            instance_id: Some("i-0aaa".to_string()),
+            ..Default::default()
+        };
+        StateBundle {
+            format: BUNDLE_FORMAT,
+            exported_at: Utc::now().to_rfc3339(),
+            hostname: "web01".to_string(),
+            state: ResourceState::new(
+                "res_1".to_string(),
+                "0.3.2".to_string(),
+                instance_metadata,
+                SessionInfo::generate(),
+            ),
+            additional: BTreeMap::new(),
+            agent_key: Some("a2V5".to_string()),
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let plain = bundle().encode(None).unwrap();
+        let decoded = StateBundle::decode(&plain, || panic!("key not needed")).unwrap();
+        assert_eq!(decoded.state.resource_id, "res_1");
+        assert_eq!(decoded.agent_key.as_deref(), Some("a2V5"));
+
+        let encoded_key = ConfigKey::generate();
+        let key = ConfigKey::parse(&encoded_key).unwrap();
+        let sealed = bundle().encode(Some(&key)).unwrap();
+        assert!(!sealed.contains("res_1"));
+        let decoded = StateBundle::decode(&sealed, || ConfigKey::parse(&encoded_key).map(Some)).unwrap();
+        assert_eq!(decoded.hostname, "web01");
+        assert!(matches!(
+            StateBundle::decode(&sealed, || Ok(None)),
+            Err(BundleError::Encryption(EncryptionError::MissingKey))
+        ));
+
+        let future = plain.replace("\"format\": 1", "\"format\": 2");
+        assert!(matches!(StateBundle::decode(&future, || Ok(None)), Err(BundleError::Format(2))));
+    }
+}
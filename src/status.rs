@@ -0,0 +1,481 @@
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt::Write as _;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, info, warn};
+
+use crate::profiling;
+
+/// Number of recent errors kept for `sentinel-agent status`
+const MAX_RECENT_ERRORS: usize = 10;
+
+/// Largest request we read from a status client
+const MAX_REQUEST_BYTES: usize = 8192;
+
+/// Snapshot of the running agent, served on the local status endpoint
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AgentStatus {
+    pub version: String,
+    pub hostname: String,
+    pub started_at: String,
+    pub registered: bool,
+    pub resource_id: Option<String>,
+    pub buffer_depth: usize,
+    /// Estimated size of the buffered metrics
+    #[serde(default)]
+    pub buffer_bytes: usize,
+    /// Sample series (e.g. from textfiles) waiting for the next flush
+    #[serde(default)]
+    pub buffered_series: usize,
+    /// Metrics pushed out of the buffer by its caps since startup, spooled or not
+    #[serde(default)]
+    pub buffer_evicted: u64,
+    /// Of those, metrics lost because there was no spool or it failed to write them
+    #[serde(default)]
+    pub buffer_dropped: u64,
+    /// Resident memory of the agent, sampled when a memory limit is configured
+    #[serde(default)]
+    pub memory_bytes: Option<u64>,
+    pub spooled_batches: usize,
+    /// Spooled metrics discarded over the spool size cap since startup
+    #[serde(default)]
+    pub spool_dropped: u64,
+    pub last_flush_at: Option<String>,
+    pub flush_paused: bool,
+    pub maintenance: bool,
+    /// Collection is backed off because the host is under pressure
+    #[serde(default)]
+    pub under_pressure: bool,
+    pub backend_unreachable_since: Option<String>,
+    /// Server minus local clock, when beyond the Date header's resolution
+    #[serde(default)]
+    pub clock_skew_seconds: Option<i64>,
+    /// Failed, timed-out or panicked invocations per collector since startup
+    #[serde(default)]
+    pub collector_failures: BTreeMap<String, u64>,
+    /// Restarts of supervised background tasks since startup
+    #[serde(default)]
+    pub task_restarts: BTreeMap<String, u64>,
+    /// Delivery to each of `api.additional_endpoints`
+    #[serde(default)]
+    pub sinks: BTreeMap<String, SinkStatus>,
+    pub recent_errors: VecDeque<RecentError>,
+}
+
+/// Copies to one additional endpoint since startup, in batches
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SinkStatus {
+    pub queued: usize,
+    pub delivered: u64,
+    pub retried: u64,
+    /// Pushed out of a full queue
+    pub dropped: u64,
+    /// Given up on after a permanent error or the last retry
+    pub failed: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecentError {
+    pub at: String,
+    pub message: String,
+}
+
+impl AgentStatus {
+    pub fn new(hostname: String) -> Self {
+        Self {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            hostname,
+            started_at: Utc::now().to_rfc3339(),
+            registered: false,
+            resource_id: None,
+            buffer_depth: 0,
+            buffer_bytes: 0,
+            buffered_series: 0,
+            buffer_evicted: 0,
+            buffer_dropped: 0,
+            memory_bytes: None,
+            spooled_batches: 0,
+            spool_dropped: 0,
+            last_flush_at: None,
+            flush_paused: false,
+            maintenance: false,
+            under_pressure: false,
+            backend_unreachable_since: None,
+            clock_skew_seconds: None,
+            collector_failures: BTreeMap::new(),
+            task_restarts: BTreeMap::new(),
+            sinks: BTreeMap::new(),
+            recent_errors: VecDeque::new(),
+        }
+    }
+
+    /// Human-readable report printed by `sentinel-agent status`
+    pub fn render_text(&self) -> String {
+        let mut out = String::new();
+        let _ = writeln!(out, "Operion Sentinel Agent {}", self.version);
+        let _ = writeln!(out, "  Hostname:         {}", self.hostname);
+        let _ = writeln!(out, "  Started:          {}", self.started_at);
+        let _ = writeln!(
+            out,
+            "  Registration:     {}",
+            match &self.resource_id {
+                Some(id) if self.registered => format!("registered ({})", id),
+                _ => "not registered".to_string(),
+            }
+        );
+        let _ = writeln!(
+            out,
+            "  Buffered metrics: {} ({} bytes)",
+            self.buffer_depth, self.buffer_bytes
+        );
+        if self.buffered_series > 0 {
+            let _ = writeln!(out, "  Sample series:    {}", self.buffered_series);
+        }
+        if let Some(memory) = self.memory_bytes {
+            let _ = writeln!(out, "  Memory:           {} bytes", memory);
+        }
+        if self.buffer_evicted > 0 {
+            let _ = writeln!(out, "  Buffer evictions: {}", self.buffer_evicted);
+        }
+        if self.buffer_dropped > 0 {
+            let _ = writeln!(out, "  Buffer dropped:   {} metrics", self.buffer_dropped);
+        }
+        let _ = writeln!(out, "  Spooled batches:  {}", self.spooled_batches);
+        if self.spool_dropped > 0 {
+            let _ = writeln!(out, "  Spool dropped:    {} metrics", self.spool_dropped);
+        }
+        let _ = writeln!(
+            out,
+            "  Last flush:       {}",
+            self.last_flush_at.as_deref().unwrap_or("never")
+        );
+        if self.maintenance {
+            let _ = writeln!(out, "  Paused for maintenance");
+        }
+        if self.under_pressure {
+            let _ = writeln!(out, "  Collection backed off, host under pressure");
+        }
+        if self.flush_paused {
+            let _ = writeln!(out, "  Flushes paused by API rate limiting");
+        }
+        if let Some(since) = &self.backend_unreachable_since {
+            let _ = writeln!(out, "  Backend unreachable since {}", since);
+        }
+        if let Some(skew) = self.clock_skew_seconds {
+            let _ = writeln!(out, "  Clock skew:       {}s relative to the API server", skew);
+        }
+        if !self.collector_failures.is_empty() {
+            let failures: Vec<String> = self
+                .collector_failures
+                .iter()
+                .map(|(collector, count)| format!("{}={}", collector, count))
+                .collect();
+            let _ = writeln!(out, "  Collector failures: {}", failures.join(", "));
+        }
+        if !self.task_restarts.is_empty() {
+            let restarts: Vec<String> = self
+                .task_restarts
+                .iter()
+                .map(|(task, count)| format!("{}={}", task, count))
+                .collect();
+            let _ = writeln!(out, "  Task restarts:    {}", restarts.join(", "));
+        }
+        for (name, sink) in &self.sinks {
+            let _ = writeln!(
+                out,
+                "  Endpoint {}: {} queued, {} delivered, {} retried, {} dropped, {} failed",
+                name, sink.queued, sink.delivered, sink.retried, sink.dropped, sink.failed
+            );
+        }
+
+        if self.recent_errors.is_empty() {
+            let _ = writeln!(out, "  Recent errors:    none");
+        } else {
+            let _ = writeln!(out, "  Recent errors:");
+            for error in &self.recent_errors {
+                let _ = writeln!(out, "    {}  {}", error.at, error.message);
+            }
+        }
+        out
+    }
+}
+
+/// Shared, cheaply cloneable handle the agent updates and the status server reads
+#[derive(Clone)]
+pub struct StatusHandle {
+    inner: Arc<RwLock<AgentStatus>>,
+}
+
+impl StatusHandle {
+    pub fn new(hostname: String) -> Self {
+        Self {
+            inner: Arc::new(RwLock::new(AgentStatus::new(hostname))),
+        }
+    }
+
+    pub fn update<F: FnOnce(&mut AgentStatus)>(&self, f: F) {
+        if let Ok(mut status) = self.inner.write() {
+            f(&mut status);
+        }
+    }
+
+    /// Remember an error for `sentinel-agent status`, keeping only the most recent ones
+    pub fn record_error(&self, message: impl Into<String>) {
+        let message = message.into();
+        self.update(|status| {
+            status.recent_errors.push_back(RecentError {
+                at: Utc::now().to_rfc3339(),
+                message,
+            });
+            while status.recent_errors.len() > MAX_RECENT_ERRORS {
+                status.recent_errors.pop_front();
+            }
+        });
+    }
+
+    pub fn snapshot(&self) -> AgentStatus {
+        match self.inner.read() {
+            Ok(status) => status.clone(),
+            Err(poisoned) => poisoned.into_inner().clone(),
+        }
+    }
+}
+
+/// Serve `GET /status` on the given listener until the task is dropped
+/// Bind `address` and serve status requests; only returns if binding fails
+///
+/// With `profiling`, CPU and heap profiles are also served under `/debug/pprof/`.
+pub async fn serve(address: String, status: StatusHandle, profiling: bool) -> Result<(), String> {
+    let listener = TcpListener::bind(&address)
+        .await
+        .map_err(|e| format!("failed to bind status endpoint {}: {}", address, e))?;
+    serve_listener(listener, status, profiling).await
+}
+
+async fn serve_listener(
+    listener: TcpListener,
+    status: StatusHandle,
+    profiling: bool,
+) -> Result<(), String> {
+    if let Ok(address) = listener.local_addr() {
+        info!(address = %address, "Status endpoint listening");
+    }
+
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                let status = status.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = handle_connection(stream, &status, profiling).await {
+                        debug!(error = %e, "Status request failed");
+                    }
+                });
+            }
+            Err(e) => {
+                warn!(error = %e, "Failed to accept status connection");
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            }
+        }
+    }
+}
+
+async fn handle_connection(
+    mut stream: TcpStream,
+    status: &StatusHandle,
+    profiling: bool,
+) -> std::io::Result<()> {
+    let mut request = Vec::new();
+    let mut chunk = [0u8; 1024];
+
+    // Only the request line matters; read until the end of the headers
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") && request.len() < MAX_REQUEST_BYTES {
+        let read = stream.read(&mut chunk).await?;
+        if read == 0 {
+            break;
+        }
+        request.extend_from_slice(&chunk[..read]);
+    }
+
+    let request = String::from_utf8_lossy(&request);
+    let mut parts = request.lines().next().unwrap_or("").split_whitespace();
+    let (method, target) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+
+    let response = match (method, path) {
+        ("GET", "/status") => match serde_json::to_string(&status.snapshot()) {
+            Ok(body) => Response::json("200 OK", body),
+            Err(e) => Response::error("500 Internal Server Error", &e.to_string()),
+        },
+        ("GET", "/debug/pprof/profile") if profiling => {
+            let seconds = query_param(query, "seconds")
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(profiling::DEFAULT_CPU_PROFILE_SECONDS)
+                .clamp(1, profiling::MAX_CPU_PROFILE_SECONDS);
+            profile_response(profiling::cpu_profile(seconds).await)
+        }
+        ("GET", "/debug/pprof/heap") if profiling => profile_response(profiling::heap_profile()),
+        ("GET", _) => Response::error("404 Not Found", "not found"),
+        _ => Response::error("405 Method Not Allowed", "method not allowed"),
+    };
+
+    let head = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.code,
+        response.content_type,
+        response.body.len()
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(&response.body).await?;
+    stream.shutdown().await
+}
+
+struct Response {
+    code: &'static str,
+    content_type: &'static str,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn json(code: &'static str, body: String) -> Self {
+        Self { code, content_type: "application/json", body: body.into_bytes() }
+    }
+
+    fn error(code: &'static str, message: &str) -> Self {
+        Self::json(code, serde_json::json!({ "error": message }).to_string())
+    }
+}
+
+fn profile_response(result: Result<Vec<u8>, profiling::ProfilingError>) -> Response {
+    match result {
+        Ok(body) => Response {
+            code: "200 OK",
+            content_type: "application/octet-stream",
+            body,
+        },
+        Err(e) => Response::error("500 Internal Server Error", &e.to_string()),
+    }
+}
+
+fn query_param<'a>(query: &'a str, name: &str) -> Option<&'a str> {
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+/// Query a running agent's status endpoint
+pub async fn fetch(address: &str) -> Result<AgentStatus, StatusError> {
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(5))
+        .build()
+        .map_err(|e| StatusError::Request(e.to_string()))?;
+
+    let response = client
+        .get(format!("http://{}/status", address))
+        .send()
+        .await
+        .map_err(|e| StatusError::Unreachable {
+            address: address.to_string(),
+            error: e.to_string(),
+        })?;
+
+    if !response.status().is_success() {
+        return Err(StatusError::Request(format!(
+            "status endpoint returned {}",
+            response.status()
+        )));
+    }
+
+    response
+        .json()
+        .await
+        .map_err(|e| StatusError::Request(e.to_string()))
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum StatusError {
+    #[error("Agent is not running or status endpoint is unreachable at {address}: {error}")]
+    Unreachable { address: String, error: String },
+    #[error("Status request failed: {0}")]
+    Request(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_error_keeps_most_recent() {
+        let handle = StatusHandle::new("test-host".to_string());
+        for i in 0..(MAX_RECENT_ERRORS + 5) {
+            handle.record_error(format!("error {}", i));
+        }
+
+        let status = handle.snapshot();
+        assert_eq!(status.recent_errors.len(), MAX_RECENT_ERRORS);
+        assert_eq!(status.recent_errors.front().unwrap().message, "error 5");
+    }
+
+    #[tokio::test]
+    async fn test_server_roundtrip() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+
+        let handle = StatusHandle::new("test-host".to_string());
+        handle.update(|status| {
+            status.registered = true;
+            status.resource_id = Some("res-123".to_string());
+            status.buffer_depth = 7;
+        });
+        handle.record_error("Failed to flush metrics");
+
+        let server = tokio::spawn(serve_listener(listener, handle, false));
+        let status = fetch(&address).await.unwrap();
+        server.abort();
+
+        assert_eq!(status.hostname, "test-host");
+        assert_eq!(status.resource_id.as_deref(), Some("res-123"));
+        assert_eq!(status.buffer_depth, 7);
+        assert_eq!(status.recent_errors.len(), 1);
+        assert!(status.render_text().contains("registered (res-123)"));
+    }
+
+    #[tokio::test]
+    async fn test_profiles_not_served_when_disabled() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+
+        let handle = StatusHandle::new("test-host".to_string());
+        let server = tokio::spawn(serve_listener(listener, handle, false));
+        let response = reqwest::get(format!("http://{}/debug/pprof/heap", address))
+            .await
+            .unwrap();
+        server.abort();
+
+        assert_eq!(response.status(), reqwest::StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn test_query_param() {
+        assert_eq!(query_param("seconds=10&debug=1", "seconds"), Some("10"));
+        assert_eq!(query_param("debug=1", "seconds"), None);
+        assert_eq!(query_param("", "seconds"), None);
+    }
+
+    #[tokio::test]
+    async fn test_fetch_unreachable() {
+        // Bind and drop to get a port nothing listens on
+        let address = {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            listener.local_addr().unwrap().to_string()
+        };
+
+        let result = fetch(&address).await;
+        assert!(matches!(result, Err(StatusError::Unreachable { .. })));
+    }
+}
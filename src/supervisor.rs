@@ -0,0 +1,222 @@
+use std::future::Future;
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, Duration, Instant};
+use tracing::{error, info, warn};
+
+use crate::status::StatusHandle;
+
+/// Delay before the first restart; doubles on each consecutive failure
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+/// A task that ran this long before failing is considered healthy, resetting the backoff
+const HEALTHY_RUN: Duration = Duration::from_secs(60);
+
+/// Run `task` in its own tokio task, restarting it with backoff when it errors or panics
+///
+/// `task` is called again for every restart. A task returning `Ok(())` has finished
+/// deliberately and is not restarted. Aborting the returned handle stops supervision.
+pub fn supervise<F, Fut>(name: &'static str, status: StatusHandle, mut task: F) -> JoinHandle<()>
+where
+    F: FnMut() -> Fut + Send + 'static,
+    Fut: Future<Output = Result<(), String>> + Send + 'static,
+{
+    tokio::spawn(async move {
+        let mut backoff = INITIAL_BACKOFF;
+        loop {
+            let started = Instant::now();
+            let mut attempt = Attempt(tokio::spawn(task()));
+            let failure = match (&mut attempt.0).await {
+                Ok(Ok(())) => {
+                    info!(task = name, "Task finished");
+                    return;
+                }
+                Ok(Err(e)) => e,
+                Err(e) if e.is_panic() => format!("panicked: {}", panic_message(e.into_panic())),
+                // Runtime shutting down
+                Err(_) => return,
+            };
+
+            if started.elapsed() >= HEALTHY_RUN {
+                backoff = INITIAL_BACKOFF;
+            }
+            record_failure(name, &status, &failure, backoff);
+
+            sleep(backoff).await;
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+            warn!(task = name, "Restarting task");
+        }
+    })
+}
+
+/// The running attempt of a supervised task, aborted when supervision stops
+///
+/// Without this, aborting the supervisor would leave the attempt running
+/// detached, e.g. heartbeats under a resource ID that was replaced.
+struct Attempt<T>(JoinHandle<T>);
+
+impl<T> Drop for Attempt<T> {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+fn record_failure(name: &'static str, status: &StatusHandle, failure: &str, backoff: Duration) {
+    error!(
+        task = name,
+        error = %failure,
+        restart_in_seconds = backoff.as_secs(),
+        "Task failed, restarting"
+    );
+    status.record_error(format!("Task {} failed: {}", name, failure));
+    status.update(|status| *status.task_restarts.entry(name.to_string()).or_default() += 1);
+}
+
+/// Supervision for work the agent loop runs itself, such as collection and flushing
+///
+/// That work needs the agent's state, so it cannot be spawned; instead each
+/// run is awaited in place with panics caught. A panic is reported and counted
+/// like a task restart, and the work is skipped until the backoff has passed.
+pub struct InlineTask {
+    name: &'static str,
+    status: StatusHandle,
+    backoff: Duration,
+    last_failure: Option<Instant>,
+    resume_at: Option<Instant>,
+}
+
+impl InlineTask {
+    pub fn new(name: &'static str, status: StatusHandle) -> Self {
+        Self {
+            name,
+            status,
+            backoff: INITIAL_BACKOFF,
+            last_failure: None,
+            resume_at: None,
+        }
+    }
+
+    /// Whether the backoff after the last panic has passed
+    pub fn is_ready(&self) -> bool {
+        self.resume_at.is_none_or(|at| Instant::now() >= at)
+    }
+
+    /// Run `work` to completion; `None` when it panicked
+    pub async fn run<T>(&mut self, work: impl Future<Output = T>) -> Option<T> {
+        match (CatchUnwind { future: Box::pin(work) }).await {
+            Ok(value) => {
+                self.resume_at = None;
+                Some(value)
+            }
+            Err(payload) => {
+                let now = Instant::now();
+                if self.last_failure.is_some_and(|last| now - last >= HEALTHY_RUN) {
+                    self.backoff = INITIAL_BACKOFF;
+                }
+                let failure = format!("panicked: {}", panic_message(payload));
+                record_failure(self.name, &self.status, &failure, self.backoff);
+                self.last_failure = Some(now);
+                self.resume_at = Some(now + self.backoff);
+                self.backoff = (self.backoff * 2).min(MAX_BACKOFF);
+                None
+            }
+        }
+    }
+}
+
+/// Polls `future`, turning a panic into an error
+struct CatchUnwind<F> {
+    future: Pin<Box<F>>,
+}
+
+impl<F: Future> Future for CatchUnwind<F> {
+    type Output = Result<F::Output, Box<dyn std::any::Any + Send>>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let future = self.future.as_mut();
+        match panic::catch_unwind(AssertUnwindSafe(|| future.poll(cx))) {
+            Ok(poll) => poll.map(Ok),
+            Err(payload) => Poll::Ready(Err(payload)),
+        }
+    }
+}
+
+/// Best-effort text of a panic payload
+pub fn panic_message(payload: Box<dyn std::any::Any + Send>) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown panic".to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_restarts_failed_and_panicked_tasks() {
+        let status = StatusHandle::new("test-host".to_string());
+        let runs = Arc::new(AtomicUsize::new(0));
+
+        let counter = runs.clone();
+        let handle = supervise("flaky", status.clone(), move || {
+            let run = counter.fetch_add(1, Ordering::SeqCst);
+            async move {
+                match run {
+                    0 => Err("connection reset".to_string()),
+                    1 => panic!("boom"),
+                    _ => Ok(()),
+                }
+            }
+        });
+        handle.await.unwrap();
+
+        assert_eq!(runs.load(Ordering::SeqCst), 3);
+        let snapshot = status.snapshot();
+        assert_eq!(snapshot.task_restarts.get("flaky"), Some(&2));
+        assert!(snapshot.recent_errors[1].message.contains("panicked: boom"));
+    }
+
+    #[tokio::test]
+    async fn test_abort_stops_the_running_attempt() {
+        let status = StatusHandle::new("test-host".to_string());
+        let (sender, mut receiver) = tokio::sync::mpsc::channel::<()>(1);
+
+        let handle = supervise("endless", status, move || {
+            let sender = sender.clone();
+            async move {
+                let _sender = sender;
+                std::future::pending::<()>().await;
+                Ok(())
+            }
+        });
+        tokio::task::yield_now().await;
+        handle.abort();
+
+        // Every sender is gone once the attempt is aborted with its supervisor
+        let closed = tokio::time::timeout(Duration::from_secs(5), receiver.recv()).await;
+        assert_eq!(closed, Ok(None));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_inline_task_catches_panics_and_backs_off() {
+        let status = StatusHandle::new("test-host".to_string());
+        let mut task = InlineTask::new("flusher", status.clone());
+
+        assert_eq!(task.run(async { 1 }).await, Some(1));
+        assert_eq!(task.run(async { panic!("boom") }).await, None::<()>);
+        assert!(!task.is_ready());
+        assert_eq!(status.snapshot().task_restarts.get("flusher"), Some(&1));
+
+        sleep(INITIAL_BACKOFF).await;
+        assert!(task.is_ready());
+    }
+}
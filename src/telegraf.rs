@@ -0,0 +1,140 @@
+//! Telegraf (Influx) JSON metrics
+//!
+//! The format of Telegraf's `json` serializer, as produced by `exec` scripts
+//! written for Telegraf and by its `socket_writer` output:
+//!
+//! ```json
+//! {"name": "raid", "tags": {"array": "md0"}, "fields": {"degraded": 0, "sync_percent": 100}, "timestamp": 1700000000}
+//! ```
+//!
+//! Documents are single metrics or `{"metrics": [...]}` batches, one after
+//! another. Every numeric or boolean field becomes a sample named
+//! `<name>_<field>` (just `<name>` for a field called `value`), with the tags
+//! as labels, as Telegraf's Prometheus output names them; string fields are
+//! dropped. Timestamps may be in seconds, milliseconds, microseconds or
+//! nanoseconds.
+
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use thiserror::Error;
+
+use crate::metrics::{Sample, SampleKind};
+
+#[derive(Error, Debug)]
+#[error("invalid Telegraf JSON: {0}")]
+pub struct TelegrafError(#[from] serde_json::Error);
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Document {
+    Batch { metrics: Vec<Metric> },
+    Single(Metric),
+}
+
+#[derive(Deserialize)]
+struct Metric {
+    name: String,
+    #[serde(default)]
+    tags: BTreeMap<String, String>,
+    fields: BTreeMap<String, serde_json::Value>,
+    /// Unix time in the unit of Telegraf's `json_timestamp_units`
+    timestamp: Option<f64>,
+}
+
+/// Samples of every document in `text`; metrics without a timestamp get `timestamp` (Unix seconds)
+pub fn parse(text: &str, timestamp: u64) -> Result<Vec<Sample>, TelegrafError> {
+    let mut samples = Vec::new();
+    for document in serde_json::Deserializer::from_str(text).into_iter::<Document>() {
+        let metrics = match document? {
+            Document::Batch { metrics } => metrics,
+            Document::Single(metric) => vec![metric],
+        };
+        for metric in metrics {
+            let metric_timestamp = metric.timestamp.map(seconds).unwrap_or(timestamp);
+            let labels: BTreeMap<String, String> = metric
+                .tags
+                .into_iter()
+                .map(|(name, value)| (sanitize(&name, false), value))
+                .collect();
+            for (field, value) in metric.fields {
+                let value = match value {
+                    serde_json::Value::Number(number) => number.as_f64(),
+                    serde_json::Value::Bool(value) => Some(if value { 1.0 } else { 0.0 }),
+                    _ => None,
+                };
+                let Some(value) = value else {
+                    continue;
+                };
+                let name = if field == "value" {
+                    sanitize(&metric.name, true)
+                } else {
+                    sanitize(&format!("{}_{}", metric.name, field), true)
+                };
+                samples.push(Sample {
+                    name,
+                    labels: labels.clone(),
+                    value,
+                    timestamp: metric_timestamp,
+                    kind: SampleKind::Untyped,
+                    exemplar: None,
+                });
+            }
+        }
+    }
+    Ok(samples)
+}
+
+/// Unix seconds from a timestamp in any of Telegraf's units, told apart by magnitude
+fn seconds(timestamp: f64) -> u64 {
+    let divisor = match timestamp.abs() {
+        t if t < 1e11 => 1.0,
+        t if t < 1e14 => 1e3,
+        t if t < 1e17 => 1e6,
+        _ => 1e9,
+    };
+    (timestamp / divisor) as u64
+}
+
+/// Replace characters Prometheus does not allow in metric (or label) names with `_`
+fn sanitize(name: &str, metric: bool) -> String {
+    let mut sanitized: String = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '_' || (metric && c == ':') { c } else { '_' })
+        .collect();
+    if sanitized.is_empty() || sanitized.starts_with(|c: char| c.is_ascii_digit()) {
+        sanitized.insert(0, '_');
+    }
+    sanitized
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_maps_fields_to_samples() {
+        let text = r#"
+{"name": "raid", "tags": {"array": "md0", "dc-zone": "a"}, "fields": {"degraded": false, "sync_percent": 99.5, "state": "clean"}, "timestamp": 1700000000000}
+{"metrics": [
+  {"name": "queue.depth", "fields": {"value": 12}},
+  {"name": "backup", "fields": {"ok": true}, "timestamp": 1700000000000000000}
+]}
+"#;
+        let samples = parse(text, 100).unwrap();
+        let names: Vec<&str> = samples.iter().map(|sample| sample.name.as_str()).collect();
+        assert_eq!(names, ["raid_degraded", "raid_sync_percent", "queue_depth", "backup_ok"]);
+
+        assert_eq!(samples[0].value, 0.0);
+        assert_eq!(samples[0].labels["dc_zone"], "a");
+        assert_eq!(samples[0].timestamp, 1_700_000_000);
+        assert_eq!(samples[1].value, 99.5);
+        assert_eq!(samples[2].value, 12.0);
+        assert_eq!(samples[2].timestamp, 100);
+        assert_eq!(samples[3].value, 1.0);
+        assert_eq!(samples[3].timestamp, 1_700_000_000);
+
+        assert!(parse("", 100).unwrap().is_empty());
+        assert!(parse(r#"{"name": "raid"}"#, 100).is_err());
+        assert!(parse("raid,array=md0 degraded=0", 100).is_err());
+    }
+}
@@ -0,0 +1,191 @@
+//! node_exporter textfile collector compatibility
+//!
+//! Every `*.prom` file in `collection.textfile.directory` is read on each
+//! collection, as node_exporter's `--collector.textfile.directory` does, so
+//! cron jobs that write textfiles keep working after the switch. `*.prom`
+//! files are Prometheus text format; `*.om` files are OpenMetrics. As in
+//! node_exporter, a file that does not parse or whose samples carry their own
+//! timestamps is skipped whole, each file read is reported as
+//! `node_textfile_mtime_seconds{file="<path>"}`, and `node_textfile_scrape_error`
+//! is 1 when the directory or any file could not be read.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+use tracing::warn;
+
+use crate::exposition::{self, Format, ParseError, Timestamps};
+use crate::metrics::{CollectContext, Sample, SampleKind};
+
+#[derive(Error, Debug)]
+pub enum TextfileError {
+    #[error("failed to read textfile directory {path}: {message}")]
+    Directory { path: PathBuf, message: String },
+    #[error("failed to read textfile {path}: {message}")]
+    Read { path: PathBuf, message: String },
+    #[error("failed to parse textfile {path}: {source}")]
+    Parse { path: PathBuf, source: ParseError },
+}
+
+pub struct TextfileCollector {
+    directory: PathBuf,
+}
+
+impl TextfileCollector {
+    pub fn new(directory: PathBuf) -> Self {
+        Self { directory }
+    }
+
+    /// Samples of every readable file, followed by the mtime and error samples
+    pub fn collect(&self, ctx: &CollectContext) -> Vec<Sample> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let mut samples = Vec::new();
+        let mut mtimes = Vec::new();
+        let mut failed = false;
+
+        match textfiles(&self.directory) {
+            Ok(paths) => {
+                for path in paths.iter().take_while(|_| !ctx.is_cancelled()) {
+                    match read_file(path, timestamp) {
+                        Ok((file_samples, mtime)) => {
+                            samples.extend(file_samples);
+                            mtimes.push(gauge(
+                                "node_textfile_mtime_seconds",
+                                Some(path),
+                                mtime,
+                                timestamp,
+                            ));
+                        }
+                        Err(e) => {
+                            warn!(error = %e, "Skipping textfile");
+                            failed = true;
+                        }
+                    }
+                }
+            }
+            Err(e) => {
+                warn!(error = %e, "Textfile collector cannot read its directory");
+                failed = true;
+            }
+        }
+
+        samples.extend(mtimes);
+        samples.push(gauge(
+            "node_textfile_scrape_error",
+            None,
+            if failed { 1.0 } else { 0.0 },
+            timestamp,
+        ));
+        samples
+    }
+}
+
+/// `*.prom` and `*.om` files in the directory, in name order
+fn textfiles(directory: &Path) -> Result<Vec<PathBuf>, TextfileError> {
+    let entries = fs::read_dir(directory).map_err(|e| TextfileError::Directory {
+        path: directory.to_path_buf(),
+        message: e.to_string(),
+    })?;
+    let mut paths: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|extension| extension == "prom" || extension == "om"))
+        .filter(|path| path.is_file())
+        .collect();
+    paths.sort();
+    Ok(paths)
+}
+
+/// A file's samples and its modification time in Unix seconds
+fn read_file(path: &Path, timestamp: u64) -> Result<(Vec<Sample>, f64), TextfileError> {
+    let read_error = |e: std::io::Error| TextfileError::Read {
+        path: path.to_path_buf(),
+        message: e.to_string(),
+    };
+    let modified = fs::metadata(path).and_then(|metadata| metadata.modified()).map_err(read_error)?;
+    let contents = fs::read_to_string(path).map_err(read_error)?;
+    let format = match path.extension() {
+        Some(extension) if extension == "om" => Format::OpenMetrics,
+        _ => Format::Prometheus,
+    };
+    let samples = exposition::parse(&contents, format, timestamp, Timestamps::Reject).map_err(|source| {
+        TextfileError::Parse {
+            path: path.to_path_buf(),
+            source,
+        }
+    })?;
+    let mtime = modified.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() as f64;
+    Ok((samples, mtime))
+}
+
+fn gauge(name: &str, file: Option<&Path>, value: f64, timestamp: u64) -> Sample {
+    Sample {
+        name: name.to_string(),
+        labels: file
+            .map(|file| [("file".to_string(), file.display().to_string())].into())
+            .unwrap_or_default(),
+        value,
+        timestamp,
+        kind: SampleKind::Gauge,
+        exemplar: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_reads_prom_files_and_skips_bad_ones() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(
+            dir.path().join("backup.prom"),
+            "# TYPE backup_age_seconds gauge\nbackup_age_seconds{job=\"db\"} 42\n",
+        )
+        .unwrap();
+        fs::write(dir.path().join("apt.prom"), "apt_upgrades_pending 3\n").unwrap();
+        fs::write(dir.path().join("jobs.om"), "# TYPE jobs counter\njobs_total 7\n# EOF\n").unwrap();
+        // Client-side timestamps make node_exporter skip the whole file, and so do we
+        fs::write(dir.path().join("stale.prom"), "stale_metric 1 1700000000000\n").unwrap();
+        fs::write(dir.path().join("notes.txt"), "not_a_metric 1\n").unwrap();
+
+        let collector = TextfileCollector::new(dir.path().to_path_buf());
+        let samples = collector.collect(&CollectContext::new(Duration::from_secs(5)));
+        let names: Vec<&str> = samples.iter().map(|sample| sample.name.as_str()).collect();
+        assert_eq!(
+            names,
+            [
+                "apt_upgrades_pending",
+                "backup_age_seconds",
+                "jobs_total",
+                "node_textfile_mtime_seconds",
+                "node_textfile_mtime_seconds",
+                "node_textfile_mtime_seconds",
+                "node_textfile_scrape_error",
+            ]
+        );
+        assert_eq!(samples[1].kind, SampleKind::Gauge);
+        assert_eq!(samples[1].labels["job"], "db");
+        // OpenMetrics names the counter family without `_total`
+        assert_eq!(samples[2].kind, SampleKind::Counter);
+        assert_eq!(
+            samples[3].labels["file"],
+            dir.path().join("apt.prom").display().to_string()
+        );
+        assert!(samples[3].value > 0.0);
+        assert_eq!(samples[6].value, 1.0);
+
+        fs::remove_file(dir.path().join("stale.prom")).unwrap();
+        let samples = collector.collect(&CollectContext::new(Duration::from_secs(5)));
+        assert_eq!(samples.last().unwrap().value, 0.0);
+
+        let missing = TextfileCollector::new(dir.path().join("missing"));
+        let samples = missing.collect(&CollectContext::new(Duration::from_secs(5)));
+        assert_eq!(samples.len(), 1);
+        assert_eq!(samples[0].value, 1.0);
+    }
+}
@@ -0,0 +1,100 @@
+//! Severity of metrics against the `thresholds` config
+//!
+//! A value takes a severity once it has stayed above that level for the
+//! threshold's `duration_seconds`, so short spikes do not count. Disk metrics
+//! carry their severity to the platform, and alert rules with `severity:` fire
+//! on it.
+
+use std::collections::HashMap;
+
+use crate::config::{AlertMetric, Severity, ThresholdsConfig};
+use crate::metrics::DiskMetric;
+
+pub struct ThresholdTracker {
+    config: ThresholdsConfig,
+    /// When each subject went above each level and has stayed there since
+    breaches: HashMap<(AlertMetric, String, Severity), u64>,
+}
+
+impl ThresholdTracker {
+    pub fn new(config: ThresholdsConfig) -> Self {
+        Self {
+            config,
+            breaches: HashMap::new(),
+        }
+    }
+
+    /// Severity of `value` sampled at `timestamp` (seconds), tracking how long it has been over each level
+    pub fn observe(&mut self, metric: AlertMetric, subject: &str, value: f64, timestamp: u64) -> Option<Severity> {
+        let threshold = *self.config.get(metric)?;
+        let mut severity = None;
+        for level in [Severity::Warn, Severity::Critical] {
+            let key = (metric, subject.to_string(), level);
+            match threshold.level(level) {
+                Some(limit) if value > limit => {
+                    let since = *self.breaches.entry(key).or_insert(timestamp);
+                    if timestamp.saturating_sub(since) >= threshold.get_duration_seconds() {
+                        severity = Some(level);
+                    }
+                }
+                _ => {
+                    self.breaches.remove(&key);
+                }
+            }
+        }
+        severity
+    }
+
+    /// Set the severity of each disk metric from its usage
+    pub fn annotate(&mut self, metrics: &mut [DiskMetric]) {
+        for metric in metrics {
+            metric.severity = self.observe(
+                AlertMetric::DiskUsage,
+                &metric.mount_point,
+                metric.usage_percentage,
+                metric.timestamp,
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Threshold;
+
+    fn tracker(duration_seconds: u64) -> ThresholdTracker {
+        ThresholdTracker::new(ThresholdsConfig {
+            disk_usage: Some(Threshold {
+                warn: Some(0.8),
+                critical: Some(0.9),
+                duration_seconds: Some(duration_seconds),
+            }),
+            failed_systemd_units: None,
+        })
+    }
+
+    #[test]
+    fn test_levels_without_duration() {
+        let mut tracker = tracker(0);
+        assert_eq!(tracker.observe(AlertMetric::DiskUsage, "/", 0.5, 100), None);
+        assert_eq!(tracker.observe(AlertMetric::DiskUsage, "/", 0.85, 100), Some(Severity::Warn));
+        assert_eq!(tracker.observe(AlertMetric::DiskUsage, "/", 0.95, 100), Some(Severity::Critical));
+        assert_eq!(tracker.observe(AlertMetric::FailedSystemdUnits, "systemd", 5.0, 100), None);
+    }
+
+    #[test]
+    fn test_breach_must_last() {
+        let mut tracker = tracker(300);
+        assert_eq!(tracker.observe(AlertMetric::DiskUsage, "/", 0.95, 1_000), None);
+        assert_eq!(tracker.observe(AlertMetric::DiskUsage, "/", 0.95, 1_200), None);
+        assert_eq!(tracker.observe(AlertMetric::DiskUsage, "/", 0.95, 1_300), Some(Severity::Critical));
+
+        // Dropping below critical restarts its clock, but the warning has lasted
+        assert_eq!(tracker.observe(AlertMetric::DiskUsage, "/", 0.85, 1_360), Some(Severity::Warn));
+        assert_eq!(tracker.observe(AlertMetric::DiskUsage, "/", 0.95, 1_420), Some(Severity::Warn));
+
+        // Subjects are tracked separately
+        assert_eq!(tracker.observe(AlertMetric::DiskUsage, "/data", 0.95, 1_420), None);
+    }
+}
@@ -0,0 +1,237 @@
+//! TLS for outbound HTTPS
+//!
+//! `api.tls` builds one rustls configuration that every HTTPS client of the
+//! agent uses: the API client, alert webhooks and AWS secret lookups. Scrape
+//! targets get the same settings without the client certificate. Instance
+//! metadata and the local status endpoint are plain HTTP and unaffected.
+
+use std::io::BufReader;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::Path;
+use std::sync::Arc;
+use thiserror::Error;
+
+use crate::config::{TlsConfig, TlsVersion};
+
+#[derive(Error, Debug)]
+pub enum TlsError {
+    #[error("failed to read {path}: {message}")]
+    File { path: String, message: String },
+    #[error("unknown TLS cipher suite {0}")]
+    UnknownCipher(String),
+    #[error("invalid TLS settings: {0}")]
+    Invalid(String),
+}
+
+/// IANA name of a cipher suite; rustls spells TLS 1.3 suites `TLS13_*`
+fn suite_name(suite: &rustls::SupportedCipherSuite) -> String {
+    format!("{:?}", suite.suite()).replacen("TLS13_", "TLS_", 1)
+}
+
+fn read_pem(path: &Path) -> Result<Vec<rustls_pemfile::Item>, TlsError> {
+    let file_error = |message: String| TlsError::File {
+        path: path.display().to_string(),
+        message,
+    };
+    let file = std::fs::File::open(path).map_err(|e| file_error(e.to_string()))?;
+    let items =
+        rustls_pemfile::read_all(&mut BufReader::new(file)).map_err(|e| file_error(e.to_string()))?;
+    if items.is_empty() {
+        return Err(file_error("no PEM data found".to_string()));
+    }
+    Ok(items)
+}
+
+fn certificates(path: &Path) -> Result<Vec<rustls::Certificate>, TlsError> {
+    Ok(read_pem(path)?
+        .into_iter()
+        .filter_map(|item| match item {
+            rustls_pemfile::Item::X509Certificate(der) => Some(rustls::Certificate(der)),
+            _ => None,
+        })
+        .collect())
+}
+
+fn private_key(path: &Path) -> Result<rustls::PrivateKey, TlsError> {
+    read_pem(path)?
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::PKCS8Key(der)
+            | rustls_pemfile::Item::RSAKey(der)
+            | rustls_pemfile::Item::ECKey(der) => Some(rustls::PrivateKey(der)),
+            _ => None,
+        })
+        .ok_or_else(|| TlsError::File {
+            path: path.display().to_string(),
+            message: "no private key found".to_string(),
+        })
+}
+
+/// Build the rustls configuration for `tls`, reading certificate files
+pub fn client_config(tls: &TlsConfig) -> Result<rustls::ClientConfig, TlsError> {
+    let suites = match &tls.ciphers {
+        Some(names) => names
+            .iter()
+            .map(|name| {
+                rustls::ALL_CIPHER_SUITES
+                    .iter()
+                    .find(|suite| suite_name(suite).eq_ignore_ascii_case(name))
+                    .copied()
+                    .ok_or_else(|| TlsError::UnknownCipher(name.clone()))
+            })
+            .collect::<Result<Vec<_>, _>>()?,
+        None => rustls::DEFAULT_CIPHER_SUITES.to_vec(),
+    };
+    let versions: &[&rustls::SupportedProtocolVersion] = match tls.get_min_version() {
+        TlsVersion::V1_2 => &[&rustls::version::TLS13, &rustls::version::TLS12],
+        TlsVersion::V1_3 => &[&rustls::version::TLS13],
+    };
+
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|anchor| {
+        rustls::OwnedTrustAnchor::from_subject_spki_name_constraints(
+            anchor.subject,
+            anchor.spki,
+            anchor.name_constraints,
+        )
+    }));
+    if let Some(path) = &tls.ca_file {
+        let certs: Vec<Vec<u8>> = certificates(path)?.into_iter().map(|cert| cert.0).collect();
+        let (_, invalid) = roots.add_parsable_certificates(&certs);
+        if invalid > 0 {
+            return Err(TlsError::File {
+                path: path.display().to_string(),
+                message: format!("{} invalid CA certificate(s)", invalid),
+            });
+        }
+    }
+
+    let builder = rustls::ClientConfig::builder()
+        .with_cipher_suites(&suites)
+        .with_safe_default_kx_groups()
+        .with_protocol_versions(versions)
+        // e.g. only TLS 1.2 suites with min_version 1.3
+        .map_err(|e| TlsError::Invalid(e.to_string()))?
+        .with_root_certificates(roots);
+
+    match (&tls.client_cert, &tls.client_key) {
+        (Some(cert), Some(key)) => builder
+            .with_client_auth_cert(certificates(cert)?, private_key(key)?)
+            .map_err(|e| TlsError::Invalid(e.to_string())),
+        _ => Ok(builder.with_no_client_auth()),
+    }
+}
+
+/// A client builder using `tls`, or reqwest's defaults without it
+pub fn client_builder(tls: Option<&TlsConfig>) -> Result<reqwest::ClientBuilder, TlsError> {
+    let builder = reqwest::Client::builder();
+    match tls {
+        Some(tls) => Ok(builder.use_preconfigured_tls(client_config(tls)?)),
+        None => Ok(builder),
+    }
+}
+
+/// Resolves the SNI override name to the endpoint's real host
+///
+/// Requests are sent to `server_name`, so it is what TLS presents and verifies,
+/// while connections still go to the addresses of the configured host.
+struct ServerNameResolver {
+    server_name: String,
+    host: String,
+}
+
+impl reqwest::dns::Resolve for ServerNameResolver {
+    fn resolve(&self, name: hyper::client::connect::dns::Name) -> reqwest::dns::Resolving {
+        // Port 0 is replaced with the URL's port by the connector
+        let lookup = if name.as_str().eq_ignore_ascii_case(&self.server_name) {
+            format!("{}:0", self.host)
+        } else {
+            format!("{}:0", name.as_str())
+        };
+        Box::pin(async move {
+            let addrs = tokio::task::spawn_blocking(move || lookup.to_socket_addrs())
+                .await
+                .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>)??;
+            Ok(Box::new(addrs.collect::<Vec<SocketAddr>>().into_iter()) as reqwest::dns::Addrs)
+        })
+    }
+}
+
+/// Apply `server_name` to a client for `endpoint`, returning the URL to send requests to
+pub fn with_server_name(
+    builder: reqwest::ClientBuilder,
+    endpoint: &str,
+    server_name: &str,
+) -> Result<(reqwest::ClientBuilder, String), TlsError> {
+    let mut url =
+        reqwest::Url::parse(endpoint).map_err(|e| TlsError::Invalid(format!("{}: {}", endpoint, e)))?;
+    let host = url
+        .host_str()
+        .ok_or_else(|| TlsError::Invalid(format!("{} has no host", endpoint)))?
+        .trim_matches(|c| c == '[' || c == ']')
+        .to_string();
+    url.set_host(Some(server_name))
+        .map_err(|e| TlsError::Invalid(format!("server_name {}: {}", server_name, e)))?;
+
+    let resolver = ServerNameResolver {
+        server_name: server_name.to_string(),
+        host,
+    };
+    let endpoint = url.as_str().trim_end_matches('/').to_string();
+    Ok((builder.dns_resolver(Arc::new(resolver)), endpoint))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cipher_names_and_versions() {
+        let tls = TlsConfig {
+            ciphers: Some(vec!["TLS_AES_256_GCM_SHA384".to_string()]),
+            min_version: Some(TlsVersion::V1_3),
+            ..Default::default()
+        };
+        assert!(client_config(&tls).is_ok());
+
+        let unknown = TlsConfig { ciphers: Some(vec!["TLS_RC4".to_string()]), ..Default::default() };
+        assert!(matches!(client_config(&unknown), Err(TlsError::UnknownCipher(_))));
+
+        // No TLS 1.3 suite left to negotiate
+        let conflicting = TlsConfig {
+            ciphers: Some(vec!["TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256".to_string()]),
+            min_version: Some(TlsVersion::V1_3),
+            ..Default::default()
+        };
+        assert!(matches!(client_config(&conflicting), Err(TlsError::Invalid(_))));
+    }
+
+    #[test]
+    fn test_missing_ca_file() {
+        let tls = TlsConfig { ca_file: Some("/nonexistent/ca.pem".into()), ..Default::default() };
+        assert!(matches!(client_config(&tls), Err(TlsError::File { .. })));
+    }
+
+    #[tokio::test]
+    async fn test_server_name_override() {
+        use wiremock::{matchers, Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        let host = format!("api.internal.example:{}", server.address().port());
+        Mock::given(matchers::method("GET"))
+            .and(matchers::header("host", host.as_str()))
+            .respond_with(ResponseTemplate::new(204))
+            .mount(&server)
+            .await;
+
+        let configured = format!("{}/", server.uri());
+        let (builder, endpoint) =
+            with_server_name(reqwest::Client::builder(), &configured, "api.internal.example").unwrap();
+        assert_eq!(endpoint, format!("http://{}", host));
+
+        // The override name resolves to the configured host
+        let client = builder.build().unwrap();
+        let response = client.get(format!("{}/health", endpoint)).send().await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::NO_CONTENT);
+    }
+}
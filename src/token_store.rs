@@ -0,0 +1,291 @@
+//! Storage of the tokens saved by `sentinel-agent login`
+//!
+//! By default the tokens sit in an owner-only (`0600`) JSON file next to the
+//! state file. With a key in `api.oauth.token_cache.encryption_key`,
+//! `SENTINEL_TOKEN_KEY` or `SENTINEL_TOKEN_KEY_FILE`, the file is encrypted
+//! (AES-256-GCM); a plaintext file is still read and encrypted on the next
+//! save. The OS credential store is opt-in: default builds leave out the
+//! `keyring` feature, since headless services often have no unlocked
+//! keyring to read from. `token_cache.path` separates tenants that share a
+//! host.
+
+use std::fmt;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use crate::config::{TokenCacheConfig, TokenStoreKind};
+use crate::encryption::{self, ConfigKey};
+use crate::oauth::{OAuthError, StoredToken};
+
+/// Tokens saved by `sentinel-agent login`, next to the state file
+pub const TOKEN_FILE_NAME: &str = "oauth-token.json";
+
+/// Environment variable holding the base64 key that encrypts the token file
+pub const TOKEN_KEY_ENV: &str = "SENTINEL_TOKEN_KEY";
+/// Environment variable naming a file that holds the token key
+pub const TOKEN_KEY_FILE_ENV: &str = "SENTINEL_TOKEN_KEY_FILE";
+
+/// Service name of keyring entries
+#[cfg(feature = "keyring")]
+const KEYRING_SERVICE: &str = "sentinel-agent";
+
+pub enum TokenStore {
+    /// Owner-only JSON file, encrypted when there is a key
+    File { path: PathBuf, key: Option<Box<ConfigKey>> },
+    /// OS credential store entry, named after the token path
+    #[cfg(feature = "keyring")]
+    Keyring { name: String, entry: keyring::Entry },
+}
+
+impl TokenStore {
+    pub fn from_settings(settings: Option<&TokenCacheConfig>) -> Result<Self, OAuthError> {
+        let settings = settings.cloned().unwrap_or_default();
+        let path = settings.path.clone().unwrap_or_else(default_path);
+
+        if settings.store == Some(TokenStoreKind::Keyring) {
+            #[cfg(feature = "keyring")]
+            {
+                let name = path.display().to_string();
+                let entry = keyring::Entry::new(KEYRING_SERVICE, &name).map_err(|e| OAuthError::TokenFile {
+                    action: "open",
+                    path: format!("keyring entry {}", name),
+                    message: e.to_string(),
+                })?;
+                return Ok(Self::Keyring { name, entry });
+            }
+            #[cfg(not(feature = "keyring"))]
+            return Err(OAuthError::TokenFile {
+                action: "open",
+                path: path.display().to_string(),
+                message: "the keyring store needs a build with the keyring feature".to_string(),
+            });
+        }
+
+        let key_error = |message: String| OAuthError::TokenFile {
+            action: "encrypt",
+            path: path.display().to_string(),
+            message,
+        };
+        let key = match &settings.encryption_key {
+            Some(encoded) => Some(ConfigKey::parse(encoded).map_err(|e| key_error(e.to_string()))?),
+            None => ConfigKey::from_env_vars(TOKEN_KEY_ENV, TOKEN_KEY_FILE_ENV).map_err(|e| key_error(e.to_string()))?,
+        };
+        Ok(Self::File {
+            path,
+            key: key.map(Box::new),
+        })
+    }
+
+    /// The saved tokens, `None` before the first login
+    pub fn load(&self) -> Result<Option<StoredToken>, OAuthError> {
+        let contents = match self {
+            Self::File { path, key } => match read_file(path, key.as_deref())? {
+                Some(contents) => contents,
+                None => return Ok(None),
+            },
+            #[cfg(feature = "keyring")]
+            Self::Keyring { entry, .. } => match entry.get_password() {
+                Ok(contents) => contents,
+                Err(keyring::Error::NoEntry) => return Ok(None),
+                Err(e) => return Err(self.error("read", e.to_string())),
+            },
+        };
+        serde_json::from_str(&contents)
+            .map(Some)
+            .map_err(|e| self.error("parse", e.to_string()))
+    }
+
+    pub fn save(&self, token: &StoredToken) -> Result<(), OAuthError> {
+        let contents = serde_json::to_string(token).map_err(|e| self.error("write", e.to_string()))?;
+        match self {
+            Self::File { path, key } => {
+                let contents = match key {
+                    Some(key) => key.encrypt(&contents),
+                    None => contents,
+                };
+                write_file(path, &contents).map_err(|e| self.error("write", e.to_string()))
+            }
+            #[cfg(feature = "keyring")]
+            Self::Keyring { entry, .. } => entry
+                .set_password(&contents)
+                .map_err(|e| self.error("write", e.to_string())),
+        }
+    }
+
+    /// Delete the saved tokens; nothing saved is not an error
+    pub fn remove(&self) -> Result<(), OAuthError> {
+        match self {
+            Self::File { path, .. } => match fs::remove_file(path) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(self.error("remove", e.to_string())),
+                _ => Ok(()),
+            },
+            #[cfg(feature = "keyring")]
+            Self::Keyring { entry, .. } => match entry.delete_credential() {
+                Err(e) if !matches!(e, keyring::Error::NoEntry) => Err(self.error("remove", e.to_string())),
+                _ => Ok(()),
+            },
+        }
+    }
+
+    fn error(&self, action: &'static str, message: String) -> OAuthError {
+        OAuthError::TokenFile {
+            action,
+            path: self.to_string(),
+            message,
+        }
+    }
+}
+
+impl fmt::Display for TokenStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::File { path, .. } => write!(f, "{}", path.display()),
+            #[cfg(feature = "keyring")]
+            Self::Keyring { name, .. } => write!(f, "keyring entry {}", name),
+        }
+    }
+}
+
+impl fmt::Debug for TokenStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::File { path, key } => f
+                .debug_struct("File")
+                .field("path", path)
+                .field("encrypted", &key.is_some())
+                .finish(),
+            #[cfg(feature = "keyring")]
+            Self::Keyring { name, .. } => f.debug_struct("Keyring").field("name", name).finish(),
+        }
+    }
+}
+
+/// `oauth-token.json` in the directory of the state file
+///
+/// That is the directory of whoever runs the agent; see `service_path` for
+/// `login` run on behalf of a service.
+pub fn default_path() -> PathBuf {
+    crate::state::ResourceState::get_state_file_path()
+        .parent()
+        .map(Path::to_path_buf)
+        .unwrap_or_else(|| PathBuf::from("."))
+        .join(TOKEN_FILE_NAME)
+}
+
+/// `oauth-token.json` in the system state directory, where the agent service reads it
+pub fn service_path() -> PathBuf {
+    crate::paths::system_state_dir().join(TOKEN_FILE_NAME)
+}
+
+/// Contents of the token file, decrypted; `None` if there is no file
+fn read_file(path: &Path, key: Option<&ConfigKey>) -> Result<Option<String>, OAuthError> {
+    let file_error = |action, message: String| OAuthError::TokenFile {
+        action,
+        path: path.display().to_string(),
+        message,
+    };
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(file_error("read", e.to_string())),
+    };
+    if !encryption::is_encrypted(contents.trim()) {
+        return Ok(Some(contents));
+    }
+    let key = key.ok_or_else(|| {
+        file_error(
+            "decrypt",
+            format!("the file is encrypted; set {} or {}", TOKEN_KEY_ENV, TOKEN_KEY_FILE_ENV),
+        )
+    })?;
+    key.decrypt(contents.trim())
+        .map(Some)
+        .map_err(|e| file_error("decrypt", e.to_string()))
+}
+
+/// Write through a temp file that is created owner-only
+///
+/// A leftover temp file is removed first rather than reused, since reopening
+/// it would keep whatever mode it had.
+fn write_file(path: &Path, contents: &str) -> std::io::Result<()> {
+    if let Some(parent) = path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+        fs::create_dir_all(parent)?;
+    }
+    let mut temp_name = path.file_name().unwrap_or_default().to_os_string();
+    temp_name.push(".tmp");
+    let temp_path = path.with_file_name(temp_name);
+    match fs::remove_file(&temp_path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(&temp_path)?;
+    file.write_all(contents.as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&temp_path, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encrypted_token_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("tenant-a").join(TOKEN_FILE_NAME);
+        let token = StoredToken {
+            access_token: "tok-1".to_string(),
+            refresh_token: Some("ref-1".to_string()),
+            expires_at: 1_700_000_000,
+            clock_offset: 0,
+        };
+
+        // A plaintext file from before encryption was turned on is still read
+        TokenStore::File { path: path.clone(), key: None }.save(&token).unwrap();
+        let settings = TokenCacheConfig {
+            store: None,
+            path: Some(path.clone()),
+            encryption_key: Some(ConfigKey::generate()),
+        };
+        let store = TokenStore::from_settings(Some(&settings)).unwrap();
+        assert_eq!(store.load().unwrap(), Some(token.clone()));
+
+        store.save(&token).unwrap();
+        let contents = fs::read_to_string(&path).unwrap();
+        assert!(encryption::is_encrypted(contents.trim()));
+        assert!(!contents.contains("ref-1"));
+        assert_eq!(store.load().unwrap(), Some(token.clone()));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        }
+
+        // A stale temp file readable by others is not reused
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let temp_path = path.with_file_name(format!("{}.tmp", TOKEN_FILE_NAME));
+            fs::write(&temp_path, "").unwrap();
+            fs::set_permissions(&temp_path, fs::Permissions::from_mode(0o644)).unwrap();
+            store.save(&token).unwrap();
+            assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        }
+
+        // Without the key the file cannot be read
+        assert!(matches!(
+            TokenStore::File { path: path.clone(), key: None }.load(),
+            Err(OAuthError::TokenFile { action: "decrypt", .. })
+        ));
+        store.remove().unwrap();
+        store.remove().unwrap();
+        assert!(store.load().unwrap().is_none());
+    }
+}
@@ -0,0 +1,258 @@
+//! Durations and sizes in the config file
+//!
+//! Settings named `*_seconds`, `*_ms` and `*_mb` take either a plain integer
+//! in that unit, as always, or a string with its own unit such as `30s`,
+//! `5m`, `1h30m`, `250ms` or `2GiB`. The value must convert to a whole number
+//! of the setting's unit, so `1500ms` is rejected for a `*_seconds` setting
+//! and `1.5` for a `*_mb` setting rather than silently rounded.
+
+use schemars::{json_schema, Schema, SchemaGenerator};
+use serde::de::{self, Deserializer, Visitor};
+use std::fmt;
+
+const DURATION_UNITS: &[(&str, u64)] = &[
+    ("ms", 1),
+    ("s", 1_000),
+    ("m", 60_000),
+    ("h", 3_600_000),
+    ("d", 86_400_000),
+];
+
+const SIZE_UNITS: &[(&str, u64)] = &[
+    ("B", 1),
+    ("KB", 1_000),
+    ("MB", 1_000_000),
+    ("GB", 1_000_000_000),
+    ("KiB", 1 << 10),
+    ("MiB", 1 << 20),
+    ("GiB", 1 << 30),
+];
+
+const MIB: u64 = 1 << 20;
+
+/// Split `text` into `<number><unit>` parts and sum them in the smallest unit
+fn parse_units(text: &str, units: &[(&str, u64)], kind: &str) -> Result<u64, String> {
+    let invalid = || format!("invalid {} {:?}", kind, text);
+    let mut rest = text.trim();
+    if rest.is_empty() {
+        return Err(invalid());
+    }
+
+    let mut total: u64 = 0;
+    while !rest.is_empty() {
+        let digits = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+        let number: u64 = rest[..digits].parse().map_err(|_| invalid())?;
+        rest = rest[digits..].trim_start();
+        let unit_len = rest.find(|c: char| c.is_ascii_digit() || c.is_whitespace()).unwrap_or(rest.len());
+        let (_, factor) = units
+            .iter()
+            .find(|(unit, _)| *unit == &rest[..unit_len])
+            .ok_or_else(|| {
+                let names: Vec<&str> = units.iter().map(|(unit, _)| *unit).collect();
+                format!("{}, expected a number with a unit ({})", invalid(), names.join(", "))
+            })?;
+        total = number
+            .checked_mul(*factor)
+            .and_then(|value| total.checked_add(value))
+            .ok_or_else(|| format!("{} {:?} is too large", kind, text))?;
+        rest = rest[unit_len..].trim_start();
+    }
+    Ok(total)
+}
+
+/// Milliseconds in a duration such as `1m30s`
+pub fn parse_duration_ms(text: &str) -> Result<u64, String> {
+    parse_units(text, DURATION_UNITS, "duration")
+}
+
+/// Bytes in a size such as `2GiB`; `KB`/`MB`/`GB` are decimal, `KiB`/`MiB`/`GiB` binary
+pub fn parse_size_bytes(text: &str) -> Result<u64, String> {
+    parse_units(text, SIZE_UNITS, "size")
+}
+
+fn whole(value: u64, per_unit: u64, text: &str, unit: &str) -> Result<u64, String> {
+    if !value.is_multiple_of(per_unit) {
+        return Err(format!("{:?} is not a whole number of {}", text, unit));
+    }
+    Ok(value / per_unit)
+}
+
+/// An integer in the setting's unit, or a string converted by `parse`
+struct UnitVisitor {
+    parse: fn(&str) -> Result<u64, String>,
+    expecting: &'static str,
+    /// The setting's unit, for rejecting fractions
+    unit: &'static str,
+}
+
+impl<'de> Visitor<'de> for UnitVisitor {
+    type Value = u64;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str(self.expecting)
+    }
+
+    fn visit_u64<E: de::Error>(self, value: u64) -> Result<u64, E> {
+        Ok(value)
+    }
+
+    fn visit_i64<E: de::Error>(self, value: i64) -> Result<u64, E> {
+        u64::try_from(value).map_err(|_| E::invalid_value(de::Unexpected::Signed(value), &self))
+    }
+
+    fn visit_f64<E: de::Error>(self, value: f64) -> Result<u64, E> {
+        Err(E::custom(format!(
+            "{} is not a whole number of {}; this setting takes whole {} only",
+            value, self.unit, self.unit
+        )))
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<u64, E> {
+        (self.parse)(value).map_err(E::custom)
+    }
+}
+
+/// Makes a unit visitor usable for `Option` fields
+struct OptionVisitor(UnitVisitor);
+
+impl<'de> Visitor<'de> for OptionVisitor {
+    type Value = Option<u64>;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        self.0.expecting(formatter)
+    }
+
+    fn visit_none<E: de::Error>(self) -> Result<Self::Value, E> {
+        Ok(None)
+    }
+
+    fn visit_unit<E: de::Error>(self) -> Result<Self::Value, E> {
+        Ok(None)
+    }
+
+    fn visit_some<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        deserializer.deserialize_any(self.0).map(Some)
+    }
+}
+
+fn seconds_visitor() -> UnitVisitor {
+    UnitVisitor {
+        parse: |text| whole(parse_duration_ms(text)?, 1_000, text, "seconds"),
+        expecting: "a number of seconds or a duration such as \"30s\" or \"5m\"",
+        unit: "seconds",
+    }
+}
+
+fn millis_visitor() -> UnitVisitor {
+    UnitVisitor {
+        parse: parse_duration_ms,
+        expecting: "a number of milliseconds or a duration such as \"250ms\" or \"2s\"",
+        unit: "milliseconds",
+    }
+}
+
+fn megabytes_visitor() -> UnitVisitor {
+    UnitVisitor {
+        parse: |text| whole(parse_size_bytes(text)?, MIB, text, "MiB"),
+        expecting: "a number of MiB or a size such as \"512MiB\" or \"2GiB\"",
+        unit: "MiB",
+    }
+}
+
+/// `deserialize_with` for a required `*_seconds` setting
+pub fn seconds<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+    deserializer.deserialize_any(seconds_visitor())
+}
+
+/// `deserialize_with` for an optional `*_seconds` setting (pair with `#[serde(default)]`)
+pub fn option_seconds<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u64>, D::Error> {
+    deserializer.deserialize_option(OptionVisitor(seconds_visitor()))
+}
+
+/// `deserialize_with` for an optional `*_ms` setting (pair with `#[serde(default)]`)
+pub fn option_millis<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u64>, D::Error> {
+    deserializer.deserialize_option(OptionVisitor(millis_visitor()))
+}
+
+/// `deserialize_with` for an optional `*_mb` setting (pair with `#[serde(default)]`)
+pub fn option_megabytes<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u64>, D::Error> {
+    deserializer.deserialize_option(OptionVisitor(megabytes_visitor()))
+}
+
+/// Schema of a duration setting: an integer or a string with units
+pub fn duration_schema(_generator: &mut SchemaGenerator) -> Schema {
+    json_schema!({
+        "anyOf": [
+            { "type": "integer", "minimum": 0 },
+            { "type": "string", "pattern": "^\\s*(\\d+\\s*(ms|s|m|h|d)\\s*)+$" }
+        ]
+    })
+}
+
+/// Schema of a size setting: an integer or a string with units
+pub fn size_schema(_generator: &mut SchemaGenerator) -> Schema {
+    json_schema!({
+        "anyOf": [
+            { "type": "integer", "minimum": 0 },
+            { "type": "string", "pattern": "^\\s*(\\d+\\s*(B|KB|MB|GB|KiB|MiB|GiB)\\s*)+$" }
+        ]
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration_ms("250ms"), Ok(250));
+        assert_eq!(parse_duration_ms("30s"), Ok(30_000));
+        assert_eq!(parse_duration_ms("5m"), Ok(300_000));
+        assert_eq!(parse_duration_ms("1h30m"), Ok(5_400_000));
+        assert_eq!(parse_duration_ms(" 1m 30s "), Ok(90_000));
+        assert!(parse_duration_ms("30").is_err());
+        assert!(parse_duration_ms("5 minutes").is_err());
+        assert!(parse_duration_ms("").is_err());
+        assert!(parse_duration_ms("-5s").is_err());
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size_bytes("2MiB"), Ok(2 * 1024 * 1024));
+        assert_eq!(parse_size_bytes("2MB"), Ok(2_000_000));
+        assert_eq!(parse_size_bytes("1GiB"), Ok(1 << 30));
+        assert!(parse_size_bytes("2mb").is_err());
+    }
+
+    #[derive(serde::Deserialize)]
+    struct Settings {
+        #[serde(default, deserialize_with = "option_seconds")]
+        flush_seconds: Option<u64>,
+        #[serde(default, deserialize_with = "option_millis")]
+        timeout_ms: Option<u64>,
+        #[serde(default, deserialize_with = "option_megabytes")]
+        max_size_mb: Option<u64>,
+    }
+
+    #[test]
+    fn test_deserialize_integers_and_strings() {
+        let settings: Settings =
+            serde_yaml::from_str("flush_seconds: 5m\ntimeout_ms: 2s\nmax_size_mb: 1GiB").unwrap();
+        assert_eq!(settings.flush_seconds, Some(300));
+        assert_eq!(settings.timeout_ms, Some(2_000));
+        assert_eq!(settings.max_size_mb, Some(1024));
+
+        let settings: Settings = serde_yaml::from_str("flush_seconds: 45\nmax_size_mb: ~").unwrap();
+        assert_eq!(settings.flush_seconds, Some(45));
+        assert_eq!(settings.max_size_mb, None);
+        assert_eq!(settings.timeout_ms, None);
+
+        // Not a whole number of the setting's unit
+        assert!(serde_yaml::from_str::<Settings>("flush_seconds: 1500ms").is_err());
+        assert!(serde_yaml::from_str::<Settings>("max_size_mb: 512KiB").is_err());
+        assert!(serde_yaml::from_str::<Settings>("flush_seconds: -1").is_err());
+
+        let error = serde_yaml::from_str::<Settings>("max_size_mb: 1.5").err().unwrap().to_string();
+        assert!(error.contains("1.5 is not a whole number of MiB"), "{}", error);
+    }
+}
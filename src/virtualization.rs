@@ -0,0 +1,138 @@
+//! Detection of the hypervisor the agent runs under, or bare metal
+//!
+//! Follows the order `systemd-detect-virt` uses: the DMI vendor and product
+//! strings first, then the CPUID hypervisor leaf, then the Xen sysfs entry.
+//! A CPU without the hypervisor bit and without a known DMI vendor is bare metal.
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Virtualization {
+    BareMetal,
+    Kvm,
+    Qemu,
+    Vmware,
+    #[serde(rename = "hyperv")]
+    HyperV,
+    Xen,
+    #[serde(rename = "virtualbox")]
+    VirtualBox,
+    /// A hypervisor is present but not one of the above
+    Other,
+}
+
+impl Virtualization {
+    /// Virtualization of this machine, `None` if it cannot be told
+    pub fn detect() -> Option<Self> {
+        let (hypervisor_present, cpuid_vendor) = cpuid_hypervisor();
+        Self::from_sources(
+            read_dmi("sys_vendor").as_deref(),
+            read_dmi("product_name").as_deref(),
+            cpuid_vendor.as_deref(),
+            hypervisor_present,
+            std::fs::read_to_string("/sys/hypervisor/type").ok().as_deref(),
+        )
+    }
+
+    fn from_sources(
+        dmi_vendor: Option<&str>,
+        dmi_product: Option<&str>,
+        cpuid_vendor: Option<&str>,
+        hypervisor_present: Option<bool>,
+        xen_type: Option<&str>,
+    ) -> Option<Self> {
+        if let Some(virtualization) = from_dmi(dmi_vendor.unwrap_or(""), dmi_product.unwrap_or("")) {
+            return Some(virtualization);
+        }
+
+        if let Some(vendor) = cpuid_vendor {
+            return Some(match vendor.trim_end_matches('\0') {
+                "KVMKVMKVM" | "Linux KVM Hv" => Self::Kvm,
+                "TCGTCGTCGTCG" => Self::Qemu,
+                "VMwareVMware" => Self::Vmware,
+                "Microsoft Hv" => Self::HyperV,
+                "XenVMMXenVMM" => Self::Xen,
+                "VBoxVBoxVBox" => Self::VirtualBox,
+                _ => Self::Other,
+            });
+        }
+
+        if xen_type.map(str::trim) == Some("xen") {
+            return Some(Self::Xen);
+        }
+
+        match hypervisor_present {
+            Some(true) => Some(Self::Other),
+            Some(false) => Some(Self::BareMetal),
+            None => None,
+        }
+    }
+}
+
+fn from_dmi(vendor: &str, product: &str) -> Option<Virtualization> {
+    let vendor = vendor.trim();
+    let product = product.trim();
+    if vendor.starts_with("VMware") {
+        Some(Virtualization::Vmware)
+    } else if vendor == "Microsoft Corporation" && product == "Virtual Machine" {
+        Some(Virtualization::HyperV)
+    } else if vendor == "innotek GmbH" || product == "VirtualBox" {
+        Some(Virtualization::VirtualBox)
+    } else if vendor == "Xen" || product.starts_with("HVM domU") {
+        Some(Virtualization::Xen)
+    } else if product == "KVM" || vendor == "Google" {
+        Some(Virtualization::Kvm)
+    } else {
+        // QEMU, and EC2 Nitro (KVM based, but also bare metal instances) are told apart by CPUID
+        None
+    }
+}
+
+fn read_dmi(field: &str) -> Option<String> {
+    std::fs::read_to_string(format!("/sys/class/dmi/id/{}", field)).ok()
+}
+
+/// Hypervisor bit of CPUID leaf 1 and the vendor of leaf 0x40000000, if set
+#[cfg(target_arch = "x86_64")]
+#[allow(unused_unsafe)]
+fn cpuid_hypervisor() -> (Option<bool>, Option<String>) {
+    use std::arch::x86_64::__cpuid;
+
+    // `__cpuid` is safe on recent toolchains, unsafe on older ones
+    let features = unsafe { __cpuid(1) };
+    if features.ecx & (1 << 31) == 0 {
+        return (Some(false), None);
+    }
+    let leaf = unsafe { __cpuid(0x4000_0000) };
+    let bytes: Vec<u8> = [leaf.ebx, leaf.ecx, leaf.edx].iter().flat_map(|r| r.to_le_bytes()).collect();
+    (Some(true), Some(String::from_utf8_lossy(&bytes).into_owned()))
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+fn cpuid_hypervisor() -> (Option<bool>, Option<String>) {
+    (None, None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detection_order() {
+        let detect = Virtualization::from_sources;
+
+        assert_eq!(detect(Some("VMware, Inc.\n"), Some("VMware7,1\n"), None, Some(true), None), Some(Virtualization::Vmware));
+        assert_eq!(
+            detect(Some("Microsoft Corporation"), Some("Virtual Machine"), Some("Microsoft Hv"), Some(true), None),
+            Some(Virtualization::HyperV)
+        );
+        // EC2 Nitro: DMI names the cloud, CPUID the hypervisor
+        assert_eq!(detect(Some("Amazon EC2"), Some("m5.large"), Some("KVMKVMKVM\0\0\0"), Some(true), None), Some(Virtualization::Kvm));
+        assert_eq!(detect(Some("Amazon EC2"), Some("m5.metal"), None, Some(false), None), Some(Virtualization::BareMetal));
+        assert_eq!(detect(None, None, None, None, Some("xen\n")), Some(Virtualization::Xen));
+        assert_eq!(detect(None, None, Some("bhyve bhyve "), Some(true), None), Some(Virtualization::Other));
+        assert_eq!(detect(Some("Dell Inc."), Some("PowerEdge R640"), None, Some(false), None), Some(Virtualization::BareMetal));
+        assert_eq!(detect(None, None, None, None, None), None);
+    }
+}
@@ -0,0 +1,602 @@
+//! Sandboxed WebAssembly collectors and transforms
+//!
+//! Every `.wasm` module in the configured directory is compiled at startup;
+//! compiled modules are cached by path and content, so reloading the config
+//! only recompiles modules whose file changed. A module exporting `collect` runs as a collector; one exporting `transform`
+//! rewrites each collection before it is buffered. Each call gets a fresh
+//! instance with a fuel (instruction) budget and a memory cap, and the only
+//! host access is through these imports from the `sentinel` module:
+//!
+//! ```text
+//! read_file(path_ptr, path_len, buf_ptr, buf_cap) -> i32
+//!     Copy up to buf_cap bytes of an allowed file into the buffer and return
+//!     the file's full length; -1 if the path is not allowed, -2 if unreadable
+//! emit_metric(ptr, len) -> i32
+//!     Output one metric as JSON in the batch format; -1 if it is invalid
+//! log(level, ptr, len)
+//!     Log a UTF-8 message; level 0 = error, 1 = warn, 2 = info, 3 = debug
+//! ```
+//!
+//! Modules export `memory` and one or both of:
+//!
+//! ```text
+//! collect() -> i32                    0 on success; metrics via emit_metric
+//! alloc(len) -> ptr                   buffer for the transform input
+//! transform(ptr, len) -> i32          input is a JSON array of metrics;
+//!                                     0 on success, outputs via emit_metric
+//! ```
+
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+use crate::config::WasmConfig;
+use crate::metrics::DiskMetric;
+
+#[derive(Error, Debug)]
+pub enum WasmError {
+    #[cfg(not(feature = "wasm"))]
+    #[error("agent was built without the wasm feature")]
+    Unsupported,
+    #[error("failed to read WASM directory {path}: {message}")]
+    Directory { path: PathBuf, message: String },
+    #[cfg(feature = "wasm")]
+    #[error("failed to load WASM module {path}: {message}")]
+    Load { path: PathBuf, message: String },
+    #[cfg(feature = "wasm")]
+    #[error("WASM module {name} trapped: {message}")]
+    Trap { name: String, message: String },
+    #[cfg(feature = "wasm")]
+    #[error("WASM module {name} returned error code {code}")]
+    Failed { name: String, code: i32 },
+}
+
+/// What a module may do when called
+#[derive(Debug, Clone)]
+#[cfg_attr(not(feature = "wasm"), allow(dead_code))]
+pub struct Sandbox {
+    /// Files, or directories whose contents, `read_file` may read
+    pub allowed_paths: Vec<PathBuf>,
+    /// Instructions a single call may execute
+    pub fuel: u64,
+    pub max_memory_bytes: usize,
+}
+
+impl Sandbox {
+    pub fn from_config(config: &WasmConfig) -> Self {
+        Self {
+            allowed_paths: config.allowed_paths.clone().unwrap_or_default(),
+            fuel: config.get_fuel(),
+            max_memory_bytes: config.get_max_memory_bytes(),
+        }
+    }
+}
+
+/// A compiled module; cheap to clone
+#[derive(Clone)]
+pub struct WasmModule {
+    name: String,
+    #[cfg(feature = "wasm")]
+    compiled: std::sync::Arc<imp::Compiled>,
+}
+
+impl WasmModule {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn is_collector(&self) -> bool {
+        #[cfg(feature = "wasm")]
+        return self.compiled.exports("collect");
+
+        #[cfg(not(feature = "wasm"))]
+        false
+    }
+
+    pub fn is_transform(&self) -> bool {
+        #[cfg(feature = "wasm")]
+        return self.compiled.exports("transform");
+
+        #[cfg(not(feature = "wasm"))]
+        false
+    }
+
+    pub fn collect(&self) -> Result<Vec<DiskMetric>, WasmError> {
+        #[cfg(feature = "wasm")]
+        return self.compiled.collect(&self.name);
+
+        #[cfg(not(feature = "wasm"))]
+        Err(WasmError::Unsupported)
+    }
+
+    pub fn transform(&self, metrics: &[DiskMetric]) -> Result<Vec<DiskMetric>, WasmError> {
+        #[cfg(feature = "wasm")]
+        return self.compiled.transform(&self.name, metrics);
+
+        #[cfg(not(feature = "wasm"))]
+        {
+            let _ = metrics;
+            Err(WasmError::Unsupported)
+        }
+    }
+}
+
+#[cfg(feature = "wasm")]
+mod imp {
+    use super::{Sandbox, WasmError};
+    use crate::metrics::DiskMetric;
+    use sha2::{Digest, Sha256};
+    use std::collections::HashMap;
+    use std::fs::File;
+    use std::io::Read;
+    use std::path::{Path, PathBuf};
+    use std::sync::{LazyLock, Mutex, OnceLock, PoisonError};
+    use tracing::{debug, error, info, warn};
+    use wasmtime::{
+        Caller, Config, Engine, Instance, Linker, Memory, Module, Store, StoreLimits,
+        StoreLimitsBuilder,
+    };
+
+    /// Largest file `read_file` will load, whatever the guest buffer size
+    const MAX_READ_BYTES: u64 = 4 * 1024 * 1024;
+
+    /// Metrics one call may emit, so a runaway module cannot exhaust memory
+    const MAX_EMITTED_METRICS: usize = 10_000;
+
+    const ERR_DENIED: i32 = -1;
+    const ERR_IO: i32 = -2;
+    const ERR_INVALID: i32 = -1;
+
+    struct HostState {
+        name: String,
+        limits: StoreLimits,
+        allowed_paths: Vec<PathBuf>,
+        emitted: Vec<DiskMetric>,
+    }
+
+    pub struct Compiled {
+        engine: Engine,
+        module: Module,
+        linker: Linker<HostState>,
+        sandbox: Sandbox,
+    }
+
+    type ModuleCache = HashMap<PathBuf, ([u8; 32], Module)>;
+
+    /// Compiled modules by path, with the SHA-256 of the bytes they were compiled from
+    static MODULES: LazyLock<Mutex<ModuleCache>> = LazyLock::new(|| Mutex::new(HashMap::new()));
+
+    /// The engine shared by every module, so cached modules stay usable
+    pub fn engine() -> Result<Engine, String> {
+        static ENGINE: OnceLock<Engine> = OnceLock::new();
+        if let Some(engine) = ENGINE.get() {
+            return Ok(engine.clone());
+        }
+        let mut config = Config::new();
+        config.consume_fuel(true);
+        let engine = Engine::new(&config).map_err(|e| e.to_string())?;
+        Ok(ENGINE.get_or_init(|| engine).clone())
+    }
+
+    /// Compile the module at `path`, or reuse the last compilation if the file is unchanged
+    pub fn compile_cached(engine: &Engine, path: &Path) -> Result<Module, String> {
+        let bytes = std::fs::read(path).map_err(|e| e.to_string())?;
+        let digest: [u8; 32] = Sha256::digest(&bytes).into();
+
+        let mut modules = MODULES.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some((cached, module)) = modules.get(path) {
+            if *cached == digest {
+                return Ok(module.clone());
+            }
+        }
+        let module = Module::new(engine, &bytes).map_err(|e| e.to_string())?;
+        modules.insert(path.to_path_buf(), (digest, module.clone()));
+        Ok(module)
+    }
+
+    impl Compiled {
+        #[cfg(test)]
+        pub fn new(engine: &Engine, bytes: &[u8], sandbox: Sandbox) -> Result<Self, String> {
+            let module = Module::new(engine, bytes).map_err(|e| e.to_string())?;
+            Self::with_module(engine, module, sandbox)
+        }
+
+        pub fn with_module(engine: &Engine, module: Module, sandbox: Sandbox) -> Result<Self, String> {
+            let mut linker = Linker::new(engine);
+            link_host_functions(&mut linker).map_err(|e| e.to_string())?;
+            Ok(Self {
+                engine: engine.clone(),
+                module,
+                linker,
+                sandbox,
+            })
+        }
+
+        pub fn exports(&self, name: &str) -> bool {
+            self.module.exports().any(|export| export.name() == name)
+        }
+
+        pub fn collect(&self, name: &str) -> Result<Vec<DiskMetric>, WasmError> {
+            self.call(name, |store, instance| {
+                let collect = instance.get_typed_func::<(), i32>(&mut *store, "collect")?;
+                collect.call(&mut *store, ())
+            })
+        }
+
+        pub fn transform(&self, name: &str, metrics: &[DiskMetric]) -> Result<Vec<DiskMetric>, WasmError> {
+            let input = serde_json::to_vec(metrics).map_err(|e| WasmError::Trap {
+                name: name.to_string(),
+                message: e.to_string(),
+            })?;
+            self.call(name, |store, instance| {
+                let len = i32::try_from(input.len())?;
+                let alloc = instance.get_typed_func::<i32, i32>(&mut *store, "alloc")?;
+                let transform = instance.get_typed_func::<(i32, i32), i32>(&mut *store, "transform")?;
+                let memory = instance
+                    .get_memory(&mut *store, "memory")
+                    .ok_or_else(|| wasmtime::Error::msg("module does not export memory"))?;
+
+                let ptr = alloc.call(&mut *store, len)?;
+                memory.write(&mut *store, usize::try_from(ptr)?, &input)?;
+                transform.call(&mut *store, (ptr, len))
+            })
+        }
+
+        /// Run `entry` in a fresh instance and return what it emitted
+        fn call<F>(&self, name: &str, entry: F) -> Result<Vec<DiskMetric>, WasmError>
+        where
+            F: FnOnce(&mut Store<HostState>, &Instance) -> wasmtime::Result<i32>,
+        {
+            let trap = |e: wasmtime::Error| WasmError::Trap {
+                name: name.to_string(),
+                message: e.to_string(),
+            };
+
+            let state = HostState {
+                name: name.to_string(),
+                limits: StoreLimitsBuilder::new()
+                    .memory_size(self.sandbox.max_memory_bytes)
+                    .instances(1)
+                    .build(),
+                allowed_paths: self.sandbox.allowed_paths.clone(),
+                emitted: Vec::new(),
+            };
+            let mut store = Store::new(&self.engine, state);
+            store.limiter(|state| &mut state.limits);
+            store.set_fuel(self.sandbox.fuel).map_err(trap)?;
+
+            let instance = self.linker.instantiate(&mut store, &self.module).map_err(trap)?;
+            let code = entry(&mut store, &instance).map_err(trap)?;
+            if code != 0 {
+                return Err(WasmError::Failed { name: name.to_string(), code });
+            }
+            Ok(store.into_data().emitted)
+        }
+    }
+
+    fn guest_memory(caller: &mut Caller<'_, HostState>) -> Option<Memory> {
+        caller.get_export("memory")?.into_memory()
+    }
+
+    fn guest_bytes(caller: &Caller<'_, HostState>, memory: &Memory, ptr: i32, len: i32) -> Option<Vec<u8>> {
+        let start = usize::try_from(ptr).ok()?;
+        let end = start.checked_add(usize::try_from(len).ok()?)?;
+        memory.data(caller).get(start..end).map(<[u8]>::to_vec)
+    }
+
+    /// What `path` resolves to, if that is an allowed file or inside an allowed directory
+    fn resolve_allowed(path: &Path, allowed: &[PathBuf]) -> Option<PathBuf> {
+        let path = path.canonicalize().ok()?;
+        allowed
+            .iter()
+            .filter_map(|allowed| allowed.canonicalize().ok())
+            .any(|allowed| path.starts_with(allowed))
+            .then_some(path)
+    }
+
+    /// Open `path` if it names an allowed file; `Err` holds the guest error code
+    ///
+    /// A path component could be swapped for a symlink between the check and
+    /// the open, so the opened file must also be the one the path resolves to
+    /// afterwards.
+    fn open_allowed(path: &Path, allowed: &[PathBuf]) -> Result<File, i32> {
+        let resolved = resolve_allowed(path, allowed).ok_or(ERR_DENIED)?;
+        let file = File::open(&resolved).map_err(|_| ERR_IO)?;
+        let opened = file.metadata().map_err(|_| ERR_IO)?;
+        let current = resolve_allowed(path, allowed)
+            .and_then(|resolved| std::fs::metadata(resolved).ok())
+            .ok_or(ERR_DENIED)?;
+        if !same_file(&opened, &current) {
+            return Err(ERR_DENIED);
+        }
+        Ok(file)
+    }
+
+    #[cfg(unix)]
+    fn same_file(a: &std::fs::Metadata, b: &std::fs::Metadata) -> bool {
+        use std::os::unix::fs::MetadataExt;
+        a.dev() == b.dev() && a.ino() == b.ino()
+    }
+
+    /// File identity is not exposed on stable Windows, so only the type and length are compared
+    #[cfg(not(unix))]
+    fn same_file(a: &std::fs::Metadata, b: &std::fs::Metadata) -> bool {
+        a.file_type() == b.file_type() && a.len() == b.len()
+    }
+
+    fn link_host_functions(linker: &mut Linker<HostState>) -> wasmtime::Result<()> {
+        linker.func_wrap(
+            "sentinel",
+            "read_file",
+            |mut caller: Caller<'_, HostState>, path_ptr: i32, path_len: i32, buf_ptr: i32, buf_cap: i32| -> i32 {
+                let Some(memory) = guest_memory(&mut caller) else {
+                    return ERR_INVALID;
+                };
+                let Some(path) = guest_bytes(&caller, &memory, path_ptr, path_len) else {
+                    return ERR_INVALID;
+                };
+                let path = PathBuf::from(String::from_utf8_lossy(&path).into_owned());
+                let file = match open_allowed(&path, &caller.data().allowed_paths) {
+                    Ok(file) => file,
+                    Err(ERR_DENIED) => {
+                        warn!(module = %caller.data().name, path = %path.display(), "WASM module denied file read");
+                        return ERR_DENIED;
+                    }
+                    Err(code) => return code,
+                };
+
+                let mut contents = Vec::new();
+                if file.take(MAX_READ_BYTES).read_to_end(&mut contents).is_err() {
+                    return ERR_IO;
+                }
+
+                let copied = contents.len().min(usize::try_from(buf_cap).unwrap_or(0));
+                let Ok(offset) = usize::try_from(buf_ptr) else {
+                    return ERR_INVALID;
+                };
+                if memory.write(&mut caller, offset, &contents[..copied]).is_err() {
+                    return ERR_INVALID;
+                }
+                i32::try_from(contents.len()).unwrap_or(i32::MAX)
+            },
+        )?;
+
+        linker.func_wrap(
+            "sentinel",
+            "emit_metric",
+            |mut caller: Caller<'_, HostState>, ptr: i32, len: i32| -> i32 {
+                let Some(memory) = guest_memory(&mut caller) else {
+                    return ERR_INVALID;
+                };
+                let Some(bytes) = guest_bytes(&caller, &memory, ptr, len) else {
+                    return ERR_INVALID;
+                };
+                let state = caller.data_mut();
+                if state.emitted.len() >= MAX_EMITTED_METRICS {
+                    return ERR_INVALID;
+                }
+                match serde_json::from_slice(&bytes) {
+                    Ok(metric) => {
+                        state.emitted.push(metric);
+                        0
+                    }
+                    Err(e) => {
+                        debug!(module = %state.name, error = %e, "WASM module emitted an invalid metric");
+                        ERR_INVALID
+                    }
+                }
+            },
+        )?;
+
+        linker.func_wrap(
+            "sentinel",
+            "log",
+            |mut caller: Caller<'_, HostState>, level: i32, ptr: i32, len: i32| {
+                let Some(memory) = guest_memory(&mut caller) else {
+                    return;
+                };
+                let Some(bytes) = guest_bytes(&caller, &memory, ptr, len) else {
+                    return;
+                };
+                let module = &caller.data().name;
+                let message = String::from_utf8_lossy(&bytes);
+                match level {
+                    0 => error!(module = %module, "{}", message),
+                    1 => warn!(module = %module, "{}", message),
+                    2 => info!(module = %module, "{}", message),
+                    _ => debug!(module = %module, "{}", message),
+                }
+            },
+        )?;
+
+        Ok(())
+    }
+}
+
+/// Compile every `.wasm` module in the configured directory, in file name order
+///
+/// A module that fails to compile is reported and skipped; the others still load.
+pub fn load_directory(config: &WasmConfig) -> (Vec<WasmModule>, Vec<WasmError>) {
+    let directory = config.get_directory();
+    let sandbox = Sandbox::from_config(config);
+
+    #[cfg(feature = "wasm")]
+    {
+        let mut paths = match module_paths(&directory) {
+            Ok(paths) => paths,
+            Err(e) => return (Vec::new(), vec![e]),
+        };
+        paths.sort();
+
+        let engine = match imp::engine() {
+            Ok(engine) => engine,
+            Err(message) => return (Vec::new(), vec![WasmError::Load { path: directory, message }]),
+        };
+
+        let mut modules = Vec::new();
+        let mut errors = Vec::new();
+        for path in paths {
+            let loaded = imp::compile_cached(&engine, &path)
+                .and_then(|module| imp::Compiled::with_module(&engine, module, sandbox.clone()));
+            match loaded {
+                Ok(compiled) => modules.push(WasmModule {
+                    name: module_name(&path),
+                    compiled: std::sync::Arc::new(compiled),
+                }),
+                Err(message) => errors.push(WasmError::Load { path, message }),
+            }
+        }
+        (modules, errors)
+    }
+
+    #[cfg(not(feature = "wasm"))]
+    {
+        let _ = (directory, sandbox);
+        (Vec::new(), vec![WasmError::Unsupported])
+    }
+}
+
+#[cfg_attr(not(feature = "wasm"), allow(dead_code))]
+fn module_paths(directory: &Path) -> Result<Vec<PathBuf>, WasmError> {
+    let entries = std::fs::read_dir(directory).map_err(|e| WasmError::Directory {
+        path: directory.to_path_buf(),
+        message: e.to_string(),
+    })?;
+    Ok(entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.is_file() && path.extension().is_some_and(|ext| ext == "wasm"))
+        .collect())
+}
+
+/// Modules are named after their file, e.g. `raid.wasm` is collector `raid`
+#[cfg_attr(not(feature = "wasm"), allow(dead_code))]
+fn module_name(path: &Path) -> String {
+    path.file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+#[cfg(all(test, feature = "wasm"))]
+mod tests {
+    use super::*;
+
+    fn sandbox(allowed_paths: Vec<PathBuf>) -> Sandbox {
+        Sandbox {
+            allowed_paths,
+            fuel: 10_000_000,
+            max_memory_bytes: 2 * 1024 * 1024,
+        }
+    }
+
+    fn module(wat: &str, sandbox: Sandbox) -> WasmModule {
+        let engine = imp::engine().unwrap();
+        WasmModule {
+            name: "test".to_string(),
+            compiled: std::sync::Arc::new(imp::Compiled::new(&engine, wat.as_bytes(), sandbox).unwrap()),
+        }
+    }
+
+    const METRIC: &str = r#"{"timestamp":1,"device":"md0","mount_point":"/data","total_space_bytes":100,"used_space_bytes":40,"available_space_bytes":60,"usage_percentage":0.4}"#;
+
+    #[test]
+    fn test_collect_emits_metrics() {
+        let wat = format!(
+            r#"(module
+                (import "sentinel" "emit_metric" (func $emit (param i32 i32) (result i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 0) "{}")
+                (func (export "collect") (result i32)
+                    (drop (call $emit (i32.const 0) (i32.const {})))
+                    (i32.const 0)))"#,
+            METRIC.replace('"', "\\\""),
+            METRIC.len()
+        );
+        let module = module(&wat, sandbox(Vec::new()));
+        assert!(module.is_collector());
+        assert!(!module.is_transform());
+
+        let metrics = module.collect().unwrap();
+        assert_eq!(metrics.len(), 1);
+        assert_eq!(metrics[0].device, "md0");
+    }
+
+    #[test]
+    fn test_fuel_limits_runaway_module() {
+        let wat = r#"(module
+            (memory (export "memory") 1)
+            (func (export "collect") (result i32)
+                (loop $forever (br $forever))
+                (i32.const 0)))"#;
+        let module = module(wat, sandbox(Vec::new()));
+        assert!(matches!(module.collect(), Err(WasmError::Trap { .. })));
+    }
+
+    #[test]
+    fn test_read_file_respects_allowed_paths() {
+        let dir = tempfile::tempdir().unwrap();
+        let allowed = dir.path().join("allowed");
+        std::fs::write(&allowed, "hello").unwrap();
+        let denied = tempfile::NamedTempFile::new().unwrap();
+
+        // Returns read_file's result for the path stored at offset 0
+        let module_for = |path: &Path| {
+            let path = path.display().to_string();
+            module(
+                &format!(
+                    r#"(module
+                        (import "sentinel" "read_file" (func $read (param i32 i32 i32 i32) (result i32)))
+                        (memory (export "memory") 1)
+                        (data (i32.const 0) "{}")
+                        (func (export "collect") (result i32)
+                            (call $read (i32.const 0) (i32.const {}) (i32.const 1024) (i32.const 64))))"#,
+                    path,
+                    path.len()
+                ),
+                sandbox(vec![dir.path().to_path_buf()]),
+            )
+        };
+
+        // The file length comes back as a nonzero "error" code from collect
+        assert!(matches!(module_for(&allowed).collect(), Err(WasmError::Failed { code: 5, .. })));
+        assert!(matches!(module_for(denied.path()).collect(), Err(WasmError::Failed { code: -1, .. })));
+    }
+
+    #[test]
+    fn test_transform_rewrites_metrics() {
+        // Drops its input and emits a fixed metric
+        let wat = format!(
+            r#"(module
+                (import "sentinel" "emit_metric" (func $emit (param i32 i32) (result i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 0) "{}")
+                (func (export "alloc") (param i32) (result i32) (i32.const 4096))
+                (func (export "transform") (param i32 i32) (result i32)
+                    (drop (call $emit (i32.const 0) (i32.const {})))
+                    (i32.const 0)))"#,
+            METRIC.replace('"', "\\\""),
+            METRIC.len()
+        );
+        let module = module(&wat, sandbox(Vec::new()));
+        assert!(module.is_transform());
+
+        let input: DiskMetric = serde_json::from_str(&METRIC.replace("md0", "sda1")).unwrap();
+        let output = module.transform(&[input.clone(), input]).unwrap();
+        assert_eq!(output.len(), 1);
+        assert_eq!(output[0].device, "md0");
+    }
+
+    #[test]
+    fn test_modules_are_compiled_once_per_content() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("noop.wasm");
+        std::fs::write(&path, r#"(module (func (export "collect") (result i32) (i32.const 0)))"#).unwrap();
+        let engine = imp::engine().unwrap();
+
+        let first = imp::compile_cached(&engine, &path).unwrap();
+        let again = imp::compile_cached(&engine, &path).unwrap();
+        assert!(wasmtime::Module::same(&first, &again));
+
+        std::fs::write(&path, r#"(module (func (export "collect") (result i32) (i32.const 1)))"#).unwrap();
+        let changed = imp::compile_cached(&engine, &path).unwrap();
+        assert!(!wasmtime::Module::same(&first, &changed));
+    }
+}
@@ -0,0 +1,201 @@
+use std::ffi::OsString;
+use std::path::PathBuf;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use tracing::{error, info};
+use windows_service::service::{
+    ServiceAccess, ServiceControl, ServiceControlAccept, ServiceErrorControl, ServiceExitCode,
+    ServiceInfo, ServiceStartType, ServiceState, ServiceStatus, ServiceType,
+};
+use windows_service::service_control_handler::{self, ServiceControlHandlerResult};
+use windows_service::service_manager::{ServiceManager, ServiceManagerAccess};
+use windows_service::{define_windows_service, service_dispatcher};
+
+use crate::agent::SentinelAgent;
+use crate::config::Config;
+use crate::limits;
+use crate::lock;
+use crate::logging;
+use crate::secrets;
+
+/// Name the service is registered under with the Service Control Manager
+pub const SERVICE_NAME: &str = "OperionSentinelAgent";
+const SERVICE_DISPLAY_NAME: &str = "Operion Sentinel Agent";
+const SERVICE_DESCRIPTION: &str = "Operion monitoring agent for system metrics";
+
+/// Config path handed from `main` to the SCM-invoked service entry point
+static CONFIG_PATH: OnceLock<PathBuf> = OnceLock::new();
+
+define_windows_service!(ffi_service_main, service_main);
+
+/// Hand control to the Service Control Manager; blocks until the service stops
+pub fn run(config_path: PathBuf) -> Result<(), ServiceError> {
+    let _ = CONFIG_PATH.set(config_path);
+    service_dispatcher::start(SERVICE_NAME, ffi_service_main)
+        .map_err(|e| ServiceError::Dispatcher(e.to_string()))
+}
+
+fn service_main(_arguments: Vec<OsString>) {
+    if let Err(e) = run_service() {
+        error!(error = %e, "Service failed");
+    }
+}
+
+fn run_service() -> Result<(), ServiceError> {
+    let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel();
+    let mut shutdown_tx = Some(shutdown_tx);
+
+    let event_handler = move |control| match control {
+        ServiceControl::Stop | ServiceControl::Shutdown => {
+            if let Some(sender) = shutdown_tx.take() {
+                let _ = sender.send(());
+            }
+            ServiceControlHandlerResult::NoError
+        }
+        ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+        _ => ServiceControlHandlerResult::NotImplemented,
+    };
+
+    let status_handle = service_control_handler::register(SERVICE_NAME, event_handler)
+        .map_err(|e| ServiceError::Control(e.to_string()))?;
+
+    let set_status = |state, controls_accepted, exit_code| {
+        status_handle
+            .set_service_status(ServiceStatus {
+                service_type: ServiceType::OWN_PROCESS,
+                current_state: state,
+                controls_accepted,
+                exit_code,
+                checkpoint: 0,
+                wait_hint: Duration::from_secs(10),
+                process_id: None,
+            })
+            .map_err(|e| ServiceError::Control(e.to_string()))
+    };
+
+    set_status(
+        ServiceState::Running,
+        ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN,
+        ServiceExitCode::Win32(0),
+    )?;
+
+    let result = run_agent(shutdown_rx);
+
+    let exit_code = match &result {
+        Ok(()) => ServiceExitCode::Win32(0),
+        Err(_) => ServiceExitCode::ServiceSpecific(1),
+    };
+    set_status(ServiceState::Stopped, ServiceControlAccept::empty(), exit_code)?;
+
+    result
+}
+
+fn run_agent(shutdown: tokio::sync::oneshot::Receiver<()>) -> Result<(), ServiceError> {
+    let config_path = CONFIG_PATH
+        .get()
+        .cloned()
+        .ok_or_else(|| ServiceError::Agent("Configuration path not set".to_string()))?;
+
+    let mut config =
+        Config::load_from_file(&config_path).map_err(|e| ServiceError::Agent(e.to_string()))?;
+    logging::init_event_log(&config.get_logging()).map_err(|e| ServiceError::Agent(e.to_string()))?;
+    limits::apply(&config).map_err(|e| ServiceError::Agent(e.to_string()))?;
+
+    let runtime = tokio::runtime::Runtime::new().map_err(|e| ServiceError::Agent(e.to_string()))?;
+    runtime.block_on(async {
+        secrets::resolve(&mut config).await.map_err(|e| ServiceError::Agent(e.to_string()))?;
+        let _lock = lock::acquire_state_dir(false).await.map_err(|e| ServiceError::Agent(e.to_string()))?;
+        let mut agent = SentinelAgent::new(config).map_err(|e| ServiceError::Agent(e.to_string()))?;
+        agent
+            .run_until(async {
+                tokio::select! {
+                    _ = shutdown => {}
+                    _ = lock::stop_requested() => {}
+                }
+            })
+            .await
+            .map_err(|e| ServiceError::Agent(e.to_string()))
+    })
+}
+
+/// Register the agent with the Service Control Manager to start automatically
+pub fn install(config_path: PathBuf) -> Result<(), ServiceError> {
+    let manager = ServiceManager::local_computer(
+        None::<&str>,
+        ServiceManagerAccess::CONNECT | ServiceManagerAccess::CREATE_SERVICE,
+    )
+    .map_err(|e| ServiceError::Manager(e.to_string()))?;
+
+    let executable_path =
+        std::env::current_exe().map_err(|e| ServiceError::Manager(e.to_string()))?;
+
+    let service_info = ServiceInfo {
+        name: OsString::from(SERVICE_NAME),
+        display_name: OsString::from(SERVICE_DISPLAY_NAME),
+        service_type: ServiceType::OWN_PROCESS,
+        start_type: ServiceStartType::AutoStart,
+        error_control: ServiceErrorControl::Normal,
+        executable_path,
+        launch_arguments: vec![
+            OsString::from("--service"),
+            OsString::from("--config"),
+            config_path.into_os_string(),
+        ],
+        dependencies: vec![],
+        // LocalSystem
+        account_name: None,
+        account_password: None,
+    };
+
+    let service = manager
+        .create_service(&service_info, ServiceAccess::CHANGE_CONFIG)
+        .map_err(|e| ServiceError::Manager(e.to_string()))?;
+    service
+        .set_description(SERVICE_DESCRIPTION)
+        .map_err(|e| ServiceError::Manager(e.to_string()))?;
+
+    info!(name = SERVICE_NAME, "Service installed");
+    Ok(())
+}
+
+/// Stop the service if running and remove it from the Service Control Manager
+pub fn uninstall() -> Result<(), ServiceError> {
+    let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)
+        .map_err(|e| ServiceError::Manager(e.to_string()))?;
+
+    let service = manager
+        .open_service(
+            SERVICE_NAME,
+            ServiceAccess::QUERY_STATUS | ServiceAccess::STOP | ServiceAccess::DELETE,
+        )
+        .map_err(|e| ServiceError::Manager(e.to_string()))?;
+
+    let status = service
+        .query_status()
+        .map_err(|e| ServiceError::Manager(e.to_string()))?;
+    if status.current_state != ServiceState::Stopped {
+        service
+            .stop()
+            .map_err(|e| ServiceError::Manager(e.to_string()))?;
+    }
+
+    service
+        .delete()
+        .map_err(|e| ServiceError::Manager(e.to_string()))?;
+
+    info!(name = SERVICE_NAME, "Service uninstalled");
+    Ok(())
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ServiceError {
+    #[error("Failed to start service dispatcher: {0}")]
+    Dispatcher(String),
+    #[error("Service control error: {0}")]
+    Control(String),
+    #[error("Service manager error: {0}")]
+    Manager(String),
+    #[error("Agent error: {0}")]
+    Agent(String),
+}
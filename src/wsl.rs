@@ -0,0 +1,70 @@
+//! Detection of the Windows Subsystem for Linux
+//!
+//! Under WSL the Windows drives are mounted into the distribution (`/mnt/c`
+//! over 9p on WSL2, drvfs on WSL1) next to WSL's own plumbing mounts. Those
+//! describe the Windows host rather than the distribution, so the disk
+//! collector skips them unless they are listed in `include_mount_points`.
+
+use serde::{Deserialize, Serialize};
+
+/// File systems WSL uses to expose Windows drives and its own files
+const HOST_FILE_SYSTEMS: &[&str] = &["9p", "v9fs", "drvfs"];
+
+/// Mounts WSL adds for WSLg and GPU drivers
+const PLUMBING_MOUNTS: &[&str] = &["/mnt/wslg", "/usr/lib/wsl", "/init"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WslVersion {
+    Wsl1,
+    Wsl2,
+}
+
+impl WslVersion {
+    /// WSL version the agent runs under, `None` outside WSL
+    pub fn detect() -> Option<Self> {
+        let release = std::fs::read_to_string("/proc/sys/kernel/osrelease").ok()?;
+        Self::from_kernel_release(&release)
+    }
+
+    /// WSL1 kernels report e.g. `4.4.0-19041-Microsoft`, WSL2 `5.15.90.1-microsoft-standard-WSL2`
+    fn from_kernel_release(release: &str) -> Option<Self> {
+        if release.contains("WSL2") || release.contains("microsoft-standard") {
+            Some(Self::Wsl2)
+        } else if release.contains("Microsoft") {
+            Some(Self::Wsl1)
+        } else if release.to_lowercase().contains("microsoft") {
+            Some(Self::Wsl2)
+        } else {
+            None
+        }
+    }
+}
+
+/// Whether a disk is a Windows drive or WSL plumbing rather than the distribution's own storage
+pub fn is_host_mount(file_system: &str, mount_point: &str) -> bool {
+    HOST_FILE_SYSTEMS.contains(&file_system)
+        || PLUMBING_MOUNTS
+            .iter()
+            .any(|prefix| mount_point == *prefix || mount_point.starts_with(&format!("{}/", prefix)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_kernel_release_and_host_mounts() {
+        assert_eq!(WslVersion::from_kernel_release("4.4.0-19041-Microsoft\n"), Some(WslVersion::Wsl1));
+        assert_eq!(
+            WslVersion::from_kernel_release("5.15.90.1-microsoft-standard-WSL2\n"),
+            Some(WslVersion::Wsl2)
+        );
+        assert_eq!(WslVersion::from_kernel_release("6.1.0-18-amd64\n"), None);
+
+        assert!(is_host_mount("9p", "/mnt/c"));
+        assert!(is_host_mount("overlay", "/mnt/wslg/versions.txt"));
+        assert!(!is_host_mount("ext4", "/"));
+        assert!(!is_host_mount("ext4", "/mnt/wslgx"));
+    }
+}
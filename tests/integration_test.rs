@@ -22,7 +22,7 @@ async fn test_sentinel_agent_integration() {
     let client = Client::new();
     let mut retries = 30;
     while retries > 0 {
-        if let Ok(response) = client.get(&format!("{}/health", api_url)).send().await {
+        if let Ok(response) = client.get(format!("{}/health", api_url)).send().await {
             if response.status().is_success() {
                 break;
             }
@@ -44,7 +44,7 @@ async fn test_sentinel_agent_integration() {
     
     // Verify metrics were received
     let stats_response = client
-        .get(&format!("{}/stats", api_url))
+        .get(format!("{}/stats", api_url))
         .send()
         .await
         .expect("Failed to get stats")
@@ -66,7 +66,7 @@ async fn test_sentinel_agent_integration() {
     
     // Verify latest metrics structure
     let latest_response = client
-        .get(&format!("{}/metrics/latest", api_url))
+        .get(format!("{}/metrics/latest", api_url))
         .send()
         .await
         .expect("Failed to get latest metrics");